@@ -60,16 +60,52 @@ int32_t BaguaNet::accept(void *listen_comm, void **recv_comm)
 {
     uintptr_t listen_comm_id = *static_cast<uintptr_t *>(listen_comm);
     auto recv_comm_id = std::make_unique<uintptr_t>(-1);
-    int32_t ret = bagua_net_c_accept(inner.get(), listen_comm_id, recv_comm_id.get());
+    bool accepted = false;
+    int32_t ret = bagua_net_c_accept(inner.get(), listen_comm_id, recv_comm_id.get(), &accepted);
     if (ret != 0)
     {
         return ret;
     }
 
-    *recv_comm = recv_comm_id.release();
+    // No send comm is connecting yet, NCCL retries on a NULL comm.
+    *recv_comm = accepted ? recv_comm_id.release() : nullptr;
     return 0;
 }
 
+// The handle itself, off by one so that it is never NULL. A handle that is
+// deregistered twice is then caught instead of freed twice.
+static void *to_mhandle(uintptr_t handle)
+{
+    return reinterpret_cast<void *>(handle + 1);
+}
+
+static uintptr_t from_mhandle(void *mhandle)
+{
+    return reinterpret_cast<uintptr_t>(mhandle) - 1;
+}
+
+int32_t BaguaNet::reg_mr(void *data, int size, int type, void **mhandle)
+{
+    Buffer buf{
+        .data = static_cast<uint8_t *>(data),
+        .len = (uintptr_t)(size),
+    };
+    uintptr_t handle = -1;
+    int32_t ret = bagua_net_c_reg_mr(inner.get(), buf, type, &handle);
+    if (ret != 0)
+    {
+        return ret;
+    }
+
+    *mhandle = to_mhandle(handle);
+    return 0;
+}
+
+int32_t BaguaNet::dereg_mr(void *mhandle)
+{
+    return bagua_net_c_dereg_mr(inner.get(), from_mhandle(mhandle));
+}
+
 int32_t BaguaNet::isend(void *send_comm, void *data, int size, void *mhandle, void **request)
 {
     uintptr_t send_comm_id = *static_cast<uintptr_t *>(send_comm);
@@ -77,9 +113,15 @@ int32_t BaguaNet::isend(void *send_comm, void *data, int size, void *mhandle, vo
         .data = static_cast<uint8_t *>(data),
         .len = (uintptr_t)(size),
     };
+    uintptr_t handle = mhandle ? from_mhandle(mhandle) : 0;
     auto request_id = std::make_unique<uintptr_t>(-1);
 
-    int32_t ret = bagua_net_c_isend(inner.get(), send_comm_id, buf, request_id.get());
+    int32_t ret = bagua_net_c_isend(inner.get(), send_comm_id, buf, mhandle ? &handle : nullptr, request_id.get());
+    if (ret == -4)
+    {
+        *request = nullptr;
+        return 0;
+    }
     if (ret != 0)
     {
         return ret;
@@ -96,9 +138,15 @@ int32_t BaguaNet::irecv(void *recv_comm, void *data, int size, void *mhandle, vo
         .data = static_cast<uint8_t *>(data),
         .len = (uintptr_t)(size),
     };
+    uintptr_t handle = mhandle ? from_mhandle(mhandle) : 0;
     auto request_id = std::make_unique<uintptr_t>(-1);
 
-    int32_t ret = bagua_net_c_irecv(inner.get(), recv_comm_id, buf, request_id.get());
+    int32_t ret = bagua_net_c_irecv(inner.get(), recv_comm_id, buf, mhandle ? &handle : nullptr, request_id.get());
+    if (ret == -4)
+    {
+        *request = nullptr;
+        return 0;
+    }
     if (ret != 0)
     {
         return ret;
@@ -108,6 +156,26 @@ int32_t BaguaNet::irecv(void *recv_comm, void *data, int size, void *mhandle, vo
     return 0;
 }
 
+int32_t BaguaNet::iflush(void *recv_comm, void *data, int size, void **request)
+{
+    uintptr_t recv_comm_id = *static_cast<uintptr_t *>(recv_comm);
+    Buffer buf{
+        .data = static_cast<uint8_t *>(data),
+        .len = (uintptr_t)(size),
+    };
+    bool posted = false;
+    auto request_id = std::make_unique<uintptr_t>(-1);
+
+    int32_t ret = bagua_net_c_iflush(inner.get(), recv_comm_id, buf, &posted, request_id.get());
+    if (ret != 0)
+    {
+        return ret;
+    }
+
+    *request = posted ? request_id.release() : nullptr;
+    return 0;
+}
+
 int32_t BaguaNet::test(void *request, bool *done, uintptr_t *bytes)
 {
     uintptr_t request_id = *static_cast<uintptr_t *>(request);
@@ -138,6 +206,24 @@ int32_t BaguaNet::close_listen(void *listen_comm)
     return bagua_net_c_close_listen(inner.get(), *listen_comm_id);
 }
 
+ncclResult_t BaguaNet::nccl_result(int32_t ret)
+{
+    switch (ret)
+    {
+    case 0:
+        return ncclSuccess;
+    case -2:
+        return ncclInvalidArgument;
+    case -3:
+    {
+        int32_t result = bagua_net_c_last_nccl_result();
+        return result > 0 ? static_cast<ncclResult_t>(result) : ncclInternalError;
+    }
+    default:
+        return ncclInternalError;
+    }
+}
+
 BaguaNet::BaguaNet()
 {
     inner = std::unique_ptr<BaguaNetC, std::function<void(BaguaNetC *)> >(
@@ -10,8 +10,33 @@
 #include <vector>
 #include <functional>
 
+#include "nccl_types.h"
+
 struct BaguaNetC;
 
+/// Of bagua_net_c_get_statistics, of the requests done since the net was
+/// created or last reset. The active ones as of the call.
+struct NetStatisticsC
+{
+  uint64_t bytes_sent;
+  uint64_t bytes_received;
+  uint64_t requests_completed;
+  uint64_t requests_failed;
+  uintptr_t active_comms;
+  uintptr_t active_requests;
+};
+
+struct CommStatisticsC
+{
+  // 0 for a send comm, 1 for a recv comm.
+  int32_t kind;
+  uintptr_t id;
+  uint64_t nbytes;
+  uint64_t requests_completed;
+  uint64_t requests_failed;
+  uintptr_t active_requests;
+};
+
 struct NCCLNetPropertiesC
 {
   const char *name;
@@ -21,13 +46,68 @@ struct NCCLNetPropertiesC
   int32_t speed;
   int32_t port;
   int32_t max_comms;
+  // -1 if unknown. Not in ncclNetProperties_v3_t/v4_t, kept for newer versions.
+  float latency_us;
+  int32_t mtu;
+  int32_t numa_node;
+};
+
+/// Large enough for both AF_INET and AF_INET6 (address plus scope id).
+union SocketAddrC
+{
+  struct sockaddr sa;
+  struct sockaddr_in sin;
+  struct sockaddr_in6 sin6;
+};
+
+struct SocketHandleAddrsC
+{
+  // AF_INET or AF_INET6 of the listener's address of the other family, 0 if none.
+  uint8_t alt_family;
+  uint8_t _reserved;
+  // Network byte order.
+  uint16_t alt_port;
+  // IPv4 addresses take the first 4 bytes.
+  uint8_t alt_ip[16];
+  uint32_t alt_scope_id;
+  // Id of the listener's unix socket, big endian, 0 if it has none.
+  uint8_t uds_id[8];
+};
+
+// The hostname starts in sockaddr right after the family and the port, in
+// place of the IP address, and goes on here.
+struct SocketHandleNamedC
+{
+  // See SocketHandleAddrsC.
+  uint8_t alt_family;
+  uint8_t _reserved;
+  uint16_t alt_port;
+  uint8_t uds_id[8];
+  // The rest of the hostname, NUL padded.
+  uint8_t hostname[20];
+};
+
+union SocketHandleBodyC
+{
+  // version 2
+  struct SocketHandleAddrsC addrs;
+  // version 3, NUL padded, resolved with the port of sockaddr.
+  uint8_t hostname[32];
+  // version 4
+  struct SocketHandleNamedC named;
 };
 
 struct SocketHandleC
 {
-  struct sockaddr sockaddr;
+  union SocketAddrC sockaddr;
+  // Layout version, older handles have a unix socket name from here on.
+  uint8_t version;
+  uint8_t _reserved[3];
+  union SocketHandleBodyC body;
 };
 
+static_assert(sizeof(SocketHandleC) <= 64, "SocketHandleC exceeds NCCL_NET_HANDLE_MAXSIZE");
+
 struct Buffer
 {
   uint8_t *data;
@@ -41,6 +121,15 @@ extern "C"
 
   void bagua_net_c_destroy(BaguaNetC **ptr);
 
+  /// Waits up to timeout_ms for the requests posted to be done, then closes
+  /// every comm. Only bagua_net_c_destroy is left to call.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -3: bagua-net inner error, e.g. requests not done in time
+  int32_t bagua_net_c_shutdown(BaguaNetC *ptr, uint64_t timeout_ms);
+
   /// Error code
   /// 0: success
   /// -1: null pointer
@@ -70,42 +159,256 @@ extern "C"
   /// Error code
   /// 0: success
   /// -1: null pointer
-  int32_t bagua_net_c_accept(BaguaNetC *ptr, uintptr_t listen_comm_id, uintptr_t *recv_comm_id);
+  /// -3: connect failed
+  int32_t bagua_net_c_connect_test(BaguaNetC *ptr, uintptr_t send_comm_id, bool *ready);
 
+  /// Error code
+  /// 0: success, `*accepted` is false if no send comm is connecting yet
+  /// -1: null pointer
+  /// -3: accept failed
+  /// -4: the listen comm was closed meanwhile
+  int32_t bagua_net_c_accept(BaguaNetC *ptr, uintptr_t listen_comm_id, uintptr_t *recv_comm_id, bool *accepted);
+
+  /// `mhandle` is null, or what `bagua_net_c_reg_mr` returned for the region
+  /// of the buffer.
+  ///
   /// Error code
   /// 0: success
   /// -1: null pointer
+  /// -3: bagua-net inner error
+  /// -4: the comm is busy, post it again later
   int32_t bagua_net_c_isend(BaguaNetC *ptr,
                             uintptr_t send_comm_id,
                             Buffer buf,
+                            const uintptr_t *mhandle,
                             uintptr_t *request_id);
 
+  /// One message of the `nbufs` buffers at `bufs` one after the other.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -3: bagua-net inner error
+  /// -4: the comm is busy, post it again later
+  int32_t bagua_net_c_isend_v(BaguaNetC *ptr,
+                              uintptr_t send_comm_id,
+                              const Buffer *bufs,
+                              uintptr_t nbufs,
+                              uintptr_t *request_id);
+
+  /// `mhandle` is null, or what `bagua_net_c_reg_mr` returned for the region
+  /// of the buffer.
+  ///
   /// Error code
   /// 0: success
   /// -1: null pointer
+  /// -3: bagua-net inner error
+  /// -4: the comm is busy, post it again later
   int32_t bagua_net_c_irecv(BaguaNetC *ptr,
                             uintptr_t recv_comm_id,
                             Buffer buf,
+                            const uintptr_t *mhandle,
                             uintptr_t *request_id);
 
+  /// One request over the `nbufs` buffers at `bufs`, each of which takes a
+  /// message like after as many `bagua_net_c_irecv`.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -3: bagua-net inner error
+  /// -4: the comm is busy, post it again later
+  int32_t bagua_net_c_irecv_multi(BaguaNetC *ptr,
+                                  uintptr_t recv_comm_id,
+                                  const Buffer *bufs,
+                                  uintptr_t nbufs,
+                                  uintptr_t *request_id);
+
+  /// Makes what the receives posted before it put in `buf` visible there. A
+  /// request to test in `request_id` if `posted`, else nothing to wait for.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -2: invalid parameter
+  /// -3: bagua-net inner error
+  int32_t bagua_net_c_iflush(BaguaNetC *ptr,
+                             uintptr_t recv_comm_id,
+                             Buffer buf,
+                             bool *posted,
+                             uintptr_t *request_id);
+
+  /// Locks host memory until `bagua_net_c_dereg_mr`.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -2: invalid parameter
+  /// -3: bagua-net inner error
+  int32_t bagua_net_c_reg_mr(BaguaNetC *ptr, Buffer buf, int32_t ptr_type, uintptr_t *mhandle);
+
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -3: bagua-net inner error, e.g. a handle deregistered already
+  int32_t bagua_net_c_dereg_mr(BaguaNetC *ptr, uintptr_t mhandle);
+
   /// Error code
   /// 0: success
   /// -1: null pointer
   int32_t bagua_net_c_test(BaguaNetC *ptr, uintptr_t request_id, bool *done, uintptr_t *bytes);
 
+  /// Like `bagua_net_c_test`, with the size of the message received into each
+  /// of the `nsizes` buffers of the request in `sizes` once it is done.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -2: invalid parameter
+  /// -3: bagua-net inner error
+  int32_t bagua_net_c_test_sizes(BaguaNetC *ptr, uintptr_t request_id, bool *done, uintptr_t *sizes, uintptr_t nsizes);
+
+  /// The size of the message the sender declared for a recv request in
+  /// `bytes`, once `known`, which it is at the latest once the request is done.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -2: invalid parameter
+  /// -3: bagua-net inner error, e.g. a send request
+  int32_t bagua_net_c_received_size(BaguaNetC *ptr, uintptr_t request_id, bool *known, uintptr_t *bytes);
+
+  /// Of a request that is done but not yet tested done, how long it was
+  /// queued and on the wire in `queued_ns` and `wire_ns`, once `done`.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -2: invalid parameter
+  /// -3: bagua-net inner error, e.g. a request tested done already
+  int32_t bagua_net_c_request_timing(BaguaNetC *ptr, uintptr_t request_id, bool *done, uint64_t *queued_ns, uint64_t *wire_ns);
+
+  /// Retracts the request, its buffer is not touched once the transfers under
+  /// way are done.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -3: bagua-net inner error, e.g. a request done and tested already
+  int32_t bagua_net_c_cancel(BaguaNetC *ptr, uintptr_t request_id);
+
+  /// The ncclResult_t of the error of the last call on this thread that
+  /// returned -3. 0 if there was none.
+  int32_t bagua_net_c_last_nccl_result();
+
+  /// Tests the `n` requests of `request_ids` at once, into the same index of
+  /// `done`, `bytes` and `errs`. The error code of each request is in `errs`,
+  /// 0 or -3 like with `bagua_net_c_test`.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -2: invalid parameter
+  int32_t bagua_net_c_test_many(BaguaNetC *ptr,
+                                const uintptr_t *request_ids,
+                                uintptr_t n,
+                                bool *done,
+                                uintptr_t *bytes,
+                                int32_t *errs);
+
+  /// Blocks until the request is done, for up to `timeout_ms` milliseconds
+  /// unless it is negative. Other calls on the net wait meanwhile.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -3: bagua-net inner error
+  /// -5: timed out, the request is still pending
+  int32_t bagua_net_c_wait(BaguaNetC *ptr, uintptr_t request_id, int64_t timeout_ms, uintptr_t *bytes);
+
+  /// Of `bagua_net_c_set_completion_hook`, called with its `user_data`, 0 or
+  /// -3 like `bagua_net_c_test`, and the bytes the request carried.
+  typedef void (*BaguaNetCompletionCallback)(void *user_data, int32_t status, uintptr_t bytes);
+
+  /// Hands the request over to `callback`, which `bagua_net_c_test` no longer
+  /// knows then. Called once, on a thread of the net once the request is
+  /// done, or on this one after the net is unlocked if it is done already.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -2: unknown request, e.g. tested to completion already
+  /// -3: bagua-net inner error, e.g. a flush
+  int32_t bagua_net_c_set_completion_hook(BaguaNetC *ptr,
+                                          uintptr_t request_id,
+                                          BaguaNetCompletionCallback callback,
+                                          void *user_data);
+
+  /// Before the first message on the send comm.
+  ///
   /// Error code
   /// 0: success
   /// -1: null pointer
+  /// -3: bagua-net inner error
+  int32_t bagua_net_c_set_fixed_message_size(BaguaNetC *ptr, uintptr_t send_comm_id, uintptr_t nbytes);
+
+  /// Before the first message on the recv comm.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -3: bagua-net inner error
+  int32_t bagua_net_c_set_recv_fixed_message_size(BaguaNetC *ptr, uintptr_t recv_comm_id, uintptr_t nbytes);
+
+  /// Caps the bandwidth of the send comm at `mbps` megabits per second, 0
+  /// lifts the cap.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -3: bagua-net inner error
+  int32_t bagua_net_c_set_max_bandwidth(BaguaNetC *ptr, uintptr_t send_comm_id, uint64_t mbps);
+
+  /// The totals into `stats`, the first `ncomms` comms of its `active_comms`
+  /// into `comms`, which can be null if `ncomms` is 0.
+  ///
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -2: invalid parameter
+  /// -3: bagua-net inner error, e.g. the tokio backend
+  int32_t bagua_net_c_get_statistics(BaguaNetC *ptr,
+                                     NetStatisticsC *stats,
+                                     CommStatisticsC *comms,
+                                     uintptr_t ncomms);
+
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -3: bagua-net inner error
+  int32_t bagua_net_c_reset_statistics(BaguaNetC *ptr);
+
+  /// Error code
+  /// 0: success
+  /// -1: null pointer
+  /// -2: unknown send comm, e.g. closed already
+  /// -3: close failed
   int32_t bagua_net_c_close_send(BaguaNetC *ptr, uintptr_t send_comm_id);
 
   /// Error code
   /// 0: success
   /// -1: null pointer
+  /// -2: unknown recv comm, e.g. closed already
+  /// -3: close failed
   int32_t bagua_net_c_close_recv(BaguaNetC *ptr, uintptr_t recv_comm_id);
 
+  /// Wakes an accept waiting on the listen comm on another thread first.
+  ///
   /// Error code
   /// 0: success
   /// -1: null pointer
+  /// -2: unknown listen comm, e.g. closed already
+  /// -3: close failed
   int32_t bagua_net_c_close_listen(BaguaNetC *ptr, uintptr_t listen_comm_id);
 
 } // extern "C"
@@ -132,10 +435,18 @@ public:
 
   int32_t accept(void *listen_comm, void **recv_comm);
 
+  int32_t reg_mr(void *data, int size, int type, void **mhandle);
+
+  int32_t dereg_mr(void *mhandle);
+
+  /// Leaves `*request` null while the comm is busy, NCCL posts it again.
   int32_t isend(void *send_comm, void *data, int size, void *mhandle, void **request);
 
   int32_t irecv(void *recv_comm, void *data, int size, void *mhandle, void **request);
 
+  /// Leaves `*request` null if there is nothing to flush.
+  int32_t iflush(void *recv_comm, void *data, int size, void **request);
+
   int32_t test(void *request, bool *done, uintptr_t *bytes);
 
   int32_t close_send(void *send_comm);
@@ -144,6 +455,10 @@ public:
 
   int32_t close_listen(void *listen_comm);
 
+  /// Of an error code returned by the calls above, on the thread that made
+  /// the call, right after it.
+  static ncclResult_t nccl_result(int32_t ret);
+
 private:
   BaguaNet();
 
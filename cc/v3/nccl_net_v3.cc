@@ -4,6 +4,7 @@
 #include <cstdlib>
 #include <ostream>
 #include <new>
+#include <sched.h>
 
 #include "nccl_net_v3.h"
 #include "bagua_net.h"
@@ -55,7 +56,7 @@ __hidden ncclResult_t baguaNetGetProperties_v3(int dev, ncclNetProperties_v3_t *
     if (ret != 0)
     {
         NCCL_WARN("baguaNetGetProperties_v3 failed, ret=%d, dev=%d", ret, dev);
-        return ncclInternalError;
+        return BaguaNet::nccl_result(ret);
     }
     set_properties_v3(*props, inner_props);
     NCCL_TRACE(NCCL_ALL, "baguaNetGetProperties_v3, dev=%d", dev);
@@ -69,7 +70,7 @@ __hidden ncclResult_t baguaNetListen_v3(int dev, void *handle, void **listenComm
     if (ret != 0)
     {
         NCCL_WARN("baguaNetListen_v3 failed, ret=%d, dev=%d", ret, dev);
-        return ncclInternalError;
+        return BaguaNet::nccl_result(ret);
     }
     NCCL_TRACE(NCCL_ALL, "baguaNetListen_v3, dev=%d", dev);
 
@@ -82,7 +83,7 @@ __hidden ncclResult_t baguaNetConnect_v3(int dev, void *handle, void **sendComm)
     if (ret != 0)
     {
         NCCL_WARN("baguaNetConnect_v3 failed, ret=%d", ret);
-        return ncclInternalError;
+        return BaguaNet::nccl_result(ret);
     }
     NCCL_TRACE(NCCL_ALL, "baguaNetConnect_v3 ok, dev=%d", dev);
 
@@ -91,11 +92,20 @@ __hidden ncclResult_t baguaNetConnect_v3(int dev, void *handle, void **sendComm)
 
 __hidden ncclResult_t baguaNetAccept_v3(void *listenComm, void **recvComm)
 {
-    int ret = BaguaNet::instance().accept(listenComm, recvComm);
+    // This version of the API expects accept to block.
+    int ret = 0;
+    do
+    {
+        ret = BaguaNet::instance().accept(listenComm, recvComm);
+        if (ret == 0 && *recvComm == nullptr)
+        {
+            sched_yield();
+        }
+    } while (ret == 0 && *recvComm == nullptr);
     if (ret != 0)
     {
         NCCL_WARN("baguaNetAccept_v3 failed, ret=%d", ret);
-        return ncclInternalError;
+        return BaguaNet::nccl_result(ret);
     }
     NCCL_TRACE(NCCL_ALL, "baguaNetAccept_v3, listenComm=%p", listenComm);
 
@@ -104,13 +114,28 @@ __hidden ncclResult_t baguaNetAccept_v3(void *listenComm, void **recvComm)
 
 __hidden ncclResult_t baguaNetRegMr_v3(void *comm, void *data, int size, int type, void **mhandle)
 {
-    NCCL_TRACE(NCCL_ALL, "baguaNetRegMr_v3, comm=%p, data=%p, type=%d", comm, data, type);
-    return (type != NCCL_PTR_HOST) ? ncclInternalError : ncclSuccess;
+    int ret = BaguaNet::instance().reg_mr(data, size, type, mhandle);
+    if (ret != 0)
+    {
+        NCCL_WARN("baguaNetRegMr_v3 failed, ret=%d, comm=%p, data=%p, size=%d, type=%d", ret, comm, data, size, type);
+        return BaguaNet::nccl_result(ret);
+    }
+    NCCL_TRACE(NCCL_ALL, "baguaNetRegMr_v3, comm=%p, data=%p, size=%d, type=%d, mhandle=%p",
+               comm, data, size, type, *mhandle);
+
+    return ncclSuccess;
 }
 
 __hidden ncclResult_t baguaNetDeregMr_v3(void *comm, void *mhandle)
 {
-    NCCL_TRACE(NCCL_ALL, "baguaNetDeregMr_v3, comm=%p", comm);
+    int ret = BaguaNet::instance().dereg_mr(mhandle);
+    if (ret != 0)
+    {
+        NCCL_WARN("baguaNetDeregMr_v3 failed, ret=%d, comm=%p, mhandle=%p", ret, comm, mhandle);
+        return BaguaNet::nccl_result(ret);
+    }
+    NCCL_TRACE(NCCL_ALL, "baguaNetDeregMr_v3, comm=%p, mhandle=%p", comm, mhandle);
+
     return ncclSuccess;
 }
 
@@ -120,7 +145,12 @@ __hidden ncclResult_t baguaNetIsend_v3(void *sendComm, void *data, int size, voi
     if (ret != 0)
     {
         NCCL_WARN("baguaNetIsend_v3 failed, ret=%d, sendComm=%p, data=%p, size=%d", ret, sendComm, data, size);
-        return ncclInternalError;
+        return BaguaNet::nccl_result(ret);
+    }
+    if (*request == nullptr)
+    {
+        NCCL_TRACE(NCCL_ALL, "baguaNetIsend_v3 busy, sendComm=%p, data=%p, size=%d", sendComm, data, size);
+        return ncclSuccess;
     }
     NCCL_TRACE(NCCL_ALL, "baguaNetIsend_v3, sendComm=%p, data=%p, size=%d, request_id=%d",
                sendComm, data, size, *(uintptr_t *)(*request));
@@ -134,7 +164,12 @@ __hidden ncclResult_t baguaNetIrecv_v3(void *recvComm, void *data, int size, voi
     if (ret != 0)
     {
         NCCL_WARN("baguaNetIrecv_v3 failed, ret=%d, sendComm=%p, data=%p, size=%d", ret, recvComm, data, size);
-        return ncclInternalError;
+        return BaguaNet::nccl_result(ret);
+    }
+    if (*request == nullptr)
+    {
+        NCCL_TRACE(NCCL_ALL, "baguaNetIrecv_v3 busy, recvComm=%p, data=%p, size=%d", recvComm, data, size);
+        return ncclSuccess;
     }
     NCCL_TRACE(NCCL_ALL, "baguaNetIrecv_v3, recvComm=%p, data=%p, size=%d, request_id=%d",
                recvComm, data, size, *(uintptr_t *)(*request));
@@ -144,8 +179,30 @@ __hidden ncclResult_t baguaNetIrecv_v3(void *recvComm, void *data, int size, voi
 
 __hidden ncclResult_t baguaNetFlush_v3(void *recvComm, void *data, int size, void *mhandle)
 {
-    // We don't support CUDA pointers, so we don't need a flush operation
-    return ncclInternalError;
+    void *request = nullptr;
+    int ret = BaguaNet::instance().iflush(recvComm, data, size, &request);
+    if (ret != 0)
+    {
+        NCCL_WARN("baguaNetFlush_v3 failed, ret=%d, recvComm=%p, data=%p, size=%d", ret, recvComm, data, size);
+        return BaguaNet::nccl_result(ret);
+    }
+    // Blocking in this version of the API.
+    bool done = request == nullptr;
+    while (!done)
+    {
+        uintptr_t nbytes = 0;
+        ret = BaguaNet::instance().test(request, &done, &nbytes);
+        if (ret != 0)
+        {
+            NCCL_WARN("baguaNetFlush_v3 failed, ret=%d, request_id=%d",
+                      ret, *static_cast<uintptr_t *>(request));
+            delete static_cast<uintptr_t *>(request);
+            return BaguaNet::nccl_result(ret);
+        }
+    }
+    delete static_cast<uintptr_t *>(request);
+
+    return ncclSuccess;
 }
 
 __hidden ncclResult_t baguaNetTest_v3(void *request, int *done, int *size)
@@ -157,7 +214,7 @@ __hidden ncclResult_t baguaNetTest_v3(void *request, int *done, int *size)
     {
         NCCL_WARN("baguaNetTest_v3 failed, ret=%d, request_id=%d",
                   ret, *static_cast<uintptr_t *>(request));
-        return ncclInternalError;
+        return BaguaNet::nccl_result(ret);
     }
     *done = b_done ? 1 : 0;
     if (b_done && size != NULL)
@@ -177,7 +234,8 @@ __hidden ncclResult_t baguaNetCloseSend_v3(void *sendComm)
     if (ret != 0)
     {
         NCCL_WARN("baguaNetCloseSend_v3 failed, ret=%d, sendComm=%p", ret, sendComm);
-        return ncclInternalError;
+        // Closed twice, or never opened.
+        return BaguaNet::nccl_result(ret);
     }
     NCCL_TRACE(NCCL_ALL, "baguaNetCloseSend_v3, sendComm=%p", sendComm);
     return ncclSuccess;
@@ -189,7 +247,8 @@ __hidden ncclResult_t baguaNetCloseRecv_v3(void *recvComm)
     if (ret != 0)
     {
         NCCL_WARN("baguaNetCloseRecv_v3 failed, ret=%d, recvComm=%p", ret, recvComm);
-        return ncclInternalError;
+        // Closed twice, or never opened.
+        return BaguaNet::nccl_result(ret);
     }
     NCCL_TRACE(NCCL_ALL, "baguaNetCloseRecv_v3, recvComm=%p", recvComm);
     return ncclSuccess;
@@ -201,7 +260,8 @@ __hidden ncclResult_t baguaNetCloseListen_v3(void *listenComm)
     if (ret != 0)
     {
         NCCL_WARN("baguaNetCloseListen_v3 failed, ret=%d, listenComm=%p", ret, listenComm);
-        return ncclInternalError;
+        // Closed twice, or never opened.
+        return BaguaNet::nccl_result(ret);
     }
     NCCL_TRACE(NCCL_ALL, "baguaNetCloseListen_v3, listenComm=%p", listenComm);
     return ncclSuccess;
@@ -0,0 +1,103 @@
+//! Ping-pong between two nets over loopback, through the futures of
+//! `bagua_net::async_api`:
+//!
+//! ```text
+//! cargo run --example async_ping_pong [ITERS] [SIZE]
+//! ```
+
+use bagua_net::async_api::{AsyncNet, AsyncRecvComm, AsyncSendComm, RecvBuffer, SendBuffer};
+use std::time::{Duration, Instant};
+
+/// A comm each way between two nets.
+struct Peer {
+    send_comm: AsyncSendComm,
+    recv_comm: AsyncRecvComm,
+}
+
+/// Of `a`, and of `b`, each connected to the other.
+async fn connect(a: &AsyncNet, b: &AsyncNet) -> (Peer, Peer) {
+    let (a_handle, a_listen) = a.listen(0).unwrap();
+    let (b_handle, b_listen) = b.listen(0).unwrap();
+    let a_send = a.connect(0, b_handle).unwrap();
+    let b_send = b.connect(0, a_handle).unwrap();
+    let (mut a_recv, mut b_recv) = (None, None);
+    while a_recv.is_none() || b_recv.is_none() {
+        if a_recv.is_none() {
+            a_recv = a_listen.accept().unwrap();
+        }
+        if b_recv.is_none() {
+            b_recv = b_listen.accept().unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    (
+        Peer {
+            send_comm: a_send,
+            recv_comm: a_recv.unwrap(),
+        },
+        Peer {
+            send_comm: b_send,
+            recv_comm: b_recv.unwrap(),
+        },
+    )
+}
+
+/// `iters` round trips of `size` bytes, pinged by `a` and ponged by `b`.
+async fn ping_pong(iters: usize, size: usize) -> Duration {
+    // Single host runs.
+    std::env::set_var("BAGUA_NET_ALLOW_LOOPBACK", "1");
+    std::env::set_var("NCCL_SOCKET_IFNAME", "lo");
+    let (a, b) = (AsyncNet::new().unwrap(), AsyncNet::new().unwrap());
+    let (ping, pong) = connect(&a, &b).await;
+
+    let ponger = tokio::spawn(async move {
+        let buf: &'static mut [u8] = Box::leak(vec![0u8; size].into_boxed_slice());
+        for _ in 0..iters {
+            // Done with by the time the transfer is ready.
+            let received = unsafe { RecvBuffer::from_raw_parts(buf.as_mut_ptr(), size) };
+            assert_eq!(pong.recv_comm.recv(received).await.unwrap(), size);
+            let sent = unsafe { SendBuffer::from_raw_parts(buf.as_ptr(), size) };
+            assert_eq!(pong.send_comm.send(sent).await.unwrap(), size);
+        }
+        pong
+    });
+
+    let sent: &'static [u8] = Box::leak(vec![1u8; size].into_boxed_slice());
+    let buf: &'static mut [u8] = Box::leak(vec![0u8; size].into_boxed_slice());
+    let start = Instant::now();
+    for _ in 0..iters {
+        // Posted before the pong can arrive.
+        let received = unsafe { RecvBuffer::from_raw_parts(buf.as_mut_ptr(), size) };
+        let pong = ping.recv_comm.recv(received);
+        assert_eq!(ping.send_comm.send(sent.into()).await.unwrap(), size);
+        assert_eq!(pong.await.unwrap(), size);
+        assert_eq!(buf, sent);
+    }
+    let elapsed = start.elapsed();
+    drop(ponger.await.unwrap());
+    elapsed
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let iters = args.next().map_or(1000, |iters| iters.parse().unwrap());
+    let size = args.next().map_or(4096, |size| size.parse().unwrap());
+    let elapsed = ping_pong(iters, size).await;
+    println!(
+        "{} round trips of {} bytes, {:.1} us each",
+        iters,
+        size,
+        elapsed.as_secs_f64() * 1e6 / iters as f64
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ping_pong() {
+        ping_pong(100, 4096).await;
+    }
+}
@@ -0,0 +1,278 @@
+//! Futures over the sends and receives of a net, for callers on an async
+//! runtime. A future is woken by the thread of the net that finishes its
+//! request, see `Net::set_completion_hook`, nothing polls `test()`.
+
+use crate::implement::nthread_per_socket_backend::BaguaNet;
+pub use crate::interface::{
+    BaguaNetError, CommKind, CommStatistics, NetStatistics, RecvBuffer, SendBuffer, SocketHandle,
+};
+use crate::interface::{
+    CompletionHook, Net, SocketListenCommID, SocketRecvCommID, SocketRequestID, SocketSendCommID,
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+type SharedNet = Arc<Mutex<BaguaNet>>;
+
+/// The basic implementation, configured by the `BAGUA_NET_*` variables like
+/// the net of the NCCL plugin. Its comms close once they are dropped.
+pub struct AsyncNet {
+    net: SharedNet,
+}
+
+impl AsyncNet {
+    pub fn new() -> Result<AsyncNet, BaguaNetError> {
+        Ok(AsyncNet {
+            net: Arc::new(Mutex::new(BaguaNet::new()?)),
+        })
+    }
+
+    pub fn devices(&self) -> Result<usize, BaguaNetError> {
+        self.net.lock().unwrap().devices()
+    }
+
+    pub fn listen(&self, dev_id: usize) -> Result<(SocketHandle, AsyncListenComm), BaguaNetError> {
+        let (socket_handle, id) = self.net.lock().unwrap().listen(dev_id)?;
+        Ok((
+            socket_handle,
+            AsyncListenComm {
+                net: self.net.clone(),
+                id,
+            },
+        ))
+    }
+
+    /// Messages sent before the comm is connected are queued.
+    pub fn connect(
+        &self,
+        dev_id: usize,
+        socket_handle: SocketHandle,
+    ) -> Result<AsyncSendComm, BaguaNetError> {
+        let id = self.net.lock().unwrap().connect(dev_id, socket_handle)?;
+        Ok(AsyncSendComm {
+            net: self.net.clone(),
+            id,
+        })
+    }
+
+    /// See `Net::get_statistics`.
+    pub fn get_statistics(&self) -> Result<NetStatistics, BaguaNetError> {
+        self.net.lock().unwrap().get_statistics()
+    }
+
+    pub fn reset_statistics(&self) -> Result<(), BaguaNetError> {
+        self.net.lock().unwrap().reset_statistics()
+    }
+}
+
+pub struct AsyncListenComm {
+    net: SharedNet,
+    id: SocketListenCommID,
+}
+
+impl AsyncListenComm {
+    /// Does not wait for a connector, `None` while none is connecting.
+    pub fn accept(&self) -> Result<Option<AsyncRecvComm>, BaguaNetError> {
+        let id = self.net.lock().unwrap().accept(self.id)?;
+        Ok(id.map(|id| AsyncRecvComm {
+            net: self.net.clone(),
+            id,
+        }))
+    }
+}
+
+impl Drop for AsyncListenComm {
+    fn drop(&mut self) {
+        if let Err(err) = self.net.lock().unwrap().close_listen(self.id) {
+            tracing::warn!("{:?}", err);
+        }
+    }
+}
+
+/// Blocks once dropped until what was sent is written out, see
+/// `Net::close_send`.
+pub struct AsyncSendComm {
+    net: SharedNet,
+    id: SocketSendCommID,
+}
+
+impl AsyncSendComm {
+    /// Posted right away, in order with the other sends on the comm. `buf` is
+    /// read until the transfer is ready, or once it is dropped, until the comm
+    /// is.
+    pub fn send(&self, buf: SendBuffer) -> Transfer {
+        Transfer::post(&self.net, |net| net.isend(self.id, buf, None))
+    }
+}
+
+impl Drop for AsyncSendComm {
+    fn drop(&mut self) {
+        if let Err(err) = self.net.lock().unwrap().close_send(self.id) {
+            tracing::warn!("{:?}", err);
+        }
+    }
+}
+
+/// The receives still posted fail once it is dropped.
+pub struct AsyncRecvComm {
+    net: SharedNet,
+    id: SocketRecvCommID,
+}
+
+impl AsyncRecvComm {
+    /// Posted right away, in order with the other receives on the comm. `buf`
+    /// is written until the transfer is ready, or once it is dropped, until
+    /// the comm is.
+    pub fn recv(&self, buf: RecvBuffer) -> Transfer {
+        Transfer::post(&self.net, |net| net.irecv(self.id, buf, None))
+    }
+}
+
+impl Drop for AsyncRecvComm {
+    fn drop(&mut self) {
+        if let Err(err) = self.net.lock().unwrap().close_recv(self.id) {
+            tracing::warn!("{:?}", err);
+        }
+    }
+}
+
+/// Set by the completion hook of a request, read by its transfer.
+#[derive(Default)]
+struct Completion {
+    ret: Option<Result<usize, BaguaNetError>>,
+    waker: Option<Waker>,
+}
+
+/// Of a send or receive, ready with the bytes it carried, or its error.
+/// Dropped before, its request is cancelled like with `Net::cancel`.
+pub struct Transfer {
+    net: SharedNet,
+    /// Until it is ready.
+    request: Option<SocketRequestID>,
+    completion: Arc<Mutex<Completion>>,
+}
+
+impl Transfer {
+    fn post(
+        net: &SharedNet,
+        post: impl FnOnce(&mut BaguaNet) -> Result<SocketRequestID, BaguaNetError>,
+    ) -> Transfer {
+        let completion = Arc::new(Mutex::new(Completion::default()));
+        let hooked = completion.clone();
+        let hook: CompletionHook = Box::new(move |ret| {
+            let waker = {
+                let mut completion = hooked.lock().unwrap();
+                completion.ret = Some(ret);
+                completion.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        });
+        let mut locked = net.lock().unwrap();
+        let request = post(&mut locked).and_then(|id| match locked.set_completion_hook(id, hook) {
+            Ok(()) => Ok(id),
+            Err(err) => {
+                let _ = locked.cancel(id);
+                Err(err)
+            }
+        });
+        drop(locked);
+        let request = match request {
+            Ok(id) => Some(id),
+            Err(err) => {
+                completion.lock().unwrap().ret = Some(Err(err));
+                None
+            }
+        };
+        Transfer {
+            net: net.clone(),
+            request,
+            completion,
+        }
+    }
+}
+
+impl Future for Transfer {
+    type Output = Result<usize, BaguaNetError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let ret = {
+            let mut completion = self.completion.lock().unwrap();
+            match completion.ret.take() {
+                Some(ret) => ret,
+                None => {
+                    completion.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        };
+        self.request = None;
+        Poll::Ready(ret)
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        if let Some(id) = self.request.take() {
+            // Done meanwhile, or not.
+            let _ = self.net.lock().unwrap().cancel(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::NCCLSocketDev;
+    use nix::sys::socket::{InetAddr, SockAddr};
+
+    fn loopback_net() -> AsyncNet {
+        let mut net = BaguaNet::new().unwrap();
+        net.socket_devs = vec![NCCLSocketDev {
+            interface_name: "lo".to_owned(),
+            addr: SockAddr::new_inet(InetAddr::from_std(&"127.0.0.1:0".parse().unwrap())),
+            alt_addr: None,
+            pci_path: "".to_owned(),
+        }];
+        AsyncNet {
+            net: Arc::new(Mutex::new(net)),
+        }
+    }
+
+    fn leak(nbytes: usize, fill: u8) -> &'static mut [u8] {
+        Box::leak(vec![fill; nbytes].into_boxed_slice())
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        let net = loopback_net();
+        let (socket_handle, listen_comm) = net.listen(0).unwrap();
+        let send_comm = net.connect(0, socket_handle).unwrap();
+        let recv_comm = loop {
+            if let Some(recv_comm) = listen_comm.accept().unwrap() {
+                break recv_comm;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        };
+
+        let received = recv_comm.recv(leak(1024, 0).into());
+        let sent = send_comm.send(leak(1024, 1).into());
+        let (received, sent) = tokio::join!(received, sent);
+        assert_eq!((received.unwrap(), sent.unwrap()), (1024, 1024));
+
+        // Fails with the error of its request.
+        let received = recv_comm.recv(leak(16, 0).into());
+        let sent = send_comm.send(leak(1024, 1).into());
+        assert!(received.await.is_err());
+        drop(sent);
+
+        // Cancelled once dropped.
+        let received = recv_comm.recv(leak(1024, 0).into());
+        let id = received.request.unwrap();
+        drop(received);
+        assert!(net.net.lock().unwrap().cancel(id).is_err());
+    }
+}
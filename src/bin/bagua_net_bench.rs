@@ -0,0 +1,517 @@
+//! Latency and bandwidth between two hosts, over the C entry points the
+//! NCCL plugin calls, for a sweep of message sizes and stream counts:
+//!
+//! ```text
+//! bagua_net_bench server [--port 7777] [--dev 0]
+//! bagua_net_bench client HOST:PORT [--dev 0] [--sizes 1K,64K,...]
+//!     [--nstreams 1,2,4,8] [--iters 100] [--bytes 1G] [--json FILE]
+//! ```
+//!
+//! The client sends the sweep to the server over the bootstrap connection,
+//! which also carries the handles of the listen comms, and prints a table of
+//! the results. The streams of a point are `BAGUA_NET_NSTREAMS`, set for the
+//! `BaguaNetC` of the point on both hosts, the other `BAGUA_NET_*` variables
+//! apply as they are.
+
+use bagua_net::{
+    bagua_net_c_accept, bagua_net_c_connect, bagua_net_c_connect_test, bagua_net_c_create,
+    bagua_net_c_destroy, bagua_net_c_irecv, bagua_net_c_isend, bagua_net_c_listen,
+    bagua_net_c_test, BaguaNetC, Buffer, SocketHandleC,
+};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+const DEFAULT_PORT: u16 = 7777;
+/// Of the ping-pongs at each point, fewer for large messages.
+const DEFAULT_ITERS: usize = 100;
+/// Moved one way at each point to measure the bandwidth.
+const DEFAULT_BYTES: usize = 1 << 30;
+/// Of the messages in flight while measuring the bandwidth, fewer for large
+/// messages.
+const MAX_WINDOW: usize = 8;
+const WINDOW_BYTES: usize = 256 << 20;
+
+fn check(ret: i32, what: &str) -> io::Result<()> {
+    match ret {
+        0 => Ok(()),
+        ret => Err(io::Error::other(format!(
+            "{} failed, error code {}",
+            what, ret
+        ))),
+    }
+}
+
+/// A `BaguaNetC`, destroyed once dropped.
+struct Net(*mut BaguaNetC);
+
+impl Net {
+    fn new(nstreams: usize) -> io::Result<Net> {
+        std::env::set_var("BAGUA_NET_NSTREAMS", nstreams.to_string());
+        let ptr = bagua_net_c_create();
+        if ptr.is_null() {
+            return Err(io::Error::other("bagua_net_c_create failed".to_owned()));
+        }
+        Ok(Net(ptr))
+    }
+
+    /// A send comm to the peer and a recv comm from it, whose handles are
+    /// exchanged over `bootstrap`.
+    fn comms(&self, dev: i32, bootstrap: &mut TcpStream) -> io::Result<(usize, usize)> {
+        let mut handle: SocketHandleC = unsafe { std::mem::zeroed() };
+        let mut listen_comm = 0;
+        check(
+            bagua_net_c_listen(self.0, dev, &mut handle, &mut listen_comm),
+            "listen",
+        )?;
+        let mut peer_handle: SocketHandleC = unsafe { std::mem::zeroed() };
+        bootstrap.write_all(handle_bytes(&mut handle))?;
+        bootstrap.read_exact(handle_bytes(&mut peer_handle))?;
+
+        let mut send_comm = 0;
+        check(
+            bagua_net_c_connect(self.0, dev, &mut peer_handle, &mut send_comm),
+            "connect",
+        )?;
+        let timer = Instant::now();
+        let (mut connected, mut recv_comm) = (false, None);
+        while !connected || recv_comm.is_none() {
+            if timer.elapsed() > Duration::from_secs(60) {
+                return Err(io::Error::other("no comms after 60s".to_owned()));
+            }
+            if !connected {
+                check(
+                    bagua_net_c_connect_test(self.0, send_comm, &mut connected),
+                    "connect",
+                )?;
+            }
+            if recv_comm.is_none() {
+                let (mut id, mut accepted) = (0, false);
+                check(
+                    bagua_net_c_accept(self.0, listen_comm, &mut id, &mut accepted),
+                    "accept",
+                )?;
+                recv_comm = Some(id).filter(|_| accepted);
+            }
+            std::thread::yield_now();
+        }
+
+        Ok((send_comm, recv_comm.unwrap()))
+    }
+
+    fn isend(&self, send_comm: usize, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let mut request = 0;
+            let buf = Buffer {
+                data: buf.as_ptr() as *mut u8,
+                len: buf.len(),
+            };
+            match bagua_net_c_isend(self.0, send_comm, buf, std::ptr::null(), &mut request) {
+                // Busy, until the messages before it are further along.
+                -4 => std::thread::yield_now(),
+                ret => return check(ret, "isend").map(|_| request),
+            }
+        }
+    }
+
+    fn irecv(&self, recv_comm: usize, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut request = 0;
+            let buf = Buffer {
+                data: buf.as_mut_ptr(),
+                len: buf.len(),
+            };
+            match bagua_net_c_irecv(self.0, recv_comm, buf, std::ptr::null(), &mut request) {
+                -4 => std::thread::yield_now(),
+                ret => return check(ret, "irecv").map(|_| request),
+            }
+        }
+    }
+
+    fn wait(&self, request: usize) -> io::Result<usize> {
+        let (mut done, mut nbytes) = (false, 0);
+        while !done {
+            check(
+                bagua_net_c_test(self.0, request, &mut done, &mut nbytes),
+                "test",
+            )?;
+            // Leaves the CPU to the event loops when they share it.
+            std::thread::yield_now();
+        }
+        Ok(nbytes)
+    }
+}
+
+impl Drop for Net {
+    fn drop(&mut self) {
+        bagua_net_c_destroy(&mut self.0);
+    }
+}
+
+fn handle_bytes(handle: &mut SocketHandleC) -> &mut [u8] {
+    unsafe {
+        std::slice::from_raw_parts_mut(
+            handle as *mut SocketHandleC as *mut u8,
+            std::mem::size_of::<SocketHandleC>(),
+        )
+    }
+}
+
+/// What the client asks the server to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Sweep {
+    nstreams: Vec<usize>,
+    sizes: Vec<usize>,
+    iters: usize,
+    bytes: usize,
+}
+
+impl Sweep {
+    fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        let mut words = vec![self.nstreams.len() as u64];
+        words.extend(self.nstreams.iter().map(|&n| n as u64));
+        words.push(self.sizes.len() as u64);
+        words.extend(self.sizes.iter().map(|&n| n as u64));
+        words.extend([self.iters as u64, self.bytes as u64].iter());
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+        stream.write_all(&bytes[..])
+    }
+
+    fn read_from<R: Read>(stream: &mut R) -> io::Result<Sweep> {
+        let mut word = || -> io::Result<usize> {
+            let mut buf = [0u8; 8];
+            stream.read_exact(&mut buf[..])?;
+            Ok(u64::from_be_bytes(buf) as usize)
+        };
+        let n = word()?;
+        let nstreams = (0..n).map(|_| word()).collect::<io::Result<_>>()?;
+        let n = word()?;
+        let sizes = (0..n).map(|_| word()).collect::<io::Result<_>>()?;
+        Ok(Sweep {
+            nstreams,
+            sizes,
+            iters: word()?,
+            bytes: word()?,
+        })
+    }
+
+    /// Of the timed ping-pongs of `size`, at least 1.
+    fn latency_iters(&self, size: usize) -> usize {
+        self.iters.min(WINDOW_BYTES / size).max(1)
+    }
+
+    /// Of the messages of `size` moved one way, at least 4.
+    fn bandwidth_iters(&self, size: usize) -> usize {
+        (self.bytes / size).max(4)
+    }
+}
+
+fn window(size: usize) -> usize {
+    (WINDOW_BYTES / size).clamp(1, MAX_WINDOW)
+}
+
+#[derive(Debug)]
+struct Point {
+    nstreams: usize,
+    size: usize,
+    /// Half of a round trip.
+    latency: Duration,
+    /// In bytes per second.
+    bandwidth: f64,
+}
+
+/// The client's side of one point.
+fn client_point(
+    net: &Net,
+    (send_comm, recv_comm): (usize, usize),
+    sweep: &Sweep,
+    nstreams: usize,
+    size: usize,
+) -> io::Result<Point> {
+    let send_buf: Vec<u8> = (0..size).map(|i| i as u8).collect();
+    let mut recv_buf = vec![0u8; size];
+    // The first round trip is not timed.
+    let iters = sweep.latency_iters(size);
+    let mut timer = Instant::now();
+    for i in 0..=iters {
+        if i == 1 {
+            timer = Instant::now();
+        }
+        let recv = net.irecv(recv_comm, &mut recv_buf[..])?;
+        let send = net.isend(send_comm, &send_buf[..])?;
+        net.wait(send)?;
+        net.wait(recv)?;
+    }
+    let latency = timer.elapsed() / (2 * iters as u32);
+
+    let mut ack = [0u8; 1];
+    let ack_recv = net.irecv(recv_comm, &mut ack[..])?;
+    let iters = sweep.bandwidth_iters(size);
+    let mut sends = VecDeque::new();
+    let timer = Instant::now();
+    for _ in 0..iters {
+        if sends.len() == window(size) {
+            net.wait(sends.pop_front().unwrap())?;
+        }
+        sends.push_back(net.isend(send_comm, &send_buf[..])?);
+    }
+    for send in sends {
+        net.wait(send)?;
+    }
+    net.wait(ack_recv)?;
+    let bandwidth = (size * iters) as f64 / timer.elapsed().as_secs_f64();
+
+    Ok(Point {
+        nstreams,
+        size,
+        latency,
+        bandwidth,
+    })
+}
+
+/// The server's side of one point.
+fn server_point(
+    net: &Net,
+    (send_comm, recv_comm): (usize, usize),
+    sweep: &Sweep,
+    size: usize,
+) -> io::Result<()> {
+    let mut slots: Vec<Vec<u8>> = (0..window(size)).map(|_| vec![0u8; size]).collect();
+    for _ in 0..=sweep.latency_iters(size) {
+        let recv = net.irecv(recv_comm, &mut slots[0][..])?;
+        net.wait(recv)?;
+        let send = net.isend(send_comm, &slots[0][..])?;
+        net.wait(send)?;
+    }
+
+    let iters = sweep.bandwidth_iters(size);
+    let mut recvs = VecDeque::new();
+    for i in 0..iters {
+        if recvs.len() == slots.len() {
+            net.wait(recvs.pop_front().unwrap())?;
+        }
+        let slot = i % slots.len();
+        recvs.push_back(net.irecv(recv_comm, &mut slots[slot][..])?);
+    }
+    for recv in recvs {
+        net.wait(recv)?;
+    }
+    let send = net.isend(send_comm, &[1u8][..])?;
+    net.wait(send)?;
+
+    Ok(())
+}
+
+fn run_client(addr: &str, dev: i32, sweep: &Sweep) -> io::Result<Vec<Point>> {
+    let mut bootstrap = TcpStream::connect(addr)?;
+    bootstrap.set_nodelay(true)?;
+    sweep.write_to(&mut bootstrap)?;
+    println!(
+        "{:>8} {:>10} {:>14} {:>16}",
+        "nstreams", "size", "latency(us)", "bandwidth(Gbps)"
+    );
+    let mut points = Vec::new();
+    for &nstreams in sweep.nstreams.iter() {
+        let net = Net::new(nstreams)?;
+        let comms = net.comms(dev, &mut bootstrap)?;
+        for &size in sweep.sizes.iter() {
+            let point = client_point(&net, comms, sweep, nstreams, size)?;
+            println!(
+                "{:>8} {:>10} {:>14.1} {:>16.3}",
+                point.nstreams,
+                format_size(point.size),
+                point.latency.as_secs_f64() * 1e6,
+                point.bandwidth * 8. / 1e9
+            );
+            points.push(point);
+        }
+    }
+
+    Ok(points)
+}
+
+fn run_server(port: u16, dev: i32) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    println!("listening on port {}", port);
+    loop {
+        let (mut bootstrap, addr) = listener.accept()?;
+        bootstrap.set_nodelay(true)?;
+        let served = Sweep::read_from(&mut bootstrap).and_then(|sweep| {
+            println!("client {}: {:?}", addr, sweep);
+            for &nstreams in sweep.nstreams.iter() {
+                let net = Net::new(nstreams)?;
+                let comms = net.comms(dev, &mut bootstrap)?;
+                for &size in sweep.sizes.iter() {
+                    server_point(&net, comms, &sweep, size)?;
+                }
+            }
+            Ok(())
+        });
+        match served {
+            Ok(()) => println!("client {}: done", addr),
+            Err(err) => println!("client {}: {}", addr, err),
+        }
+    }
+}
+
+fn to_json(points: &[Point]) -> String {
+    let points: Vec<String> = points
+        .iter()
+        .map(|point| {
+            format!(
+                "  {{\"nstreams\": {}, \"size\": {}, \"latency_us\": {:.3}, \"bandwidth_gbps\": {:.6}}}",
+                point.nstreams,
+                point.size,
+                point.latency.as_secs_f64() * 1e6,
+                point.bandwidth * 8. / 1e9
+            )
+        })
+        .collect();
+    format!("[\n{}\n]\n", points.join(",\n"))
+}
+
+/// E.g. `4K`, `256M`, in powers of 1024.
+fn parse_size(raw: &str) -> Result<usize, String> {
+    let (digits, shift) = match raw.chars().last() {
+        Some('K') | Some('k') => (&raw[..raw.len() - 1], 10),
+        Some('M') | Some('m') => (&raw[..raw.len() - 1], 20),
+        Some('G') | Some('g') => (&raw[..raw.len() - 1], 30),
+        _ => (raw, 0),
+    };
+    match digits.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n << shift),
+        _ => Err(format!("bad size {:?}", raw)),
+    }
+}
+
+fn format_size(size: usize) -> String {
+    match size {
+        size if size >= 1 << 30 && size % (1 << 30) == 0 => format!("{}G", size >> 30),
+        size if size >= 1 << 20 && size % (1 << 20) == 0 => format!("{}M", size >> 20),
+        size if size >= 1 << 10 && size % (1 << 10) == 0 => format!("{}K", size >> 10),
+        size => size.to_string(),
+    }
+}
+
+fn parse_list(raw: &str, parse: fn(&str) -> Result<usize, String>) -> Result<Vec<usize>, String> {
+    raw.split(',').map(parse).collect()
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: bagua_net_bench server [--port {}] [--dev 0]\n       \
+         bagua_net_bench client HOST:PORT [--dev 0] [--sizes 1K,...,256M] [--nstreams 1,2,4,8]\n       \
+         \x20   [--iters {}] [--bytes 1G] [--json FILE]",
+        DEFAULT_PORT, DEFAULT_ITERS
+    );
+    std::process::exit(2)
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (mode, rest) = match args.split_first() {
+        Some((mode, rest)) => (mode.as_str(), rest),
+        None => usage(),
+    };
+    let (addr, rest) = match (mode, rest.split_first()) {
+        ("client", Some((addr, rest))) => (Some(addr.clone()), rest),
+        ("server", _) => (None, rest),
+        _ => usage(),
+    };
+
+    let mut port = DEFAULT_PORT;
+    let mut dev = 0;
+    let mut sweep = Sweep {
+        nstreams: vec![1, 2, 4, 8],
+        // 1K to 256M, by 4.
+        sizes: (0..10).map(|i| 1024 << (2 * i)).collect(),
+        iters: DEFAULT_ITERS,
+        bytes: DEFAULT_BYTES,
+    };
+    let mut json = None;
+    if rest.len() % 2 != 0 {
+        usage();
+    }
+    for option in rest.chunks(2) {
+        let value = option[1].as_str();
+        let parsed = match option[0].as_str() {
+            "--port" => value.parse().map(|value| port = value).map_err(|_| ()),
+            "--dev" => value.parse().map(|value| dev = value).map_err(|_| ()),
+            "--sizes" => parse_list(value, parse_size)
+                .map(|sizes| sweep.sizes = sizes)
+                .map_err(|_| ()),
+            "--nstreams" => parse_list(value, |n| {
+                n.parse().map_err(|_| format!("bad stream count {:?}", n))
+            })
+            .map(|nstreams| sweep.nstreams = nstreams)
+            .map_err(|_| ()),
+            "--iters" => value
+                .parse()
+                .map(|value| sweep.iters = value)
+                .map_err(|_| ()),
+            "--bytes" => parse_size(value)
+                .map(|value| sweep.bytes = value)
+                .map_err(|_| ()),
+            "--json" => {
+                json = Some(value.to_owned());
+                Ok(())
+            }
+            _ => Err(()),
+        };
+        if parsed.is_err() {
+            eprintln!("bad option {} {:?}", option[0], value);
+            usage();
+        }
+    }
+
+    let ret = match addr {
+        None => run_server(port, dev),
+        Some(addr) => run_client(&addr, dev, &sweep).and_then(|points| match json {
+            Some(path) => std::fs::write(path, to_json(&points)),
+            None => Ok(()),
+        }),
+    };
+    if let Err(err) = ret {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sweep_round_trip() {
+        let sweep = Sweep {
+            nstreams: vec![1, 8],
+            sizes: vec![1024, 256 << 20],
+            iters: 10,
+            bytes: 1 << 30,
+        };
+        let mut buf = Vec::new();
+        sweep.write_to(&mut buf).unwrap();
+        assert_eq!(Sweep::read_from(&mut &buf[..]).unwrap(), sweep);
+        assert_eq!(sweep.latency_iters(1024), 10);
+        assert_eq!(sweep.latency_iters(256 << 20), 1);
+        assert_eq!(sweep.bandwidth_iters(256 << 20), 4);
+        assert_eq!(
+            (window(1024), window(64 << 20), window(256 << 20)),
+            (8, 4, 1)
+        );
+    }
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!(parse_size("1K"), Ok(1024));
+        assert_eq!(parse_size("256M"), Ok(256 << 20));
+        assert_eq!(parse_size("3"), Ok(3));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("M").is_err());
+        assert_eq!(format_size(256 << 20), "256M");
+        assert_eq!(format_size(1536), "1536");
+        assert_eq!(parse_list("1K,4M", parse_size), Ok(vec![1024, 4 << 20]));
+    }
+}
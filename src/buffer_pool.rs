@@ -0,0 +1,274 @@
+//! The host buffers the data path copies chunks into, the compressed frames
+//! and the staging buffers that could not be page-locked. They are aligned
+//! to `ALIGN` and a multiple of it, so that the kernel can back them with
+//! huge pages, mapped from the reserved ones with `BAGUA_NET_HUGEPAGES=1`,
+//! and they go back to the pool, `BAGUA_NET_BUFFER_POOL_SIZE` of them kept,
+//! instead of being freed.
+
+use std::alloc::Layout;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Of a huge page.
+pub const ALIGN: usize = 2 << 20;
+
+lazy_static! {
+    /// Of every comm.
+    pub static ref POOL: Arc<BufferPool> = BufferPool::new(BufferPoolConfig::from_env());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufferPoolConfig {
+    /// Buffers kept for reuse.
+    pub pool_size: usize,
+    /// Mapped from the huge pages reserved in `/proc/sys/vm/nr_hugepages`.
+    pub hugepages: bool,
+}
+
+impl BufferPoolConfig {
+    pub fn from_env() -> BufferPoolConfig {
+        BufferPoolConfig {
+            pool_size: std::env::var("BAGUA_NET_BUFFER_POOL_SIZE")
+                .unwrap_or("32".to_owned())
+                .parse()
+                .unwrap(),
+            hugepages: std::env::var("BAGUA_NET_HUGEPAGES").unwrap_or("0".to_owned()) == "1",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Region {
+    ptr: usize,
+    cap: usize,
+    /// From `mmap`, otherwise from the global allocator.
+    mapped: bool,
+}
+
+impl Region {
+    fn alloc(cap: usize, hugepages: bool) -> Region {
+        if hugepages {
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    cap,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+                    -1,
+                    0,
+                )
+            };
+            if ptr != libc::MAP_FAILED {
+                return Region {
+                    ptr: ptr as usize,
+                    cap,
+                    mapped: true,
+                };
+            }
+            tracing::warn!(
+                "failed to map {} bytes of huge pages, err={:?}",
+                cap,
+                std::io::Error::last_os_error()
+            );
+        }
+        let layout = Layout::from_size_align(cap, ALIGN).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        Region {
+            ptr: ptr as usize,
+            cap,
+            mapped: false,
+        }
+    }
+
+    fn free(self) {
+        if self.mapped {
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.cap) };
+        } else {
+            let layout = Layout::from_size_align(self.cap, ALIGN).unwrap();
+            unsafe { std::alloc::dealloc(self.ptr as *mut u8, layout) };
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BufferPool {
+    config: BufferPoolConfig,
+    /// Smallest first.
+    free: Mutex<Vec<Region>>,
+    leased: AtomicUsize,
+    leased_bytes: AtomicUsize,
+    pooled_bytes: AtomicUsize,
+}
+
+impl BufferPool {
+    pub fn new(config: BufferPoolConfig) -> Arc<BufferPool> {
+        Arc::new(BufferPool {
+            config,
+            free: Default::default(),
+            leased: AtomicUsize::new(0),
+            leased_bytes: AtomicUsize::new(0),
+            pooled_bytes: AtomicUsize::new(0),
+        })
+    }
+
+    /// A buffer of at least `len` bytes, from the pool if it has one.
+    pub fn lease(self: &Arc<Self>, len: usize) -> PooledBuffer {
+        let reused = {
+            let mut free = self.free.lock().unwrap();
+            free.iter()
+                .position(|region| region.cap >= len)
+                .map(|i| free.remove(i))
+        };
+        let region = match reused {
+            Some(region) => {
+                self.pooled_bytes.fetch_sub(region.cap, Ordering::Relaxed);
+                region
+            }
+            None => Region::alloc(len.max(1).div_ceil(ALIGN) * ALIGN, self.config.hugepages),
+        };
+        self.leased.fetch_add(1, Ordering::Relaxed);
+        self.leased_bytes.fetch_add(region.cap, Ordering::Relaxed);
+        PooledBuffer {
+            region: Some(region),
+            pool: self.clone(),
+        }
+    }
+
+    /// Of the buffers out of the pool, that are not back until dropped.
+    pub fn leased(&self) -> usize {
+        self.leased.load(Ordering::Relaxed)
+    }
+
+    pub fn leased_bytes(&self) -> usize {
+        self.leased_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Of the buffers kept for reuse.
+    pub fn pooled_bytes(&self) -> usize {
+        self.pooled_bytes.load(Ordering::Relaxed)
+    }
+
+    fn release(&self, region: Region) {
+        self.leased.fetch_sub(1, Ordering::Relaxed);
+        self.leased_bytes.fetch_sub(region.cap, Ordering::Relaxed);
+        self.pooled_bytes.fetch_add(region.cap, Ordering::Relaxed);
+        let mut free = self.free.lock().unwrap();
+        let i = free.partition_point(|other| other.cap < region.cap);
+        free.insert(i, region);
+        // The smallest are the least likely to fit.
+        if free.len() > self.config.pool_size {
+            let evicted = free.remove(0);
+            drop(free);
+            self.pooled_bytes.fetch_sub(evicted.cap, Ordering::Relaxed);
+            evicted.free();
+        }
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        for region in std::mem::take(&mut *self.free.lock().unwrap()) {
+            region.free();
+        }
+    }
+}
+
+/// A buffer of the pool, back in it once dropped.
+#[derive(Debug)]
+pub struct PooledBuffer {
+    region: Option<Region>,
+    pool: Arc<BufferPool>,
+}
+
+impl PooledBuffer {
+    pub fn ptr(&self) -> usize {
+        self.region.as_ref().unwrap().ptr
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.region.as_ref().unwrap().cap
+    }
+
+    /// Its first `len` bytes, valid until it is dropped.
+    pub fn slice(&self, len: usize) -> &'static mut [u8] {
+        assert!(len <= self.capacity());
+        unsafe { std::slice::from_raw_parts_mut(self.ptr() as *mut u8, len) }
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.release(self.region.take().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(pool_size: usize, hugepages: bool) -> Arc<BufferPool> {
+        BufferPool::new(BufferPoolConfig {
+            pool_size,
+            hugepages,
+        })
+    }
+
+    #[test]
+    fn test_aligned() {
+        // Without huge pages reserved, the mapping falls back to the
+        // allocator.
+        for hugepages in [false, true] {
+            let pool = pool(4, hugepages);
+            for len in [0, 1, 4096, ALIGN, ALIGN + 1, 3 * ALIGN] {
+                let buffer = pool.lease(len);
+                assert_eq!(buffer.ptr() % ALIGN, 0);
+                assert_eq!(buffer.capacity() % ALIGN, 0);
+                assert!(buffer.capacity() >= len.max(1));
+                // Writable end to end.
+                let data = buffer.slice(buffer.capacity());
+                data[0] = 1;
+                data[data.len() - 1] = 2;
+            }
+        }
+    }
+
+    #[test]
+    fn test_reuse() {
+        let pool = pool(2, false);
+        let first = pool.lease(1000);
+        let ptr = first.ptr();
+        assert_eq!((pool.leased(), pool.leased_bytes()), (1, ALIGN));
+        drop(first);
+        assert_eq!((pool.leased(), pool.pooled_bytes()), (0, ALIGN));
+
+        // Reused if it fits.
+        let second = pool.lease(ALIGN);
+        assert_eq!(second.ptr(), ptr);
+        assert_eq!(pool.pooled_bytes(), 0);
+        let larger = pool.lease(ALIGN + 1);
+        assert_ne!(larger.ptr(), ptr);
+        assert_eq!(larger.capacity(), 2 * ALIGN);
+        drop((second, larger));
+
+        let leased: Vec<PooledBuffer> = (1..=4).map(|i| pool.lease(i * ALIGN)).collect();
+        assert_eq!(pool.leased(), 4);
+        drop(leased);
+        // The 1 and 2 huge page leases reuse the pooled buffers, the largest
+        // two of the four are kept.
+        let caps: Vec<usize> = pool
+            .free
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|region| region.cap)
+            .collect();
+        assert_eq!(caps, vec![3 * ALIGN, 4 * ALIGN]);
+        assert_eq!(
+            (pool.leased(), pool.leased_bytes(), pool.pooled_bytes()),
+            (0, 0, 7 * ALIGN)
+        );
+    }
+}
@@ -0,0 +1,259 @@
+//! LZ4 compression of the data stream chunks, with
+//! `BAGUA_NET_COMPRESSION=lz4`. Every chunk goes out as a frame, a
+//! `FrameHeader` and the chunk, compressed unless it is under
+//! `BAGUA_NET_COMPRESSION_THRESHOLD` bytes or does not shrink. The chunks
+//! are compressed and decompressed on `BAGUA_NET_COMPRESSION_THREADS` threads
+//! of their own, so that a comm does that on several cores while its event
+//! loop writes and reads the chunks before them.
+
+use crate::interface::BaguaNetError;
+use std::sync::Arc;
+
+/// Ahead of every chunk of a comm that compresses them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// Of the chunk.
+    pub nbytes: u64,
+    /// What follows the header.
+    pub wire_nbytes: u64,
+    /// Unless the chunk follows as is.
+    pub compressed: bool,
+}
+
+impl FrameHeader {
+    pub const NBYTES: usize = 8 + 8;
+    /// Of `wire_nbytes`, on the wire.
+    const COMPRESSED: u64 = 1 << 63;
+
+    pub fn to_bytes(self) -> [u8; FrameHeader::NBYTES] {
+        let wire_nbytes = match self.compressed {
+            true => self.wire_nbytes | FrameHeader::COMPRESSED,
+            false => self.wire_nbytes,
+        };
+        let mut buf = [0u8; FrameHeader::NBYTES];
+        buf[..8].copy_from_slice(&self.nbytes.to_be_bytes());
+        buf[8..].copy_from_slice(&wire_nbytes.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; FrameHeader::NBYTES]) -> FrameHeader {
+        let mut nbytes = [0u8; 8];
+        let mut wire_nbytes = [0u8; 8];
+        nbytes.copy_from_slice(&buf[..8]);
+        wire_nbytes.copy_from_slice(&buf[8..]);
+        let wire_nbytes = u64::from_be_bytes(wire_nbytes);
+        FrameHeader {
+            nbytes: u64::from_be_bytes(nbytes),
+            wire_nbytes: wire_nbytes & !FrameHeader::COMPRESSED,
+            compressed: wire_nbytes & FrameHeader::COMPRESSED != 0,
+        }
+    }
+
+    /// Unless it cannot be the header of a chunk of `nbytes`, e.g. the data
+    /// streams are out of sync.
+    pub fn check(&self, nbytes: usize) -> Result<(), BaguaNetError> {
+        let fits = match self.compressed {
+            true => self.wire_nbytes as usize <= max_compressed_len(nbytes),
+            false => self.wire_nbytes == self.nbytes,
+        };
+        if self.nbytes != nbytes as u64 || !fits {
+            return Err(BaguaNetError::remote(format!(
+                "bad frame header {:?} for a chunk of {} bytes",
+                self, nbytes
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn max_compressed_len(nbytes: usize) -> usize {
+    lz4_flex::block::get_maximum_output_size(nbytes)
+}
+
+/// Of the frame of a chunk of `nbytes`, either way.
+pub fn max_frame_len(nbytes: usize) -> usize {
+    FrameHeader::NBYTES + max_compressed_len(nbytes).max(nbytes)
+}
+
+/// Writes the frame of `data` to the start of `frame`, of at least
+/// `max_frame_len` bytes, compressed if `compress` and it shrinks. Returns
+/// its length.
+pub fn write_frame(data: &[u8], frame: &mut [u8], compress: bool) -> usize {
+    let (header, body) = frame.split_at_mut(FrameHeader::NBYTES);
+    if compress {
+        match lz4_flex::block::compress_into(data, body) {
+            Ok(n) if n < data.len() => {
+                let frame_header = FrameHeader {
+                    nbytes: data.len() as u64,
+                    wire_nbytes: n as u64,
+                    compressed: true,
+                };
+                header.copy_from_slice(&frame_header.to_bytes());
+                return FrameHeader::NBYTES + n;
+            }
+            Ok(_) => {}
+            Err(err) => tracing::debug!("failed to compress {} bytes, err={:?}", data.len(), err),
+        }
+    }
+    let frame_header = FrameHeader {
+        nbytes: data.len() as u64,
+        wire_nbytes: data.len() as u64,
+        compressed: false,
+    };
+    header.copy_from_slice(&frame_header.to_bytes());
+    body[..data.len()].copy_from_slice(data);
+    FrameHeader::NBYTES + data.len()
+}
+
+/// Of a compressed chunk, into the `out` it was compressed from.
+pub fn decompress(wire: &[u8], out: &mut [u8]) -> Result<(), BaguaNetError> {
+    match lz4_flex::block::decompress_into(wire, out) {
+        Ok(n) if n == out.len() => Ok(()),
+        ret => Err(BaguaNetError::Corruption(format!(
+            "lz4 frame of {} bytes does not decompress to {} bytes, got {:?}",
+            wire.len(),
+            out.len(),
+            ret
+        ))),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// Smaller chunks are not worth compressing.
+    pub threshold: usize,
+    pub threads: usize,
+}
+
+impl CompressionConfig {
+    /// Unless `BAGUA_NET_COMPRESSION` is unset or `none`.
+    pub fn from_env() -> Option<CompressionConfig> {
+        match std::env::var("BAGUA_NET_COMPRESSION")
+            .unwrap_or("none".to_owned())
+            .as_str()
+        {
+            "lz4" => {}
+            "none" | "" => return None,
+            other => {
+                tracing::warn!("unknown BAGUA_NET_COMPRESSION={}, not compressing", other);
+                return None;
+            }
+        }
+        Some(CompressionConfig {
+            threshold: std::env::var("BAGUA_NET_COMPRESSION_THRESHOLD")
+                .unwrap_or("65536".to_owned())
+                .parse()
+                .unwrap(),
+            threads: std::env::var("BAGUA_NET_COMPRESSION_THREADS")
+                .unwrap_or("4".to_owned())
+                .parse()
+                .unwrap(),
+        })
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The threads that compress and decompress the chunks of every comm.
+pub struct Compression {
+    threshold: usize,
+    jobs: flume::Sender<Job>,
+}
+
+impl Compression {
+    pub fn new(config: CompressionConfig) -> Arc<Compression> {
+        let (jobs, queued) = flume::unbounded::<Job>();
+        for _ in 0..config.threads.max(1) {
+            let queued = queued.clone();
+            // They stop once this is dropped.
+            std::thread::spawn(move || {
+                while let Ok(job) = queued.recv() {
+                    job();
+                }
+            });
+        }
+
+        Arc::new(Compression {
+            threshold: config.threshold,
+            jobs,
+        })
+    }
+
+    pub fn from_env() -> Option<Arc<Compression>> {
+        CompressionConfig::from_env().map(Compression::new)
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        let _ = self.jobs.send(Box::new(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_header_bytes() {
+        let header = FrameHeader {
+            nbytes: 1 << 20,
+            wire_nbytes: 12345,
+            compressed: true,
+        };
+        assert_eq!(FrameHeader::from_bytes(&header.to_bytes()), header);
+        assert!(header.check(1 << 20).is_ok());
+        assert!(header.check(1 << 19).is_err());
+
+        let raw = FrameHeader {
+            wire_nbytes: 1 << 20,
+            compressed: false,
+            ..header
+        };
+        assert_eq!(FrameHeader::from_bytes(&raw.to_bytes()), raw);
+        assert!(raw.check(1 << 20).is_ok());
+        let short = FrameHeader {
+            wire_nbytes: 100,
+            ..raw
+        };
+        assert!(short.check(1 << 20).is_err());
+    }
+
+    #[test]
+    fn test_write_frame() {
+        let compressible: Vec<u8> = (0..1 << 16).map(|i| (i / 64) as u8).collect();
+        let mut buf = vec![0u8; max_frame_len(compressible.len())];
+        let n = write_frame(&compressible, &mut buf, true);
+        let frame = &buf[..n];
+        let mut bytes = [0u8; FrameHeader::NBYTES];
+        bytes.copy_from_slice(&frame[..FrameHeader::NBYTES]);
+        let header = FrameHeader::from_bytes(&bytes);
+        assert!(header.compressed);
+        assert_eq!(header.nbytes, compressible.len() as u64);
+        assert_eq!(
+            frame.len(),
+            FrameHeader::NBYTES + header.wire_nbytes as usize
+        );
+        assert!(frame.len() < compressible.len() / 4);
+        let mut out = vec![0u8; compressible.len()];
+        decompress(&frame[FrameHeader::NBYTES..], &mut out).unwrap();
+        assert_eq!(out, compressible);
+        assert!(decompress(&frame[FrameHeader::NBYTES..], &mut out[1..]).is_err());
+
+        // Random bytes do not shrink, and go as they are.
+        let random: Vec<u8> = (0..1 << 16).map(|_| rand::random::<u8>()).collect();
+        let n = write_frame(&random, &mut buf, true);
+        let frame = &buf[..n];
+        bytes.copy_from_slice(&frame[..FrameHeader::NBYTES]);
+        assert!(!FrameHeader::from_bytes(&bytes).compressed);
+        assert_eq!(&frame[FrameHeader::NBYTES..], &random[..]);
+
+        let n = write_frame(&compressible, &mut buf, false);
+        let frame = &buf[..n];
+        bytes.copy_from_slice(&frame[..FrameHeader::NBYTES]);
+        assert!(!FrameHeader::from_bytes(&bytes).compressed);
+        assert_eq!(&frame[FrameHeader::NBYTES..], &compressible[..]);
+    }
+}
@@ -0,0 +1,4367 @@
+use crate::interface::{BaguaNetError, SocketHandle};
+use crate::tls::{TlsConfig, TlsStream};
+use crate::utils::{self, LiveThreads, NCCLSocketDev};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{InetAddr, SockAddr, UnixAddr};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rustls::pki_types::ServerName;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net;
+use std::net::ToSocketAddrs;
+use std::ops::RangeInclusive;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Of the events of listeners, comms and their streams being opened and
+/// closed, logged at the level of `BAGUA_NET_LOG_CONNECTIONS` and those less
+/// verbose, `info` by default, none with `off`. They carry the UUID of the
+/// comm, and where they are known, its ID, that metrics and spans have.
+pub const LOG_TARGET: &str = "bagua_net::connections";
+
+lazy_static! {
+    static ref CONNECTION_LOG_LEVEL: Option<tracing::Level> =
+        parse_log_level(&std::env::var("BAGUA_NET_LOG_CONNECTIONS").unwrap_or("info".to_owned()))
+            .unwrap();
+}
+
+/// Like `warn`, `Some(None)` for `off`.
+pub fn parse_log_level(level: &str) -> Option<Option<tracing::Level>> {
+    match level.trim() {
+        "off" => Some(None),
+        level => level.parse().ok().map(Some),
+    }
+}
+
+pub fn logs_connections(level: tracing::Level) -> bool {
+    CONNECTION_LOG_LEVEL.is_some_and(|max| level <= max)
+}
+
+/// Of `LOG_TARGET`, at the level named like `INFO`.
+macro_rules! connection_event {
+    ($level:ident, $($event:tt)+) => {
+        if $crate::connection::logs_connections(tracing::Level::$level) {
+            tracing::event!(
+                target: $crate::connection::LOG_TARGET,
+                tracing::Level::$level,
+                $($event)+
+            );
+        }
+    };
+}
+
+/// Written by `connect()` on every socket it opens, so that `accept()` can
+/// tell which send comm a socket belongs to and where it goes in that comm.
+///
+/// Data streams carry `stream_id` in `0..nstreams`, the master (ctrl) stream
+/// carries `CTRL_STREAM_ID` so that it is recognized whatever the peer's
+/// nstreams is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamHandshake {
+    pub comm_uuid: Uuid,
+    pub stream_id: usize,
+}
+
+impl StreamHandshake {
+    pub const NBYTES: usize = 16 + 8;
+    pub const CTRL_STREAM_ID: usize = u64::MAX as usize;
+
+    pub fn to_bytes(self) -> [u8; StreamHandshake::NBYTES] {
+        let mut buf = [0u8; StreamHandshake::NBYTES];
+        buf[..16].copy_from_slice(self.comm_uuid.as_bytes());
+        buf[16..].copy_from_slice(&(self.stream_id as u64).to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; StreamHandshake::NBYTES]) -> StreamHandshake {
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&buf[..16]);
+        let mut stream_id = [0u8; 8];
+        stream_id.copy_from_slice(&buf[16..]);
+
+        StreamHandshake {
+            comm_uuid: Uuid::from_bytes(uuid),
+            stream_id: u64::from_be_bytes(stream_id) as usize,
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        stream.write_all(&self.to_bytes()[..])
+    }
+
+    pub fn read_from<R: Read>(stream: &mut R) -> io::Result<StreamHandshake> {
+        let mut buf = [0u8; StreamHandshake::NBYTES];
+        stream.read_exact(&mut buf[..])?;
+
+        Ok(StreamHandshake::from_bytes(&buf))
+    }
+
+    /// `ctrl` for the master stream, the index of a data stream otherwise.
+    pub fn stream_name(&self) -> String {
+        match self.stream_id {
+            StreamHandshake::CTRL_STREAM_ID => "ctrl".to_owned(),
+            stream_id => stream_id.to_string(),
+        }
+    }
+}
+
+/// Length header on the master stream telling the receiver that the send
+/// comm was closed, after its last message. No message is that large.
+pub const CLOSE_NBYTES: usize = usize::MAX;
+/// Like `CLOSE_NBYTES`, but the streams stay open in the sender's connection
+/// cache, see `ConnCacheConfig`.
+pub const PARK_NBYTES: usize = usize::MAX - 1;
+/// Sent on parked streams when a new send comm takes them over.
+pub const REVIVE_NBYTES: usize = usize::MAX - 2;
+/// The sender opened the next data stream, see `AdaptiveStreamsConfig`. The
+/// messages that follow are spread over it too.
+pub const GROW_NBYTES: usize = usize::MAX - 3;
+/// Or'ed with the size of every message of the send comm from then on, see
+/// `Net::set_fixed_message_size`. The receiver answers with the size, or 0
+/// if it does not expect it, and the sizes no longer go on the master
+/// stream. Before `CLOSE_NBYTES` or `PARK_NBYTES`, how many messages it sent
+/// since does.
+pub const FIXED_NBYTES: usize = 1 << 62;
+/// Or'ed with the `min_chunksize` that the messages of the send comm are
+/// split by from then on, see `split_tuning`.
+pub const SPLIT_NBYTES: usize = 1 << 61;
+/// Or'ed with the index of a message that was cancelled while it was being
+/// sent, counted from the first of the send comm: the receiver fails the
+/// receive it matched. In place of the size of an inlined one not written
+/// yet, which then fails the next receive. The zeros written for the rest of
+/// the others follow it, a receive that has all its chunks before the
+/// receiver reads it completes.
+pub const CANCEL_NBYTES: usize = 1 << 60;
+
+/// The size announced by a `FIXED_NBYTES` length header.
+pub fn fixed_nbytes(header: usize) -> Option<usize> {
+    (header & FIXED_NBYTES != 0 && header < GROW_NBYTES).then_some(header & !FIXED_NBYTES)
+}
+
+/// The `min_chunksize` announced by a `SPLIT_NBYTES` length header.
+pub fn split_nbytes(header: usize) -> Option<usize> {
+    (header & SPLIT_NBYTES != 0 && header < FIXED_NBYTES).then_some(header & !SPLIT_NBYTES)
+}
+
+/// The index of the message a `CANCEL_NBYTES` length header tells.
+pub fn cancel_nbytes(header: usize) -> Option<usize> {
+    (header & CANCEL_NBYTES != 0 && header < SPLIT_NBYTES).then_some(header & !CANCEL_NBYTES)
+}
+
+/// Exchanged on the master stream right after its `StreamHandshake`, before
+/// any data stream is opened: the connector sends its own, the acceptor
+/// answers with its own, and both sides refuse a peer that differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommHandshake {
+    pub magic: u32,
+    pub version: u32,
+    pub nstreams: u32,
+    /// Where the acceptor takes the replacements of broken data streams, 0 if
+    /// it does not. Always 0 from the connector.
+    pub reconnect_port: u32,
+    /// 1 if the streams continue in TLS sessions after their handshakes.
+    pub tls: u32,
+    /// Messages up to this size follow their length header on the master
+    /// stream instead of going over the data streams.
+    pub inline_threshold: u32,
+    /// With `nstreams` and `chunk_bytes`, how both sides split a message in
+    /// chunks, see `utils::chunk_size`. `AUTO_MIN_CHUNKSIZE` if the sender
+    /// announces it, see `SPLIT_NBYTES`.
+    pub min_chunksize: u32,
+    /// 0 if messages are split in `nstreams` chunks.
+    pub chunk_bytes: u32,
+    /// 1 if every chunk follows its `ChunkHeader` on the data stream.
+    pub seq_check: u32,
+    /// 1 if every message is followed by its CRC32C on the master stream.
+    pub crc: u32,
+    /// 1 if the data stream chunks go in LZ4 frames, see `compression`.
+    pub compression: u32,
+    /// How many data streams the comm may grow to, 0 if it does not.
+    pub max_nstreams: u32,
+    /// Of `WORK_STEALING` and the like, offered by either side. Both use
+    /// those both offered, see `negotiated`, the others do not have to match.
+    pub capabilities: u32,
+    /// The most the acceptor's streams buffer on receive, see
+    /// `Listener::rcvbuf`. Always 0 from the connector.
+    pub rcvbuf: u32,
+}
+
+impl CommHandshake {
+    pub const NBYTES: usize = 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4;
+    /// "BGNT"
+    pub const MAGIC: u32 = 0x4247_4e54;
+    /// Bump whenever the bytes on the wire change.
+    pub const VERSION: u32 = 15;
+    /// With `BAGUA_NET_MIN_CHUNKSIZE=auto`.
+    pub const AUTO_MIN_CHUNKSIZE: usize = u32::MAX as usize;
+    /// The data streams take the next chunk of the comm when they can write
+    /// it, each after its `ChunkHeader`, instead of every other one.
+    pub const WORK_STEALING: u32 = 1;
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn local(
+        nstreams: usize,
+        tls: bool,
+        inline_threshold: usize,
+        min_chunksize: usize,
+        chunk_bytes: usize,
+        seq_check: bool,
+        crc: bool,
+        compression: bool,
+        max_nstreams: usize,
+    ) -> CommHandshake {
+        CommHandshake {
+            magic: CommHandshake::MAGIC,
+            version: CommHandshake::VERSION,
+            nstreams: nstreams as u32,
+            reconnect_port: 0,
+            tls: tls as u32,
+            inline_threshold: inline_threshold as u32,
+            min_chunksize: min_chunksize as u32,
+            chunk_bytes: chunk_bytes as u32,
+            seq_check: seq_check as u32,
+            crc: crc as u32,
+            compression: compression as u32,
+            max_nstreams: max_nstreams as u32,
+            capabilities: 0,
+            rcvbuf: 0,
+        }
+    }
+
+    /// Of the capabilities, those both sides offered.
+    pub fn negotiated(&self, peer: &CommHandshake) -> u32 {
+        self.capabilities & peer.capabilities
+    }
+
+    pub fn to_bytes(self) -> [u8; CommHandshake::NBYTES] {
+        let mut buf = [0u8; CommHandshake::NBYTES];
+        buf[..4].copy_from_slice(&self.magic.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.version.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.nstreams.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.reconnect_port.to_be_bytes());
+        buf[16..20].copy_from_slice(&self.tls.to_be_bytes());
+        buf[20..24].copy_from_slice(&self.inline_threshold.to_be_bytes());
+        buf[24..28].copy_from_slice(&self.min_chunksize.to_be_bytes());
+        buf[28..32].copy_from_slice(&self.max_nstreams.to_be_bytes());
+        buf[32..36].copy_from_slice(&self.chunk_bytes.to_be_bytes());
+        buf[36..40].copy_from_slice(&self.seq_check.to_be_bytes());
+        buf[40..44].copy_from_slice(&self.crc.to_be_bytes());
+        buf[44..48].copy_from_slice(&self.compression.to_be_bytes());
+        buf[48..52].copy_from_slice(&self.capabilities.to_be_bytes());
+        buf[52..].copy_from_slice(&self.rcvbuf.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; CommHandshake::NBYTES]) -> CommHandshake {
+        let field = |i: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&buf[i * 4..(i + 1) * 4]);
+            u32::from_be_bytes(bytes)
+        };
+
+        CommHandshake {
+            magic: field(0),
+            version: field(1),
+            nstreams: field(2),
+            reconnect_port: field(3),
+            tls: field(4),
+            inline_threshold: field(5),
+            min_chunksize: field(6),
+            chunk_bytes: field(8),
+            seq_check: field(9),
+            crc: field(10),
+            compression: field(11),
+            max_nstreams: field(7),
+            capabilities: field(12),
+            rcvbuf: field(13),
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        stream.write_all(&self.to_bytes()[..])
+    }
+
+    pub fn read_from<R: Read>(stream: &mut R) -> io::Result<CommHandshake> {
+        let mut buf = [0u8; CommHandshake::NBYTES];
+        stream.read_exact(&mut buf[..4])?;
+        // The challenge of an acceptor that requires authentication may be
+        // shorter than a handshake, `check` reports it from the magic alone.
+        if buf[..4] != AuthKey::MAGIC.to_be_bytes() {
+            stream.read_exact(&mut buf[4..])?;
+        }
+
+        Ok(CommHandshake::from_bytes(&buf))
+    }
+
+    /// Checks that `peer` speaks the same protocol as `self`.
+    pub fn check(&self, peer: &CommHandshake) -> Result<(), BaguaNetError> {
+        if peer.magic == AuthKey::MAGIC {
+            return Err(BaguaNetError::remote(
+                "peer requires authentication, BAGUA_NET_AUTH_KEY must be set on both sides"
+                    .to_owned(),
+            ));
+        }
+        if peer.magic != self.magic {
+            return Err(BaguaNetError::remote(format!(
+                "bad magic {:#x} from peer, expected {:#x}, the peer is not bagua-net",
+                peer.magic, self.magic
+            )));
+        }
+        if peer.version != self.version {
+            return Err(BaguaNetError::remote(format!(
+                "protocol version mismatch, local version={}, peer version={}",
+                self.version, peer.version
+            )));
+        }
+        if peer.nstreams != self.nstreams {
+            return Err(BaguaNetError::remote(format!(
+                "nstreams mismatch, local nstreams={}, peer nstreams={} (version={})",
+                self.nstreams, peer.nstreams, self.version
+            )));
+        }
+        if peer.tls != self.tls {
+            return Err(BaguaNetError::remote(format!(
+                "TLS mismatch, local tls={}, peer tls={}, BAGUA_NET_TLS_* must be set on both sides",
+                self.tls, peer.tls
+            )));
+        }
+        if peer.inline_threshold != self.inline_threshold {
+            return Err(BaguaNetError::remote(format!(
+                "inline threshold mismatch, local inline_threshold={}, peer inline_threshold={}, BAGUA_NET_INLINE_THRESHOLD must be the same on both sides",
+                self.inline_threshold, peer.inline_threshold
+            )));
+        }
+        // Both sides would cut messages differently and read garbage.
+        if peer.min_chunksize != self.min_chunksize {
+            return Err(BaguaNetError::remote(format!(
+                "min chunksize mismatch, local min_chunksize={}, peer min_chunksize={}, BAGUA_NET_MIN_CHUNKSIZE must be the same on both sides",
+                self.min_chunksize, peer.min_chunksize
+            )));
+        }
+        if peer.chunk_bytes != self.chunk_bytes {
+            return Err(BaguaNetError::remote(format!(
+                "chunk bytes mismatch, local chunk_bytes={}, peer chunk_bytes={}, BAGUA_NET_CHUNK_BYTES must be the same on both sides",
+                self.chunk_bytes, peer.chunk_bytes
+            )));
+        }
+        if peer.seq_check != self.seq_check {
+            return Err(BaguaNetError::remote(format!(
+                "seq check mismatch, local seq_check={}, peer seq_check={}, BAGUA_NET_SEQ_CHECK must be the same on both sides",
+                self.seq_check, peer.seq_check
+            )));
+        }
+        if peer.crc != self.crc {
+            return Err(BaguaNetError::remote(format!(
+                "crc mismatch, local crc={}, peer crc={}, BAGUA_NET_CRC must be the same on both sides",
+                self.crc, peer.crc
+            )));
+        }
+        if peer.compression != self.compression {
+            return Err(BaguaNetError::remote(format!(
+                "compression mismatch, local compression={}, peer compression={}, BAGUA_NET_COMPRESSION must be the same on both sides",
+                self.compression, peer.compression
+            )));
+        }
+        if peer.max_nstreams != self.max_nstreams {
+            return Err(BaguaNetError::remote(format!(
+                "max nstreams mismatch, local max_nstreams={}, peer max_nstreams={}, BAGUA_NET_NSTREAMS=auto and BAGUA_NET_MAX_NSTREAMS must be the same on both sides",
+                self.max_nstreams, peer.max_nstreams
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Ahead of every chunk on a data stream with `BAGUA_NET_SEQ_CHECK=1`, so
+/// that a receiver whose streams are out of sync with the sender's fails the
+/// message instead of filling it with the wrong chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHeader {
+    /// Of the chunked messages of the comm, counting from 0.
+    pub message: u32,
+    /// Of the chunk in its message.
+    pub index: u32,
+    pub nbytes: u64,
+}
+
+impl ChunkHeader {
+    pub const NBYTES: usize = 4 + 4 + 8;
+
+    pub fn to_bytes(self) -> [u8; ChunkHeader::NBYTES] {
+        let mut buf = [0u8; ChunkHeader::NBYTES];
+        buf[..4].copy_from_slice(&self.message.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.index.to_be_bytes());
+        buf[8..].copy_from_slice(&self.nbytes.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; ChunkHeader::NBYTES]) -> ChunkHeader {
+        let mut message = [0u8; 4];
+        let mut index = [0u8; 4];
+        let mut nbytes = [0u8; 8];
+        message.copy_from_slice(&buf[..4]);
+        index.copy_from_slice(&buf[4..8]);
+        nbytes.copy_from_slice(&buf[8..]);
+        ChunkHeader {
+            message: u32::from_be_bytes(message),
+            index: u32::from_be_bytes(index),
+            nbytes: u64::from_be_bytes(nbytes),
+        }
+    }
+}
+
+/// The secret of `BAGUA_NET_AUTH_KEY`. When it is set, the acceptor of every
+/// stream challenges the connector with a nonce right after the
+/// `StreamHandshake`, and drops the stream unless the connector answers with
+/// its HMAC.
+#[derive(Clone)]
+pub struct AuthKey(Arc<Vec<u8>>);
+
+impl fmt::Debug for AuthKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthKey(..)")
+    }
+}
+
+impl AuthKey {
+    /// "BGNA", leads the challenge so that a connector without the key can
+    /// tell it from a `CommHandshake`.
+    pub const MAGIC: u32 = 0x4247_4e41;
+    pub const NONCE_NBYTES: usize = 32;
+    /// HMAC-SHA256
+    pub const MAC_NBYTES: usize = 32;
+
+    pub fn from_env() -> Option<AuthKey> {
+        std::env::var("BAGUA_NET_AUTH_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())
+            .map(|key| AuthKey(Arc::new(key.into_bytes())))
+    }
+
+    fn mac(&self, nonce: &[u8], handshake: &StreamHandshake) -> io::Result<Vec<u8>> {
+        let key = PKey::hmac(&self.0)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(nonce)?;
+        signer.update(&handshake.to_bytes()[..])?;
+
+        Ok(signer.sign_to_vec()?)
+    }
+
+    /// Run by the acceptor once it read `handshake` from `stream`.
+    pub fn challenge<S: Read + Write>(
+        &self,
+        stream: &mut S,
+        handshake: &StreamHandshake,
+    ) -> io::Result<()> {
+        let challenge = AuthKey::new_challenge()?;
+        stream.write_all(&challenge[..])?;
+
+        let mut mac = [0u8; AuthKey::MAC_NBYTES];
+        stream.read_exact(&mut mac[..])?;
+        self.check_answer(&challenge, &mac, handshake)
+    }
+
+    /// The magic and a fresh nonce.
+    fn new_challenge() -> io::Result<[u8; 4 + AuthKey::NONCE_NBYTES]> {
+        let mut challenge = [0u8; 4 + AuthKey::NONCE_NBYTES];
+        challenge[..4].copy_from_slice(&AuthKey::MAGIC.to_be_bytes());
+        openssl::rand::rand_bytes(&mut challenge[4..])?;
+        Ok(challenge)
+    }
+
+    fn check_answer(
+        &self,
+        challenge: &[u8],
+        mac: &[u8],
+        handshake: &StreamHandshake,
+    ) -> io::Result<()> {
+        if !openssl::memcmp::eq(mac, &self.mac(&challenge[4..], handshake)?) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "authentication failed, bad mac",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Run by the connector once it wrote `handshake` to `stream`.
+    pub fn answer<S: Read + Write>(
+        &self,
+        stream: &mut S,
+        handshake: &StreamHandshake,
+    ) -> io::Result<()> {
+        let mut challenge = [0u8; 4 + AuthKey::NONCE_NBYTES];
+        stream.read_exact(&mut challenge[..])?;
+        let mut magic = [0u8; 4];
+        magic.copy_from_slice(&challenge[..4]);
+        let magic = u32::from_be_bytes(magic);
+        if magic != AuthKey::MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad challenge magic {:#x}", magic),
+            ));
+        }
+
+        stream.write_all(&self.mac(&challenge[4..], handshake)?)
+    }
+}
+
+/// A stream of a comm, over TCP, or over a unix socket when both ends are on
+/// the same host and `BAGUA_NET_ENABLE_UDS=1`, inside a TLS session with
+/// `BAGUA_NET_TLS_*`.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(net::TcpStream),
+    Unix(UnixStream),
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Tls(stream) => stream.stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// A no-op on unix sockets, they do not delay small writes.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nodelay(nodelay),
+            Stream::Unix(_) => Ok(()),
+            Stream::Tls(stream) => stream.stream.set_nodelay(nodelay),
+        }
+    }
+
+    /// Acks what arrives right away instead of waiting to piggyback on a
+    /// write. Linux clears it as it acks, so it is set again after reads.
+    /// A no-op on unix sockets.
+    pub fn set_quickack(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => SockRef::from(stream).set_quickack(true),
+            Stream::Unix(_) => Ok(()),
+            Stream::Tls(stream) => stream.stream.set_quickack(),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            Stream::Tls(stream) => stream.stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+            Stream::Tls(stream) => stream.stream.shutdown(how),
+        }
+    }
+
+    /// Whether the stream is between two processes on the same host.
+    pub fn is_unix(&self) -> bool {
+        match self {
+            Stream::Tcp(_) => false,
+            Stream::Unix(_) => true,
+            Stream::Tls(stream) => stream.stream.is_unix(),
+        }
+    }
+
+    /// Whether the bytes on the socket are not those written to the stream.
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Stream::Tls(_))
+    }
+
+    /// Who is on the other end, for logging.
+    pub fn peer(&self) -> String {
+        match self {
+            Stream::Tcp(stream) => format!("{:?}", stream.peer_addr()),
+            Stream::Unix(stream) => format!("unix {:?}", stream.peer_addr()),
+            Stream::Tls(stream) => format!("tls {}", stream.stream.peer()),
+        }
+    }
+
+    /// Of who is on the other end, for metric labels. Unix sockets of a comm
+    /// are unnamed.
+    pub fn peer_addr(&self) -> String {
+        match self {
+            Stream::Tcp(stream) => stream
+                .peer_addr()
+                .map_or("unknown".to_owned(), |addr| addr.to_string()),
+            Stream::Unix(_) => "unix".to_owned(),
+            Stream::Tls(stream) => stream.stream.peer_addr(),
+        }
+    }
+
+    /// For the backends that only speak TCP.
+    pub fn into_tcp(self) -> Result<net::TcpStream, BaguaNetError> {
+        match self {
+            Stream::Tcp(stream) => Ok(stream),
+            others => Err(BaguaNetError::InnerError(format!(
+                "unexpected stream {:?}, not plain TCP",
+                others
+            ))),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write_vectored(bufs),
+            Stream::Unix(stream) => stream.write_vectored(bufs),
+            Stream::Tls(stream) => stream.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+            Stream::Tls(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+/// Which receiving streams re-arm `TCP_QUICKACK` after their reads, from
+/// `BAGUA_NET_QUICKACK`: `0`, `1` for the master stream, or `all`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuickAck {
+    Off,
+    Master,
+    All,
+}
+
+impl QuickAck {
+    pub fn from_env() -> QuickAck {
+        match std::env::var("BAGUA_NET_QUICKACK")
+            .unwrap_or("1".to_owned())
+            .as_str()
+        {
+            "0" => QuickAck::Off,
+            "1" => QuickAck::Master,
+            "all" => QuickAck::All,
+            others => panic!("BAGUA_NET_QUICKACK={:?}, expected 0, 1 or all", others),
+        }
+    }
+}
+
+/// TCP keepalive on every stream, so that a connection whose peer is gone,
+/// or whose conntrack entry a firewall dropped while it was idle, fails
+/// instead of hanging forever.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub count: u32,
+}
+
+impl KeepaliveConfig {
+    /// `None` when disabled with `BAGUA_NET_KEEPALIVE=0`.
+    pub fn from_env() -> Option<KeepaliveConfig> {
+        if std::env::var("BAGUA_NET_KEEPALIVE").unwrap_or("1".to_owned()) == "0" {
+            return None;
+        }
+
+        Some(KeepaliveConfig {
+            idle: Duration::from_secs(
+                std::env::var("BAGUA_NET_KEEPALIVE_IDLE_SECS")
+                    .unwrap_or("60".to_owned())
+                    .parse()
+                    .unwrap(),
+            ),
+            interval: Duration::from_secs(
+                std::env::var("BAGUA_NET_KEEPALIVE_INTERVAL_SECS")
+                    .unwrap_or("10".to_owned())
+                    .parse()
+                    .unwrap(),
+            ),
+            count: std::env::var("BAGUA_NET_KEEPALIVE_COUNT")
+                .unwrap_or("5".to_owned())
+                .parse()
+                .unwrap(),
+        })
+    }
+
+    pub fn apply(&self, stream: &net::TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        socket.set_tcp_keepalive(
+            &TcpKeepalive::new()
+                .with_time(self.idle)
+                .with_interval(self.interval)
+                .with_retries(self.count),
+        )?;
+        // Probes are only sent on idle connections, data that is never
+        // acknowledged needs its own bound.
+        socket.set_tcp_user_timeout(Some(self.idle + self.interval * self.count))
+    }
+}
+
+fn set_keepalive(stream: &Stream, keepalive: &Option<KeepaliveConfig>) {
+    // A unix peer cannot vanish without the kernel noticing.
+    let stream = match stream {
+        Stream::Tcp(stream) => stream,
+        Stream::Unix(_) => return,
+        Stream::Tls(stream) => return set_keepalive(&stream.stream, keepalive),
+    };
+    if let Some(keepalive) = keepalive {
+        if let Err(err) = keepalive.apply(stream) {
+            tracing::warn!(
+                "set keepalive {:?} on {:?} failed, err={:?}",
+                keepalive,
+                stream.peer_addr(),
+                err
+            );
+        }
+    }
+}
+
+/// `BAGUA_NET_TCP_CONGESTION`, e.g. `cubic` or `dctcp`, the kernel default
+/// unless set.
+fn tcp_congestion() -> Option<String> {
+    std::env::var("BAGUA_NET_TCP_CONGESTION")
+        .ok()
+        .filter(|algo| !algo.is_empty())
+}
+
+/// Of `TCP_CA_NAME_MAX` in `net/tcp.h`.
+const TCP_CA_NAME_MAX: usize = 16;
+
+fn set_tcp_congestion(fd: RawFd, algo: &str) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            algo.as_ptr() as *const libc::c_void,
+            algo.len() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn get_tcp_congestion(fd: RawFd) -> io::Result<String> {
+    let mut name = [0u8; TCP_CA_NAME_MAX];
+    let mut len = name.len() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            name.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let name = &name[..len as usize];
+    let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    Ok(String::from_utf8_lossy(&name[..end]).into_owned())
+}
+
+/// Of a TCP stream, e.g. its round trip time and congestion window.
+#[cfg(target_os = "linux")]
+pub fn tcp_info(fd: RawFd) -> io::Result<libc::tcp_info> {
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info)
+}
+
+/// Sets the congestion control of a TCP stream of `handshake`, and logs the
+/// one it ends up with. An algorithm the kernel rejects, e.g. one that is
+/// not loaded or not in `net.ipv4.tcp_allowed_congestion_control`, leaves
+/// the default.
+fn set_congestion(stream: &Stream, handshake: &StreamHandshake, congestion: &Option<String>) {
+    if stream.is_unix() {
+        return;
+    }
+    let fd = stream.as_raw_fd();
+    if let Some(algo) = congestion {
+        if let Err(err) = set_tcp_congestion(fd, algo) {
+            tracing::warn!(
+                "BAGUA_NET_TCP_CONGESTION={:?} rejected on {:?} with {}, the default is kept, err={:?}",
+                algo,
+                handshake,
+                stream.peer(),
+                err
+            );
+        }
+    }
+    match get_tcp_congestion(fd) {
+        Ok(effective) if congestion.is_some() => tracing::info!(
+            "{:?} with {} uses TCP congestion control {}",
+            handshake,
+            stream.peer(),
+            effective
+        ),
+        Ok(effective) => tracing::debug!(
+            "{:?} with {} uses TCP congestion control {}",
+            handshake,
+            stream.peer(),
+            effective
+        ),
+        Err(err) => tracing::debug!(
+            "TCP congestion control of {:?} unknown, err={:?}",
+            handshake,
+            err
+        ),
+    }
+}
+
+/// Kernel socket buffer sizes of every stream, left to the kernel defaults
+/// unless set. Needed to fill fat pipes with a high RTT.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketBufferConfig {
+    pub sndbuf: Option<usize>,
+    pub rcvbuf: Option<usize>,
+}
+
+impl SocketBufferConfig {
+    pub fn from_env() -> SocketBufferConfig {
+        let size = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|size| size.parse::<usize>().unwrap())
+        };
+
+        SocketBufferConfig {
+            sndbuf: size("BAGUA_NET_SNDBUF_BYTES"),
+            rcvbuf: size("BAGUA_NET_RCVBUF_BYTES"),
+        }
+    }
+
+    /// Must happen before connect/listen, the window scale is negotiated in
+    /// the SYN.
+    pub fn apply(&self, socket: &Socket) -> io::Result<()> {
+        if let Some(sndbuf) = self.sndbuf {
+            socket.set_send_buffer_size(sndbuf)?;
+            let actual = socket.send_buffer_size()?;
+            tracing::debug!("SO_SNDBUF requested={}, actual={}", sndbuf, actual);
+            if actual < sndbuf {
+                tracing::warn!(
+                    "SO_SNDBUF clamped by the kernel, requested={}, actual={}, see net.core.wmem_max",
+                    sndbuf,
+                    actual
+                );
+            }
+        }
+        if let Some(rcvbuf) = self.rcvbuf {
+            socket.set_recv_buffer_size(rcvbuf)?;
+            let actual = socket.recv_buffer_size()?;
+            tracing::debug!("SO_RCVBUF requested={}, actual={}", rcvbuf, actual);
+            if actual < rcvbuf {
+                tracing::warn!(
+                    "SO_RCVBUF clamped by the kernel, requested={}, actual={}, see net.core.rmem_max",
+                    rcvbuf,
+                    actual
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The most `stream` buffers on send: its SO_SNDBUF if set, otherwise
+    /// what the kernel autotunes it up to.
+    pub fn sndbuf_limit(&self, stream: &Stream) -> usize {
+        match self.sndbuf {
+            Some(_) => SockRef::from(stream)
+                .send_buffer_size()
+                .unwrap_or_else(|_| autotuned_buffer_limit("tcp_wmem")),
+            None => autotuned_buffer_limit("tcp_wmem"),
+        }
+    }
+}
+
+/// The largest TCP buffer the kernel autotunes to, the last value of
+/// `net.ipv4.tcp_wmem` or `net.ipv4.tcp_rmem`, or its default.
+fn autotuned_buffer_limit(sysctl: &str) -> usize {
+    let default = match sysctl {
+        "tcp_wmem" => 4 << 20,
+        _ => 6 << 20,
+    };
+    std::fs::read_to_string(format!("/proc/sys/net/ipv4/{}", sysctl))
+        .ok()
+        .and_then(|values| values.split_whitespace().nth(2)?.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Unix sockets between same-host peers, opt-in until they had more soak time.
+fn uds_enabled() -> bool {
+    std::env::var("BAGUA_NET_ENABLE_UDS").unwrap_or("0".to_owned()) == "1"
+}
+
+/// Messages up to `BAGUA_NET_INLINE_THRESHOLD` bytes skip the data streams,
+/// see `CommHandshake::inline_threshold`.
+fn inline_threshold() -> usize {
+    std::env::var("BAGUA_NET_INLINE_THRESHOLD")
+        .unwrap_or("4096".to_owned())
+        .parse()
+        .unwrap()
+}
+
+/// Whether chunks follow their `ChunkHeader`, see `CommHandshake::seq_check`.
+fn seq_check() -> bool {
+    std::env::var("BAGUA_NET_SEQ_CHECK").unwrap_or("0".to_owned()) == "1"
+}
+
+/// Whether messages are followed by their CRC32C, see `CommHandshake::crc`.
+fn crc() -> bool {
+    std::env::var("BAGUA_NET_CRC").unwrap_or("0".to_owned()) == "1"
+}
+
+/// Whether `CommHandshake::WORK_STEALING` is offered.
+fn work_stealing() -> bool {
+    std::env::var("BAGUA_NET_WORK_STEALING").unwrap_or("0".to_owned()) == "1"
+}
+
+/// Options for opening the streams of a send comm.
+///
+/// Every stream is retried independently when its peer is not reachable
+/// yet, e.g. because the receiver has not called `listen()` at that point.
+#[derive(Debug, Clone)]
+pub struct ConnectConfig {
+    pub retries: usize,
+    pub backoff: Duration,
+    pub timeout: Duration,
+    /// How long to wait for the acceptor's comm handshake, `None` waits
+    /// forever. It is only sent once NCCL calls accept on the other side,
+    /// which can be long after the connect.
+    pub handshake_timeout: Option<Duration>,
+    /// Local address the streams are bound to, so that they leave through
+    /// the NIC of the device passed to `connect()`.
+    pub bind_addr: Option<net::SocketAddr>,
+    pub keepalive: Option<KeepaliveConfig>,
+    /// `BAGUA_NET_TCP_CONGESTION`.
+    pub congestion: Option<String>,
+    pub buffers: SocketBufferConfig,
+    /// Whether to use the unix socket of a listener on the same host.
+    pub uds: bool,
+    /// Abstract name of that unix socket, see `same_host_unix_name`.
+    pub unix_peer: Option<Vec<u8>>,
+    /// Set by the backend from `TlsConfig::from_env()`.
+    pub tls: Option<TlsConfig>,
+    pub auth_key: Option<AuthKey>,
+    pub inline_threshold: usize,
+    /// Set by the backend from `BAGUA_NET_MIN_CHUNKSIZE`, see
+    /// `CommHandshake::min_chunksize`.
+    pub min_chunksize: usize,
+    /// Set by the backend from `BAGUA_NET_CHUNK_BYTES`.
+    pub chunk_bytes: usize,
+    /// `BAGUA_NET_SEQ_CHECK=1`, see `ChunkHeader`.
+    pub seq_check: bool,
+    /// `BAGUA_NET_CRC=1`.
+    pub crc: bool,
+    /// Set by the backend from `BAGUA_NET_COMPRESSION`.
+    pub compression: bool,
+    /// Set by the backend, see `CommHandshake::max_nstreams`.
+    pub max_nstreams: usize,
+    /// `BAGUA_NET_WORK_STEALING=1`, offered in the comm handshake.
+    pub work_stealing: bool,
+}
+
+impl ConnectConfig {
+    const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+    pub fn from_env() -> ConnectConfig {
+        ConnectConfig {
+            retries: std::env::var("BAGUA_NET_CONNECT_RETRIES")
+                .unwrap_or("5".to_owned())
+                .parse()
+                .unwrap(),
+            backoff: Duration::from_millis(
+                std::env::var("BAGUA_NET_CONNECT_BACKOFF_MS")
+                    .unwrap_or("100".to_owned())
+                    .parse()
+                    .unwrap(),
+            ),
+            timeout: Duration::from_millis(
+                std::env::var("BAGUA_NET_CONNECT_TIMEOUT_MS")
+                    .unwrap_or("5000".to_owned())
+                    .parse()
+                    .unwrap(),
+            ),
+            handshake_timeout: match std::env::var("BAGUA_NET_HANDSHAKE_TIMEOUT_MS")
+                .unwrap_or("600000".to_owned())
+                .parse()
+                .unwrap()
+            {
+                0 => None,
+                timeout_ms => Some(Duration::from_millis(timeout_ms)),
+            },
+            bind_addr: None,
+            keepalive: KeepaliveConfig::from_env(),
+            congestion: tcp_congestion(),
+            buffers: SocketBufferConfig::from_env(),
+            uds: uds_enabled(),
+            unix_peer: None,
+            tls: None,
+            auth_key: AuthKey::from_env(),
+            inline_threshold: inline_threshold(),
+            min_chunksize: 0,
+            chunk_bytes: 0,
+            seq_check: seq_check(),
+            crc: crc(),
+            compression: false,
+            max_nstreams: 0,
+            work_stealing: work_stealing(),
+        }
+    }
+
+    /// The same options, with the streams bound to `addr` (port 0).
+    pub fn bind_to(&self, addr: &SockAddr) -> ConnectConfig {
+        let bind_addr = match addr {
+            SockAddr::Inet(inet_addr) => {
+                let mut bind_addr = inet_addr.to_std();
+                bind_addr.set_port(0);
+                Some(bind_addr)
+            }
+            others => {
+                tracing::warn!("cannot bind streams to {:?}, leave them unbound", others);
+                None
+            }
+        };
+
+        ConnectConfig {
+            bind_addr,
+            ..self.clone()
+        }
+    }
+
+    /// Exponential backoff before the `attempt`-th retry (counting from 0),
+    /// with jitter so that a connect storm does not retry in lockstep.
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let exp = self
+            .backoff
+            .checked_mul(1 << std::cmp::min(attempt, 16))
+            .unwrap_or(ConnectConfig::MAX_BACKOFF);
+        let exp = std::cmp::min(exp, ConnectConfig::MAX_BACKOFF);
+
+        exp / 2 + exp.mul_f64(rand::random::<f64>() / 2.)
+    }
+
+    fn is_retryable(err: &io::Error) -> bool {
+        if err.kind() == io::ErrorKind::ConnectionRefused {
+            return true;
+        }
+        matches!(
+            err.raw_os_error(),
+            Some(libc::ENETUNREACH) | Some(libc::EHOSTUNREACH)
+        )
+    }
+}
+
+/// Options for collecting the streams of a send comm in `accept()`.
+#[derive(Debug, Clone)]
+pub struct AcceptConfig {
+    /// How long the streams of a send comm are kept once its first stream
+    /// arrived, waiting for the rest, `None` keeps them forever.
+    pub collect_timeout: Option<Duration>,
+    /// How long an accepted socket may take to introduce itself.
+    pub handshake_timeout: Duration,
+    pub keepalive: Option<KeepaliveConfig>,
+    /// `BAGUA_NET_TCP_CONGESTION`.
+    pub congestion: Option<String>,
+    /// Set by the backend from `TlsConfig::from_env()`.
+    pub tls: Option<TlsConfig>,
+    /// Streams that fail its challenge are dropped.
+    pub auth_key: Option<AuthKey>,
+    pub inline_threshold: usize,
+    /// Set by the backend from `BAGUA_NET_MIN_CHUNKSIZE`, see
+    /// `CommHandshake::min_chunksize`.
+    pub min_chunksize: usize,
+    /// Set by the backend from `BAGUA_NET_CHUNK_BYTES`.
+    pub chunk_bytes: usize,
+    /// `BAGUA_NET_SEQ_CHECK=1`, see `ChunkHeader`.
+    pub seq_check: bool,
+    /// `BAGUA_NET_CRC=1`.
+    pub crc: bool,
+    /// Set by the backend from `BAGUA_NET_COMPRESSION`.
+    pub compression: bool,
+    /// Set by the backend, see `CommHandshake::max_nstreams`.
+    pub max_nstreams: usize,
+    /// Like `ConnectConfig::work_stealing`.
+    pub work_stealing: bool,
+    pub quickack: QuickAck,
+}
+
+impl AcceptConfig {
+    pub fn from_env() -> AcceptConfig {
+        let collect_timeout_ms: u64 = std::env::var("BAGUA_NET_COLLECT_TIMEOUT_MS")
+            .unwrap_or("10000".to_owned())
+            .parse()
+            .unwrap();
+
+        AcceptConfig {
+            collect_timeout: if collect_timeout_ms == 0 {
+                None
+            } else {
+                Some(Duration::from_millis(collect_timeout_ms))
+            },
+            handshake_timeout: ConnectConfig::from_env().timeout,
+            keepalive: KeepaliveConfig::from_env(),
+            congestion: tcp_congestion(),
+            tls: None,
+            auth_key: AuthKey::from_env(),
+            inline_threshold: inline_threshold(),
+            min_chunksize: 0,
+            chunk_bytes: 0,
+            seq_check: seq_check(),
+            crc: crc(),
+            compression: false,
+            max_nstreams: 0,
+            work_stealing: work_stealing(),
+            quickack: QuickAck::from_env(),
+        }
+    }
+}
+
+lazy_static! {
+    /// Ports of `BAGUA_NET_PORT_RANGE` currently held by a listen comm of this
+    /// process, whichever `BaguaNet` it belongs to.
+    static ref RESERVED_PORTS: Mutex<HashSet<u16>> = Mutex::new(HashSet::new());
+}
+
+/// Options for binding the socket of a listen comm.
+#[derive(Debug, Clone)]
+pub struct ListenConfig {
+    /// Ports `listen()` may bind, `None` lets the kernel pick one.
+    pub port_range: Option<RangeInclusive<u16>>,
+    pub backlog: i32,
+    /// Also set SO_REUSEPORT, for setups that deliberately share a port.
+    pub reuse_port: bool,
+    /// Inherited by the accepted streams.
+    pub buffers: SocketBufferConfig,
+    /// Also listen on a unix socket for peers on the same host.
+    pub uds: bool,
+    /// Advertised in the handles, for the connectors to resolve instead of
+    /// dialing the listener's address. Names longer than 44 bytes do not fit, the
+    /// handles have the address instead.
+    pub advertise_hostname: Option<String>,
+}
+
+impl ListenConfig {
+    pub const DEFAULT_BACKLOG: i32 = 16384;
+
+    pub fn from_env() -> ListenConfig {
+        let port_range = std::env::var("BAGUA_NET_PORT_RANGE").unwrap_or("".to_owned());
+
+        ListenConfig {
+            port_range: parse_port_range(&port_range).unwrap(),
+            backlog: ListenConfig::DEFAULT_BACKLOG,
+            reuse_port: std::env::var("BAGUA_NET_REUSEPORT").unwrap_or("0".to_owned()) == "1",
+            buffers: SocketBufferConfig::from_env(),
+            uds: uds_enabled(),
+            advertise_hostname: advertise_hostname(),
+        }
+    }
+}
+
+/// `BAGUA_NET_ADVERTISE_HOSTNAME` is either the name to advertise or `1` for
+/// the name of this host, unset advertises the address.
+fn advertise_hostname() -> Option<String> {
+    let hostname = std::env::var("BAGUA_NET_ADVERTISE_HOSTNAME").unwrap_or("".to_owned());
+    match hostname.trim() {
+        "" | "0" => None,
+        "1" => {
+            let mut buf = [0u8; 256];
+            match nix::unistd::gethostname(&mut buf) {
+                Ok(hostname) => Some(hostname.to_string_lossy().into_owned()),
+                Err(err) => {
+                    tracing::warn!("gethostname failed, advertise the address, err={:?}", err);
+                    None
+                }
+            }
+        }
+        hostname => Some(hostname.to_owned()),
+    }
+}
+
+/// Parses `"20000-20999"`, an empty string means no range.
+pub fn parse_port_range(raw: &str) -> Result<Option<RangeInclusive<u16>>, BaguaNetError> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Ok(None);
+    }
+
+    let invalid = || BaguaNetError::InvalidArgument(format!("invalid port range {:?}", raw));
+    let (start, end) = raw.split_once('-').ok_or_else(invalid)?;
+    let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+    let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+    if start == 0 || start > end {
+        return Err(invalid());
+    }
+
+    Ok(Some(start..=end))
+}
+
+/// Keeps a port of `BAGUA_NET_PORT_RANGE` away from other listen comms until
+/// dropped.
+#[derive(Debug)]
+pub struct PortReservation {
+    port: u16,
+}
+
+impl Drop for PortReservation {
+    fn drop(&mut self) {
+        RESERVED_PORTS.lock().unwrap().remove(&self.port);
+    }
+}
+
+fn bind_and_listen(addr: net::SocketAddr, config: &ListenConfig) -> io::Result<net::TcpListener> {
+    let domain = match addr {
+        net::SocketAddr::V4(_) => Domain::IPV4,
+        net::SocketAddr::V6(_) => Domain::IPV6,
+    };
+    let socket = Socket::new(domain, Type::STREAM, None)?;
+    // Lets a restarted job rebind ports whose old connections are in TIME_WAIT.
+    socket.set_reuse_address(true)?;
+    if config.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    config.buffers.apply(&socket)?;
+    socket.bind(&addr.into())?;
+    socket.listen(config.backlog)?;
+    // `accept()` must be able to return when nobody is connecting.
+    socket.set_nonblocking(true)?;
+
+    Ok(socket.into())
+}
+
+/// Wakes the accepts waiting on a listener, from any thread. They err with
+/// `BaguaNetError::Closed` from then on.
+#[derive(Debug, Clone)]
+pub struct ListenCloser {
+    closed: Arc<AtomicBool>,
+    wake: Arc<UnixStream>,
+}
+
+impl ListenCloser {
+    /// With the end its listener polls.
+    fn new() -> io::Result<(ListenCloser, UnixStream)> {
+        let (wake, woken) = UnixStream::pair()?;
+        let closer = ListenCloser {
+            closed: Arc::new(AtomicBool::new(false)),
+            wake: Arc::new(wake),
+        };
+        Ok((closer, woken))
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        // Never read, so it stays readable.
+        let _ = (&*self.wake).write(&[0u8]);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+/// The sockets a listen comm accepts streams on.
+#[derive(Debug)]
+pub struct Listener {
+    pub tcp: net::TcpListener,
+    /// On an address of the other family, for dual-stack hosts.
+    pub alt_tcp: Option<net::TcpListener>,
+    /// Abstract name and socket for peers on the same host.
+    pub unix: Option<(Vec<u8>, UnixListener)>,
+    /// Told to the connectors, see `CommHandshake::reconnect_port`.
+    pub reconnect_port: u16,
+    /// The most an accepted stream buffers on receive, told to the
+    /// connectors to size their `ReplayWindow`.
+    pub rcvbuf: usize,
+    /// See `ListenConfig::advertise_hostname`.
+    pub hostname: Option<String>,
+    _port_reservation: Option<PortReservation>,
+    _alt_port_reservation: Option<PortReservation>,
+    pub closer: ListenCloser,
+    /// Readable once `closer` is closed.
+    woken: UnixStream,
+}
+
+impl Listener {
+    /// What the connectors need to reach this listener.
+    pub fn socket_handle(&self) -> Result<SocketHandle, BaguaNetError> {
+        let local_addr = self.tcp.local_addr().map_err(BaguaNetError::from)?;
+        let alt_addr = match &self.alt_tcp {
+            Some(alt_tcp) => Some(SockAddr::new_inet(InetAddr::from_std(
+                &alt_tcp.local_addr().map_err(BaguaNetError::from)?,
+            ))),
+            None => None,
+        };
+        let uds_addr = match &self.unix {
+            Some((name, _)) => Some(SockAddr::Unix(
+                UnixAddr::new_abstract(name).map_err(BaguaNetError::from)?,
+            )),
+            None => None,
+        };
+
+        Ok(SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&local_addr)),
+            alt_addr,
+            uds_addr,
+            hostname: self.hostname.clone(),
+        })
+    }
+
+    /// Also accepts streams on `addr`, which should be of the other family,
+    /// on the port of `tcp` if it is free there. Stays single-stack if it
+    /// cannot listen on `addr`.
+    pub fn bind_alt(&mut self, addr: InetAddr, config: &ListenConfig) {
+        let mut same_port = addr.to_std();
+        if let Ok(local_addr) = self.tcp.local_addr() {
+            same_port.set_port(local_addr.port());
+        }
+        let alt_tcp = match bind_and_listen(same_port, config) {
+            Ok(alt_tcp) => Ok(alt_tcp),
+            Err(_) => bind_tcp_listener(addr, config).map(|(alt_tcp, port_reservation)| {
+                self._alt_port_reservation = port_reservation;
+                alt_tcp
+            }),
+        };
+        match alt_tcp {
+            Ok(alt_tcp) => self.alt_tcp = Some(alt_tcp),
+            Err(err) => {
+                tracing::warn!(
+                    "listen on {} failed, single-stack only, err={:?}",
+                    addr,
+                    err
+                );
+            }
+        }
+    }
+
+    fn accept(&self) -> io::Result<Option<(Stream, String)>> {
+        for tcp in std::iter::once(&self.tcp).chain(&self.alt_tcp) {
+            match tcp.accept() {
+                Ok((stream, addr)) => return Ok(Some((Stream::Tcp(stream), addr.to_string()))),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if let Some((name, listener)) = &self.unix {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let addr = format!("unix {}", String::from_utf8_lossy(name));
+                    return Ok(Some((Stream::Unix(stream), addr)));
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Abstract name of the unix socket of a listener, ids are never 0.
+pub fn unix_name(id: u64) -> Vec<u8> {
+    format!("bagua-net-{:016x}", id).into_bytes()
+}
+
+/// The id `name` was made from by `unix_name`.
+pub fn unix_name_id(name: &[u8]) -> Option<u64> {
+    let id = std::str::from_utf8(name.strip_prefix(b"bagua-net-")?).ok()?;
+    u64::from_str_radix(id, 16)
+        .ok()
+        .filter(|&id| id != 0 && unix_name(id) == name)
+}
+
+fn bind_unix_listener() -> io::Result<(Vec<u8>, UnixListener)> {
+    let name = unix_name(rand::random::<u64>().max(1));
+    let listener =
+        UnixListener::bind_addr(&std::os::unix::net::SocketAddr::from_abstract_name(&name)?)?;
+    listener.set_nonblocking(true)?;
+
+    Ok((name, listener))
+}
+
+/// Binds the listener of a listen comm on `addr`, on the first free port of
+/// `config.port_range` if there is one.
+pub fn bind_listener(addr: InetAddr, config: &ListenConfig) -> Result<Listener, BaguaNetError> {
+    let (tcp, port_reservation) = bind_tcp_listener(addr, config)?;
+    let unix = if config.uds {
+        match bind_unix_listener() {
+            Ok(unix) => Some(unix),
+            Err(err) => {
+                tracing::warn!("listen on a unix socket failed, TCP only, err={:?}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let (closer, woken) = ListenCloser::new().map_err(BaguaNetError::from)?;
+    // Accepted streams inherit the buffer of the listener.
+    let rcvbuf = match config.buffers.rcvbuf {
+        Some(_) => SockRef::from(&tcp)
+            .recv_buffer_size()
+            .map_err(BaguaNetError::from)?,
+        None => autotuned_buffer_limit("tcp_rmem"),
+    };
+
+    Ok(Listener {
+        tcp,
+        alt_tcp: None,
+        unix,
+        reconnect_port: 0,
+        rcvbuf,
+        hostname: config.advertise_hostname.clone(),
+        _port_reservation: port_reservation,
+        _alt_port_reservation: None,
+        closer,
+        woken,
+    })
+}
+
+fn bind_tcp_listener(
+    addr: InetAddr,
+    config: &ListenConfig,
+) -> Result<(net::TcpListener, Option<PortReservation>), BaguaNetError> {
+    let mut addr = addr.to_std();
+    let port_range = match &config.port_range {
+        Some(port_range) => port_range.clone(),
+        None => {
+            addr.set_port(0);
+            let listener = bind_and_listen(addr, config)
+                .map_err(|err| BaguaNetError::system_io(format!("bind {}", addr), err))?;
+            return Ok((listener, None));
+        }
+    };
+
+    let mut reserved_ports = RESERVED_PORTS.lock().unwrap();
+    for port in port_range.clone() {
+        if reserved_ports.contains(&port) {
+            continue;
+        }
+        addr.set_port(port);
+        match bind_and_listen(addr, config) {
+            Ok(listener) => {
+                reserved_ports.insert(port);
+                return Ok((listener, Some(PortReservation { port })));
+            }
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(err) => return Err(BaguaNetError::system_io(format!("bind {}", addr), err)),
+        }
+    }
+
+    Err(BaguaNetError::system(format!(
+        "no free port on {} in BAGUA_NET_PORT_RANGE {}-{}",
+        addr.ip(),
+        port_range.start(),
+        port_range.end()
+    )))
+}
+
+/// The preferred address of the listener.
+fn peer_socket_addr(socket_handle: &SocketHandle) -> Result<net::SocketAddr, BaguaNetError> {
+    match socket_handle.addr {
+        SockAddr::Inet(inet_addr) => Ok(inet_addr.to_std()),
+        others => Err(BaguaNetError::InnerError(format!(
+            "Got invalid socket address, which is {:?}",
+            others
+        ))),
+    }
+}
+
+/// The addresses of the listener in the order to try them: those of the
+/// family the streams are bound to first, then the listener's preference, or
+/// the resolver's if the listener advertised a hostname.
+fn peer_socket_addrs(
+    socket_handle: &SocketHandle,
+    config: &ConnectConfig,
+) -> Result<Vec<net::SocketAddr>, BaguaNetError> {
+    let mut peer_addrs = match &socket_handle.hostname {
+        Some(hostname) => {
+            let port = peer_socket_addr(socket_handle)?.port();
+            let alt_addr = match socket_handle.alt_addr {
+                Some(SockAddr::Inet(alt_addr)) => Some(alt_addr.to_std()),
+                _ => None,
+            };
+            resolve_hostname(hostname, port)?
+                .into_iter()
+                .map(|mut addr| {
+                    // The listener of the other family may be on another port.
+                    if let Some(alt_addr) = alt_addr {
+                        if alt_addr.is_ipv4() == addr.is_ipv4() {
+                            addr.set_port(alt_addr.port());
+                        }
+                    }
+                    addr
+                })
+                .collect()
+        }
+        None => {
+            let mut peer_addrs = vec![peer_socket_addr(socket_handle)?];
+            if let Some(SockAddr::Inet(alt_addr)) = socket_handle.alt_addr {
+                peer_addrs.push(alt_addr.to_std());
+            }
+            peer_addrs
+        }
+    };
+    if let Some(bind_addr) = config.bind_addr {
+        peer_addrs.sort_by_key(|peer_addr| peer_addr.is_ipv4() != bind_addr.is_ipv4());
+    }
+
+    Ok(peer_addrs)
+}
+
+fn resolve_hostname(hostname: &str, port: u16) -> Result<Vec<net::SocketAddr>, BaguaNetError> {
+    let resolved: Vec<net::SocketAddr> = (hostname, port)
+        .to_socket_addrs()
+        .map_err(|err| BaguaNetError::remote_io(format!("resolve {} failed", hostname), err))?
+        .collect();
+    if resolved.is_empty() {
+        return Err(BaguaNetError::remote(format!(
+            "resolve {} failed, no address",
+            hostname
+        )));
+    }
+
+    Ok(resolved)
+}
+
+/// The abstract name of the listener's unix socket if it is on this host,
+/// that is if its address, or one its hostname resolves to, is the address of
+/// one of `local_devs`.
+pub fn same_host_unix_name(
+    socket_handle: &SocketHandle,
+    local_devs: &[NCCLSocketDev],
+) -> Option<Vec<u8>> {
+    let name = match &socket_handle.uds_addr {
+        Some(SockAddr::Unix(unix_addr)) => unix_addr.as_abstract()?.to_vec(),
+        _ => return None,
+    };
+    let peer_ips: Vec<net::IpAddr> = match &socket_handle.hostname {
+        Some(hostname) => match resolve_hostname(hostname, 0) {
+            Ok(addrs) => addrs.iter().map(|addr| addr.ip()).collect(),
+            Err(err) => {
+                tracing::warn!("{:?}", err);
+                return None;
+            }
+        },
+        None => std::iter::once(&socket_handle.addr)
+            .chain(&socket_handle.alt_addr)
+            .filter_map(|addr| match addr {
+                SockAddr::Inet(inet_addr) => Some(inet_addr.to_std().ip()),
+                _ => None,
+            })
+            .collect(),
+    };
+    let same_host = local_devs.iter().any(|socket_dev| match socket_dev.addr {
+        SockAddr::Inet(inet_addr) => peer_ips.contains(&inet_addr.to_std().ip()),
+        _ => false,
+    });
+
+    if same_host {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+fn connect_unix_stream(name: &[u8]) -> Option<Stream> {
+    let stream = std::os::unix::net::SocketAddr::from_abstract_name(name)
+        .and_then(|addr| UnixStream::connect_addr(&addr));
+    match stream {
+        Ok(stream) => Some(Stream::Unix(stream)),
+        Err(err) => {
+            // E.g. the peer is in another network namespace.
+            tracing::warn!(
+                "connect to unix {} failed, fall back to TCP, err={:?}",
+                String::from_utf8_lossy(name),
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Connects to `peer_addr`, from `config.bind_addr` when it is usable.
+fn open_stream(peer_addr: &net::SocketAddr, config: &ConnectConfig) -> io::Result<net::TcpStream> {
+    let domain = match peer_addr {
+        net::SocketAddr::V4(_) => Domain::IPV4,
+        net::SocketAddr::V6(_) => Domain::IPV6,
+    };
+    let socket = Socket::new(domain, Type::STREAM, None)?;
+    config.buffers.apply(&socket)?;
+    match config.bind_addr {
+        Some(bind_addr) if bind_addr.is_ipv4() != peer_addr.is_ipv4() => {
+            tracing::debug!(
+                "not binding to {} to reach {}, address families differ",
+                bind_addr,
+                peer_addr
+            );
+        }
+        Some(bind_addr) => {
+            if let Err(err) = socket.bind(&bind_addr.into()) {
+                tracing::warn!(
+                    "bind to {} failed, connect to {} unbound, err={:?}",
+                    bind_addr,
+                    peer_addr,
+                    err
+                );
+            }
+        }
+        None => {}
+    }
+    socket.connect_timeout(&(*peer_addr).into(), config.timeout)?;
+
+    Ok(socket.into())
+}
+
+/// Opens one stream of a send comm and introduces it to the acceptor.
+pub fn connect_stream(
+    socket_handle: &SocketHandle,
+    handshake: StreamHandshake,
+    config: &ConnectConfig,
+) -> Result<Stream, BaguaNetError> {
+    let started = Instant::now();
+    connection_event!(
+        DEBUG,
+        comm_uuid = %handshake.comm_uuid,
+        stream = %handshake.stream_name(),
+        peer = %socket_handle.addr,
+        "connecting stream"
+    );
+    let connected = open_stream_of(socket_handle, handshake, config);
+    match &connected {
+        Ok(stream) => connection_event!(
+            INFO,
+            comm_uuid = %handshake.comm_uuid,
+            stream = %handshake.stream_name(),
+            peer = %stream.peer_addr(),
+            duration_us = started.elapsed().as_micros() as u64,
+            "stream connected"
+        ),
+        Err(err) => connection_event!(
+            WARN,
+            comm_uuid = %handshake.comm_uuid,
+            stream = %handshake.stream_name(),
+            peer = %socket_handle.addr,
+            duration_us = started.elapsed().as_micros() as u64,
+            error = %err,
+            "stream connect failed"
+        ),
+    }
+    connected
+}
+
+/// Of `connect_stream`.
+fn open_stream_of(
+    socket_handle: &SocketHandle,
+    handshake: StreamHandshake,
+    config: &ConnectConfig,
+) -> Result<Stream, BaguaNetError> {
+    let unix_stream = config
+        .unix_peer
+        .as_ref()
+        .and_then(|name| connect_unix_stream(name));
+    let mut stream = match unix_stream {
+        Some(stream) => stream,
+        None => Stream::Tcp(connect_tcp_stream(socket_handle, config)?),
+    };
+    set_keepalive(&stream, &config.keepalive);
+    set_congestion(&stream, &handshake, &config.congestion);
+    if let Err(err) = handshake.write_to(&mut stream) {
+        return Err(BaguaNetError::remote_io(
+            format!("peer={}, handshake={:?}", stream.peer(), handshake),
+            err,
+        ));
+    }
+    if let Some(auth_key) = &config.auth_key {
+        let answered = stream
+            .set_read_timeout(Some(config.timeout))
+            .and_then(|_| auth_key.answer(&mut stream, &handshake))
+            .and_then(|_| stream.set_read_timeout(None));
+        if let Err(err) = answered {
+            return Err(BaguaNetError::remote_io(
+                format!(
+                    "peer={}, handshake={:?}, authentication failed, BAGUA_NET_AUTH_KEY must be set on both sides",
+                    stream.peer(),
+                    handshake
+                ),
+                err,
+            ));
+        }
+    }
+    // The master stream only starts TLS once both sides agreed on it.
+    if handshake.stream_id == StreamHandshake::CTRL_STREAM_ID {
+        return Ok(stream);
+    }
+
+    connect_tls(stream, socket_handle, config)
+}
+
+/// Continues `stream` in a TLS session if `config.tls` is set.
+fn connect_tls(
+    stream: Stream,
+    socket_handle: &SocketHandle,
+    config: &ConnectConfig,
+) -> Result<Stream, BaguaNetError> {
+    let tls = match &config.tls {
+        Some(tls) => tls,
+        None => return Ok(stream),
+    };
+    // Certificates are not checked against it, see `tls::CaOnlyVerifier`.
+    let server_name = match socket_handle
+        .hostname
+        .clone()
+        .and_then(|hostname| ServerName::try_from(hostname).ok())
+    {
+        Some(server_name) => server_name,
+        None => ServerName::from(peer_socket_addr(socket_handle)?.ip()),
+    };
+    let peer = stream.peer();
+    match TlsStream::connect(stream, tls, server_name, config.timeout) {
+        Ok(stream) => Ok(Stream::Tls(Box::new(stream))),
+        Err(err) => Err(BaguaNetError::remote_io(
+            format!("TLS handshake with {} failed", peer),
+            err,
+        )),
+    }
+}
+
+/// Continues an accepted `stream` in a TLS session if `config.tls` is set.
+fn accept_tls(stream: Stream, config: &AcceptConfig) -> io::Result<Stream> {
+    match &config.tls {
+        Some(tls) => Ok(Stream::Tls(Box::new(TlsStream::accept(
+            stream,
+            tls,
+            config.handshake_timeout,
+        )?))),
+        None => Ok(stream),
+    }
+}
+
+fn connect_tcp_stream(
+    socket_handle: &SocketHandle,
+    config: &ConnectConfig,
+) -> Result<net::TcpStream, BaguaNetError> {
+    let peer_addrs = peer_socket_addrs(socket_handle, config)?;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        // Each attempt tries every address, the error of the last is reported.
+        let mut retryable = false;
+        let mut last_err = None;
+        for peer_addr in &peer_addrs {
+            match open_stream(peer_addr, config) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    tracing::debug!("connect to {} failed, err={:?}", peer_addr, err);
+                    retryable |= ConnectConfig::is_retryable(&err);
+                    last_err = Some((peer_addr, err));
+                }
+            }
+        }
+        let (peer_addr, err) = last_err.unwrap();
+        match err {
+            err if attempts <= config.retries && retryable => {
+                let delay = config.delay(attempts - 1);
+                tracing::debug!(
+                    "net::TcpStream::connect failed, retry in {:?}, attempts={}, err={:?}, peer={}",
+                    delay,
+                    attempts,
+                    err,
+                    peer_addr
+                );
+                std::thread::sleep(delay);
+            }
+            err if err.kind() == io::ErrorKind::TimedOut => {
+                tracing::warn!(
+                    "net::TcpStream::connect timed out, timeout={:?}, peer={}",
+                    config.timeout,
+                    peer_addr
+                );
+                return Err(BaguaNetError::remote_io(
+                    format!(
+                        "connect to {} timed out after {:?}",
+                        peer_addr, config.timeout
+                    ),
+                    err,
+                ));
+            }
+            err => {
+                tracing::warn!(
+                    "net::TcpStream::connect failed, attempts={}, err={:?}, peer={}",
+                    attempts,
+                    err,
+                    peer_addr
+                );
+                return Err(BaguaNetError::remote_io(
+                    format!("connect to {} failed, attempts={}", peer_addr, attempts),
+                    err,
+                ));
+            }
+        }
+    }
+}
+
+/// Opens the master stream of a send comm and negotiates the protocol with
+/// the acceptor, whose handshake it returns with the capabilities both
+/// offered. Must be called before opening the data streams.
+pub fn connect_ctrl_stream(
+    socket_handle: &SocketHandle,
+    comm_uuid: Uuid,
+    nstreams: usize,
+    config: &ConnectConfig,
+) -> Result<(Stream, CommHandshake), BaguaNetError> {
+    let mut stream = connect_stream(
+        socket_handle,
+        StreamHandshake {
+            comm_uuid,
+            stream_id: StreamHandshake::CTRL_STREAM_ID,
+        },
+        config,
+    )?;
+
+    let local = CommHandshake {
+        capabilities: capabilities(config.work_stealing),
+        ..CommHandshake::local(
+            nstreams,
+            config.tls.is_some(),
+            config.inline_threshold,
+            config.min_chunksize,
+            config.chunk_bytes,
+            config.seq_check,
+            config.crc,
+            config.compression,
+            config.max_nstreams,
+        )
+    };
+    let peer = local
+        .write_to(&mut stream)
+        .and_then(|_| stream.set_read_timeout(config.handshake_timeout))
+        .and_then(|_| CommHandshake::read_from(&mut stream))
+        .and_then(|peer| stream.set_read_timeout(None).map(|_| peer));
+    let peer = match peer {
+        Ok(peer) => peer,
+        Err(err) => {
+            return Err(BaguaNetError::remote_io(
+                format!("peer={}, comm handshake={:?}", stream.peer(), local),
+                err,
+            ))
+        }
+    };
+    local.check(&peer)?;
+    let peer = CommHandshake {
+        capabilities: local.negotiated(&peer),
+        ..peer
+    };
+
+    Ok((connect_tls(stream, socket_handle, config)?, peer))
+}
+
+/// Opens the `nstreams` data streams of a send comm concurrently, each one
+/// costs a round trip. Fails if any of them fails, closing the others.
+pub fn connect_data_streams(
+    socket_handle: &SocketHandle,
+    comm_uuid: Uuid,
+    nstreams: usize,
+    config: &ConnectConfig,
+) -> Result<Vec<Stream>, BaguaNetError> {
+    let connected: Vec<Result<Stream, BaguaNetError>> = std::thread::scope(|scope| {
+        let connects: Vec<_> = (0..nstreams)
+            .map(|stream_id| {
+                scope.spawn(move || {
+                    connect_stream(
+                        socket_handle,
+                        StreamHandshake {
+                            comm_uuid,
+                            stream_id,
+                        },
+                        config,
+                    )
+                })
+            })
+            .collect();
+        connects
+            .into_iter()
+            .map(|connect| connect.join().unwrap())
+            .collect()
+    });
+
+    connected.into_iter().collect()
+}
+
+/// The streams of one send comm, as seen by the acceptor.
+#[derive(Debug)]
+pub struct StreamGroup {
+    pub comm_uuid: Uuid,
+    pub data_streams: Vec<Stream>,
+    pub ctrl_stream: Stream,
+    /// Negotiated in the comm handshake, see `CommHandshake::capabilities`.
+    pub capabilities: u32,
+    /// Of the acceptor, see `CommHandshake::rcvbuf`. 0 on the accepting side.
+    pub peer_rcvbuf: usize,
+}
+
+/// Sockets accepted on a listener whose send comm has not finished
+/// connecting yet, keyed by the comm's UUID.
+///
+/// Peers connecting to the same listener at the same time interleave their
+/// sockets, so a group is only handed out once all of its nstreams+1
+/// sockets arrived. Groups whose connector died half way are dropped after
+/// `AcceptConfig::collect_timeout`.
+#[derive(Default)]
+pub struct PendingStreams {
+    /// With the time the first stream of the group arrived.
+    groups: HashMap<Uuid, (Instant, BTreeMap<usize, Stream>)>,
+    /// Negotiated on the master stream of a group.
+    capabilities: HashMap<Uuid, u32>,
+    /// Complete groups not handed out yet, several may complete at once.
+    ready: VecDeque<StreamGroup>,
+    /// Accepted sockets whose handshakes are not read yet.
+    introducing: Vec<Introduction>,
+}
+
+impl PendingStreams {
+    /// Parks `stream`, with the capabilities negotiated on it if it is the
+    /// master stream, and returns its group if it was the last one missing.
+    pub fn insert(
+        &mut self,
+        handshake: StreamHandshake,
+        stream: Stream,
+        capabilities: u32,
+        nstreams: usize,
+    ) -> Option<StreamGroup> {
+        if handshake.stream_id != StreamHandshake::CTRL_STREAM_ID && handshake.stream_id >= nstreams
+        {
+            tracing::warn!(
+                "drop stream {:?} with out of range id, peer={}, nstreams={}",
+                handshake,
+                stream.peer(),
+                nstreams
+            );
+            return None;
+        }
+
+        if handshake.stream_id == StreamHandshake::CTRL_STREAM_ID {
+            self.capabilities.insert(handshake.comm_uuid, capabilities);
+        }
+        let (_, group) = self
+            .groups
+            .entry(handshake.comm_uuid)
+            .or_insert_with(|| (Instant::now(), BTreeMap::new()));
+        if group.insert(handshake.stream_id, stream).is_some() {
+            tracing::warn!("duplicate stream {:?}, replaced", handshake);
+        }
+        if group.len() != nstreams + 1 {
+            return None;
+        }
+
+        let (_, mut streams) = self.groups.remove(&handshake.comm_uuid).unwrap();
+        let ctrl_stream = streams.remove(&StreamHandshake::CTRL_STREAM_ID).unwrap();
+
+        Some(StreamGroup {
+            comm_uuid: handshake.comm_uuid,
+            data_streams: streams.into_values().collect(),
+            ctrl_stream,
+            capabilities: self.capabilities.remove(&handshake.comm_uuid).unwrap_or(0),
+            peer_rcvbuf: 0,
+        })
+    }
+
+    /// Drops the groups that have been collecting for longer than `timeout`.
+    fn evict_stale(&mut self, timeout: Duration) {
+        let capabilities = &mut self.capabilities;
+        self.groups.retain(|comm_uuid, (first_seen, streams)| {
+            if first_seen.elapsed() < timeout {
+                return true;
+            }
+            tracing::warn!(
+                "drop {} streams of send comm {}, the rest did not arrive within {:?}",
+                streams.len(),
+                comm_uuid,
+                timeout
+            );
+            capabilities.remove(comm_uuid);
+            false
+        });
+    }
+}
+
+/// Whether `listener` has a pending connection, without waiting for one.
+fn acceptable(listener: &Listener) -> Result<bool, BaguaNetError> {
+    loop {
+        if listener.closer.is_closed() {
+            return Err(BaguaNetError::Closed);
+        }
+        let mut fds = vec![PollFd::new(listener.tcp.as_raw_fd(), PollFlags::POLLIN)];
+        if let Some(alt_tcp) = &listener.alt_tcp {
+            fds.push(PollFd::new(alt_tcp.as_raw_fd(), PollFlags::POLLIN));
+        }
+        if let Some((_, unix)) = &listener.unix {
+            fds.push(PollFd::new(unix.as_raw_fd(), PollFlags::POLLIN));
+        }
+        fds.push(PollFd::new(listener.woken.as_raw_fd(), PollFlags::POLLIN));
+        match poll(&mut fds, 0) {
+            Ok(0) => return Ok(false),
+            Ok(_) if listener.closer.is_closed() => return Err(BaguaNetError::Closed),
+            Ok(_) => return Ok(true),
+            Err(nix::Error::EINTR) => continue,
+            Err(err) => return Err(BaguaNetError::from(err)),
+        }
+    }
+}
+
+/// Accepts the sockets pending on `listener` and returns a send comm once all
+/// of its streams connected.
+///
+/// Never waits for a connection nor for a handshake, `Ok(None)` means no
+/// send comm is complete yet. Sockets that have not introduced themselves
+/// are parked until the next call, and dropped after
+/// `config.handshake_timeout`. Groups still collecting after
+/// `config.collect_timeout` are dropped.
+pub fn accept_stream_group(
+    listener: &Listener,
+    pending: &mut PendingStreams,
+    nstreams: usize,
+    config: &AcceptConfig,
+) -> Result<Option<StreamGroup>, BaguaNetError> {
+    if let Some(group) = pending.ready.pop_front() {
+        return Ok(Some(group));
+    }
+    if let Some(timeout) = config.collect_timeout {
+        pending.evict_stale(timeout);
+    }
+    loop {
+        for introduction in std::mem::take(&mut pending.introducing) {
+            match introduction.advance(listener, nstreams, config) {
+                Introduced::Pending(introduction) => pending.introducing.push(introduction),
+                Introduced::Done(handshake, stream, capabilities) => {
+                    if let Some(group) = pending.insert(handshake, stream, capabilities, nstreams) {
+                        pending.ready.push_back(group);
+                    }
+                }
+                Introduced::Dropped => {}
+            }
+        }
+        if let Some(group) = pending.ready.pop_front() {
+            return Ok(Some(group));
+        }
+
+        if !acceptable(listener)? {
+            return Ok(None);
+        }
+        for _ in 0..MAX_ACCEPTED_AT_ONCE {
+            match listener.accept() {
+                Ok(Some((stream, addr))) => {
+                    set_keepalive(&stream, &config.keepalive);
+                    match stream.set_nonblocking(true) {
+                        Ok(()) => pending.introducing.push(Introduction::new(stream, addr)),
+                        Err(err) => tracing::warn!("drop stream from {:?}, err={:?}", addr, err),
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => return Err(BaguaNetError::from(err)),
+            }
+        }
+    }
+}
+
+/// Streams accepted before the handshakes of those parked are read again.
+const MAX_ACCEPTED_AT_ONCE: usize = 64;
+
+/// Reads the `StreamHandshake` of an accepted stream and, with
+/// `config.auth_key`, challenges its connector.
+fn read_stream_handshake(
+    stream: &mut Stream,
+    config: &AcceptConfig,
+) -> io::Result<StreamHandshake> {
+    let handshake = StreamHandshake::read_from(stream)?;
+    if let Some(auth_key) = &config.auth_key {
+        auth_key.challenge(stream, &handshake)?;
+    }
+
+    Ok(handshake)
+}
+
+/// An accepted stream through its handshakes, read without blocking as
+/// they arrive, see `accept_stream_group`.
+struct Introduction {
+    stream: Stream,
+    addr: String,
+    accepted_at: Instant,
+    step: IntroductionStep,
+    /// Read so far in a reading step, written so far in a writing step.
+    buf: Vec<u8>,
+    pos: usize,
+    handshake: Option<StreamHandshake>,
+    capabilities: u32,
+    /// Of the comm handshake, reported once the reply went out.
+    mismatch: Option<BaguaNetError>,
+}
+
+enum IntroductionStep {
+    StreamHandshake,
+    /// Writes the challenge of `config.auth_key` in `buf`.
+    Challenge,
+    /// Reads the MAC of the challenge.
+    Answer([u8; 4 + AuthKey::NONCE_NBYTES]),
+    /// Of a master stream, then the reply in `buf` is written.
+    CommHandshake,
+    Reply,
+    /// Handshakes read, with `config.tls` the stream is then put in a TLS
+    /// session by `advance`.
+    Tls,
+}
+
+enum Introduced {
+    /// Waits for the peer, parked until the next `accept_stream_group`.
+    Pending(Introduction),
+    Done(StreamHandshake, Stream, u32),
+    Dropped,
+}
+
+impl Introduction {
+    fn new(stream: Stream, addr: String) -> Introduction {
+        Introduction {
+            stream,
+            addr,
+            accepted_at: Instant::now(),
+            step: IntroductionStep::StreamHandshake,
+            buf: vec![0u8; StreamHandshake::NBYTES],
+            pos: 0,
+            handshake: None,
+            capabilities: 0,
+            mismatch: None,
+        }
+    }
+
+    /// Reads into the rest of `buf`, whether it is full.
+    fn fill(&mut self) -> io::Result<bool> {
+        while self.pos < self.buf.len() {
+            match self.stream.read(&mut self.buf[self.pos..]) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.pos += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Writes the rest of `buf`, whether all of it went out.
+    fn flush(&mut self) -> io::Result<bool> {
+        while self.pos < self.buf.len() {
+            match self.stream.write(&self.buf[self.pos..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.pos += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+
+    fn start(&mut self, step: IntroductionStep, buf: Vec<u8>) {
+        self.step = step;
+        self.buf = buf;
+        self.pos = 0;
+    }
+
+    /// Continues the handshakes until the peer is waited for, and of a
+    /// master stream negotiates the capabilities both sides offered.
+    fn advance(
+        mut self,
+        listener: &Listener,
+        nstreams: usize,
+        config: &AcceptConfig,
+    ) -> Introduced {
+        if self.accepted_at.elapsed() >= config.handshake_timeout {
+            tracing::warn!(
+                "drop stream from {:?}, no handshake within {:?}",
+                self.addr,
+                config.handshake_timeout
+            );
+            return Introduced::Dropped;
+        }
+        let dropped = |addr: &str, err: io::Error| {
+            tracing::warn!("drop stream from {:?}, err={:?}", addr, err);
+            Introduced::Dropped
+        };
+        match self.steps(listener, nstreams, config) {
+            Ok(true) => {}
+            Ok(false) => return Introduced::Pending(self),
+            Err(err) => return dropped(&self.addr, err),
+        }
+        // Goes into a TLS session once it introduced itself.
+        if let (Some(tls), false) = (&config.tls, self.stream.is_tls()) {
+            let mut stream = match TlsStream::accepting(self.stream, tls) {
+                Ok(stream) => stream,
+                Err(err) => return dropped(&self.addr, err),
+            };
+            let done = stream.advance_handshake();
+            self.stream = Stream::Tls(Box::new(stream));
+            match done {
+                Ok(true) => {}
+                Ok(false) => return Introduced::Pending(self),
+                Err(err) => return dropped(&self.addr, err),
+            }
+        }
+        match self.stream.set_nonblocking(false) {
+            Ok(()) => Introduced::Done(self.handshake.unwrap(), self.stream, self.capabilities),
+            Err(err) => dropped(&self.addr, err),
+        }
+    }
+
+    /// Whether all handshakes are done.
+    fn steps(
+        &mut self,
+        listener: &Listener,
+        nstreams: usize,
+        config: &AcceptConfig,
+    ) -> io::Result<bool> {
+        loop {
+            match self.step {
+                IntroductionStep::StreamHandshake => {
+                    if !self.fill()? {
+                        return Ok(false);
+                    }
+                    let mut buf = [0u8; StreamHandshake::NBYTES];
+                    buf.copy_from_slice(&self.buf);
+                    self.handshake = Some(StreamHandshake::from_bytes(&buf));
+                    match &config.auth_key {
+                        Some(_) => self.start(
+                            IntroductionStep::Challenge,
+                            AuthKey::new_challenge()?.to_vec(),
+                        ),
+                        None => self.introduced(config),
+                    }
+                }
+                IntroductionStep::Challenge => {
+                    if !self.flush()? {
+                        return Ok(false);
+                    }
+                    let mut challenge = [0u8; 4 + AuthKey::NONCE_NBYTES];
+                    challenge.copy_from_slice(&self.buf);
+                    self.start(
+                        IntroductionStep::Answer(challenge),
+                        vec![0u8; AuthKey::MAC_NBYTES],
+                    );
+                }
+                IntroductionStep::Answer(challenge) => {
+                    if !self.fill()? {
+                        return Ok(false);
+                    }
+                    let auth_key = config.auth_key.as_ref().unwrap();
+                    auth_key.check_answer(
+                        &challenge,
+                        &self.buf,
+                        self.handshake.as_ref().unwrap(),
+                    )?;
+                    self.introduced(config);
+                }
+                IntroductionStep::CommHandshake => {
+                    if !self.fill()? {
+                        return Ok(false);
+                    }
+                    // Like `CommHandshake::read_from`, which stops after the
+                    // magic of a challenge.
+                    if self.buf.len() == 4 && self.buf[..] != AuthKey::MAGIC.to_be_bytes() {
+                        self.buf.resize(CommHandshake::NBYTES, 0);
+                        continue;
+                    }
+                    let mut buf = [0u8; CommHandshake::NBYTES];
+                    buf[..self.buf.len()].copy_from_slice(&self.buf);
+                    let peer = CommHandshake::from_bytes(&buf);
+                    let local = CommHandshake {
+                        capabilities: self::capabilities(config.work_stealing),
+                        ..CommHandshake::local(
+                            nstreams,
+                            config.tls.is_some(),
+                            config.inline_threshold,
+                            config.min_chunksize,
+                            config.chunk_bytes,
+                            config.seq_check,
+                            config.crc,
+                            config.compression,
+                            config.max_nstreams,
+                        )
+                    };
+                    // The connector sees the mismatch in the reply and fails.
+                    match local.check(&peer) {
+                        Ok(()) => self.capabilities = local.negotiated(&peer),
+                        Err(err) => self.mismatch = Some(err),
+                    }
+                    let reply = CommHandshake {
+                        reconnect_port: listener.reconnect_port as u32,
+                        rcvbuf: listener.rcvbuf.min(u32::MAX as usize) as u32,
+                        ..local
+                    };
+                    self.start(IntroductionStep::Reply, reply.to_bytes().to_vec());
+                }
+                IntroductionStep::Reply => {
+                    if !self.flush()? {
+                        return Ok(false);
+                    }
+                    if let Some(err) = self.mismatch.take() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("comm handshake mismatch, err={:?}", err),
+                        ));
+                    }
+                    self.step = IntroductionStep::Tls;
+                }
+                IntroductionStep::Tls => {
+                    return match &mut self.stream {
+                        Stream::Tls(stream) => stream.advance_handshake(),
+                        _ => Ok(true),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Once the connector answered any challenge.
+    fn introduced(&mut self, config: &AcceptConfig) {
+        let handshake = self.handshake.unwrap();
+        set_congestion(&self.stream, &handshake, &config.congestion);
+        match handshake.stream_id {
+            StreamHandshake::CTRL_STREAM_ID => {
+                self.start(IntroductionStep::CommHandshake, vec![0u8; 4]);
+            }
+            _ => self.step = IntroductionStep::Tls,
+        }
+    }
+}
+
+/// Of `CommHandshake::capabilities`, those offered.
+fn capabilities(work_stealing: bool) -> u32 {
+    match work_stealing {
+        true => CommHandshake::WORK_STEALING,
+        false => 0,
+    }
+}
+
+/// Keeping the streams of closed comms open for the next comm between the
+/// same peers, which NCCL recreates a lot while searching for graphs.
+#[derive(Debug, Clone)]
+pub struct ConnCacheConfig {
+    pub enabled: bool,
+    /// Parked send comms kept at most, the oldest go first.
+    pub capacity: usize,
+    /// How long a send comm stays parked. Receivers keep their end twice as
+    /// long, so that senders do not revive streams that were just dropped.
+    pub ttl: Duration,
+}
+
+impl ConnCacheConfig {
+    pub fn from_env() -> ConnCacheConfig {
+        ConnCacheConfig {
+            enabled: std::env::var("BAGUA_NET_CONN_CACHE").unwrap_or("0".to_owned()) == "1",
+            capacity: std::env::var("BAGUA_NET_CONN_CACHE_SIZE")
+                .unwrap_or("64".to_owned())
+                .parse()
+                .unwrap(),
+            ttl: Duration::from_millis(
+                std::env::var("BAGUA_NET_CONN_CACHE_TTL_MS")
+                    .unwrap_or("60000".to_owned())
+                    .parse()
+                    .unwrap(),
+            ),
+        }
+    }
+}
+
+/// Replacing a data stream that was reset mid-transfer, e.g. by a flapping
+/// switch port.
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    /// Replacement attempts per reset, 0 disables reconnecting.
+    pub retries: usize,
+    /// How many of the last bytes written a sender keeps, to replay those
+    /// that were still in flight when the stream broke. By default what its
+    /// send buffer and the receive buffer of the peer hold, see
+    /// `window_bytes`.
+    pub window: Option<usize>,
+    /// How long a receiver waits for the replacement of a broken stream.
+    pub timeout: Duration,
+}
+
+impl ReconnectConfig {
+    pub fn from_env() -> ReconnectConfig {
+        ReconnectConfig {
+            retries: std::env::var("BAGUA_NET_RECONNECT_RETRIES")
+                .unwrap_or("3".to_owned())
+                .parse()
+                .unwrap(),
+            window: std::env::var("BAGUA_NET_RECONNECT_WINDOW_BYTES")
+                .ok()
+                .map(|window| window.parse().unwrap()),
+            timeout: Duration::from_millis(
+                std::env::var("BAGUA_NET_RECONNECT_TIMEOUT_MS")
+                    .unwrap_or("10000".to_owned())
+                    .parse()
+                    .unwrap(),
+            ),
+        }
+    }
+
+    /// Of the data stream `stream`, whose receiver buffers up to
+    /// `peer_rcvbuf`: nothing older can still be in flight.
+    pub fn window_bytes(
+        &self,
+        stream: &Stream,
+        buffers: &SocketBufferConfig,
+        peer_rcvbuf: usize,
+    ) -> usize {
+        self.window
+            .unwrap_or_else(|| buffers.sndbuf_limit(stream) + peer_rcvbuf)
+    }
+}
+
+/// With `BAGUA_NET_NSTREAMS=auto`, comms start with `INITIAL_NSTREAMS` data
+/// streams, and a send comm opens more while it is backlogged but uses less
+/// than `saturation` of the link speed. New streams are accepted like the
+/// replacements of reset ones, so this needs reconnecting to be enabled.
+#[derive(Debug, Clone)]
+pub struct AdaptiveStreamsConfig {
+    pub max_nstreams: usize,
+    /// Over which the throughput of a send comm is measured.
+    pub window: Duration,
+    pub saturation: f64,
+}
+
+impl AdaptiveStreamsConfig {
+    pub const INITIAL_NSTREAMS: usize = 2;
+
+    /// `None` unless `BAGUA_NET_NSTREAMS=auto`.
+    pub fn from_env() -> Option<AdaptiveStreamsConfig> {
+        if std::env::var("BAGUA_NET_NSTREAMS").unwrap_or("2".to_owned()) != "auto" {
+            return None;
+        }
+
+        Some(AdaptiveStreamsConfig {
+            max_nstreams: std::env::var("BAGUA_NET_MAX_NSTREAMS")
+                .unwrap_or("8".to_owned())
+                .parse()
+                .unwrap(),
+            window: Duration::from_millis(
+                std::env::var("BAGUA_NET_NSTREAMS_WINDOW_MS")
+                    .unwrap_or("1000".to_owned())
+                    .parse()
+                    .unwrap(),
+            ),
+            saturation: std::env::var("BAGUA_NET_NSTREAMS_SATURATION")
+                .unwrap_or("0.8".to_owned())
+                .parse()
+                .unwrap(),
+        })
+    }
+
+    /// `BAGUA_NET_NSTREAMS`, the data streams a comm starts with.
+    pub fn nstreams_from_env() -> usize {
+        match std::env::var("BAGUA_NET_NSTREAMS") {
+            Ok(nstreams) if nstreams == "auto" => AdaptiveStreamsConfig::INITIAL_NSTREAMS,
+            nstreams => nstreams.unwrap_or("2".to_owned()).parse().unwrap(),
+        }
+    }
+}
+
+/// Unless `BAGUA_NET_IMBALANCE_RATIO=0`, a send comm warns once its fastest
+/// data stream writes more than `ratio` times as fast as its slowest, over
+/// `windows` windows in a row. With `BAGUA_NET_IMBALANCE_REHASH=1` it then
+/// replaces the slowest one, whose new source port may hash it onto another
+/// path. Like reset streams, that needs reconnecting to be enabled, and it is
+/// only done for comms whose streams steal their chunks, as the receiver
+/// takes a replacement once its chunks are in.
+#[derive(Debug, Clone)]
+pub struct ImbalanceConfig {
+    pub ratio: f64,
+    pub window: Duration,
+    pub windows: usize,
+    pub rehash: bool,
+}
+
+impl ImbalanceConfig {
+    pub fn from_env() -> Option<ImbalanceConfig> {
+        let ratio: f64 = std::env::var("BAGUA_NET_IMBALANCE_RATIO")
+            .unwrap_or("2".to_owned())
+            .parse()
+            .unwrap();
+        if ratio <= 0. {
+            return None;
+        }
+
+        Some(ImbalanceConfig {
+            ratio,
+            window: Duration::from_millis(
+                std::env::var("BAGUA_NET_IMBALANCE_WINDOW_MS")
+                    .unwrap_or("1000".to_owned())
+                    .parse()
+                    .unwrap(),
+            ),
+            windows: std::env::var("BAGUA_NET_IMBALANCE_WINDOWS")
+                .unwrap_or("3".to_owned())
+                .parse()
+                .unwrap(),
+            rehash: std::env::var("BAGUA_NET_IMBALANCE_REHASH").unwrap_or("0".to_owned()) == "1",
+        })
+    }
+}
+
+/// Whether `err` means that the connection was reset under the stream, which
+/// a replacement can recover from. A peer that closed its end is not.
+pub fn is_stream_reset(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// The last bytes written to a data stream, in a ring allocated on the first
+/// write.
+#[derive(Debug)]
+pub struct ReplayWindow {
+    /// Byte `i` of the stream is at `i % capacity`.
+    ring: Vec<u8>,
+    capacity: usize,
+    written: u64,
+}
+
+impl ReplayWindow {
+    pub fn new(capacity: usize) -> ReplayWindow {
+        ReplayWindow {
+            ring: Vec::new(),
+            capacity,
+            written: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Total bytes written to the stream, replacements included.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn record(&mut self, buf: &[u8]) {
+        self.written += buf.len() as u64;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.ring.is_empty() {
+            self.ring = vec![0u8; self.capacity];
+        }
+        let buf = &buf[buf.len().saturating_sub(self.capacity)..];
+        let start = ((self.written - buf.len() as u64) % self.capacity as u64) as usize;
+        let (head, tail) = buf.split_at(std::cmp::min(buf.len(), self.capacity - start));
+        self.ring[start..start + head.len()].copy_from_slice(head);
+        self.ring[..tail.len()].copy_from_slice(tail);
+    }
+
+    /// What follows the first `received` bytes, in at most two parts of the
+    /// ring, `None` if it is no longer all kept.
+    pub fn since(&self, received: u64) -> Option<[&[u8]; 2]> {
+        let missed = self.written.checked_sub(received)? as usize;
+        if missed == 0 {
+            return Some([&[], &[]]);
+        }
+        if missed > std::cmp::min(self.written, self.capacity as u64) as usize {
+            return None;
+        }
+
+        let start = (received % self.capacity as u64) as usize;
+        let head = &self.ring[start..std::cmp::min(start + missed, self.capacity)];
+        Some([head, &self.ring[..missed - head.len()]])
+    }
+}
+
+type ReconnectRoutes = Arc<Mutex<HashMap<(Uuid, usize), flume::Sender<Stream>>>>;
+
+/// Accepts the replacements of broken data streams and hands each to the
+/// receiver of its stream. Shared by the recv comms of a device, unlike a
+/// listen comm it lives as long as they do. Its thread ends once it is
+/// dropped, after the handshake it may be reading.
+pub struct ReconnectAcceptor {
+    pub port: u16,
+    routes: ReconnectRoutes,
+    /// Of the thread's listener, shut down to wake it.
+    listener: net::TcpListener,
+    stopped: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+    handshake_timeout: Duration,
+}
+
+impl ReconnectAcceptor {
+    pub fn spawn(
+        threads: &LiveThreads,
+        addr: InetAddr,
+        listen_config: &ListenConfig,
+        accept_config: &AcceptConfig,
+    ) -> Result<ReconnectAcceptor, BaguaNetError> {
+        let listener = bind_listener(
+            addr,
+            &ListenConfig {
+                uds: false,
+                ..listen_config.clone()
+            },
+        )?;
+        let port = listener
+            .tcp
+            .local_addr()
+            .and_then(|local_addr| listener.tcp.set_nonblocking(false).map(|_| local_addr))
+            .map_err(BaguaNetError::from)?
+            .port();
+        let routes = ReconnectRoutes::default();
+        let accept_config = accept_config.clone();
+        let handshake_timeout = accept_config.handshake_timeout;
+        let thread_routes = routes.clone();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = stopped.clone();
+        let shut = listener.tcp.try_clone().map_err(BaguaNetError::from)?;
+        let thread = threads.spawn(move || loop {
+            let (stream, addr) = match listener.tcp.accept() {
+                Ok(accepted) => accepted,
+                Err(_) if thread_stopped.load(Ordering::Acquire) => return,
+                Err(err) => {
+                    tracing::warn!("accept replacement stream failed, err={:?}", err);
+                    std::thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            let mut stream = Stream::Tcp(stream);
+            set_keepalive(&stream, &accept_config.keepalive);
+            let handshake = stream
+                .set_read_timeout(Some(accept_config.handshake_timeout))
+                .and_then(|_| read_stream_handshake(&mut stream, &accept_config))
+                .and_then(|handshake| stream.set_read_timeout(None).map(|_| handshake));
+            let handshake = match handshake {
+                Ok(handshake) => handshake,
+                Err(err) => {
+                    tracing::warn!("drop stream from {:?}, bad handshake, err={:?}", addr, err);
+                    continue;
+                }
+            };
+            set_congestion(&stream, &handshake, &accept_config.congestion);
+            let stream = match accept_tls(stream, &accept_config) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::warn!("drop stream from {:?}, err={:?}", addr, err);
+                    continue;
+                }
+            };
+
+            let route = thread_routes
+                .lock()
+                .unwrap()
+                .get(&(handshake.comm_uuid, handshake.stream_id))
+                .cloned();
+            match route {
+                Some(route) if route.send(stream).is_ok() => {
+                    tracing::debug!("replacement of {:?} from {:?}", handshake, addr);
+                }
+                _ => {
+                    tracing::warn!("drop replacement of unknown stream {:?}", handshake);
+                }
+            }
+        });
+
+        Ok(ReconnectAcceptor {
+            port,
+            routes,
+            listener: shut,
+            stopped,
+            thread: Some(thread),
+            handshake_timeout,
+        })
+    }
+
+    /// The replacements of data stream `stream_id` of send comm `comm_uuid`.
+    pub fn route(&self, comm_uuid: Uuid, stream_id: usize) -> ReconnectRoute {
+        let (sender, replacements) = flume::unbounded();
+        let key = (comm_uuid, stream_id);
+        self.routes.lock().unwrap().insert(key, sender);
+
+        ReconnectRoute {
+            key,
+            routes: self.routes.clone(),
+            replacements,
+        }
+    }
+}
+
+impl Drop for ReconnectAcceptor {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        // Fails the accept the thread is blocked in, if any.
+        let _ =
+            nix::sys::socket::shutdown(self.listener.as_raw_fd(), nix::sys::socket::Shutdown::Both);
+        let deadline = Instant::now() + self.handshake_timeout;
+        if let Some(thread) = self.thread.take() {
+            if !utils::join_until(thread, deadline) {
+                tracing::warn!(
+                    "reconnect acceptor on port {} still running {:?} after it was dropped",
+                    self.port,
+                    self.handshake_timeout
+                );
+            }
+        }
+    }
+}
+
+/// Replacements are dropped again once this is.
+pub struct ReconnectRoute {
+    key: (Uuid, usize),
+    routes: ReconnectRoutes,
+    pub replacements: flume::Receiver<Stream>,
+}
+
+impl Drop for ReconnectRoute {
+    fn drop(&mut self) {
+        self.routes.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Where to open the replacements of the data streams of a send comm to
+/// `socket_handle`, whose acceptor answered with `reconnect_port`.
+pub fn reconnect_handle(
+    socket_handle: &SocketHandle,
+    reconnect_port: u16,
+) -> Result<SocketHandle, BaguaNetError> {
+    let mut addr = peer_socket_addr(socket_handle)?;
+    addr.set_port(reconnect_port);
+
+    Ok(SocketHandle {
+        addr: SockAddr::new_inet(InetAddr::from_std(&addr)),
+        alt_addr: None,
+        uds_addr: None,
+        hostname: socket_handle.hostname.clone(),
+    })
+}
+
+/// Opens the replacement of the data stream of `handshake`, returns it with
+/// how many bytes the receiver got on the broken one.
+pub fn reconnect_stream(
+    socket_handle: &SocketHandle,
+    handshake: StreamHandshake,
+    connect_config: &ConnectConfig,
+    timeout: Duration,
+) -> Result<(Stream, u64), BaguaNetError> {
+    let mut stream = connect_stream(socket_handle, handshake, connect_config)?;
+    let mut received = [0u8; 8];
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.read_exact(&mut received[..]))
+        .and_then(|_| stream.set_read_timeout(None))
+        .map_err(|err| {
+            BaguaNetError::remote_io(
+                format!(
+                    "peer={}, replacement of {:?} not adopted",
+                    stream.peer(),
+                    handshake
+                ),
+                err,
+            )
+        })?;
+
+    Ok((stream, u64::from_be_bytes(received)))
+}
+
+/// Takes over `replacement` on the receiver, tells the sender to go on after
+/// the first `received` bytes.
+pub fn adopt_stream(replacement: &mut Stream, received: u64) -> io::Result<()> {
+    replacement.write_all(&received.to_be_bytes()[..])
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::interface::ErrorKind;
+
+    #[derive(Clone, Default)]
+    struct EventLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for EventLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Of `f`, with the events it logged on this thread, one per line.
+    pub fn logged<R>(f: impl FnOnce() -> R) -> (R, String) {
+        let log = EventLog::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .with_max_level(tracing::Level::TRACE)
+            .finish();
+        let ret = tracing::subscriber::with_default(subscriber, f);
+        let logged = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        (ret, logged)
+    }
+
+    fn tcp_listener(addr: &str) -> Listener {
+        let tcp = net::TcpListener::bind(addr).unwrap();
+        tcp.set_nonblocking(true).unwrap();
+        let (closer, woken) = ListenCloser::new().unwrap();
+        Listener {
+            tcp,
+            alt_tcp: None,
+            unix: None,
+            reconnect_port: 0,
+            rcvbuf: 0,
+            hostname: None,
+            _port_reservation: None,
+            _alt_port_reservation: None,
+            closer,
+            woken,
+        }
+    }
+
+    /// Polls `accept_stream_group` like the NCCL proxy thread does.
+    fn accept_blocking(
+        listener: &Listener,
+        pending: &mut PendingStreams,
+        nstreams: usize,
+        config: &AcceptConfig,
+    ) -> Result<StreamGroup, BaguaNetError> {
+        loop {
+            if let Some(group) = accept_stream_group(listener, pending, nstreams, config)? {
+                return Ok(group);
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    /// Polls `accept_stream_group` until the returned flag is set, failing if
+    /// it accepts anything, and returns what it logged.
+    fn rejecting_acceptor(
+        listener: Listener,
+        nstreams: usize,
+        config: AcceptConfig,
+    ) -> (Arc<AtomicBool>, std::thread::JoinHandle<String>) {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            logged(|| {
+                while !stopped.load(Ordering::SeqCst) {
+                    assert!(
+                        accept_stream_group(&listener, &mut pending, nstreams, &config)
+                            .unwrap()
+                            .is_none()
+                    );
+                    std::thread::yield_now();
+                }
+                assert!(pending.groups.is_empty());
+            })
+            .1
+        });
+        (stop, acceptor)
+    }
+
+    #[test]
+    fn test_handshake_bytes() {
+        let handshake = StreamHandshake {
+            comm_uuid: Uuid::new_v4(),
+            stream_id: 3,
+        };
+
+        assert_eq!(
+            StreamHandshake::from_bytes(&handshake.to_bytes()),
+            handshake
+        );
+    }
+
+    #[test]
+    fn test_chunk_header_bytes() {
+        let header = ChunkHeader {
+            message: 7,
+            index: 3,
+            nbytes: 1 << 40,
+        };
+        assert_eq!(ChunkHeader::from_bytes(&header.to_bytes()), header);
+    }
+
+    #[test]
+    fn test_comm_handshake_bytes() {
+        let handshake = CommHandshake::local(8, false, 0, 0, 0, false, false, false, 0);
+        assert_eq!(CommHandshake::from_bytes(&handshake.to_bytes()), handshake);
+        assert!(handshake.check(&handshake).is_ok());
+
+        let reply = CommHandshake {
+            reconnect_port: 20001,
+            rcvbuf: 6 << 20,
+            ..handshake
+        };
+        assert_eq!(CommHandshake::from_bytes(&reply.to_bytes()), reply);
+        assert!(handshake.check(&reply).is_ok());
+
+        let tls = CommHandshake::local(8, true, 0, 0, 0, false, false, false, 0);
+        assert_eq!(CommHandshake::from_bytes(&tls.to_bytes()), tls);
+        assert!(handshake.check(&tls).is_err());
+
+        let inline = CommHandshake::local(8, false, 4096, 0, 0, false, false, false, 0);
+        assert_eq!(CommHandshake::from_bytes(&inline.to_bytes()), inline);
+        assert!(handshake.check(&inline).is_err());
+
+        let chunked = CommHandshake::local(8, false, 0, 65536, 0, false, false, false, 0);
+        assert_eq!(CommHandshake::from_bytes(&chunked.to_bytes()), chunked);
+        assert!(handshake.check(&chunked).is_err());
+
+        let pipelined = CommHandshake::local(8, false, 0, 65536, 262144, false, false, false, 0);
+        assert_eq!(CommHandshake::from_bytes(&pipelined.to_bytes()), pipelined);
+        assert!(handshake.check(&pipelined).is_err());
+        assert!(chunked.check(&pipelined).is_err());
+
+        let checked = CommHandshake::local(8, false, 0, 0, 0, true, false, false, 0);
+        assert_eq!(CommHandshake::from_bytes(&checked.to_bytes()), checked);
+        assert!(handshake.check(&checked).is_err());
+
+        let crc = CommHandshake::local(8, false, 0, 0, 0, false, true, false, 0);
+        assert_eq!(CommHandshake::from_bytes(&crc.to_bytes()), crc);
+        let msg = format!("{:?}", handshake.check(&crc).unwrap_err());
+        assert!(msg.contains("BAGUA_NET_CRC"), "{}", msg);
+
+        let compressed = CommHandshake::local(8, false, 0, 0, 0, false, false, true, 0);
+        assert_eq!(
+            CommHandshake::from_bytes(&compressed.to_bytes()),
+            compressed
+        );
+        let msg = format!("{:?}", handshake.check(&compressed).unwrap_err());
+        assert!(msg.contains("BAGUA_NET_COMPRESSION"), "{}", msg);
+
+        let adaptive = CommHandshake::local(8, false, 0, 0, 0, false, false, false, 16);
+        assert_eq!(CommHandshake::from_bytes(&adaptive.to_bytes()), adaptive);
+        assert!(handshake.check(&adaptive).is_err());
+
+        let tuned = CommHandshake::local(
+            8,
+            false,
+            0,
+            CommHandshake::AUTO_MIN_CHUNKSIZE,
+            0,
+            false,
+            false,
+            false,
+            0,
+        );
+        assert_eq!(CommHandshake::from_bytes(&tuned.to_bytes()), tuned);
+        let msg = format!("{:?}", handshake.check(&tuned).unwrap_err());
+        assert!(msg.contains("BAGUA_NET_MIN_CHUNKSIZE"), "{}", msg);
+
+        // Offered, not checked.
+        let stealing = CommHandshake {
+            capabilities: CommHandshake::WORK_STEALING,
+            ..handshake
+        };
+        assert_eq!(CommHandshake::from_bytes(&stealing.to_bytes()), stealing);
+        assert!(handshake.check(&stealing).is_ok());
+        assert_eq!(handshake.negotiated(&stealing), 0);
+        assert_eq!(stealing.negotiated(&stealing), CommHandshake::WORK_STEALING);
+    }
+
+    #[test]
+    fn test_comm_handshake() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            accept_blocking(&listener, &mut pending, 1, &AcceptConfig::from_env())
+                .map(|group| group.comm_uuid)
+        });
+        let comm_uuid = Uuid::new_v4();
+        let config = ConnectConfig::from_env();
+        let (_ctrl, _) = connect_ctrl_stream(&socket_handle, comm_uuid, 1, &config).unwrap();
+        let _data = connect_stream(
+            &socket_handle,
+            StreamHandshake {
+                comm_uuid,
+                stream_id: 0,
+            },
+            &config,
+        )
+        .unwrap();
+        assert_eq!(acceptor.join().unwrap().unwrap(), comm_uuid);
+    }
+
+    #[test]
+    fn test_comm_handshake_slow_accept() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+        let connect_config = ConnectConfig {
+            timeout: Duration::from_millis(100),
+            ..ConnectConfig::from_env()
+        };
+
+        // NCCL calls accept long after the connect.
+        let acceptor = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(500));
+            let mut pending = PendingStreams::default();
+            accept_blocking(&listener, &mut pending, 0, &AcceptConfig::from_env()).unwrap()
+        });
+        connect_ctrl_stream(&socket_handle, Uuid::new_v4(), 0, &connect_config).unwrap();
+        acceptor.join().unwrap();
+    }
+
+    #[test]
+    fn test_comm_handshake_mismatch() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+
+        // The acceptor expects 4 streams, the connector brings 2.
+        let (stop, acceptor) = rejecting_acceptor(listener, 4, AcceptConfig::from_env());
+        let err = connect_ctrl_stream(
+            &socket_handle,
+            Uuid::new_v4(),
+            2,
+            &ConnectConfig::from_env(),
+        )
+        .unwrap_err();
+        let msg = format!("{:?}", err);
+        assert!(msg.contains("local nstreams=2, peer nstreams=4"), "{}", msg);
+
+        stop.store(true, Ordering::SeqCst);
+        let events = acceptor.join().unwrap();
+        assert!(events.contains("comm handshake mismatch"), "{}", events);
+        assert!(
+            events.contains("local nstreams=4, peer nstreams=2"),
+            "{}",
+            events
+        );
+    }
+
+    #[test]
+    fn test_comm_handshake_version_mismatch() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let addr = listener.tcp.local_addr().unwrap();
+
+        let (stop, acceptor) = rejecting_acceptor(listener, 1, AcceptConfig::from_env());
+        // A peer speaking a future version of the protocol.
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        StreamHandshake {
+            comm_uuid: Uuid::new_v4(),
+            stream_id: StreamHandshake::CTRL_STREAM_ID,
+        }
+        .write_to(&mut stream)
+        .unwrap();
+        CommHandshake {
+            version: CommHandshake::VERSION + 1,
+            ..CommHandshake::local(1, false, 0, 0, 0, false, false, false, 0)
+        }
+        .write_to(&mut stream)
+        .unwrap();
+        assert_eq!(
+            CommHandshake::read_from(&mut stream).unwrap(),
+            CommHandshake::local(1, false, inline_threshold(), 0, 0, false, false, false, 0)
+        );
+
+        stop.store(true, Ordering::SeqCst);
+        let events = acceptor.join().unwrap();
+        assert!(
+            events.contains(&format!(
+                "local version={}, peer version={}",
+                CommHandshake::VERSION,
+                CommHandshake::VERSION + 1
+            )),
+            "{}",
+            events
+        );
+    }
+
+    #[test]
+    fn test_interleaved_connects() {
+        let nstreams = 2;
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+
+        // Two send comms open their streams in an interleaved order.
+        let uuids = [Uuid::new_v4(), Uuid::new_v4()];
+        let mut connected = Vec::new();
+        for index in (0..=nstreams).rev() {
+            for (i, comm_uuid) in uuids.iter().enumerate() {
+                let stream_id = if index == nstreams {
+                    StreamHandshake::CTRL_STREAM_ID
+                } else {
+                    index
+                };
+                let mut stream = connect_stream(
+                    &socket_handle,
+                    StreamHandshake {
+                        comm_uuid: *comm_uuid,
+                        stream_id,
+                    },
+                    &ConnectConfig::from_env(),
+                )
+                .unwrap();
+                if stream_id == StreamHandshake::CTRL_STREAM_ID {
+                    // Not waiting for the answer, nobody accepts yet.
+                    CommHandshake::local(
+                        nstreams,
+                        false,
+                        inline_threshold(),
+                        0,
+                        0,
+                        false,
+                        false,
+                        false,
+                        0,
+                    )
+                    .write_to(&mut stream)
+                    .unwrap();
+                }
+                stream.write_all(&[i as u8, index as u8]).unwrap();
+                connected.push(stream);
+            }
+        }
+
+        let mut pending = PendingStreams::default();
+        for _ in 0..uuids.len() {
+            let mut group =
+                accept_blocking(&listener, &mut pending, nstreams, &AcceptConfig::from_env())
+                    .unwrap();
+            let i = uuids.iter().position(|u| *u == group.comm_uuid).unwrap();
+
+            let mut streams: Vec<&mut Stream> = group.data_streams.iter_mut().collect();
+            streams.push(&mut group.ctrl_stream);
+            for (index, stream) in streams.into_iter().enumerate() {
+                let mut buf = [0u8; 2];
+                stream.read_exact(&mut buf[..]).unwrap();
+                assert_eq!(buf, [i as u8, index as u8]);
+            }
+        }
+        assert!(pending.groups.is_empty());
+    }
+
+    #[test]
+    fn test_connect_retry_delay() {
+        let retry = ConnectConfig {
+            retries: 8,
+            backoff: Duration::from_millis(100),
+            timeout: Duration::from_secs(1),
+            handshake_timeout: None,
+            bind_addr: None,
+            keepalive: None,
+            congestion: None,
+            buffers: Default::default(),
+            uds: false,
+            unix_peer: None,
+            tls: None,
+            auth_key: None,
+            inline_threshold: 0,
+            min_chunksize: 0,
+            chunk_bytes: 0,
+            seq_check: false,
+            crc: false,
+            compression: false,
+            max_nstreams: 0,
+            work_stealing: false,
+        };
+
+        for attempt in 0..4 {
+            let exp = Duration::from_millis(100 << attempt);
+            let delay = retry.delay(attempt);
+            assert!(delay >= exp / 2 && delay <= exp, "{:?}", delay);
+        }
+        assert!(retry.delay(100) <= ConnectConfig::MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_parse_log_level() {
+        assert_eq!(parse_log_level("warn"), Some(Some(tracing::Level::WARN)));
+        assert_eq!(
+            parse_log_level(" DEBUG "),
+            Some(Some(tracing::Level::DEBUG))
+        );
+        assert_eq!(parse_log_level("off"), Some(None));
+        assert_eq!(parse_log_level("loud"), None);
+        // At `info` by default, connections are, not their attempts.
+        assert!(logs_connections(tracing::Level::WARN));
+        assert!(logs_connections(tracing::Level::INFO));
+        assert!(!logs_connections(tracing::Level::DEBUG));
+    }
+
+    #[test]
+    fn test_connection_events() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket_handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&addr)),
+            alt_addr: None,
+            uds_addr: None,
+            hostname: None,
+        };
+        let config = ConnectConfig {
+            retries: 0,
+            ..ConnectConfig::from_env()
+        };
+        let handshake = StreamHandshake {
+            comm_uuid: Uuid::new_v4(),
+            stream_id: 3,
+        };
+        let (connected, events) = logged(|| connect_stream(&socket_handle, handshake, &config));
+        connected.unwrap();
+        assert!(events.contains("stream connected"), "{}", events);
+        assert!(events.contains(&format!("comm_uuid={}", handshake.comm_uuid)));
+        assert!(events.contains("stream=3"), "{}", events);
+        assert!(events.contains(&format!("peer={}", addr)), "{}", events);
+        assert!(events.contains("duration_us="), "{}", events);
+
+        drop(listener);
+        let handshake = StreamHandshake {
+            stream_id: StreamHandshake::CTRL_STREAM_ID,
+            ..handshake
+        };
+        let (connected, events) = logged(|| connect_stream(&socket_handle, handshake, &config));
+        assert!(connected.is_err());
+        assert!(events.contains("WARN"), "{}", events);
+        assert!(events.contains("stream connect failed"), "{}", events);
+        assert!(events.contains("stream=ctrl"), "{}", events);
+        assert!(events.contains("error="), "{}", events);
+    }
+
+    #[test]
+    fn test_connect_retry_exhausted() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let socket_handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&addr)),
+            alt_addr: None,
+            uds_addr: None,
+            hostname: None,
+        };
+        let retry = ConnectConfig {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+            timeout: Duration::from_secs(1),
+            handshake_timeout: None,
+            bind_addr: None,
+            keepalive: None,
+            congestion: None,
+            buffers: Default::default(),
+            uds: false,
+            unix_peer: None,
+            tls: None,
+            auth_key: None,
+            inline_threshold: 0,
+            min_chunksize: 0,
+            chunk_bytes: 0,
+            seq_check: false,
+            crc: false,
+            compression: false,
+            max_nstreams: 0,
+            work_stealing: false,
+        };
+        let handshake = StreamHandshake {
+            comm_uuid: Uuid::new_v4(),
+            stream_id: 0,
+        };
+        match connect_stream(&socket_handle, handshake, &retry) {
+            Err(err @ BaguaNetError::TCPError { .. }) => {
+                assert_eq!(err.kind(), ErrorKind::RemoteError);
+                assert_eq!(err.nccl_result(), 6);
+                assert!(err.to_string().contains("attempts=3"), "{}", err);
+                let source = std::error::Error::source(&err)
+                    .and_then(|source| source.downcast_ref::<Arc<std::io::Error>>())
+                    .unwrap();
+                assert_eq!(source.kind(), std::io::ErrorKind::ConnectionRefused);
+            }
+            _ => panic!("connect to a closed port should fail"),
+        }
+    }
+
+    #[test]
+    fn test_connect_retry_late_listener() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let late_listener = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            let listener = net::TcpListener::bind(addr).unwrap();
+            listener.accept().unwrap();
+        });
+
+        let socket_handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&addr)),
+            alt_addr: None,
+            uds_addr: None,
+            hostname: None,
+        };
+        let retry = ConnectConfig {
+            retries: 10,
+            backoff: Duration::from_millis(20),
+            timeout: Duration::from_secs(1),
+            handshake_timeout: None,
+            bind_addr: None,
+            keepalive: None,
+            congestion: None,
+            buffers: Default::default(),
+            uds: false,
+            unix_peer: None,
+            tls: None,
+            auth_key: None,
+            inline_threshold: 0,
+            min_chunksize: 0,
+            chunk_bytes: 0,
+            seq_check: false,
+            crc: false,
+            compression: false,
+            max_nstreams: 0,
+            work_stealing: false,
+        };
+        let handshake = StreamHandshake {
+            comm_uuid: Uuid::new_v4(),
+            stream_id: 0,
+        };
+        connect_stream(&socket_handle, handshake, &retry).unwrap();
+        late_listener.join().unwrap();
+    }
+
+    #[test]
+    fn test_accept_evicts_stale_group() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+        let config = AcceptConfig {
+            collect_timeout: Some(Duration::from_millis(200)),
+            handshake_timeout: Duration::from_millis(50),
+            ..AcceptConfig::from_env()
+        };
+        let mut pending = PendingStreams::default();
+
+        // A connector that never introduces itself is dropped once its
+        // handshake times out.
+        let _silent = net::TcpStream::connect(listener.tcp.local_addr().unwrap()).unwrap();
+        assert!(accept_stream_group(&listener, &mut pending, 1, &config)
+            .unwrap()
+            .is_none());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(accept_stream_group(&listener, &mut pending, 1, &config)
+            .unwrap()
+            .is_none());
+        assert!(pending.introducing.is_empty());
+        assert!(pending.groups.is_empty());
+
+        // A send comm that never opens its master stream does not hold up
+        // the accept while its group is collecting.
+        let _data = connect_stream(
+            &socket_handle,
+            StreamHandshake {
+                comm_uuid: Uuid::new_v4(),
+                stream_id: 0,
+            },
+            &ConnectConfig::from_env(),
+        )
+        .unwrap();
+        let timer = Instant::now();
+        while pending.groups.is_empty() {
+            assert!(accept_stream_group(&listener, &mut pending, 1, &config)
+                .unwrap()
+                .is_none());
+            assert!(timer.elapsed() < Duration::from_secs(5));
+        }
+        assert!(accept_stream_group(&listener, &mut pending, 1, &config)
+            .unwrap()
+            .is_none());
+        assert!(timer.elapsed() < Duration::from_millis(200));
+        std::thread::sleep(Duration::from_millis(200));
+
+        // The next send comm is accepted, and the abandoned group dropped.
+        let comm_uuid = Uuid::new_v4();
+        let connector = std::thread::spawn(move || {
+            let connect_config = ConnectConfig::from_env();
+            let (ctrl, _) =
+                connect_ctrl_stream(&socket_handle, comm_uuid, 1, &connect_config).unwrap();
+            let data = connect_stream(
+                &socket_handle,
+                StreamHandshake {
+                    comm_uuid,
+                    stream_id: 0,
+                },
+                &connect_config,
+            )
+            .unwrap();
+            (ctrl, data)
+        });
+        let group = accept_blocking(&listener, &mut pending, 1, &config).unwrap();
+        let _streams = connector.join().unwrap();
+        assert_eq!(group.comm_uuid, comm_uuid);
+        assert!(pending.groups.is_empty());
+        assert!(pending.capabilities.is_empty());
+    }
+
+    #[test]
+    fn test_accept_silent_connector() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+        let config = AcceptConfig {
+            handshake_timeout: Duration::from_secs(5),
+            ..AcceptConfig::from_env()
+        };
+        let mut pending = PendingStreams::default();
+
+        // Parked while it says nothing, the accept does not wait for it.
+        let _silent = net::TcpStream::connect(listener.tcp.local_addr().unwrap()).unwrap();
+        let timer = Instant::now();
+        while pending.introducing.is_empty() {
+            assert!(accept_stream_group(&listener, &mut pending, 1, &config)
+                .unwrap()
+                .is_none());
+        }
+        assert!(accept_stream_group(&listener, &mut pending, 1, &config)
+            .unwrap()
+            .is_none());
+        assert!(timer.elapsed() < Duration::from_secs(1));
+
+        // Nor does the send comm that connects after it.
+        let comm_uuid = Uuid::new_v4();
+        let connector = std::thread::spawn(move || {
+            let connect_config = ConnectConfig::from_env();
+            let (ctrl, _) =
+                connect_ctrl_stream(&socket_handle, comm_uuid, 1, &connect_config).unwrap();
+            let data = connect_stream(
+                &socket_handle,
+                StreamHandshake {
+                    comm_uuid,
+                    stream_id: 0,
+                },
+                &connect_config,
+            )
+            .unwrap();
+            (ctrl, data)
+        });
+        let group = accept_blocking(&listener, &mut pending, 1, &config).unwrap();
+        let _streams = connector.join().unwrap();
+        assert_eq!(group.comm_uuid, comm_uuid);
+        assert!(timer.elapsed() < Duration::from_secs(1));
+        assert_eq!(pending.introducing.len(), 1);
+
+        let expired = AcceptConfig {
+            handshake_timeout: Duration::ZERO,
+            ..config
+        };
+        assert!(accept_stream_group(&listener, &mut pending, 1, &expired)
+            .unwrap()
+            .is_none());
+        assert!(pending.introducing.is_empty());
+    }
+
+    #[test]
+    fn test_accept_nonblocking() {
+        let listener = bind_listener(
+            InetAddr::from_std(&"127.0.0.1:0".parse().unwrap()),
+            &ListenConfig {
+                port_range: None,
+                backlog: ListenConfig::DEFAULT_BACKLOG,
+                reuse_port: false,
+                buffers: Default::default(),
+                uds: false,
+                advertise_hostname: None,
+            },
+        )
+        .unwrap();
+        let config = AcceptConfig {
+            collect_timeout: None,
+            handshake_timeout: Duration::from_secs(1),
+            keepalive: None,
+            congestion: None,
+            tls: None,
+            auth_key: None,
+            inline_threshold: 0,
+            min_chunksize: 0,
+            chunk_bytes: 0,
+            seq_check: false,
+            crc: false,
+            compression: false,
+            max_nstreams: 0,
+            work_stealing: false,
+            quickack: QuickAck::Off,
+        };
+        let mut pending = PendingStreams::default();
+
+        // Nobody connects, even without a collection timeout.
+        let timer = Instant::now();
+        assert!(accept_stream_group(&listener, &mut pending, 1, &config)
+            .unwrap()
+            .is_none());
+        assert!(timer.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(parse_port_range("").unwrap(), None);
+        assert_eq!(
+            parse_port_range("20000-20999").unwrap(),
+            Some(20000..=20999)
+        );
+        assert!(parse_port_range("20000").is_err());
+        assert!(parse_port_range("20999-20000").is_err());
+        assert!(parse_port_range("0-10").is_err());
+    }
+
+    #[test]
+    fn test_bind_listener_port_range() {
+        let addr: net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let addr = InetAddr::from_std(&addr);
+        // Find two consecutive free ports for the range.
+        let (start, blocker) = loop {
+            let probe = net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = probe.local_addr().unwrap().port();
+            drop(probe);
+            if port < u16::MAX {
+                if let Ok(blocker) = net::TcpListener::bind(("127.0.0.1", port + 1)) {
+                    break (port, blocker);
+                }
+            }
+        };
+        drop(blocker);
+        let config = ListenConfig {
+            port_range: Some(start..=start + 1),
+            backlog: ListenConfig::DEFAULT_BACKLOG,
+            reuse_port: false,
+            buffers: Default::default(),
+            uds: false,
+            advertise_hostname: None,
+        };
+
+        let first = bind_listener(addr, &config).unwrap();
+        let second = bind_listener(addr, &config).unwrap();
+        let mut ports = vec![
+            first.tcp.local_addr().unwrap().port(),
+            second.tcp.local_addr().unwrap().port(),
+        ];
+        ports.sort_unstable();
+        assert_eq!(ports, vec![start, start + 1]);
+
+        let err = bind_listener(addr, &config).unwrap_err();
+        assert!(format!("{:?}", err).contains(&format!("{}-{}", start, start + 1)));
+
+        let freed = first.tcp.local_addr().unwrap().port();
+        drop(first);
+        let third = bind_listener(addr, &config).unwrap();
+        assert_eq!(third.tcp.local_addr().unwrap().port(), freed);
+    }
+
+    #[test]
+    fn test_rebind_after_close() {
+        let addr: net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let addr = InetAddr::from_std(&addr);
+        let port = net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = ListenConfig {
+            port_range: Some(port..=port),
+            backlog: ListenConfig::DEFAULT_BACKLOG,
+            reuse_port: false,
+            buffers: Default::default(),
+            uds: false,
+            advertise_hostname: None,
+        };
+
+        let listener = bind_listener(addr, &config).unwrap();
+        let client = net::TcpStream::connect(listener.tcp.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.tcp.accept().unwrap();
+        // Closing the accepted side first leaves the listening port in TIME_WAIT.
+        drop(server);
+        drop(client);
+        drop(listener);
+
+        let listener = bind_listener(addr, &config).unwrap();
+        assert_eq!(listener.tcp.local_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn test_connect_bind_addr() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&listener.local_addr().unwrap())),
+            alt_addr: None,
+            uds_addr: None,
+            hostname: None,
+        };
+        let handshake = StreamHandshake {
+            comm_uuid: Uuid::new_v4(),
+            stream_id: 0,
+        };
+        let bind_to = |addr: &str| {
+            let addr: net::SocketAddr = addr.parse().unwrap();
+            ConnectConfig::from_env().bind_to(&SockAddr::new_inet(InetAddr::from_std(&addr)))
+        };
+
+        // The whole 127/8 is local, so the source address shows the bind.
+        let _stream = connect_stream(&socket_handle, handshake, &bind_to("127.0.0.2:0")).unwrap();
+        let (_, peer_addr) = listener.accept().unwrap();
+        assert_eq!(peer_addr.ip(), "127.0.0.2".parse::<net::IpAddr>().unwrap());
+
+        // Not an address of this host, falls back to an unbound socket.
+        let _stream = connect_stream(&socket_handle, handshake, &bind_to("203.0.113.7:0")).unwrap();
+        let (_, peer_addr) = listener.accept().unwrap();
+        assert_eq!(peer_addr.ip(), "127.0.0.1".parse::<net::IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+        let keepalive = KeepaliveConfig {
+            idle: Duration::from_secs(30),
+            interval: Duration::from_secs(3),
+            count: 4,
+        };
+        let connect_config = ConnectConfig {
+            keepalive: Some(keepalive.clone()),
+            ..ConnectConfig::from_env()
+        };
+        let accept_config = AcceptConfig {
+            keepalive: Some(keepalive.clone()),
+            ..AcceptConfig::from_env()
+        };
+
+        let comm_uuid = Uuid::new_v4();
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            accept_blocking(&listener, &mut pending, 1, &accept_config).unwrap()
+        });
+        let (ctrl, _) = connect_ctrl_stream(&socket_handle, comm_uuid, 1, &connect_config).unwrap();
+        let data = connect_stream(
+            &socket_handle,
+            StreamHandshake {
+                comm_uuid,
+                stream_id: 0,
+            },
+            &connect_config,
+        )
+        .unwrap();
+        let group = acceptor.join().unwrap();
+
+        let streams = [&ctrl, &data, &group.ctrl_stream, &group.data_streams[0]];
+        for stream in streams.iter() {
+            let socket = SockRef::from(*stream);
+            assert!(socket.keepalive().unwrap());
+            assert_eq!(socket.keepalive_time().unwrap(), keepalive.idle);
+            assert_eq!(socket.keepalive_interval().unwrap(), keepalive.interval);
+            assert_eq!(socket.keepalive_retries().unwrap(), keepalive.count);
+            assert_eq!(
+                socket.tcp_user_timeout().unwrap(),
+                Some(Duration::from_secs(42))
+            );
+        }
+    }
+
+    #[test]
+    fn test_tcp_congestion() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+        let connect_config = ConnectConfig {
+            congestion: Some("cubic".to_owned()),
+            ..ConnectConfig::from_env()
+        };
+        let accept_config = AcceptConfig {
+            congestion: Some("cubic".to_owned()),
+            ..AcceptConfig::from_env()
+        };
+
+        let comm_uuid = Uuid::new_v4();
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            accept_blocking(&listener, &mut pending, 1, &accept_config).unwrap()
+        });
+        let (ctrl, _) = connect_ctrl_stream(&socket_handle, comm_uuid, 1, &connect_config).unwrap();
+        let handshake = StreamHandshake {
+            comm_uuid,
+            stream_id: 0,
+        };
+        let data = connect_stream(&socket_handle, handshake, &connect_config).unwrap();
+        let group = acceptor.join().unwrap();
+        let streams = [&ctrl, &data, &group.ctrl_stream, &group.data_streams[0]];
+        for stream in streams.iter() {
+            assert_eq!(get_tcp_congestion(stream.as_raw_fd()).unwrap(), "cubic");
+        }
+
+        // Rejected, the stream keeps working with the default.
+        let default = get_tcp_congestion(data.as_raw_fd()).unwrap();
+        assert!(set_tcp_congestion(data.as_raw_fd(), "no-such-algo").is_err());
+        set_congestion(&data, &handshake, &Some("no-such-algo".to_owned()));
+        assert_eq!(get_tcp_congestion(data.as_raw_fd()).unwrap(), default);
+    }
+
+    #[test]
+    fn test_quickack() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _sender = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (receiver, _) = listener.accept().unwrap();
+        let receiver = Stream::Tcp(receiver);
+        let socket = SockRef::from(&receiver);
+        socket.set_quickack(false).unwrap();
+        assert!(!socket.quickack().unwrap());
+        receiver.set_quickack().unwrap();
+        assert!(socket.quickack().unwrap());
+
+        let (unix, _) = UnixStream::pair().unwrap();
+        Stream::Unix(unix).set_quickack().unwrap();
+    }
+
+    #[test]
+    fn test_socket_buffers() {
+        let buffers = SocketBufferConfig {
+            sndbuf: Some(96 * 1024),
+            rcvbuf: Some(80 * 1024),
+        };
+        let listener = bind_listener(
+            InetAddr::from_std(&"127.0.0.1:0".parse().unwrap()),
+            &ListenConfig {
+                port_range: None,
+                backlog: ListenConfig::DEFAULT_BACKLOG,
+                reuse_port: false,
+                buffers: buffers.clone(),
+                uds: false,
+                advertise_hostname: None,
+            },
+        )
+        .unwrap();
+        let socket_handle = listener.socket_handle().unwrap();
+        let connect_config = ConnectConfig {
+            buffers: buffers.clone(),
+            ..ConnectConfig::from_env()
+        };
+
+        let comm_uuid = Uuid::new_v4();
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            accept_blocking(&listener, &mut pending, 1, &AcceptConfig::from_env()).unwrap()
+        });
+        let (ctrl, peer) =
+            connect_ctrl_stream(&socket_handle, comm_uuid, 1, &connect_config).unwrap();
+        let data = connect_stream(
+            &socket_handle,
+            StreamHandshake {
+                comm_uuid,
+                stream_id: 0,
+            },
+            &connect_config,
+        )
+        .unwrap();
+        let group = acceptor.join().unwrap();
+
+        // Linux reports twice the requested size, to account for bookkeeping.
+        let streams = [&ctrl, &data, &group.ctrl_stream, &group.data_streams[0]];
+        for stream in streams.iter() {
+            let socket = SockRef::from(*stream);
+            assert!(socket.send_buffer_size().unwrap() >= buffers.sndbuf.unwrap());
+            assert!(socket.recv_buffer_size().unwrap() >= buffers.rcvbuf.unwrap());
+        }
+
+        // The replay window covers what both kernels may hold.
+        let rcvbuf = SockRef::from(&group.data_streams[0])
+            .recv_buffer_size()
+            .unwrap();
+        assert_eq!(peer.rcvbuf as usize, rcvbuf);
+        let reconnect_config = ReconnectConfig {
+            window: None,
+            ..ReconnectConfig::from_env()
+        };
+        assert_eq!(
+            reconnect_config.window_bytes(&data, &buffers, peer.rcvbuf as usize),
+            SockRef::from(&data).send_buffer_size().unwrap() + rcvbuf
+        );
+    }
+    #[test]
+    fn test_connect_uds() {
+        let listener = bind_listener(
+            InetAddr::from_std(&"127.0.0.1:0".parse().unwrap()),
+            &ListenConfig {
+                port_range: None,
+                backlog: ListenConfig::DEFAULT_BACKLOG,
+                reuse_port: false,
+                buffers: Default::default(),
+                uds: true,
+                advertise_hostname: None,
+            },
+        )
+        .unwrap();
+        let socket_handle = listener.socket_handle().unwrap();
+        let local_dev = |addr: &str| NCCLSocketDev {
+            interface_name: "lo".to_owned(),
+            addr: SockAddr::new_inet(InetAddr::from_std(&addr.parse().unwrap())),
+            alt_addr: None,
+            pci_path: "".to_owned(),
+        };
+        assert!(same_host_unix_name(&socket_handle, &[local_dev("192.0.2.1:0")]).is_none());
+        // A handle with a hostname is on this host if the name resolves here.
+        let mut named_handle = socket_handle.clone();
+        named_handle.addr = SockAddr::new_inet(InetAddr::from_std(&"0.0.0.0:0".parse().unwrap()));
+        named_handle.hostname = Some("localhost".to_owned());
+        assert!(same_host_unix_name(&named_handle, &[local_dev("127.0.0.1:0")]).is_some());
+        let connect_config = ConnectConfig {
+            unix_peer: same_host_unix_name(&socket_handle, &[local_dev("127.0.0.1:0")]),
+            ..ConnectConfig::from_env()
+        };
+        assert!(connect_config.unix_peer.is_some());
+
+        let comm_uuid = Uuid::new_v4();
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            accept_blocking(&listener, &mut pending, 1, &AcceptConfig::from_env()).unwrap()
+        });
+        let (ctrl, _) = connect_ctrl_stream(&socket_handle, comm_uuid, 1, &connect_config).unwrap();
+        let mut data = connect_stream(
+            &socket_handle,
+            StreamHandshake {
+                comm_uuid,
+                stream_id: 0,
+            },
+            &connect_config,
+        )
+        .unwrap();
+        let mut group = acceptor.join().unwrap();
+        for stream in [&ctrl, &data, &group.ctrl_stream, &group.data_streams[0]].iter() {
+            assert!(matches!(stream, Stream::Unix(_)), "{:?}", stream);
+        }
+
+        data.write_all(b"bagua").unwrap();
+        let mut buf = [0u8; 5];
+        group.data_streams[0].read_exact(&mut buf[..]).unwrap();
+        assert_eq!(&buf, b"bagua");
+    }
+
+    #[test]
+    fn test_connect_uds_fallback() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+        // E.g. a listener in another network namespace.
+        let connect_config = ConnectConfig {
+            unix_peer: Some(b"bagua-net-nonexistent".to_vec()),
+            ..ConnectConfig::from_env()
+        };
+
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            accept_blocking(&listener, &mut pending, 1, &AcceptConfig::from_env()).unwrap()
+        });
+        let comm_uuid = Uuid::new_v4();
+        let (ctrl, _) = connect_ctrl_stream(&socket_handle, comm_uuid, 1, &connect_config).unwrap();
+        let _data = connect_stream(
+            &socket_handle,
+            StreamHandshake {
+                comm_uuid,
+                stream_id: 0,
+            },
+            &connect_config,
+        )
+        .unwrap();
+        assert!(matches!(ctrl, Stream::Tcp(_)));
+        assert!(matches!(
+            acceptor.join().unwrap().ctrl_stream,
+            Stream::Tcp(_)
+        ));
+    }
+
+    #[test]
+    fn test_connect_dual_stack() {
+        let mut listener = tcp_listener("127.0.0.1:0");
+        listener.bind_alt(
+            InetAddr::from_std(&"[::1]:0".parse().unwrap()),
+            &ListenConfig::from_env(),
+        );
+        let mut socket_handle = listener.socket_handle().unwrap();
+        let port = listener.tcp.local_addr().unwrap().port();
+        match socket_handle.alt_addr {
+            Some(SockAddr::Inet(alt_addr)) => {
+                assert_eq!(
+                    alt_addr.to_std(),
+                    format!("[::1]:{}", port).parse().unwrap()
+                )
+            }
+            others => panic!("unexpected alt address {:?}", others),
+        }
+        assert_eq!(unix_name_id(&unix_name(42)), Some(42));
+        assert_eq!(unix_name_id(b"bagua-net-nonexistent"), None);
+
+        // The IPv4 address is unreachable, the connector falls back to IPv6.
+        let closed = net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        socket_handle.addr = SockAddr::new_inet(InetAddr::from_std(&closed));
+        let connect_config = ConnectConfig {
+            retries: 0,
+            ..ConnectConfig::from_env()
+        };
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            accept_blocking(&listener, &mut pending, 1, &AcceptConfig::from_env()).unwrap()
+        });
+        let comm_uuid = Uuid::new_v4();
+        let (ctrl, _) = connect_ctrl_stream(&socket_handle, comm_uuid, 1, &connect_config).unwrap();
+        let _data = connect_stream(
+            &socket_handle,
+            StreamHandshake {
+                comm_uuid,
+                stream_id: 0,
+            },
+            &connect_config,
+        )
+        .unwrap();
+        assert!(ctrl.into_tcp().unwrap().peer_addr().unwrap().is_ipv6());
+        assert_eq!(acceptor.join().unwrap().data_streams.len(), 1);
+    }
+
+    #[test]
+    fn test_connect_hostname() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let mut socket_handle = listener.socket_handle().unwrap();
+        // Stale, only its port is used.
+        let port = listener.tcp.local_addr().unwrap().port();
+        socket_handle.addr = SockAddr::new_inet(InetAddr::from_std(
+            &format!("192.0.2.1:{}", port).parse().unwrap(),
+        ));
+        socket_handle.hostname = Some("localhost".to_owned());
+        let connect_config = ConnectConfig {
+            retries: 0,
+            ..ConnectConfig::from_env()
+        };
+
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            accept_blocking(&listener, &mut pending, 1, &AcceptConfig::from_env()).unwrap()
+        });
+        let comm_uuid = Uuid::new_v4();
+        let _ctrl = connect_ctrl_stream(&socket_handle, comm_uuid, 1, &connect_config).unwrap();
+        let _data = connect_stream(
+            &socket_handle,
+            StreamHandshake {
+                comm_uuid,
+                stream_id: 0,
+            },
+            &connect_config,
+        )
+        .unwrap();
+        assert_eq!(acceptor.join().unwrap().data_streams.len(), 1);
+
+        socket_handle.hostname = Some("bagua-net.invalid".to_owned());
+        match connect_ctrl_stream(&socket_handle, comm_uuid, 1, &connect_config) {
+            Err(BaguaNetError::TCPError { msg, .. }) => {
+                assert!(msg.contains("bagua-net.invalid"), "{}", msg)
+            }
+            others => panic!("unexpected result {:?}", others.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_connect_tls() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+        let tls = crate::tls::tests::tls_config();
+        let accept_config = AcceptConfig {
+            tls: Some(tls.clone()),
+            ..AcceptConfig::from_env()
+        };
+        let connect_config = ConnectConfig {
+            tls: Some(tls),
+            ..ConnectConfig::from_env()
+        };
+
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            accept_blocking(&listener, &mut pending, 2, &accept_config).unwrap()
+        });
+        let comm_uuid = Uuid::new_v4();
+        let (mut ctrl, _) =
+            connect_ctrl_stream(&socket_handle, comm_uuid, 2, &connect_config).unwrap();
+        let mut data = connect_data_streams(&socket_handle, comm_uuid, 2, &connect_config).unwrap();
+        let mut group = acceptor.join().unwrap();
+        for stream in data
+            .iter()
+            .chain(group.data_streams.iter())
+            .chain([&ctrl, &group.ctrl_stream].iter().copied())
+        {
+            assert!(matches!(stream, Stream::Tls(_)), "{:?}", stream);
+        }
+
+        ctrl.write_all(b"ctrl").unwrap();
+        data[1].write_all(b"bagua").unwrap();
+        let mut buf = [0u8; 4];
+        group.ctrl_stream.read_exact(&mut buf[..]).unwrap();
+        assert_eq!(&buf, b"ctrl");
+        let mut buf = [0u8; 5];
+        group.data_streams[1].read_exact(&mut buf[..]).unwrap();
+        assert_eq!(&buf, b"bagua");
+    }
+
+    #[test]
+    fn test_connect_tls_mismatch() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+
+        let (stop, acceptor) = rejecting_acceptor(listener, 1, AcceptConfig::from_env());
+        let connect_config = ConnectConfig {
+            tls: Some(crate::tls::tests::tls_config()),
+            ..ConnectConfig::from_env()
+        };
+        let err =
+            connect_ctrl_stream(&socket_handle, Uuid::new_v4(), 1, &connect_config).unwrap_err();
+        let msg = format!("{:?}", err);
+        assert!(msg.contains("TLS mismatch"), "{}", msg);
+
+        stop.store(true, Ordering::SeqCst);
+        let events = acceptor.join().unwrap();
+        assert!(events.contains("TLS mismatch"), "{}", events);
+    }
+
+    fn auth_key(key: &str) -> Option<AuthKey> {
+        Some(AuthKey(Arc::new(key.as_bytes().to_vec())))
+    }
+
+    #[test]
+    fn test_auth_drops_intruders() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let addr = listener.tcp.local_addr().unwrap();
+        let socket_handle = listener.socket_handle().unwrap();
+        let accept_config = AcceptConfig {
+            auth_key: auth_key("secret"),
+            ..AcceptConfig::from_env()
+        };
+        let connect_config = ConnectConfig {
+            auth_key: auth_key("secret"),
+            ..ConnectConfig::from_env()
+        };
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            accept_blocking(&listener, &mut pending, 1, &accept_config).unwrap()
+        });
+
+        // One guesses the key, one injects bytes right after a handshake.
+        let comm_uuid = Uuid::new_v4();
+        let guessed = ConnectConfig {
+            auth_key: auth_key("guess"),
+            ..connect_config.clone()
+        };
+        assert!(connect_ctrl_stream(&socket_handle, comm_uuid, 1, &guessed).is_err());
+        let mut injector = net::TcpStream::connect(addr).unwrap();
+        StreamHandshake {
+            comm_uuid,
+            stream_id: 0,
+        }
+        .write_to(&mut injector)
+        .unwrap();
+        let _ = injector.write_all(&[0u8; 64][..]);
+
+        let (_ctrl, _) =
+            connect_ctrl_stream(&socket_handle, comm_uuid, 1, &connect_config).unwrap();
+        let mut data = connect_data_streams(&socket_handle, comm_uuid, 1, &connect_config).unwrap();
+        let mut group = acceptor.join().unwrap();
+        assert_eq!(group.comm_uuid, comm_uuid);
+
+        data[0].write_all(b"bagua").unwrap();
+        let mut buf = [0u8; 5];
+        group.data_streams[0].read_exact(&mut buf[..]).unwrap();
+        assert_eq!(&buf, b"bagua");
+    }
+
+    #[test]
+    fn test_auth_mismatch() {
+        // Only the acceptor has the key.
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+        let accept_config = AcceptConfig {
+            auth_key: auth_key("secret"),
+            handshake_timeout: Duration::from_millis(500),
+            ..AcceptConfig::from_env()
+        };
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            let timer = Instant::now();
+            while timer.elapsed() < Duration::from_secs(1) {
+                assert!(
+                    accept_stream_group(&listener, &mut pending, 1, &accept_config)
+                        .unwrap()
+                        .is_none()
+                );
+                std::thread::yield_now();
+            }
+        });
+        let err = connect_ctrl_stream(
+            &socket_handle,
+            Uuid::new_v4(),
+            1,
+            &ConnectConfig::from_env(),
+        )
+        .unwrap_err();
+        let msg = format!("{:?}", err);
+        assert!(msg.contains("BAGUA_NET_AUTH_KEY"), "{}", msg);
+        acceptor.join().unwrap();
+
+        // Only the connector has it.
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+        let _acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            accept_blocking(&listener, &mut pending, 1, &AcceptConfig::from_env())
+        });
+        let connect_config = ConnectConfig {
+            auth_key: auth_key("secret"),
+            timeout: Duration::from_millis(500),
+            ..ConnectConfig::from_env()
+        };
+        let err =
+            connect_ctrl_stream(&socket_handle, Uuid::new_v4(), 1, &connect_config).unwrap_err();
+        let msg = format!("{:?}", err);
+        assert!(msg.contains("BAGUA_NET_AUTH_KEY"), "{}", msg);
+    }
+
+    #[test]
+    fn test_accept_comms_connected_at_once() {
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+        let connectors: Vec<_> = (0..3)
+            .map(|_| {
+                let socket_handle = socket_handle.clone();
+                std::thread::spawn(move || {
+                    let config = ConnectConfig::from_env();
+                    let comm_uuid = Uuid::new_v4();
+                    let (ctrl, _) =
+                        connect_ctrl_stream(&socket_handle, comm_uuid, 2, &config).unwrap();
+                    let data = connect_data_streams(&socket_handle, comm_uuid, 2, &config).unwrap();
+                    (comm_uuid, ctrl, data)
+                })
+            })
+            .collect();
+        // Queues the streams of all comms on the listener.
+        std::thread::sleep(Duration::from_millis(100));
+
+        let mut pending = PendingStreams::default();
+        let mut accepted: Vec<Uuid> = (0..3)
+            .map(|_| {
+                accept_blocking(&listener, &mut pending, 2, &AcceptConfig::from_env())
+                    .unwrap()
+                    .comm_uuid
+            })
+            .collect();
+        let mut connected: Vec<Uuid> = connectors
+            .into_iter()
+            .map(|connector| connector.join().unwrap().0)
+            .collect();
+        accepted.sort();
+        connected.sort();
+        assert_eq!(accepted, connected);
+    }
+
+    #[test]
+    fn test_connect_64_comms() {
+        const NCOMMS: usize = 64;
+        const NSTREAMS: usize = 8;
+        let listener = tcp_listener("127.0.0.1:0");
+        let socket_handle = listener.socket_handle().unwrap();
+        let config = ConnectConfig::from_env();
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            (0..NCOMMS)
+                .map(|_| {
+                    accept_blocking(&listener, &mut pending, NSTREAMS, &AcceptConfig::from_env())
+                        .unwrap()
+                })
+                .collect::<Vec<_>>()
+        });
+        let mut connected: HashMap<Uuid, Vec<Stream>> = (0..NCOMMS)
+            .map(|_| {
+                let comm_uuid = Uuid::new_v4();
+                let (ctrl, _) =
+                    connect_ctrl_stream(&socket_handle, comm_uuid, NSTREAMS, &config).unwrap();
+                let mut streams =
+                    connect_data_streams(&socket_handle, comm_uuid, NSTREAMS, &config).unwrap();
+                streams.push(ctrl);
+                (comm_uuid, streams)
+            })
+            .collect();
+
+        // Each data stream is accepted in the place it was connected for,
+        // whichever arrived first.
+        for mut group in acceptor.join().unwrap() {
+            let streams = connected.get_mut(&group.comm_uuid).unwrap();
+            for (index, stream) in streams[..NSTREAMS].iter_mut().enumerate() {
+                stream.write_all(&[index as u8]).unwrap();
+            }
+            for (index, stream) in group.data_streams.iter_mut().enumerate() {
+                let mut byte = [0u8; 1];
+                stream.read_exact(&mut byte).unwrap();
+                assert_eq!(byte[0] as usize, index);
+            }
+        }
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new(8);
+        assert_eq!(window.since(0).map(|parts| parts.concat()), Some(vec![]));
+        window.record(b"0123");
+        assert_eq!(window.since(4).map(|parts| parts.concat()), Some(vec![]));
+        assert_eq!(
+            window.since(1).map(|parts| parts.concat()),
+            Some(b"123".to_vec())
+        );
+
+        window.record(b"456789ab");
+        assert_eq!(window.written(), 12);
+        assert_eq!(
+            window.since(4).map(|parts| parts.concat()),
+            Some(b"456789ab".to_vec())
+        );
+        assert_eq!(window.since(3).map(|parts| parts.concat()), None);
+        assert_eq!(window.since(13).map(|parts| parts.concat()), None);
+
+        window.record(&[7u8; 20]);
+        assert_eq!(
+            window.since(24).map(|parts| parts.concat()),
+            Some(vec![7u8; 8])
+        );
+
+        // Across the end of the ring.
+        window.record(b"cdefg");
+        assert_eq!(window.written(), 37);
+        assert_eq!(
+            window.since(30).map(|parts| parts.concat()),
+            Some([&[7u8; 2][..], b"cdefg"].concat())
+        );
+        assert_eq!(
+            window.since(29).map(|parts| parts.concat()),
+            Some([&[7u8; 3][..], b"cdefg"].concat())
+        );
+        assert_eq!(window.since(28).map(|parts| parts.concat()), None);
+
+        let mut disabled = ReplayWindow::new(0);
+        disabled.record(b"0123");
+        assert_eq!(disabled.since(4).map(|parts| parts.concat()), Some(vec![]));
+        assert_eq!(disabled.since(3).map(|parts| parts.concat()), None);
+    }
+
+    #[test]
+    fn test_reconnect_acceptor() {
+        let threads = LiveThreads::default();
+        let reconnect_acceptor = ReconnectAcceptor::spawn(
+            &threads,
+            InetAddr::from_std(&"127.0.0.1:0".parse().unwrap()),
+            &ListenConfig::from_env(),
+            &AcceptConfig::from_env(),
+        )
+        .unwrap();
+        let listener_handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&"127.0.0.1:1".parse().unwrap())),
+            alt_addr: None,
+            uds_addr: None,
+            hostname: None,
+        };
+        let socket_handle = reconnect_handle(&listener_handle, reconnect_acceptor.port).unwrap();
+        let handshake = StreamHandshake {
+            comm_uuid: Uuid::new_v4(),
+            stream_id: 1,
+        };
+
+        let route = reconnect_acceptor.route(handshake.comm_uuid, handshake.stream_id);
+        let receiver = std::thread::spawn(move || {
+            let mut replacement = route
+                .replacements
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap();
+            adopt_stream(&mut replacement, 42).unwrap();
+            let mut buf = [0u8; 5];
+            replacement.read_exact(&mut buf[..]).unwrap();
+            buf
+        });
+        let (mut stream, received) = reconnect_stream(
+            &socket_handle,
+            handshake,
+            &ConnectConfig::from_env(),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        assert_eq!(received, 42);
+        stream.write_all(b"bagua").unwrap();
+        assert_eq!(&receiver.join().unwrap(), b"bagua");
+
+        // The route is gone with the receiver.
+        assert!(reconnect_stream(
+            &socket_handle,
+            handshake,
+            &ConnectConfig::from_env(),
+            Duration::from_secs(10),
+        )
+        .is_err());
+
+        // Blocked in accept until then.
+        assert_eq!(threads.count(), 1);
+        drop(reconnect_acceptor);
+        assert_eq!(threads.count(), 0);
+    }
+}
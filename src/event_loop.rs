@@ -0,0 +1,688 @@
+//! A fixed pool of threads driving nonblocking streams on readiness, in
+//! place of a thread per stream. With the `io-uring` feature, reads and
+//! writes can be submitted to an io_uring per thread instead, batched over
+//! the drivers of the thread.
+
+use crate::utils::{self, LiveThreads};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Registry, Token};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+#[cfg(feature = "io-uring")]
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often every driver is polled, whether or not its streams are ready.
+pub const TICK: Duration = Duration::from_millis(100);
+/// Of the threads, once the pool is dropped without `EventLoops::stop`.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+const WAKER_TOKEN: Token = Token(usize::MAX);
+#[cfg(feature = "io-uring")]
+const RING_TOKEN: Token = Token(usize::MAX - 1);
+/// The streams of a driver are registered as `id * MAX_SOURCES + index`.
+const MAX_SOURCES: usize = 1 << 16;
+/// Of the user data of a cancel, whose own completion is not reported.
+#[cfg(feature = "io-uring")]
+const CANCEL_USER_DATA: u64 = 1 << 63;
+
+/// What an event loop drives, e.g. the streams of a comm.
+pub trait Driver: Send {
+    /// The stream registered as `index` may be readable or writable. Its
+    /// readiness is only reported again once it would block.
+    fn ready(&mut self, index: usize, readable: bool, writable: bool);
+
+    /// A read or write submitted for the stream registered as `index`
+    /// completed, with the result of the syscall or its negated errno.
+    #[cfg_attr(not(feature = "io-uring"), allow(dead_code))]
+    fn completed(&mut self, _index: usize, _result: i32) {}
+
+    /// Makes what progress it can without blocking, false once it is done.
+    fn poll(&mut self, sources: &Sources) -> bool;
+
+    /// Called once it is done, e.g. to deregister the streams it keeps.
+    fn finish(self: Box<Self>, sources: &Sources);
+}
+
+/// When to poll a driver again, the earliest first.
+type Timers = BinaryHeap<Reverse<(Instant, usize)>>;
+
+/// Registers the streams of a driver with its event loop.
+pub struct Sources<'a> {
+    registry: &'a Registry,
+    id: usize,
+    timers: &'a RefCell<Timers>,
+    #[cfg(feature = "io-uring")]
+    ring: Option<&'a RefCell<Ring>>,
+}
+
+impl Sources<'_> {
+    pub fn register(&self, index: usize, fd: RawFd) -> io::Result<()> {
+        assert!(
+            index < MAX_SOURCES,
+            "driver stream index {} too large",
+            index
+        );
+        self.registry.register(
+            &mut SourceFd(&fd),
+            Token(self.id * MAX_SOURCES + index),
+            Interest::READABLE | Interest::WRITABLE,
+        )
+    }
+
+    pub fn deregister(&self, fd: RawFd) -> io::Result<()> {
+        self.registry.deregister(&mut SourceFd(&fd))
+    }
+
+    /// Polls the driver again at `deadline`, if nothing else does before.
+    pub fn wake_at(&self, deadline: Instant) {
+        self.timers.borrow_mut().push(Reverse((deadline, self.id)));
+    }
+
+    /// Submits a send of `buf` on the socket `fd`, registered as `index`,
+    /// false if the thread has no io_uring or it is full.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid until the send completed.
+    pub unsafe fn submit_write(&self, index: usize, fd: RawFd, buf: &[u8]) -> bool {
+        #[cfg(feature = "io-uring")]
+        if let Some(ring) = self.ring {
+            let entry = io_uring::opcode::Send::new(
+                io_uring::types::Fd(fd),
+                buf.as_ptr(),
+                buf.len().min(u32::MAX as usize) as u32,
+            )
+            .flags(libc::MSG_NOSIGNAL)
+            .build()
+            .user_data((self.id * MAX_SOURCES + index) as u64);
+            return ring.borrow_mut().push(&entry);
+        }
+        let _ = (index, fd, buf);
+        false
+    }
+
+    /// Submits a receive into `buf` from the socket `fd`, like
+    /// `submit_write`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid, and not be accessed, until the receive
+    /// completed.
+    pub unsafe fn submit_read(&self, index: usize, fd: RawFd, buf: &mut [u8]) -> bool {
+        #[cfg(feature = "io-uring")]
+        if let Some(ring) = self.ring {
+            let entry = io_uring::opcode::Recv::new(
+                io_uring::types::Fd(fd),
+                buf.as_mut_ptr(),
+                buf.len().min(u32::MAX as usize) as u32,
+            )
+            .build()
+            .user_data((self.id * MAX_SOURCES + index) as u64);
+            return ring.borrow_mut().push(&entry);
+        }
+        let _ = (index, fd, buf);
+        false
+    }
+
+    /// Cancels the read or write submitted for the stream registered as
+    /// `index`, which then completes with `ECANCELED` unless it already
+    /// did. False like `submit_write`.
+    pub fn submit_cancel(&self, index: usize) -> bool {
+        #[cfg(feature = "io-uring")]
+        if let Some(ring) = self.ring {
+            let user_data = (self.id * MAX_SOURCES + index) as u64;
+            let entry = io_uring::opcode::AsyncCancel::new(user_data)
+                .build()
+                .user_data(CANCEL_USER_DATA | user_data);
+            return ring.borrow_mut().push(&entry);
+        }
+        let _ = index;
+        false
+    }
+}
+
+/// The result of a completed read or write, see `Driver::completed`.
+pub fn completion_result(result: i32) -> io::Result<usize> {
+    if result < 0 {
+        Err(io::Error::from_raw_os_error(-result))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// The io_uring of an event loop thread. What is submitted while polling
+/// the drivers goes to the kernel at once.
+#[cfg(feature = "io-uring")]
+struct Ring {
+    ring: io_uring::IoUring,
+}
+
+#[cfg(feature = "io-uring")]
+impl Ring {
+    const ENTRIES: u32 = 256;
+
+    fn new() -> io::Result<Ring> {
+        let ring = io_uring::IoUring::new(Self::ENTRIES)?;
+        let mut probe = io_uring::Probe::new();
+        ring.submitter().register_probe(&mut probe)?;
+        if !probe.is_supported(io_uring::opcode::Send::CODE)
+            || !probe.is_supported(io_uring::opcode::Recv::CODE)
+            || !probe.is_supported(io_uring::opcode::AsyncCancel::CODE)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "io_uring has no send, recv or cancel",
+            ));
+        }
+
+        Ok(Ring { ring })
+    }
+
+    fn push(&mut self, entry: &io_uring::squeue::Entry) -> bool {
+        if unsafe { self.ring.submission().push(entry) }.is_ok() {
+            return true;
+        }
+        // Makes room.
+        self.submit();
+        unsafe { self.ring.submission().push(entry) }.is_ok()
+    }
+
+    fn submit(&mut self) {
+        if let Err(err) = self.ring.submit() {
+            tracing::warn!("io_uring submit failed, err={:?}", err);
+        }
+    }
+
+    /// Those of the cancels aside, the cancelled ops complete on their own.
+    fn completions(&mut self) -> Vec<(usize, i32)> {
+        self.ring
+            .completion()
+            .filter(|cqe| cqe.user_data() & CANCEL_USER_DATA == 0)
+            .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+            .collect()
+    }
+}
+
+enum Command {
+    Start(usize, Box<dyn Driver>),
+    Wake(usize),
+    Stop,
+}
+
+struct EventLoop {
+    commands: flume::Sender<Command>,
+    waker: mio::Waker,
+    /// The thread runs on, any if empty.
+    cpus: Vec<usize>,
+}
+
+impl EventLoop {
+    fn send(&self, command: Command) {
+        if self.commands.send(command).is_err() {
+            return;
+        }
+        if let Err(err) = self.waker.wake() {
+            tracing::warn!("failed to wake event loop, err={:?}", err);
+        }
+    }
+}
+
+/// Of a driver, to be started and woken up once there is work for it.
+#[derive(Clone)]
+pub struct DriverWaker {
+    id: usize,
+    event_loop: Arc<EventLoop>,
+}
+
+impl DriverWaker {
+    /// Hands `driver` to its event loop, which polls it right away.
+    pub fn start<D: Driver + 'static>(&self, driver: D) {
+        self.event_loop
+            .send(Command::Start(self.id, Box::new(driver)));
+    }
+
+    /// Polls the driver again. Ignored until it is started.
+    pub fn wake(&self) {
+        self.event_loop.send(Command::Wake(self.id));
+    }
+
+    /// The event loop runs on, any if empty.
+    pub fn cpus(&self) -> &[usize] {
+        &self.event_loop.cpus
+    }
+}
+
+/// The drivers are spread over the threads round-robin, and the threads over
+/// the sets of CPUs they run on. Those still running are dropped once the
+/// pool is stopped, at the latest when it is dropped.
+pub struct EventLoops {
+    loops: Vec<Arc<EventLoop>>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+    next_id: AtomicUsize,
+}
+
+impl EventLoops {
+    /// With `io_uring`, reads and writes are submitted to an io_uring if the
+    /// `io-uring` feature is on and the kernel supports it. Thread `i` runs
+    /// on `affinity[i % affinity.len()]`, on any CPU if that is empty. A thread
+    /// keeps polling without blocking for `spin` after it last had work, so
+    /// that what comes in meanwhile does not wait for it to be scheduled.
+    pub fn spawn(
+        threads_of: &LiveThreads,
+        nthreads: usize,
+        io_uring: bool,
+        affinity: &[Vec<usize>],
+        spin: Duration,
+    ) -> io::Result<EventLoops> {
+        let mut loops = Vec::new();
+        let mut threads = Vec::new();
+        for i in 0..nthreads.max(1) {
+            let poll = Poll::new()?;
+            let waker = mio::Waker::new(poll.registry(), WAKER_TOKEN)?;
+            let (commands, command_receiver) = flume::unbounded();
+            let cpus = match affinity.is_empty() {
+                true => Vec::new(),
+                false => affinity[i % affinity.len()].clone(),
+            };
+            loops.push(Arc::new(EventLoop {
+                commands,
+                waker,
+                cpus: cpus.clone(),
+            }));
+            threads.push(threads_of.spawn(move || {
+                if !cpus.is_empty() {
+                    if let Err(err) = utils::set_thread_affinity(&cpus) {
+                        tracing::warn!(
+                            "failed to pin event loop to CPUs {:?}, err={:?}",
+                            cpus,
+                            err
+                        );
+                    }
+                }
+                run(poll, command_receiver, io_uring, spin)
+            }));
+        }
+
+        Ok(EventLoops {
+            loops,
+            threads,
+            next_id: AtomicUsize::new(0),
+        })
+    }
+
+    /// For a new driver.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn waker(&self) -> DriverWaker {
+        self.waker_on(&[])
+    }
+
+    /// For a new driver, on a thread that runs on some of `cpus` only, e.g.
+    /// those of the NUMA node of its NIC, if there is one.
+    pub fn waker_on(&self, cpus: &[usize]) -> DriverWaker {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let near: Vec<&Arc<EventLoop>> = self
+            .loops
+            .iter()
+            .filter(|event_loop| {
+                !event_loop.cpus.is_empty() && event_loop.cpus.iter().all(|cpu| cpus.contains(cpu))
+            })
+            .collect();
+        let event_loop = match near.is_empty() {
+            true => &self.loops[id % self.loops.len()],
+            false => near[id % near.len()],
+        };
+        DriverWaker {
+            id,
+            event_loop: event_loop.clone(),
+        }
+    }
+
+    /// Drops the drivers and waits up to `timeout` for the threads to end,
+    /// leaving those that do not running.
+    pub fn stop(&mut self, timeout: Duration) {
+        for event_loop in self.loops.iter() {
+            event_loop.send(Command::Stop);
+        }
+        let deadline = Instant::now() + timeout;
+        for thread in self.threads.drain(..) {
+            if !utils::join_until(thread, deadline) {
+                tracing::warn!("event loop thread still running {:?} after stop", timeout);
+            }
+        }
+    }
+}
+
+impl Drop for EventLoops {
+    fn drop(&mut self) {
+        self.stop(STOP_TIMEOUT);
+    }
+}
+
+#[cfg(feature = "io-uring")]
+fn ring(poll: &Poll) -> io::Result<Ring> {
+    let ring = Ring::new()?;
+    poll.registry().register(
+        &mut SourceFd(&ring.ring.as_raw_fd()),
+        RING_TOKEN,
+        Interest::READABLE,
+    )?;
+    Ok(ring)
+}
+
+fn run(mut poll: Poll, commands: flume::Receiver<Command>, io_uring: bool, spin: Duration) {
+    #[cfg(feature = "io-uring")]
+    let ring = match io_uring {
+        true => match ring(&poll) {
+            Ok(ring) => Some(RefCell::new(ring)),
+            Err(err) => {
+                tracing::info!("io_uring not available, err={:?}", err);
+                None
+            }
+        },
+        false => None,
+    };
+    #[cfg(not(feature = "io-uring"))]
+    let _ = io_uring;
+    let mut drivers: HashMap<usize, Box<dyn Driver>> = HashMap::new();
+    let mut events = Events::with_capacity(1024);
+    let mut polled = Vec::new();
+    let timers = RefCell::new(Timers::new());
+    let mut last_tick = Instant::now();
+    let mut spin_until = Instant::now();
+    loop {
+        let now = Instant::now();
+        let timeout = match timers.borrow().peek() {
+            _ if now < spin_until => Duration::ZERO,
+            Some(Reverse((deadline, _))) => deadline.saturating_duration_since(now).min(TICK),
+            None => TICK,
+        };
+        if let Err(err) = poll.poll(&mut events, Some(timeout)) {
+            if err.kind() != io::ErrorKind::Interrupted {
+                tracing::error!("event loop failed, err={:?}", err);
+                return;
+            }
+        }
+        for event in events.iter() {
+            if event.token() == WAKER_TOKEN {
+                continue;
+            }
+            // Completions are reaped below, every time.
+            #[cfg(feature = "io-uring")]
+            if event.token() == RING_TOKEN {
+                continue;
+            }
+            let id = event.token().0 / MAX_SOURCES;
+            if let Some(driver) = drivers.get_mut(&id) {
+                // Errors and hang-ups show on the next read or write.
+                driver.ready(
+                    event.token().0 % MAX_SOURCES,
+                    event.is_readable() || event.is_read_closed() || event.is_error(),
+                    event.is_writable() || event.is_write_closed() || event.is_error(),
+                );
+                polled.push(id);
+            }
+        }
+        #[cfg(feature = "io-uring")]
+        if let Some(ring) = &ring {
+            for (user_data, result) in ring.borrow_mut().completions() {
+                let id = user_data / MAX_SOURCES;
+                if let Some(driver) = drivers.get_mut(&id) {
+                    driver.completed(user_data % MAX_SOURCES, result);
+                    polled.push(id);
+                }
+            }
+        }
+        for command in commands.try_iter() {
+            match command {
+                Command::Start(id, driver) => {
+                    drivers.insert(id, driver);
+                    polled.push(id);
+                }
+                Command::Wake(id) => polled.push(id),
+                Command::Stop => return,
+            }
+        }
+        let now = Instant::now();
+        {
+            let mut timers = timers.borrow_mut();
+            while let Some(&Reverse((deadline, id))) = timers.peek() {
+                if deadline > now {
+                    break;
+                }
+                timers.pop();
+                polled.push(id);
+            }
+        }
+        if last_tick.elapsed() >= TICK {
+            last_tick = Instant::now();
+            polled.extend(drivers.keys());
+        }
+
+        if !polled.is_empty() {
+            spin_until = Instant::now() + spin;
+        }
+        polled.sort_unstable();
+        polled.dedup();
+        for id in polled.drain(..) {
+            let sources = Sources {
+                registry: poll.registry(),
+                id,
+                timers: &timers,
+                #[cfg(feature = "io-uring")]
+                ring: ring.as_ref(),
+            };
+            let done = match drivers.get_mut(&id) {
+                Some(driver) => !driver.poll(&sources),
+                None => false,
+            };
+            if done {
+                drivers.remove(&id).unwrap().finish(&sources);
+            }
+        }
+        #[cfg(feature = "io-uring")]
+        if let Some(ring) = &ring {
+            ring.borrow_mut().submit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sched::CpuSet;
+    use nix::unistd::Pid;
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    /// Reads `expected` bytes, then sends what it read back through `done`.
+    struct ReadDriver {
+        stream: UnixStream,
+        registered: bool,
+        readable: bool,
+        buf: Vec<u8>,
+        expected: usize,
+        done: flume::Sender<Vec<u8>>,
+    }
+
+    impl Driver for ReadDriver {
+        fn ready(&mut self, index: usize, readable: bool, _writable: bool) {
+            assert_eq!(index, 0);
+            self.readable |= readable;
+        }
+
+        fn poll(&mut self, sources: &Sources) -> bool {
+            if !self.registered {
+                sources.register(0, self.stream.as_raw_fd()).unwrap();
+                self.registered = true;
+            }
+            let mut buf = [0u8; 16];
+            while self.readable && self.buf.len() < self.expected {
+                match self.stream.read(&mut buf[..]) {
+                    Ok(n) => self.buf.extend_from_slice(&buf[..n]),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => self.readable = false,
+                    Err(err) => panic!("{:?}", err),
+                }
+            }
+            self.buf.len() < self.expected
+        }
+
+        fn finish(self: Box<Self>, sources: &Sources) {
+            sources.deregister(self.stream.as_raw_fd()).unwrap();
+            self.done.send(self.buf).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_drive_on_readiness() {
+        let loops =
+            EventLoops::spawn(&LiveThreads::default(), 1, false, &[], Duration::ZERO).unwrap();
+        let (done, finished) = flume::unbounded();
+        let mut writers = Vec::new();
+        for _ in 0..4 {
+            let (stream, writer) = UnixStream::pair().unwrap();
+            stream.set_nonblocking(true).unwrap();
+            loops.waker().start(ReadDriver {
+                stream,
+                registered: false,
+                readable: true,
+                buf: Vec::new(),
+                expected: 8,
+                done: done.clone(),
+            });
+            writers.push(writer);
+        }
+
+        for (i, writer) in writers.iter_mut().enumerate() {
+            writer.write_all(&[i as u8; 4][..]).unwrap();
+        }
+        assert!(finished.recv_timeout(TICK * 2).is_err());
+        for (i, writer) in writers.iter_mut().enumerate() {
+            writer.write_all(&[i as u8; 4][..]).unwrap();
+        }
+        let mut received: Vec<Vec<u8>> = (0..4)
+            .map(|_| finished.recv_timeout(Duration::from_secs(10)).unwrap())
+            .collect();
+        received.sort();
+        for (i, buf) in received.iter().enumerate() {
+            assert_eq!(buf, &vec![i as u8; 8]);
+        }
+    }
+
+    /// Sends the CPUs its thread may run on.
+    struct AffinityDriver {
+        done: flume::Sender<Vec<usize>>,
+    }
+
+    impl Driver for AffinityDriver {
+        fn ready(&mut self, _index: usize, _readable: bool, _writable: bool) {}
+
+        fn poll(&mut self, _sources: &Sources) -> bool {
+            let cpu_set = nix::sched::sched_getaffinity(Pid::from_raw(0)).unwrap();
+            let cpus = (0..CpuSet::count())
+                .filter(|&cpu| cpu_set.is_set(cpu).unwrap())
+                .collect();
+            self.done.send(cpus).unwrap();
+            false
+        }
+
+        fn finish(self: Box<Self>, _sources: &Sources) {}
+    }
+
+    #[test]
+    fn test_pinned_threads() {
+        let allowed = nix::sched::sched_getaffinity(Pid::from_raw(0)).unwrap();
+        let cpus: Vec<usize> = (0..CpuSet::count())
+            .filter(|&cpu| allowed.is_set(cpu).unwrap())
+            .take(2)
+            .collect();
+        // More threads than CPUs, they wrap around.
+        let affinity: Vec<Vec<usize>> = cpus.iter().map(|&cpu| vec![cpu]).collect();
+        let loops = EventLoops::spawn(
+            &LiveThreads::default(),
+            3,
+            false,
+            &affinity[..],
+            Duration::ZERO,
+        )
+        .unwrap();
+        let (done, pinned) = flume::unbounded();
+        for i in 0..3 {
+            let waker = loops.waker();
+            assert_eq!(waker.cpus(), &[cpus[i % cpus.len()]][..]);
+            waker.start(AffinityDriver { done: done.clone() });
+            let pinned = pinned.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert_eq!(pinned, vec![cpus[i % cpus.len()]]);
+        }
+
+        // The threads on the CPUs asked for, any of them if none is.
+        for _ in 0..3 {
+            assert_eq!(loops.waker_on(&cpus[..1]).cpus(), &cpus[..1]);
+        }
+        assert_eq!(loops.waker_on(&[usize::MAX]).cpus().len(), 1);
+
+        // Restricted to a set.
+        let loops = EventLoops::spawn(
+            &LiveThreads::default(),
+            1,
+            false,
+            std::slice::from_ref(&cpus),
+            Duration::ZERO,
+        )
+        .unwrap();
+        let waker = loops.waker_on(&cpus[..]);
+        assert_eq!(waker.cpus(), &cpus[..]);
+        waker.start(AffinityDriver { done });
+        assert_eq!(pinned.recv_timeout(Duration::from_secs(10)).unwrap(), cpus);
+
+        let loops =
+            EventLoops::spawn(&LiveThreads::default(), 1, false, &[], Duration::ZERO).unwrap();
+        assert!(loops.waker().cpus().is_empty());
+        assert!(loops.waker_on(&cpus[..]).cpus().is_empty());
+    }
+
+    /// Asks to be polled again after `delay`, `npolls` times.
+    struct TimerDriver {
+        delay: Duration,
+        npolls: usize,
+        done: flume::Sender<Instant>,
+    }
+
+    impl Driver for TimerDriver {
+        fn ready(&mut self, _index: usize, _readable: bool, _writable: bool) {}
+
+        fn poll(&mut self, sources: &Sources) -> bool {
+            self.done.send(Instant::now()).unwrap();
+            self.npolls -= 1;
+            sources.wake_at(Instant::now() + self.delay);
+            self.npolls > 0
+        }
+
+        fn finish(self: Box<Self>, _sources: &Sources) {}
+    }
+
+    #[test]
+    fn test_wake_at() {
+        let loops =
+            EventLoops::spawn(&LiveThreads::default(), 1, false, &[], Duration::ZERO).unwrap();
+        let (done, polled) = flume::unbounded();
+        let delay = TICK / 10;
+        loops.waker().start(TimerDriver {
+            delay,
+            npolls: 4,
+            done,
+        });
+        let polls: Vec<Instant> = (0..4)
+            .map(|_| polled.recv_timeout(Duration::from_secs(10)).unwrap())
+            .collect();
+        // Well ahead of the ticks.
+        assert!(polls[3] - polls[0] >= delay * 3);
+        assert!(polls[3] - polls[0] < TICK * 2);
+    }
+}
@@ -1,2 +1,5 @@
-pub mod tokio_backend;
 pub mod nthread_per_socket_backend;
+mod request_state;
+pub mod tokio_backend;
+
+pub use request_state::{BufferLoan, RequestState, Tally};
@@ -1,21 +1,51 @@
-use crate::interface;
+use crate::buffer_pool::{self, BufferPool, PooledBuffer};
+use crate::compression;
+use crate::compression::{Compression, FrameHeader};
+use crate::connection;
+use crate::connection::{
+    AcceptConfig, AdaptiveStreamsConfig, ChunkHeader, CommHandshake, ConnCacheConfig,
+    ConnectConfig, ImbalanceConfig, ListenCloser, ListenConfig, Listener, PendingStreams, QuickAck,
+    ReconnectAcceptor, ReconnectConfig, ReconnectRoute, ReplayWindow, Stream, StreamGroup,
+    StreamHandshake,
+};
+use crate::event_loop;
+use crate::event_loop::{Driver, DriverWaker, EventLoops, Sources};
+pub use crate::implement::RequestState;
+use crate::implement::{BufferLoan, Tally};
 use crate::interface::{
-    BaguaNetError, NCCLNetProperties, Net, SocketHandle, SocketListenCommID, SocketRecvCommID,
-    SocketRequestID, SocketSendCommID,
+    BaguaNetError, CommKind, CommStatistics, CompletionHook, MrHandle, NCCLNetProperties, Net,
+    NetStatistics, RecvBuffer, RequestTiming, SendBuffer, SocketHandle, SocketListenCommID,
+    SocketRecvCommID, SocketRequestID, SocketSendCommID,
+};
+use crate::mr::{MrRegistry, PtrType};
+use crate::rate_limit::{self, MaxBandwidth, TokenBucket};
+use crate::slab;
+use crate::slab::{RecentlyDone, Slab};
+use crate::split_tuning::{self, SplitTuner};
+use crate::staging::{Bounce, CopyRange, Staging};
+use crate::telemetry::{
+    self, Gauge, Meters, PeerGauges, RequestSpan, SpanSampler, TelemetryConfig,
 };
+use crate::tls::TlsConfig;
 use crate::utils;
-use crate::utils::NCCLSocketDev;
-use nix::sys::socket::{InetAddr, SockAddr};
+use crate::utils::{IoOutcome, LiveThreads, NCCLSocketDev, WaitMode};
+use crate::zerocopy;
+use nix::sys::socket::SockAddr;
 use opentelemetry::{
-    metrics::{BoundValueRecorder, ObserverResult},
+    metrics::{Meter, ObserverResult},
     trace::{Span, TraceContextExt, Tracer},
     KeyValue,
 };
-use socket2::{Domain, Socket, Type};
-use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::Read;
 use std::net;
-use std::sync::{Arc, Mutex};
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
 const NCCL_PTR_HOST: i32 = 1;
 const NCCL_PTR_CUDA: i32 = 2;
@@ -25,38 +55,275 @@ lazy_static! {
 }
 
 pub struct SocketListenComm {
-    pub tcp_listener: Arc<Mutex<net::TcpListener>>,
+    /// Of its socket handle, what parked recv comms are kept by.
+    pub addr: SockAddr,
+    pub listener: Arc<Mutex<Listener>>,
+    pub pending_streams: Arc<Mutex<PendingStreams>>,
+    pub reconnect_acceptor: Option<Arc<ReconnectAcceptor>>,
+    pub dev_id: usize,
+    /// Of its device.
+    pub numa_node: Option<usize>,
+    /// Of `listener`, which an accept keeps locked.
+    pub closer: ListenCloser,
+}
+
+impl Drop for SocketListenComm {
+    fn drop(&mut self) {
+        self.closer.close();
+    }
+}
+
+/// With what `isend` copied the message into, if it did.
+type SendTask = (SendData, Arc<RequestState>, Option<Arc<SendCopy>>);
+/// After how many messages the comm had posted before it.
+type LaneTask = (usize, SendTask);
+/// With where the size of its message goes.
+type RecvTask = (RecvBuffer, Arc<RequestState>, RecvSize);
+/// Of the messages received into the buffers of a request, by buffer, as
+/// their senders declared them on the master stream. `UNDECLARED` until then.
+type RecvSizes = Arc<[AtomicUsize]>;
+/// One of `RecvSizes`.
+type RecvSize = (RecvSizes, usize);
+const UNDECLARED: usize = usize::MAX;
+
+/// The bytes of a message, those of the slices of `isend_v` one after the
+/// other.
+pub enum SendData {
+    Contiguous(SendBuffer),
+    Gather(Box<[SendBuffer]>),
+}
+
+impl SendData {
+    fn buffers(&self) -> &[SendBuffer] {
+        match self {
+            SendData::Contiguous(data) => std::slice::from_ref(data),
+            SendData::Gather(buffers) => buffers,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.buffers().iter().map(SendBuffer::len).sum()
+    }
+
+    /// Of the bytes from `start`, up to `len` of them, the parts of the
+    /// slices they are in.
+    fn pieces(&self, start: usize, len: usize) -> impl Iterator<Item = &'static [u8]> + '_ {
+        let end = start + len;
+        let mut offset = 0;
+        self.buffers().iter().filter_map(move |buf| {
+            let slice = buf.slice();
+            let (from, to) = (offset, offset + slice.len());
+            offset = to;
+            let (lo, hi) = (start.max(from), end.min(to));
+            (lo < hi).then(|| &slice[lo - from..hi - from])
+        })
+    }
+}
+
+/// Set by `Net::set_fixed_message_size` before the first message of a comm,
+/// read by its driver when that comes in.
+type FixedMessageSize = Arc<Mutex<Option<usize>>>;
+
+/// Of a comm, whether the sizes of its messages go on the master stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FixedSize {
+    /// Until the first message.
+    Unknown,
+    Dynamic,
+    /// The sender waits for the receiver to acknowledge the size.
+    Announced(usize),
+    Fixed(usize),
+}
+
+/// Of a comm, the error of the first of its requests that timed out, see
+/// `BAGUA_NET_REQUEST_TIMEOUT_SECS`. Posts to it fail with it from then on.
+pub type CommHealth = Arc<Mutex<Option<BaguaNetError>>>;
+
+/// Of a send or receive, counted in flight on its comm and in the
+/// `inflight_requests` metric until it is dropped, once `test()` saw it done
+/// or failed, or it was cancelled or handed to its completion hook.
+pub struct Inflight {
+    comm: Arc<AtomicUsize>,
+    total: Arc<AtomicU64>,
+}
+
+impl Inflight {
+    fn count(comm: &Arc<AtomicUsize>, total: &Arc<AtomicU64>) -> Inflight {
+        comm.fetch_add(1, Ordering::Relaxed);
+        total.fetch_add(1, Ordering::Relaxed);
+        Inflight {
+            comm: comm.clone(),
+            total: total.clone(),
+        }
+    }
+}
+
+impl Drop for Inflight {
+    fn drop(&mut self) {
+        self.comm.fetch_sub(1, Ordering::Relaxed);
+        self.total.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub enum ConnectState {
+    Connecting,
+    Connected,
+    Failed(BaguaNetError),
 }
 
 // TODO: make Rotating communicator
 #[derive(Clone)]
 pub struct SocketSendComm {
-    pub tcp_sender: Arc<std::thread::JoinHandle<()>>,
-    pub msg_sender: flume::Sender<(&'static [u8], Arc<Mutex<RequestState>>)>,
+    pub waker: DriverWaker,
+    /// The normal lane and the priority lane, see `SendLanes`.
+    pub msg_senders: [flume::Sender<LaneTask>; 2],
+    /// Of the messages that go in the priority lane.
+    pub priority_threshold: usize,
+    /// Of the messages queued, in either lane.
+    pub nposted: usize,
+    pub connect_state: Arc<Mutex<ConnectState>>,
+    pub fixed_size: FixedMessageSize,
+    /// Whether a message was posted, after which the size cannot be fixed.
+    pub posted: bool,
+    pub max_bandwidth: MaxBandwidth,
+    /// Of the messages `isend` copied, those not sent yet. The first that
+    /// failed fails the messages posted after it.
+    pub copies: VecDeque<Arc<RequestState>>,
+    pub health: CommHealth,
+    /// Of its requests, see `Inflight`.
+    pub inflight: Arc<AtomicUsize>,
+    /// Of the listener.
+    pub peer: String,
+    pub opened_at: Instant,
+    /// Of its messages, as they are sent, and of its requests.
+    pub tally: Arc<Tally>,
+    pub dev_id: usize,
+    _device: Arc<DeviceComm>,
+    queue_depth: Arc<SendQueueDepth>,
+    /// Disconnected once its driver is done: every message posted was
+    /// written out, and the streams are closed or parked. Or once connecting
+    /// failed.
+    pub finished: flume::Receiver<()>,
+}
+
+impl Drop for SocketSendComm {
+    /// Its driver finds the channels closed.
+    fn drop(&mut self) {
+        self.waker.wake();
+    }
 }
 
 #[derive(Clone)]
 pub struct SocketRecvComm {
-    pub tcp_sender: Arc<std::thread::JoinHandle<()>>,
-    pub msg_sender: flume::Sender<(&'static mut [u8], Arc<Mutex<RequestState>>)>,
+    pub waker: DriverWaker,
+    pub msg_sender: flume::Sender<RecvTask>,
+    /// Set once the sender announced that it closed the comm, to how many
+    /// messages it sent.
+    pub peer_closed: Arc<Mutex<Option<usize>>>,
+    pub fixed_size: FixedMessageSize,
+    /// Of the messages posted.
+    pub nposted: usize,
+    pub health: CommHealth,
+    /// Of its requests, see `Inflight`.
+    pub inflight: Arc<AtomicUsize>,
+    /// Of the master stream.
+    pub peer: String,
+    pub opened_at: Instant,
+    /// Of its messages, as they are received, and of its requests.
+    pub tally: Arc<Tally>,
+    /// Of the listen comm it was accepted on.
+    pub dev_id: usize,
+    _device: Arc<DeviceComm>,
+    /// Disconnected once its driver is done and nothing else touches the
+    /// buffers posted: the decompression and the copies to the device of
+    /// their chunks hold it as well.
+    pub finished: flume::Receiver<()>,
+}
+
+impl Drop for SocketRecvComm {
+    /// Its driver fails the messages still posted.
+    fn drop(&mut self) {
+        self.waker.wake();
+    }
+}
+
+impl SocketRecvComm {
+    /// Whether the message posted `index`th is after those the sender sent
+    /// before it closed the comm.
+    fn unsent(&self, index: usize) -> bool {
+        self.peer_closed
+            .lock()
+            .unwrap()
+            .is_some_and(|sent| index >= sent)
+    }
 }
 
 pub struct SocketSendRequest {
-    pub state: Arc<Mutex<RequestState>>,
-    pub trace_span: opentelemetry::global::BoxedSpan,
+    pub state: Arc<RequestState>,
+    pub trace_span: RequestSpan,
+    pub send_comm_id: SocketSendCommID,
+    pub posted_at: Instant,
+    /// Of its comm.
+    pub health: CommHealth,
+    pub inflight: Option<Inflight>,
 }
 
 pub struct SocketRecvRequest {
-    pub state: Arc<Mutex<RequestState>>,
-    pub trace_span: opentelemetry::global::BoxedSpan,
+    pub state: Arc<RequestState>,
+    pub trace_span: RequestSpan,
+    pub posted_at: Instant,
+    /// Of its comm.
+    pub health: CommHealth,
+    /// None for a flush.
+    pub inflight: Option<Inflight>,
+    sizes: RecvSizes,
+    recv_comm_id: SocketRecvCommID,
+    /// Of the buffers, by address, none for a flush.
+    bufs: Box<[Range<usize>]>,
+    /// Of a flush, the receives it waits for.
+    flushed: Vec<Arc<RequestState>>,
 }
 
-#[derive(Debug)]
-pub struct RequestState {
-    pub nsubtasks: usize,
-    pub completed_subtasks: usize,
-    pub nbytes_transferred: usize,
-    pub err: Option<BaguaNetError>,
+impl SocketRecvRequest {
+    /// Of a flush, completes it once the receives it waits for are done, or
+    /// fails it with the first that failed.
+    fn settle_flush(&mut self) {
+        if self.flushed.is_empty() {
+            return;
+        }
+        let mut failed = None;
+        self.flushed.retain(|state| {
+            if let Some(err) = state.err() {
+                failed.get_or_insert(err);
+                return false;
+            }
+            !state.progress().0 && !state.is_cancelled()
+        });
+        match failed {
+            Some(err) => {
+                self.flushed.clear();
+                self.state.fail(err);
+            }
+            None if self.flushed.is_empty() => self.state.complete_subtask(0),
+            None => {}
+        }
+    }
+
+    fn received_size(&self) -> Option<usize> {
+        declared_size(&self.sizes)
+    }
+}
+
+/// Summed over the buffers, once every message is declared.
+fn declared_size(sizes: &RecvSizes) -> Option<usize> {
+    sizes
+        .iter()
+        .map(|size| match size.load(Ordering::Relaxed) {
+            UNDECLARED => None,
+            nbytes => Some(nbytes),
+        })
+        .sum()
 }
 
 pub enum SocketRequest {
@@ -64,595 +331,10494 @@ pub enum SocketRequest {
     RecvRequest(SocketRecvRequest),
 }
 
-static TELEMETRY_INIT_ONCE: std::sync::Once = std::sync::Once::new();
-// static TELEMETRY_GUARD: Option<TelemetryGuard> = None;
+/// Fails a request, and its comm, once it was posted `timeout` ago, with
+/// `BAGUA_NET_REQUEST_TIMEOUT_SECS`.
+fn check_deadline(
+    timeout: Option<Duration>,
+    posted_at: Instant,
+    state: &RequestState,
+    health: &CommHealth,
+    span: &mut RequestSpan,
+) -> Result<(), BaguaNetError> {
+    match timeout {
+        Some(timeout) if posted_at.elapsed() >= timeout => {
+            let err = BaguaNetError::Timeout;
+            utils::lock(health).get_or_insert_with(|| err.clone());
+            state.fail(err.clone());
+            span.set_attribute(KeyValue::new("error", "timeout"));
+            span.end();
+            Err(err)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Of the `nbytes` at `offset` in the message of `state`, a child of its
+/// request's span if its chunks are traced. Dropped unfinished, it ends
+/// without a stream.
+fn chunk_span(
+    name: &'static str,
+    state: &RequestState,
+    offset: usize,
+    nbytes: usize,
+) -> Option<opentelemetry::global::BoxedSpan> {
+    let parent = state.trace_parent()?;
+    let tracer = opentelemetry::global::tracer("bagua-net");
+    let mut span = tracer
+        .span_builder(name)
+        .with_parent_context(parent.clone())
+        .start(&tracer);
+    span.set_attribute(KeyValue::new("offset", offset as i64));
+    span.set_attribute(KeyValue::new("nbytes", nbytes as i64));
+    Some(span)
+}
+
+/// Once `requests` changed, for the `live_requests` metric.
+fn count_live_requests(state: &AppState, requests: &Slab<SocketRequest>) {
+    state
+        .live_requests
+        .store(requests.len() as u64, Ordering::Relaxed);
+}
+
+/// Once nothing reads into or writes from the buffer of the request, which
+/// its driver gives back once it is woken.
+fn cancel_lent(state: &RequestState, waker: Option<DriverWaker>) {
+    state.cancel();
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+    state.wait_returned();
+}
+
+/// Of a request found done, how long it took.
+fn record_latency(gauge: &Gauge, timing: Option<RequestTiming>) {
+    if let Some(timing) = timing {
+        gauge.record((timing.queued + timing.wire).as_micros() as u64);
+    }
+}
+
+/// Of a request found done, how long it was queued and on the wire.
+fn set_timing(span: &mut RequestSpan, timing: Option<RequestTiming>) {
+    if let Some(timing) = timing {
+        span.set_attribute(KeyValue::new("queued_us", timing.queued.as_micros() as i64));
+        span.set_attribute(KeyValue::new("wire_us", timing.wire.as_micros() as i64));
+    }
+}
 
+/// What the metrics are observed from, whether they are pushed or not.
 struct AppState {
-    exporter: opentelemetry_prometheus::PrometheusExporter,
-    isend_nbytes_gauge: BoundValueRecorder<'static, u64>,
-    irecv_nbytes_gauge: BoundValueRecorder<'static, u64>,
-    isend_nbytes_per_second: Arc<Mutex<f64>>,
-    isend_percentage_of_effective_time: Arc<Mutex<f64>>,
-    // isend_nbytes_gauge: BoundValueRecorder<'static, u64>,
-    // irecv_nbytes_gauge: BoundValueRecorder<'static, u64>,
-    uploader: std::thread::JoinHandle<()>,
+    isend_nbytes_gauge: Gauge,
+    irecv_nbytes_gauge: Gauge,
+    /// The same, by peer and comm, see `SendDriver::peer_nbytes_gauge`.
+    isend_peer_nbytes: PeerGauges,
+    irecv_peer_nbytes: PeerGauges,
+    /// Of the requests done, from when they were posted, in microseconds.
+    /// Not of flushes.
+    isend_latency_gauge: Gauge,
+    irecv_latency_gauge: Gauge,
+    send_streams: Arc<SendStreamMetrics>,
+    /// Data streams of a send comm each time it grows.
+    send_comm_nstreams_gauge: Gauge,
+    /// Writes on the master streams, and the messages they finished. Fewer
+    /// writes than messages is what coalescing saved.
+    ctrl_writes: Arc<AtomicU64>,
+    ctrl_messages: Arc<AtomicU64>,
+    /// Of the data streams replaced for being slow, see `ImbalanceConfig`.
+    stream_rehashes: Arc<AtomicU64>,
+    /// Of the messages `isend` copied, and the bytes of those not sent yet.
+    send_copies: Arc<AtomicU64>,
+    send_copy_bytes: Arc<AtomicU64>,
+    /// Of the sends and receives on every comm, see `Inflight`.
+    inflight_requests: Arc<AtomicU64>,
+    send_queues: Arc<SendQueues>,
+    /// Of `BaguaNet::socket_request_map`, done or not.
+    live_requests: Arc<AtomicU64>,
+    /// Of the send comms and the recv comms, see `Net::get_statistics`.
+    sent: Arc<Tally>,
+    received: Arc<Tally>,
+    devices: Arc<DeviceTallies>,
+    /// With `BAGUA_NET_TCP_INFO=1`, once metrics are observed.
+    tcp_streams: Option<Arc<TcpStreams>>,
+}
+
+impl AppState {
+    fn new(meters: Option<&Meters>, per_peer: bool, tcp_info: bool) -> AppState {
+        let meter = meters.map(|meters| &meters.meter);
+        let latency_meter = meters.map(|meters| &meters.latency);
+        AppState {
+            isend_nbytes_gauge: Gauge::new(meter, "isend_nbytes", HANDLER_ALL.as_ref()),
+            irecv_nbytes_gauge: Gauge::new(meter, "irecv_nbytes", HANDLER_ALL.as_ref()),
+            isend_peer_nbytes: PeerGauges::new(meter, "isend_nbytes", per_peer),
+            irecv_peer_nbytes: PeerGauges::new(meter, "irecv_nbytes", per_peer),
+            isend_latency_gauge: Gauge::new(
+                latency_meter,
+                "isend_latency_us",
+                HANDLER_ALL.as_ref(),
+            ),
+            irecv_latency_gauge: Gauge::new(
+                latency_meter,
+                "irecv_latency_us",
+                HANDLER_ALL.as_ref(),
+            ),
+            send_streams: Default::default(),
+            send_comm_nstreams_gauge: Gauge::new(meter, "send_comm_nstreams", HANDLER_ALL.as_ref()),
+            ctrl_writes: Default::default(),
+            ctrl_messages: Default::default(),
+            stream_rehashes: Default::default(),
+            send_copies: Default::default(),
+            send_copy_bytes: Default::default(),
+            inflight_requests: Default::default(),
+            send_queues: Default::default(),
+            live_requests: Default::default(),
+            sent: Default::default(),
+            received: Default::default(),
+            devices: Default::default(),
+            tcp_streams: (meter.is_some() && tcp_info).then(Default::default),
+        }
+    }
+
+    /// For the driver of a comm to record its data streams in.
+    fn open_tcp_streams(&self, kind: CommKind, id: usize) -> Option<StreamFds> {
+        self.tcp_streams
+            .as_ref()
+            .map(|tcp_streams| tcp_streams.open(kind, id))
+    }
+
+    fn close_tcp_streams(&self, kind: CommKind, id: usize) {
+        if let Some(tcp_streams) = &self.tcp_streams {
+            tcp_streams.close(kind, id);
+        }
+    }
+
+    /// Of a comm opened on the device, whose tally adds up in that of the
+    /// device.
+    fn open_on_device(&self, dev_id: usize, interface_name: &str) -> Arc<DeviceComm> {
+        let device = self
+            .devices
+            .of(dev_id, interface_name, &self.sent, &self.received);
+        device.comms.fetch_add(1, Ordering::Relaxed);
+        Arc::new(DeviceComm(device))
+    }
+}
+
+/// Of a device, what the comms on it sent and received, and how many of them
+/// are open.
+#[derive(Debug)]
+struct DeviceTally {
+    interface_name: String,
+    sent: Arc<Tally>,
+    received: Arc<Tally>,
+    comms: AtomicUsize,
+}
+
+/// Of the devices that comms were opened on, by ID.
+#[derive(Debug, Default)]
+struct DeviceTallies(Mutex<HashMap<usize, Arc<DeviceTally>>>);
+
+impl DeviceTallies {
+    fn of(
+        &self,
+        dev_id: usize,
+        interface_name: &str,
+        sent: &Arc<Tally>,
+        received: &Arc<Tally>,
+    ) -> Arc<DeviceTally> {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(dev_id)
+            .or_insert_with(|| {
+                Arc::new(DeviceTally {
+                    interface_name: interface_name.to_owned(),
+                    sent: Arc::new(Tally::of_comm(sent)),
+                    received: Arc::new(Tally::of_comm(received)),
+                    comms: AtomicUsize::new(0),
+                })
+            })
+            .clone()
+    }
+
+    fn devices(&self) -> Vec<(usize, Arc<DeviceTally>)> {
+        let devices = self.0.lock().unwrap();
+        devices
+            .iter()
+            .map(|(&dev_id, device)| (dev_id, device.clone()))
+            .collect()
+    }
+}
+
+/// Counts a comm as open on its device until it is dropped, with its clones.
+#[derive(Debug)]
+struct DeviceComm(Arc<DeviceTally>);
+
+impl Drop for DeviceComm {
+    fn drop(&mut self) {
+        self.0.comms.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Of a send comm, what is queued in it.
+#[derive(Debug, Default)]
+struct SendQueueDepth {
+    /// Posted, not taken by its driver yet.
+    messages: AtomicUsize,
+    /// Chunks on each data stream, as of the last time its driver polled.
+    streams: Mutex<Vec<usize>>,
+    /// Of its fastest data stream to its slowest, over the last window that
+    /// told, see `ImbalanceWindow`.
+    imbalance: Mutex<Option<f64>>,
+}
+
+impl SendQueueDepth {
+    fn record_streams(&self, streams: &[SendStream]) {
+        let mut depths = self.streams.lock().unwrap();
+        depths.clear();
+        depths.extend(streams.iter().map(|stream| stream.chunks.len()));
+    }
+}
+
+/// Of the live send comms, by ID, so that the observers need not lock the
+/// net.
+#[derive(Debug, Default)]
+struct SendQueues(Mutex<HashMap<SocketSendCommID, Arc<SendQueueDepth>>>);
+
+impl SendQueues {
+    fn open(&self, id: SocketSendCommID, depth: Arc<SendQueueDepth>) {
+        self.0.lock().unwrap().insert(id, depth);
+    }
+
+    fn close(&self, id: SocketSendCommID) {
+        self.0.lock().unwrap().remove(&id);
+    }
+
+    fn comms(&self) -> Vec<(SocketSendCommID, Arc<SendQueueDepth>)> {
+        let comms = self.0.lock().unwrap();
+        comms
+            .iter()
+            .map(|(&id, depth)| (id, depth.clone()))
+            .collect()
+    }
+}
+
+/// Of a comm, dups of the fds of its data streams, see `TcpStreams`.
+type StreamFds = Arc<Mutex<Vec<Arc<OwnedFd>>>>;
+
+/// Of the live comms, by kind and ID, for the observers of the `TCP_INFO` of
+/// their streams. The observers read the dups, never an fd closed and reused
+/// meanwhile, and a stream replaced since its driver last polled reports as
+/// the one it replaced until the next poll.
+#[derive(Debug, Default)]
+struct TcpStreams(Mutex<HashMap<(CommKind, usize), StreamFds>>);
+
+impl TcpStreams {
+    fn open(&self, kind: CommKind, id: usize) -> StreamFds {
+        let fds = StreamFds::default();
+        self.0.lock().unwrap().insert((kind, id), fds.clone());
+        fds
+    }
+
+    fn close(&self, kind: CommKind, id: usize) {
+        self.0.lock().unwrap().remove(&(kind, id));
+    }
+
+    /// Off the lock of each comm, so that its driver does not wait on the
+    /// observers.
+    fn comms(&self) -> Vec<(CommKind, usize, Vec<Arc<OwnedFd>>)> {
+        let comms = self.0.lock().unwrap();
+        comms
+            .iter()
+            .map(|(&(kind, id), fds)| (kind, id, fds.lock().unwrap().clone()))
+            .collect()
+    }
+}
+
+/// Where a driver records its data streams, cleared once it is dropped so
+/// that the dups do not keep its streams open.
+struct RecordedFds(Option<StreamFds>);
+
+impl RecordedFds {
+    /// Once the driver polled its data streams, dups them again if any is new
+    /// since the last time.
+    fn record<S>(&self, streams: &mut [S], io: fn(&mut S) -> &mut DrivenStream) {
+        let stream_fds = match &self.0 {
+            Some(stream_fds) => stream_fds,
+            None => return,
+        };
+        let mut fds = stream_fds.lock().unwrap();
+        if fds.len() == streams.len() && streams.iter_mut().all(|stream| io(stream).recorded) {
+            return;
+        }
+        fds.clear();
+        for stream in streams.iter_mut() {
+            let stream = io(stream);
+            let fd = unsafe { BorrowedFd::borrow_raw(stream.stream.as_raw_fd()) };
+            match fd.try_clone_to_owned() {
+                Ok(fd) => fds.push(Arc::new(fd)),
+                Err(err) => tracing::debug!("dup {} failed, err={:?}", stream.stream.peer(), err),
+            }
+            stream.recorded = true;
+        }
+    }
+}
+
+impl Drop for RecordedFds {
+    fn drop(&mut self) {
+        if let Some(stream_fds) = &self.0 {
+            stream_fds.lock().unwrap().clear();
+        }
+    }
+}
+
+/// Of the data streams with the same index on every send comm.
+#[derive(Debug, Default)]
+struct StreamThroughput {
+    nbytes: AtomicU64,
+    /// While chunks were being written.
+    busy_ns: AtomicU64,
+}
+
+impl StreamThroughput {
+    fn record(&self, nbytes: usize, busy: Duration) {
+        self.nbytes.fetch_add(nbytes as u64, Ordering::Relaxed);
+        self.busy_ns
+            .fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// By data stream index, as many as the widest send comm has had.
+#[derive(Debug, Default)]
+struct SendStreamMetrics(Mutex<Vec<Arc<StreamThroughput>>>);
+
+impl SendStreamMetrics {
+    /// Taken once by each data stream.
+    fn of(&self, index: usize) -> Arc<StreamThroughput> {
+        let mut streams = self.0.lock().unwrap();
+        if streams.len() <= index {
+            streams.resize_with(index + 1, Default::default);
+        }
+        streams[index].clone()
+    }
+
+    fn streams(&self) -> Vec<Arc<StreamThroughput>> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Of the metrics that are pushed.
+fn observe_metrics(
+    meter: &Meter,
+    state: &AppState,
+    mr_registry: &Arc<Mutex<MrRegistry>>,
+    split_tuner: Option<Arc<Mutex<SplitTuner>>>,
+    min_chunksize: usize,
+) {
+    // Since they were last observed, by data stream index: the bytes per
+    // second, and how much of the time chunks were being written.
+    for (name, counter) in [
+        (
+            "isend_nbytes_per_second",
+            (|throughput| throughput.nbytes.load(Ordering::Relaxed) as f64)
+                as fn(&StreamThroughput) -> f64,
+        ),
+        ("isend_percentage_of_effective_time", |throughput| {
+            Duration::from_nanos(throughput.busy_ns.load(Ordering::Relaxed)).as_secs_f64()
+        }),
+    ] {
+        let send_streams = state.send_streams.clone();
+        let observed = Mutex::new((Instant::now(), Vec::new()));
+        meter
+            .f64_value_observer(name, move |res: ObserverResult<f64>| {
+                let totals: Vec<f64> = send_streams.streams().iter().map(|t| counter(t)).collect();
+                let (at, last) = &mut *observed.lock().unwrap();
+                let elapsed = std::mem::replace(at, Instant::now())
+                    .elapsed()
+                    .as_secs_f64();
+                for (index, total) in totals.iter().enumerate() {
+                    let delta = total - last.get(index).copied().unwrap_or(0.);
+                    res.observe(delta / elapsed, &[KeyValue::new("stream", index as i64)]);
+                }
+                *last = totals;
+            })
+            .init();
+    }
+    let mr_registry_clone = mr_registry.clone();
+    meter
+        .u64_value_observer("mr_count", move |res: ObserverResult<u64>| {
+            res.observe(
+                mr_registry_clone.lock().unwrap().count() as u64,
+                HANDLER_ALL.as_ref(),
+            );
+        })
+        .init();
+    let mr_registry_clone = mr_registry.clone();
+    meter
+        .u64_value_observer("mr_pinned_bytes", move |res: ObserverResult<u64>| {
+            res.observe(
+                mr_registry_clone.lock().unwrap().pinned_bytes() as u64,
+                HANDLER_ALL.as_ref(),
+            );
+        })
+        .init();
+    // Of `buffer_pool::POOL`, the leased ones grow with what leaks.
+    for (name, observe) in [
+        (
+            "buffer_pool_leased",
+            BufferPool::leased as fn(&BufferPool) -> usize,
+        ),
+        ("buffer_pool_leased_bytes", BufferPool::leased_bytes),
+        ("buffer_pool_pooled_bytes", BufferPool::pooled_bytes),
+    ] {
+        meter
+            .u64_value_observer(name, move |res: ObserverResult<u64>| {
+                res.observe(observe(&buffer_pool::POOL) as u64, HANDLER_ALL.as_ref());
+            })
+            .init();
+    }
+    meter
+        .u64_value_observer("min_chunksize", move |res: ObserverResult<u64>| {
+            let min_chunksize = match &split_tuner {
+                Some(tuner) => tuner.lock().unwrap().threshold(),
+                None => min_chunksize,
+            };
+            res.observe(min_chunksize as u64, HANDLER_ALL.as_ref());
+        })
+        .init();
+    for (name, counter) in [
+        ("ctrl_writes", &state.ctrl_writes),
+        ("ctrl_messages", &state.ctrl_messages),
+        ("stream_rehashes", &state.stream_rehashes),
+        ("send_copies", &state.send_copies),
+    ] {
+        let counter = counter.clone();
+        meter
+            .u64_sum_observer(name, move |res: ObserverResult<u64>| {
+                res.observe(counter.load(Ordering::Relaxed), HANDLER_ALL.as_ref());
+            })
+            .init();
+    }
+    // By send comm, and by its data streams, what is backed up in the net
+    // rather than waiting on the caller.
+    let send_queues = state.send_queues.clone();
+    meter
+        .u64_value_observer("send_queue_depth", move |res: ObserverResult<u64>| {
+            for (id, depth) in send_queues.comms() {
+                res.observe(
+                    depth.messages.load(Ordering::Relaxed) as u64,
+                    &[KeyValue::new("comm_id", id as i64)],
+                );
+            }
+        })
+        .init();
+    let send_queues = state.send_queues.clone();
+    meter
+        .u64_value_observer("stream_queue_depth", move |res: ObserverResult<u64>| {
+            for (id, depth) in send_queues.comms() {
+                for (index, &nchunks) in depth.streams.lock().unwrap().iter().enumerate() {
+                    res.observe(
+                        nchunks as u64,
+                        &[
+                            KeyValue::new("comm_id", id as i64),
+                            KeyValue::new("stream", index as i64),
+                        ],
+                    );
+                }
+            }
+        })
+        .init();
+    // By device, labelled with its interface, what its comms moved.
+    for (name, tally) in [
+        (
+            "dev_sent_nbytes",
+            (|device| &device.sent) as fn(&DeviceTally) -> &Arc<Tally>,
+        ),
+        ("dev_received_nbytes", |device| &device.received),
+    ] {
+        let devices = state.devices.clone();
+        meter
+            .u64_sum_observer(name, move |res: ObserverResult<u64>| {
+                for (dev_id, device) in devices.devices() {
+                    res.observe(
+                        tally(&device).nbytes.load(Ordering::Relaxed),
+                        &[
+                            KeyValue::new("dev", dev_id as i64),
+                            KeyValue::new("interface_name", device.interface_name.clone()),
+                        ],
+                    );
+                }
+            })
+            .init();
+    }
+    let devices = state.devices.clone();
+    meter
+        .u64_value_observer("comm_count", move |res: ObserverResult<u64>| {
+            for (dev_id, device) in devices.devices() {
+                res.observe(
+                    device.comms.load(Ordering::Relaxed) as u64,
+                    &[KeyValue::new("dev", dev_id as i64)],
+                );
+            }
+        })
+        .init();
+    let send_queues = state.send_queues.clone();
+    meter
+        .f64_value_observer("stream_imbalance_ratio", move |res: ObserverResult<f64>| {
+            for (id, depth) in send_queues.comms() {
+                if let Some(ratio) = *depth.imbalance.lock().unwrap() {
+                    res.observe(ratio, &[KeyValue::new("comm_id", id as i64)]);
+                }
+            }
+        })
+        .init();
+    #[cfg(target_os = "linux")]
+    if let Some(tcp_streams) = &state.tcp_streams {
+        for (name, field) in [
+            (
+                "tcp_rtt_us",
+                (|info| info.tcpi_rtt) as fn(&libc::tcp_info) -> u32,
+            ),
+            ("tcp_retrans_total", |info| info.tcpi_total_retrans),
+            ("tcp_snd_cwnd", |info| info.tcpi_snd_cwnd),
+        ] {
+            let tcp_streams = tcp_streams.clone();
+            meter
+                .u64_value_observer(name, move |res: ObserverResult<u64>| {
+                    for (kind, id, fds) in tcp_streams.comms() {
+                        let kind = match kind {
+                            CommKind::Send => "send",
+                            CommKind::Recv => "recv",
+                        };
+                        for (index, fd) in fds.into_iter().enumerate() {
+                            // Not a TCP stream.
+                            if let Ok(info) = connection::tcp_info(fd.as_raw_fd()) {
+                                res.observe(
+                                    field(&info) as u64,
+                                    &[
+                                        KeyValue::new("comm", kind),
+                                        KeyValue::new("comm_id", id as i64),
+                                        KeyValue::new("stream", index as i64),
+                                    ],
+                                );
+                            }
+                        }
+                    }
+                })
+                .init();
+        }
+    }
+    for (name, value) in [
+        ("send_copy_bytes", &state.send_copy_bytes),
+        ("inflight_requests", &state.inflight_requests),
+        ("live_requests", &state.live_requests),
+    ] {
+        let value = value.clone();
+        meter
+            .u64_value_observer(name, move |res: ObserverResult<u64>| {
+                res.observe(value.load(Ordering::Relaxed), HANDLER_ALL.as_ref());
+            })
+            .init();
+    }
 }
 
 pub struct BaguaNet {
     pub socket_devs: Vec<NCCLSocketDev>,
-    pub listen_comm_next_id: usize,
-    pub listen_comm_map: HashMap<SocketListenCommID, SocketListenComm>,
-    pub send_comm_next_id: usize,
-    pub send_comm_map: HashMap<SocketSendCommID, SocketSendComm>,
-    pub recv_comm_next_id: usize,
-    pub recv_comm_map: HashMap<SocketRecvCommID, SocketRecvComm>,
-    pub socket_request_next_id: usize,
-    pub socket_request_map: HashMap<SocketRequestID, SocketRequest>,
+    /// See `utils::socket_dev_latencies_us`, devices added since are
+    /// estimated.
+    latencies_us: HashMap<String, f32>,
+    pub listen_comm_map: Slab<SocketListenComm>,
+    pub send_comm_map: Slab<SocketSendComm>,
+    pub recv_comm_map: Slab<SocketRecvComm>,
+    pub socket_request_map: Slab<SocketRequest>,
+    pub recently_done: RecentlyDone,
+    /// Of the requests handed to their completion hooks, for `cancel()`, with
+    /// the driver of their comm. Pruned of those done as more are handed over.
+    hooked: HashMap<SocketRequestID, (Weak<RequestState>, Option<DriverWaker>)>,
     pub trace_span_context: opentelemetry::Context,
-    pub trace_on_flag: bool,
+    /// Of the sends and receives that get a span, `None` if none do.
+    span_sampler: Option<SpanSampler>,
+    #[allow(dead_code)]
     pub rank: i32,
     state: Arc<AppState>,
     nstreams: usize,
+    /// What comms start with, with `BAGUA_NET_MIN_CHUNKSIZE=auto` too.
     min_chunksize: usize,
+    /// With `BAGUA_NET_MIN_CHUNKSIZE=auto`, shared by the send comms.
+    split_tuner: Option<Arc<Mutex<SplitTuner>>>,
+    /// 0 splits messages in as many chunks as streams.
+    chunk_bytes: usize,
+    /// Of the messages posted to a comm, and of the chunks queued on each of
+    /// its data streams.
+    queue_capacity: usize,
+    /// `BAGUA_NET_MAX_INFLIGHT_REQUESTS`, of the sends or receives on a comm
+    /// not done yet, past which posting more is `BaguaNetError::Busy`.
+    max_inflight: usize,
+    /// With `BAGUA_NET_NSTREAMS=auto`.
+    adaptive_streams: Option<AdaptiveStreamsConfig>,
+    /// Unless `BAGUA_NET_IMBALANCE_RATIO=0`.
+    imbalance: Option<ImbalanceConfig>,
+    connect_config: ConnectConfig,
+    accept_config: AcceptConfig,
+    listen_config: ListenConfig,
+    reconnect_config: ReconnectConfig,
+    /// Of the writes that block a connecting thread, e.g. replays.
+    wait_mode: WaitMode,
+    /// With `BAGUA_NET_ZEROCOPY=1`, chunks of at least this many bytes are
+    /// sent in place. The replay window of reconnecting still copies them.
+    zerocopy_threshold: Option<usize>,
+    /// By device, created by the first listen comm on it.
+    reconnect_acceptors: HashMap<usize, Arc<ReconnectAcceptor>>,
+    /// With `BAGUA_NET_CONN_CACHE=1`, by device and listener address.
+    send_comm_cache: SendCommCache,
+    /// With `BAGUA_NET_CONN_CACHE=1`, by listener address.
+    recv_comm_cache: RecvCommCache,
+    /// Messages up to this size, and no larger than the inline threshold, are
+    /// taken ahead of the chunks of larger ones before them, see `SendLanes`.
+    priority_threshold: usize,
+    /// `BAGUA_NET_MAX_BW_MBPS` of every send comm, unless set for one with
+    /// `Net::set_max_bandwidth`.
+    max_bandwidth: Option<u64>,
+    /// With `BAGUA_NET_COPY_THRESHOLD`, `isend` copies messages up to this
+    /// size and completes them right away, they are sent from the copy.
+    copy_threshold: Option<usize>,
+    /// Drive the streams of every comm, `BAGUA_NET_IO_THREADS` of them.
+    event_loops: EventLoops,
+    /// Unless `BAGUA_NET_THREAD_AFFINITY` is set, the threads of a comm run
+    /// on the NUMA node of its NIC.
+    numa_affinity: bool,
+    /// Shared with the metrics observers.
+    mr_registry: Arc<Mutex<MrRegistry>>,
+    /// `BAGUA_NET_CHECK_BUFFERS=1`, isend and irecv fail on buffers outside
+    /// their registered region that are not mapped. For debugging, it costs
+    /// a syscall per transfer.
+    check_buffers: bool,
+    /// With the `cuda` feature, device buffers are staged through it.
+    staging: Option<Arc<Staging>>,
+    /// With `BAGUA_NET_COMPRESSION=lz4`.
+    compression: Option<Arc<Compression>>,
+    /// With `BAGUA_NET_REQUEST_TIMEOUT_SECS`, `test()` fails the requests not
+    /// done this long after they were posted, and their comms.
+    request_timeout: Option<Duration>,
+    /// `BAGUA_NET_CLOSE_TIMEOUT_SECS`, of `close_send` waiting for the
+    /// messages posted to be sent, and of the net waiting for its threads
+    /// once it is dropped.
+    close_timeout: Duration,
+    /// Of the event loops, the reconnect acceptors, the metrics uploader and
+    /// server.
+    /// Connecting a comm and replacing its streams take threads of their
+    /// own, which end once that is done or timed out.
+    threads: LiveThreads,
+    /// Pushes the metrics until it is told to stop, by dropping the sender.
+    uploader: Option<(flume::Sender<()>, std::thread::JoinHandle<()>)>,
+    /// Serves the metrics to scrapers the same way.
+    metrics_server: Option<(flume::Sender<()>, std::thread::JoinHandle<()>)>,
+    /// Once `shutdown()` started, nothing more is posted or connected.
+    shut_down: bool,
+    /// Whether the chunks of a request get spans under its own, see
+    /// `chunk_span`.
+    trace_chunks: bool,
 }
 
 impl BaguaNet {
     const DEFAULT_SOCKET_MAX_COMMS: i32 = 65536;
-    const DEFAULT_LISTEN_BACKLOG: i32 = 16384;
 
     pub fn new() -> Result<BaguaNet, BaguaNetError> {
-        let rank: i32 = std::env::var("RANK")
-            .unwrap_or("-1".to_string())
-            .parse()
-            .unwrap();
-        TELEMETRY_INIT_ONCE.call_once(|| {
-            if rank == -1 || rank > 7 {
-                return;
-            }
-
-            let jaeger_addr = match std::env::var("BAGUA_NET_JAEGER_ADDRESS") {
-                Ok(jaeger_addr) => {
-                    tracing::info!("detected auto tuning server, connecting");
-                    jaeger_addr
-                }
-                Err(_) => {
-                    tracing::warn!("Jaeger server not detected.");
-                    return;
-                }
-            };
+        let rank = utils::detect_rank();
+        let telemetry = TelemetryConfig::from_env();
+        telemetry.init_tracing(rank);
 
-            opentelemetry::global::set_text_map_propagator(opentelemetry_jaeger::Propagator::new());
-            opentelemetry_jaeger::new_pipeline()
-                .with_collector_endpoint(format!("http://{}/api/traces", jaeger_addr))
-                .with_service_name("bagua-net")
-                .install_batch(opentelemetry::runtime::AsyncStd)
-                .unwrap();
-        });
+        let socket_devs = utils::filter_socket_devs(
+            utils::find_interfaces(),
+            &std::env::var("BAGUA_NET_SOCKET_IFNAME").unwrap_or("".to_owned()),
+        );
 
         let tracer = opentelemetry::global::tracer("bagua-net");
         let mut span = tracer.start(format!("BaguaNet-{}", rank));
         span.set_attribute(KeyValue::new(
             "socket_devs",
-            format!("{:?}", utils::find_interfaces()),
+            telemetry::capped_attribute(format!("{:?}", socket_devs)),
         ));
 
-        let prom_exporter = opentelemetry_prometheus::exporter()
-            .with_default_histogram_boundaries(vec![16., 1024., 4096., 1048576.])
-            .init();
-
-        let isend_nbytes_per_second = Arc::new(Mutex::new(0.));
-        let isend_percentage_of_effective_time = Arc::new(Mutex::new(0.));
-
-        let meter = opentelemetry::global::meter("bagua-net");
-        let isend_nbytes_per_second_clone = isend_nbytes_per_second.clone();
-        meter
-            .f64_value_observer(
-                "isend_nbytes_per_second",
-                move |res: ObserverResult<f64>| {
-                    res.observe(
-                        *isend_nbytes_per_second_clone.lock().unwrap(),
-                        HANDLER_ALL.as_ref(),
-                    );
-                },
-            )
-            .init();
-        let isend_percentage_of_effective_time_clone = isend_percentage_of_effective_time.clone();
-        meter
-            .f64_value_observer(
-                "isend_percentage_of_effective_time",
-                move |res: ObserverResult<f64>| {
-                    res.observe(
-                        *isend_percentage_of_effective_time_clone.lock().unwrap(),
-                        HANDLER_ALL.as_ref(),
-                    );
-                },
-            )
-            .init();
-        let state = Arc::new(AppState {
-            exporter: prom_exporter.clone(),
-            isend_nbytes_gauge: meter
-                .u64_value_recorder("isend_nbytes")
-                .init()
-                .bind(HANDLER_ALL.as_ref()),
-            irecv_nbytes_gauge: meter
-                .u64_value_recorder("irecv_nbytes")
-                .init()
-                .bind(HANDLER_ALL.as_ref()),
-            isend_nbytes_per_second: isend_nbytes_per_second,
-            isend_percentage_of_effective_time: isend_percentage_of_effective_time,
-            uploader: std::thread::spawn(move || {
-                let prometheus_addr =
-                    std::env::var("BAGUA_NET_PROMETHEUS_ADDRESS").unwrap_or_default();
-                let (user, pass, address) = match utils::parse_user_pass_and_addr(&prometheus_addr)
-                {
-                    Some(ret) => ret,
-                    None => return,
-                };
-
-                loop {
-                    std::thread::sleep(std::time::Duration::from_micros(200));
-                    let metric_families = prom_exporter.registry().gather();
-                    match prometheus::push_metrics(
-                        "BaguaNet",
-                        prometheus::labels! { "rank".to_owned() => rank.to_string(), },
-                        &address,
-                        metric_families,
-                        Some(prometheus::BasicAuthentication {
-                            username: user.clone(),
-                            password: pass.clone(),
-                        }),
-                    ) {
-                        Ok(_) => {}
-                        Err(err) => {
-                            tracing::warn!("{:?}", err);
-                        }
+        let metrics = telemetry.metrics();
+        let meters = metrics.as_ref().map(|(_, meters)| meters);
+        let meter = meters.map(|meters| &meters.meter);
+        let mr_registry = Arc::new(Mutex::new(MrRegistry::default()));
+        let split_tuner = SplitTuner::from_env().map(|tuner| Arc::new(Mutex::new(tuner)));
+        let min_chunksize = split_tuning::min_chunksize_from_env(1048576)
+            .unwrap_or(split_tuning::INITIAL_MIN_CHUNKSIZE);
+        let state = Arc::new(AppState::new(
+            meters,
+            telemetry.per_peer,
+            telemetry.tcp_info,
+        ));
+        if let Some(meter) = meter {
+            observe_metrics(
+                meter,
+                &state,
+                &mr_registry,
+                split_tuner.clone(),
+                min_chunksize,
+            );
+        }
+        let threads = LiveThreads::default();
+        let metrics_server = match (&metrics, telemetry.prometheus_listen) {
+            (Some((exporter, meters)), Some(addr)) => {
+                match telemetry::listen_metrics(addr, &meters.meter) {
+                    Ok(listener) => {
+                        let exporter = exporter.clone();
+                        let (stop_server, stopped) = flume::bounded::<()>(0);
+                        let server = threads
+                            .spawn(move || telemetry::serve_metrics(listener, exporter, stopped));
+                        Some((stop_server, server))
+                    }
+                    Err(err) => {
+                        tracing::warn!("failed to serve metrics at {}, err={:?}", addr, err);
+                        None
                     }
                 }
-            }),
-        });
+            }
+            _ => None,
+        };
+        let trace_chunks = telemetry.traces_chunks();
+        let span_sampler = telemetry.span_sampler();
+        // Nothing to push to otherwise.
+        let uploader = match (metrics, telemetry.prometheus) {
+            (Some((exporter, _)), Some(prometheus)) => {
+                let (stop_uploader, stopped) = flume::bounded::<()>(0);
+                let uploader = threads
+                    .spawn(move || telemetry::push_metrics(prometheus, exporter, rank, stopped));
+                Some((stop_uploader, uploader))
+            }
+            _ => None,
+        };
 
+        let tls = TlsConfig::from_env()?;
+        let io_threads: usize = std::env::var("BAGUA_NET_IO_THREADS")
+            .unwrap_or("4".to_owned())
+            .parse()
+            .unwrap();
+        let affinity_spec = std::env::var("BAGUA_NET_THREAD_AFFINITY").unwrap_or("".to_owned());
+        let affinity = utils::thread_affinity(&affinity_spec, &socket_devs);
+        // Spinning I/O threads without a core to spare take it from the
+        // threads they wait for.
+        let spare_cpus = std::thread::available_parallelism().is_ok_and(|n| n.get() > io_threads);
+        let worker_spin_us: u64 = std::env::var("BAGUA_NET_WORKER_SPIN_US")
+            .unwrap_or(if spare_cpus { "50" } else { "0" }.to_owned())
+            .parse()
+            .unwrap();
+        let event_loops = EventLoops::spawn(
+            &threads,
+            io_threads,
+            true,
+            &affinity[..],
+            Duration::from_micros(worker_spin_us),
+        )
+        .map_err(BaguaNetError::from)?;
+        let (send_comm_cache, recv_comm_cache) = conn_caches(&ConnCacheConfig::from_env());
+        let handshake_min_chunksize = match split_tuner {
+            Some(_) => CommHandshake::AUTO_MIN_CHUNKSIZE,
+            None => min_chunksize,
+        };
+        let chunk_bytes = std::env::var("BAGUA_NET_CHUNK_BYTES")
+            .unwrap_or("262144".to_owned())
+            .parse()
+            .unwrap();
+        let queue_capacity = std::env::var("BAGUA_NET_QUEUE_CAPACITY")
+            .unwrap_or("256".to_owned())
+            .parse()
+            .unwrap();
+        let adaptive_streams = AdaptiveStreamsConfig::from_env();
+        let zerocopy_threshold =
+            (std::env::var("BAGUA_NET_ZEROCOPY").unwrap_or("0".to_owned()) == "1").then(|| {
+                std::env::var("BAGUA_NET_ZEROCOPY_THRESHOLD")
+                    .unwrap_or("65536".to_owned())
+                    .parse()
+                    .unwrap()
+            });
+        let max_nstreams = adaptive_streams
+            .as_ref()
+            .map_or(0, |config| config.max_nstreams);
+        let compression = Compression::from_env();
         Ok(Self {
-            socket_devs: utils::find_interfaces(),
-            listen_comm_next_id: 0,
+            latencies_us: utils::socket_dev_latencies_us(&socket_devs),
+            socket_devs,
             listen_comm_map: Default::default(),
-            send_comm_next_id: 0,
             send_comm_map: Default::default(),
-            recv_comm_next_id: 0,
             recv_comm_map: Default::default(),
-            socket_request_next_id: 0,
             socket_request_map: Default::default(),
+            recently_done: Default::default(),
+            hooked: Default::default(),
             trace_span_context: opentelemetry::Context::current_with_span(span),
-            rank: rank,
-            trace_on_flag: rank < 8,
-            state: state,
-            nstreams: std::env::var("BAGUA_NET_NSTREAMS")
-                .unwrap_or("2".to_owned())
+            rank,
+            span_sampler,
+            state,
+            nstreams: AdaptiveStreamsConfig::nstreams_from_env(),
+            min_chunksize,
+            split_tuner,
+            chunk_bytes,
+            queue_capacity,
+            max_inflight: std::env::var("BAGUA_NET_MAX_INFLIGHT_REQUESTS")
+                .unwrap_or("1024".to_owned())
                 .parse()
                 .unwrap(),
-            min_chunksize: std::env::var("BAGUA_NET_MIN_CHUNKSIZE")
-                .unwrap_or("1048576".to_owned())
+            adaptive_streams,
+            imbalance: ImbalanceConfig::from_env(),
+            connect_config: ConnectConfig {
+                tls: tls.clone(),
+                min_chunksize: handshake_min_chunksize,
+                chunk_bytes,
+                max_nstreams,
+                compression: compression.is_some(),
+                ..ConnectConfig::from_env()
+            },
+            accept_config: AcceptConfig {
+                tls,
+                min_chunksize: handshake_min_chunksize,
+                chunk_bytes,
+                max_nstreams,
+                compression: compression.is_some(),
+                ..AcceptConfig::from_env()
+            },
+            listen_config: ListenConfig::from_env(),
+            reconnect_config: ReconnectConfig::from_env(),
+            wait_mode: WaitMode::from_env(),
+            zerocopy_threshold,
+            reconnect_acceptors: Default::default(),
+            send_comm_cache,
+            recv_comm_cache,
+            priority_threshold: std::env::var("BAGUA_NET_PRIORITY_THRESHOLD")
+                .unwrap_or("4096".to_owned())
                 .parse()
                 .unwrap(),
+            max_bandwidth: rate_limit::max_bandwidth_from_env(),
+            copy_threshold: std::env::var("BAGUA_NET_COPY_THRESHOLD")
+                .ok()
+                .map(|threshold| threshold.parse().unwrap()),
+            event_loops,
+            numa_affinity: affinity_spec.is_empty(),
+            mr_registry,
+            check_buffers: std::env::var("BAGUA_NET_CHECK_BUFFERS").unwrap_or("0".to_owned())
+                == "1",
+            staging: Staging::from_env(),
+            compression,
+            request_timeout: Some(Duration::from_secs(
+                std::env::var("BAGUA_NET_REQUEST_TIMEOUT_SECS")
+                    .unwrap_or("0".to_owned())
+                    .parse()
+                    .unwrap(),
+            ))
+            .filter(|timeout| !timeout.is_zero()),
+            close_timeout: Duration::from_secs(
+                std::env::var("BAGUA_NET_CLOSE_TIMEOUT_SECS")
+                    .unwrap_or("60".to_owned())
+                    .parse()
+                    .unwrap(),
+            ),
+            threads,
+            uploader,
+            metrics_server,
+            trace_chunks,
+            shut_down: false,
         })
     }
-}
 
-impl Net for BaguaNet {
-    fn devices(&self) -> Result<usize, BaguaNetError> {
-        Ok(self.socket_devs.len())
+    /// Of the net being dropped, if it was not shut down before.
+    const DROP_DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn check_running(&self) -> Result<(), BaguaNetError> {
+        match self.shut_down {
+            true => Err(BaguaNetError::Closed),
+            false => Ok(()),
+        }
     }
 
-    fn get_properties(&self, dev_id: usize) -> Result<NCCLNetProperties, BaguaNetError> {
-        let socket_dev = &self.socket_devs[dev_id];
+    /// Of the requests that were not tested to completion yet, those that
+    /// never are leak.
+    pub fn live_requests(&self) -> usize {
+        self.socket_request_map.len()
+    }
 
-        Ok(NCCLNetProperties {
-            name: socket_dev.interface_name.clone(),
-            pci_path: socket_dev.pci_path.clone(),
-            guid: dev_id as u64,
-            ptr_support: NCCL_PTR_HOST,
-            speed: utils::get_net_if_speed(&socket_dev.interface_name),
-            port: 0,
-            max_comms: BaguaNet::DEFAULT_SOCKET_MAX_COMMS,
+    /// Device buffers are not mapped on the host.
+    /// Of `irecv` and `irecv_multi`, once the buffers are checked. Each
+    /// buffer takes a message, like after as many `irecv`.
+    fn post_recv(
+        &mut self,
+        recv_comm_id: SocketRecvCommID,
+        bufs: Vec<RecvBuffer>,
+    ) -> Result<SocketRequestID, BaguaNetError> {
+        self.check_running()?;
+        let mut span = RequestSpan::start(self.span_sampler, &self.trace_span_context, || {
+            format!("irecv-{}", recv_comm_id)
+        });
+        let trace_parent = self.chunk_trace_parent(&span);
+        let recv_comm = self
+            .recv_comm_map
+            .get_mut(recv_comm_id)
+            .ok_or_else(|| slab::unknown("recv comm", recv_comm_id))?;
+        if let Some(err) = &*utils::lock(&recv_comm.health) {
+            return Err(err.clone());
+        }
+        for data in bufs.iter() {
+            check_fixed_size(&recv_comm.fixed_size, data.len())?;
+        }
+        let closed_err = || {
+            BaguaNetError::remote(format!(
+                "recv comm {} was closed by the sender",
+                recv_comm_id
+            ))
+        };
+        let nbufs = bufs.len();
+        let ranges = bufs
+            .iter()
+            .map(|data| address_range(data.as_raw()))
+            .collect();
+        let last = recv_comm.nposted + nbufs - 1;
+        if recv_comm.unsent(last) {
+            return Err(closed_err());
+        }
+        // All of the buffers are queued or none, only the driver takes them
+        // off the channel meanwhile.
+        let capacity = recv_comm.msg_sender.capacity().unwrap_or(usize::MAX);
+        if nbufs > capacity {
+            return Err(BaguaNetError::InvalidArgument(format!(
+                "{} buffers posted at once, recv comm {} queues {}",
+                nbufs, recv_comm_id, capacity
+            )));
+        }
+        if recv_comm.msg_sender.len() + nbufs > capacity
+            || recv_comm.inflight.load(Ordering::Relaxed) >= self.max_inflight
+        {
+            return Err(BaguaNetError::Busy);
+        }
+        // Before the task is queued, which it cannot be taken back from.
+        let id = self.socket_request_map.next_id()?;
+        let task_state = Arc::new(
+            RequestState::new(nbufs)
+                .traced(trace_parent)
+                .counted(recv_comm.tally.clone())
+                .tallied(recv_comm.tally.clone()),
+        );
+        let sizes: RecvSizes = (0..nbufs).map(|_| AtomicUsize::new(UNDECLARED)).collect();
+
+        // Like in isend, catches those posted while the driver was failing
+        // the queued ones.
+        let mut sent = true;
+        for (index, data) in bufs.into_iter().enumerate() {
+            let size = (sizes.clone(), index);
+            match recv_comm
+                .msg_sender
+                .try_send((data, task_state.clone(), size))
+            {
+                Ok(()) => recv_comm.nposted += 1,
+                Err(flume::TrySendError::Full(_)) if index == 0 => return Err(BaguaNetError::Busy),
+                Err(_) => {
+                    sent = false;
+                    break;
+                }
+            }
+        }
+        recv_comm.waker.wake();
+
+        span.set_attribute(KeyValue::new("id", id as i64));
+        if nbufs > 1 {
+            span.set_attribute(KeyValue::new("nbufs", nbufs as i64));
+        }
+
+        self.socket_request_map
+            .insert(SocketRequest::RecvRequest(SocketRecvRequest {
+                state: task_state.clone(),
+                trace_span: span,
+                posted_at: Instant::now(),
+                health: recv_comm.health.clone(),
+                inflight: Some(Inflight::count(
+                    &recv_comm.inflight,
+                    &self.state.inflight_requests,
+                )),
+                sizes: sizes.clone(),
+                recv_comm_id,
+                bufs: ranges,
+                flushed: Vec::new(),
+            }))?;
+        count_live_requests(&self.state, &self.socket_request_map);
+        if recv_comm.unsent(last) {
+            task_state.fail(closed_err());
+        } else if !sent {
+            task_state.fail(BaguaNetError::remote(format!(
+                "recv comm {} is gone",
+                recv_comm_id
+            )));
+        }
+
+        Ok(id)
+    }
+
+    /// Under `span`, of a request being posted.
+    fn chunk_trace_parent(&self, span: &RequestSpan) -> Option<opentelemetry::Context> {
+        self.trace_chunks.then(|| {
+            self.trace_span_context
+                .with_remote_span_context(span.span_context().clone())
+        })
+    }
+
+    /// Of `isend` and `isend_v`, once the buffers are checked.
+    fn post_send(
+        &mut self,
+        send_comm_id: SocketSendCommID,
+        data: SendData,
+    ) -> Result<SocketRequestID, BaguaNetError> {
+        self.check_running()?;
+        let mut span = RequestSpan::start(self.span_sampler, &self.trace_span_context, || {
+            format!("isend-{}", send_comm_id)
+        });
+        let trace_parent = self.chunk_trace_parent(&span);
+        let send_comm = self
+            .send_comm_map
+            .get_mut(send_comm_id)
+            .ok_or_else(|| slab::unknown("send comm", send_comm_id))?;
+        if let ConnectState::Failed(err) = &*utils::lock(&send_comm.connect_state) {
+            return Err(err.clone());
+        }
+        if let Some(err) = &*utils::lock(&send_comm.health) {
+            return Err(err.clone());
+        }
+        if send_comm.inflight.load(Ordering::Relaxed) >= self.max_inflight {
+            return Err(BaguaNetError::Busy);
+        }
+        let len = data.len();
+        check_fixed_size(&send_comm.fixed_size, len)?;
+        while let Some(state) = send_comm.copies.front() {
+            let (done, _) = state.progress();
+            if let Some(err) = state.err() {
+                return Err(err);
+            }
+            if !done {
+                break;
+            }
+            send_comm.copies.pop_front();
+        }
+        // Before the task is queued, which it cannot be taken back from.
+        let id = self.socket_request_map.next_id()?;
+        let tally = send_comm.tally.clone();
+        let request_state = RequestState::new(1)
+            .traced(trace_parent.clone())
+            .tallied(tally.clone());
+        // Done once copied, the copy is sent under a state of its own, which
+        // counts the bytes sent.
+        let (data, request_state, task_state, copy) = match self.copy_threshold {
+            Some(threshold) if len <= threshold && len > 0 => {
+                let copy = SendCopy::lease(len, self.state.send_copy_bytes.clone());
+                copy.fill(data.buffers(), self.staging.as_ref())?;
+                let data = SendData::Contiguous(copy.data().into());
+                let state = RequestState::new(1).traced(trace_parent).counted(tally);
+                (
+                    data,
+                    Arc::new(request_state),
+                    Arc::new(state),
+                    Some(Arc::new(copy)),
+                )
+            }
+            _ => {
+                let request_state = Arc::new(request_state.counted(tally));
+                (data, request_state.clone(), request_state, None)
+            }
+        };
+        let copied = copy.is_some();
+
+        // Messages of a fixed size never go inline.
+        let lane = match len <= send_comm.priority_threshold
+            && send_comm.fixed_size.lock().unwrap().is_none()
+        {
+            true => PRIORITY_LANE,
+            false => NORMAL_LANE,
+        };
+        // Messages posted while the comm is still connecting are queued. If
+        // connecting fails, the connecting thread fails the queued ones, this
+        // catches those posted while it was giving up.
+        let task = (send_comm.nposted, (data, task_state.clone(), copy));
+        // Before the driver can take it.
+        let depth = &send_comm.queue_depth.messages;
+        depth.fetch_add(1, Ordering::Relaxed);
+        let sent = match send_comm.msg_senders[lane].try_send(task) {
+            Ok(()) => true,
+            Err(err) => {
+                depth.fetch_sub(1, Ordering::Relaxed);
+                match err {
+                    flume::TrySendError::Full(_) => return Err(BaguaNetError::Busy),
+                    flume::TrySendError::Disconnected(_) => false,
+                }
+            }
+        };
+        send_comm.nposted += 1;
+        send_comm.posted = true;
+        send_comm.waker.wake();
+
+        span.set_attribute(KeyValue::new("id", id as i64));
+        span.set_attribute(KeyValue::new("nbytes", len as i64));
+        if len == 0 {
+            span.set_attribute(KeyValue::new("zero_byte", true));
+        }
+
+        self.socket_request_map
+            .insert(SocketRequest::SendRequest(SocketSendRequest {
+                state: request_state.clone(),
+                trace_span: span,
+                send_comm_id,
+                posted_at: Instant::now(),
+                health: send_comm.health.clone(),
+                inflight: Some(Inflight::count(
+                    &send_comm.inflight,
+                    &self.state.inflight_requests,
+                )),
+            }))?;
+        count_live_requests(&self.state, &self.socket_request_map);
+        if let ConnectState::Failed(err) = &*utils::lock(&send_comm.connect_state) {
+            request_state.fail(err.clone());
+        } else if !sent {
+            request_state.fail(BaguaNetError::remote(format!(
+                "send comm {} is gone",
+                send_comm_id
+            )));
+        } else if copied {
+            self.state.send_copies.fetch_add(1, Ordering::Relaxed);
+            send_comm.copies.push_back(task_state);
+            request_state.complete_subtask(len);
+        }
+
+        Ok(id)
+    }
+
+    fn check_buffer(&self, mr: Option<MrHandle>, data: *const [u8]) -> Result<(), BaguaNetError> {
+        if !self.check_buffers {
+            return Ok(());
+        }
+        match &self.staging {
+            Some(staging) if staging.is_device(data) => Ok(()),
+            _ => self.mr_registry.lock().unwrap().check(mr, data),
+        }
+    }
+
+    /// The event loop of a new comm on `numa_node`, on a thread of the node if
+    /// there is one.
+    fn waker_on(&self, numa_node: Option<usize>) -> DriverWaker {
+        self.event_loops.waker_on(&numa_node_cpus(numa_node))
+    }
+
+    /// Of the comm of `request`, unless it was closed.
+    fn driver_of(&self, request: &SocketRequest) -> Option<DriverWaker> {
+        match request {
+            SocketRequest::SendRequest(send_req) => self
+                .send_comm_map
+                .get(send_req.send_comm_id)
+                .map(|send_comm| send_comm.waker.clone()),
+            SocketRequest::RecvRequest(recv_req) => self
+                .recv_comm_map
+                .get(recv_req.recv_comm_id)
+                .map(|recv_comm| recv_comm.waker.clone()),
+        }
+    }
+
+    fn socket_dev(&self, dev_id: usize) -> Result<&NCCLSocketDev, BaguaNetError> {
+        self.socket_devs.get(dev_id).ok_or_else(|| {
+            BaguaNetError::InvalidArgument(format!(
+                "invalid dev_id {}, there are {} devices",
+                dev_id,
+                self.socket_devs.len()
+            ))
         })
     }
 
-    fn listen(
-        &mut self,
-        dev_id: usize,
-    ) -> Result<(SocketHandle, SocketListenCommID), BaguaNetError> {
-        let socket_dev = &self.socket_devs[dev_id];
-        let addr = match socket_dev.addr.clone() {
-            SockAddr::Inet(inet_addr) => inet_addr,
-            others => {
-                return Err(BaguaNetError::InnerError(format!(
-                    "Got invalid socket address, which is {:?}",
-                    others
-                )))
+    /// Connect options for the streams of a send comm on device `dev_id` to
+    /// the listener of `socket_handle`.
+    fn connect_config_of(
+        &self,
+        dev_id: usize,
+        socket_handle: &SocketHandle,
+    ) -> Result<ConnectConfig, BaguaNetError> {
+        let mut config = self.connect_config.bind_to(&self.socket_dev(dev_id)?.addr);
+        if config.uds {
+            config.unix_peer = connection::same_host_unix_name(socket_handle, &self.socket_devs);
+        }
+        Ok(config)
+    }
+
+    /// Starts a recv comm on the streams of `group`, accepted or revived on
+    /// `listen_comm_id`.
+    fn spawn_recv_comm(
+        &mut self,
+        listen_comm_id: SocketListenCommID,
+        group: StreamGroup,
+    ) -> Result<SocketRecvCommID, BaguaNetError> {
+        let id = self.recv_comm_map.next_id()?;
+        let listen_comm = self
+            .listen_comm_map
+            .get(listen_comm_id)
+            .ok_or_else(|| slab::unknown("listen comm", listen_comm_id))?;
+        let comm_uuid = group.comm_uuid;
+        let (finished, driver_finished) = flume::bounded(0);
+        let mut streams = Vec::new();
+        for (stream_id, stream) in group.data_streams.into_iter().enumerate() {
+            stream.set_nodelay(true).unwrap();
+            stream.set_nonblocking(true).unwrap();
+
+            let reconnect = match (&listen_comm.reconnect_acceptor, &stream) {
+                (Some(reconnect_acceptor), stream) if !stream.is_unix() => Some((
+                    reconnect_acceptor.route(comm_uuid, stream_id),
+                    self.reconnect_config.timeout,
+                )),
+                _ => None,
+            };
+            streams.push(RecvStream::new(stream, reconnect, finished.clone()));
+        }
+        let growth = match &listen_comm.reconnect_acceptor {
+            Some(acceptor)
+                if self.adaptive_streams.is_some()
+                    && streams.iter().all(|stream| stream.reconnect.is_some())
+                    && streams.len() < self.accept_config.max_nstreams =>
+            {
+                Some(GrowRoute {
+                    acceptor: acceptor.clone(),
+                    route: acceptor.route(comm_uuid, streams.len()),
+                    timeout: self.reconnect_config.timeout,
+                    max_nstreams: self.accept_config.max_nstreams,
+                    adopted: None,
+                })
+            }
+            _ => None,
+        };
+        let ctrl_stream = group.ctrl_stream;
+        let capabilities = group.capabilities;
+        let peer = ctrl_stream.peer_addr();
+        let peer_nbytes_gauge = self.state.irecv_peer_nbytes.of(peer.clone(), id);
+        let dev_id = listen_comm.dev_id;
+        let device = self
+            .state
+            .open_on_device(dev_id, &self.socket_dev(dev_id)?.interface_name);
+        connection_event!(
+            INFO,
+            listen_comm = listen_comm_id,
+            recv_comm = id,
+            comm_uuid = %comm_uuid,
+            peer = %peer,
+            nstreams = streams.len(),
+            "recv comm accepted"
+        );
+
+        ctrl_stream.set_nodelay(true).unwrap();
+        ctrl_stream.set_nonblocking(true).unwrap();
+
+        let (msg_sender, msg_receiver) = flume::bounded::<RecvTask>(self.queue_capacity);
+        let peer_closed = Arc::new(Mutex::new(None));
+        let fixed_size = Arc::new(Mutex::new(None));
+        let waker = self.waker_on(listen_comm.numa_node);
+        log_comm_placement("recv", comm_uuid, listen_comm.numa_node, &waker);
+        waker.start(RecvDriver {
+            id,
+            comm_uuid,
+            msg_receiver: Some(msg_receiver),
+            tasks: VecDeque::new(),
+            ctrl: DrivenStream::new(ctrl_stream),
+            ctrl_buf: [0u8; 8],
+            ctrl_pos: 0,
+            ctrl_next: None,
+            ctrl_inline: None,
+            streams,
+            growth,
+            downstream_id: 0,
+            capabilities,
+            placements: (capabilities & CommHandshake::WORK_STEALING != 0).then(HashMap::new),
+            peer_closed: peer_closed.clone(),
+            reusable: true,
+            started: false,
+            min_chunksize: self.min_chunksize,
+            chunk_bytes: self.chunk_bytes,
+            queue_capacity: self.queue_capacity,
+            seq_check: self.accept_config.seq_check,
+            next_message: 0,
+            crc: self.accept_config.crc,
+            ctrl_crc: None,
+            compression: self
+                .compression
+                .clone()
+                .filter(|_| self.accept_config.compression),
+            inline_threshold: self.accept_config.inline_threshold,
+            fixed_size: fixed_size.clone(),
+            fixed: FixedSize::Unknown,
+            fixed_ack: [0u8; 8],
+            fixed_ack_pos: 8,
+            fixed_pending: VecDeque::new(),
+            fixed_sent: None,
+            queued: 0,
+            announced: VecDeque::new(),
+            cancelled_ahead: Vec::new(),
+            quickack: self.accept_config.quickack,
+            staging: self.staging.clone(),
+            metrics: self.state.clone(),
+            peer_nbytes_gauge,
+            stream_fds: RecordedFds(self.state.open_tcp_streams(CommKind::Recv, id)),
+            cache: self.recv_comm_cache.clone(),
+            listen_addr: listen_comm.addr,
+            finished,
+        });
+        self.recv_comm_map.insert(SocketRecvComm {
+            waker,
+            msg_sender,
+            peer_closed,
+            fixed_size,
+            nposted: 0,
+            health: Default::default(),
+            inflight: Default::default(),
+            peer,
+            opened_at: Instant::now(),
+            tally: Arc::new(Tally::of_comm(&device.0.received)),
+            dev_id,
+            _device: device,
+            finished: driver_finished,
+        })?;
+
+        Ok(id)
+    }
+
+    /// After `wait()` timed out: the request may have completed since, and
+    /// `test()` then removes it.
+    fn timed_out(&mut self, request_id: SocketRequestID) -> Result<usize, BaguaNetError> {
+        match self.test(request_id)? {
+            (true, nbytes) => Ok(nbytes),
+            (false, _) => Err(BaguaNetError::Timeout),
+        }
+    }
+}
+
+/// Of `Net::set_fixed_message_size` on a comm.
+fn fix_size(
+    fixed_size: &FixedMessageSize,
+    posted: bool,
+    crc: bool,
+    nbytes: usize,
+) -> Result<(), BaguaNetError> {
+    if posted {
+        return Err(BaguaNetError::InvalidArgument(
+            "the message size is fixed before the first message".to_owned(),
+        ));
+    }
+    if crc {
+        return Err(BaguaNetError::InvalidArgument(
+            "messages of a fixed size go without their BAGUA_NET_CRC".to_owned(),
+        ));
+    }
+    if connection::fixed_nbytes(connection::FIXED_NBYTES | nbytes) != Some(nbytes) {
+        return Err(BaguaNetError::InvalidArgument(format!(
+            "cannot fix the message size to {} bytes",
+            nbytes
+        )));
+    }
+    *fixed_size.lock().unwrap() = Some(nbytes);
+    Ok(())
+}
+
+/// Of a message of `nbytes` posted on a comm.
+fn check_fixed_size(fixed_size: &FixedMessageSize, nbytes: usize) -> Result<(), BaguaNetError> {
+    match *fixed_size.lock().unwrap() {
+        Some(fixed) if fixed != nbytes => Err(BaguaNetError::InvalidArgument(format!(
+            "the comm only takes messages of {} bytes, not {}",
+            fixed, nbytes
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn address_range(data: *const [u8]) -> Range<usize> {
+    let start = data as *const u8 as usize;
+    start..start + data.len()
+}
+
+/// How a send stream is replaced when it is reset.
+struct Reconnect {
+    socket_handle: SocketHandle,
+    handshake: StreamHandshake,
+    connect_config: ConnectConfig,
+    config: ReconnectConfig,
+    window: ReplayWindow,
+    wait_mode: WaitMode,
+}
+
+impl Reconnect {
+    /// Opens a replacement, and replays what did not reach the receiver.
+    fn replace(&self) -> Result<Stream, BaguaNetError> {
+        let mut last_err = None;
+        for attempt in 0..self.config.retries {
+            if attempt > 0 {
+                std::thread::sleep(self.connect_config.delay(attempt - 1));
+            }
+            let (mut stream, received) = match connection::reconnect_stream(
+                &self.socket_handle,
+                self.handshake,
+                &self.connect_config,
+                self.config.timeout,
+            ) {
+                Ok(replacement) => replacement,
+                Err(err) => {
+                    tracing::warn!("replace {:?} failed, err={:?}", self.handshake, err);
+                    last_err = Some(err);
+                    continue;
+                }
+            };
+            let missed = self.window.since(received).ok_or_else(|| {
+                BaguaNetError::remote(format!(
+                    "cannot replay {:?}, the receiver got {} of {} bytes and only the last {} are kept",
+                    self.handshake,
+                    received,
+                    self.window.written(),
+                    self.window.capacity()
+                ))
+            })?;
+
+            stream.set_nodelay(true).unwrap();
+            stream.set_nonblocking(true).unwrap();
+            match utils::nonblocking_write_all_vectored(&mut stream, &missed, self.wait_mode) {
+                IoOutcome::Done => {
+                    tracing::info!(
+                        "replaced {:?}, replayed {} bytes",
+                        self.handshake,
+                        missed[0].len() + missed[1].len()
+                    );
+                    return Ok(stream);
+                }
+                // Reset again, the next replacement may last.
+                IoOutcome::PeerClosed => {
+                    tracing::warn!(
+                        "replacement of {:?} was closed by the receiver",
+                        self.handshake
+                    );
+                    last_err = Some(BaguaNetError::remote(format!(
+                        "replacement of {:?} was closed by the receiver",
+                        self.handshake
+                    )));
+                }
+                IoOutcome::Fatal(err) => {
+                    tracing::warn!("replace {:?} failed, err={:?}", self.handshake, err);
+                    return Err(BaguaNetError::system_io(
+                        format!("replace {:?}", self.handshake),
+                        err,
+                    ));
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            BaguaNetError::InnerError("BAGUA_NET_RECONNECT_RETRIES is 0".to_owned())
+        }))
+    }
+}
+
+/// A stream of a comm on an event loop, and whether it may be ready.
+struct DrivenStream {
+    stream: Stream,
+    registered: bool,
+    readable: bool,
+    writable: bool,
+    /// In the driver's `RecordedFds`.
+    recorded: bool,
+}
+
+impl DrivenStream {
+    fn new(stream: Stream) -> DrivenStream {
+        // Until it would block.
+        DrivenStream {
+            stream,
+            registered: false,
+            readable: true,
+            writable: true,
+            recorded: false,
+        }
+    }
+
+    fn register(&mut self, index: usize, sources: &Sources) -> io::Result<()> {
+        if !self.registered {
+            sources.register(index, self.stream.as_raw_fd())?;
+            self.registered = true;
+        }
+        Ok(())
+    }
+
+    fn deregister(&mut self, sources: &Sources) {
+        if self.registered {
+            if let Err(err) = sources.deregister(self.stream.as_raw_fd()) {
+                tracing::debug!("deregister {} failed, err={:?}", self.stream.peer(), err);
+            }
+            self.registered = false;
+        }
+    }
+
+    fn ready(&mut self, readable: bool, writable: bool) {
+        self.readable |= readable;
+        self.writable |= writable;
+    }
+
+    /// Registers `stream` in its place, under the same index.
+    fn replace(&mut self, index: usize, stream: Stream, sources: &Sources) -> io::Result<()> {
+        self.deregister(sources);
+        *self = DrivenStream::new(stream);
+        self.register(index, sources)
+    }
+}
+
+/// A chunk of a message, queued on a data stream.
+struct Chunk<T> {
+    data: T,
+    pos: usize,
+    state: Arc<RequestState>,
+    /// Of a device buffer, `data` is then its host copy.
+    staged: Option<Staged>,
+    /// Of the next chunk, which `data` is written from or read into. The
+    /// receiver expects the one it holds.
+    header: Option<(ChunkHeader, Box<[u8; ChunkHeader::NBYTES]>)>,
+    /// Of the message, and the index of the chunk in it.
+    crc: Option<(Arc<Mutex<MessageCrc>>, usize)>,
+    frame: Option<Frame>,
+    /// What a send chunk is in, if `isend` copied its message.
+    #[allow(dead_code)]
+    copy: Option<Arc<SendCopy>>,
+    /// Of a cancelled message, what the rest of the chunk is written from or
+    /// read into in place of its buffer.
+    discard: Option<PooledBuffer>,
+    /// Of the buffer, while `data` is written from or read into it in place.
+    loan: Option<BufferLoan>,
+    /// Of a send chunk, set once the receiver was told its message was
+    /// cancelled, before which the zeros in its place wait.
+    told: Option<Arc<AtomicBool>>,
+    /// Of a part of the message, see `chunk_span`.
+    span: Option<opentelemetry::global::BoxedSpan>,
+}
+
+impl<T> Chunk<T> {
+    fn new(data: T, state: Arc<RequestState>) -> Chunk<T> {
+        Chunk {
+            data,
+            pos: 0,
+            state,
+            staged: None,
+            header: None,
+            crc: None,
+            frame: None,
+            copy: None,
+            discard: None,
+            loan: None,
+            told: None,
+            span: None,
+        }
+    }
+
+    /// By the data stream `index` that wrote or read it.
+    fn end_span(&mut self, index: usize) {
+        if let Some(mut span) = self.span.take() {
+            span.set_attribute(KeyValue::new("stream", index as i64));
+            span.end();
+        }
+    }
+
+    /// Whether `data` is the buffer of the message, rather than its host
+    /// copy, frame or header.
+    fn in_place(&self) -> bool {
+        self.discard.is_none()
+            && self.header.is_none()
+            && self.frame.is_none()
+            && self.staged.is_none()
+            && self.copy.is_none()
+    }
+
+    /// Whether it was lent the buffer, which its message gave up on since.
+    fn gave_up(&self) -> bool {
+        self.loan.is_some() && self.state.gave_up()
+    }
+}
+
+impl<T: AsRef<[u8]>> Chunk<T> {
+    /// Before `data` is written from or read into, whether it may be in
+    /// place: it is lent the buffer, unless its message gave up on it.
+    fn lend(&mut self) -> bool {
+        if self.data.as_ref().is_empty() || !self.in_place() {
+            return false;
+        }
+        if self.loan.is_none() {
+            self.loan = self.state.lend();
+        }
+        match &self.loan {
+            Some(_) if self.state.gave_up() => {
+                self.loan = None;
+                false
+            }
+            loan => loan.is_some(),
+        }
+    }
+
+    /// Of the message, none of a header.
+    fn nbytes(&self) -> usize {
+        match (&self.header, &self.frame) {
+            (Some(_), _) | (_, Some(Frame::Header(_))) => 0,
+            (_, Some(Frame::Send { nbytes, .. })) => *nbytes,
+            (_, Some(Frame::Recv { dst, .. })) => dst.len(),
+            (None, None) => self.data.as_ref().len(),
+        }
+    }
+}
+
+impl Chunk<&'static [u8]> {
+    /// Unless it is lent the buffer, writes the rest as zeros.
+    fn lend_or_discard(&mut self) {
+        if self.lend() || self.data.is_empty() || !self.in_place() {
+            return;
+        }
+        let discard = buffer_pool::POOL.lease(self.data.len());
+        let data = discard.slice(self.data.len());
+        data.fill(0);
+        self.data = data;
+        self.discard = Some(discard);
+    }
+
+    fn header(header: ChunkHeader, state: Arc<RequestState>) -> Chunk<&'static [u8]> {
+        let bytes = Box::new(header.to_bytes());
+        // The box stays put as long as the chunk.
+        let data = unsafe { std::slice::from_raw_parts(bytes.as_ptr(), bytes.len()) };
+        Chunk {
+            header: Some((header, bytes)),
+            ..Chunk::new(data, state)
+        }
+    }
+}
+
+impl Chunk<&'static mut [u8]> {
+    /// Unless it is lent the buffer, reads the rest to drop it.
+    fn lend_or_discard(&mut self) {
+        if self.lend() || self.data.is_empty() || !self.in_place() {
+            return;
+        }
+        let discard = buffer_pool::POOL.lease(self.data.len());
+        self.data = discard.slice(self.data.len());
+        self.discard = Some(discard);
+    }
+
+    fn header(expected: ChunkHeader, state: Arc<RequestState>) -> Chunk<&'static mut [u8]> {
+        let mut bytes = Box::new([0u8; ChunkHeader::NBYTES]);
+        let data = unsafe { std::slice::from_raw_parts_mut(bytes.as_mut_ptr(), bytes.len()) };
+        Chunk {
+            header: Some((expected, bytes)),
+            ..Chunk::new(data, state)
+        }
+    }
+
+    /// Of the chunk queued after it.
+    fn frame_header(state: Arc<RequestState>) -> Chunk<&'static mut [u8]> {
+        let mut bytes = Box::new([0u8; FrameHeader::NBYTES]);
+        let data = unsafe { std::slice::from_raw_parts_mut(bytes.as_mut_ptr(), bytes.len()) };
+        Chunk {
+            frame: Some(Frame::Header(bytes)),
+            ..Chunk::new(data, state)
+        }
+    }
+
+    /// Unless it is a header other than the expected one.
+    fn check_header(&self) -> Result<(), BaguaNetError> {
+        let expected = match &self.header {
+            Some((expected, _)) => expected,
+            None => return Ok(()),
+        };
+        let mut bytes = [0u8; ChunkHeader::NBYTES];
+        bytes.copy_from_slice(self.data);
+        let header = ChunkHeader::from_bytes(&bytes);
+        if header != *expected {
+            return Err(BaguaNetError::remote(format!(
+                "chunk header mismatch, expected {:?}, got {:?}, the data streams are out of sync",
+                expected, header
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Of a data stream chunk of a comm that compresses them, see `compression`.
+enum Frame {
+    /// Read ahead of the chunk, into the box `data` is in.
+    Header(#[allow(dead_code)] Box<[u8; FrameHeader::NBYTES]>),
+    /// The frame of a send chunk, none until its chunk is compressed or
+    /// copied into it.
+    Send { buf: SendFrame, nbytes: usize },
+    /// A compressed receive chunk, read into `buf`, that `data` is in, to be
+    /// decompressed into `dst`.
+    Recv {
+        #[allow(dead_code)]
+        buf: PooledBuffer,
+        dst: &'static mut [u8],
+    },
+}
+
+/// With its length.
+type SendFrame = Arc<Mutex<Option<(PooledBuffer, usize)>>>;
+
+/// Writes a send chunk to its frame, on the compression threads unless it is
+/// too small to be worth compressing.
+struct Compress {
+    compression: Arc<Compression>,
+    frame: SendFrame,
+    waker: DriverWaker,
+    /// What the chunk is in, if `isend` copied its message.
+    copy: Option<Arc<SendCopy>>,
+    /// Of the buffer the chunk is in otherwise, read on the compression
+    /// threads.
+    loan: Option<BufferLoan>,
+}
+
+impl Compress {
+    fn new(compression: &Arc<Compression>, waker: DriverWaker) -> Compress {
+        Compress {
+            compression: compression.clone(),
+            frame: Default::default(),
+            waker,
+            copy: None,
+            loan: None,
+        }
+    }
+
+    fn frame(&self, nbytes: usize) -> Frame {
+        Frame::Send {
+            buf: self.frame.clone(),
+            nbytes,
+        }
+    }
+
+    /// Once `data` is on the host.
+    fn run(self, data: &'static [u8]) {
+        let Compress {
+            compression,
+            frame,
+            waker,
+            copy,
+            loan,
+        } = self;
+        if data.len() < compression.threshold() {
+            return write_send_frame(data, &frame, false);
+        }
+        compression.spawn(move || {
+            write_send_frame(data, &frame, true);
+            // Read until the frame is written, whatever became of the chunk.
+            drop(copy);
+            drop(loan);
+            waker.wake();
+        });
+    }
+}
+
+fn write_send_frame(data: &[u8], frame: &SendFrame, compress: bool) {
+    let buf = buffer_pool::POOL.lease(compression::max_frame_len(data.len()));
+    let len = compression::write_frame(data, buf.slice(buf.capacity()), compress);
+    *frame.lock().unwrap() = Some((buf, len));
+}
+
+/// On the compression threads, then it completes like any other.
+fn decompress_recv_chunk(
+    compression: &Compression,
+    mut chunk: Chunk<&'static mut [u8]>,
+    finished: flume::Sender<()>,
+) {
+    compression.spawn(move || {
+        if let Some(Frame::Recv { buf, dst }) = chunk.frame.take() {
+            // Not into the buffer of a message that gave up on it.
+            match chunk.state.lend() {
+                Some(loan) => {
+                    if let Err(err) = compression::decompress(chunk.data, &mut *dst) {
+                        chunk.state.fail(err);
+                    }
+                    chunk.data = dst;
+                    chunk.loan = Some(loan);
+                }
+                None => chunk.discard = Some(buf),
+            }
+        }
+        record_recv_crc(&chunk);
+        complete_recv_chunk(chunk, false, &finished);
+    });
+}
+
+/// The CRC32C of a message, from those of its chunks, see `BAGUA_NET_CRC`.
+/// The sender computes them as it stages its chunks, the receiver as it
+/// reads them.
+struct MessageCrc {
+    /// With their lengths.
+    chunks: Vec<Option<(u32, usize)>>,
+    /// The sender's, once the receiver read it off the master stream.
+    expected: Option<u32>,
+}
+
+impl MessageCrc {
+    fn new(nchunks: usize) -> MessageCrc {
+        MessageCrc {
+            chunks: vec![None; nchunks],
+            expected: None,
+        }
+    }
+
+    /// Of a message that is not staged.
+    fn of(data: &[u8]) -> MessageCrc {
+        let mut crc = MessageCrc::new(1);
+        crc.record(0, data);
+        crc
+    }
+
+    fn record(&mut self, index: usize, data: &[u8]) {
+        self.chunks[index] = Some((crc32c::crc32c(data), data.len()));
+    }
+
+    /// Once every chunk is in.
+    fn value(&self) -> Option<u32> {
+        let mut value = 0;
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            let (crc, len) = (*chunk)?;
+            value = match index {
+                0 => crc,
+                _ => crc32c::crc32c_combine(value, crc, len),
+            };
+        }
+        Some(value)
+    }
+
+    /// Once both the chunks and the sender's CRC are in, fails the message
+    /// unless they match, and completes its subtask.
+    fn verify(&self, state: &RequestState) {
+        let (value, expected) = match (self.value(), self.expected) {
+            (Some(value), Some(expected)) => (value, expected),
+            _ => return,
+        };
+        if value != expected {
+            state.fail(BaguaNetError::Corruption(format!(
+                "crc32c of the {} bytes received is {:#010x}, the sender's {:#010x}",
+                self.chunks
+                    .iter()
+                    .flatten()
+                    .map(|(_, len)| len)
+                    .sum::<usize>(),
+                value,
+                expected
+            )));
+        }
+        state.complete_subtask(0);
+    }
+}
+
+/// Of a received chunk, before it completes.
+fn record_recv_crc(chunk: &Chunk<&'static mut [u8]>) {
+    if let Some((crc, index)) = &chunk.crc {
+        let mut crc = crc.lock().unwrap();
+        crc.record(*index, chunk.data);
+        crc.verify(&chunk.state);
+    }
+}
+
+struct Staged {
+    bounce: Bounce,
+    /// Where the chunk is on the device.
+    device: usize,
+    /// Once a send chunk was copied to the host.
+    ready: Arc<AtomicBool>,
+}
+
+/// The pooled buffer `isend` copied a message into, or the driver gathered
+/// its slices into, shared by its chunks.
+pub struct SendCopy {
+    buf: PooledBuffer,
+    len: usize,
+    /// `AppState::send_copy_bytes`.
+    in_flight: Arc<AtomicU64>,
+}
+
+impl SendCopy {
+    /// Of `len` bytes, not filled yet.
+    fn lease(len: usize, in_flight: Arc<AtomicU64>) -> SendCopy {
+        in_flight.fetch_add(len as u64, Ordering::Relaxed);
+        SendCopy {
+            buf: buffer_pool::POOL.lease(len),
+            len,
+            in_flight,
+        }
+    }
+
+    /// With `buffers` one after the other, from the device those that are on
+    /// it.
+    fn fill(
+        &self,
+        buffers: &[SendBuffer],
+        staging: Option<&Arc<Staging>>,
+    ) -> Result<(), BaguaNetError> {
+        let mut offset = 0;
+        for buf in buffers {
+            let slice = buf.slice();
+            match staging.filter(|staging| staging.is_device(slice)) {
+                Some(staging) => staging.copy_now(CopyRange {
+                    dst: self.buf.ptr() + offset,
+                    src: slice.as_ptr() as usize,
+                    len: slice.len(),
+                })?,
+                None => {
+                    self.buf.slice(self.len)[offset..offset + slice.len()].copy_from_slice(slice)
+                }
+            }
+            offset += slice.len();
+        }
+        Ok(())
+    }
+
+    /// Valid as long as the copy.
+    fn data(&self) -> &'static [u8] {
+        self.buf.slice(self.len)
+    }
+}
+
+impl Drop for SendCopy {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(self.len as u64, Ordering::Relaxed);
+    }
+}
+
+/// The chunk is written once the copy thread copied it to the host, and
+/// computed its CRC32C if the message has one. It is compressed after.
+fn stage_send_chunk(
+    staging: &Arc<Staging>,
+    bucket: &'static [u8],
+    state: Arc<RequestState>,
+    crc: Option<(Arc<Mutex<MessageCrc>>, usize)>,
+    compress: Option<Compress>,
+    waker: DriverWaker,
+) -> Chunk<&'static [u8]> {
+    let bounce = staging.lease(bucket.len());
+    let data: &'static [u8] = bounce.slice(bucket.len());
+    let ready = Arc::new(AtomicBool::new(false));
+    let chunk = Chunk {
+        staged: Some(Staged {
+            bounce,
+            device: bucket.as_ptr() as usize,
+            ready: ready.clone(),
+        }),
+        ..Chunk::new(data, state.clone())
+    };
+    let range = CopyRange {
+        dst: chunk.staged.as_ref().unwrap().bounce.ptr(),
+        src: bucket.as_ptr() as usize,
+        len: bucket.len(),
+    };
+    let loan = state.lend();
+    let copied = move |ret: Result<(), BaguaNetError>| {
+        if let Err(err) = ret {
+            state.fail(err);
+        }
+        if let Some((crc, index)) = crc {
+            crc.lock().unwrap().record(index, data);
+        }
+        if let Some(compress) = compress {
+            compress.run(data);
+        }
+        ready.store(true, Ordering::Release);
+        waker.wake();
+    };
+    match loan {
+        // Read off the device while it is lent the buffer.
+        Some(loan) => staging.copy(range, move |ret| {
+            drop(loan);
+            copied(ret);
+        }),
+        // Given up on while it was taken, what goes is what the bounce
+        // buffer held.
+        None => copied(Ok(())),
+    }
+    chunk
+}
+
+/// The chunk is read into a host buffer, and copied to the device after.
+fn stage_recv_chunk(
+    staging: &Arc<Staging>,
+    bucket: &'static mut [u8],
+    state: Arc<RequestState>,
+) -> Chunk<&'static mut [u8]> {
+    let bounce = staging.lease(bucket.len());
+    let data = bounce.slice(bucket.len());
+    Chunk {
+        staged: Some(Staged {
+            bounce,
+            device: bucket.as_ptr() as usize,
+            ready: Arc::new(AtomicBool::new(true)),
+        }),
+        ..Chunk::new(data, state)
+    }
+}
+
+/// A received chunk is done once it is on the device, if it goes there.
+/// Inlined ones are small, those are copied right away. The copies hold
+/// `finished` of the driver, see `SocketRecvComm::finished`.
+fn complete_recv_chunk(chunk: Chunk<&'static mut [u8]>, now: bool, finished: &flume::Sender<()>) {
+    let nbytes = chunk.nbytes();
+    let Chunk { state, staged, .. } = chunk;
+    // Not onto the buffer of a message that gave up on it.
+    let (staged, loan) = match staged.map(|staged| (staged, state.lend())) {
+        Some((staged, Some(loan))) => (staged, loan),
+        _ => return state.complete_subtask(nbytes),
+    };
+    let range = CopyRange {
+        dst: staged.device,
+        src: staged.bounce.ptr(),
+        len: nbytes,
+    };
+    let copied = move |ret: Result<(), BaguaNetError>| {
+        drop(loan);
+        if let Err(err) = ret {
+            state.fail(err);
+        }
+        state.complete_subtask(nbytes);
+    };
+    if now {
+        copied(staged.bounce.staging().copy_now(range));
+    } else {
+        let bounce = staged.bounce;
+        let staging = bounce.staging().clone();
+        let finished = finished.clone();
+        staging.copy(range, move |ret| {
+            copied(ret);
+            drop(bounce);
+            drop(finished);
+        });
+    }
+}
+
+/// The chunks of a send comm whose data streams steal them, see
+/// `CommHandshake::WORK_STEALING`, each after its `ChunkHeader`. A queue of
+/// the driver, which drives every stream of the comm.
+type Injector = VecDeque<Vec<Chunk<&'static [u8]>>>;
+
+/// The chunks of a recv comm whose data streams steal them, queued by the
+/// message and index of their `ChunkHeader` until it is read.
+type Placements = HashMap<(u32, u32), Vec<Chunk<&'static mut [u8]>>>;
+
+/// Once a stream broke, every chunk on it fails the same way.
+fn fail_chunks<T>(chunks: &mut VecDeque<Chunk<T>>, err: &BaguaNetError) {
+    for chunk in chunks.drain(..) {
+        chunk.state.fail(err.clone());
+    }
+}
+
+/// The replacement for the send stream registered under an index.
+type Replaced = (usize, Reconnect, Result<Stream, BaguaNetError>);
+
+/// Replaces the reset streams of a send comm on threads of their own, as
+/// that blocks, and hands the replacements back to its driver.
+struct Replacer {
+    waker: DriverWaker,
+    replaced: flume::Sender<Replaced>,
+}
+
+impl Replacer {
+    fn spawn(&self, index: usize, reconnect: Reconnect) {
+        let (waker, replaced) = (self.waker.clone(), self.replaced.clone());
+        std::thread::spawn(move || {
+            let replacement = reconnect.replace();
+            if replaced.send((index, reconnect, replacement)).is_ok() {
+                waker.wake();
+            }
+        });
+    }
+}
+
+/// The throughput of a send comm over consecutive windows.
+struct GrowthWindow {
+    config: AdaptiveStreamsConfig,
+    /// In bytes per second.
+    link_speed: f64,
+    start: Instant,
+    sent: usize,
+    /// Whether the comm ran out of chunks to send.
+    idled: bool,
+}
+
+impl GrowthWindow {
+    fn new(config: AdaptiveStreamsConfig, link_speed: f64) -> GrowthWindow {
+        GrowthWindow {
+            config,
+            link_speed,
+            start: Instant::now(),
+            sent: 0,
+            idled: false,
+        }
+    }
+
+    /// After `sent` more bytes went out, the throughput of a window that
+    /// just ended if it was backlogged throughout and below saturation.
+    fn observe(&mut self, sent: usize, backlogged: bool) -> Option<f64> {
+        self.sent += sent;
+        self.idled |= !backlogged;
+        let elapsed = self.start.elapsed();
+        if elapsed < self.config.window {
+            return None;
+        }
+
+        let throughput = self.sent as f64 / elapsed.as_secs_f64();
+        let saturated = throughput >= self.config.saturation * self.link_speed;
+        let idled = self.idled;
+        self.start = Instant::now();
+        self.sent = 0;
+        self.idled = false;
+        (!idled && !saturated).then_some(throughput)
+    }
+}
+
+/// How evenly the data streams of a send comm write over consecutive
+/// windows, see `ImbalanceConfig`. A stream is measured by what it wrote
+/// over how long it was writing, so that a slow one still shows when the
+/// chunks go round-robin and the others wait on it.
+struct ImbalanceWindow {
+    config: ImbalanceConfig,
+    start: Instant,
+    /// Whether the comm ran out of chunks to send.
+    idled: bool,
+    /// Of the windows in a row over `config.ratio`.
+    streak: usize,
+}
+
+/// Of the data streams over a window.
+#[derive(Debug, PartialEq)]
+struct Imbalance {
+    /// Of the fastest to the slowest.
+    ratio: f64,
+    slowest: usize,
+    /// Over `ImbalanceConfig::ratio` for `windows` windows in a row, which
+    /// starts another streak.
+    persistent: bool,
+}
+
+impl ImbalanceWindow {
+    fn new(config: ImbalanceConfig) -> ImbalanceWindow {
+        ImbalanceWindow {
+            config,
+            start: Instant::now(),
+            idled: false,
+            streak: 0,
+        }
+    }
+
+    /// Whether the window ended, each time the comm is polled.
+    fn ended(&mut self, backlogged: bool) -> bool {
+        self.idled |= !backlogged;
+        self.start.elapsed() >= self.config.window
+    }
+
+    /// Starts the next window. With the bytes each data stream wrote
+    /// meanwhile and for how long, how they compare, unless the comm idled
+    /// or fewer than two of them wrote.
+    fn close(&mut self, windowed: &[(usize, Duration)]) -> Option<Imbalance> {
+        self.start = Instant::now();
+        if std::mem::take(&mut self.idled) {
+            return None;
+        }
+        let rates: Vec<(usize, f64)> = windowed
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, busy))| !busy.is_zero())
+            .map(|(index, &(nbytes, busy))| (index, nbytes as f64 / busy.as_secs_f64()))
+            .collect();
+        if rates.len() < 2 {
+            return None;
+        }
+
+        let fastest = rates.iter().map(|&(_, rate)| rate).fold(0., f64::max);
+        let &(slowest, rate) = rates.iter().min_by(|a, b| a.1.total_cmp(&b.1)).unwrap();
+        let ratio = fastest / rate;
+        self.streak = match ratio > self.config.ratio {
+            true => self.streak + 1,
+            false => 0,
+        };
+        let persistent = self.streak >= self.config.windows;
+        if persistent {
+            self.streak = 0;
+        }
+        Some(Imbalance {
+            ratio,
+            slowest,
+            persistent,
+        })
+    }
+}
+
+/// An opened data stream, or why it could not be.
+type Grown = Result<Stream, BaguaNetError>;
+
+/// Opens more data streams for a send comm that is backlogged but does not
+/// saturate the link, see `AdaptiveStreamsConfig`. They are accepted like
+/// replacements.
+struct StreamGrower {
+    window: GrowthWindow,
+    reconnect_handle: SocketHandle,
+    connect_config: ConnectConfig,
+    reconnect_config: ReconnectConfig,
+    /// See `StreamGroup::peer_rcvbuf`.
+    peer_rcvbuf: usize,
+    wait_mode: WaitMode,
+    /// The stream being opened.
+    opening: Option<flume::Receiver<Grown>>,
+}
+
+impl StreamGrower {
+    fn open(&mut self, comm_uuid: Uuid, stream_id: usize, waker: DriverWaker) {
+        let (grown, opening) = flume::bounded(1);
+        let handshake = StreamHandshake {
+            comm_uuid,
+            stream_id,
+        };
+        let reconnect_handle = self.reconnect_handle.clone();
+        let connect_config = self.connect_config.clone();
+        let timeout = self.reconnect_config.timeout;
+        std::thread::spawn(move || {
+            let opened = connection::reconnect_stream(
+                &reconnect_handle,
+                handshake,
+                &connect_config,
+                timeout,
+            )
+            .and_then(|(stream, received)| match received {
+                0 => Ok(stream),
+                received => Err(BaguaNetError::remote(format!(
+                    "{:?} was opened as a replacement, {} bytes received",
+                    handshake, received
+                ))),
+            });
+            if grown.send(opened).is_ok() {
+                waker.wake();
+            }
+        });
+        self.opening = Some(opening);
+    }
+
+    fn reconnect(&self, handshake: StreamHandshake, stream: &Stream) -> Reconnect {
+        Reconnect {
+            socket_handle: self.reconnect_handle.clone(),
+            handshake,
+            connect_config: self.connect_config.clone(),
+            config: self.reconnect_config.clone(),
+            window: ReplayWindow::new(self.reconnect_config.window_bytes(
+                stream,
+                &self.connect_config.buffers,
+                self.peer_rcvbuf,
+            )),
+            wait_mode: self.wait_mode,
+        }
+    }
+}
+
+/// The `MSG_ZEROCOPY` sends of a data stream, with `BAGUA_NET_ZEROCOPY=1`.
+struct ZerocopySends {
+    /// Smaller writes copy, as does everything once the kernel did.
+    threshold: usize,
+    completions: zerocopy::Completions,
+    /// Written chunks, done once the sends before their number completed.
+    unacked: VecDeque<(u32, Chunk<&'static [u8]>)>,
+    /// Whether the front chunk went out in place, some of it.
+    front: bool,
+}
+
+impl ZerocopySends {
+    /// On TCP streams whose kernel supports it.
+    fn enable(stream: &Stream, threshold: usize) -> Option<ZerocopySends> {
+        if !matches!(stream, Stream::Tcp(_)) {
+            return None;
+        }
+        if let Err(err) = zerocopy::enable(stream.as_raw_fd()) {
+            tracing::info!("no zerocopy sends on {}, err={:?}", stream.peer(), err);
+            return None;
+        }
+        Some(ZerocopySends {
+            threshold,
+            completions: Default::default(),
+            unacked: VecDeque::new(),
+            front: false,
+        })
+    }
+
+    /// Writes more of `chunk` in place, up to `end`, None to copy it after
+    /// all.
+    fn send(
+        &mut self,
+        fd: RawFd,
+        chunk: &mut Chunk<&'static [u8]>,
+        end: usize,
+    ) -> Option<io::Result<bool>> {
+        match zerocopy::send(fd, &chunk.data[chunk.pos..end]) {
+            Ok(n) => {
+                self.completions.sent();
+                self.front = true;
+                chunk.pos += n;
+                Some(Ok(chunk.pos == chunk.data.len()))
+            }
+            // Out of the memory to pin pages with.
+            Err(err) if err.raw_os_error() == Some(libc::ENOBUFS) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    /// Completes the chunks whose sends completed.
+    fn reap(&mut self, stream: &Stream) -> io::Result<()> {
+        if self.unacked.is_empty() {
+            return Ok(());
+        }
+        let mut copied = false;
+        let completions = &mut self.completions;
+        zerocopy::reap(stream.as_raw_fd(), |first, last, was_copied| {
+            completions.completed(first, last);
+            copied |= was_copied;
+        })?;
+        if copied && self.threshold != usize::MAX {
+            tracing::info!(
+                "kernel copied zerocopy sends on {}, copy instead",
+                stream.peer()
+            );
+            self.threshold = usize::MAX;
+        }
+        while let Some((seq, _)) = self.unacked.front() {
+            if !self.completions.done_before(*seq) {
+                break;
+            }
+            let (_, chunk) = self.unacked.pop_front().unwrap();
+            chunk.state.complete_subtask(chunk.data.len());
+        }
+
+        Ok(())
+    }
+}
+
+/// A data stream of a send comm, with the chunks to write to it.
+struct SendStream {
+    io: DrivenStream,
+    /// Taken while the stream is replaced.
+    reconnect: Option<Reconnect>,
+    replacing: bool,
+    chunks: VecDeque<Chunk<&'static [u8]>>,
+    /// Of the front chunk, submitted to the io_uring of the event loop.
+    in_flight: bool,
+    /// Whether the submitted write is being cancelled, see `give_back`.
+    cancelling: bool,
+    /// Of the tokens of the comm, for the bytes in flight.
+    reserved: usize,
+    completion: Option<i32>,
+    err: Option<BaguaNetError>,
+    /// Since the front chunk was first written.
+    in_timer: Option<Instant>,
+    /// Of the data streams with its index, once it sent a chunk.
+    throughput: Option<Arc<StreamThroughput>>,
+    /// Of the chunks done since the driver last took it.
+    sent: usize,
+    /// Of the chunks done since the driver last took it, and how long they
+    /// were being written, see `ImbalanceWindow`.
+    windowed: (usize, Duration),
+    /// Replaced once idle, as it was slow, see `ImbalanceConfig::rehash`.
+    rehashing: bool,
+    /// `BAGUA_NET_ZEROCOPY_THRESHOLD`, if `BAGUA_NET_ZEROCOPY=1`.
+    zerocopy_threshold: Option<usize>,
+    zerocopy: Option<ZerocopySends>,
+}
+
+impl SendStream {
+    fn new(stream: Stream, reconnect: Option<Reconnect>) -> SendStream {
+        SendStream {
+            io: DrivenStream::new(stream),
+            reconnect,
+            replacing: false,
+            chunks: VecDeque::new(),
+            in_flight: false,
+            cancelling: false,
+            reserved: 0,
+            completion: None,
+            err: None,
+            in_timer: None,
+            throughput: None,
+            sent: 0,
+            windowed: Default::default(),
+            rehashing: false,
+            zerocopy_threshold: None,
+            zerocopy: None,
+        }
+    }
+
+    /// Sends chunks of at least `threshold` bytes in place from now on.
+    fn enable_zerocopy(&mut self, threshold: usize) {
+        self.zerocopy_threshold = Some(threshold);
+        self.zerocopy = ZerocopySends::enable(&self.io.stream, threshold);
+    }
+
+    fn is_idle(&self) -> bool {
+        self.chunks.is_empty()
+            && !self.replacing
+            && self
+                .zerocopy
+                .as_ref()
+                .is_none_or(|zerocopy| zerocopy.unacked.is_empty())
+    }
+
+    /// Writes the queued chunks, or those it steals from `injector`, until
+    /// the stream would block or `bucket` is empty, or until `yield_to` says
+    /// so after a chunk.
+    #[allow(clippy::too_many_arguments)]
+    fn progress(
+        &mut self,
+        index: usize,
+        sources: &Sources,
+        replacer: &Replacer,
+        bucket: &mut TokenBucket,
+        metrics: &AppState,
+        peer_nbytes_gauge: &Gauge,
+        mut injector: Option<&mut Injector>,
+        yield_to: &mut dyn FnMut() -> bool,
+    ) {
+        self.give_back(index, sources);
+        if self.replacing {
+            return;
+        }
+        if let Some(err) = &self.err {
+            fail_chunks(&mut self.chunks, err);
+            return;
+        }
+        if let Some(zerocopy) = &mut self.zerocopy {
+            if let Err(err) = zerocopy.reap(&self.io.stream) {
+                return self.broke(index, err, sources, replacer);
+            }
+        }
+        if self.rehashing && self.is_idle() {
+            return self.rehash(index, sources, replacer, metrics);
+        }
+        if self.chunks.is_empty() && self.io.readable {
+            self.check_idle(index, sources, replacer);
+        }
+        self.steal(&mut injector);
+        while let Some(ret) = self.write_front(index, sources, bucket) {
+            let done = match ret {
+                Ok(done) => done,
+                Err(err) => return self.broke(index, err, sources, replacer),
+            };
+            if !done {
+                continue;
+            }
+
+            let mut chunk = self.chunks.pop_front().unwrap();
+            chunk.end_span(index);
+            let busy = self.in_timer.take().unwrap().elapsed();
+            self.throughput
+                .get_or_insert_with(|| metrics.send_streams.of(index - 1))
+                .record(chunk.data.len(), busy);
+            metrics.isend_nbytes_gauge.record(chunk.data.len() as u64);
+            peer_nbytes_gauge.record(chunk.data.len() as u64);
+            self.sent += chunk.data.len();
+            self.windowed.0 += chunk.data.len();
+            self.windowed.1 += busy;
+            match &mut self.zerocopy {
+                Some(zerocopy) if zerocopy.front => {
+                    zerocopy.front = false;
+                    let seq = zerocopy.completions.next();
+                    zerocopy.unacked.push_back((seq, chunk));
+                }
+                _ => chunk.state.complete_subtask(chunk.nbytes()),
+            }
+            if yield_to() {
+                return;
+            }
+            self.steal(&mut injector);
+        }
+    }
+
+    /// Once the message of the front chunk gave up on its buffer, which the
+    /// chunk was lent, writes the rest as zeros. Cancels the write submitted
+    /// from it first.
+    fn give_back(&mut self, index: usize, sources: &Sources) {
+        match self.chunks.front_mut() {
+            Some(chunk) if chunk.gave_up() => {
+                if !self.in_flight {
+                    chunk.lend_or_discard();
+                } else if !self.cancelling {
+                    self.cancelling = sources.submit_cancel(index);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Takes the next chunk of the comm, after its header, once it wrote the
+    /// last one and can write more.
+    fn steal(&mut self, injector: &mut Option<&mut Injector>) {
+        if !self.chunks.is_empty()
+            || !self.io.writable
+            || self.replacing
+            || self.rehashing
+            || self.err.is_some()
+        {
+            return;
+        }
+        if let Some(chunks) = injector.as_mut().and_then(|injector| injector.pop_front()) {
+            self.chunks.extend(chunks);
+        }
+    }
+
+    /// Writes more of the front chunk, or submits that to the io_uring of
+    /// the event loop, whether the chunk is done. None once it has to wait
+    /// for the stream, the submitted write or the tokens of `bucket`.
+    fn write_front(
+        &mut self,
+        index: usize,
+        sources: &Sources,
+        bucket: &mut TokenBucket,
+    ) -> Option<io::Result<bool>> {
+        let chunk = self.chunks.front_mut()?;
+        if let Some(staged) = &chunk.staged {
+            if !staged.ready.load(Ordering::Acquire) {
+                return None;
+            }
+        }
+        if !self.in_flight {
+            chunk.lend_or_discard();
+            // The receiver hears of it first, see `SendDriver::tell_cancelled`.
+            let untold = chunk
+                .told
+                .as_ref()
+                .is_some_and(|told| !told.load(Ordering::Acquire));
+            if chunk.discard.is_some() && chunk.state.is_cancelled() && untold {
+                return None;
+            }
+        }
+        if let (Some(Frame::Send { buf, .. }), true) = (&chunk.frame, chunk.data.is_empty()) {
+            let frame = buf.lock().unwrap();
+            let (buf, len) = frame.as_ref()?;
+            // The frame stays put as long as the chunk.
+            chunk.data = buf.slice(*len);
+        }
+        self.in_timer.get_or_insert_with(Instant::now);
+        let start = chunk.pos;
+        let ret = match self.completion.take() {
+            Some(result) => {
+                self.in_flight = false;
+                self.cancelling = false;
+                bucket.refund(std::mem::take(&mut self.reserved));
+                event_loop::completion_result(result).and_then(|n| match n {
+                    0 => Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    )),
+                    n => {
+                        chunk.pos += n;
+                        Ok(chunk.pos == chunk.data.len())
+                    }
+                })
+            }
+            None if self.in_flight || !self.io.writable => return None,
+            None => {
+                let end = match bucket.allowance(chunk.data.len() - chunk.pos, Instant::now()) {
+                    Ok(allowed) => chunk.pos + allowed,
+                    Err(deadline) => {
+                        sources.wake_at(deadline);
+                        return None;
+                    }
+                };
+                let fd = self.io.stream.as_raw_fd();
+                // A posted message stays put until its request completes.
+                let zerocopied = match &mut self.zerocopy {
+                    Some(zerocopy) if end - chunk.pos >= zerocopy.threshold => {
+                        zerocopy.send(fd, chunk, end)
+                    }
+                    _ => None,
+                };
+                match zerocopied {
+                    Some(ret) => ret,
+                    // TLS records are written by rustls.
+                    None if !self.io.stream.is_tls()
+                        && unsafe {
+                            sources.submit_write(index, fd, &chunk.data[chunk.pos..end])
+                        } =>
+                    {
+                        self.in_flight = true;
+                        self.reserved = end - chunk.pos;
+                        bucket.consume(self.reserved);
+                        return None;
+                    }
+                    None => {
+                        let ret = utils::try_write_from(
+                            &mut self.io.stream,
+                            &chunk.data[..end],
+                            &mut chunk.pos,
+                        );
+                        if let Ok(false) = ret {
+                            self.io.writable = false;
+                        }
+                        ret.map(|_| chunk.pos == chunk.data.len())
+                    }
+                }
+            }
+        };
+        bucket.consume(chunk.pos - start);
+        if let Some(reconnect) = &mut self.reconnect {
+            reconnect.window.record(&chunk.data[start..chunk.pos]);
+        }
+
+        match ret {
+            // From an io_uring that does not wait for nonblocking sockets.
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                self.io.writable = false;
+                Some(Ok(false))
+            }
+            // Of a write its message gave up on, the rest goes as zeros.
+            Err(err) if err.raw_os_error() == Some(libc::ECANCELED) => Some(Ok(false)),
+            ret => Some(ret),
+        }
+    }
+
+    /// Replaces an idle stream that was reset. What was written before may
+    /// not all have reached the receiver, which cannot reconnect by itself.
+    fn check_idle(&mut self, index: usize, sources: &Sources, replacer: &Replacer) {
+        if self.reconnect.is_none() {
+            self.io.readable = false;
+            return;
+        }
+        // Nothing is ever sent back on a data stream.
+        match self.io.stream.read(&mut [0u8; 1][..]) {
+            Err(err) if connection::is_stream_reset(&err) => {
+                self.broke(index, err, sources, replacer)
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            _ => self.io.readable = false,
+        }
+    }
+
+    /// Replaces the stream if it was reset, fails its chunks otherwise.
+    fn broke(&mut self, index: usize, err: io::Error, sources: &Sources, replacer: &Replacer) {
+        match self.reconnect.take() {
+            Some(reconnect) if connection::is_stream_reset(&err) => {
+                tracing::warn!(
+                    "data stream {} was reset, replacing it, err={:?}",
+                    self.io.stream.peer(),
+                    err
+                );
+                self.io.deregister(sources);
+                self.replacing = true;
+                replacer.spawn(index, reconnect);
+            }
+            reconnect => {
+                self.reconnect = reconnect;
+                self.fail(BaguaNetError::remote_io("data stream broke", err));
+            }
+        }
+    }
+
+    /// Replaces the stream like a reset one, with a new source port.
+    fn rehash(&mut self, index: usize, sources: &Sources, replacer: &Replacer, metrics: &AppState) {
+        self.rehashing = false;
+        if let Some(reconnect) = self.reconnect.take() {
+            tracing::info!(
+                "data stream {} is slow, replacing it",
+                self.io.stream.peer()
+            );
+            metrics.stream_rehashes.fetch_add(1, Ordering::Relaxed);
+            self.io.deregister(sources);
+            self.replacing = true;
+            replacer.spawn(index, reconnect);
+        }
+    }
+
+    fn replaced(
+        &mut self,
+        index: usize,
+        reconnect: Reconnect,
+        replacement: Result<Stream, BaguaNetError>,
+        sources: &Sources,
+    ) {
+        self.reconnect = Some(reconnect);
+        self.replacing = false;
+        // Completions of the broken stream never come, it was torn down
+        // along with what it still sent from the chunks.
+        if let Some(zerocopy) = self.zerocopy.take() {
+            for (_, chunk) in zerocopy.unacked {
+                chunk.state.complete_subtask(chunk.data.len());
+            }
+        }
+        let replaced = replacement.and_then(|stream| {
+            self.io
+                .replace(index, stream, sources)
+                .map_err(BaguaNetError::from)
+        });
+        if let Err(err) = replaced {
+            return self.fail(err);
+        }
+        if let Some(threshold) = self.zerocopy_threshold {
+            self.enable_zerocopy(threshold);
+        }
+    }
+
+    fn fail(&mut self, err: BaguaNetError) {
+        tracing::warn!("data stream {} broke, err={:?}", self.io.stream.peer(), err);
+        fail_chunks(&mut self.chunks, &err);
+        if let Some(zerocopy) = &mut self.zerocopy {
+            for (_, chunk) in zerocopy.unacked.drain(..) {
+                chunk.state.fail(err.clone());
+            }
+        }
+        self.err = Some(err);
+    }
+}
+
+/// Of the master stream messages a send comm writes in one syscall, unless
+/// the first is larger.
+const COALESCED_BYTES: usize = 64 * 1024;
+
+/// A master stream message, the size of a message or that none follow.
+struct CtrlMessage {
+    bytes: [u8; 8],
+    /// The message itself when it is inlined, see `inline_threshold`.
+    payload: &'static [u8],
+    /// Follows `payload` once every chunk of the message is in.
+    crc: Option<Arc<Mutex<MessageCrc>>>,
+    crc_bytes: Option<[u8; 4]>,
+    /// Into `bytes`, then `payload`, then `crc_bytes`.
+    pos: usize,
+    /// Whose master subtask is done once it is written.
+    state: Option<Arc<RequestState>>,
+    /// What `payload` is in, for an inlined device buffer.
+    #[allow(dead_code)]
+    staged: Option<Bounce>,
+    /// Or if `isend` copied the message.
+    #[allow(dead_code)]
+    copy: Option<Arc<SendCopy>>,
+    /// Or once part of it was written, see `give_back`.
+    rest: Option<PooledBuffer>,
+    /// Of the buffer `payload` is in place in, while it is written.
+    loan: Option<BufferLoan>,
+    /// Of the message, see `CANCEL_NBYTES`. None of the others.
+    index: Option<usize>,
+    /// Of a `CANCEL_NBYTES` one, like `Chunk::told`.
+    told: Option<Arc<AtomicBool>>,
+    /// Whether it tells the receiver that the comm is closed, after which it
+    /// reads no more.
+    closing: bool,
+}
+
+impl CtrlMessage {
+    fn new(nbytes: usize, state: Option<Arc<RequestState>>) -> CtrlMessage {
+        CtrlMessage {
+            bytes: nbytes.to_be_bytes(),
+            payload: &[],
+            crc: None,
+            crc_bytes: None,
+            pos: 0,
+            state,
+            staged: None,
+            copy: None,
+            rest: None,
+            loan: None,
+            index: None,
+            told: None,
+            closing: false,
+        }
+    }
+
+    fn inline(payload: &'static [u8], state: Arc<RequestState>, index: usize) -> CtrlMessage {
+        CtrlMessage {
+            payload,
+            index: Some(index),
+            ..CtrlMessage::new(payload.len(), Some(state))
+        }
+    }
+
+    /// Before it is written, lends it the buffer its payload is in place in.
+    /// Unless its message gave up on it, telling the receiver in its place.
+    fn lend(&mut self) {
+        let state = match &self.state {
+            Some(state) => state,
+            None => return,
+        };
+        if self.payload.is_empty()
+            || self.rest.is_some()
+            || self.staged.is_some()
+            || self.copy.is_some()
+            || self.loan.is_some()
+        {
+            return;
+        }
+        self.loan = state.lend();
+        // None of it was written, see `give_back`.
+        if self.loan.is_none() {
+            let index = self.index.unwrap();
+            *self = CtrlMessage::new(connection::CANCEL_NBYTES | index, None);
+        }
+    }
+
+    /// Once it was written from the buffer its payload is in place in, the
+    /// rest is written from a copy rather than after its message might have
+    /// given up on it.
+    fn give_back(&mut self) {
+        if self.loan.is_none() {
+            return;
+        }
+        if self.pos > 0 && self.pos < self.len() {
+            let rest = buffer_pool::POOL.lease(self.payload.len());
+            let payload = rest.slice(self.payload.len());
+            payload.copy_from_slice(self.payload);
+            self.payload = payload;
+            self.rest = Some(rest);
+        }
+        self.loan = None;
+    }
+
+    fn len(&self) -> usize {
+        let crc = match self.crc {
+            Some(_) => 4,
+            None => 0,
+        };
+        self.bytes.len() + self.payload.len() + crc
+    }
+
+    /// Whether all that is left to write is known, the CRC of a staged
+    /// message is not until its chunks are on the host.
+    fn ready(&mut self) -> bool {
+        if let (Some(crc), None) = (&self.crc, self.crc_bytes) {
+            self.crc_bytes = crc.lock().unwrap().value().map(u32::to_be_bytes);
+        }
+        self.crc.is_none() || self.crc_bytes.is_some()
+    }
+
+    /// What is left to write of the header, of the payload, then of the CRC
+    /// if it is known.
+    fn remaining(&self) -> [&[u8]; 3] {
+        let header = self.bytes.len();
+        let payload = std::cmp::min(self.pos.saturating_sub(header), self.payload.len());
+        let crc = match &self.crc_bytes {
+            Some(bytes) => &bytes[self.pos.saturating_sub(header + self.payload.len())..],
+            None => &[],
+        };
+        [
+            &self.bytes[std::cmp::min(self.pos, header)..],
+            &self.payload[payload..],
+            crc,
+        ]
+    }
+}
+
+const NORMAL_LANE: usize = 0;
+const PRIORITY_LANE: usize = 1;
+
+/// The messages posted to a send comm, those of up to
+/// `BAGUA_NET_PRIORITY_THRESHOLD` bytes in a lane of their own. They go on the
+/// master stream, inlined, and are taken in the order posted as soon as the
+/// messages before them are, while the data streams are still backlogged with
+/// the chunks of those.
+struct SendLanes {
+    lanes: [flume::Receiver<LaneTask>; 2],
+    /// Received from their lane, not taken yet.
+    fronts: [Option<LaneTask>; 2],
+    /// Of the messages taken.
+    ntaken: usize,
+    /// Counts the messages posted, down as they are taken.
+    depth: Arc<SendQueueDepth>,
+}
+
+impl SendLanes {
+    fn new(lanes: [flume::Receiver<LaneTask>; 2], depth: Arc<SendQueueDepth>) -> SendLanes {
+        SendLanes {
+            lanes,
+            fronts: [None, None],
+            ntaken: 0,
+            depth,
+        }
+    }
+
+    /// Of the next message, unless it was not posted yet.
+    fn next_lane(&mut self) -> Option<usize> {
+        for lane in [NORMAL_LANE, PRIORITY_LANE] {
+            if self.fronts[lane].is_none() {
+                self.fronts[lane] = self.lanes[lane].try_recv().ok();
+            }
+            if let Some((n, _)) = &self.fronts[lane] {
+                if *n == self.ntaken {
+                    return Some(lane);
+                }
+            }
+        }
+        None
+    }
+
+    /// Once `next_lane` returned `lane`.
+    fn take(&mut self, lane: usize) -> SendTask {
+        self.ntaken += 1;
+        self.depth.messages.fetch_sub(1, Ordering::Relaxed);
+        self.fronts[lane].take().unwrap().1
+    }
+
+    fn is_empty(&self) -> bool {
+        self.fronts.iter().all(Option::is_none) && self.lanes.iter().all(|lane| lane.is_empty())
+    }
+
+    /// Once the comm is closed, there may still be messages to take.
+    fn is_disconnected(&self) -> bool {
+        self.lanes.iter().all(|lane| lane.is_disconnected())
+    }
+
+    fn drain(self) -> Vec<SendTask> {
+        let SendLanes {
+            lanes,
+            mut fronts,
+            depth,
+            ..
+        } = self;
+        let mut tasks: Vec<LaneTask> = fronts.iter_mut().filter_map(Option::take).collect();
+        for lane in lanes.iter() {
+            tasks.extend(lane.drain());
+        }
+        depth.messages.fetch_sub(tasks.len(), Ordering::Relaxed);
+        tasks.sort_by_key(|(n, _)| *n);
+        tasks.into_iter().map(|(_, task)| task).collect()
+    }
+}
+
+/// Drives a send comm on an event loop: announces each message on the
+/// master stream, and spreads its chunks over the data streams.
+struct SendDriver {
+    comm_uuid: Uuid,
+    reconnect_handle: Option<SocketHandle>,
+    /// Gone once the comm is closed.
+    msg_receiver: Option<SendLanes>,
+    ctrl: DrivenStream,
+    ctrl_queue: VecDeque<CtrlMessage>,
+    ctrl_broken: bool,
+    streams: Vec<SendStream>,
+    downstream_id: usize,
+    /// Negotiated, they stay with the streams once parked.
+    capabilities: u32,
+    /// See `StreamGroup::peer_rcvbuf`.
+    peer_rcvbuf: usize,
+    /// Unless the chunks go round-robin.
+    injector: Option<Injector>,
+    replacer: Replacer,
+    replacements: flume::Receiver<Replaced>,
+    /// Unless the comm does not grow, or no longer.
+    grower: Option<StreamGrower>,
+    /// With `BAGUA_NET_IMBALANCE_RATIO` above 0.
+    imbalance: Option<ImbalanceWindow>,
+    /// Of the grown streams.
+    zerocopy_threshold: Option<usize>,
+    started: bool,
+    /// Announced to the receiver before the first message split by it.
+    min_chunksize: usize,
+    /// Unless `BAGUA_NET_MIN_CHUNKSIZE` is set, or the size of the messages
+    /// is fixed.
+    split_tuner: Option<Arc<Mutex<SplitTuner>>>,
+    /// The chunked messages not done yet, with their size, what they were
+    /// split by, and when they were taken.
+    timed: Vec<(Arc<RequestState>, usize, usize, Instant)>,
+    chunk_bytes: usize,
+    /// Messages stay in the channel while a stream has this many chunks
+    /// queued, or the master stream this many messages.
+    queue_capacity: usize,
+    /// Whether chunks follow their `ChunkHeader`.
+    seq_check: bool,
+    /// Of the next chunked message, in its `ChunkHeader`s.
+    next_message: u32,
+    /// Of the messages taken, the index of the next, see `CANCEL_NBYTES`.
+    taken: usize,
+    /// The chunked messages not done yet, with their index and whether the
+    /// receiver was told they were cancelled, see `tell_cancelled`.
+    cancellable: VecDeque<(usize, Arc<RequestState>, Arc<AtomicBool>)>,
+    /// Whether messages are followed by their CRC32C on the master stream.
+    crc: bool,
+    /// Unless the chunks go as they are.
+    compression: Option<Arc<Compression>>,
+    /// Messages up to this size go on the master stream, after their size.
+    inline_threshold: usize,
+    fixed_size: FixedMessageSize,
+    fixed: FixedSize,
+    /// The receiver's answer to the announced size.
+    fixed_ack: [u8; 8],
+    fixed_ack_pos: usize,
+    /// Of the messages sent without their size.
+    fixed_sent: usize,
+    /// Unless device buffers are not supported.
+    staging: Option<Arc<Staging>>,
+    /// Of the data streams together.
+    bucket: TokenBucket,
+    metrics: Arc<AppState>,
+    /// Of the bytes sent, labelled by the listener's address and the comm ID.
+    peer_nbytes_gauge: Arc<Gauge>,
+    queue_depth: Arc<SendQueueDepth>,
+    stream_fds: RecordedFds,
+    cache: SendCommCache,
+    cache_key: SendCommKey,
+    /// Dropped along with the driver, see `SocketSendComm::finished`.
+    #[allow(dead_code)]
+    finished: flume::Sender<()>,
+}
+
+impl SendDriver {
+    fn backlogged(&self) -> bool {
+        self.ctrl_queue.len() >= self.queue_capacity
+            || self
+                .streams
+                .iter()
+                .any(|stream| stream.chunks.len() >= self.queue_capacity)
+            || self
+                .injector
+                .as_ref()
+                .is_some_and(|injector| injector.len() >= self.queue_capacity * self.streams.len())
+    }
+
+    /// Those of the priority lane only go on the master stream.
+    fn backlogged_for(&self, lane: usize) -> bool {
+        match lane {
+            PRIORITY_LANE => self.ctrl_queue.len() >= self.queue_capacity,
+            _ => self.backlogged(),
+        }
+    }
+
+    /// Whether there are messages it did not take for being backlogged, but
+    /// would now.
+    fn can_take(&mut self) -> bool {
+        if matches!(self.fixed, FixedSize::Announced(_)) {
+            return false;
+        }
+        match self.msg_receiver.as_mut().and_then(SendLanes::next_lane) {
+            Some(lane) => !self.backlogged_for(lane),
+            None => false,
+        }
+    }
+
+    /// Whether messages can be taken. The size set on the comm is read once
+    /// its first message is in the channel, posted after it was set, and
+    /// announced to the receiver, which no message is taken until it answers.
+    fn poll_fixed(&mut self) -> bool {
+        match self.fixed {
+            FixedSize::Unknown => {
+                let msg_receiver = match &self.msg_receiver {
+                    Some(msg_receiver) => msg_receiver,
+                    None => return true,
+                };
+                // Closing is still taken.
+                if msg_receiver.is_empty() {
+                    return msg_receiver.is_disconnected();
+                }
+                match *self.fixed_size.lock().unwrap() {
+                    Some(nbytes) => {
+                        self.ctrl_queue
+                            .push_back(CtrlMessage::new(connection::FIXED_NBYTES | nbytes, None));
+                        self.fixed = FixedSize::Announced(nbytes);
+                        // The chunks of a message are split over as many
+                        // streams, and by as much, on both ends without a
+                        // size to tell them.
+                        self.grower = None;
+                        self.split_tuner = None;
+                        false
+                    }
+                    None => {
+                        self.fixed = FixedSize::Dynamic;
+                        true
+                    }
+                }
+            }
+            FixedSize::Announced(nbytes) => {
+                if !self.ctrl.readable {
+                    return false;
+                }
+                match utils::try_read_into(
+                    &mut self.ctrl.stream,
+                    &mut self.fixed_ack[..],
+                    &mut self.fixed_ack_pos,
+                ) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        self.ctrl.readable = false;
+                        return false;
+                    }
+                    Err(err) => {
+                        self.ctrl_failed(err);
+                        return false;
+                    }
+                }
+                if usize::from_be_bytes(self.fixed_ack) != nbytes {
+                    self.fail(BaguaNetError::remote(format!(
+                        "the receiver of send comm {} does not expect messages of {} bytes",
+                        self.comm_uuid, nbytes
+                    )));
+                    return false;
+                }
+                self.fixed = FixedSize::Fixed(nbytes);
+                true
+            }
+            FixedSize::Dynamic | FixedSize::Fixed(_) => true,
+        }
+    }
+
+    /// Until the lanes are empty or it is backlogged, which keeps the rest
+    /// in their lane: once that is full `isend` is busy.
+    fn take_tasks(&mut self) {
+        loop {
+            if !self.poll_fixed() {
+                return;
+            }
+            let (lane, closed) = match &mut self.msg_receiver {
+                Some(msg_receiver) => (
+                    msg_receiver.next_lane(),
+                    msg_receiver.is_disconnected() && msg_receiver.is_empty(),
+                ),
+                None => return,
+            };
+            let (data, state, copy) = match lane {
+                Some(lane) if !self.backlogged_for(lane) => {
+                    self.msg_receiver.as_mut().unwrap().take(lane)
+                }
+                Some(_) => return,
+                None if !closed || self.backlogged() => return,
+                None => {
+                    // The comm was closed and every queued message went to
+                    // the data streams, tell the receiver that none follow.
+                    let close_nbytes = if self.cache.is_some() {
+                        connection::PARK_NBYTES
+                    } else {
+                        connection::CLOSE_NBYTES
+                    };
+                    if let FixedSize::Fixed(_) = self.fixed {
+                        self.ctrl_queue.push_back(CtrlMessage {
+                            closing: true,
+                            ..CtrlMessage::new(self.fixed_sent, None)
+                        });
+                    }
+                    self.ctrl_queue.push_back(CtrlMessage {
+                        closing: true,
+                        ..CtrlMessage::new(close_nbytes, None)
+                    });
+                    self.msg_receiver = None;
+                    return;
+                }
+            };
+
+            // Not announced, the receiver never hears of it, nor of one that
+            // failed already. Its buffer is read below, while it is lent.
+            let _loan = match state.lend() {
+                Some(loan) => loan,
+                None => continue,
+            };
+            state.start();
+            let message_index = self.taken;
+            self.taken += 1;
+            let len = data.len();
+            let fixed = matches!(self.fixed, FixedSize::Fixed(_));
+            // Its header only, without a CRC, done once it is written.
+            if len == 0 && !fixed {
+                self.ctrl_queue
+                    .push_back(CtrlMessage::inline(&[], state, message_index));
+                continue;
+            }
+            let inline = len <= self.inline_threshold && !fixed;
+            // Inlined or compressed, a message is written from one buffer.
+            let (data, copy) = match data {
+                SendData::Gather(buffers) if inline || self.compression.is_some() => {
+                    let gathered = SendCopy::lease(len, self.metrics.send_copy_bytes.clone());
+                    if let Err(err) = gathered.fill(&buffers, self.staging.as_ref()) {
+                        state.fail(err);
+                    }
+                    (
+                        SendData::Contiguous(gathered.data().into()),
+                        Some(Arc::new(gathered)),
+                    )
+                }
+                data => (data, copy),
+            };
+            if let (true, &SendData::Contiguous(data)) = (inline, &data) {
+                let data = data.slice();
+                let staging = self
+                    .staging
+                    .as_ref()
+                    .filter(|staging| staging.is_device(data));
+                let mut message = match staging {
+                    Some(staging) => {
+                        // Small enough to copy right away.
+                        let bounce = staging.lease(data.len());
+                        let range = CopyRange {
+                            dst: bounce.ptr(),
+                            src: data.as_ptr() as usize,
+                            len: data.len(),
+                        };
+                        if let Err(err) = staging.copy_now(range) {
+                            state.fail(err);
+                        }
+                        let payload: &'static [u8] = bounce.slice(data.len());
+                        CtrlMessage {
+                            staged: Some(bounce),
+                            ..CtrlMessage::inline(payload, state, message_index)
+                        }
+                    }
+                    None => CtrlMessage {
+                        copy,
+                        ..CtrlMessage::inline(data, state, message_index)
+                    },
+                };
+                if self.crc {
+                    message.crc = Some(Arc::new(Mutex::new(MessageCrc::of(message.payload))));
+                }
+                self.ctrl_queue.push_back(message);
+                continue;
+            }
+            let chunk_size = utils::chunk_size(
+                len,
+                self.min_chunksize,
+                self.chunk_bytes,
+                self.streams.len(),
+            );
+            if self.split_tuner.is_some() {
+                self.timed
+                    .push((state.clone(), len, self.min_chunksize, Instant::now()));
+            }
+            let told = Arc::new(AtomicBool::new(false));
+            self.cancellable
+                .push_back((message_index, state.clone(), told.clone()));
+
+            // A chunk is written from the parts of the slices it is in, one
+            // after the other on its stream, the receiver reads it whole.
+            let nchunks = len.div_ceil(chunk_size);
+            let buckets = (0..nchunks).map(|index| {
+                let start = index * chunk_size;
+                (start, chunk_size.min(len - start))
+            });
+            // From the parts, as they are copied to the host if they are on
+            // the device.
+            let crc = self.crc.then(|| {
+                let nparts = buckets
+                    .clone()
+                    .map(|(start, nbytes)| data.pieces(start, nbytes).count())
+                    .sum();
+                Arc::new(Mutex::new(MessageCrc::new(nparts)))
+            });
+            let mut part_index = 0;
+
+            for (index, (start, nbytes)) in buckets.enumerate() {
+                let header = ChunkHeader {
+                    message: self.next_message,
+                    index: index as u32,
+                    nbytes: nbytes as u64,
+                };
+                let mut chunks: Vec<Chunk<&'static [u8]>> = (self.seq_check
+                    || self.injector.is_some())
+                .then(|| {
+                    state.add_subtasks(1);
+                    Chunk::<&'static [u8]>::header(header, state.clone())
+                })
+                .into_iter()
+                .collect();
+                // Only contiguous messages are compressed, in one part.
+                let mut compress = self.compression.as_ref().map(|compression| Compress {
+                    copy: copy.clone(),
+                    ..Compress::new(compression, self.replacer.waker.clone())
+                });
+                let mut offset = start;
+                for bucket in data.pieces(start, nbytes) {
+                    state.add_subtasks(1);
+                    let span = chunk_span("isend-chunk", &state, offset, bucket.len());
+                    offset += bucket.len();
+                    let crc = crc.clone().map(|crc| (crc, part_index));
+                    part_index += 1;
+                    let compress = compress.take();
+                    let frame = compress
+                        .as_ref()
+                        .map(|compress| compress.frame(bucket.len()));
+                    let staging = self
+                        .staging
+                        .as_ref()
+                        .filter(|staging| staging.is_device(bucket));
+                    let chunk = match staging {
+                        Some(staging) => stage_send_chunk(
+                            staging,
+                            bucket,
+                            state.clone(),
+                            crc,
+                            compress,
+                            self.replacer.waker.clone(),
+                        ),
+                        None => {
+                            if let Some((crc, index)) = crc {
+                                crc.lock().unwrap().record(index, bucket);
+                            }
+                            if let Some(mut compress) = compress {
+                                // Read on the compression threads while it is
+                                // lent the buffer, right away otherwise.
+                                match state.lend() {
+                                    Some(loan) => {
+                                        compress.loan = Some(loan);
+                                        compress.run(bucket);
+                                    }
+                                    None => write_send_frame(bucket, &compress.frame, true),
+                                }
+                            }
+                            Chunk {
+                                copy: copy.clone(),
+                                ..Chunk::new(bucket, state.clone())
+                            }
+                        }
+                    };
+                    let chunk = Chunk {
+                        span,
+                        told: Some(told.clone()),
+                        ..chunk
+                    };
+                    chunks.push(match frame {
+                        // Written from its frame once that is done.
+                        Some(frame) => Chunk {
+                            data: &[][..],
+                            frame: Some(frame),
+                            ..chunk
+                        },
+                        None => chunk,
+                    });
+                }
+                match &mut self.injector {
+                    Some(injector) => injector.push_back(chunks),
+                    None => {
+                        self.streams[self.downstream_id].chunks.extend(chunks);
+                        self.downstream_id = (self.downstream_id + 1) % self.streams.len();
+                    }
+                }
+            }
+            self.next_message = self.next_message.wrapping_add(1);
+            if fixed {
+                self.fixed_sent += 1;
+                state.complete_subtask(0);
+                continue;
+            }
+            self.ctrl_queue.push_back(CtrlMessage {
+                crc,
+                ..CtrlMessage::new(len, Some(state))
+            });
+        }
+    }
+
+    /// Tells the receiver of the chunked messages cancelled since, ahead of
+    /// the zeros written for the rest of them and of the close.
+    fn tell_cancelled(&mut self) {
+        let (ctrl_queue, ctrl_broken) = (&mut self.ctrl_queue, self.ctrl_broken);
+        let mut at = ctrl_queue
+            .iter()
+            .position(|message| message.closing && message.pos == 0)
+            .unwrap_or(ctrl_queue.len());
+        self.cancellable.retain(|(index, state, told)| {
+            if state.done() {
+                return false;
+            }
+            if !state.is_cancelled() {
+                return true;
+            }
+            match ctrl_broken {
+                true => told.store(true, Ordering::Release),
+                false => {
+                    ctrl_queue.insert(
+                        at,
+                        CtrlMessage {
+                            told: Some(told.clone()),
+                            ..CtrlMessage::new(connection::CANCEL_NBYTES | index, None)
+                        },
+                    );
+                    at += 1;
+                }
+            }
+            false
+        });
+    }
+
+    /// Writes the queued master stream messages until the stream would block,
+    /// up to `COALESCED_BYTES` of them at once rather than one each. A message
+    /// whose CRC is not known yet holds back the ones after it.
+    fn write_ctrl(&mut self) {
+        while self.ctrl.writable && !self.ctrl_queue.is_empty() {
+            let mut nbytes = 0;
+            let mut batch = 0;
+            for message in self.ctrl_queue.iter_mut() {
+                message.lend();
+                nbytes += message.len() - message.pos;
+                if batch > 0 && nbytes > COALESCED_BYTES {
+                    message.give_back();
+                    break;
+                }
+                batch += 1;
+                if !message.ready() {
+                    break;
+                }
+            }
+            let bufs: Vec<&[u8]> = self
+                .ctrl_queue
+                .iter()
+                .take(batch)
+                .flat_map(|message| message.remaining())
+                .collect();
+            if bufs.iter().all(|buf| buf.is_empty()) {
+                return;
+            }
+            let mut written = 0;
+            let ret =
+                utils::try_write_vectored_from(&mut self.ctrl.stream, &bufs[..], &mut written);
+            self.metrics.ctrl_writes.fetch_add(1, Ordering::Relaxed);
+            for message in self.ctrl_queue.iter_mut().take(batch) {
+                let n = std::cmp::min(written, message.len() - message.pos);
+                message.pos += n;
+                written -= n;
+                message.give_back();
+            }
+            // Even if the rest would block.
+            while let Some(message) = self.ctrl_queue.front() {
+                if message.pos < message.len() {
+                    break;
+                }
+                let message = self.ctrl_queue.pop_front().unwrap();
+                if let Some(told) = &message.told {
+                    told.store(true, Ordering::Release);
+                }
+                if let Some(state) = message.state {
+                    state.complete_subtask(message.payload.len());
+                }
+                self.metrics.ctrl_messages.fetch_add(1, Ordering::Relaxed);
+            }
+            match ret {
+                Ok(true) => {}
+                Ok(false) => self.ctrl.writable = false,
+                Err(err) => return self.ctrl_failed(err),
+            }
+        }
+    }
+
+    /// Adds the stream opened by the grower, if it is. The messages queued
+    /// after it are spread over it too.
+    fn grow(&mut self, sources: &Sources) {
+        let grower = match &mut self.grower {
+            Some(grower) => grower,
+            None => return,
+        };
+        let grown = match grower.opening.as_ref().map(flume::Receiver::try_recv) {
+            Some(Ok(grown)) => grown,
+            Some(Err(flume::TryRecvError::Empty)) | None => return,
+            Some(Err(flume::TryRecvError::Disconnected)) => unreachable!(),
+        };
+        grower.opening = None;
+        let stream_id = self.streams.len();
+        let handshake = StreamHandshake {
+            comm_uuid: self.comm_uuid,
+            stream_id,
+        };
+        let mut stream = match grown {
+            // Too late once the receiver was told that no message follows.
+            Ok(_) if self.msg_receiver.is_none() => return,
+            Ok(stream) => {
+                let reconnect = grower.reconnect(handshake, &stream);
+                SendStream::new(stream, Some(reconnect))
+            }
+            Err(err) => {
+                tracing::warn!("stop growing send comm {}, err={:?}", self.comm_uuid, err);
+                self.grower = None;
+                return;
+            }
+        };
+        stream.io.stream.set_nodelay(true).unwrap();
+        stream.io.stream.set_nonblocking(true).unwrap();
+        if let Some(threshold) = self.zerocopy_threshold {
+            stream.enable_zerocopy(threshold);
+        }
+        if let Err(err) = stream.io.register(stream_id + 1, sources) {
+            tracing::warn!("stop growing send comm {}, err={:?}", self.comm_uuid, err);
+            self.grower = None;
+            return;
+        }
+
+        self.streams.push(stream);
+        self.ctrl_queue
+            .push_back(CtrlMessage::new(connection::GROW_NBYTES, None));
+        self.metrics
+            .send_comm_nstreams_gauge
+            .record(self.streams.len() as u64);
+        tracing::info!(
+            "send comm {} grew to {} data streams",
+            self.comm_uuid,
+            self.streams.len()
+        );
+    }
+
+    /// Opens another stream once a window ends backlogged below saturation.
+    fn observe(&mut self) {
+        let grower = match &mut self.grower {
+            Some(grower) if self.msg_receiver.is_some() => grower,
+            _ => return,
+        };
+        let sent = self
+            .streams
+            .iter_mut()
+            .map(|stream| std::mem::take(&mut stream.sent))
+            .sum();
+        // Held back by the cap rather than by too few streams.
+        let backlogged =
+            self.streams.iter().any(|stream| !stream.chunks.is_empty()) && !self.bucket.is_capped();
+        let throughput = match grower.window.observe(sent, backlogged) {
+            Some(throughput) if grower.opening.is_none() => throughput,
+            _ => return,
+        };
+        if self.streams.len() >= grower.window.config.max_nstreams {
+            self.grower = None;
+            return;
+        }
+        tracing::debug!(
+            "send comm {} moves {:.0} bytes/s of {:.0}, open data stream {}",
+            self.comm_uuid,
+            throughput,
+            grower.window.link_speed,
+            self.streams.len()
+        );
+        grower.open(
+            self.comm_uuid,
+            self.streams.len(),
+            self.replacer.waker.clone(),
+        );
+    }
+
+    /// Records how evenly the data streams wrote once a window ends. Warns
+    /// once they keep being uneven, and replaces the slowest if it can.
+    fn check_imbalance(&mut self) {
+        let window = match &mut self.imbalance {
+            Some(window) => window,
+            None => return,
+        };
+        let backlogged =
+            self.streams.iter().any(|stream| !stream.chunks.is_empty()) && !self.bucket.is_capped();
+        if !window.ended(backlogged) {
+            return;
+        }
+        let windowed: Vec<(usize, Duration)> = self
+            .streams
+            .iter_mut()
+            .map(|stream| std::mem::take(&mut stream.windowed))
+            .collect();
+        let imbalance = match window.close(&windowed) {
+            Some(imbalance) => imbalance,
+            None => return,
+        };
+        *self.queue_depth.imbalance.lock().unwrap() = Some(imbalance.ratio);
+        if !imbalance.persistent {
+            return;
+        }
+
+        let slowest = &mut self.streams[imbalance.slowest];
+        let rehash = window.config.rehash && self.injector.is_some() && slowest.reconnect.is_some();
+        tracing::warn!(
+            "data streams of send comm {} wrote {:?} bytes in the last {:?}, the fastest {:.1} times as fast as {}{}",
+            self.comm_uuid,
+            windowed.iter().map(|&(nbytes, _)| nbytes).collect::<Vec<_>>(),
+            window.config.window,
+            imbalance.ratio,
+            slowest.io.stream.peer(),
+            if rehash { ", replacing it" } else { "" }
+        );
+        slowest.rehashing |= rehash;
+    }
+
+    /// Records the chunked messages done since, and announces where the
+    /// threshold was tuned to before the next message is split by it.
+    fn tune(&mut self) {
+        let split_tuner = match &self.split_tuner {
+            Some(split_tuner) => split_tuner,
+            None => return,
+        };
+        let now = Instant::now();
+        let mut tuner = split_tuner.lock().unwrap();
+        self.timed
+            .retain(|(state, nbytes, min_chunksize, taken_at)| {
+                let (done, _) = state.progress();
+                let failed = state.err().is_some();
+                if done && !failed {
+                    tuner.record(*nbytes, *min_chunksize, now - *taken_at);
+                }
+                !done && !failed
+            });
+        tuner.tune(now);
+        let threshold = tuner.threshold();
+        drop(tuner);
+        // Not after the receiver was told that no message follows.
+        if threshold != self.min_chunksize && self.msg_receiver.is_some() {
+            self.ctrl_queue
+                .push_back(CtrlMessage::new(connection::SPLIT_NBYTES | threshold, None));
+            self.min_chunksize = threshold;
+        }
+    }
+
+    /// Once a data stream broke, the chunks left fail like those it had, as
+    /// they would round-robin.
+    fn fail_stolen(&mut self) {
+        let err = self.streams.iter().find_map(|stream| stream.err.clone());
+        if let (Some(injector), Some(err)) = (&mut self.injector, err) {
+            for chunk in injector.drain(..).flatten() {
+                chunk.state.fail(err.clone());
+            }
+        }
+    }
+
+    fn ctrl_failed(&mut self, err: io::Error) {
+        tracing::warn!(
+            "master stream {} broke, err={:?}",
+            self.ctrl.stream.peer(),
+            err
+        );
+        self.fail(BaguaNetError::remote_io("master stream broke", err));
+    }
+
+    /// Fails the messages not announced yet, and those posted later.
+    fn fail(&mut self, err: BaguaNetError) {
+        self.ctrl_broken = true;
+        for message in self.ctrl_queue.drain(..) {
+            // What it would have told goes without.
+            if let Some(told) = message.told {
+                told.store(true, Ordering::Release);
+            }
+            if let Some(state) = message.state {
+                state.fail(err.clone());
+            }
+        }
+        if let Some(msg_receiver) = self.msg_receiver.take() {
+            for (_, state, _) in msg_receiver.drain() {
+                state.fail(err.clone());
+            }
+        }
+    }
+}
+
+impl Driver for SendDriver {
+    fn ready(&mut self, index: usize, readable: bool, writable: bool) {
+        match index {
+            0 => self.ctrl.ready(readable, writable),
+            index => self.streams[index - 1].io.ready(readable, writable),
+        }
+    }
+
+    fn completed(&mut self, index: usize, result: i32) {
+        self.streams[index - 1].completion = Some(result);
+    }
+
+    fn poll(&mut self, sources: &Sources) -> bool {
+        if !self.started {
+            self.started = true;
+            if let Err(err) = self.ctrl.register(0, sources) {
+                self.ctrl_failed(err);
+            }
+            for (stream_id, stream) in self.streams.iter_mut().enumerate() {
+                if let Err(err) = stream.io.register(stream_id + 1, sources) {
+                    stream.fail(BaguaNetError::from(err));
+                }
+            }
+        }
+        for (index, reconnect, replacement) in self.replacements.try_iter() {
+            self.streams[index - 1].replaced(index, reconnect, replacement, sources);
+        }
+
+        self.grow(sources);
+        self.bucket.update();
+        loop {
+            self.take_tasks();
+            self.tell_cancelled();
+            self.write_ctrl();
+            // Between chunks, makes way for the next message once it is one of
+            // the priority lane.
+            let (msg_receiver, ctrl_queue) = (&mut self.msg_receiver, &self.ctrl_queue);
+            let queue_capacity = self.queue_capacity;
+            let mut priority_next = || {
+                ctrl_queue.len() < queue_capacity
+                    && msg_receiver.as_mut().and_then(SendLanes::next_lane) == Some(PRIORITY_LANE)
+            };
+            for (stream_id, stream) in self.streams.iter_mut().enumerate() {
+                stream.progress(
+                    stream_id + 1,
+                    sources,
+                    &self.replacer,
+                    &mut self.bucket,
+                    &self.metrics,
+                    &self.peer_nbytes_gauge,
+                    self.injector.as_mut(),
+                    &mut priority_next,
+                );
+            }
+            if !self.can_take() {
+                break;
+            }
+        }
+        self.fail_stolen();
+        self.observe();
+        self.check_imbalance();
+        self.tune();
+        self.queue_depth.record_streams(&self.streams);
+        self.stream_fds
+            .record(&mut self.streams, |stream| &mut stream.io);
+
+        self.msg_receiver.is_some()
+            || !self.ctrl_queue.is_empty()
+            || !self.streams.iter().all(SendStream::is_idle)
+            || self
+                .injector
+                .as_ref()
+                .is_some_and(|injector| !injector.is_empty())
+    }
+
+    fn finish(self: Box<Self>, sources: &Sources) {
+        let SendDriver {
+            comm_uuid,
+            reconnect_handle,
+            mut ctrl,
+            ctrl_broken,
+            streams,
+            capabilities,
+            peer_rcvbuf,
+            cache,
+            cache_key,
+            ..
+        } = *self;
+        ctrl.deregister(sources);
+        let data_streams: Option<Vec<Stream>> = streams
+            .into_iter()
+            .map(|mut stream| {
+                stream.io.deregister(sources);
+                stream.err.is_none().then_some(stream.io.stream)
+            })
+            .collect();
+        if let (Some(cache), Some(data_streams), false) = (cache, data_streams, ctrl_broken) {
+            let group = StreamGroup {
+                comm_uuid,
+                data_streams,
+                ctrl_stream: ctrl.stream,
+                capabilities,
+                peer_rcvbuf,
+            };
+            cache
+                .lock()
+                .unwrap()
+                .park(cache_key, ParkedStreams::new(group, reconnect_handle));
+        }
+    }
+}
+
+/// Of the queued chunks a data stream reads into in one syscall.
+const MAX_READ_CHUNKS: usize = 64;
+
+/// A data stream of a recv comm, with the chunks to fill from it.
+struct RecvStream {
+    io: DrivenStream,
+    reconnect: Option<(ReconnectRoute, Duration)>,
+    chunks: VecDeque<Chunk<&'static mut [u8]>>,
+    /// Of the front chunk, submitted to the io_uring of the event loop.
+    in_flight: bool,
+    /// Like `SendStream::cancelling`.
+    cancelling: bool,
+    completion: Option<i32>,
+    received: u64,
+    /// Of the next chunk the stream carries, when its chunks are stolen.
+    tag: [u8; ChunkHeader::NBYTES],
+    tag_pos: usize,
+    /// Since when the stream is reset, until its replacement comes in.
+    reset: Option<(Instant, io::Error)>,
+    err: Option<BaguaNetError>,
+    /// Of its driver, for the work on its chunks.
+    finished: flume::Sender<()>,
+}
+
+impl RecvStream {
+    fn new(
+        stream: Stream,
+        reconnect: Option<(ReconnectRoute, Duration)>,
+        finished: flume::Sender<()>,
+    ) -> RecvStream {
+        RecvStream {
+            io: DrivenStream::new(stream),
+            reconnect,
+            chunks: VecDeque::new(),
+            in_flight: false,
+            cancelling: false,
+            completion: None,
+            received: 0,
+            tag: [0u8; ChunkHeader::NBYTES],
+            tag_pos: 0,
+            reset: None,
+            err: None,
+            finished,
+        }
+    }
+
+    /// Fills the queued chunks, or those of `placements` the stream tells,
+    /// until the stream would block.
+    #[allow(clippy::too_many_arguments)]
+    fn progress(
+        &mut self,
+        index: usize,
+        sources: &Sources,
+        metrics: &AppState,
+        peer_nbytes_gauge: &Gauge,
+        compression: Option<&Arc<Compression>>,
+        mut placements: Option<&mut Placements>,
+        next_message: u32,
+    ) {
+        self.give_back(index, sources);
+        if let Some(err) = &self.err {
+            fail_chunks(&mut self.chunks, err);
+            return;
+        }
+        // While idle, adopt the replacements the sender opens meanwhile.
+        let replacement = match &self.reconnect {
+            Some((route, _)) if self.chunks.is_empty() || self.reset.is_some() => {
+                route.replacements.try_recv().ok()
+            }
+            _ => None,
+        };
+        if let Some(replacement) = replacement {
+            self.adopt(index, replacement, sources);
+        }
+        if let Some((reset_at, err)) = &self.reset {
+            let timeout = self.reconnect.as_ref().unwrap().1;
+            if reset_at.elapsed() > timeout {
+                let err = BaguaNetError::remote(format!(
+                    "data stream was reset and not replaced within {:?}, err={:?}",
+                    timeout, err
+                ));
+                self.reset = None;
+                self.fail(err);
+            }
+            return;
+        }
+
+        while let Some(ret) =
+            self.read_front(index, sources, placements.as_deref_mut(), next_message)
+        {
+            match ret {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) if self.reconnect.is_some() && connection::is_stream_reset(&err) => {
+                    tracing::warn!(
+                        "data stream {} was reset, waiting for its replacement, err={:?}",
+                        self.io.stream.peer(),
+                        err
+                    );
+                    self.io.deregister(sources);
+                    self.reset = Some((Instant::now(), err));
+                    return;
+                }
+                Err(err) => return self.fail(BaguaNetError::remote_io("data stream broke", err)),
+            }
+
+            let mut chunk = self.chunks.pop_front().unwrap();
+            chunk.end_span(index);
+            if let Err(err) = chunk.check_header() {
+                chunk.state.fail(err.clone());
+                return self.fail(err);
+            }
+            if let Some(Frame::Header(_)) = &chunk.frame {
+                if let Err(err) = self.expect_frame(&chunk) {
+                    chunk.state.fail(err.clone());
+                    return self.fail(err);
+                }
+                chunk.state.complete_subtask(0);
+                continue;
+            }
+            metrics.irecv_nbytes_gauge.record(chunk.data.len() as u64);
+            peer_nbytes_gauge.record(chunk.data.len() as u64);
+            match (&chunk.frame, compression) {
+                (Some(Frame::Recv { .. }), Some(compression)) => {
+                    decompress_recv_chunk(compression, chunk, self.finished.clone())
+                }
+                _ => {
+                    record_recv_crc(&chunk);
+                    complete_recv_chunk(chunk, false, &self.finished);
+                }
+            }
+        }
+    }
+
+    /// Like `SendStream::give_back`, for the chunks a read goes into.
+    fn give_back(&mut self, index: usize, sources: &Sources) {
+        for (i, chunk) in self.chunks.iter_mut().take(MAX_READ_CHUNKS).enumerate() {
+            if !chunk.gave_up() {
+                continue;
+            }
+            if i > 0 || !self.in_flight {
+                chunk.lend_or_discard();
+            } else if !self.cancelling {
+                self.cancelling = sources.submit_cancel(index);
+            }
+        }
+    }
+
+    /// Reads the chunk after `header` as the frame it announces.
+    fn expect_frame(&mut self, header: &Chunk<&'static mut [u8]>) -> Result<(), BaguaNetError> {
+        let mut bytes = [0u8; FrameHeader::NBYTES];
+        bytes.copy_from_slice(header.data);
+        let header = FrameHeader::from_bytes(&bytes);
+        // Queued right after its header.
+        let chunk = self.chunks.front_mut().unwrap();
+        header.check(chunk.data.len())?;
+        if header.compressed {
+            let buf = buffer_pool::POOL.lease(header.wire_nbytes as usize);
+            let data = buf.slice(header.wire_nbytes as usize);
+            let dst = std::mem::replace(&mut chunk.data, data);
+            chunk.frame = Some(Frame::Recv { buf, dst });
+        }
+        Ok(())
+    }
+
+    /// Like `SendStream::write_front`.
+    fn read_front(
+        &mut self,
+        index: usize,
+        sources: &Sources,
+        placements: Option<&mut Placements>,
+        next_message: u32,
+    ) -> Option<io::Result<bool>> {
+        if self.chunks.is_empty() {
+            return self.read_tag(placements?, next_message);
+        }
+        let chunk = self.chunks.front_mut().unwrap();
+        let ret = match self.completion.take() {
+            Some(result) => {
+                self.in_flight = false;
+                self.cancelling = false;
+                let ret = match event_loop::completion_result(result) {
+                    Ok(0) => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    )),
+                    Ok(n) => {
+                        chunk.pos += n;
+                        self.received += n as u64;
+                        Ok(chunk.pos == chunk.data.len())
+                    }
+                    // Of a read its message gave up on, the rest is dropped.
+                    Err(err) if err.raw_os_error() == Some(libc::ECANCELED) => Ok(false),
+                    Err(err) => Err(err),
+                };
+                if chunk.gave_up() {
+                    chunk.lend_or_discard();
+                }
+                ret
+            }
+            // Filled by the read into the chunks before it.
+            None if !self.in_flight && chunk.pos == chunk.data.len() => Ok(true),
+            None if self.in_flight || !self.io.readable => return None,
+            None => {
+                for chunk in self.chunks.iter_mut().take(MAX_READ_CHUNKS) {
+                    chunk.lend_or_discard();
+                }
+                let chunk = self.chunks.front_mut().unwrap();
+                let fd = self.io.stream.as_raw_fd();
+                // A posted buffer is not touched until its request completes.
+                if !self.io.stream.is_tls()
+                    && unsafe { sources.submit_read(index, fd, &mut chunk.data[chunk.pos..]) }
+                {
+                    self.in_flight = true;
+                    return None;
+                }
+                self.read_queued()
+            }
+        };
+
+        match ret {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                self.io.readable = false;
+                Some(Ok(false))
+            }
+            ret => Some(ret),
+        }
+    }
+
+    /// Reads the header of the next chunk, and queues what it is read into,
+    /// unless its message is not queued yet. Messages before `next_message`
+    /// were, the chunk is then none of theirs.
+    fn read_tag(
+        &mut self,
+        placements: &mut Placements,
+        next_message: u32,
+    ) -> Option<io::Result<bool>> {
+        if self.tag_pos < self.tag.len() {
+            if !self.io.readable {
+                return None;
+            }
+            let pos = self.tag_pos;
+            let ret =
+                utils::try_read_into(&mut self.io.stream, &mut self.tag[..], &mut self.tag_pos);
+            self.received += (self.tag_pos - pos) as u64;
+            match ret {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.io.readable = false;
+                    return None;
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        let tag = ChunkHeader::from_bytes(&self.tag);
+        let chunks = match placements.remove(&(tag.message, tag.index)) {
+            Some(chunks) => chunks,
+            None if tag.message.wrapping_sub(next_message) < 1 << 31 => return None,
+            None => {
+                self.fail(BaguaNetError::remote(format!(
+                    "unexpected chunk {:?}, the data streams are out of sync",
+                    tag
+                )));
+                return None;
+            }
+        };
+        // The chunk is last, after its frame header if any.
+        let nbytes = chunks.last().unwrap().data.len();
+        if nbytes as u64 != tag.nbytes {
+            let err = BaguaNetError::remote(format!(
+                "chunk {:?} does not fit in the {} bytes posted for it, the data streams are out of sync",
+                tag, nbytes
+            ));
+            chunks[0].state.fail(err.clone());
+            self.fail(err);
+            return None;
+        }
+        self.tag_pos = 0;
+        self.chunks.extend(chunks);
+        Some(Ok(false))
+    }
+
+    /// Fills the front chunk, and the chunks queued after it in the same
+    /// syscalls, up to a frame header, whose chunk is read as it tells.
+    /// Whether the front chunk is full.
+    fn read_queued(&mut self) -> io::Result<bool> {
+        let mut bufs: Vec<&mut [u8]> = Vec::new();
+        for chunk in self.chunks.iter_mut().take(MAX_READ_CHUNKS) {
+            let frame_header = matches!(chunk.frame, Some(Frame::Header(_)));
+            bufs.push(&mut chunk.data[chunk.pos..]);
+            if frame_header {
+                break;
+            }
+        }
+        let mut read = 0;
+        let ret = utils::try_read_vectored_into(&mut self.io.stream, &mut bufs[..], &mut read);
+        self.received += read as u64;
+        for chunk in self.chunks.iter_mut() {
+            if read == 0 {
+                break;
+            }
+            let n = std::cmp::min(read, chunk.data.len() - chunk.pos);
+            chunk.pos += n;
+            read -= n;
+        }
+        let front = self.chunks.front().unwrap();
+        match ret {
+            Ok(false) => self.io.readable = false,
+            // The rest of the error, if any, comes with the next read.
+            Err(_) if front.pos == front.data.len() => {}
+            Err(err) => return Err(err),
+            Ok(true) => {}
+        }
+        Ok(front.pos == front.data.len())
+    }
+
+    /// A broken replacement is dropped, the sender opens another one.
+    fn adopt(&mut self, index: usize, mut replacement: Stream, sources: &Sources) {
+        let adopted = connection::adopt_stream(&mut replacement, self.received)
+            .and_then(|_| replacement.set_nodelay(true))
+            .and_then(|_| replacement.set_nonblocking(true));
+        if let Err(err) = adopted {
+            tracing::warn!("drop replacement {}, err={:?}", replacement.peer(), err);
+            return;
+        }
+        self.reset = None;
+        if let Err(err) = self.io.replace(index, replacement, sources) {
+            self.fail(BaguaNetError::from(err));
+        }
+    }
+
+    fn fail(&mut self, err: BaguaNetError) {
+        tracing::warn!("data stream {} broke, err={:?}", self.io.stream.peer(), err);
+        fail_chunks(&mut self.chunks, &err);
+        self.err = Some(err);
+    }
+}
+
+/// Where a recv comm takes the next data stream its sender grows.
+struct GrowRoute {
+    acceptor: Arc<ReconnectAcceptor>,
+    route: ReconnectRoute,
+    timeout: Duration,
+    max_nstreams: usize,
+    /// Until the sender announces it on the master stream.
+    adopted: Option<Stream>,
+}
+
+impl GrowRoute {
+    fn adopt(&mut self) {
+        if self.adopted.is_some() {
+            return;
+        }
+        if let Ok(mut stream) = self.route.replacements.try_recv() {
+            match connection::adopt_stream(&mut stream, 0) {
+                Ok(()) => self.adopted = Some(stream),
+                Err(err) => tracing::warn!("drop grown stream {}, err={:?}", stream.peer(), err),
+            }
+        }
+    }
+}
+
+/// Drives a recv comm on an event loop: reads the size of each posted
+/// message from the master stream, and spreads its chunks over the data
+/// streams.
+/// Linux clears `TCP_QUICKACK` once it acks, see `Stream::set_quickack`.
+fn rearm_quickack(stream: &Stream) {
+    if let Err(err) = stream.set_quickack() {
+        tracing::debug!(
+            "failed to set TCP_QUICKACK on {}, err={:?}",
+            stream.peer(),
+            err
+        );
+    }
+}
+
+/// The CRC32C that follows a message on the master stream, being read.
+struct CtrlCrc {
+    bytes: [u8; 4],
+    pos: usize,
+    message: Arc<Mutex<MessageCrc>>,
+    state: Arc<RequestState>,
+}
+
+/// If messages have a CRC, the one of the message of `state` it reads next,
+/// and a subtask that verifies it once its `nchunks` chunks are in too.
+fn expect_crc(
+    ctrl_crc: &mut Option<CtrlCrc>,
+    crc: bool,
+    nchunks: usize,
+    state: &Arc<RequestState>,
+) -> Option<Arc<Mutex<MessageCrc>>> {
+    if !crc {
+        return None;
+    }
+    state.add_subtasks(1);
+    let message = Arc::new(Mutex::new(MessageCrc::new(nchunks)));
+    *ctrl_crc = Some(CtrlCrc {
+        bytes: [0u8; 4],
+        pos: 0,
+        message: message.clone(),
+        state: state.clone(),
+    });
+    Some(message)
+}
+
+struct RecvDriver {
+    id: SocketRecvCommID,
+    comm_uuid: Uuid,
+    /// Gone once the comm is closed, by either end.
+    msg_receiver: Option<flume::Receiver<RecvTask>>,
+    /// Posted messages whose size did not come in yet.
+    tasks: VecDeque<RecvTask>,
+    ctrl: DrivenStream,
+    ctrl_buf: [u8; 8],
+    ctrl_pos: usize,
+    /// A master stream message read before a message was posted for it.
+    ctrl_next: Option<usize>,
+    /// An inlined message being read off the master stream.
+    ctrl_inline: Option<Chunk<&'static mut [u8]>>,
+    streams: Vec<RecvStream>,
+    /// Unless the comm does not grow, or no longer.
+    growth: Option<GrowRoute>,
+    downstream_id: usize,
+    /// Like `SendDriver::capabilities`.
+    capabilities: u32,
+    /// Unless the chunks go round-robin.
+    placements: Option<Placements>,
+    peer_closed: Arc<Mutex<Option<usize>>>,
+    /// Unless the sender parks its end, see `ParkedStreams`.
+    reusable: bool,
+    started: bool,
+    min_chunksize: usize,
+    chunk_bytes: usize,
+    /// Of the posted messages taken from the channel.
+    queue_capacity: usize,
+    /// Like `SendDriver::seq_check`.
+    seq_check: bool,
+    next_message: u32,
+    /// Like `SendDriver::crc`.
+    crc: bool,
+    /// Like `SendDriver::compression`.
+    compression: Option<Arc<Compression>>,
+    /// Of the last message, verified once it is read.
+    ctrl_crc: Option<CtrlCrc>,
+    inline_threshold: usize,
+    fixed_size: FixedMessageSize,
+    /// Never `FixedSize::Announced`.
+    fixed: FixedSize,
+    /// The answer to the announced size, written before anything else.
+    fixed_ack: [u8; 8],
+    fixed_ack_pos: usize,
+    /// Of the messages queued without their size, those not done yet.
+    fixed_pending: VecDeque<Arc<RequestState>>,
+    /// How many messages the sender sent before it closed the comm.
+    fixed_sent: Option<usize>,
+    /// Of the messages queued, in every mode.
+    queued: usize,
+    /// The chunked messages queued and not done yet, with their index, see
+    /// `CANCEL_NBYTES`.
+    announced: VecDeque<(usize, Arc<RequestState>)>,
+    /// Of the messages the sender cancelled before they were posted, with a
+    /// fixed size.
+    cancelled_ahead: Vec<usize>,
+    quickack: QuickAck,
+    /// Unless device buffers are not supported.
+    staging: Option<Arc<Staging>>,
+    metrics: Arc<AppState>,
+    /// Of the bytes received, labelled by the sender's address and the comm
+    /// ID.
+    peer_nbytes_gauge: Arc<Gauge>,
+    stream_fds: RecordedFds,
+    cache: RecvCommCache,
+    listen_addr: SockAddr,
+    /// Dropped along with the driver, see `SocketRecvComm::finished`.
+    finished: flume::Sender<()>,
+}
+
+impl RecvDriver {
+    fn backlogged(&self) -> bool {
+        self.tasks.len() + self.fixed_pending.len() >= self.queue_capacity
+    }
+
+    /// Up to `queue_capacity`, like `SendDriver::take_tasks`.
+    fn take_tasks(&mut self) {
+        while !self.backlogged() {
+            let task = match &self.msg_receiver {
+                Some(msg_receiver) => msg_receiver.try_recv(),
+                None => return,
+            };
+            match task {
+                Ok(task) => self.tasks.push_back(task),
+                Err(flume::TryRecvError::Empty) => return,
+                Err(flume::TryRecvError::Disconnected) => {
+                    self.msg_receiver = None;
+                    return self.abort_posted();
+                }
+            }
+        }
+    }
+
+    /// Once the comm is closed, fails the messages still posted and lets
+    /// none of their buffers be touched anymore. The reads submitted to the
+    /// io_uring end once the streams are shut down.
+    fn abort_posted(&mut self) {
+        let posted = !self.tasks.is_empty()
+            || self.ctrl_inline.is_some()
+            || !self.fixed_pending.is_empty()
+            || self.streams.iter().any(|stream| !stream.chunks.is_empty())
+            || self
+                .placements
+                .as_ref()
+                .is_some_and(|placements| !placements.is_empty());
+        if !posted {
+            return;
+        }
+        let err = BaguaNetError::InvalidArgument(format!(
+            "recv comm {} was closed with messages posted",
+            self.id
+        ));
+        self.stop(err.clone(), false);
+        let abort = |state: &RequestState| {
+            state.cancel();
+            state.fail(err.clone());
+        };
+        for state in self.fixed_pending.drain(..) {
+            abort(&state);
+        }
+        if let Some(placements) = &mut self.placements {
+            for (_, chunks) in placements.drain() {
+                chunks.iter().for_each(|chunk| abort(&chunk.state));
+            }
+        }
+        for stream in self.streams.iter_mut() {
+            let in_flight = stream.in_flight as usize;
+            for chunk in stream.chunks.drain(in_flight..) {
+                abort(&chunk.state);
+            }
+            if let Some(chunk) = stream.chunks.front() {
+                abort(&chunk.state);
+            }
+            let _ = stream.io.stream.shutdown(net::Shutdown::Read);
+        }
+    }
+
+    /// Reads the sizes of the posted messages until the master stream would
+    /// block, and queues their chunks round-robin. Inlined messages are read
+    /// right away. So are the messages the sender cancelled, posted or not.
+    fn read_ctrl(&mut self, sources: &Sources) {
+        if let FixedSize::Fixed(nbytes) = self.fixed {
+            return self.read_fixed(nbytes);
+        }
+        while self.ctrl.readable || (self.ctrl_next.is_some() && !self.tasks.is_empty()) {
+            if let Some(chunk) = &mut self.ctrl_inline {
+                chunk.lend_or_discard();
+                match utils::try_read_into(&mut self.ctrl.stream, chunk.data, &mut chunk.pos) {
+                    Ok(true) => {
+                        let chunk = self.ctrl_inline.take().unwrap();
+                        record_recv_crc(&chunk);
+                        complete_recv_chunk(chunk, true, &self.finished);
+                    }
+                    Ok(false) => {
+                        self.ctrl.readable = false;
+                        return;
+                    }
+                    Err(err) => {
+                        return self
+                            .stop(BaguaNetError::remote_io("master stream broke", err), false)
+                    }
+                }
+            }
+            if let Some(crc) = &mut self.ctrl_crc {
+                match utils::try_read_into(&mut self.ctrl.stream, &mut crc.bytes[..], &mut crc.pos)
+                {
+                    Ok(true) => {
+                        let crc = self.ctrl_crc.take().unwrap();
+                        let mut message = crc.message.lock().unwrap();
+                        message.expected = Some(u32::from_be_bytes(crc.bytes));
+                        message.verify(&crc.state);
+                    }
+                    Ok(false) => {
+                        self.ctrl.readable = false;
+                        return;
+                    }
+                    Err(err) => {
+                        return self
+                            .stop(BaguaNetError::remote_io("master stream broke", err), false)
+                    }
+                }
+            }
+            if let FixedSize::Fixed(nbytes) = self.fixed {
+                return self.read_fixed(nbytes);
+            }
+            let target_nbytes = match self.ctrl_next.take() {
+                Some(target_nbytes) => target_nbytes,
+                None => {
+                    match utils::try_read_into(
+                        &mut self.ctrl.stream,
+                        &mut self.ctrl_buf[..],
+                        &mut self.ctrl_pos,
+                    ) {
+                        Ok(true) => self.ctrl_pos = 0,
+                        Ok(false) => {
+                            self.ctrl.readable = false;
+                            return;
+                        }
+                        Err(err) => {
+                            return self
+                                .stop(BaguaNetError::remote_io("master stream broke", err), false)
+                        }
+                    }
+                    usize::from_be_bytes(self.ctrl_buf)
+                }
+            };
+            match connection::cancel_nbytes(target_nbytes) {
+                Some(index) if index < self.queued => {
+                    self.cancelled_by_sender(index);
+                    continue;
+                }
+                _ if self.tasks.is_empty() => {
+                    self.ctrl_next = Some(target_nbytes);
+                    return;
+                }
+                // In place of an inlined one.
+                Some(index) => {
+                    self.cancelled_by_sender(index);
+                    continue;
+                }
+                None => {}
+            }
+            if target_nbytes == connection::CLOSE_NBYTES || target_nbytes == connection::PARK_NBYTES
+            {
+                *self.peer_closed.lock().unwrap() = Some(self.queued);
+                let err = BaguaNetError::remote(format!(
+                    "recv comm {} was closed by the sender",
+                    self.id
+                ));
+                return self.stop(err, target_nbytes == connection::PARK_NBYTES);
+            }
+            if target_nbytes == connection::GROW_NBYTES {
+                if let Err(err) = self.grow(sources) {
+                    return self.stop(err, false);
+                }
+                continue;
+            }
+            if let Some(min_chunksize) = connection::split_nbytes(target_nbytes) {
+                self.min_chunksize = min_chunksize;
+                continue;
+            }
+            if let Err(err) = self.decide_fixed(target_nbytes) {
+                return self.stop(err, false);
+            }
+            if let FixedSize::Fixed(_) = self.fixed {
+                continue;
+            }
+
+            let (data, state, size) = self.tasks.pop_front().unwrap();
+            if target_nbytes > data.len() {
+                let err = BaguaNetError::remote(format!(
+                    "message of {} bytes does not fit in the {} bytes posted",
+                    target_nbytes,
+                    data.len()
+                ));
+                state.fail(err.clone());
+                return self.stop(err, false);
+            }
+            self.queue_chunks(data, state, size, target_nbytes);
+        }
+    }
+
+    /// Of the message `target_nbytes` into `data`, on the master stream if
+    /// inlined, round-robin on the data streams or until their header tells
+    /// which one otherwise.
+    fn queue_chunks(
+        &mut self,
+        data: RecvBuffer,
+        state: Arc<RequestState>,
+        (sizes, index): RecvSize,
+        target_nbytes: usize,
+    ) {
+        state.start();
+        let data = data.into_slice();
+        self.queued += 1;
+        // Read once the message is done, which publishes it.
+        sizes[index].store(target_nbytes, Ordering::Relaxed);
+        let fixed = matches!(self.fixed, FixedSize::Fixed(_));
+        // Done with its header, like `SendDriver` writes it.
+        if target_nbytes == 0 && !fixed {
+            return state.complete_subtask(0);
+        }
+        let staging = self
+            .staging
+            .as_ref()
+            .filter(|staging| staging.is_device(data));
+        let chunk = |bucket: &'static mut [u8], state: Arc<RequestState>| match staging {
+            Some(staging) => stage_recv_chunk(staging, bucket, state),
+            None => Chunk::new(bucket, state),
+        };
+        if target_nbytes <= self.inline_threshold && !fixed {
+            let crc = expect_crc(&mut self.ctrl_crc, self.crc, 1, &state);
+            self.ctrl_inline = Some(Chunk {
+                crc: crc.map(|crc| (crc, 0)),
+                ..chunk(&mut data[..target_nbytes], state)
+            });
+            return;
+        }
+        let chunk_size = utils::chunk_size(
+            target_nbytes,
+            self.min_chunksize,
+            self.chunk_bytes,
+            self.streams.len(),
+        );
+        let nchunks = target_nbytes.div_ceil(chunk_size);
+        let crc = expect_crc(&mut self.ctrl_crc, self.crc, nchunks, &state);
+        while self
+            .announced
+            .front()
+            .is_some_and(|(_, state)| state.done())
+        {
+            self.announced.pop_front();
+        }
+        let message_index = self.queued - 1;
+        self.announced.push_back((message_index, state.clone()));
+        if let Some(at) = self
+            .cancelled_ahead
+            .iter()
+            .position(|&index| index == message_index)
+        {
+            self.cancelled_ahead.swap_remove(at);
+            state.fail(self.cancelled_err(message_index));
+        }
+        for (index, bucket) in data[..target_nbytes].chunks_mut(chunk_size).enumerate() {
+            if let Some(placements) = &mut self.placements {
+                let mut chunks = Vec::new();
+                if self.compression.is_some() {
+                    state.add_subtasks(1);
+                    chunks.push(Chunk::<&'static mut [u8]>::frame_header(state.clone()));
+                }
+                state.add_subtasks(1);
+                chunks.push(Chunk {
+                    crc: crc.clone().map(|crc| (crc, index)),
+                    span: chunk_span("irecv-chunk", &state, index * chunk_size, bucket.len()),
+                    ..chunk(bucket, state.clone())
+                });
+                placements.insert((self.next_message, index as u32), chunks);
+                continue;
+            }
+            let chunks = &mut self.streams[self.downstream_id].chunks;
+            if self.seq_check {
+                state.add_subtasks(1);
+                let expected = ChunkHeader {
+                    message: self.next_message,
+                    index: index as u32,
+                    nbytes: bucket.len() as u64,
+                };
+                chunks.push_back(Chunk::<&'static mut [u8]>::header(expected, state.clone()));
+            }
+            if self.compression.is_some() {
+                state.add_subtasks(1);
+                chunks.push_back(Chunk::<&'static mut [u8]>::frame_header(state.clone()));
+            }
+            state.add_subtasks(1);
+            chunks.push_back(Chunk {
+                crc: crc.clone().map(|crc| (crc, index)),
+                span: chunk_span("irecv-chunk", &state, index * chunk_size, bucket.len()),
+                ..chunk(bucket, state.clone())
+            });
+            self.downstream_id = (self.downstream_id + 1) % self.streams.len();
+        }
+        self.next_message = self.next_message.wrapping_add(1);
+        state.complete_subtask(0);
+    }
+
+    /// Fails the receive of the message `index`, which the sender cancelled,
+    /// unless it is done. The next one posted, if it was not queued yet.
+    fn cancelled_by_sender(&mut self, index: usize) {
+        let err = self.cancelled_err(index);
+        if index < self.queued {
+            if let Some((_, state)) = self.announced.iter().find(|(i, _)| *i == index) {
+                state.fail(err);
+            }
+            return;
+        }
+        match self.fixed {
+            FixedSize::Fixed(_) => self.cancelled_ahead.push(index),
+            _ => {
+                if let Some((_, state, _)) = self.tasks.pop_front() {
+                    self.queued += 1;
+                    state.fail(err);
+                }
+            }
+        }
+    }
+
+    fn cancelled_err(&self, index: usize) -> BaguaNetError {
+        BaguaNetError::remote(format!(
+            "message {} of recv comm {} was cancelled by the sender",
+            index, self.id
+        ))
+    }
+
+    /// Whether the sizes of the messages go on the master stream from the
+    /// first of them, `header`, and the size set on the comm.
+    fn decide_fixed(&mut self, header: usize) -> Result<(), BaguaNetError> {
+        let announced = connection::fixed_nbytes(header);
+        if self.fixed != FixedSize::Unknown {
+            return match announced {
+                Some(nbytes) => Err(BaguaNetError::remote(format!(
+                    "recv comm {} was announced messages of {} bytes after its first",
+                    self.id, nbytes
+                ))),
+                None => Ok(()),
+            };
+        }
+        let expected = *self.fixed_size.lock().unwrap();
+        match (announced, expected) {
+            (Some(nbytes), Some(expected)) if nbytes == expected => {
+                self.fixed_ack = nbytes.to_be_bytes();
+                self.fixed_ack_pos = 0;
+                self.fixed = FixedSize::Fixed(nbytes);
+                Ok(())
+            }
+            (None, None) => {
+                self.fixed = FixedSize::Dynamic;
+                Ok(())
+            }
+            (announced, expected) => {
+                if announced.is_some() {
+                    // Lets the sender fail its messages too, if it can.
+                    let _ = utils::try_write_from(&mut self.ctrl.stream, &[0u8; 8], &mut 0);
+                }
+                Err(BaguaNetError::remote(format!(
+                    "recv comm {} expects messages of {:?} bytes, the sender sends {:?}",
+                    self.id, expected, announced
+                )))
+            }
+        }
+    }
+
+    /// With a fixed size the posted messages are queued right away, all
+    /// there is on the master stream is how many the sender sent before it
+    /// closed the comm, and that it did.
+    fn read_fixed(&mut self, nbytes: usize) {
+        if self.fixed_ack_pos < self.fixed_ack.len() && self.ctrl.writable {
+            match utils::try_write_from(
+                &mut self.ctrl.stream,
+                &self.fixed_ack[..],
+                &mut self.fixed_ack_pos,
+            ) {
+                Ok(true) => {}
+                Ok(false) => self.ctrl.writable = false,
+                Err(err) => {
+                    return self.stop(BaguaNetError::remote_io("master stream broke", err), false)
+                }
+            }
+        }
+        while self
+            .fixed_pending
+            .front()
+            .is_some_and(|state| state.progress().0 || state.err().is_some())
+        {
+            self.fixed_pending.pop_front();
+        }
+        loop {
+            if self.fixed_sent.is_some_and(|sent| self.queued >= sent) {
+                break;
+            }
+            let (data, state, size) = match self.tasks.pop_front() {
+                Some(task) => task,
+                None => break,
+            };
+            self.queue_chunks(data, state.clone(), size, nbytes);
+            self.fixed_pending.push_back(state);
+        }
+        // The close follows once the messages sent are posted.
+        while self.ctrl.readable && self.fixed_sent.unwrap_or(0) <= self.queued {
+            match utils::try_read_into(
+                &mut self.ctrl.stream,
+                &mut self.ctrl_buf[..],
+                &mut self.ctrl_pos,
+            ) {
+                Ok(true) => self.ctrl_pos = 0,
+                Ok(false) => {
+                    self.ctrl.readable = false;
+                    return;
+                }
+                Err(err) => {
+                    return self.stop(BaguaNetError::remote_io("master stream broke", err), false)
+                }
+            }
+            let header = usize::from_be_bytes(self.ctrl_buf);
+            if let Some(index) = connection::cancel_nbytes(header) {
+                self.cancelled_by_sender(index);
+                continue;
+            }
+            let sent = match self.fixed_sent {
+                Some(sent) => sent,
+                None => {
+                    if header < self.queued {
+                        self.fail_unsent(self.queued - header);
+                    }
+                    self.fixed_sent = Some(header);
+                    continue;
+                }
+            };
+            if header == connection::CLOSE_NBYTES || header == connection::PARK_NBYTES {
+                *self.peer_closed.lock().unwrap() = Some(sent);
+                let err = BaguaNetError::remote(format!(
+                    "recv comm {} was closed by the sender",
+                    self.id
+                ));
+                return self.stop(err, header == connection::PARK_NBYTES);
+            }
+            let err = BaguaNetError::remote(format!(
+                "recv comm {} got {} on the master stream after the sender closed it",
+                self.id, header
+            ));
+            return self.stop(err, false);
+        }
+    }
+
+    /// The last `n` messages queued, which the sender closed the comm
+    /// before sending.
+    fn fail_unsent(&mut self, n: usize) {
+        let err = BaguaNetError::remote(format!("recv comm {} was closed by the sender", self.id));
+        let pending = self.fixed_pending.len();
+        let unsent: Vec<_> = self
+            .fixed_pending
+            .drain(pending - std::cmp::min(n, pending)..)
+            .collect();
+        let unsent_chunk = |chunk: &Chunk<&'static mut [u8]>| {
+            unsent.iter().any(|state| Arc::ptr_eq(state, &chunk.state))
+        };
+        for stream in self.streams.iter_mut() {
+            stream.chunks.retain(|chunk| !unsent_chunk(chunk));
+        }
+        if let Some(placements) = &mut self.placements {
+            placements.retain(|_, chunks| !unsent_chunk(&chunks[0]));
+        }
+        for state in unsent {
+            state.fail(err.clone());
+        }
+    }
+
+    /// Adds the data stream the sender announced, which it opened before.
+    fn grow(&mut self, sources: &Sources) -> Result<(), BaguaNetError> {
+        let id = self.id;
+        let growth = self
+            .growth
+            .as_mut()
+            .ok_or_else(|| BaguaNetError::InnerError(format!("recv comm {} cannot grow", id)))?;
+        growth.adopt();
+        let stream = growth.adopted.take().ok_or_else(|| {
+            BaguaNetError::InnerError(format!("recv comm {} grew without a stream", id))
+        })?;
+        stream.set_nonblocking(true).map_err(BaguaNetError::from)?;
+
+        // The stream keeps its route for its replacements.
+        let stream_id = self.streams.len();
+        let timeout = growth.timeout;
+        let route = if stream_id + 1 < growth.max_nstreams {
+            let next = growth.acceptor.route(self.comm_uuid, stream_id + 1);
+            std::mem::replace(&mut growth.route, next)
+        } else {
+            self.growth.take().unwrap().route
+        };
+        let mut stream = RecvStream::new(stream, Some((route, timeout)), self.finished.clone());
+        if let Err(err) = stream.io.register(stream_id + 1, sources) {
+            stream.fail(BaguaNetError::from(err));
+        }
+        self.streams.push(stream);
+        tracing::info!(
+            "recv comm {} grew to {} data streams",
+            self.id,
+            self.streams.len()
+        );
+
+        Ok(())
+    }
+
+    /// Like `SendDriver::fail_stolen`.
+    fn fail_stolen(&mut self) {
+        let err = self.streams.iter().find_map(|stream| stream.err.clone());
+        if let (Some(placements), Some(err)) = (&mut self.placements, err) {
+            for (_, chunks) in placements.drain() {
+                chunks[0].state.fail(err.clone());
+            }
+        }
+    }
+
+    /// Fails the posted messages, and those posted later.
+    fn stop(&mut self, err: BaguaNetError, reusable: bool) {
+        self.reusable = reusable;
+        if let Some(chunk) = self.ctrl_inline.take() {
+            chunk.state.fail(err.clone());
+        }
+        if let Some(crc) = self.ctrl_crc.take() {
+            crc.state.fail(err.clone());
+        }
+        for (_, state, _) in self.tasks.drain(..) {
+            state.fail(err.clone());
+        }
+        if let Some(msg_receiver) = self.msg_receiver.take() {
+            for (_, state, _) in msg_receiver.drain() {
+                state.fail(err.clone());
+            }
+        }
+    }
+}
+
+impl Driver for RecvDriver {
+    fn ready(&mut self, index: usize, readable: bool, writable: bool) {
+        match index {
+            0 => self.ctrl.ready(readable, writable),
+            index => self.streams[index - 1].io.ready(readable, writable),
+        }
+    }
+
+    fn completed(&mut self, index: usize, result: i32) {
+        self.streams[index - 1].completion = Some(result);
+    }
+
+    fn poll(&mut self, sources: &Sources) -> bool {
+        if !self.started {
+            self.started = true;
+            if let Err(err) = self.ctrl.register(0, sources) {
+                self.stop(BaguaNetError::from(err), false);
+            }
+            for (stream_id, stream) in self.streams.iter_mut().enumerate() {
+                if let Err(err) = stream.io.register(stream_id + 1, sources) {
+                    stream.fail(BaguaNetError::from(err));
+                }
+            }
+        }
+
+        if let Some(growth) = &mut self.growth {
+            growth.adopt();
+        }
+        // Read into once the master stream is readable again.
+        if let Some(chunk) = self.ctrl_inline.as_mut().filter(|chunk| chunk.gave_up()) {
+            chunk.lend_or_discard();
+        }
+        let ctrl_readable = self.ctrl.readable;
+        loop {
+            self.take_tasks();
+            self.read_ctrl(sources);
+            let can_take = !self.backlogged()
+                && self
+                    .msg_receiver
+                    .as_ref()
+                    .is_some_and(|msg_receiver| !msg_receiver.is_empty());
+            if !can_take {
+                break;
+            }
+        }
+        // Read until it would block.
+        if ctrl_readable && !self.ctrl.readable && self.quickack != QuickAck::Off {
+            rearm_quickack(&self.ctrl.stream);
+        }
+        for (stream_id, stream) in self.streams.iter_mut().enumerate() {
+            let received = stream.received;
+            stream.progress(
+                stream_id + 1,
+                sources,
+                &self.metrics,
+                &self.peer_nbytes_gauge,
+                self.compression.as_ref(),
+                self.placements.as_mut(),
+                self.next_message,
+            );
+            if stream.received != received && self.quickack == QuickAck::All {
+                rearm_quickack(&stream.io.stream);
+            }
+        }
+        self.fail_stolen();
+        self.stream_fds
+            .record(&mut self.streams, |stream| &mut stream.io);
+
+        // Lets the data streams finish the chunks they have.
+        self.msg_receiver.is_some()
+            || !self.tasks.is_empty()
+            || self.ctrl_inline.is_some()
+            || self.ctrl_crc.is_some()
+            || self.streams.iter().any(|stream| !stream.chunks.is_empty())
+            || self
+                .placements
+                .as_ref()
+                .is_some_and(|placements| !placements.is_empty())
+    }
+
+    fn finish(self: Box<Self>, sources: &Sources) {
+        let RecvDriver {
+            comm_uuid,
+            mut ctrl,
+            streams,
+            capabilities,
+            reusable,
+            cache,
+            listen_addr,
+            ..
+        } = *self;
+        ctrl.deregister(sources);
+        let data_streams: Option<Vec<Stream>> = streams
+            .into_iter()
+            .map(|mut stream| {
+                stream.io.deregister(sources);
+                (stream.err.is_none() && stream.reset.is_none()).then_some(stream.io.stream)
+            })
+            .collect();
+        // Whether the sender parked its end shows on the master stream
+        // later, if it did not already.
+        if let (Some(cache), Some(data_streams), true) = (cache, data_streams, reusable) {
+            let group = StreamGroup {
+                comm_uuid,
+                data_streams,
+                ctrl_stream: ctrl.stream,
+                capabilities,
+                peer_rcvbuf: 0,
+            };
+            cache
+                .lock()
+                .unwrap()
+                .park(listen_addr, ParkedStreams::new(group, None));
+        }
+    }
+}
+
+/// The streams of a closed comm, kept open for the next comm between the
+/// same peers.
+struct ParkedStreams {
+    group: StreamGroup,
+    /// Of the sender, see `connection::reconnect_handle`.
+    reconnect_handle: Option<SocketHandle>,
+    parked_at: Instant,
+    /// A master stream message the receiver read part of.
+    ctrl_buf: [u8; 8],
+    ctrl_pos: usize,
+}
+
+impl ParkedStreams {
+    fn new(group: StreamGroup, reconnect_handle: Option<SocketHandle>) -> ParkedStreams {
+        ParkedStreams {
+            group,
+            reconnect_handle,
+            parked_at: Instant::now(),
+            ctrl_buf: [0u8; 8],
+            ctrl_pos: 0,
+        }
+    }
+
+    /// Run by the sender, nothing is ever sent back on its streams unless
+    /// they are closed or reset.
+    fn is_alive(&mut self) -> bool {
+        let group = &mut self.group;
+        std::iter::once(&mut group.ctrl_stream)
+            .chain(group.data_streams.iter_mut())
+            .all(|stream| {
+                matches!(
+                    stream.read(&mut [0u8; 1][..]),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock
+                )
+            })
+    }
+
+    /// Run by the receiver, the next master stream message if it came in
+    /// completely.
+    fn poll_ctrl_message(&mut self) -> io::Result<Option<usize>> {
+        loop {
+            if self.ctrl_pos == self.ctrl_buf.len() {
+                self.ctrl_pos = 0;
+                return Ok(Some(usize::from_be_bytes(self.ctrl_buf)));
+            }
+            match self
+                .group
+                .ctrl_stream
+                .read(&mut self.ctrl_buf[self.ctrl_pos..])
+            {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.ctrl_pos += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Parked comms, oldest first. Those older than `ttl` are closed, and only
+/// the newest `capacity` are kept.
+struct ParkedComms<K> {
+    entries: VecDeque<(K, ParkedStreams)>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl<K: PartialEq> ParkedComms<K> {
+    fn new(capacity: usize, ttl: Duration) -> ParkedComms<K> {
+        ParkedComms {
+            entries: VecDeque::new(),
+            capacity,
+            ttl,
+        }
+    }
+
+    fn expire(&mut self) {
+        while let Some((_, parked)) = self.entries.front() {
+            if parked.parked_at.elapsed() <= self.ttl {
+                break;
+            }
+            self.entries.pop_front();
+        }
+    }
+
+    fn park(&mut self, key: K, parked: ParkedStreams) {
+        self.expire();
+        while !self.entries.is_empty() && self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        if self.capacity > 0 {
+            self.entries.push_back((key, parked));
+        }
+    }
+
+    /// The newest comm parked under `key`.
+    fn take(&mut self, key: &K) -> Option<ParkedStreams> {
+        self.expire();
+        let index = self.entries.iter().rposition(|(k, _)| k == key)?;
+        self.entries.remove(index).map(|(_, parked)| parked)
+    }
+
+    /// The oldest comm parked under `key` whose sender revived it. Drops
+    /// those the sender closed meanwhile.
+    fn take_revived(&mut self, key: &K) -> Option<ParkedStreams> {
+        self.expire();
+        let mut index = 0;
+        while index < self.entries.len() {
+            let (k, parked) = &mut self.entries[index];
+            if k != key {
+                index += 1;
+                continue;
+            }
+            match parked.poll_ctrl_message() {
+                Ok(Some(connection::REVIVE_NBYTES)) => {
+                    return self.entries.remove(index).map(|(_, parked)| parked);
+                }
+                // Read after the comm was closed by the receiver.
+                Ok(Some(connection::PARK_NBYTES)) => {}
+                Ok(None) => index += 1,
+                Ok(Some(nbytes)) => {
+                    tracing::debug!(
+                        "drop parked recv comm {}, message of {} bytes",
+                        parked.group.comm_uuid,
+                        nbytes
+                    );
+                    self.entries.remove(index);
+                }
+                Err(err) => {
+                    tracing::debug!(
+                        "drop parked recv comm {}, err={:?}",
+                        parked.group.comm_uuid,
+                        err
+                    );
+                    self.entries.remove(index);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Empty if the node is not known.
+fn numa_node_cpus(numa_node: Option<usize>) -> Vec<usize> {
+    numa_node
+        .and_then(utils::get_numa_node_cpus)
+        .unwrap_or_default()
+}
+
+fn log_comm_placement(kind: &str, comm_uuid: Uuid, numa_node: Option<usize>, waker: &DriverWaker) {
+    if numa_node.is_some() || !waker.cpus().is_empty() {
+        tracing::info!(
+            "{} comm {} of NUMA node {:?} is driven on CPUs {:?}",
+            kind,
+            comm_uuid,
+            numa_node,
+            waker.cpus()
+        );
+    }
+}
+
+/// The device and the listener's address, and its hostname since the IPs of
+/// a handle with one are unspecified.
+type SendCommKey = (usize, SockAddr, Option<String>);
+type SendCommCache = Option<Arc<Mutex<ParkedComms<SendCommKey>>>>;
+type RecvCommCache = Option<Arc<Mutex<ParkedComms<SockAddr>>>>;
+
+fn conn_caches(config: &ConnCacheConfig) -> (SendCommCache, RecvCommCache) {
+    if !config.enabled {
+        return (None, None);
+    }
+    (
+        Some(Arc::new(Mutex::new(ParkedComms::new(
+            config.capacity,
+            config.ttl,
+        )))),
+        Some(Arc::new(Mutex::new(ParkedComms::new(
+            config.capacity,
+            config.ttl * 2,
+        )))),
+    )
+}
+
+/// The established streams of a send comm.
+struct SendStreams {
+    comm_uuid: Uuid,
+    reconnect_handle: Option<SocketHandle>,
+    streams: Vec<SendStream>,
+    ctrl_stream: Stream,
+    capabilities: u32,
+    peer_rcvbuf: usize,
+}
+
+fn connect_streams(
+    socket_handle: &SocketHandle,
+    nstreams: usize,
+    connect_config: &ConnectConfig,
+    reconnect_config: &ReconnectConfig,
+    wait_mode: WaitMode,
+) -> Result<SendStreams, BaguaNetError> {
+    let comm_uuid = Uuid::new_v4();
+    let (ctrl_stream, peer) =
+        connection::connect_ctrl_stream(socket_handle, comm_uuid, nstreams, connect_config)?;
+    let reconnect_handle = if peer.reconnect_port != 0 && reconnect_config.retries > 0 {
+        Some(connection::reconnect_handle(
+            socket_handle,
+            peer.reconnect_port as u16,
+        )?)
+    } else {
+        None
+    };
+
+    let data_streams =
+        connection::connect_data_streams(socket_handle, comm_uuid, nstreams, connect_config)?;
+
+    Ok(send_streams(
+        StreamGroup {
+            comm_uuid,
+            data_streams,
+            ctrl_stream,
+            capabilities: peer.capabilities,
+            peer_rcvbuf: peer.rcvbuf as usize,
+        },
+        reconnect_handle,
+        connect_config,
+        reconnect_config,
+        wait_mode,
+    ))
+}
+
+/// Takes over the streams of a closed send comm, unless the receiver closed
+/// them meanwhile.
+fn revive_streams(
+    mut parked: ParkedStreams,
+    connect_config: &ConnectConfig,
+    reconnect_config: &ReconnectConfig,
+    wait_mode: WaitMode,
+) -> Result<SendStreams, BaguaNetError> {
+    let comm_uuid = parked.group.comm_uuid;
+    if !parked.is_alive() {
+        return Err(BaguaNetError::remote(format!(
+            "parked send comm {} was closed by the receiver",
+            comm_uuid
+        )));
+    }
+    let revive_nbytes = connection::REVIVE_NBYTES.to_be_bytes();
+    match utils::nonblocking_write_all(&mut parked.group.ctrl_stream, &revive_nbytes[..], wait_mode)
+    {
+        IoOutcome::Done => {}
+        IoOutcome::PeerClosed => {
+            return Err(BaguaNetError::remote(format!(
+                "parked send comm {} was closed by the receiver",
+                comm_uuid
+            )))
+        }
+        IoOutcome::Fatal(err) => {
+            return Err(BaguaNetError::system_io(
+                format!("revive send comm {}", comm_uuid),
+                err,
+            ))
+        }
+    }
+
+    Ok(send_streams(
+        parked.group,
+        parked.reconnect_handle,
+        connect_config,
+        reconnect_config,
+        wait_mode,
+    ))
+}
+
+fn send_streams(
+    group: StreamGroup,
+    reconnect_handle: Option<SocketHandle>,
+    connect_config: &ConnectConfig,
+    reconnect_config: &ReconnectConfig,
+    wait_mode: WaitMode,
+) -> SendStreams {
+    let StreamGroup {
+        comm_uuid,
+        data_streams,
+        ctrl_stream,
+        capabilities,
+        peer_rcvbuf,
+    } = group;
+    ctrl_stream.set_nodelay(true).unwrap();
+    ctrl_stream.set_nonblocking(true).unwrap();
+
+    let mut streams = Vec::new();
+    for (stream_id, stream) in data_streams.into_iter().enumerate() {
+        let handshake = StreamHandshake {
+            comm_uuid,
+            stream_id,
+        };
+        stream.set_nodelay(true).unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        // Unix sockets are not reset under a live peer.
+        let reconnect = match (&reconnect_handle, &stream) {
+            (Some(reconnect_handle), stream) if !stream.is_unix() => Some(Reconnect {
+                socket_handle: reconnect_handle.clone(),
+                handshake,
+                connect_config: ConnectConfig {
+                    unix_peer: None,
+                    ..connect_config.clone()
+                },
+                config: reconnect_config.clone(),
+                window: ReplayWindow::new(reconnect_config.window_bytes(
+                    stream,
+                    &connect_config.buffers,
+                    peer_rcvbuf,
+                )),
+                wait_mode,
+            }),
+            _ => None,
+        };
+        streams.push(SendStream::new(stream, reconnect));
+    }
+
+    SendStreams {
+        comm_uuid,
+        reconnect_handle,
+        streams,
+        ctrl_stream,
+        capabilities,
+        peer_rcvbuf,
+    }
+}
+
+impl Net for BaguaNet {
+    fn devices(&self) -> Result<usize, BaguaNetError> {
+        Ok(self.socket_devs.len())
+    }
+
+    fn get_properties(&self, dev_id: usize) -> Result<NCCLNetProperties, BaguaNetError> {
+        let socket_dev = self.socket_dev(dev_id)?;
+        let speed = utils::get_socket_dev_speed(socket_dev);
+
+        Ok(NCCLNetProperties {
+            name: socket_dev.interface_name.clone(),
+            pci_path: socket_dev.pci_path.clone(),
+            guid: dev_id as u64,
+            ptr_support: if self.staging.is_some() {
+                NCCL_PTR_HOST | NCCL_PTR_CUDA
+            } else {
+                NCCL_PTR_HOST
+            },
+            speed,
+            port: 0,
+            max_comms: BaguaNet::DEFAULT_SOCKET_MAX_COMMS,
+            latency_us: self
+                .latencies_us
+                .get(&socket_dev.interface_name)
+                .copied()
+                .unwrap_or_else(|| utils::estimate_latency_us(speed)),
+            mtu: utils::get_net_if_mtu(&socket_dev.interface_name),
+            numa_node: utils::get_socket_dev_numa_node(socket_dev).map_or(-1, |node| node as i32),
+        })
+    }
+
+    fn listen(
+        &mut self,
+        dev_id: usize,
+    ) -> Result<(SocketHandle, SocketListenCommID), BaguaNetError> {
+        self.check_running()?;
+        let socket_dev = self.socket_dev(dev_id)?;
+        let addr = match socket_dev.addr {
+            SockAddr::Inet(inet_addr) => inet_addr,
+            others => {
+                return Err(BaguaNetError::InnerError(format!(
+                    "Got invalid socket address, which is {:?}",
+                    others
+                )))
+            }
+        };
+
+        let mut listener = connection::bind_listener(addr, &self.listen_config)?;
+        if let Some(SockAddr::Inet(alt_addr)) = socket_dev.alt_addr {
+            listener.bind_alt(alt_addr, &self.listen_config);
+        }
+        let numa_node = utils::get_socket_dev_numa_node(socket_dev);
+        let reconnect_acceptor = if self.reconnect_config.retries > 0 {
+            let reconnect_acceptor = match self.reconnect_acceptors.get(&dev_id) {
+                Some(reconnect_acceptor) => reconnect_acceptor.clone(),
+                None => {
+                    let reconnect_acceptor = Arc::new(ReconnectAcceptor::spawn(
+                        &self.threads,
+                        addr,
+                        &self.listen_config,
+                        &self.accept_config,
+                    )?);
+                    self.reconnect_acceptors
+                        .insert(dev_id, reconnect_acceptor.clone());
+                    reconnect_acceptor
+                }
+            };
+            listener.reconnect_port = reconnect_acceptor.port;
+            Some(reconnect_acceptor)
+        } else {
+            None
+        };
+        let socket_handle = listener.socket_handle()?;
+        let id = self.listen_comm_map.next_id()?;
+        self.listen_comm_map.insert(SocketListenComm {
+            addr: socket_handle.addr,
+            closer: listener.closer.clone(),
+            listener: Arc::new(Mutex::new(listener)),
+            pending_streams: Default::default(),
+            reconnect_acceptor,
+            dev_id,
+            numa_node,
+        })?;
+        connection_event!(
+            INFO,
+            listen_comm = id,
+            dev = dev_id,
+            addr = %socket_handle.addr,
+            "listening"
+        );
+
+        Ok((socket_handle, id))
+    }
+
+    fn connect(
+        &mut self,
+        dev_id: usize,
+        socket_handle: SocketHandle,
+    ) -> Result<SocketSendCommID, BaguaNetError> {
+        self.check_running()?;
+        let connect_config = self.connect_config_of(dev_id, &socket_handle)?;
+        let (msg_sender, msg_receiver) = flume::bounded::<LaneTask>(self.queue_capacity);
+        let (priority_sender, priority_receiver) = flume::bounded(self.queue_capacity);
+        let queue_depth = Arc::new(SendQueueDepth::default());
+        let msg_receiver = SendLanes::new([msg_receiver, priority_receiver], queue_depth.clone());
+        // Only inlined messages skip the chunks before them.
+        let priority_threshold = self.priority_threshold.min(connect_config.inline_threshold);
+        let connect_state = Arc::new(Mutex::new(ConnectState::Connecting));
+        let nstreams = self.nstreams;
+        let min_chunksize = self.min_chunksize;
+        let split_tuner = self.split_tuner.clone();
+        let chunk_bytes = self.chunk_bytes;
+        let queue_capacity = self.queue_capacity;
+        let adaptive_streams = self.adaptive_streams.clone();
+        let imbalance = self.imbalance.clone();
+        // Mbps, in bytes per second.
+        let link_speed = utils::get_socket_dev_speed(self.socket_dev(dev_id)?) as f64 * 1e6 / 8.;
+        let metrics = self.state.clone();
+        let reconnect_config = self.reconnect_config.clone();
+        let wait_mode = self.wait_mode;
+        let zerocopy_threshold = self.zerocopy_threshold;
+        let staging = self.staging.clone();
+        let compression = self
+            .compression
+            .clone()
+            .filter(|_| self.connect_config.compression);
+        let send_comm_cache = self.send_comm_cache.clone();
+        let cache_key = (dev_id, socket_handle.addr, socket_handle.hostname.clone());
+        let parked = send_comm_cache
+            .as_ref()
+            .and_then(|cache| cache.lock().unwrap().take(&cache_key));
+        let numa_node = utils::get_socket_dev_numa_node(self.socket_dev(dev_id)?);
+        let thread_cpus = match self.numa_affinity {
+            true => numa_node_cpus(numa_node),
+            false => Vec::new(),
+        };
+        let waker = self.waker_on(numa_node);
+        let fixed_size = Arc::new(Mutex::new(None));
+        let max_bandwidth = Arc::new(Mutex::new(self.max_bandwidth));
+        let id = self.send_comm_map.next_id()?;
+        let peer_nbytes_gauge = metrics
+            .isend_peer_nbytes
+            .of(socket_handle.addr.to_string(), id);
+        let device = metrics.open_on_device(dev_id, &self.socket_dev(dev_id)?.interface_name);
+        let (finished, driver_finished) = flume::bounded(0);
+        self.send_comm_map.insert(SocketSendComm {
+            waker: waker.clone(),
+            msg_senders: [msg_sender, priority_sender],
+            priority_threshold,
+            nposted: 0,
+            connect_state: connect_state.clone(),
+            fixed_size: fixed_size.clone(),
+            posted: false,
+            max_bandwidth: max_bandwidth.clone(),
+            copies: VecDeque::new(),
+            health: Default::default(),
+            inflight: Default::default(),
+            peer: socket_handle.addr.to_string(),
+            opened_at: Instant::now(),
+            tally: Arc::new(Tally::of_comm(&device.0.sent)),
+            dev_id,
+            _device: device,
+            queue_depth: queue_depth.clone(),
+            finished: driver_finished,
+        })?;
+        metrics.send_queues.open(id, queue_depth.clone());
+        let stream_fds = metrics.open_tcp_streams(CommKind::Send, id);
+
+        let connect_started = Instant::now();
+        std::thread::spawn(move || {
+            // What it allocates for the comm is on the node too.
+            if !thread_cpus.is_empty() {
+                if let Err(err) = utils::set_thread_affinity(&thread_cpus) {
+                    tracing::warn!("failed to run on CPUs {:?}, err={:?}", thread_cpus, err);
+                }
+            }
+            let revived = parked.and_then(|parked| {
+                match revive_streams(parked, &connect_config, &reconnect_config, wait_mode) {
+                    Ok(streams) => {
+                        tracing::debug!("revived send comm {}", streams.comm_uuid);
+                        Some(streams)
+                    }
+                    Err(err) => {
+                        tracing::debug!("{:?}, connect a new one", err);
+                        None
+                    }
+                }
+            });
+            // The kernel numbers the zerocopy sends of a socket from its
+            // first, those of revived ones are not known.
+            let fresh = revived.is_none();
+            let streams = match revived {
+                Some(streams) => Ok(streams),
+                None => connect_streams(
+                    &socket_handle,
+                    nstreams,
+                    &connect_config,
+                    &reconnect_config,
+                    wait_mode,
+                ),
+            };
+            let SendStreams {
+                comm_uuid,
+                reconnect_handle,
+                mut streams,
+                ctrl_stream,
+                capabilities,
+                peer_rcvbuf,
+            } = match streams {
+                Ok(streams) => streams,
+                Err(err) => {
+                    connection_event!(
+                        WARN,
+                        send_comm = id,
+                        peer = %socket_handle.addr,
+                        duration_us = connect_started.elapsed().as_micros() as u64,
+                        error = %err,
+                        "send comm connect failed"
+                    );
+                    *utils::lock(&connect_state) = ConnectState::Failed(err.clone());
+                    for (_, state, _) in msg_receiver.drain() {
+                        state.fail(err.clone());
+                    }
+                    return;
+                }
+            };
+
+            if let (Some(threshold), true) = (zerocopy_threshold, fresh) {
+                for stream in streams.iter_mut() {
+                    stream.enable_zerocopy(threshold);
+                }
+            }
+
+            // Grown streams are accepted like replacements, over TCP.
+            let grower = match (adaptive_streams, &reconnect_handle) {
+                (Some(config), Some(reconnect_handle))
+                    if streams.iter().all(|stream| stream.reconnect.is_some()) =>
+                {
+                    Some(StreamGrower {
+                        window: GrowthWindow::new(config, link_speed),
+                        reconnect_handle: reconnect_handle.clone(),
+                        connect_config: ConnectConfig {
+                            unix_peer: None,
+                            ..connect_config.clone()
+                        },
+                        reconnect_config: reconnect_config.clone(),
+                        peer_rcvbuf,
+                        wait_mode,
+                        opening: None,
+                    })
+                }
+                _ => None,
+            };
+            metrics
+                .send_comm_nstreams_gauge
+                .record(streams.len() as u64);
+            connection_event!(
+                INFO,
+                send_comm = id,
+                comm_uuid = %comm_uuid,
+                peer = %socket_handle.addr,
+                nstreams = streams.len(),
+                revived = !fresh,
+                duration_us = connect_started.elapsed().as_micros() as u64,
+                "send comm connected"
+            );
+            log_comm_placement("send", comm_uuid, numa_node, &waker);
+            let (replaced, replacements) = flume::unbounded();
+            waker.start(SendDriver {
+                comm_uuid,
+                reconnect_handle,
+                msg_receiver: Some(msg_receiver),
+                ctrl: DrivenStream::new(ctrl_stream),
+                ctrl_queue: VecDeque::new(),
+                ctrl_broken: false,
+                streams,
+                downstream_id: 0,
+                capabilities,
+                peer_rcvbuf,
+                injector: (capabilities & CommHandshake::WORK_STEALING != 0).then(VecDeque::new),
+                replacer: Replacer {
+                    waker: waker.clone(),
+                    replaced,
+                },
+                replacements,
+                grower,
+                imbalance: imbalance.map(ImbalanceWindow::new),
+                zerocopy_threshold,
+                started: false,
+                min_chunksize,
+                split_tuner,
+                timed: Vec::new(),
+                chunk_bytes,
+                queue_capacity,
+                seq_check: connect_config.seq_check,
+                next_message: 0,
+                taken: 0,
+                cancellable: VecDeque::new(),
+                crc: connect_config.crc,
+                compression,
+                inline_threshold: connect_config.inline_threshold,
+                fixed_size,
+                fixed: FixedSize::Unknown,
+                fixed_ack: [0u8; 8],
+                fixed_ack_pos: 0,
+                fixed_sent: 0,
+                staging,
+                bucket: TokenBucket::new(max_bandwidth),
+                metrics,
+                peer_nbytes_gauge,
+                queue_depth,
+                stream_fds: RecordedFds(stream_fds),
+                cache: send_comm_cache,
+                cache_key,
+                finished,
+            });
+            *utils::lock(&connect_state) = ConnectState::Connected;
+        });
+
+        Ok(id)
+    }
+
+    fn connect_test(&mut self, send_comm_id: SocketSendCommID) -> Result<bool, BaguaNetError> {
+        let send_comm = self
+            .send_comm_map
+            .get(send_comm_id)
+            .ok_or_else(|| slab::unknown("send comm", send_comm_id))?;
+        let connect_state = utils::lock(&send_comm.connect_state);
+        match &*connect_state {
+            ConnectState::Connecting => Ok(false),
+            ConnectState::Connected => Ok(true),
+            ConnectState::Failed(err) => Err(err.clone()),
+        }
+    }
+
+    fn accept(
+        &mut self,
+        listen_comm_id: SocketListenCommID,
+    ) -> Result<Option<SocketRecvCommID>, BaguaNetError> {
+        let listen_comm = self
+            .listen_comm_map
+            .get(listen_comm_id)
+            .ok_or_else(|| slab::unknown("listen comm", listen_comm_id))?;
+        let revived = self
+            .recv_comm_cache
+            .as_ref()
+            .and_then(|cache| cache.lock().unwrap().take_revived(&listen_comm.addr));
+        if let Some(parked) = revived {
+            tracing::debug!("revived recv comm {}", parked.group.comm_uuid);
+            return Ok(Some(self.spawn_recv_comm(listen_comm_id, parked.group)?));
+        }
+        let group = connection::accept_stream_group(
+            &utils::lock(&listen_comm.listener),
+            &mut utils::lock(&listen_comm.pending_streams),
+            self.nstreams,
+            &self.accept_config,
+        )?;
+        let group = match group {
+            Some(group) => group,
+            None => return Ok(None),
+        };
+
+        Ok(Some(self.spawn_recv_comm(listen_comm_id, group)?))
+    }
+
+    fn reg_mr(
+        &mut self,
+        data: &'static [u8],
+        ptr_type: PtrType,
+    ) -> Result<MrHandle, BaguaNetError> {
+        if ptr_type == PtrType::Cuda && self.staging.is_none() {
+            return Err(BaguaNetError::InvalidArgument(format!(
+                "{:?} memory is not supported",
+                ptr_type
+            )));
+        }
+        self.mr_registry.lock().unwrap().register(data, ptr_type)
+    }
+
+    fn dereg_mr(&mut self, handle: MrHandle) -> Result<(), BaguaNetError> {
+        self.mr_registry.lock().unwrap().deregister(handle)
+    }
+
+    fn isend(
+        &mut self,
+        send_comm_id: SocketSendCommID,
+        data: SendBuffer,
+        mr: Option<MrHandle>,
+    ) -> Result<SocketRequestID, BaguaNetError> {
+        self.check_buffer(mr, data.as_raw())?;
+        self.post_send(send_comm_id, SendData::Contiguous(data))
+    }
+
+    fn isend_v(
+        &mut self,
+        send_comm_id: SocketSendCommID,
+        iovs: &[SendBuffer],
+    ) -> Result<SocketRequestID, BaguaNetError> {
+        for iov in iovs {
+            self.check_buffer(None, iov.as_raw())?;
+        }
+        let mut buffers: Vec<SendBuffer> =
+            iovs.iter().copied().filter(|iov| !iov.is_empty()).collect();
+        let data = match buffers.len() {
+            0 => SendData::Contiguous(SendBuffer::from(&[][..])),
+            1 => SendData::Contiguous(buffers.pop().unwrap()),
+            _ => SendData::Gather(buffers.into_boxed_slice()),
+        };
+        self.post_send(send_comm_id, data)
+    }
+
+    fn irecv(
+        &mut self,
+        recv_comm_id: SocketRecvCommID,
+        data: RecvBuffer,
+        mr: Option<MrHandle>,
+    ) -> Result<SocketRequestID, BaguaNetError> {
+        self.check_buffer(mr, data.as_raw())?;
+        self.post_recv(recv_comm_id, vec![data])
+    }
+
+    fn irecv_multi(
+        &mut self,
+        recv_comm_id: SocketRecvCommID,
+        bufs: Vec<RecvBuffer>,
+    ) -> Result<SocketRequestID, BaguaNetError> {
+        if bufs.is_empty() {
+            return Err(BaguaNetError::InvalidArgument(
+                "no buffers to receive into".to_owned(),
+            ));
+        }
+        for data in bufs.iter() {
+            self.check_buffer(None, data.as_raw())?;
+        }
+        self.post_recv(recv_comm_id, bufs)
+    }
+
+    fn iflush(
+        &mut self,
+        recv_comm_id: SocketRecvCommID,
+        data: *const [u8],
+    ) -> Result<Option<SocketRequestID>, BaguaNetError> {
+        let recv_comm = self
+            .recv_comm_map
+            .get(recv_comm_id)
+            .ok_or_else(|| slab::unknown("recv comm", recv_comm_id))?;
+        // Host buffers are received into directly.
+        match &self.staging {
+            Some(staging) if staging.is_device(data) => {}
+            _ => return Ok(None),
+        }
+        let range = address_range(data);
+        let flushed: Vec<Arc<RequestState>> = self
+            .socket_request_map
+            .iter()
+            .filter_map(|(_, request)| match request {
+                SocketRequest::RecvRequest(recv_req)
+                    if recv_req.recv_comm_id == recv_comm_id
+                        && recv_req
+                            .bufs
+                            .iter()
+                            .any(|buf| buf.start < range.end && range.start < buf.end) =>
+                {
+                    Some(recv_req.state.clone())
+                }
+                _ => None,
+            })
+            .collect();
+
+        let mut span = RequestSpan::start(self.span_sampler, &self.trace_span_context, || {
+            format!("iflush-{}", recv_comm_id)
+        });
+        let id = self.socket_request_map.next_id()?;
+        span.set_attribute(KeyValue::new("id", id as i64));
+        span.set_attribute(KeyValue::new("nflushed", flushed.len() as i64));
+        let mut request = SocketRecvRequest {
+            state: Arc::new(RequestState::new(1)),
+            trace_span: span,
+            posted_at: Instant::now(),
+            health: recv_comm.health.clone(),
+            inflight: None,
+            sizes: Arc::new([]),
+            recv_comm_id,
+            bufs: Box::new([]),
+            flushed: Vec::new(),
+        };
+        match flushed.is_empty() {
+            true => request.state.complete_subtask(0),
+            false => request.flushed = flushed,
+        }
+        self.socket_request_map
+            .insert(SocketRequest::RecvRequest(request))?;
+        count_live_requests(&self.state, &self.socket_request_map);
+        Ok(Some(id))
+    }
+
+    fn test(&mut self, request_id: SocketRequestID) -> Result<(bool, usize), BaguaNetError> {
+        let request_timeout = self.request_timeout;
+        let request = match self.socket_request_map.get_mut(request_id) {
+            Some(request) => request,
+            None => {
+                return match self.recently_done.take(request_id) {
+                    Some(nbytes) => Ok((true, nbytes)),
+                    None => Err(slab::unknown("request", request_id)),
+                }
+            }
+        };
+        let ret = match request {
+            SocketRequest::SendRequest(send_req) => {
+                let (task_completed, nbytes_transferred) = send_req.state.progress();
+                if let Some(err) = send_req.state.err() {
+                    // Failed requests stay until they are cancelled.
+                    send_req.inflight = None;
+                    send_req.trace_span.set_attribute(KeyValue::new(
+                        "error",
+                        telemetry::capped_attribute(format!("{:?}", err)),
+                    ));
+                    send_req.trace_span.end();
+                    return Err(err);
+                }
+                if !task_completed {
+                    if let Err(err) = check_deadline(
+                        request_timeout,
+                        send_req.posted_at,
+                        &send_req.state,
+                        &send_req.health,
+                        &mut send_req.trace_span,
+                    ) {
+                        send_req.inflight = None;
+                        return Err(err);
+                    }
+                }
+
+                if task_completed {
+                    let timing = send_req.state.timing();
+                    record_latency(&self.state.isend_latency_gauge, timing);
+                    set_timing(&mut send_req.trace_span, timing);
+                    send_req.trace_span.end();
+                }
+                Ok((task_completed, nbytes_transferred))
+            }
+            SocketRequest::RecvRequest(recv_req) => {
+                recv_req.settle_flush();
+                let (task_completed, mut nbytes_transferred) = recv_req.state.progress();
+                if let Some(err) = recv_req.state.err() {
+                    // Failed requests stay until they are cancelled.
+                    recv_req.inflight = None;
+                    recv_req.trace_span.set_attribute(KeyValue::new(
+                        "error",
+                        telemetry::capped_attribute(format!("{:?}", err)),
+                    ));
+                    recv_req.trace_span.end();
+                    return Err(err);
+                }
+                if !task_completed {
+                    if let Err(err) = check_deadline(
+                        request_timeout,
+                        recv_req.posted_at,
+                        &recv_req.state,
+                        &recv_req.health,
+                        &mut recv_req.trace_span,
+                    ) {
+                        recv_req.inflight = None;
+                        return Err(err);
+                    }
+                }
+
+                if task_completed {
+                    // What the senders declared, the buffers may be larger.
+                    nbytes_transferred = recv_req.received_size().unwrap_or(nbytes_transferred);
+                    if nbytes_transferred == 0 && !recv_req.bufs.is_empty() {
+                        recv_req
+                            .trace_span
+                            .set_attribute(KeyValue::new("zero_byte", true));
+                    }
+                    let timing = recv_req.state.timing();
+                    if !recv_req.bufs.is_empty() {
+                        record_latency(&self.state.irecv_latency_gauge, timing);
+                    }
+                    set_timing(&mut recv_req.trace_span, timing);
+                    recv_req.trace_span.end();
+                }
+                Ok((task_completed, nbytes_transferred))
+            }
+        };
+
+        if let Ok(ret) = ret {
+            if ret.0 {
+                self.socket_request_map.remove(request_id).unwrap();
+                count_live_requests(&self.state, &self.socket_request_map);
+                self.recently_done.push(request_id, ret.1);
+            }
+        }
+
+        ret
+    }
+
+    fn test_sizes(
+        &mut self,
+        request_id: SocketRequestID,
+    ) -> Result<(bool, Vec<usize>), BaguaNetError> {
+        let sizes = match self.socket_request_map.get(request_id) {
+            Some(SocketRequest::RecvRequest(recv_req)) => Some(recv_req.sizes.clone()),
+            _ => None,
+        };
+        let (done, nbytes) = self.test(request_id)?;
+        let sizes = match sizes {
+            Some(sizes) => sizes
+                .iter()
+                .map(|size| match size.load(Ordering::Relaxed) {
+                    UNDECLARED => 0,
+                    nbytes => nbytes,
+                })
+                .collect(),
+            None => vec![nbytes],
+        };
+        Ok((done, sizes))
+    }
+
+    fn received_size(
+        &mut self,
+        request_id: SocketRequestID,
+    ) -> Result<Option<usize>, BaguaNetError> {
+        match self
+            .socket_request_map
+            .get(request_id)
+            .ok_or_else(|| slab::unknown("request", request_id))?
+        {
+            SocketRequest::RecvRequest(recv_req) => Ok(recv_req.received_size()),
+            SocketRequest::SendRequest(_) => Err(BaguaNetError::InvalidArgument(format!(
+                "request {} is a send",
+                request_id
+            ))),
+        }
+    }
+
+    fn wait(
+        &mut self,
+        request_id: SocketRequestID,
+        timeout: Option<Duration>,
+    ) -> Result<usize, BaguaNetError> {
+        let request = match self.socket_request_map.get(request_id) {
+            Some(request) => request,
+            None => {
+                return self
+                    .recently_done
+                    .take(request_id)
+                    .ok_or_else(|| slab::unknown("request", request_id))
+            }
+        };
+        let (states, posted_at) = match request {
+            SocketRequest::SendRequest(send_req) => {
+                (vec![send_req.state.clone()], send_req.posted_at)
+            }
+            // A flush is settled by `test()`, once what it waits for is done.
+            SocketRequest::RecvRequest(recv_req) if !recv_req.flushed.is_empty() => {
+                (recv_req.flushed.clone(), recv_req.posted_at)
+            }
+            SocketRequest::RecvRequest(recv_req) => {
+                (vec![recv_req.state.clone()], recv_req.posted_at)
+            }
+        };
+        // No longer than until the request times out, which `test()` fails.
+        let timeout = match (timeout, self.request_timeout) {
+            (timeout, None) => timeout,
+            (timeout, Some(request_timeout)) => {
+                let left = request_timeout.saturating_sub(posted_at.elapsed());
+                Some(timeout.map_or(left, |timeout| timeout.min(left)))
+            }
+        };
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        for state in states {
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if !state.wait(timeout) {
+                return self.timed_out(request_id);
+            }
+        }
+        let (_, nbytes) = self.test(request_id)?;
+        Ok(nbytes)
+    }
+
+    /// Not for a flush, which only `test()` settles.
+    fn set_completion_hook(
+        &mut self,
+        request_id: SocketRequestID,
+        hook: CompletionHook,
+    ) -> Result<(), BaguaNetError> {
+        if let SocketRequest::RecvRequest(recv_req) = self
+            .socket_request_map
+            .get(request_id)
+            .ok_or_else(|| slab::unknown("request", request_id))?
+        {
+            if !recv_req.flushed.is_empty() {
+                return Err(BaguaNetError::InvalidArgument(format!(
+                    "request {} is a flush",
+                    request_id
+                )));
+            }
+        }
+        // Called by the state, which a hook does not keep.
+        let end = |mut span: RequestSpan,
+                   state: Weak<RequestState>,
+                   latency_gauge: &Gauge,
+                   ret: &Result<usize, BaguaNetError>| {
+            match ret {
+                Ok(_) => {
+                    let timing = state.upgrade().and_then(|state| state.timing());
+                    record_latency(latency_gauge, timing);
+                    set_timing(&mut span, timing);
+                }
+                Err(err) => span.set_attribute(KeyValue::new(
+                    "error",
+                    telemetry::capped_attribute(format!("{:?}", err)),
+                )),
+            }
+            span.end();
+        };
+        self.hooked
+            .retain(|_, (state, _)| state.upgrade().is_some_and(|state| !state.done()));
+        let request = self.socket_request_map.remove(request_id).unwrap();
+        count_live_requests(&self.state, &self.socket_request_map);
+        let state = match &request {
+            SocketRequest::SendRequest(send_req) => &send_req.state,
+            SocketRequest::RecvRequest(recv_req) => &recv_req.state,
+        };
+        let weak = Arc::downgrade(state);
+        self.hooked
+            .insert(request_id, (weak.clone(), self.driver_of(&request)));
+        let metrics = self.state.clone();
+        match request {
+            SocketRequest::SendRequest(send_req) => {
+                let (span, inflight) = (send_req.trace_span, send_req.inflight);
+                send_req.state.set_hook(Box::new(move |ret| {
+                    drop(inflight);
+                    end(span, weak, &metrics.isend_latency_gauge, &ret);
+                    hook(ret)
+                }));
+            }
+            SocketRequest::RecvRequest(recv_req) => {
+                let (mut span, sizes) = (recv_req.trace_span, recv_req.sizes);
+                let (has_bufs, inflight) = (!recv_req.bufs.is_empty(), recv_req.inflight);
+                recv_req.state.set_hook(Box::new(move |ret| {
+                    drop(inflight);
+                    // What the senders declared, the buffers may be larger.
+                    let ret = ret.map(|nbytes| declared_size(&sizes).unwrap_or(nbytes));
+                    if let (Ok(0), true) = (&ret, has_bufs) {
+                        span.set_attribute(KeyValue::new("zero_byte", true));
+                    }
+                    end(span, weak, &metrics.irecv_latency_gauge, &ret);
+                    hook(ret)
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    fn request_timing(
+        &mut self,
+        request_id: SocketRequestID,
+    ) -> Result<Option<RequestTiming>, BaguaNetError> {
+        let state = match self
+            .socket_request_map
+            .get(request_id)
+            .ok_or_else(|| slab::unknown("request", request_id))?
+        {
+            SocketRequest::SendRequest(send_req) => &send_req.state,
+            SocketRequest::RecvRequest(recv_req) => &recv_req.state,
+        };
+        Ok(state.timing())
+    }
+
+    fn cancel(&mut self, request_id: SocketRequestID) -> Result<(), BaguaNetError> {
+        let request = match self.socket_request_map.remove(request_id) {
+            Some(request) => {
+                count_live_requests(&self.state, &self.socket_request_map);
+                request
+            }
+            // Handed to its hook, unknown like a tested one once it is done.
+            None => {
+                let hooked = self.hooked.remove(&request_id);
+                return match hooked.and_then(|(state, waker)| Some((state.upgrade()?, waker))) {
+                    Some((state, waker)) if !state.done() => {
+                        cancel_lent(&state, waker);
+                        Ok(())
+                    }
+                    _ => Err(slab::unknown("request", request_id)),
+                };
+            }
+        };
+        let waker = self.driver_of(&request);
+        let (state, mut span) = match request {
+            SocketRequest::SendRequest(send_req) => (send_req.state, send_req.trace_span),
+            SocketRequest::RecvRequest(recv_req) => (recv_req.state, recv_req.trace_span),
+        };
+        cancel_lent(&state, waker);
+        span.set_attribute(KeyValue::new("cancelled", true));
+        span.end();
+        Ok(())
+    }
+
+    fn set_fixed_message_size(
+        &mut self,
+        send_comm_id: SocketSendCommID,
+        nbytes: usize,
+    ) -> Result<(), BaguaNetError> {
+        let send_comm = self
+            .send_comm_map
+            .get(send_comm_id)
+            .ok_or_else(|| slab::unknown("send comm", send_comm_id))?;
+        fix_size(
+            &send_comm.fixed_size,
+            send_comm.posted,
+            self.connect_config.crc,
+            nbytes,
+        )
+    }
+
+    fn set_recv_fixed_message_size(
+        &mut self,
+        recv_comm_id: SocketRecvCommID,
+        nbytes: usize,
+    ) -> Result<(), BaguaNetError> {
+        let recv_comm = self
+            .recv_comm_map
+            .get(recv_comm_id)
+            .ok_or_else(|| slab::unknown("recv comm", recv_comm_id))?;
+        fix_size(
+            &recv_comm.fixed_size,
+            recv_comm.nposted > 0,
+            self.accept_config.crc,
+            nbytes,
+        )
+    }
+
+    fn get_statistics(&self) -> Result<NetStatistics, BaguaNetError> {
+        let comm = |kind, id, peer: &String, tally: &Tally, inflight: &AtomicUsize| {
+            let [nbytes, requests_completed, requests_failed] = tally.since_reset();
+            CommStatistics {
+                kind,
+                id,
+                peer: peer.clone(),
+                nbytes,
+                requests_completed,
+                requests_failed,
+                active_requests: inflight.load(Ordering::Relaxed),
+            }
+        };
+        let comms: Vec<CommStatistics> = self
+            .send_comm_map
+            .iter()
+            .map(|(id, send_comm)| {
+                let (peer, tally) = (&send_comm.peer, &send_comm.tally);
+                comm(CommKind::Send, id, peer, tally, &send_comm.inflight)
+            })
+            .chain(self.recv_comm_map.iter().map(|(id, recv_comm)| {
+                let (peer, tally) = (&recv_comm.peer, &recv_comm.tally);
+                comm(CommKind::Recv, id, peer, tally, &recv_comm.inflight)
+            }))
+            .collect();
+        let sent = self.state.sent.since_reset();
+        let received = self.state.received.since_reset();
+        Ok(NetStatistics {
+            bytes_sent: sent[Tally::NBYTES],
+            bytes_received: received[Tally::NBYTES],
+            requests_completed: sent[Tally::COMPLETED] + received[Tally::COMPLETED],
+            requests_failed: sent[Tally::FAILED] + received[Tally::FAILED],
+            active_comms: comms.len(),
+            active_requests: self.state.inflight_requests.load(Ordering::Relaxed) as usize,
+            comms,
+        })
+    }
+
+    fn reset_statistics(&mut self) -> Result<(), BaguaNetError> {
+        self.state.sent.reset();
+        self.state.received.reset();
+        for (_, send_comm) in self.send_comm_map.iter() {
+            send_comm.tally.reset();
+        }
+        for (_, recv_comm) in self.recv_comm_map.iter() {
+            recv_comm.tally.reset();
+        }
+        Ok(())
+    }
+
+    fn set_max_bandwidth(
+        &mut self,
+        send_comm_id: SocketSendCommID,
+        mbps: Option<u64>,
+    ) -> Result<(), BaguaNetError> {
+        let send_comm = self
+            .send_comm_map
+            .get(send_comm_id)
+            .ok_or_else(|| slab::unknown("send comm", send_comm_id))?;
+        *send_comm.max_bandwidth.lock().unwrap() = mbps;
+        send_comm.waker.wake();
+        Ok(())
+    }
+
+    /// Waits for the messages posted to be written out and the streams to be
+    /// closed, or parked. If that takes longer than `close_timeout`, errs
+    /// with the requests not done, which the comm goes on sending meanwhile.
+    fn close_send(&mut self, send_comm_id: SocketSendCommID) -> Result<(), BaguaNetError> {
+        let send_comm = self
+            .send_comm_map
+            .remove(send_comm_id)
+            .ok_or_else(|| slab::unknown("send comm", send_comm_id))?;
+        self.state.send_queues.close(send_comm_id);
+        self.state.close_tcp_streams(CommKind::Send, send_comm_id);
+        let (peer, dev_id, tally, opened_at) = (
+            send_comm.peer.clone(),
+            send_comm.dev_id,
+            send_comm.tally.clone(),
+            send_comm.opened_at,
+        );
+        let finished = send_comm.finished.clone();
+        // Its driver finds the channels closed.
+        drop(send_comm);
+        // Nothing is ever sent on it.
+        let drained = !matches!(
+            finished.recv_timeout(self.close_timeout),
+            Err(flume::RecvTimeoutError::Timeout)
+        );
+        connection_event!(
+            INFO,
+            send_comm = send_comm_id,
+            peer = %peer,
+            dev = dev_id,
+            nbytes = tally.nbytes.load(Ordering::Relaxed),
+            lifetime_ms = opened_at.elapsed().as_millis() as u64,
+            drained,
+            "send comm closed"
+        );
+        if !drained {
+            let outstanding: Vec<SocketRequestID> = self
+                .socket_request_map
+                .iter()
+                .filter_map(|(id, request)| match request {
+                    SocketRequest::SendRequest(send_req)
+                        if send_req.send_comm_id == send_comm_id
+                            && !send_req.state.progress().0 =>
+                    {
+                        Some(id)
+                    }
+                    _ => None,
+                })
+                .collect();
+            return Err(BaguaNetError::remote(format!(
+                "send comm {} was closed with requests {:?} not sent after {:?}",
+                send_comm_id, outstanding, self.close_timeout
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Fails the messages still posted and waits, up to `close_timeout`,
+    /// until none of their buffers is touched anymore.
+    fn close_recv(&mut self, recv_comm_id: SocketRecvCommID) -> Result<(), BaguaNetError> {
+        let recv_comm = self
+            .recv_comm_map
+            .remove(recv_comm_id)
+            .ok_or_else(|| slab::unknown("recv comm", recv_comm_id))?;
+        self.state.close_tcp_streams(CommKind::Recv, recv_comm_id);
+        let (peer, dev_id, tally, opened_at) = (
+            recv_comm.peer.clone(),
+            recv_comm.dev_id,
+            recv_comm.tally.clone(),
+            recv_comm.opened_at,
+        );
+        let finished = recv_comm.finished.clone();
+        // Its driver fails the messages still posted.
+        drop(recv_comm);
+        let drained = !matches!(
+            finished.recv_timeout(self.close_timeout),
+            Err(flume::RecvTimeoutError::Timeout)
+        );
+        connection_event!(
+            INFO,
+            recv_comm = recv_comm_id,
+            peer = %peer,
+            dev = dev_id,
+            nbytes = tally.nbytes.load(Ordering::Relaxed),
+            lifetime_ms = opened_at.elapsed().as_millis() as u64,
+            drained,
+            "recv comm closed"
+        );
+        if !drained {
+            return Err(BaguaNetError::remote(format!(
+                "recv comm {} was closed but still reads after {:?}",
+                recv_comm_id, self.close_timeout
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn close_listen(&mut self, listen_comm_id: SocketListenCommID) -> Result<(), BaguaNetError> {
+        // Closed once dropped.
+        self.listen_comm_map
+            .remove(listen_comm_id)
+            .ok_or_else(|| slab::unknown("listen comm", listen_comm_id))?;
+
+        Ok(())
+    }
+
+    /// Stops taking requests and comms, waits up to `timeout` for the
+    /// requests posted to be done, then closes every comm and waits up to
+    /// `close_timeout` for the threads of the net, then flushes the spans.
+    /// Errs with `BaguaNetError::Timeout` if requests were still not done.
+    /// Does nothing once it was called.
+    fn shutdown(&mut self, timeout: Duration) -> Result<(), BaguaNetError> {
+        if self.shut_down {
+            return Ok(());
+        }
+        self.shut_down = true;
+
+        let deadline = Instant::now() + timeout;
+        // Tested, or handed to their completion hooks.
+        let pending = |net: &BaguaNet| {
+            let tested = net
+                .socket_request_map
+                .iter()
+                .map(|(_, request)| match request {
+                    SocketRequest::SendRequest(send_req) => send_req.state.clone(),
+                    SocketRequest::RecvRequest(recv_req) => recv_req.state.clone(),
+                });
+            tested
+                .chain(net.hooked.values().filter_map(|(state, _)| state.upgrade()))
+                .filter(|state| !state.done())
+                .count()
+        };
+        let mut npending = pending(self);
+        while npending > 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+            npending = pending(self);
+        }
+        if self.live_requests() > 0 {
+            tracing::warn!(
+                "{} requests were never tested to completion",
+                self.live_requests()
+            );
+        }
+
+        // Requests first, their comms may wait for them. Then the comms,
+        // which their drivers find closed until the event loops drop them.
+        drop(std::mem::take(&mut self.socket_request_map));
+        count_live_requests(&self.state, &self.socket_request_map);
+        self.hooked.clear();
+        drop(std::mem::take(&mut self.listen_comm_map));
+        drop(std::mem::take(&mut self.send_comm_map));
+        drop(std::mem::take(&mut self.recv_comm_map));
+        self.event_loops.stop(self.close_timeout);
+        self.reconnect_acceptors.clear();
+        for (name, exporter) in [
+            ("uploader", self.uploader.take()),
+            ("server", self.metrics_server.take()),
+        ] {
+            if let Some((stop, exporter)) = exporter {
+                drop(stop);
+                if !utils::join_until(exporter, Instant::now() + self.close_timeout) {
+                    tracing::warn!("metrics {} still running after the net was shut down", name);
+                }
+            }
+        }
+        if self.threads.count() > 0 {
+            tracing::warn!(
+                "{} threads still running after the net was shut down",
+                self.threads.count()
+            );
+        }
+        // TODO: make shutdown global
+        self.trace_span_context.span().end();
+        opentelemetry::global::shutdown_tracer_provider();
+
+        match npending {
+            0 => Ok(()),
+            _ => {
+                tracing::warn!("{} requests not done after {:?}", npending, timeout);
+                Err(BaguaNetError::Timeout)
+            }
+        }
+    }
+
+    fn listen_closer(
+        &self,
+        listen_comm_id: SocketListenCommID,
+    ) -> Result<Option<ListenCloser>, BaguaNetError> {
+        let listen_comm = self
+            .listen_comm_map
+            .get(listen_comm_id)
+            .ok_or_else(|| slab::unknown("listen comm", listen_comm_id))?;
+        Ok(Some(listen_comm.closer.clone()))
+    }
+}
+
+impl Drop for BaguaNet {
+    fn drop(&mut self) {
+        // Warned about already.
+        let _ = self.shutdown(Self::DROP_DRAIN_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::CompressionConfig;
+    use crate::interface::ErrorKind;
+    use crate::staging::fake::FakeDevice;
+    use crate::staging::StagingConfig;
+    use nix::sys::socket::InetAddr;
+    use socket2::SockRef;
+    use std::io::Write;
+    use std::net;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A `BaguaNet` whose only device is the loopback address `addr`.
+    fn loopback_net(addr: &str) -> BaguaNet {
+        let mut net = BaguaNet::new().unwrap();
+        let addr: net::SocketAddr = addr.parse().unwrap();
+        net.socket_devs = vec![NCCLSocketDev {
+            interface_name: "lo".to_owned(),
+            addr: SockAddr::new_inet(InetAddr::from_std(&addr)),
+            alt_addr: None,
+            pci_path: "".to_owned(),
+        }];
+        net
+    }
+
+    fn wait_connected(net: &mut BaguaNet, id: SocketSendCommID) -> Result<(), BaguaNetError> {
+        let timer = std::time::Instant::now();
+        while !net.connect_test(id)? {
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+        Ok(())
+    }
+
+    /// Accepts until the connect of `send_id` fails on a comm handshake the
+    /// acceptor dropped, and returns its error.
+    fn wait_rejected(
+        net: &mut BaguaNet,
+        listen_id: SocketListenCommID,
+        send_id: SocketSendCommID,
+    ) -> BaguaNetError {
+        let timer = std::time::Instant::now();
+        loop {
+            assert!(net.accept(listen_id).unwrap().is_none());
+            match net.connect_test(send_id) {
+                Ok(connected) => assert!(!connected),
+                Err(err) => return err,
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+    }
+
+    fn wait_accepted(net: &mut BaguaNet, listen_id: SocketListenCommID) -> SocketRecvCommID {
+        let timer = std::time::Instant::now();
+        loop {
+            if let Some(id) = net.accept(listen_id).unwrap() {
+                return id;
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_accept_nonblocking() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+
+        // Nobody has connected yet.
+        let timer = std::time::Instant::now();
+        assert!(net.accept(listen_id).unwrap().is_none());
+        assert!(timer.elapsed() < std::time::Duration::from_secs(1));
+
+        let send_id = net.connect(0, socket_handle).unwrap();
+        wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+    }
+
+    #[test]
+    fn test_connect_async() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let listener = connection::bind_listener(
+            InetAddr::from_std(&"127.0.0.1:0".parse().unwrap()),
+            &ListenConfig::from_env(),
+        )
+        .unwrap();
+        let socket_handle = listener.socket_handle().unwrap();
+
+        let id = net.connect(0, socket_handle).unwrap();
+        let nstreams = net.nstreams;
+        let accept_config = net.accept_config.clone();
+        let acceptor = std::thread::spawn(move || {
+            let mut pending = PendingStreams::default();
+            while connection::accept_stream_group(&listener, &mut pending, nstreams, &accept_config)
+                .unwrap()
+                .is_none()
+            {
+                std::thread::yield_now();
+            }
+        });
+        wait_connected(&mut net, id).unwrap();
+        acceptor.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_async_failed() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.connect_config.retries = 0;
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&listener.local_addr().unwrap())),
+            alt_addr: None,
+            uds_addr: None,
+            hostname: None,
+        };
+        drop(listener);
+
+        let id = net.connect(0, socket_handle).unwrap();
+        assert!(wait_connected(&mut net, id).is_err());
+        assert!(net.isend(id, (&[0u8; 4][..]).into(), None).is_err());
+    }
+
+    fn wait_done(net: &mut BaguaNet, id: SocketRequestID) -> usize {
+        let timer = std::time::Instant::now();
+        loop {
+            let (done, nbytes) = net.test(id).unwrap();
+            if done {
+                return nbytes;
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let request_timeout = std::time::Duration::from_millis(200);
+        let mut net = loopback_net("127.0.0.1:0");
+        net.request_timeout = Some(request_timeout);
+        // A peer that stopped without closing its streams, which never
+        // receives nor sends.
+        let mut remote = loopback_net("127.0.0.1:0");
+        let (socket_handle, remote_listen_id) = remote.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        wait_accepted(&mut remote, remote_listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let remote_send_id = remote.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut remote, remote_send_id).unwrap();
+
+        // More than the socket buffers hold.
+        let send_req = net.isend(send_id, leak(256 << 20, 1).into(), None).unwrap();
+        let recv_req = net.irecv(recv_id, leak(1 << 20, 0).into(), None).unwrap();
+        let timer = std::time::Instant::now();
+        assert!(!net.test(send_req).unwrap().0);
+        assert!(!net.test(recv_req).unwrap().0);
+        assert!(matches!(
+            net.wait(recv_req, None),
+            Err(BaguaNetError::Timeout)
+        ));
+        assert!(timer.elapsed() >= request_timeout);
+        let timed_out = loop {
+            match net.test(send_req) {
+                Ok((false, _)) => {
+                    assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+                    std::thread::yield_now();
+                }
+                ret => break ret,
+            }
+        };
+        assert!(matches!(timed_out, Err(BaguaNetError::Timeout)));
+        assert!(matches!(net.test(send_req), Err(BaguaNetError::Timeout)));
+        assert!(matches!(net.test(recv_req), Err(BaguaNetError::Timeout)));
+
+        // Both comms fail fast from then on.
+        assert!(matches!(
+            net.isend(send_id, leak(1, 1).into(), None),
+            Err(BaguaNetError::Timeout)
+        ));
+        assert!(matches!(
+            net.irecv(recv_id, leak(1, 0).into(), None),
+            Err(BaguaNetError::Timeout)
+        ));
+
+        // Off by default.
+        assert!(loopback_net("127.0.0.1:0").request_timeout.is_none());
+    }
+
+    #[test]
+    fn test_wait() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        // Nothing was sent, the request stays.
+        let nbytes = 1 << 20;
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        let timeout = std::time::Duration::from_millis(50);
+        let timer = std::time::Instant::now();
+        assert!(matches!(
+            net.wait(recv_req, Some(timeout)),
+            Err(BaguaNetError::Timeout)
+        ));
+        assert!(timer.elapsed() >= timeout);
+        assert_eq!(net.test(recv_req).unwrap(), (false, 0));
+
+        let send_buf: &'static [u8] = Box::leak(vec![1u8; nbytes].into_boxed_slice());
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        assert_eq!(net.wait(recv_req, None).unwrap(), nbytes);
+        assert_eq!(net.wait(send_req, None).unwrap(), nbytes);
+        // Gone once done, like with test.
+        assert_eq!(net.test(recv_req).unwrap(), (true, nbytes));
+        assert!(net.test(recv_req).is_err());
+
+        // Done after the wait timed out, before it tests.
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        let state = match net.socket_request_map.get(recv_req) {
+            Some(SocketRequest::RecvRequest(recv_req)) => recv_req.state.clone(),
+            _ => unreachable!(),
+        };
+        assert!(state.wait(Some(Duration::from_secs(10))));
+        assert_eq!(net.timed_out(recv_req).unwrap(), nbytes);
+        // Still there for the next wait, once.
+        assert_eq!(net.wait(recv_req, None).unwrap(), nbytes);
+        assert!(matches!(
+            net.wait(recv_req, None),
+            Err(BaguaNetError::InvalidId { .. })
+        ));
+        assert_eq!(net.wait(send_req, None).unwrap(), nbytes);
+
+        // Done around when the wait times out, either way it completes once.
+        for i in 0..50 {
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            let timeout = std::time::Duration::from_micros(20 * i);
+            match net.wait(recv_req, Some(timeout)) {
+                Ok(received) => assert_eq!(received, nbytes),
+                Err(BaguaNetError::Timeout) => {
+                    let (done, _) = net.test(recv_req).unwrap();
+                    let received = match done {
+                        true => nbytes,
+                        false => net.wait(recv_req, None).unwrap(),
+                    };
+                    assert_eq!(received, nbytes);
+                }
+                Err(err) => panic!("{:?}", err),
+            }
+            assert_eq!(net.wait(send_req, None).unwrap(), nbytes);
+        }
+    }
+
+    #[test]
+    fn test_completion_hook() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle.clone()).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        let (sender, called) = flume::unbounded();
+        let hook = |sender: &flume::Sender<Result<usize, BaguaNetError>>| -> CompletionHook {
+            let sender = sender.clone();
+            Box::new(move |ret| sender.send(ret).unwrap())
+        };
+        let called_once = || {
+            let ret = called.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert!(called.recv_timeout(Duration::from_millis(10)).is_err());
+            ret
+        };
+
+        let nbytes = 1 << 20;
+        let send_buf: &'static [u8] = leak(nbytes, 1);
+        let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+        net.set_completion_hook(recv_req, hook(&sender)).unwrap();
+        // No longer tested.
+        assert!(matches!(
+            net.test(recv_req),
+            Err(BaguaNetError::InvalidId { .. })
+        ));
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        assert_eq!(called_once().unwrap(), nbytes);
+        assert!(net.cancel(recv_req).is_err());
+        // Done already, called right away.
+        let timer = Instant::now();
+        while !matches!(
+            net.socket_request_map.get(send_req),
+            Some(SocketRequest::SendRequest(send_req)) if send_req.state.progress().0
+        ) {
+            assert!(timer.elapsed() < Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+        net.set_completion_hook(send_req, hook(&sender)).unwrap();
+        assert_eq!(called.try_recv().unwrap().unwrap(), nbytes);
+        // Tested to completion already.
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+        wait_done(&mut net, recv_req);
+        assert!(net.set_completion_hook(recv_req, hook(&sender)).is_err());
+        wait_done(&mut net, send_req);
+
+        // Tested or hooked around when it completes, either reports it once.
+        for i in 0..50 {
+            let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            let timer = Instant::now();
+            let mut tested = false;
+            while timer.elapsed() < Duration::from_micros(20 * i) && !tested {
+                tested = net.test(recv_req).unwrap().0;
+            }
+            match tested {
+                true => assert!(net.set_completion_hook(recv_req, hook(&sender)).is_err()),
+                false => {
+                    net.set_completion_hook(recv_req, hook(&sender)).unwrap();
+                    assert_eq!(called_once().unwrap(), nbytes);
+                    assert!(net.test(recv_req).is_err());
+                }
+            }
+            wait_done(&mut net, send_req);
+        }
+
+        // With the error of a message too large for its buffer.
+        let recv_req = net.irecv(recv_id, leak(16, 0).into(), None).unwrap();
+        net.set_completion_hook(recv_req, hook(&sender)).unwrap();
+        net.isend(send_id, send_buf.into(), None).unwrap();
+        let err = called_once().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::RemoteError, "{:?}", err);
+
+        // Still cancelled, once. Failed once its comm is closed.
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+        net.set_completion_hook(recv_req, hook(&sender)).unwrap();
+        net.cancel(recv_req).unwrap();
+        assert!(net.cancel(recv_req).is_err());
+        net.close_recv(recv_id).unwrap();
+        assert!(called_once().is_err());
+    }
+
+    fn leak(nbytes: usize, fill: u8) -> &'static mut [u8] {
+        Box::leak(vec![fill; nbytes].into_boxed_slice())
+    }
+
+    #[test]
+    fn test_cancel_before_dispatch() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        // Queued while connecting, never sent.
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let cancelled_send = net.isend(send_id, leak(1 << 20, 1).into(), None).unwrap();
+        net.cancel(cancelled_send).unwrap();
+        assert!(net.test(cancelled_send).is_err());
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        // Takes the message it was posted for, without touching its buffer.
+        let cancelled_buf = leak(1 << 20, 7);
+        let cancelled_ptr = cancelled_buf.as_ptr();
+        let cancelled_recv = net.irecv(recv_id, cancelled_buf.into(), None).unwrap();
+        net.cancel(cancelled_recv).unwrap();
+        assert!(net.test(cancelled_recv).is_err());
+        let recv_buf = leak(1 << 20, 0);
+        let recv_ptr = recv_buf.as_ptr();
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        let send_req = net.isend(send_id, leak(1 << 20, 2).into(), None).unwrap();
+        let next_send = net.isend(send_id, leak(1 << 20, 3).into(), None).unwrap();
+
+        assert_eq!(net.wait(send_req, None).unwrap(), 1 << 20);
+        assert_eq!(net.wait(next_send, None).unwrap(), 1 << 20);
+        assert_eq!(net.wait(recv_req, None).unwrap(), 1 << 20);
+        let received = unsafe { std::slice::from_raw_parts(recv_ptr, 1 << 20) };
+        assert!(received.iter().all(|&b| b == 3));
+        let cancelled = unsafe { std::slice::from_raw_parts(cancelled_ptr, 1 << 20) };
+        assert!(cancelled.iter().all(|&b| b == 7));
+    }
+
+    #[test]
+    fn test_cancel_mid_transfer() {
+        let mut net = loopback_net("127.0.0.1:0");
+        #[cfg(feature = "io-uring")]
+        {
+            net.event_loops =
+                EventLoops::spawn(&net.threads, 2, true, &[], Duration::ZERO).unwrap();
+        }
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        let nbytes = 16 << 20;
+
+        let wait_started = |net: &mut BaguaNet, id: SocketRequestID| {
+            let timer = std::time::Instant::now();
+            while net.test(id).unwrap().1 == 0 {
+                assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+                std::thread::yield_now();
+            }
+        };
+
+        // The receiver drops the rest of the message.
+        net.set_max_bandwidth(send_id, Some(400)).unwrap();
+        let send_req = net.isend(send_id, leak(nbytes, 1).into(), None).unwrap();
+        let cancelled_buf = leak(nbytes, 0);
+        let cancelled_ptr = cancelled_buf.as_ptr();
+        let cancelled_recv = net.irecv(recv_id, cancelled_buf.into(), None).unwrap();
+        wait_started(&mut net, cancelled_recv);
+        // Reads submitted to the io_uring are cancelled before it returns.
+        net.cancel(cancelled_recv).unwrap();
+        let cancelled = unsafe { std::slice::from_raw_parts(cancelled_ptr, nbytes) };
+        let snapshot = cancelled.to_vec();
+        net.set_max_bandwidth(send_id, None).unwrap();
+        assert_eq!(net.wait(send_req, None).unwrap(), nbytes);
+        assert!(cancelled == &snapshot[..]);
+        assert_eq!(cancelled[nbytes - 1], 0);
+
+        // The sender writes the rest as zeros, once it told the receiver.
+        net.set_max_bandwidth(send_id, Some(400)).unwrap();
+        let cancelled_send = net.isend(send_id, leak(nbytes, 1).into(), None).unwrap();
+        let recv_req = net.irecv(recv_id, leak(nbytes, 7).into(), None).unwrap();
+        wait_started(&mut net, recv_req);
+        net.cancel(cancelled_send).unwrap();
+        net.set_max_bandwidth(send_id, None).unwrap();
+        let err = net.wait(recv_req, None).unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("cancelled by the sender"),
+            "{:?}",
+            err
+        );
+
+        // The streams are still in step.
+        for _ in 0..3 {
+            let send_req = net.isend(send_id, leak(nbytes, 5).into(), None).unwrap();
+            let recv_buf = leak(nbytes, 0);
+            let recv_ptr = recv_buf.as_ptr();
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            assert_eq!(net.wait(send_req, None).unwrap(), nbytes);
+            assert_eq!(net.wait(recv_req, None).unwrap(), nbytes);
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+            assert!(received.iter().all(|&b| b == 5));
+        }
+    }
+
+    #[test]
+    fn test_cancel_after_completion() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let send_req = net.isend(send_id, leak(4096, 1).into(), None).unwrap();
+        let recv_req = net.irecv(recv_id, leak(4096, 0).into(), None).unwrap();
+        assert_eq!(net.wait(send_req, None).unwrap(), 4096);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        net.cancel(recv_req).unwrap();
+        assert!(net.test(recv_req).is_err());
+        // Done and tested already.
+        assert!(net.cancel(send_req).is_err());
+        assert!(net.cancel(recv_req).is_err());
+    }
+
+    #[test]
+    fn test_irecv_multi() {
+        let mut net = inline_net(4096);
+        net.nstreams = 2;
+        net.min_chunksize = 64 * 1024;
+        net.queue_capacity = 8;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        // Inlined, empty and chunked, then one for an irecv after it.
+        let sizes = [100, 3 << 20, 0, (5 << 20) + 7];
+        let bufs: Vec<RecvBuffer> = sizes.iter().map(|_| leak(8 << 20, 0).into()).collect();
+        let ptrs: Vec<*const u8> = bufs.iter().map(|buf| buf.as_raw() as *const u8).collect();
+        let multi_req = net.irecv_multi(recv_id, bufs).unwrap();
+        let next_buf = leak(1 << 20, 0);
+        let next_ptr = next_buf.as_ptr();
+        let next_req = net.irecv(recv_id, next_buf.into(), None).unwrap();
+        let sent = |i: usize| -> &'static [u8] { leak(sizes[i], i as u8 + 1) };
+        for (i, &nbytes) in sizes.iter().enumerate().take(3) {
+            let send_req = net.isend(send_id, sent(i).into(), None).unwrap();
+            assert_eq!(net.wait(send_req, None).unwrap(), nbytes);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!net.test_sizes(multi_req).unwrap().0);
+
+        let send_req = net.isend(send_id, sent(3).into(), None).unwrap();
+        let next_send = net.isend(send_id, leak(1 << 20, 9).into(), None).unwrap();
+        net.wait(send_req, None).unwrap();
+        net.wait(next_send, None).unwrap();
+        let timer = std::time::Instant::now();
+        let received = loop {
+            match net.test_sizes(multi_req).unwrap() {
+                (true, received) => break received,
+                (false, _) => {
+                    assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+                    std::thread::yield_now();
+                }
+            }
+        };
+        assert_eq!(received, sizes);
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            let received = unsafe { std::slice::from_raw_parts(ptr, sizes[i]) };
+            assert!(received.iter().all(|&b| b == i as u8 + 1));
+        }
+        assert_eq!(net.wait(next_req, None).unwrap(), 1 << 20);
+        let received = unsafe { std::slice::from_raw_parts(next_ptr, 1 << 20) };
+        assert!(received.iter().all(|&b| b == 9));
+
+        // The bytes of every buffer.
+        let multi_req = net
+            .irecv_multi(recv_id, vec![leak(4096, 0).into(), leak(1 << 20, 0).into()])
+            .unwrap();
+        net.isend(send_id, leak(10, 1).into(), None).unwrap();
+        net.isend(send_id, leak(1 << 20, 1).into(), None).unwrap();
+        assert_eq!(net.wait(multi_req, None).unwrap(), 10 + (1 << 20));
+
+        // All or none of them.
+        let bufs: Vec<RecvBuffer> = (0..9).map(|_| leak(16, 0).into()).collect();
+        assert!(matches!(
+            net.irecv_multi(recv_id, bufs),
+            Err(BaguaNetError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_irecv_multi_fixed_size() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let nbytes = 100 * 1000;
+        let (send_id, recv_id) = fixed_size_comms(&mut net, nbytes);
+        let multi_req = net
+            .irecv_multi(recv_id, (0..3).map(|_| leak(nbytes, 0).into()).collect())
+            .unwrap();
+        for _ in 0..3 {
+            net.isend(send_id, leak(nbytes, 1).into(), None).unwrap();
+        }
+        let timer = std::time::Instant::now();
+        loop {
+            match net.test_sizes(multi_req).unwrap() {
+                (true, received) => break assert_eq!(received, vec![nbytes; 3]),
+                (false, _) => {
+                    assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+                    std::thread::yield_now();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_test_many() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let nbytes = 4096;
+        let send_buf: &'static [u8] = Box::leak(vec![1u8; nbytes].into_boxed_slice());
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        let done_req = net
+            .irecv(
+                recv_id,
+                Box::leak(vec![0u8; nbytes].into_boxed_slice()).into(),
+                None,
+            )
+            .unwrap();
+        let pending_req = net
+            .irecv(
+                recv_id,
+                Box::leak(vec![0u8; nbytes].into_boxed_slice()).into(),
+                None,
+            )
+            .unwrap();
+        net.wait(send_req, None).unwrap();
+
+        let ids = [done_req, pending_req, usize::MAX];
+        let mut out = vec![Ok((false, 0)); ids.len()];
+        let timer = std::time::Instant::now();
+        loop {
+            net.test_many(&ids, &mut out).unwrap();
+            assert_eq!(out[1].as_ref().unwrap(), &(false, 0));
+            assert!(out[2].is_err());
+            if out[0].as_ref().unwrap().0 {
+                break;
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+        assert_eq!(out[0].as_ref().unwrap(), &(true, nbytes));
+        // Removed like with test.
+        assert_eq!(net.test(done_req).unwrap(), (true, nbytes));
+        assert!(net.test(done_req).is_err());
+        assert_eq!(net.test(pending_req).unwrap(), (false, 0));
+
+        assert!(net.test_many(&ids, &mut out[..1]).is_err());
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture
+    /// bench_test_many`.
+    #[test]
+    #[ignore]
+    fn bench_test_many() {
+        let nrequests = 1000;
+        let mut net = loopback_net("127.0.0.1:0");
+        net.queue_capacity = nrequests;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        let ids: Vec<SocketRequestID> = (0..nrequests)
+            .map(|_| {
+                let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 8].into_boxed_slice());
+                net.irecv(recv_id, recv_buf.into(), None).unwrap()
+            })
+            .collect();
+        // Behind the lock of the C API.
+        let net: Mutex<Box<dyn Net + Send>> = Mutex::new(Box::new(net));
+
+        let iterations = 1000;
+        let timer = std::time::Instant::now();
+        for _ in 0..iterations {
+            for &id in ids.iter() {
+                assert!(!net.lock().unwrap().test(id).unwrap().0);
+            }
+        }
+        let sequential = timer.elapsed() / iterations;
+        let mut out = vec![Ok((false, 0)); nrequests];
+        let timer = std::time::Instant::now();
+        for _ in 0..iterations {
+            net.lock().unwrap().test_many(&ids, &mut out).unwrap();
+            assert!(out.iter().all(|ret| matches!(ret, Ok((false, _)))));
+        }
+        let batched = timer.elapsed() / iterations;
+        println!(
+            "{} requests: test {:?}, test_many {:?}",
+            nrequests, sequential, batched
+        );
+    }
+
+    #[test]
+    fn test_send_recv_v6_loopback() {
+        let mut net = loopback_net("[::1]:0");
+
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        assert!(matches!(
+            socket_handle.addr,
+            SockAddr::Inet(InetAddr::V6(_))
+        ));
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        check_send_recv(&mut net, send_id, recv_id);
+    }
+
+    #[test]
+    fn test_send_recv_uds() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.listen_config.uds = true;
+        net.connect_config.uds = true;
+
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        assert!(socket_handle.uds_addr.is_some());
+        let config = net.connect_config_of(0, &socket_handle).unwrap();
+        assert!(config.unix_peer.is_some());
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        check_send_recv(&mut net, send_id, recv_id);
+    }
+
+    #[test]
+    fn test_send_recv_tls() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let tls = crate::tls::tests::tls_config();
+        net.connect_config.tls = Some(tls.clone());
+        net.accept_config.tls = Some(tls);
+
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        check_send_recv(&mut net, send_id, recv_id);
+        check_send_recv(&mut net, send_id, recv_id);
+    }
+
+    /// Sends 1 MiB from `send_id` to `recv_id` and checks it arrived intact.
+    fn check_send_recv(net: &mut BaguaNet, send_id: SocketSendCommID, recv_id: SocketRecvCommID) {
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; data.len()].into_boxed_slice());
+        let recv_ptr = recv_buf.as_ptr();
+
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        assert_eq!(wait_done(net, send_req), data.len());
+        assert_eq!(wait_done(net, recv_req), data.len());
+
+        let received = unsafe { std::slice::from_raw_parts(recv_ptr, data.len()) };
+        assert_eq!(received, &data[..]);
+    }
+
+    #[test]
+    fn test_stale_ids() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let send_buf: &'static [u8] = Box::leak(vec![1u8; 1024].into_boxed_slice());
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 1024].into_boxed_slice());
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        wait_done(&mut net, send_req);
+        wait_done(&mut net, recv_req);
+        assert_eq!(net.test(send_req).unwrap(), (true, 1024));
+        assert!(net.test(send_req).is_err());
+
+        // Takes the slot of one of the done requests, not its ID.
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 1024].into_boxed_slice());
+        let next_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        assert!(next_req != send_req && next_req != recv_req);
+        assert_eq!(net.live_requests(), 1);
+        assert!(net.test(send_req).is_err());
+        // Not the request that took its slot.
+        assert_eq!(net.test(recv_req).unwrap(), (true, 1024));
+        assert!(net.test(recv_req).is_err());
+        let send_next_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        wait_done(&mut net, send_next_req);
+        wait_done(&mut net, next_req);
+
+        assert_eq!(net.live_requests(), 0);
+
+        net.close_send(send_id).unwrap();
+        assert!(net.isend(send_id, send_buf.into(), None).is_err());
+        net.close_listen(listen_id).unwrap();
+        assert!(net.accept(listen_id).is_err());
+    }
+
+    #[test]
+    fn test_drop_stops_threads() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        let send_buf: &'static [u8] = Box::leak(vec![1u8; 1024].into_boxed_slice());
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 1024].into_boxed_slice());
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        wait_done(&mut net, send_req);
+        wait_done(&mut net, recv_req);
+        // Never tested.
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 1024].into_boxed_slice());
+        net.irecv(recv_id, recv_buf.into(), None).unwrap();
+
+        // Comms and the request left open.
+        let threads = net.threads.clone();
+        assert!(threads.count() > 0);
+        drop(net);
+        assert_eq!(threads.count(), 0);
+    }
+
+    /// Whose metrics are gathered from the exporter, by peer too.
+    fn metered_net() -> (BaguaNet, telemetry::MetricsExporter, Meters) {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (exporter, meters) = TelemetryConfig::from_env().exporter();
+        net.state = Arc::new(AppState::new(Some(&meters), true, false));
+        (net, exporter, meters)
+    }
+
+    #[test]
+    fn test_peer_labels() {
+        let (mut net, exporter, _meters) = metered_net();
+        // Comms to two listeners.
+        let mut listen_addrs = Vec::new();
+        for _ in 0..2 {
+            let (socket_handle, listen_id) = net.listen(0).unwrap();
+            listen_addrs.push(socket_handle.addr.to_string());
+            let send_id = net.connect(0, socket_handle).unwrap();
+            let recv_id = wait_accepted(&mut net, listen_id);
+            // Not inlined on the master stream.
+            let send_req = net.isend(send_id, leak(1 << 20, 1).into(), None).unwrap();
+            let recv_req = net.irecv(recv_id, leak(1 << 20, 0).into(), None).unwrap();
+            wait_done(&mut net, send_req);
+            wait_done(&mut net, recv_req);
+        }
+        assert_ne!(listen_addrs[0], listen_addrs[1]);
+
+        let families = exporter.gather();
+        let label_sets = |name: &str| -> Vec<Vec<(String, String)>> {
+            let family = families.iter().find(|f| f.get_name() == name).unwrap();
+            family
+                .get_metric()
+                .iter()
+                .map(|metric| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .map(|pair| (pair.get_name().to_owned(), pair.get_value().to_owned()))
+                        .filter(|(name, _)| ["handler", "peer", "comm_id"].contains(&name.as_str()))
+                        .collect()
+                })
+                .collect()
+        };
+        for name in ["isend_nbytes", "irecv_nbytes"] {
+            let mut label_sets = label_sets(name);
+            // Besides the aggregate.
+            let all = vec![("handler".to_owned(), "all".to_owned())];
+            assert!(label_sets.contains(&all), "{:?}", label_sets);
+            label_sets.retain(|labels| labels != &all);
+            label_sets.dedup();
+            assert_eq!(label_sets.len(), 2, "{:?}", label_sets);
+            assert!(label_sets.iter().all(|labels| labels
+                .iter()
+                .map(|(name, _)| name)
+                .eq(["comm_id", "peer"].iter())));
+        }
+        let mut peers: Vec<String> = label_sets("isend_nbytes")
+            .into_iter()
+            .filter_map(|labels| labels.into_iter().find(|(name, _)| name == "peer"))
+            .map(|(_, peer)| peer)
+            .collect();
+        peers.sort();
+        listen_addrs.sort();
+        assert_eq!(peers, listen_addrs);
+    }
+
+    #[test]
+    fn test_queue_depths() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (exporter, meters) = TelemetryConfig::from_env().exporter();
+        net.state = Arc::new(AppState::new(Some(&meters), true, false));
+        observe_metrics(
+            &meters.meter,
+            &net.state,
+            &net.mr_registry,
+            None,
+            net.min_chunksize,
+        );
+        let gauges = |name: &str| -> Vec<(Vec<(String, String)>, f64)> {
+            let families = exporter.gather();
+            let family = families.iter().find(|f| f.get_name() == name).unwrap();
+            family
+                .get_metric()
+                .iter()
+                .map(|metric| {
+                    let labels = metric
+                        .get_label()
+                        .iter()
+                        .map(|pair| (pair.get_name().to_owned(), pair.get_value().to_owned()))
+                        .filter(|(name, _)| ["comm_id", "stream"].contains(&name.as_str()))
+                        .collect();
+                    (labels, metric.get_gauge().get_value())
+                })
+                .collect()
+        };
+        let comm_id = |id: SocketSendCommID| ("comm_id".to_owned(), id.to_string());
+
+        // Queued while the comm waits to be accepted.
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let nbytes = 1 << 20;
+        let send_reqs: Vec<_> = (0..3)
+            .map(|_| net.isend(send_id, leak(nbytes, 1).into(), None).unwrap())
+            .collect();
+        assert_eq!(gauges("send_queue_depth"), [(vec![comm_id(send_id)], 3.)]);
+        assert_eq!(gauges("live_requests"), [(vec![], 3.)]);
+
+        let recv_id = wait_accepted(&mut net, listen_id);
+        for send_req in send_reqs {
+            let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+            wait_done(&mut net, send_req);
+            wait_done(&mut net, recv_req);
+        }
+        assert_eq!(gauges("send_queue_depth"), [(vec![comm_id(send_id)], 0.)]);
+        assert_eq!(gauges("live_requests"), [(vec![], 0.)]);
+        let streams: Vec<_> = gauges("stream_queue_depth")
+            .into_iter()
+            .map(|(labels, _)| labels)
+            .collect();
+        let expected: Vec<_> = (0..net.nstreams)
+            .map(|index| vec![comm_id(send_id), ("stream".to_owned(), index.to_string())])
+            .collect();
+        assert_eq!(streams, expected);
+
+        net.close_send(send_id).unwrap();
+        assert!(net.state.send_queues.comms().is_empty());
+    }
+
+    #[test]
+    fn test_tcp_info() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (exporter, meters) = TelemetryConfig::from_env().exporter();
+        net.state = Arc::new(AppState::new(Some(&meters), true, true));
+        observe_metrics(
+            &meters.meter,
+            &net.state,
+            &net.mr_registry,
+            None,
+            net.min_chunksize,
+        );
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        let send_req = net.isend(send_id, leak(1 << 20, 1).into(), None).unwrap();
+        let recv_req = net.irecv(recv_id, leak(1 << 20, 0).into(), None).unwrap();
+        wait_done(&mut net, send_req);
+        wait_done(&mut net, recv_req);
+
+        let streams = |name: &str| -> Vec<(String, String, String, f64)> {
+            let families = exporter.gather();
+            let family = families.iter().find(|f| f.get_name() == name).unwrap();
+            let mut streams: Vec<_> = family
+                .get_metric()
+                .iter()
+                .map(|metric| {
+                    let label = |name: &str| {
+                        let pair = metric.get_label().iter().find(|p| p.get_name() == name);
+                        pair.unwrap().get_value().to_owned()
+                    };
+                    let value = metric.get_gauge().get_value();
+                    (label("comm"), label("comm_id"), label("stream"), value)
+                })
+                .collect();
+            streams.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            streams
+        };
+        let cwnds = streams("tcp_snd_cwnd");
+        let expected: Vec<_> = ["recv", "send"]
+            .iter()
+            .flat_map(|&kind| {
+                let id = match kind {
+                    "send" => send_id,
+                    _ => recv_id,
+                };
+                (0..net.nstreams).map(move |index| (kind.to_owned(), id.to_string(), index))
+            })
+            .map(|(kind, id, index)| (kind, id, index.to_string()))
+            .collect();
+        let labels: Vec<_> = cwnds
+            .iter()
+            .map(|(kind, id, index, _)| (kind.clone(), id.clone(), index.clone()))
+            .collect();
+        assert_eq!(labels, expected);
+        assert!(cwnds.iter().all(|&(.., cwnd)| cwnd > 0.), "{:?}", cwnds);
+        assert!(streams("tcp_rtt_us").iter().all(|&(.., rtt)| rtt > 0.));
+        assert_eq!(streams("tcp_retrans_total").len(), expected.len());
+
+        net.close_send(send_id).unwrap();
+        net.close_recv(recv_id).unwrap();
+        assert!(net.state.tcp_streams.as_ref().unwrap().comms().is_empty());
+    }
+
+    #[test]
+    fn test_recorded_fds() {
+        let tcp_streams = TcpStreams::default();
+        let recorded = RecordedFds(Some(tcp_streams.open(CommKind::Send, 0)));
+        let (stream, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut streams = vec![DrivenStream::new(Stream::Unix(stream))];
+        recorded.record(&mut streams, |stream| stream);
+        let comms = tcp_streams.comms();
+        let first = comms[0].2[0].clone();
+        assert_ne!(first.as_raw_fd(), streams[0].stream.as_raw_fd());
+
+        // The replaced stream is closed once the next poll records the
+        // replacement, and its dup with it.
+        let (replacement, _replacement_peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        streams[0] = DrivenStream::new(Stream::Unix(replacement));
+        drop((comms, first));
+        recorded.record(&mut streams, |stream| stream);
+        assert_eq!(peer.read(&mut [0u8; 1]).unwrap(), 0);
+
+        drop(recorded);
+        assert!(tcp_streams.comms()[0].2.is_empty());
+    }
+
+    #[test]
+    fn test_device_tallies() {
+        // Two devices on aliased loopback addresses.
+        let mut net = loopback_net("127.0.0.1:0");
+        let mut alias = net.socket_devs[0].clone();
+        alias.addr = SockAddr::new_inet(InetAddr::from_std(&"127.0.0.2:0".parse().unwrap()));
+        net.socket_devs.push(alias);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(1, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        let nbytes = 1 << 20;
+        let send_req = net.isend(send_id, leak(nbytes, 1).into(), None).unwrap();
+        let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+        assert_eq!(wait_done(&mut net, send_req), nbytes);
+        assert_eq!(wait_done(&mut net, recv_req), nbytes);
+
+        let mut devices = net.state.devices.devices();
+        devices.sort_by_key(|&(dev_id, _)| dev_id);
+        let tallied: Vec<(usize, u64, u64, usize)> = devices
+            .iter()
+            .map(|(dev_id, device)| {
+                (
+                    *dev_id,
+                    device.sent.nbytes.load(Ordering::Relaxed),
+                    device.received.nbytes.load(Ordering::Relaxed),
+                    device.comms.load(Ordering::Relaxed),
+                )
+            })
+            .collect();
+        let nbytes = nbytes as u64;
+        assert_eq!(tallied, [(0, 0, nbytes, 1), (1, nbytes, 0, 1)]);
+        assert_eq!(devices[0].1.interface_name, "lo");
+        // Of the net as well.
+        let stats = net.get_statistics().unwrap();
+        assert_eq!((stats.bytes_sent, stats.bytes_received), (nbytes, nbytes));
+
+        net.close_send(send_id).unwrap();
+        net.close_recv(recv_id).unwrap();
+        assert!(devices
+            .iter()
+            .all(|(_, device)| device.comms.load(Ordering::Relaxed) == 0));
+    }
+
+    #[test]
+    fn test_statistics() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        let transfer = |net: &mut BaguaNet, nbytes: usize| {
+            let send_req = net.isend(send_id, leak(nbytes, 1).into(), None).unwrap();
+            let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+            assert_eq!(wait_done(net, send_req), nbytes);
+            assert_eq!(wait_done(net, recv_req), nbytes);
+        };
+        let sizes = [1 << 20, 4096, 0, 3 << 20];
+        for &nbytes in sizes.iter() {
+            transfer(&mut net, nbytes);
+        }
+        let moved = sizes.iter().sum::<usize>() as u64;
+        let stats = net.get_statistics().unwrap();
+        assert_eq!((stats.bytes_sent, stats.bytes_received), (moved, moved));
+        assert_eq!((stats.requests_completed, stats.requests_failed), (8, 0));
+        assert_eq!((stats.active_comms, stats.active_requests), (2, 0));
+        let comm = |stats: &NetStatistics, kind| {
+            stats
+                .comms
+                .iter()
+                .find(|comm| comm.kind == kind)
+                .unwrap()
+                .clone()
+        };
+        let send_comm = comm(&stats, CommKind::Send);
+        assert_eq!((send_comm.id, send_comm.nbytes), (send_id, moved));
+        assert_eq!(send_comm.requests_completed, 4);
+        let recv_comm = comm(&stats, CommKind::Recv);
+        assert_eq!((recv_comm.id, recv_comm.nbytes), (recv_id, moved));
+        assert_eq!(recv_comm.peer, net.recv_comm_map.get(recv_id).unwrap().peer);
+
+        // Of what is done from then on.
+        net.reset_statistics().unwrap();
+        transfer(&mut net, 1000);
+        let stats = net.get_statistics().unwrap();
+        assert_eq!((stats.bytes_sent, stats.bytes_received), (1000, 1000));
+        assert_eq!(stats.requests_completed, 2);
+        assert_eq!(comm(&stats, CommKind::Recv).nbytes, 1000);
+
+        // A receive too small for its message fails.
+        let recv_req = net.irecv(recv_id, leak(16, 0).into(), None).unwrap();
+        let send_req = net.isend(send_id, leak(1024, 1).into(), None).unwrap();
+        assert_eq!(net.get_statistics().unwrap().active_requests, 2);
+        let timer = std::time::Instant::now();
+        while net.test(recv_req).is_ok() {
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+        let stats = net.get_statistics().unwrap();
+        assert_eq!(comm(&stats, CommKind::Recv).requests_failed, 1);
+        assert!(stats.requests_failed >= 1);
+        let _ = net.cancel(send_req);
+
+        // The totals outlive the comms.
+        net.close_recv(recv_id).unwrap();
+        let _ = net.close_send(send_id);
+        let stats = net.get_statistics().unwrap();
+        assert!(stats.comms.is_empty());
+        assert_eq!(stats.active_comms, 0);
+        assert!(stats.bytes_sent >= 1000);
+    }
+
+    #[test]
+    fn test_latency_histograms() {
+        let (mut net, exporter, _meters) = metered_net();
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        // Found done by `test()`.
+        let send_req = net.isend(send_id, leak(1 << 20, 1).into(), None).unwrap();
+        let recv_req = net.irecv(recv_id, leak(1 << 20, 0).into(), None).unwrap();
+        wait_done(&mut net, send_req);
+        wait_done(&mut net, recv_req);
+        // Through their hooks.
+        let (done, hooked) = flume::unbounded();
+        let send_req = net.isend(send_id, leak(64, 1).into(), None).unwrap();
+        let recv_req = net.irecv(recv_id, leak(64, 0).into(), None).unwrap();
+        for id in [send_req, recv_req] {
+            let done = done.clone();
+            net.set_completion_hook(id, Box::new(move |ret| done.send(ret).unwrap()))
+                .unwrap();
+        }
+        for _ in 0..2 {
+            assert!(hooked
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap()
+                .is_ok());
+        }
+
+        let families = exporter.gather();
+        let bounds = |name: &str| -> Vec<f64> {
+            let family = families.iter().find(|f| f.get_name() == name).unwrap();
+            family.get_metric()[0]
+                .get_histogram()
+                .get_bucket()
+                .iter()
+                .map(|bucket| bucket.get_upper_bound())
+                .collect()
+        };
+        for name in ["isend_latency_us", "irecv_latency_us"] {
+            let family = families.iter().find(|f| f.get_name() == name).unwrap();
+            let histogram = family.get_metric()[0].get_histogram();
+            assert_eq!(histogram.get_sample_count(), 2, "{}", name);
+            assert!(histogram.get_sample_sum() > 0., "{}", name);
+            // In microseconds, without the buckets of the byte counters.
+            assert!(bounds(name).contains(&1000.));
+            assert!(!bounds(name).contains(&1024.));
+        }
+        assert_eq!(bounds("isend_nbytes"), [16., 1024., 4096., 1048576.]);
+    }
+
+    #[test]
+    fn test_scrape_metrics() {
+        let (mut net, exporter, meters) = metered_net();
+        let listener =
+            telemetry::listen_metrics("127.0.0.1:0".parse().unwrap(), &meters.meter).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = flume::bounded::<()>(0);
+        let server =
+            std::thread::spawn(move || telemetry::serve_metrics(listener, exporter, stopped));
+
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        let send_req = net.isend(send_id, leak(1 << 20, 1).into(), None).unwrap();
+        let recv_req = net.irecv(recv_id, leak(1 << 20, 0).into(), None).unwrap();
+        wait_done(&mut net, send_req);
+        wait_done(&mut net, recv_req);
+
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains("isend_nbytes_count{handler=\"all\"} "),
+            "{}",
+            response
+        );
+        drop(stop);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_telemetry_unconfigured() {
+        // Neither BAGUA_NET_JAEGER_ADDRESS nor BAGUA_NET_PROMETHEUS_ADDRESS.
+        let mut net = BaguaNet::new().unwrap();
+        assert!(net.uploader.is_none());
+        net.state.irecv_nbytes_gauge.record(1024);
+        net.event_loops.stop(Duration::from_secs(10));
+        // Only the event loops ran.
+        assert_eq!(net.threads.count(), 0);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle.clone()).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        // Drained, though never tested.
+        let send_req = net.isend(send_id, leak(1 << 20, 1).into(), None).unwrap();
+        let recv_req = net.irecv(recv_id, leak(1 << 20, 0).into(), None).unwrap();
+        let threads = net.threads.clone();
+        net.shutdown(Duration::from_secs(10)).unwrap();
+        assert_eq!(threads.count(), 0);
+
+        // Once.
+        net.shutdown(Duration::from_secs(10)).unwrap();
+        for id in [send_req, recv_req] {
+            assert!(net.test(id).is_err());
+        }
+        assert!(matches!(net.listen(0), Err(BaguaNetError::Closed)));
+        assert!(matches!(
+            net.connect(0, socket_handle),
+            Err(BaguaNetError::Closed)
+        ));
+        assert!(matches!(
+            net.isend(send_id, leak(4, 1).into(), None),
+            Err(BaguaNetError::Closed)
+        ));
+        drop(net);
+
+        // Until the timeout, with a receive nothing is sent to.
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        net.irecv(recv_id, leak(1024, 0).into(), None).unwrap();
+        let timer = Instant::now();
+        assert!(matches!(
+            net.shutdown(Duration::from_millis(200)),
+            Err(BaguaNetError::Timeout)
+        ));
+        assert!(timer.elapsed() >= Duration::from_millis(200));
+        net.shutdown(Duration::from_millis(200)).unwrap();
+    }
+
+    #[test]
+    fn test_request_ids_run_out() {
+        let mut net = loopback_net("127.0.0.1:0");
+        // 2 slots of 4 generations.
+        net.socket_request_map = Slab::with_id_bits(1, 2);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let send_buf: &'static [u8] = Box::leak(vec![1u8; 1024].into_boxed_slice());
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 1024].into_boxed_slice());
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            wait_done(&mut net, send_req);
+            wait_done(&mut net, recv_req);
+            ids.push(send_req);
+            ids.push(recv_req);
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 8);
+
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 1024].into_boxed_slice());
+        assert!(matches!(
+            net.irecv(recv_id, recv_buf.into(), None),
+            Err(BaguaNetError::InnerError(_))
+        ));
+        assert!(net.isend(send_id, send_buf.into(), None).is_err());
+        assert_eq!(net.live_requests(), 0);
+    }
+
+    #[test]
+    fn test_check_buffers() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                page,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        unsafe { libc::munmap(addr, page) };
+        let unmapped: &'static mut [u8] =
+            unsafe { std::slice::from_raw_parts_mut(addr as *mut u8, page) };
+
+        net.check_buffers = true;
+        let err = net.irecv(recv_id, unmapped.into(), None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidArgument, "{:?}", err);
+        let recv_req = net.irecv(recv_id, leak(page, 0).into(), None).unwrap();
+        let send_req = net.isend(send_id, leak(page, 1).into(), None).unwrap();
+        assert_eq!(wait_done(&mut net, send_req), page);
+        assert_eq!(wait_done(&mut net, recv_req), page);
+    }
+
+    #[test]
+    fn test_send_recv_registered() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; data.len()].into_boxed_slice());
+        let recv_ptr = recv_buf.as_ptr();
+        let send_mr = net.reg_mr(send_buf, PtrType::Host).unwrap();
+        let recv_mr = net
+            .reg_mr(
+                unsafe { std::slice::from_raw_parts(recv_ptr, data.len()) },
+                PtrType::Host,
+            )
+            .unwrap();
+        assert!(net.reg_mr(send_buf, PtrType::Cuda).is_err());
+        assert_eq!(net.mr_registry.lock().unwrap().count(), 2);
+
+        let recv_req = net.irecv(recv_id, recv_buf.into(), Some(recv_mr)).unwrap();
+        let send_req = net.isend(send_id, send_buf.into(), Some(send_mr)).unwrap();
+        assert_eq!(wait_done(&mut net, send_req), data.len());
+        assert_eq!(wait_done(&mut net, recv_req), data.len());
+        let received = unsafe { std::slice::from_raw_parts(recv_ptr, data.len()) };
+        assert_eq!(received, &data[..]);
+
+        net.dereg_mr(send_mr).unwrap();
+        net.dereg_mr(recv_mr).unwrap();
+        assert!(net.dereg_mr(send_mr).is_err());
+        assert_eq!(net.mr_registry.lock().unwrap().count(), 0);
+        assert_eq!(net.mr_registry.lock().unwrap().pinned_bytes(), 0);
+    }
+
+    #[test]
+    fn test_send_recv_one_io_thread() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.event_loops = EventLoops::spawn(&net.threads, 1, false, &[], Duration::ZERO).unwrap();
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let comms: Vec<(SocketSendCommID, SocketRecvCommID)> = (0..3)
+            .map(|_| {
+                let send_id = net.connect(0, socket_handle.clone()).unwrap();
+                let recv_id = wait_accepted(&mut net, listen_id);
+                wait_connected(&mut net, send_id).unwrap();
+                (send_id, recv_id)
+            })
+            .collect();
+
+        // All in flight at once, on the same thread.
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| i as u8).collect();
+        let requests: Vec<(SocketRequestID, SocketRequestID, *const u8)> = comms
+            .iter()
+            .map(|&(send_id, recv_id)| {
+                let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+                let recv_buf: &'static mut [u8] =
+                    Box::leak(vec![0u8; data.len()].into_boxed_slice());
+                let recv_ptr = recv_buf.as_ptr();
+                let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+                let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+                (send_req, recv_req, recv_ptr)
+            })
+            .collect();
+        for (send_req, recv_req, recv_ptr) in requests {
+            assert_eq!(wait_done(&mut net, send_req), data.len());
+            assert_eq!(wait_done(&mut net, recv_req), data.len());
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, data.len()) };
+            assert_eq!(received, &data[..]);
+        }
+    }
+
+    fn inline_net(inline_threshold: usize) -> BaguaNet {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.connect_config.inline_threshold = inline_threshold;
+        net.accept_config.inline_threshold = inline_threshold;
+        net
+    }
+
+    #[test]
+    fn test_received_size() {
+        // Inlined or not, and split in chunks.
+        for &inline_threshold in [4096, 0].iter() {
+            let mut net = inline_net(inline_threshold);
+            let (socket_handle, listen_id) = net.listen(0).unwrap();
+            let send_id = net.connect(0, socket_handle).unwrap();
+            let recv_id = wait_accepted(&mut net, listen_id);
+            wait_connected(&mut net, send_id).unwrap();
+
+            for &nbytes in [3, 0, 1 << 20].iter() {
+                let recv_buf = leak(1 << 20, 0);
+                let recv_ptr = recv_buf.as_ptr();
+                let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+                assert_eq!(net.received_size(recv_req).unwrap(), None);
+                let send_req = net.isend(send_id, leak(nbytes, 7).into(), None).unwrap();
+                assert_eq!(wait_done(&mut net, send_req), nbytes);
+                let timer = std::time::Instant::now();
+                while net.received_size(recv_req).unwrap().is_none() {
+                    assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+                    std::thread::yield_now();
+                }
+                assert_eq!(net.received_size(recv_req).unwrap(), Some(nbytes));
+                assert_eq!(wait_done(&mut net, recv_req), nbytes);
+                assert!(net.received_size(recv_req).is_err());
+                let received = unsafe { std::slice::from_raw_parts(recv_ptr, 1 << 20) };
+                assert!(received[..nbytes].iter().all(|&b| b == 7));
+                assert!(received[nbytes..].iter().all(|&b| b == 0));
+            }
+            let send_req = net.isend(send_id, leak(1, 0).into(), None).unwrap();
+            assert!(net.received_size(send_req).is_err());
+        }
+    }
+
+    #[test]
+    fn test_zero_byte_interleaved() {
+        let sizes = [0, 1 << 20, 0, 0, 3, 0, 5000, 0];
+        for &(inline_threshold, crc, seq_check) in [
+            (4096, false, false),
+            (0, false, false),
+            (4096, true, false),
+            (0, true, true),
+        ]
+        .iter()
+        {
+            let mut net = inline_net(inline_threshold);
+            net.connect_config.crc = crc;
+            net.accept_config.crc = crc;
+            net.connect_config.seq_check = seq_check;
+            net.accept_config.seq_check = seq_check;
+            let (socket_handle, listen_id) = net.listen(0).unwrap();
+            let send_id = net.connect(0, socket_handle).unwrap();
+            let recv_id = wait_accepted(&mut net, listen_id);
+            wait_connected(&mut net, send_id).unwrap();
+
+            let recvs: Vec<_> = sizes
+                .iter()
+                .map(|_| {
+                    let recv_buf = leak(1 << 20, 9);
+                    let recv_ptr = recv_buf.as_ptr();
+                    (net.irecv(recv_id, recv_buf.into(), None).unwrap(), recv_ptr)
+                })
+                .collect();
+            let sends: Vec<_> = sizes
+                .iter()
+                .enumerate()
+                .map(|(i, &nbytes)| {
+                    net.isend(send_id, leak(nbytes, i as u8).into(), None)
+                        .unwrap()
+                })
+                .collect();
+            for (i, (&nbytes, &send_req)) in sizes.iter().zip(sends.iter()).enumerate() {
+                assert_eq!(wait_done(&mut net, send_req), nbytes);
+                let (recv_req, recv_ptr) = recvs[i];
+                assert_eq!(wait_done(&mut net, recv_req), nbytes);
+                let received = unsafe { std::slice::from_raw_parts(recv_ptr, 1 << 20) };
+                assert!(received[..nbytes].iter().all(|&b| b == i as u8));
+                assert!(received[nbytes..].iter().all(|&b| b == 9));
+            }
+            check_send_recv(&mut net, send_id, recv_id);
+        }
+    }
+
+    #[test]
+    fn test_null_empty_buffers() {
+        // As NCCL may post them for empty messages.
+        let mut net = inline_net(4096);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let recv_buf = unsafe { RecvBuffer::from_raw_parts(std::ptr::null_mut(), 0) };
+        let recv_req = net.irecv(recv_id, recv_buf, None).unwrap();
+        let send_buf = unsafe { SendBuffer::from_raw_parts(std::ptr::null(), 0) };
+        let send_req = net.isend(send_id, send_buf, None).unwrap();
+        assert_eq!(wait_done(&mut net, send_req), 0);
+        assert_eq!(wait_done(&mut net, recv_req), 0);
+        let send_req = net.isend_v(send_id, &[send_buf, send_buf]).unwrap();
+        let recv_buf = unsafe { RecvBuffer::from_raw_parts(std::ptr::null_mut(), 0) };
+        let recv_req = net.irecv(recv_id, recv_buf, None).unwrap();
+        assert_eq!(wait_done(&mut net, send_req), 0);
+        assert_eq!(wait_done(&mut net, recv_req), 0);
+        check_send_recv(&mut net, send_id, recv_id);
+    }
+
+    #[test]
+    fn test_send_recv_inline() {
+        let mut net = inline_net(4096);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        // Inlined and chunked messages in flight at once, into larger buffers.
+        let sizes = [0, 1, 256, 4096, 4097, 1 << 20, 3];
+        let requests: Vec<(SocketRequestID, SocketRequestID, Vec<u8>, *const u8)> = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &nbytes)| {
+                let data: Vec<u8> = (0..nbytes).map(|j| (i + j) as u8).collect();
+                let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+                let recv_buf: &'static mut [u8] =
+                    Box::leak(vec![0u8; nbytes + 16].into_boxed_slice());
+                let recv_ptr = recv_buf.as_ptr();
+                let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+                let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+                (send_req, recv_req, data, recv_ptr)
+            })
+            .collect();
+        for (send_req, recv_req, data, recv_ptr) in requests {
+            assert_eq!(wait_done(&mut net, send_req), data.len());
+            assert_eq!(wait_done(&mut net, recv_req), data.len());
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, data.len()) };
+            assert_eq!(received, &data[..]);
+        }
+    }
+
+    #[test]
+    fn test_priority_lane() {
+        for &priority_threshold in [4096, 0].iter() {
+            let mut net = inline_net(4096);
+            net.priority_threshold = priority_threshold;
+            // The chunks of the large message backlog the data streams.
+            net.queue_capacity = 4;
+            let (socket_handle, listen_id) = net.listen(0).unwrap();
+            let send_id = net.connect(0, socket_handle).unwrap();
+            let recv_id = wait_accepted(&mut net, listen_id);
+            wait_connected(&mut net, send_id).unwrap();
+
+            let large: Vec<u8> = (0..64 << 20).map(|i| (i / 4096) as u8).collect();
+            let small: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+            let send_large = net
+                .isend(
+                    send_id,
+                    Box::leak(large.clone().into_boxed_slice()).into(),
+                    None,
+                )
+                .unwrap();
+            let send_small = net
+                .isend(
+                    send_id,
+                    Box::leak(small.clone().into_boxed_slice()).into(),
+                    None,
+                )
+                .unwrap();
+            // Nothing is received yet, so the large message is stuck, and the
+            // small one with it unless it takes the priority lane.
+            if priority_threshold > 0 {
+                assert_eq!(wait_done(&mut net, send_small), small.len());
+            } else {
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                assert!(!net.test(send_small).unwrap().0);
+            }
+            assert!(!net.test(send_large).unwrap().0);
+
+            // Still in the order posted.
+            let recv_large: &'static mut [u8] =
+                Box::leak(vec![0u8; large.len()].into_boxed_slice());
+            let recv_large_ptr = recv_large.as_ptr();
+            let recv_small: &'static mut [u8] = Box::leak(vec![0u8; 4096].into_boxed_slice());
+            let recv_small_ptr = recv_small.as_ptr();
+            let recv_large = net.irecv(recv_id, recv_large.into(), None).unwrap();
+            let recv_small = net.irecv(recv_id, recv_small.into(), None).unwrap();
+            assert_eq!(wait_done(&mut net, recv_small), small.len());
+            assert_eq!(wait_done(&mut net, recv_large), large.len());
+            if priority_threshold == 0 {
+                assert_eq!(wait_done(&mut net, send_small), small.len());
+            }
+            assert_eq!(wait_done(&mut net, send_large), large.len());
+            unsafe {
+                assert_eq!(
+                    std::slice::from_raw_parts(recv_small_ptr, small.len()),
+                    &small[..]
+                );
+                assert!(std::slice::from_raw_parts(recv_large_ptr, large.len()) == &large[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_across_streams() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.nstreams = 4;
+        net.chunk_bytes = 0;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| i as u8).collect();
+        let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; data.len()].into_boxed_slice());
+        let recv_ptr = recv_buf.as_ptr();
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        let state = |net: &BaguaNet, id: SocketRequestID| match &net.socket_request_map[id] {
+            SocketRequest::SendRequest(request) => request.state.clone(),
+            SocketRequest::RecvRequest(request) => request.state.clone(),
+        };
+        let (send_state, recv_state) = (state(&net, send_req), state(&net, recv_req));
+        assert_eq!(wait_done(&mut net, send_req), data.len());
+        assert_eq!(wait_done(&mut net, recv_req), data.len());
+
+        // A chunk on each stream, and the size on the master stream.
+        assert_eq!(send_state.nsubtasks.load(Ordering::Relaxed), 4 + 1);
+        assert_eq!(recv_state.nsubtasks.load(Ordering::Relaxed), 4 + 1);
+        let received = unsafe { std::slice::from_raw_parts(recv_ptr, data.len()) };
+        assert_eq!(received, &data[..]);
+    }
+
+    #[test]
+    fn test_pipelined_chunks() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.nstreams = 2;
+        net.min_chunksize = 1 << 20;
+        net.chunk_bytes = 256 * 1024;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        // Cut in 256 KiB chunks, but the last, unless under the threshold.
+        for &(nbytes, nchunks) in [(4 << 20, 16), ((4 << 20) + 1, 17), (1 << 20, 1)].iter() {
+            let data: Vec<u8> = (0..nbytes).map(|i| (i % 251) as u8).collect();
+            let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+            let recv_buf: &'static mut [u8] =
+                Box::leak(vec![0u8; nbytes + 4096].into_boxed_slice());
+            let recv_ptr = recv_buf.as_ptr();
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            let state = |net: &BaguaNet, id: SocketRequestID| match &net.socket_request_map[id] {
+                SocketRequest::SendRequest(request) => request.state.clone(),
+                SocketRequest::RecvRequest(request) => request.state.clone(),
+            };
+            let (send_state, recv_state) = (state(&net, send_req), state(&net, recv_req));
+            assert_eq!(wait_done(&mut net, send_req), nbytes);
+            assert_eq!(wait_done(&mut net, recv_req), nbytes);
+
+            assert_eq!(send_state.nsubtasks.load(Ordering::Relaxed), nchunks + 1);
+            assert_eq!(recv_state.nsubtasks.load(Ordering::Relaxed), nchunks + 1);
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+            assert_eq!(received, &data[..]);
+        }
+    }
+
+    #[test]
+    fn test_seq_check() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.nstreams = 2;
+        net.min_chunksize = 1 << 20;
+        net.chunk_bytes = 256 * 1024;
+        net.connect_config.seq_check = true;
+        net.accept_config.seq_check = true;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        // Inline messages have no chunks, so no headers either.
+        for &(nbytes, nchunks) in [(4 << 20, 16), (100, 0), ((1 << 20) + 1, 5)].iter() {
+            let data: Vec<u8> = (0..nbytes).map(|i| (i % 251) as u8).collect();
+            let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+            let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+            let recv_ptr = recv_buf.as_ptr();
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            let state = |net: &BaguaNet, id: SocketRequestID| match &net.socket_request_map[id] {
+                SocketRequest::SendRequest(request) => request.state.clone(),
+                SocketRequest::RecvRequest(request) => request.state.clone(),
+            };
+            let (send_state, recv_state) = (state(&net, send_req), state(&net, recv_req));
+            assert_eq!(wait_done(&mut net, send_req), nbytes);
+            assert_eq!(wait_done(&mut net, recv_req), nbytes);
+
+            // A header before each chunk.
+            assert_eq!(
+                send_state.nsubtasks.load(Ordering::Relaxed),
+                2 * nchunks + 1
+            );
+            assert_eq!(
+                recv_state.nsubtasks.load(Ordering::Relaxed),
+                2 * nchunks + 1
+            );
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+            assert_eq!(received, &data[..]);
+        }
+    }
+
+    #[test]
+    fn test_seq_check_out_of_sync() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.nstreams = 2;
+        net.min_chunksize = 0;
+        net.chunk_bytes = 4096;
+        net.connect_config.seq_check = true;
+        net.accept_config.seq_check = true;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let nstreams = net.nstreams;
+        let config = net.connect_config.clone();
+
+        let connector = std::thread::spawn(move || {
+            let comm_uuid = Uuid::new_v4();
+            let (ctrl_stream, _) =
+                connection::connect_ctrl_stream(&socket_handle, comm_uuid, nstreams, &config)
+                    .unwrap();
+            let data_streams: Vec<Stream> = (0..nstreams)
+                .map(|stream_id| {
+                    connection::connect_stream(
+                        &socket_handle,
+                        StreamHandshake {
+                            comm_uuid,
+                            stream_id,
+                        },
+                        &config,
+                    )
+                    .unwrap()
+                })
+                .collect();
+            (ctrl_stream, data_streams)
+        });
+        let recv_id = wait_accepted(&mut net, listen_id);
+        let (mut ctrl_stream, mut data_streams) = connector.join().unwrap();
+
+        // A sender that puts the two chunks of a message on the wrong streams.
+        ctrl_stream.write_all(&8192usize.to_be_bytes()[..]).unwrap();
+        for (index, stream) in data_streams.iter_mut().rev().enumerate() {
+            let header = ChunkHeader {
+                message: 0,
+                index: index as u32,
+                nbytes: 4096,
+            };
+            stream.write_all(&header.to_bytes()[..]).unwrap();
+            stream.write_all(&[0u8; 4096][..]).unwrap();
+        }
+
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 8192].into_boxed_slice());
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        let timer = std::time::Instant::now();
+        let err = loop {
+            match net.test(recv_req) {
+                Ok((done, _)) => assert!(!done),
+                Err(err) => break err,
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        };
+        let msg = format!("{:?}", err);
+        assert!(msg.contains("chunk header mismatch"), "{}", msg);
+        drop(data_streams);
+    }
+
+    fn stealing_net(connect: bool, accept: bool) -> BaguaNet {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.nstreams = 4;
+        net.min_chunksize = 1 << 20;
+        net.chunk_bytes = 256 * 1024;
+        net.connect_config.work_stealing = connect;
+        net.accept_config.work_stealing = accept;
+        net
+    }
+
+    #[test]
+    fn test_work_stealing() {
+        for &(connect, accept, seq_check) in [
+            (true, true, false),
+            (true, true, true),
+            (true, false, false),
+            (false, true, false),
+        ]
+        .iter()
+        {
+            let mut net = stealing_net(connect, accept);
+            net.connect_config.seq_check = seq_check;
+            net.accept_config.seq_check = seq_check;
+            let (socket_handle, listen_id) = net.listen(0).unwrap();
+            let send_id = net.connect(0, socket_handle).unwrap();
+            let recv_id = wait_accepted(&mut net, listen_id);
+            wait_connected(&mut net, send_id).unwrap();
+            // Unless both offer it, the chunks go round-robin, after their
+            // header only with seq_check.
+            let headers = (connect && accept) || seq_check;
+
+            // Posted after it was sent, its chunks wait for it.
+            for &(nbytes, nchunks, posted_first) in [
+                (4 << 20, 16, true),
+                (100, 0, true),
+                ((1 << 20) + 1, 5, false),
+                (4 << 20, 16, false),
+            ]
+            .iter()
+            {
+                let data: Vec<u8> = (0..nbytes).map(|i| (i % 251) as u8).collect();
+                let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+                let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+                let recv_ptr = recv_buf.as_ptr();
+                let mut recv_buf = Some(recv_buf);
+                let mut irecv = |net: &mut BaguaNet| {
+                    net.irecv(recv_id, recv_buf.take().unwrap().into(), None)
+                        .unwrap()
+                };
+                let (send_req, recv_req) = match posted_first {
+                    true => {
+                        let recv_req = irecv(&mut net);
+                        (net.isend(send_id, send_buf.into(), None).unwrap(), recv_req)
+                    }
+                    false => {
+                        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+                        std::thread::sleep(std::time::Duration::from_millis(50));
+                        (send_req, irecv(&mut net))
+                    }
+                };
+                let state = |net: &BaguaNet, id: SocketRequestID| match &net.socket_request_map[id]
+                {
+                    SocketRequest::SendRequest(request) => request.state.clone(),
+                    SocketRequest::RecvRequest(request) => request.state.clone(),
+                };
+                let (send_state, recv_state) = (state(&net, send_req), state(&net, recv_req));
+                assert_eq!(wait_done(&mut net, send_req), nbytes);
+                assert_eq!(wait_done(&mut net, recv_req), nbytes);
+
+                let nsubtasks = match headers {
+                    true => 2 * nchunks + 1,
+                    false => nchunks + 1,
+                };
+                assert_eq!(send_state.nsubtasks.load(Ordering::Relaxed), nsubtasks);
+                // The receiver reads the headers into the stream.
+                assert_eq!(
+                    recv_state.nsubtasks.load(Ordering::Relaxed),
+                    match connect && accept {
+                        true => nchunks + 1,
+                        false => nsubtasks,
+                    }
+                );
+                let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+                assert_eq!(received, &data[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_work_stealing_placement() {
+        let mut net = stealing_net(true, true);
+        net.nstreams = 2;
+        net.min_chunksize = 0;
+        net.chunk_bytes = 4096;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let nstreams = net.nstreams;
+        let config = net.connect_config.clone();
+
+        let connector = std::thread::spawn(move || {
+            let comm_uuid = Uuid::new_v4();
+            let (ctrl_stream, peer) =
+                connection::connect_ctrl_stream(&socket_handle, comm_uuid, nstreams, &config)
+                    .unwrap();
+            assert_eq!(peer.capabilities, CommHandshake::WORK_STEALING);
+            let data_streams =
+                connection::connect_data_streams(&socket_handle, comm_uuid, nstreams, &config)
+                    .unwrap();
+            (ctrl_stream, data_streams)
+        });
+        let recv_id = wait_accepted(&mut net, listen_id);
+        let (mut ctrl_stream, mut data_streams) = connector.join().unwrap();
+        let mut recv = |nbytes: usize| {
+            let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+            let recv_ptr = recv_buf.as_ptr();
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            let timer = std::time::Instant::now();
+            loop {
+                match net.test(recv_req) {
+                    Ok((true, _)) => {
+                        break Ok(unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) })
+                    }
+                    Ok((false, _)) => {}
+                    Err(err) => break Err(err),
+                }
+                assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+                std::thread::yield_now();
+            }
+        };
+        let write_chunk = |stream: &mut Stream, message: u32, index: u32, nbytes: usize| {
+            let header = ChunkHeader {
+                message,
+                index,
+                nbytes: nbytes as u64,
+            };
+            stream.write_all(&header.to_bytes()[..]).unwrap();
+            stream
+                .write_all(&vec![index as u8 + 1; nbytes][..])
+                .unwrap();
+        };
+
+        // Both chunks on the last stream, the second first.
+        ctrl_stream.write_all(&8192usize.to_be_bytes()[..]).unwrap();
+        write_chunk(&mut data_streams[1], 0, 1, 4096);
+        write_chunk(&mut data_streams[1], 0, 0, 4096);
+        let received = recv(8192).unwrap();
+        assert_eq!(&received[..4096], &[1u8; 4096][..]);
+        assert_eq!(&received[4096..], &[2u8; 4096][..]);
+
+        // A chunk of another size than its receiver expects.
+        ctrl_stream.write_all(&8192usize.to_be_bytes()[..]).unwrap();
+        write_chunk(&mut data_streams[0], 1, 0, 2048);
+        let msg = format!("{:?}", recv(8192).unwrap_err());
+        assert!(msg.contains("out of sync"), "{}", msg);
+        drop(data_streams);
+    }
+
+    #[test]
+    fn test_crc() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.nstreams = 2;
+        net.min_chunksize = 1 << 20;
+        net.chunk_bytes = 256 * 1024;
+        net.connect_config.crc = true;
+        net.accept_config.crc = true;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        for &(nbytes, nchunks) in [(4 << 20, 16), (100, 0), (0, 0), ((1 << 20) + 1, 5)].iter() {
+            let data: Vec<u8> = (0..nbytes).map(|i| (i % 251) as u8).collect();
+            let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+            let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+            let recv_ptr = recv_buf.as_ptr();
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            let state = |net: &BaguaNet, id: SocketRequestID| match &net.socket_request_map[id] {
+                SocketRequest::SendRequest(request) => request.state.clone(),
+                SocketRequest::RecvRequest(request) => request.state.clone(),
+            };
+            let recv_state = state(&net, recv_req);
+            assert_eq!(wait_done(&mut net, send_req), nbytes);
+            assert_eq!(wait_done(&mut net, recv_req), nbytes);
+
+            // Verifying the CRC is a subtask of its own. A zero-byte message
+            // is its header only.
+            let nsubtasks = match nbytes {
+                0 => 1,
+                _ => nchunks + 2,
+            };
+            assert_eq!(recv_state.nsubtasks.load(Ordering::Relaxed), nsubtasks);
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+            assert_eq!(received, &data[..]);
+        }
+    }
+
+    #[test]
+    fn test_crc_corruption() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.nstreams = 2;
+        net.min_chunksize = 0;
+        net.chunk_bytes = 4096;
+        net.connect_config.crc = true;
+        net.accept_config.crc = true;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let nstreams = net.nstreams;
+        let config = net.connect_config.clone();
+
+        let connector = std::thread::spawn(move || {
+            let comm_uuid = Uuid::new_v4();
+            let (ctrl_stream, _) =
+                connection::connect_ctrl_stream(&socket_handle, comm_uuid, nstreams, &config)
+                    .unwrap();
+            let data_streams: Vec<Stream> = (0..nstreams)
+                .map(|stream_id| {
+                    connection::connect_stream(
+                        &socket_handle,
+                        StreamHandshake {
+                            comm_uuid,
+                            stream_id,
+                        },
+                        &config,
+                    )
+                    .unwrap()
+                })
+                .collect();
+            (ctrl_stream, data_streams)
+        });
+        let recv_id = wait_accepted(&mut net, listen_id);
+        let (mut ctrl_stream, mut data_streams) = connector.join().unwrap();
+
+        // A sender whose NIC flips a bit of the second chunk, of a chunked
+        // and then of an inlined message.
+        let data: Vec<u8> = (0..8192).map(|i| i as u8).collect();
+        ctrl_stream.write_all(&8192usize.to_be_bytes()[..]).unwrap();
+        ctrl_stream
+            .write_all(&crc32c::crc32c(&data).to_be_bytes()[..])
+            .unwrap();
+        let mut corrupted = data.clone();
+        corrupted[4096 + 7] ^= 0x10;
+        for (stream, chunk) in data_streams.iter_mut().zip(corrupted.chunks(4096)) {
+            stream.write_all(chunk).unwrap();
+        }
+        ctrl_stream.write_all(&100usize.to_be_bytes()[..]).unwrap();
+        ctrl_stream.write_all(&corrupted[4096..4196]).unwrap();
+        ctrl_stream
+            .write_all(&crc32c::crc32c(&data[4096..4196]).to_be_bytes()[..])
+            .unwrap();
+
+        for &nbytes in [8192, 100].iter() {
+            let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            let timer = std::time::Instant::now();
+            let err = loop {
+                match net.test(recv_req) {
+                    Ok((done, _)) => assert!(!done),
+                    Err(err) => break err,
+                }
+                assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+                std::thread::yield_now();
+            };
+            assert!(matches!(err, BaguaNetError::Corruption(_)), "{:?}", err);
+        }
+        drop(data_streams);
+    }
+
+    /// Every other 64 bytes zero, like a sparse gradient, LZ4 halves it.
+    fn compressible(nbytes: usize) -> Vec<u8> {
+        (0..nbytes)
+            .map(|i| match i / 64 % 2 {
+                0 => rand::random::<u8>(),
+                _ => 0,
+            })
+            .collect()
+    }
+
+    fn compressing_net(threshold: usize) -> BaguaNet {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.compression = Some(Compression::new(CompressionConfig {
+            threshold,
+            threads: 2,
+        }));
+        net.connect_config.compression = true;
+        net.accept_config.compression = true;
+        net
+    }
+
+    #[test]
+    fn test_compression() {
+        for &crc in [false, true].iter() {
+            let mut net = compressing_net(64 * 1024);
+            net.nstreams = 2;
+            net.min_chunksize = 1 << 20;
+            net.chunk_bytes = 256 * 1024;
+            net.connect_config.crc = crc;
+            net.accept_config.crc = crc;
+            let (socket_handle, listen_id) = net.listen(0).unwrap();
+            let send_id = net.connect(0, socket_handle).unwrap();
+            let recv_id = wait_accepted(&mut net, listen_id);
+            wait_connected(&mut net, send_id).unwrap();
+
+            // Compressed, incompressible, and a last chunk too small to try.
+            let random: Vec<u8> = (0..2 << 20).map(|_| rand::random::<u8>()).collect();
+            for &(ref data, nchunks) in [
+                (compressible(4 << 20), 16),
+                (random, 8),
+                (compressible((1 << 20) + 100), 5),
+            ]
+            .iter()
+            {
+                let nbytes = data.len();
+                let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+                let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+                let recv_ptr = recv_buf.as_ptr();
+                let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+                let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+                let recv_state = match &net.socket_request_map[recv_req] {
+                    SocketRequest::RecvRequest(request) => request.state.clone(),
+                    _ => unreachable!(),
+                };
+                // Uncompressed.
+                assert_eq!(wait_done(&mut net, send_req), nbytes);
+                assert_eq!(wait_done(&mut net, recv_req), nbytes);
+
+                // A frame header before each chunk.
+                assert_eq!(
+                    recv_state.nsubtasks.load(Ordering::Relaxed),
+                    2 * nchunks + 1 + crc as usize
+                );
+                let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+                assert!(received == &data[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compression_mismatch() {
+        let mut net = compressing_net(0);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        net.connect_config.compression = false;
+        let send_id = net.connect(0, socket_handle).unwrap();
+
+        let msg = format!("{:?}", wait_rejected(&mut net, listen_id, send_id));
+        assert!(msg.contains("BAGUA_NET_COMPRESSION"), "{}", msg);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture
+    /// bench_compression`, over a link slower than loopback to see it pay.
+    #[test]
+    #[ignore]
+    fn bench_compression() {
+        let nbytes = 64 << 20;
+        let send_buf: &'static [u8] = Box::leak(compressible(nbytes).into_boxed_slice());
+        for &compress in [false, true].iter() {
+            let mut net = match compress {
+                true => compressing_net(64 * 1024),
+                false => loopback_net("127.0.0.1:0"),
+            };
+            net.nstreams = 2;
+            net.event_loops =
+                EventLoops::spawn(&net.threads, 2, false, &[], Duration::ZERO).unwrap();
+            let throughput = send_recv_throughput(net, send_buf, 16);
+            println!(
+                "64 MiB compressible messages, lz4={}: {:.1} MiB/s",
+                compress,
+                throughput / (1 << 20) as f64
+            );
+        }
+    }
+
+    #[test]
+    fn test_send_busy() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.nstreams = 1;
+        net.queue_capacity = 4;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        // Nothing is received, until the socket buffers and then the queues
+        // are full.
+        let nbytes = 1 << 20;
+        let data: Vec<u8> = (0..nbytes).map(|i| (i % 251) as u8).collect();
+        let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+        let timer = std::time::Instant::now();
+        let mut send_reqs = Vec::new();
+        loop {
+            match net.isend(send_id, send_buf.into(), None) {
+                Ok(id) => send_reqs.push(id),
+                Err(BaguaNetError::Busy) => break,
+                Err(err) => panic!("{:?}", err),
+            }
+            assert!(send_reqs.len() < 1000, "never busy");
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+        }
+        // Still queued.
+        assert!(!net.test(*send_reqs.last().unwrap()).unwrap().0);
+
+        // Every message posted before it was busy still goes through.
+        let mut recv_reqs = VecDeque::new();
+        for _ in 0..send_reqs.len() {
+            let recv_ptr = Box::leak(vec![0u8; nbytes].into_boxed_slice()).as_mut_ptr();
+            loop {
+                let recv_buf = unsafe { std::slice::from_raw_parts_mut(recv_ptr, nbytes) };
+                match net.irecv(recv_id, recv_buf.into(), None) {
+                    Ok(id) => {
+                        recv_reqs.push_back((id, recv_ptr));
+                        break;
+                    }
+                    Err(BaguaNetError::Busy) => {
+                        let (id, recv_ptr) = recv_reqs.pop_front().unwrap();
+                        assert_eq!(wait_done(&mut net, id), nbytes);
+                        let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+                        assert_eq!(received, &data[..]);
+                    }
+                    Err(err) => panic!("{:?}", err),
+                }
+            }
+        }
+        for (id, recv_ptr) in recv_reqs {
+            assert_eq!(wait_done(&mut net, id), nbytes);
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+            assert_eq!(received, &data[..]);
+        }
+        for id in send_reqs {
+            assert_eq!(wait_done(&mut net, id), nbytes);
+        }
+        assert!(net.isend(send_id, send_buf.into(), None).is_ok());
+    }
+
+    #[test]
+    fn test_recv_busy() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.queue_capacity = 4;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        // Those in the channel, and those the driver took.
+        let mut recv_reqs = Vec::new();
+        let recv_bufs: Vec<*mut u8> = (0..2 * 4 + 1)
+            .map(|_| Box::leak(vec![0u8; 64].into_boxed_slice()).as_mut_ptr())
+            .collect();
+        for &recv_ptr in recv_bufs.iter() {
+            let recv_buf = unsafe { std::slice::from_raw_parts_mut(recv_ptr, 64) };
+            match net.irecv(recv_id, recv_buf.into(), None) {
+                Ok(id) => recv_reqs.push((id, recv_ptr)),
+                Err(BaguaNetError::Busy) => break,
+                Err(err) => panic!("{:?}", err),
+            }
+        }
+        assert!(
+            recv_reqs.len() >= 4 && recv_reqs.len() <= 2 * 4,
+            "{}",
+            recv_reqs.len()
+        );
+
+        for (i, (id, recv_ptr)) in recv_reqs.into_iter().enumerate() {
+            let send_buf: &'static [u8] = Box::leak(vec![i as u8; 64].into_boxed_slice());
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            assert_eq!(wait_done(&mut net, send_req), 64);
+            assert_eq!(wait_done(&mut net, id), 64);
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, 64) };
+            assert_eq!(received, send_buf);
+        }
+    }
+
+    #[test]
+    fn test_inflight_limit() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.max_inflight = 2;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        let recv_buf = || -> RecvBuffer { Box::leak(vec![0u8; 64].into_boxed_slice()).into() };
+        let send_buf: &'static [u8] = Box::leak(vec![1u8; 64].into_boxed_slice());
+
+        let recv_reqs = [
+            net.irecv(recv_id, recv_buf(), None).unwrap(),
+            net.irecv(recv_id, recv_buf(), None).unwrap(),
+        ];
+        assert!(matches!(
+            net.irecv(recv_id, recv_buf(), None),
+            Err(BaguaNetError::Busy)
+        ));
+        assert_eq!(net.state.inflight_requests.load(Ordering::Relaxed), 2);
+        // Per comm.
+        let send_reqs = [
+            net.isend(send_id, send_buf.into(), None).unwrap(),
+            net.isend(send_id, send_buf.into(), None).unwrap(),
+        ];
+        assert!(matches!(
+            net.isend(send_id, send_buf.into(), None),
+            Err(BaguaNetError::Busy)
+        ));
+        assert_eq!(net.state.inflight_requests.load(Ordering::Relaxed), 4);
+
+        // Counted until tested done, cancelled or failed.
+        for &id in send_reqs.iter().chain(recv_reqs.iter()) {
+            assert_eq!(wait_done(&mut net, id), 64);
+        }
+        assert_eq!(net.state.inflight_requests.load(Ordering::Relaxed), 0);
+        let small: RecvBuffer = Box::leak(vec![0u8; 16].into_boxed_slice()).into();
+        let failed = net.irecv(recv_id, small, None).unwrap();
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        let timer = std::time::Instant::now();
+        while net.test(failed).is_ok() {
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+        }
+        drop(net.test(send_req));
+        let id = net.irecv(recv_id, recv_buf(), None).unwrap();
+        net.cancel(id).unwrap();
+        assert!(net.irecv(recv_id, recv_buf(), None).is_ok());
+        assert!(net.irecv(recv_id, recv_buf(), None).is_ok());
+    }
+
+    #[test]
+    fn test_request_timing() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let nbytes = 4 << 20;
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+        let send_buf: &'static [u8] = Box::leak(vec![1u8; nbytes].into_boxed_slice());
+        let timer = std::time::Instant::now();
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        assert_eq!(net.request_timing(recv_req).unwrap(), None);
+        // The receive waits for the message.
+        std::thread::sleep(Duration::from_millis(20));
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        let timings: Vec<RequestTiming> = [send_req, recv_req]
+            .iter()
+            .map(|&id| loop {
+                if let Some(timing) = net.request_timing(id).unwrap() {
+                    break timing;
+                }
+                assert!(timer.elapsed() < Duration::from_secs(10));
+                std::thread::yield_now();
+            })
+            .collect();
+        assert!(
+            timings[1].queued >= Duration::from_millis(20),
+            "{:?}",
+            timings
+        );
+        for timing in timings {
+            assert!(timing.queued + timing.wire <= timer.elapsed());
+        }
+
+        // Until it is tested done.
+        for id in [send_req, recv_req] {
+            assert_eq!(net.test(id).unwrap(), (true, nbytes));
+            assert!(net.request_timing(id).is_err());
+        }
+    }
+
+    #[test]
+    fn test_chunk_spans() {
+        use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceId, TraceState};
+        assert!(chunk_span("isend-chunk", &RequestState::new(1), 0, 1024).is_none());
+        let parent = SpanContext::new(
+            TraceId::from_u128(1),
+            SpanId::from_u64(2),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let state = RequestState::new(1).traced(Some(
+            opentelemetry::Context::new().with_remote_span_context(parent.clone()),
+        ));
+        let span = chunk_span("isend-chunk", &state, 0, 1024).unwrap();
+        assert_eq!(span.span_context().trace_id(), parent.trace_id());
+
+        // Ended by the streams, with their chunks in flight.
+        let mut net = loopback_net("127.0.0.1:0");
+        net.nstreams = 2;
+        net.trace_chunks = true;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        let nbytes = 4 << 20;
+        let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+        let send_req = net.isend(send_id, leak(nbytes, 1).into(), None).unwrap();
+        assert_eq!(wait_done(&mut net, send_req), nbytes);
+        assert_eq!(wait_done(&mut net, recv_req), nbytes);
+    }
+
+    #[test]
+    fn test_comm_lifecycle_events() {
+        use crate::connection::tests::logged;
+        let mut net = loopback_net("127.0.0.1:0");
+        let ((listen_id, send_id), listened) = logged(|| {
+            let (socket_handle, listen_id) = net.listen(0).unwrap();
+            (listen_id, net.connect(0, socket_handle).unwrap())
+        });
+        assert!(listened.contains("listening"), "{}", listened);
+        assert!(listened.contains(&format!("listen_comm={}", listen_id)));
+        assert!(listened.contains("dev=0"), "{}", listened);
+        let (recv_id, accepted) = logged(|| wait_accepted(&mut net, listen_id));
+        assert!(accepted.contains("recv comm accepted"), "{}", accepted);
+        assert!(accepted.contains(&format!("recv_comm={}", recv_id)));
+        assert!(accepted.contains("comm_uuid="), "{}", accepted);
+        assert!(accepted.contains(&format!("nstreams={}", net.nstreams)));
+
+        // Of the messages, inlined or not.
+        for nbytes in [1 << 20, 1024] {
+            let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+            let send_req = net.isend(send_id, leak(nbytes, 1).into(), None).unwrap();
+            wait_done(&mut net, send_req);
+            wait_done(&mut net, recv_req);
+        }
+        let nbytes = (1 << 20) + 1024;
+        let ((), events) = logged(|| {
+            net.close_send(send_id).unwrap();
+            net.close_recv(recv_id).unwrap();
+        });
+        for closed in [
+            format!("send comm closed send_comm={}", send_id),
+            format!("recv comm closed recv_comm={}", recv_id),
+        ] {
+            let line = events.lines().find(|line| line.contains(&closed));
+            let line = line.unwrap_or_else(|| panic!("{}", events));
+            assert!(line.contains(&format!("nbytes={}", nbytes)), "{}", line);
+            assert!(line.contains("lifetime_ms="), "{}", line);
+            assert!(line.contains("drained=true"), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_trace_off() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        let has_span = |net: &BaguaNet, id| match net.socket_request_map.get(id) {
+            Some(SocketRequest::SendRequest(send_req)) => send_req.trace_span.0.is_some(),
+            Some(SocketRequest::RecvRequest(recv_req)) => recv_req.trace_span.0.is_some(),
+            None => unreachable!(),
+        };
+        let nbytes = 1 << 20;
+        let send_req = net.isend(send_id, leak(nbytes, 1).into(), None).unwrap();
+        assert!(has_span(&net, send_req));
+        let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+        wait_done(&mut net, send_req);
+        wait_done(&mut net, recv_req);
+
+        // `BAGUA_NET_TRACE=off`.
+        net.span_sampler = None;
+        let send_req = net.isend(send_id, leak(nbytes, 1).into(), None).unwrap();
+        let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+        assert!(!has_span(&net, send_req));
+        assert!(!has_span(&net, recv_req));
+        assert_eq!(wait_done(&mut net, send_req), nbytes);
+        assert_eq!(wait_done(&mut net, recv_req), nbytes);
+    }
+
+    #[test]
+    fn test_send_stream_throughput() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.nstreams = 2;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        let nbytes = 4 << 20;
+        let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+        let send_req = net.isend(send_id, leak(nbytes, 1).into(), None).unwrap();
+        wait_done(&mut net, send_req);
+        wait_done(&mut net, recv_req);
+
+        // Of each data stream.
+        let streams = net.state.send_streams.streams();
+        assert_eq!(streams.len(), 2);
+        for throughput in streams.iter() {
+            assert!(throughput.nbytes.load(Ordering::Relaxed) > 0);
+            assert!(throughput.busy_ns.load(Ordering::Relaxed) > 0);
+        }
+        let sent: u64 = streams
+            .iter()
+            .map(|throughput| throughput.nbytes.load(Ordering::Relaxed))
+            .sum();
+        assert_eq!(sent, nbytes as u64);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture
+    /// bench_chunk_bytes`.
+    #[test]
+    #[ignore]
+    fn bench_chunk_bytes() {
+        let nbytes = 64 << 20;
+        for &nchunks in [2, 16].iter() {
+            let mut net = loopback_net("127.0.0.1:0");
+            net.nstreams = 2;
+            net.chunk_bytes = nbytes / nchunks;
+            net.event_loops =
+                EventLoops::spawn(&net.threads, 2, false, &[], Duration::ZERO).unwrap();
+            let send_buf = Box::leak(vec![1u8; nbytes].into_boxed_slice());
+            let throughput = send_recv_throughput(net, send_buf, 16);
+            println!(
+                "64 MiB messages in {} chunks: {:.1} MiB/s",
+                nchunks,
+                throughput / (1 << 20) as f64
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_queued_chunks() {
+        let (stream, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut stream = RecvStream::new(Stream::Unix(stream), None, flume::bounded(0).0);
+        let state = Arc::new(RequestState::new(1));
+        let buf = |nbytes: usize| -> &'static mut [u8] {
+            Box::leak(vec![0u8; nbytes].into_boxed_slice())
+        };
+        for &nbytes in [3, 5, 2].iter() {
+            stream
+                .chunks
+                .push_back(Chunk::new(buf(nbytes), state.clone()));
+        }
+        stream
+            .chunks
+            .push_back(Chunk::<&'static mut [u8]>::frame_header(state.clone()));
+        stream.chunks.push_back(Chunk::new(buf(4), state.clone()));
+        let data: Vec<u8> = (1..=10 + FrameHeader::NBYTES as u8 + 4).collect();
+
+        // Ends in the second chunk, which the next read goes on with.
+        peer.write_all(&data[..6]).unwrap();
+        assert!(stream.read_queued().unwrap());
+        assert!(!stream.io.readable);
+        let pos: Vec<usize> = stream.chunks.iter().map(|chunk| chunk.pos).collect();
+        assert_eq!(pos, [3, 3, 0, 0, 0]);
+        stream.chunks.pop_front();
+        stream.io.readable = true;
+        assert!(!stream.read_queued().unwrap());
+
+        // Up to the frame header, not into the chunk after it.
+        peer.write_all(&data[6..]).unwrap();
+        stream.io.readable = true;
+        assert!(stream.read_queued().unwrap());
+        let pos: Vec<usize> = stream.chunks.iter().map(|chunk| chunk.pos).collect();
+        assert_eq!(pos, [5, 2, FrameHeader::NBYTES, 0]);
+        assert_eq!(stream.received, (10 + FrameHeader::NBYTES) as u64);
+        assert_eq!(&stream.chunks[0].data[..], &data[3..8]);
+        assert_eq!(&stream.chunks[1].data[..], &data[8..10]);
+        for _ in 0..3 {
+            stream.chunks.pop_front();
+        }
+        assert!(stream.read_queued().unwrap());
+        assert_eq!(
+            &stream.chunks[0].data[..],
+            &data[10 + FrameHeader::NBYTES..]
+        );
+
+        drop(peer);
+        stream.chunks.push_back(Chunk::new(buf(1), state));
+        stream.chunks.pop_front();
+        let err = stream.read_queued().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_growth_window() {
+        let config = |window| AdaptiveStreamsConfig {
+            max_nstreams: 4,
+            window,
+            saturation: 0.5,
+        };
+        let mut window = GrowthWindow::new(config(Duration::ZERO), f64::INFINITY);
+        assert!(window.observe(10, true).is_some());
+        // Idle at some point of the window, or saturating.
+        assert!(window.observe(10, false).is_none());
+        assert!(window.observe(10, true).is_some());
+        let mut window = GrowthWindow::new(config(Duration::ZERO), 0.);
+        assert!(window.observe(10, true).is_none());
+
+        let mut window = GrowthWindow::new(config(Duration::from_secs(3600)), f64::INFINITY);
+        assert!(window.observe(10, true).is_none());
+        assert_eq!(window.sent, 10);
+    }
+
+    #[test]
+    fn test_imbalance_window() {
+        let config = |window| ImbalanceConfig {
+            ratio: 2.,
+            window,
+            windows: 2,
+            rehash: false,
+        };
+        let ms = Duration::from_millis;
+        let mut window = ImbalanceWindow::new(config(Duration::ZERO));
+        assert!(window.ended(true));
+        let uneven = [(300, ms(1)), (100, ms(1)), (0, Duration::ZERO)];
+        assert_eq!(
+            window.close(&uneven),
+            Some(Imbalance {
+                ratio: 3.,
+                slowest: 1,
+                persistent: false,
+            })
+        );
+        // Idle at some point of the window, or too few streams wrote.
+        assert!(window.ended(false));
+        assert!(window.close(&uneven).is_none());
+        assert!(window.ended(true));
+        assert!(window.close(&uneven[1..]).is_none());
+        assert!(window.ended(true));
+        assert!(window.close(&uneven).unwrap().persistent);
+        // Which starts another streak, broken by even windows.
+        assert!(window.ended(true));
+        assert!(!window.close(&uneven).unwrap().persistent);
+        assert!(window.ended(true));
+        assert_eq!(
+            window.close(&[(100, ms(1)), (200, ms(2))]).unwrap().ratio,
+            1.
+        );
+        assert!(window.ended(true));
+        assert!(!window.close(&uneven).unwrap().persistent);
+
+        let mut window = ImbalanceWindow::new(config(Duration::from_secs(3600)));
+        assert!(!window.ended(true));
+    }
+
+    #[test]
+    fn test_imbalance_rehash() {
+        let mut net = stealing_net(true, true);
+        // Never even.
+        net.imbalance = Some(ImbalanceConfig {
+            ratio: 1.,
+            window: Duration::from_millis(5),
+            windows: 1,
+            rehash: true,
+        });
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let timer = Instant::now();
+        let nbytes = 4 << 20;
+        let data: Vec<u8> = (0..nbytes).map(|i| (i % 251) as u8).collect();
+        while net.state.stream_rehashes.load(Ordering::Relaxed) < 2 {
+            assert!(timer.elapsed() < Duration::from_secs(30));
+            let mut requests = Vec::new();
+            for _ in 0..8 {
+                let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+                let recv_buf = leak(nbytes, 0);
+                let recv_ptr = recv_buf.as_ptr();
+                let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+                let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+                requests.push((send_req, recv_req, recv_ptr));
+            }
+            for (send_req, recv_req, recv_ptr) in requests {
+                assert_eq!(wait_done(&mut net, send_req), nbytes);
+                assert_eq!(wait_done(&mut net, recv_req), nbytes);
+                let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+                assert_eq!(received, &data[..]);
+            }
+        }
+        let comms = net.state.send_queues.comms();
+        assert!(comms[0].1.imbalance.lock().unwrap().is_some());
+        net.close_send(send_id).unwrap();
+        net.close_recv(recv_id).unwrap();
+    }
+
+    /// Grows every window of `window_ms`, up to `max_nstreams`.
+    fn adaptive_net(window_ms: u64, max_nstreams: usize) -> BaguaNet {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.nstreams = AdaptiveStreamsConfig::INITIAL_NSTREAMS;
+        net.adaptive_streams = Some(AdaptiveStreamsConfig {
+            max_nstreams,
+            window: Duration::from_millis(window_ms),
+            saturation: f64::INFINITY,
+        });
+        net.connect_config.max_nstreams = max_nstreams;
+        net.accept_config.max_nstreams = max_nstreams;
+        net
+    }
+
+    #[test]
+    fn test_adaptive_streams() {
+        let mut net = adaptive_net(10, 4);
+        enable_conn_cache(&mut net, true, false);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        // Posted at once, the comm is backlogged for several windows.
+        let buffers: Vec<(Vec<u8>, &'static [u8], &'static mut [u8])> = (0..32)
+            .map(|i| {
+                let data: Vec<u8> = (0..4 * 1024 * 1024).map(|j| (i + j) as u8).collect();
+                let send_buf = Box::leak(data.clone().into_boxed_slice());
+                let recv_buf = Box::leak(vec![0u8; data.len()].into_boxed_slice());
+                (data, &*send_buf, recv_buf)
+            })
+            .collect();
+        let requests: Vec<(SocketRequestID, SocketRequestID, Vec<u8>, *const u8)> = buffers
+            .into_iter()
+            .map(|(data, send_buf, recv_buf)| {
+                let recv_ptr = recv_buf.as_ptr();
+                let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+                let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+                (send_req, recv_req, data, recv_ptr)
+            })
+            .collect();
+        for (send_req, recv_req, data, recv_ptr) in requests {
+            assert_eq!(wait_done(&mut net, send_req), data.len());
+            assert_eq!(wait_done(&mut net, recv_req), data.len());
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, data.len()) };
+            assert_eq!(received, &data[..]);
+        }
+
+        close_comm(&mut net, send_id, recv_id);
+        let cache = net.send_comm_cache.as_ref().unwrap().lock().unwrap();
+        let nstreams = cache.entries[0].1.group.data_streams.len();
+        assert!(nstreams > 2 && nstreams <= 4, "{}", nstreams);
+    }
+
+    #[test]
+    fn test_min_chunksize_mismatch() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        net.connect_config.min_chunksize = 65536;
+        let send_id = net.connect(0, socket_handle).unwrap();
+
+        let msg = format!("{:?}", wait_rejected(&mut net, listen_id, send_id));
+        assert!(msg.contains("local min_chunksize=65536"), "{}", msg);
+    }
+
+    #[test]
+    fn test_tuned_min_chunksize() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.nstreams = 2;
+        net.chunk_bytes = 0;
+        net.connect_config.min_chunksize = CommHandshake::AUTO_MIN_CHUNKSIZE;
+        net.accept_config.min_chunksize = CommHandshake::AUTO_MIN_CHUNKSIZE;
+        // Out of sync, the receiver would fail the chunk headers.
+        net.connect_config.seq_check = true;
+        net.accept_config.seq_check = true;
+        // 256 KiB messages were sent whole, so splitting them is probed once
+        // the period ends.
+        let mut tuner = SplitTuner::new(Duration::from_millis(500));
+        for _ in 0..8 {
+            tuner.record(256 << 10, 1 << 20, Duration::from_millis(1));
+        }
+        let tuner = Arc::new(Mutex::new(tuner));
+        net.split_tuner = Some(tuner.clone());
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let mut nchunks = Vec::new();
+        let mut i = 0;
+        while nchunks.iter().filter(|&&n| n == 2).count() < 4 {
+            i += 1;
+            let nbytes = 256 << 10;
+            let data: Vec<u8> = (0..nbytes).map(|j| (i + j) as u8).collect();
+            let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+            let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+            let recv_ptr = recv_buf.as_ptr();
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            let state = |net: &BaguaNet, id: SocketRequestID| match &net.socket_request_map[id] {
+                SocketRequest::SendRequest(request) => request.state.clone(),
+                SocketRequest::RecvRequest(request) => request.state.clone(),
+            };
+            let (send_state, recv_state) = (state(&net, send_req), state(&net, recv_req));
+            assert_eq!(wait_done(&mut net, send_req), nbytes);
+            assert_eq!(wait_done(&mut net, recv_req), nbytes);
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+            assert_eq!(received, &data[..]);
+
+            // A header before each chunk.
+            let subtasks = send_state.nsubtasks.load(Ordering::Relaxed);
+            assert_eq!(recv_state.nsubtasks.load(Ordering::Relaxed), subtasks);
+            nchunks.push((subtasks - 1) / 2);
+            assert!(i < 100000, "{:?}", nchunks);
+        }
+        assert_eq!(tuner.lock().unwrap().threshold(), 1 << 17);
+        // Whole until the receiver was told otherwise.
+        assert_eq!(nchunks[0], 1);
+    }
+
+    #[test]
+    fn test_coalesced_ctrl_writes() {
+        let mut net = inline_net(4096);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+
+        // Queued until the comm is accepted, then taken at once.
+        let nmessages = 200;
+        let send_reqs: Vec<SocketRequestID> = (0..nmessages)
+            .map(|i| {
+                let send_buf: &'static [u8] = Box::leak(vec![i as u8; 1024].into_boxed_slice());
+                net.isend(send_id, send_buf.into(), None).unwrap()
+            })
+            .collect();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        for (i, send_req) in send_reqs.into_iter().enumerate() {
+            let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 1024].into_boxed_slice());
+            let recv_ptr = recv_buf.as_ptr();
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            assert_eq!(wait_done(&mut net, recv_req), 1024);
+            assert_eq!(wait_done(&mut net, send_req), 1024);
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, 1024) };
+            assert_eq!(received, &[i as u8; 1024][..]);
+        }
+
+        let writes = net.state.ctrl_writes.load(Ordering::Relaxed);
+        assert_eq!(net.state.ctrl_messages.load(Ordering::Relaxed), nmessages);
+        // 200 KiB in writes of 64 KiB, and some when the socket is full.
+        assert!(writes >= 4 && writes < nmessages / 4, "{} writes", writes);
+    }
+
+    #[test]
+    fn test_send_recv_staged() {
+        // The sender computes the CRCs of the staged chunks as they are
+        // copied, the CRC of the message follows once they all are. They
+        // are compressed once copied too.
+        for &(crc, compress) in [(false, false), (true, false), (true, true)].iter() {
+            let device = Arc::new(FakeDevice::default());
+            let mut net = inline_net(4096);
+            net.min_chunksize = 256 * 1024;
+            net.connect_config.crc = crc;
+            net.accept_config.crc = crc;
+            if compress {
+                net.compression = Some(Compression::new(CompressionConfig {
+                    threshold: 0,
+                    threads: 2,
+                }));
+                net.connect_config.compression = true;
+                net.accept_config.compression = true;
+            }
+            net.staging = Some(Staging::new(
+                device.clone(),
+                StagingConfig {
+                    pool_size: 4,
+                    depth: 2,
+                },
+            ));
+            assert_eq!(
+                net.get_properties(0).unwrap().ptr_support,
+                NCCL_PTR_HOST | NCCL_PTR_CUDA
+            );
+            let (socket_handle, listen_id) = net.listen(0).unwrap();
+            let send_id = net.connect(0, socket_handle).unwrap();
+            let recv_id = wait_accepted(&mut net, listen_id);
+            wait_connected(&mut net, send_id).unwrap();
+
+            // Either end on the device, and an inlined one.
+            let mut staged = 0;
+            for &(nbytes, send_on_device, recv_on_device) in [
+                (4 << 20, true, true),
+                (1 << 20, false, true),
+                ((1 << 20) + 3, true, false),
+                (100, true, true),
+            ]
+            .iter()
+            {
+                let data: Vec<u8> = (0..nbytes).map(|i| (i * 13 + nbytes) as u8).collect();
+                let alloc = |data: Vec<u8>, on_device: bool| -> &'static mut [u8] {
+                    if on_device {
+                        device.alloc(data)
+                    } else {
+                        Box::leak(data.into_boxed_slice())
+                    }
+                };
+                let send_buf: &'static [u8] = alloc(data.clone(), send_on_device);
+                let recv_buf = alloc(vec![0u8; nbytes], recv_on_device);
+                let recv_ptr = recv_buf.as_ptr();
+
+                let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+                let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+                assert_eq!(wait_done(&mut net, send_req), nbytes);
+                assert_eq!(wait_done(&mut net, recv_req), nbytes);
+                let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+                assert_eq!(received, &data[..]);
+                staged += nbytes * (send_on_device as usize + recv_on_device as usize);
+            }
+            assert_eq!(device.copied.load(Ordering::Relaxed), staged);
+        }
+    }
+
+    #[test]
+    fn test_iflush() {
+        let device = Arc::new(FakeDevice::default());
+        let mut net = inline_net(4096);
+        net.min_chunksize = 256 * 1024;
+        net.staging = Some(Staging::new(
+            device.clone(),
+            StagingConfig {
+                pool_size: 4,
+                depth: 2,
+            },
+        ));
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let host_buf: &'static mut [u8] = Box::leak(vec![0u8; 100].into_boxed_slice());
+        assert_eq!(net.iflush(recv_id, host_buf).unwrap(), None);
+        let err = net.iflush(recv_id + 1000, device.alloc(vec![0u8; 100]));
+        assert!(matches!(err, Err(BaguaNetError::InvalidId { .. })));
+
+        let nbytes = 1 << 20;
+        let first: Vec<u8> = (0..nbytes).map(|i| (i * 7) as u8).collect();
+        let second: Vec<u8> = (0..nbytes).map(|i| (i * 11) as u8).collect();
+        let first_buf = device.alloc(vec![0u8; nbytes]);
+        let first_ptr = first_buf.as_mut_ptr();
+        let second_buf = device.alloc(vec![0u8; nbytes]);
+        let second_ptr = second_buf.as_mut_ptr();
+        let first_req = net.irecv(recv_id, first_buf.into(), None).unwrap();
+        let second_req = net.irecv(recv_id, second_buf.into(), None).unwrap();
+
+        // Waits for the receive into its buffer only.
+        let flush_buf = || unsafe { std::slice::from_raw_parts_mut(first_ptr, nbytes) };
+        let flush_req = net.iflush(recv_id, flush_buf()).unwrap().unwrap();
+        let unrelated_req = net
+            .iflush(recv_id, device.alloc(vec![0u8; 100]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(net.test(unrelated_req).unwrap(), (true, 0));
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(net.test(flush_req).unwrap(), (false, 0));
+
+        let send_req = net
+            .isend(
+                send_id,
+                Box::leak(first.clone().into_boxed_slice()).into(),
+                None,
+            )
+            .unwrap();
+        assert_eq!(wait_done(&mut net, flush_req), 0);
+        assert_eq!(net.test(first_req).unwrap(), (true, nbytes));
+        let received = unsafe { std::slice::from_raw_parts(first_ptr, nbytes) };
+        assert_eq!(received, &first[..]);
+        assert_eq!(wait_done(&mut net, send_req), nbytes);
+        assert_eq!(net.test(second_req).unwrap(), (false, 0));
+
+        // Overlapping the second buffer, waited for.
+        let overlap = unsafe { std::slice::from_raw_parts_mut(second_ptr.add(100), 100) };
+        let flush_req = net.iflush(recv_id, overlap).unwrap().unwrap();
+        let send_req = net
+            .isend(
+                send_id,
+                Box::leak(second.clone().into_boxed_slice()).into(),
+                None,
+            )
+            .unwrap();
+        let timeout = Some(std::time::Duration::from_secs(10));
+        assert_eq!(net.wait(flush_req, timeout).unwrap(), 0);
+        assert_eq!(net.test(second_req).unwrap(), (true, nbytes));
+        let received = unsafe { std::slice::from_raw_parts(second_ptr, nbytes) };
+        assert_eq!(received, &second[..]);
+        assert_eq!(wait_done(&mut net, send_req), nbytes);
+
+        // Nothing posted into it anymore.
+        let flush_req = net.iflush(recv_id, flush_buf()).unwrap().unwrap();
+        assert_eq!(net.test(flush_req).unwrap(), (true, 0));
+    }
+
+    #[test]
+    fn test_inline_threshold_mismatch() {
+        let mut net = inline_net(4096);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        net.connect_config.inline_threshold = 0;
+        let send_id = net.connect(0, socket_handle).unwrap();
+
+        let msg = format!("{:?}", wait_rejected(&mut net, listen_id, send_id));
+        assert!(
+            msg.contains("local inline_threshold=0, peer inline_threshold=4096"),
+            "{}",
+            msg
+        );
+    }
+
+    fn wait_failed(net: &mut BaguaNet, id: SocketRequestID) -> BaguaNetError {
+        let timer = std::time::Instant::now();
+        loop {
+            match net.test(id) {
+                Ok((done, _)) => assert!(!done),
+                Err(err) => return err,
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+    }
+
+    fn fixed_size_comms(net: &mut BaguaNet, nbytes: usize) -> (SocketSendCommID, SocketRecvCommID) {
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(net, listen_id);
+        wait_connected(net, send_id).unwrap();
+        net.set_fixed_message_size(send_id, nbytes).unwrap();
+        net.set_recv_fixed_message_size(recv_id, nbytes).unwrap();
+        (send_id, recv_id)
+    }
+
+    #[test]
+    fn test_fixed_message_size() {
+        let mut net = inline_net(4096);
+        net.nstreams = 2;
+        net.min_chunksize = 1024;
+        net.chunk_bytes = 64 * 1024;
+        let nbytes = 200 * 1000;
+        let (send_id, recv_id) = fixed_size_comms(&mut net, nbytes);
+
+        let nmessages = 8;
+        let requests: Vec<(SocketRequestID, SocketRequestID, Vec<u8>, *const u8)> = (0..nmessages)
+            .map(|i| {
+                let data: Vec<u8> = (0..nbytes).map(|j| (i + j) as u8).collect();
+                let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+                let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+                let recv_ptr = recv_buf.as_ptr();
+                let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+                let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+                (send_req, recv_req, data, recv_ptr)
+            })
+            .collect();
+        for (send_req, recv_req, data, recv_ptr) in requests {
+            assert_eq!(wait_done(&mut net, send_req), nbytes);
+            assert_eq!(wait_done(&mut net, recv_req), nbytes);
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+            assert_eq!(received, &data[..]);
+        }
+        // The size, once.
+        assert_eq!(net.state.ctrl_messages.load(Ordering::Relaxed), 1);
+
+        // Neither smaller messages, nor changing the size once they go.
+        let small: &'static [u8] = Box::leak(vec![0u8; 100].into_boxed_slice());
+        assert!(net.isend(send_id, small.into(), None).is_err());
+        let small: &'static mut [u8] = Box::leak(vec![0u8; 100].into_boxed_slice());
+        assert!(net.irecv(recv_id, small.into(), None).is_err());
+        assert!(net.set_fixed_message_size(send_id, 100).is_err());
+        assert!(net.set_recv_fixed_message_size(recv_id, 100).is_err());
+
+        // Queued right away, and failed once the sender closes the comm.
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        net.close_send(send_id).unwrap();
+        let err = wait_failed(&mut net, recv_req);
+        assert!(
+            format!("{:?}", err).contains("closed by the sender"),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_fixed_message_size_close() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let nbytes = 4096;
+        let post_recv = |net: &mut BaguaNet, recv_id| {
+            let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+            net.irecv(recv_id, recv_buf.into(), None)
+        };
+        let check_closed = |err: BaguaNetError| {
+            assert!(
+                format!("{:?}", err).contains("closed by the sender"),
+                "{:?}",
+                err
+            );
+        };
+
+        // Posted before the sender closed the comm after 2 messages.
+        let (send_id, recv_id) = fixed_size_comms(&mut net, nbytes);
+        let recv_reqs: Vec<SocketRequestID> = (0..4)
+            .map(|_| post_recv(&mut net, recv_id).unwrap())
+            .collect();
+        for _ in 0..2 {
+            let send_buf: &'static [u8] = Box::leak(vec![7u8; nbytes].into_boxed_slice());
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            assert_eq!(wait_done(&mut net, send_req), nbytes);
+        }
+        net.close_send(send_id).unwrap();
+        assert_eq!(wait_done(&mut net, recv_reqs[0]), nbytes);
+        assert_eq!(wait_done(&mut net, recv_reqs[1]), nbytes);
+        for &recv_req in recv_reqs[2..].iter() {
+            check_closed(wait_failed(&mut net, recv_req));
+        }
+
+        // Posted after, the messages sent are still received.
+        let (send_id, recv_id) = fixed_size_comms(&mut net, nbytes);
+        let recv_req = post_recv(&mut net, recv_id).unwrap();
+        let send_reqs: Vec<SocketRequestID> = (0..3)
+            .map(|i| {
+                let send_buf: &'static [u8] = Box::leak(vec![i as u8; nbytes].into_boxed_slice());
+                net.isend(send_id, send_buf.into(), None).unwrap()
+            })
+            .collect();
+        net.close_send(send_id).unwrap();
+        assert_eq!(wait_done(&mut net, recv_req), nbytes);
+        for send_req in send_reqs {
+            assert_eq!(wait_done(&mut net, send_req), nbytes);
+        }
+        for _ in 0..2 {
+            let recv_req = post_recv(&mut net, recv_id).unwrap();
+            assert_eq!(wait_done(&mut net, recv_req), nbytes);
+        }
+        // Unless it already knows that none follow.
+        match post_recv(&mut net, recv_id) {
+            Ok(recv_req) => check_closed(wait_failed(&mut net, recv_req)),
+            Err(err) => check_closed(err),
+        }
+    }
+
+    #[test]
+    fn test_fixed_message_size_mismatch() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        // Only the sender fixes the size.
+        net.set_fixed_message_size(send_id, 1024).unwrap();
+        let send_buf: &'static [u8] = Box::leak(vec![0u8; 1024].into_boxed_slice());
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 1024].into_boxed_slice());
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        let msg = format!("{:?}", wait_failed(&mut net, recv_req));
+        assert!(msg.contains("expects messages of None bytes"), "{}", msg);
+        let msg = format!("{:?}", wait_failed(&mut net, send_req));
+        assert!(
+            msg.contains("does not expect messages of 1024 bytes"),
+            "{}",
+            msg
+        );
+
+        // Not with CRCs.
+        net.connect_config.crc = true;
+        let (socket_handle, _) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        assert!(net.set_fixed_message_size(send_id, 1024).is_err());
+    }
+
+    /// Average time for a message of `nbytes` to be sent and received.
+    fn send_recv_latency(mut net: BaguaNet, nbytes: usize, iterations: u32) -> std::time::Duration {
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let send_buf: &'static [u8] = Box::leak(vec![1u8; nbytes].into_boxed_slice());
+        let recv_ptr = Box::leak(vec![0u8; nbytes].into_boxed_slice()).as_mut_ptr();
+        let timer = std::time::Instant::now();
+        for _ in 0..iterations {
+            let recv_buf = unsafe { std::slice::from_raw_parts_mut(recv_ptr, nbytes) };
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            wait_done(&mut net, send_req);
+            wait_done(&mut net, recv_req);
+        }
+
+        timer.elapsed() / iterations
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_inline`.
+    #[test]
+    #[ignore]
+    fn bench_inline() {
+        for &nbytes in [256, 1024, 4096].iter() {
+            for &inline_threshold in [0, 4096].iter() {
+                println!(
+                    "{} B messages, inline_threshold={}: {:?}",
+                    nbytes,
+                    inline_threshold,
+                    send_recv_latency(inline_net(inline_threshold), nbytes, 10000)
+                );
+            }
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture
+    /// bench_priority_lane`.
+    #[test]
+    #[ignore]
+    fn bench_priority_lane() {
+        let large: &'static [u8] = Box::leak(vec![1u8; 64 << 20].into_boxed_slice());
+        let small: &'static [u8] = Box::leak(vec![1u8; 1024].into_boxed_slice());
+        for &priority_threshold in [0, 4096].iter() {
+            let mut net = inline_net(4096);
+            net.priority_threshold = priority_threshold;
+            // Fewer chunks queued than those of a large message.
+            net.queue_capacity = 16;
+            let (socket_handle, listen_id) = net.listen(0).unwrap();
+            let send_id = net.connect(0, socket_handle).unwrap();
+            let recv_id = wait_accepted(&mut net, listen_id);
+            wait_connected(&mut net, send_id).unwrap();
+            let iterations = 20;
+            let mut latency = std::time::Duration::ZERO;
+            for _ in 0..iterations {
+                let recv_large = Box::leak(vec![0u8; large.len()].into_boxed_slice());
+                let recv_small = Box::leak(vec![0u8; small.len()].into_boxed_slice());
+                let recv_large = net.irecv(recv_id, recv_large.into(), None).unwrap();
+                let recv_small = net.irecv(recv_id, recv_small.into(), None).unwrap();
+                let send_large = net.isend(send_id, large.into(), None).unwrap();
+                let timer = std::time::Instant::now();
+                let send_small = net.isend(send_id, small.into(), None).unwrap();
+                wait_done(&mut net, recv_small);
+                latency += timer.elapsed();
+                for id in [recv_large, send_large, send_small] {
+                    wait_done(&mut net, id);
+                }
+            }
+            println!(
+                "1 KiB messages behind 64 MiB ones, priority_threshold={}: {:?}",
+                priority_threshold,
+                latency / iterations
+            );
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture
+    /// bench_worker_spin`.
+    #[test]
+    #[ignore]
+    fn bench_worker_spin() {
+        for &spin_us in [0, 50].iter() {
+            let mut net = loopback_net("127.0.0.1:0");
+            net.event_loops =
+                EventLoops::spawn(&net.threads, 2, false, &[], Duration::from_micros(spin_us))
+                    .unwrap();
+            println!(
+                "1 KiB messages, spin={}us: {:?}",
+                spin_us,
+                send_recv_latency(net, 1024, 10000)
+            );
+        }
+    }
+
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn test_send_recv_io_uring() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.event_loops = EventLoops::spawn(&net.threads, 1, true, &[], Duration::ZERO).unwrap();
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        check_send_recv(&mut net, send_id, recv_id);
+        check_send_recv(&mut net, send_id, recv_id);
+    }
+
+    #[test]
+    fn test_send_recv_zerocopy() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.zerocopy_threshold = Some(4096);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        // Loopback copies, the later ones are sent as usual.
+        for _ in 0..3 {
+            check_send_recv(&mut net, send_id, recv_id);
+        }
+    }
+
+    /// Sends the CPU time of its event loop thread.
+    struct CpuTimeProbe {
+        done: flume::Sender<Duration>,
+    }
+
+    impl Driver for CpuTimeProbe {
+        fn ready(&mut self, _index: usize, _readable: bool, _writable: bool) {}
+
+        fn poll(&mut self, _sources: &Sources) -> bool {
+            let now = nix::time::clock_gettime(nix::time::ClockId::CLOCK_THREAD_CPUTIME_ID);
+            self.done.send(Duration::from(now.unwrap())).unwrap();
+            false
+        }
+
+        fn finish(self: Box<Self>, _sources: &Sources) {}
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture
+    /// bench_zerocopy`, between hosts for meaningful numbers.
+    #[test]
+    #[ignore]
+    fn bench_zerocopy() {
+        let nbytes = 64 << 20;
+        let iterations = 32;
+        for &zerocopy_threshold in [None, Some(65536)].iter() {
+            let mut net = loopback_net("127.0.0.1:0");
+            net.zerocopy_threshold = zerocopy_threshold;
+            // The send comm takes the first loop, the recv comm the other.
+            net.event_loops =
+                EventLoops::spawn(&net.threads, 2, false, &[], Duration::ZERO).unwrap();
+            let (socket_handle, listen_id) = net.listen(0).unwrap();
+            let send_id = net.connect(0, socket_handle).unwrap();
+            let recv_id = wait_accepted(&mut net, listen_id);
+            wait_connected(&mut net, send_id).unwrap();
+            // Of the send and the recv loop, the probes take turns too.
+            let cpu_times = |net: &BaguaNet| {
+                let probes: Vec<flume::Receiver<Duration>> = (0..2)
+                    .map(|_| {
+                        let (done, cpu_time) = flume::bounded(1);
+                        net.event_loops.waker().start(CpuTimeProbe { done });
+                        cpu_time
+                    })
+                    .collect();
+                let cpu_times: Vec<Duration> =
+                    probes.iter().map(|probe| probe.recv().unwrap()).collect();
+                cpu_times
+            };
+
+            let send_buf: &'static [u8] = Box::leak(vec![1u8; nbytes].into_boxed_slice());
+            let recv_buf: *mut u8 = Box::leak(vec![0u8; nbytes].into_boxed_slice()).as_mut_ptr();
+            let start = cpu_times(&net);
+            let timer = std::time::Instant::now();
+            for _ in 0..iterations {
+                let recv_buf = unsafe { std::slice::from_raw_parts_mut(recv_buf, nbytes) };
+                let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+                let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+                wait_done(&mut net, send_req);
+                wait_done(&mut net, recv_req);
+            }
+            let elapsed = timer.elapsed();
+            let end = cpu_times(&net);
+            let gib = (nbytes * iterations) as f64 / (1 << 30) as f64;
+            println!(
+                "zerocopy_threshold={:?}: {:.1} MiB/s, CPU per GiB send={:?} recv={:?}",
+                zerocopy_threshold,
+                gib * 1024. / elapsed.as_secs_f64(),
+                (end[0] - start[0]).div_f64(gib),
+                (end[1] - start[1]).div_f64(gib)
+            );
+        }
+    }
+
+    #[test]
+    fn test_isend_v() {
+        for &name in [
+            "chunked",
+            "inlined",
+            "checked",
+            "compressed",
+            "stolen",
+            "copied",
+        ]
+        .iter()
+        {
+            let mut net = match name {
+                "inlined" => inline_net(1 << 20),
+                "compressed" => compressing_net(64 * 1024),
+                "stolen" => stealing_net(true, true),
+                _ => loopback_net("127.0.0.1:0"),
+            };
+            match name {
+                "chunked" | "checked" | "compressed" => {
+                    net.nstreams = 3;
+                    net.min_chunksize = 64 * 1024;
+                    net.chunk_bytes = 100 * 1024;
+                }
+                "copied" => net.copy_threshold = Some(4 << 20),
+                _ => {}
+            }
+            if name == "checked" {
+                net.connect_config.seq_check = true;
+                net.accept_config.seq_check = true;
+                net.connect_config.crc = true;
+                net.accept_config.crc = true;
+            }
+            let (socket_handle, listen_id) = net.listen(0).unwrap();
+            let send_id = net.connect(0, socket_handle).unwrap();
+            let recv_id = wait_accepted(&mut net, listen_id);
+            wait_connected(&mut net, send_id).unwrap();
+
+            // Chunk boundaries fall inside the slices, and across the empty
+            // one.
+            for sizes in [&[100, 300_000, 0, 77, 700_003][..], &[5, 1 << 20], &[0, 0]].iter() {
+                let slices: Vec<&'static [u8]> = sizes
+                    .iter()
+                    .map(|&nbytes| -> &'static [u8] {
+                        Box::leak(compressible(nbytes).into_boxed_slice())
+                    })
+                    .collect();
+                let data = slices.concat();
+                let nbytes = data.len();
+                let iovs: Vec<SendBuffer> = slices.iter().map(|&slice| slice.into()).collect();
+                let send_req = net.isend_v(send_id, &iovs).unwrap();
+                let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+                let recv_ptr = recv_buf.as_ptr();
+                let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+                assert_eq!(wait_done(&mut net, send_req), nbytes, "{}", name);
+                assert_eq!(wait_done(&mut net, recv_req), nbytes, "{}", name);
+                let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+                assert!(received == &data[..], "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_copy_on_send() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.copy_threshold = Some(256 * 1024);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        // Inlined, chunked, and larger than the threshold.
+        for &(nbytes, copied) in [(100, true), (256 * 1024, true), (1 << 20, false)].iter() {
+            let data: Vec<u8> = (0..nbytes).map(|i| (i % 251) as u8).collect();
+            let send_buf: &'static mut [u8] = Box::leak(data.clone().into_boxed_slice());
+            let send_ptr = send_buf.as_mut_ptr();
+            let copies = net.state.send_copies.load(Ordering::Relaxed);
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            if copied {
+                // Reused right away, before anything is received.
+                assert_eq!(net.test(send_req).unwrap(), (true, nbytes));
+                unsafe { std::ptr::write_bytes(send_ptr, 0xff, nbytes) };
+            }
+            assert_eq!(
+                net.state.send_copies.load(Ordering::Relaxed),
+                copies + copied as u64
+            );
+
+            let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+            let recv_ptr = recv_buf.as_ptr();
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            assert_eq!(wait_done(&mut net, recv_req), nbytes);
+            if !copied {
+                assert_eq!(wait_done(&mut net, send_req), nbytes);
+            }
+            let received = unsafe { std::slice::from_raw_parts(recv_ptr, nbytes) };
+            assert_eq!(received, &data[..]);
+        }
+
+        // Back in the pool once sent.
+        let timer = std::time::Instant::now();
+        while net.state.send_copy_bytes.load(Ordering::Relaxed) > 0 {
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_copy_on_send_failed() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.copy_threshold = Some(4096);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        // Only the sender fixes the size, the receiver fails the copy once
+        // it was done.
+        net.set_fixed_message_size(send_id, 1024).unwrap();
+        let send_buf: &'static [u8] = Box::leak(vec![0u8; 1024].into_boxed_slice());
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        assert_eq!(net.test(send_req).unwrap(), (true, 1024));
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 1024].into_boxed_slice());
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        wait_failed(&mut net, recv_req);
+
+        // The next isend tells.
+        let timer = std::time::Instant::now();
+        let err = loop {
+            match net.isend(send_id, send_buf.into(), None) {
+                Ok(send_req) => assert_eq!(net.test(send_req).unwrap(), (true, 1024)),
+                Err(err) => break err,
             }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::sleep(std::time::Duration::from_millis(1));
         };
+        let msg = format!("{:?}", err);
+        assert!(
+            msg.contains("does not expect messages of 1024 bytes"),
+            "{}",
+            msg
+        );
+        assert!(net.isend(send_id, send_buf.into(), None).is_err());
+    }
 
-        let socket = match Socket::new(
-            match addr {
-                InetAddr::V4(_) => Domain::IPV4,
-                InetAddr::V6(_) => Domain::IPV6,
-            },
-            Type::STREAM,
-            None,
-        ) {
-            Ok(sock) => sock,
-            Err(err) => return Err(BaguaNetError::IOError(format!("{:?}", err))),
+    /// Bytes per second of `nbytes` messages over a loopback comm of `net`,
+    /// received with one message posted ahead.
+    fn send_recv_throughput(mut net: BaguaNet, send_buf: &'static [u8], iterations: usize) -> f64 {
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+
+        let nbytes = send_buf.len();
+        let recv_bufs: Vec<*mut u8> = (0..2)
+            .map(|_| Box::leak(vec![0u8; nbytes].into_boxed_slice()).as_mut_ptr())
+            .collect();
+        let recv_buf = |i: usize| -> &'static mut [u8] {
+            unsafe { std::slice::from_raw_parts_mut(recv_bufs[i % 2], nbytes) }
         };
-        socket.bind(&addr.to_std().into()).unwrap();
-        socket.listen(BaguaNet::DEFAULT_LISTEN_BACKLOG).unwrap();
+        let timer = std::time::Instant::now();
+        let mut recv_req = net.irecv(recv_id, recv_buf(0).into(), None).unwrap();
+        for i in 0..iterations {
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            let next_recv_req = if i + 1 < iterations {
+                Some(net.irecv(recv_id, recv_buf(i + 1).into(), None).unwrap())
+            } else {
+                None
+            };
+            wait_done(&mut net, send_req);
+            wait_done(&mut net, recv_req);
+            if let Some(next_recv_req) = next_recv_req {
+                recv_req = next_recv_req;
+            }
+        }
 
-        let listener: net::TcpListener = socket.into();
-        let socket_addr = listener.local_addr().unwrap();
-        let socket_handle = SocketHandle {
-            addr: SockAddr::new_inet(InetAddr::from_std(&socket_addr)),
+        (nbytes * iterations) as f64 / timer.elapsed().as_secs_f64()
+    }
+
+    #[test]
+    fn test_max_bandwidth() {
+        // In bytes per second.
+        let mbps = |mbps: f64| mbps * 1e6 / 8.;
+        let send_buf: &'static [u8] = Box::leak(vec![7u8; 1 << 20].into_boxed_slice());
+        let mut net = loopback_net("127.0.0.1:0");
+        net.max_bandwidth = Some(100);
+        let throughput = send_recv_throughput(net, send_buf, 8);
+        assert!(
+            throughput > mbps(70.) && throughput < mbps(105.),
+            "{} bytes/s under 100 Mbps",
+            throughput
+        );
+
+        // Overridden for one comm.
+        let mut net = loopback_net("127.0.0.1:0");
+        net.max_bandwidth = Some(100);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        let send_buf: &'static [u8] = Box::leak(vec![7u8; 8 << 20].into_boxed_slice());
+        let send_recv = |net: &mut BaguaNet| {
+            let recv_buf = Box::leak(vec![0u8; send_buf.len()].into_boxed_slice());
+            let timer = std::time::Instant::now();
+            let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+            wait_done(net, send_req);
+            assert_eq!(wait_done(net, recv_req), send_buf.len());
+            send_buf.len() as f64 / timer.elapsed().as_secs_f64()
         };
-        let id = self.listen_comm_next_id;
-        self.listen_comm_next_id += 1;
-        self.listen_comm_map.insert(
-            id,
-            SocketListenComm {
-                tcp_listener: Arc::new(Mutex::new(listener)),
-            },
+        net.set_max_bandwidth(send_id, Some(200)).unwrap();
+        let throughput = send_recv(&mut net);
+        assert!(
+            throughput > mbps(140.) && throughput < mbps(210.),
+            "{} bytes/s under 200 Mbps",
+            throughput
         );
+        net.set_max_bandwidth(send_id, None).unwrap();
+        let throughput = send_recv(&mut net);
+        assert!(throughput > mbps(400.), "{} bytes/s uncapped", throughput);
+        assert!(net.set_max_bandwidth(usize::MAX, Some(100)).is_err());
+    }
 
-        Ok((socket_handle, id))
+    /// Run with `cargo test --release --features io-uring -- --ignored
+    /// --nocapture bench_io_uring`.
+    #[cfg(feature = "io-uring")]
+    #[test]
+    #[ignore]
+    fn bench_io_uring() {
+        for &(nbytes, iterations) in [(1 << 20, 512), (64 << 20, 16)].iter() {
+            for &io_uring in [false, true].iter() {
+                let mut net = loopback_net("127.0.0.1:0");
+                net.event_loops =
+                    EventLoops::spawn(&net.threads, 2, io_uring, &[], Duration::ZERO).unwrap();
+                let send_buf = Box::leak(vec![1u8; nbytes].into_boxed_slice());
+                let throughput = send_recv_throughput(net, send_buf, iterations);
+                println!(
+                    "{} MiB messages, io_uring={}: {:.1} MiB/s",
+                    nbytes >> 20,
+                    io_uring,
+                    throughput / (1 << 20) as f64
+                );
+            }
+        }
     }
 
-    fn connect(
-        &mut self,
-        _dev_id: usize,
-        socket_handle: SocketHandle,
-    ) -> Result<SocketSendCommID, BaguaNetError> {
-        let mut parallel_streams = Vec::new();
-        let mut streams_input = Vec::new();
-        for stream_id in 0..self.nstreams {
-            let mut stream = match net::TcpStream::connect(socket_handle.addr.clone().to_str()) {
-                Ok(stream) => stream,
-                Err(err) => {
-                    tracing::warn!(
-                        "net::TcpStream::connect failed, err={:?}, socket_handle={:?}",
-                        err,
-                        socket_handle
-                    );
-                    return Err(BaguaNetError::TCPError(format!(
-                        "socket_handle={:?}, err={:?}",
-                        socket_handle, err
-                    )));
-                }
-            };
-            stream.write_all(&stream_id.to_be_bytes()[..]).unwrap();
+    #[test]
+    fn test_get_properties() {
+        let net = loopback_net("127.0.0.1:0");
+        let props = net.get_properties(0).unwrap();
+        assert_eq!(props.speed, utils::LOOPBACK_SPEED);
+        assert_eq!(props.latency_us, utils::estimate_latency_us(props.speed));
+        assert!(props.mtu > 0);
+        // Loopback is on no PCI bus.
+        assert_eq!(props.numa_node, -1);
+    }
 
-            stream.set_nodelay(true).unwrap();
-            stream.set_nonblocking(true).unwrap();
+    #[test]
+    fn test_connect_invalid_dev() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, _listen_id) = net.listen(0).unwrap();
+        assert!(net.connect(1, socket_handle).is_err());
+    }
 
-            let (msg_sender, msg_receiver) =
-                flume::unbounded::<(&'static [u8], Arc<Mutex<RequestState>>)>();
-            let metrics = self.state.clone();
-            // TODO: Consider dynamically assigning tasks to make the least stream full
-            parallel_streams.push(std::thread::spawn(move || {
-                let out_timer = std::time::Instant::now();
-                let mut sum_in_time = 0.;
-                for (data, state) in msg_receiver.iter() {
-                    let in_timer = std::time::Instant::now();
-                    utils::nonblocking_write_all(&mut stream, &data[..]).unwrap();
-
-                    let dur = in_timer.elapsed().as_secs_f64();
-                    sum_in_time += dur;
-
-                    *metrics.isend_nbytes_per_second.lock().unwrap() = data.len() as f64 / dur;
-                    *metrics.isend_percentage_of_effective_time.lock().unwrap() =
-                        sum_in_time / out_timer.elapsed().as_secs_f64();
-
-                    metrics.isend_nbytes_gauge.record(data.len() as u64);
-                    match state.lock() {
-                        Ok(mut state) => {
-                            state.completed_subtasks += 1;
-                            state.nbytes_transferred += data.len();
-                        }
-                        Err(poisoned) => {
-                            tracing::warn!("{:?}", poisoned);
-                        }
-                    };
-                }
-            }));
-            streams_input.push(msg_sender);
+    #[test]
+    fn test_recv_peer_gone() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let nstreams = net.nstreams;
+        let config = net.connect_config.clone();
+
+        // A sender that announces a message and dies before sending it.
+        let connector = std::thread::spawn(move || {
+            let comm_uuid = Uuid::new_v4();
+            let (ctrl_stream, _) =
+                connection::connect_ctrl_stream(&socket_handle, comm_uuid, nstreams, &config)
+                    .unwrap();
+            let data_streams: Vec<Stream> = (0..nstreams)
+                .map(|stream_id| {
+                    connection::connect_stream(
+                        &socket_handle,
+                        StreamHandshake {
+                            comm_uuid,
+                            stream_id,
+                        },
+                        &config,
+                    )
+                    .unwrap()
+                })
+                .collect();
+            (ctrl_stream, data_streams)
+        });
+        let recv_id = wait_accepted(&mut net, listen_id);
+        let (mut ctrl_stream, data_streams) = connector.join().unwrap();
+        std::io::Write::write_all(&mut ctrl_stream, &4096usize.to_be_bytes()[..]).unwrap();
+        drop(data_streams);
+        drop(ctrl_stream);
+
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 4096].into_boxed_slice());
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        let timer = std::time::Instant::now();
+        while let Ok((done, _)) = net.test(recv_req) {
+            assert!(!done);
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
         }
+    }
+    #[test]
+    fn test_close_send() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
 
-        let nstreams = self.nstreams;
-        let mut ctrl_stream = match net::TcpStream::connect(socket_handle.addr.clone().to_str()) {
-            Ok(ctrl_stream) => ctrl_stream,
-            Err(err) => {
-                tracing::warn!(
-                    "net::TcpStream::connect failed, err={:?}, socket_handle={:?}",
-                    err,
-                    socket_handle
-                );
-                return Err(BaguaNetError::TCPError(format!(
-                    "socket_handle={:?}, err={:?}",
-                    socket_handle, err
-                )));
+        // Still in flight when the comm is closed.
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+        let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+        net.close_send(send_id).unwrap();
+
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; data.len()].into_boxed_slice());
+        let recv_ptr = recv_buf.as_ptr();
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        assert_eq!(wait_done(&mut net, recv_req), data.len());
+        assert_eq!(wait_done(&mut net, send_req), data.len());
+        let received = unsafe { std::slice::from_raw_parts(recv_ptr, data.len()) };
+        assert_eq!(received, &data[..]);
+
+        // The next one learns that the sender is done.
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 16].into_boxed_slice());
+        let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        let timer = std::time::Instant::now();
+        let err = loop {
+            match net.test(recv_req) {
+                Ok((done, _)) => assert!(!done),
+                Err(err) => break err,
             }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
         };
-        ctrl_stream.write_all(&nstreams.to_be_bytes()[..]).unwrap();
-        ctrl_stream.set_nodelay(true).unwrap();
-        ctrl_stream.set_nonblocking(true).unwrap();
-
-        let (msg_sender, msg_receiver) = flume::unbounded();
-        let min_chunksize = self.min_chunksize;
-        let id = self.send_comm_next_id;
-        self.send_comm_next_id += 1;
-        self.send_comm_map.insert(
-            id,
-            SocketSendComm {
-                msg_sender: msg_sender,
-                tcp_sender: Arc::new(std::thread::spawn(move || {
-                    let mut downstream_id = 0;
-                    for (data, state) in msg_receiver.iter() {
-                        let send_nbytes = data.len().to_be_bytes();
-                        if let Err(err) =
-                            utils::nonblocking_write_all(&mut ctrl_stream, &send_nbytes[..])
-                        {
-                            state.lock().unwrap().err =
-                                Some(BaguaNetError::IOError(format!("{:?}", err)));
-                            break;
-                        }
+        assert!(
+            format!("{:?}", err).contains("closed by the sender"),
+            "{:?}",
+            err
+        );
 
-                        if data.len() != 0 {
-                            let chunk_size = utils::chunk_size(data.len(), min_chunksize, nstreams);
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 16].into_boxed_slice());
+        assert!(net.irecv(recv_id, recv_buf.into(), None).is_err());
+        net.close_recv(recv_id).unwrap();
+    }
 
-                            for bucket in data.chunks(chunk_size) {
-                                state.lock().unwrap().nsubtasks += 1;
-                                streams_input[downstream_id]
-                                    .send((bucket, state.clone()))
-                                    .unwrap();
-                                downstream_id = (downstream_id + 1) % parallel_streams.len();
-                            }
-                        }
+    #[test]
+    fn test_close_send_outstanding() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.close_timeout = std::time::Duration::from_millis(200);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
 
-                        state.lock().unwrap().completed_subtasks += 1;
-                    }
-                })),
-            },
+        // More than the socket buffers hold, which nothing receives yet.
+        let nbytes = 256 << 20;
+        let send_req = net.isend(send_id, leak(nbytes, 1).into(), None).unwrap();
+        let timer = std::time::Instant::now();
+        let err = net.close_send(send_id).unwrap_err();
+        assert!(timer.elapsed() >= net.close_timeout);
+        assert!(
+            format!("{:?}", err).contains(&format!("[{}]", send_req)),
+            "{:?}",
+            err
         );
 
-        Ok(id)
+        // Still sent.
+        let recv_req = net.irecv(recv_id, leak(nbytes, 0).into(), None).unwrap();
+        assert_eq!(wait_done(&mut net, recv_req), nbytes);
+        assert_eq!(wait_done(&mut net, send_req), nbytes);
+        net.close_recv(recv_id).unwrap();
     }
 
-    fn accept(
-        &mut self,
-        listen_comm_id: SocketListenCommID,
-    ) -> Result<SocketRecvCommID, BaguaNetError> {
-        let listen_comm = self.listen_comm_map.get(&listen_comm_id).unwrap();
-        let mut parallel_streams = Vec::new();
-        let mut ctrl_stream = None;
-        let mut streams_input = std::collections::BTreeMap::new();
-        for _ in 0..=self.nstreams {
-            let (mut stream, _addr) = match listen_comm.tcp_listener.lock().unwrap().accept() {
-                Ok(listen) => listen,
-                Err(err) => {
-                    return Err(BaguaNetError::TCPError(format!("{:?}", err)));
-                }
-            };
-            let mut stream_id = (0 as usize).to_be_bytes();
-            stream.read_exact(&mut stream_id[..]).unwrap();
-            let stream_id = usize::from_be_bytes(stream_id);
+    #[test]
+    fn test_close_recv_posted() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        let nbytes = 16 << 20;
 
-            if stream_id == self.nstreams {
-                ctrl_stream = Some(stream);
-                continue;
-            }
+        // One message half read, the next not sent yet.
+        net.set_max_bandwidth(send_id, Some(400)).unwrap();
+        let send_req = net.isend(send_id, leak(nbytes, 1).into(), None).unwrap();
+        let started_buf = leak(nbytes, 0);
+        let started_ptr = started_buf.as_ptr();
+        let started_recv = net.irecv(recv_id, started_buf.into(), None).unwrap();
+        let posted_buf = leak(nbytes, 7);
+        let posted_ptr = posted_buf.as_ptr();
+        let posted_recv = net.irecv(recv_id, posted_buf.into(), None).unwrap();
+        let timer = std::time::Instant::now();
+        while net.test(started_recv).unwrap().1 == 0 {
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        }
 
-            stream.set_nodelay(true).unwrap();
-            stream.set_nonblocking(true).unwrap();
+        net.close_recv(recv_id).unwrap();
+        assert!(net.test(started_recv).is_err());
+        assert!(net.test(posted_recv).is_err());
+        let started = unsafe { std::slice::from_raw_parts(started_ptr, nbytes) };
+        let snapshot = started.to_vec();
+        net.set_max_bandwidth(send_id, None).unwrap();
+        let _ = net.isend(send_id, leak(nbytes, 2).into(), None);
+        let _ = net.wait(send_req, None);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(started == &snapshot[..]);
+        assert_eq!(started[nbytes - 1], 0);
+        let posted = unsafe { std::slice::from_raw_parts(posted_ptr, nbytes) };
+        assert!(posted.iter().all(|&b| b == 7));
+    }
 
-            let (msg_sender, msg_receiver) =
-                flume::unbounded::<(&'static mut [u8], Arc<Mutex<RequestState>>)>();
-            let metrics = self.state.clone();
-            parallel_streams.push(std::thread::spawn(move || {
-                for (data, state) in msg_receiver.iter() {
-                    utils::nonblocking_read_exact(&mut stream, &mut data[..]).unwrap();
-
-                    metrics.irecv_nbytes_gauge.record(data.len() as u64);
-                    match state.lock() {
-                        Ok(mut state) => {
-                            state.completed_subtasks += 1;
-                            state.nbytes_transferred += data.len();
-                        }
-                        Err(poisoned) => {
-                            tracing::warn!("{:?}", poisoned);
-                        }
-                    };
+    #[test]
+    fn test_close_listen_pending_accept() {
+        let mut net = loopback_net("127.0.0.1:0");
+        net.accept_config.collect_timeout = None;
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let closer = net.listen_closer(listen_id).unwrap().unwrap();
+        // A send comm whose connector died after its first stream, which
+        // the accept keeps collecting.
+        let _data = connection::connect_stream(
+            &socket_handle,
+            StreamHandshake {
+                comm_uuid: Uuid::new_v4(),
+                stream_id: 0,
+            },
+            &ConnectConfig::from_env(),
+        )
+        .unwrap();
+        let net = Arc::new(Mutex::new(net));
+        let accepting = {
+            let net = net.clone();
+            std::thread::spawn(move || loop {
+                match net.lock().unwrap().accept(listen_id) {
+                    Ok(None) => std::thread::yield_now(),
+                    ret => return ret,
                 }
-            }));
-            streams_input.insert(stream_id, msg_sender);
-        }
-        let mut ctrl_stream = ctrl_stream.unwrap();
-        let streams_input: Vec<_> = streams_input
-            .into_iter()
-            .map(|(_, stream)| stream)
-            .collect();
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!accepting.is_finished());
 
-        ctrl_stream.set_nodelay(true).unwrap();
-        ctrl_stream.set_nonblocking(true).unwrap();
+        closer.close();
+        assert!(matches!(
+            accepting.join().unwrap(),
+            Err(BaguaNetError::Closed)
+        ));
+        net.lock().unwrap().close_listen(listen_id).unwrap();
+        assert!(net.lock().unwrap().accept(listen_id).is_err());
+    }
 
-        let nstreams = self.nstreams;
-        let (msg_sender, msg_receiver) = flume::unbounded();
-        let min_chunksize = self.min_chunksize;
-        let id = self.recv_comm_next_id;
-        self.recv_comm_next_id += 1;
-        self.recv_comm_map.insert(
-            id,
-            SocketRecvComm {
-                msg_sender: msg_sender,
-                tcp_sender: Arc::new(std::thread::spawn(move || {
-                    let mut downstream_id = 0;
-                    for (data, state) in msg_receiver.iter() {
-                        let mut target_nbytes = data.len().to_be_bytes();
-                        if let Err(err) =
-                            utils::nonblocking_read_exact(&mut ctrl_stream, &mut target_nbytes[..])
-                        {
-                            state.lock().unwrap().err =
-                                Some(BaguaNetError::IOError(format!("{:?}", err)));
-                            break;
-                        }
-                        let target_nbytes = usize::from_be_bytes(target_nbytes);
-
-                        if target_nbytes != 0 {
-                            let chunk_size =
-                                utils::chunk_size(target_nbytes, min_chunksize, nstreams);
-                            for bucket in data[..target_nbytes].chunks_mut(chunk_size) {
-                                state.lock().unwrap().nsubtasks += 1;
-                                streams_input[downstream_id]
-                                    .send((&mut bucket[..], state.clone()))
-                                    .unwrap();
-                                downstream_id = (downstream_id + 1) % parallel_streams.len();
-                            }
-                        }
-                        state.lock().unwrap().completed_subtasks += 1;
-                    }
-                })),
-            },
+    #[test]
+    fn test_close_unknown_ids() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let invalid = |ret: Result<(), BaguaNetError>, expected: &str| {
+            assert!(
+                matches!(ret, Err(BaguaNetError::InvalidId { kind, .. }) if kind == expected),
+                "{:?}",
+                ret
+            );
+        };
+        // Never created.
+        invalid(net.close_send(0), "send comm");
+        invalid(net.close_recv(0), "recv comm");
+        invalid(net.close_listen(0), "listen comm");
+        let err = net.listen(1).unwrap_err();
+        assert!(
+            matches!(err, BaguaNetError::InvalidArgument(_)),
+            "{:?}",
+            err
         );
+        assert_eq!(err.kind(), ErrorKind::InvalidArgument);
+        assert!(matches!(
+            net.accept(0),
+            Err(BaguaNetError::InvalidId { .. })
+        ));
+        assert!(matches!(
+            net.isend(0, SendBuffer::from(&[0u8; 4][..]), None),
+            Err(BaguaNetError::InvalidId { .. })
+        ));
 
-        Ok(id)
+        // Closed twice.
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        net.close_send(send_id).unwrap();
+        net.close_recv(recv_id).unwrap();
+        net.close_listen(listen_id).unwrap();
+        invalid(net.close_send(send_id), "send comm");
+        invalid(net.close_recv(recv_id), "recv comm");
+        invalid(net.close_listen(listen_id), "listen comm");
+        assert!(matches!(
+            net.irecv(recv_id, leak(4, 0).into(), None),
+            Err(BaguaNetError::InvalidId { .. })
+        ));
     }
 
-    fn isend(
-        &mut self,
-        send_comm_id: SocketSendCommID,
-        data: &'static [u8],
-    ) -> Result<SocketRequestID, BaguaNetError> {
-        let tracer = opentelemetry::global::tracer("bagua-net");
-        let mut span = tracer
-            .span_builder(format!("isend-{}", send_comm_id))
-            .with_parent_context(self.trace_span_context.clone())
-            .start(&tracer);
-        let send_comm = self.send_comm_map.get(&send_comm_id).unwrap();
-        let id = self.socket_request_next_id;
-
-        span.set_attribute(KeyValue::new("id", id as i64));
-        span.set_attribute(KeyValue::new("nbytes", data.len() as i64));
+    #[test]
+    fn test_poll_done_again() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        let recv_req = net.irecv(recv_id, leak(1024, 0).into(), None).unwrap();
+        let send_req = net.isend(send_id, leak(1024, 1).into(), None).unwrap();
+        assert_eq!(wait_done(&mut net, send_req), 1024);
+        assert_eq!(wait_done(&mut net, recv_req), 1024);
 
-        self.socket_request_next_id += 1;
-        let task_state = Arc::new(Mutex::new(RequestState {
-            nsubtasks: 1,
-            completed_subtasks: 0,
-            nbytes_transferred: 0,
-            err: None,
-        }));
-        self.socket_request_map.insert(
-            id,
-            SocketRequest::SendRequest(SocketSendRequest {
-                state: task_state.clone(),
-                trace_span: span,
-            }),
-        );
+        // Once more, then never again.
+        assert_eq!(net.test(recv_req).unwrap(), (true, 1024));
+        assert_eq!(net.test(send_req).unwrap(), (true, 1024));
+        for id in [recv_req, send_req] {
+            let err = net.test(id).unwrap_err();
+            assert!(matches!(
+                err,
+                BaguaNetError::InvalidId { kind: "request", id: unknown } if unknown == id
+            ));
+            assert_eq!(err.kind(), ErrorKind::InvalidArgument);
+            assert_eq!(err.nccl_result(), 4);
+        }
+        assert_eq!(net.live_requests(), 0);
+    }
 
-        send_comm.msg_sender.send((data, task_state)).unwrap();
+    fn enable_conn_cache(net: &mut BaguaNet, send: bool, recv: bool) {
+        let (send_comm_cache, recv_comm_cache) = conn_caches(&ConnCacheConfig {
+            enabled: true,
+            capacity: 4,
+            ttl: Duration::from_secs(60),
+        });
+        if send {
+            net.send_comm_cache = send_comm_cache;
+        }
+        if recv {
+            net.recv_comm_cache = recv_comm_cache;
+        }
+    }
 
-        Ok(id)
+    fn nparked<K>(cache: &Option<Arc<Mutex<ParkedComms<K>>>>) -> usize {
+        cache
+            .as_ref()
+            .map_or(0, |cache| cache.lock().unwrap().entries.len())
     }
 
-    fn irecv(
-        &mut self,
-        recv_comm_id: SocketRecvCommID,
-        data: &'static mut [u8],
-    ) -> Result<SocketRequestID, BaguaNetError> {
-        let tracer = opentelemetry::global::tracer("bagua-net");
-        let mut span = tracer
-            .span_builder(format!("irecv-{}", recv_comm_id))
-            .with_parent_context(self.trace_span_context.clone())
-            .start(&tracer);
-        let recv_comm = self.recv_comm_map.get(&recv_comm_id).unwrap();
-        let id = self.socket_request_next_id;
+    /// Closes both ends of a comm and waits for the caching ends to park it.
+    fn close_comm(net: &mut BaguaNet, send_id: SocketSendCommID, recv_id: SocketRecvCommID) {
+        let expected = (
+            nparked(&net.send_comm_cache) + net.send_comm_cache.is_some() as usize,
+            nparked(&net.recv_comm_cache) + net.recv_comm_cache.is_some() as usize,
+        );
+        net.close_send(send_id).unwrap();
+        net.close_recv(recv_id).unwrap();
+        let timer = std::time::Instant::now();
+        while (nparked(&net.send_comm_cache), nparked(&net.recv_comm_cache)) != expected {
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+    }
 
-        span.set_attribute(KeyValue::new("id", id as i64));
+    #[test]
+    fn test_conn_cache_revive() {
+        let mut net = loopback_net("127.0.0.1:0");
+        enable_conn_cache(&mut net, true, true);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle.clone()).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        check_send_recv(&mut net, send_id, recv_id);
+        close_comm(&mut net, send_id, recv_id);
+        assert_eq!(nparked(&net.send_comm_cache), 1);
+        assert_eq!(nparked(&net.recv_comm_cache), 1);
 
-        self.socket_request_next_id += 1;
-        let task_state = Arc::new(Mutex::new(RequestState {
-            nsubtasks: 1,
-            completed_subtasks: 0,
-            nbytes_transferred: 0,
-            err: None,
-        }));
-        self.socket_request_map.insert(
-            id,
-            SocketRequest::RecvRequest(SocketRecvRequest {
-                state: task_state.clone(),
-                trace_span: span,
-            }),
-        );
+        let send_id = net.connect(0, socket_handle).unwrap();
+        assert_eq!(nparked(&net.send_comm_cache), 0);
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        assert_eq!(nparked(&net.recv_comm_cache), 0);
+        // Without opening a stream.
+        let listen_comm = &net.listen_comm_map[listen_id];
+        assert!(listen_comm.listener.lock().unwrap().tcp.accept().is_err());
+        check_send_recv(&mut net, send_id, recv_id);
+    }
 
-        recv_comm.msg_sender.send((data, task_state)).unwrap();
+    #[test]
+    fn test_conn_cache_receiver_closed() {
+        // The receiver does not cache, its end is closed.
+        let mut net = loopback_net("127.0.0.1:0");
+        enable_conn_cache(&mut net, true, false);
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let send_id = net.connect(0, socket_handle.clone()).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        close_comm(&mut net, send_id, recv_id);
+        assert_eq!(nparked(&net.send_comm_cache), 1);
+        // Reviving before the receiver's end is closed would not be noticed.
+        let timer = std::time::Instant::now();
+        while net
+            .send_comm_cache
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .entries[0]
+            .1
+            .is_alive()
+        {
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        }
 
-        Ok(id)
+        let send_id = net.connect(0, socket_handle).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        check_send_recv(&mut net, send_id, recv_id);
     }
 
-    fn test(&mut self, request_id: SocketRequestID) -> Result<(bool, usize), BaguaNetError> {
-        let request = self.socket_request_map.get_mut(&request_id).unwrap();
-        let ret = match request {
-            SocketRequest::SendRequest(send_req) => {
-                let state = send_req.state.lock().unwrap();
-                if let Some(err) = state.err.clone() {
-                    return Err(err);
-                }
+    #[test]
+    fn test_parked_comms() {
+        let parked = || {
+            let (ctrl_stream, peer) = std::os::unix::net::UnixStream::pair().unwrap();
+            ctrl_stream.set_nonblocking(true).unwrap();
+            let group = StreamGroup {
+                comm_uuid: Uuid::new_v4(),
+                data_streams: Vec::new(),
+                ctrl_stream: Stream::Unix(ctrl_stream),
+                capabilities: 0,
+                peer_rcvbuf: 0,
+            };
+            (ParkedStreams::new(group, None), peer)
+        };
 
-                let task_completed = state.nsubtasks == state.completed_subtasks;
-                if task_completed {
-                    send_req.trace_span.end();
-                }
-                Ok((task_completed, state.nbytes_transferred))
-            }
-            SocketRequest::RecvRequest(recv_req) => {
-                let state = recv_req.state.lock().unwrap();
-                if let Some(err) = state.err.clone() {
-                    return Err(err);
-                }
+        // The oldest go first.
+        let mut comms = ParkedComms::new(2, Duration::from_secs(60));
+        comms.park(1, parked().0);
+        comms.park(1, parked().0);
+        comms.park(2, parked().0);
+        assert_eq!(comms.entries.len(), 2);
+        assert!(comms.take(&1).is_some());
+        assert!(comms.take(&1).is_none());
+        assert!(comms.take(&2).is_some());
 
-                let task_completed = state.nsubtasks == state.completed_subtasks;
-                if task_completed {
-                    recv_req.trace_span.end();
-                }
-                Ok((task_completed, state.nbytes_transferred))
-            }
+        let mut comms = ParkedComms::new(2, Duration::from_millis(10));
+        comms.park(1, parked().0);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(comms.take(&1).is_none());
+
+        // Only what the sender revived, not what it closed.
+        let mut comms = ParkedComms::new(2, Duration::from_secs(60));
+        let (closed, mut closed_peer) = parked();
+        let (revived, mut revived_peer) = parked();
+        let revived_uuid = revived.group.comm_uuid;
+        comms.park(1, closed);
+        comms.park(1, revived);
+        assert!(comms.take_revived(&1).is_none());
+        assert_eq!(comms.entries.len(), 2);
+        closed_peer
+            .write_all(&connection::CLOSE_NBYTES.to_be_bytes()[..])
+            .unwrap();
+        revived_peer
+            .write_all(&connection::PARK_NBYTES.to_be_bytes()[..])
+            .unwrap();
+        revived_peer
+            .write_all(&connection::REVIVE_NBYTES.to_be_bytes()[..])
+            .unwrap();
+        assert_eq!(
+            comms.take_revived(&1).unwrap().group.comm_uuid,
+            revived_uuid
+        );
+        assert!(comms.entries.is_empty());
+    }
+
+    /// Forwards connections to `upstream`, and resets both ends of the first
+    /// one that carried more than `reset_after` bytes towards it.
+    fn resetting_proxy(
+        upstream: net::SocketAddr,
+        reset_after: usize,
+    ) -> (SocketHandle, Arc<AtomicBool>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&listener.local_addr().unwrap())),
+            alt_addr: None,
+            uds_addr: None,
+            hostname: None,
         };
+        let reset = Arc::new(AtomicBool::new(false));
+        let proxy_reset = reset.clone();
+        std::thread::spawn(move || {
+            for client in listener.incoming() {
+                let mut client = client.unwrap();
+                let server = net::TcpStream::connect(upstream).unwrap();
+                let (mut from, mut to) = (server.try_clone().unwrap(), client.try_clone().unwrap());
+                std::thread::spawn(move || std::io::copy(&mut from, &mut to));
 
-        if let Ok(ret) = ret {
-            if ret.0 {
-                self.socket_request_map.remove(&request_id).unwrap();
+                let reset = proxy_reset.clone();
+                std::thread::spawn(move || {
+                    let mut buf = vec![0u8; 64 * 1024];
+                    let mut forwarded = 0;
+                    loop {
+                        let n = match client.read(&mut buf[..]) {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => n,
+                        };
+                        if (&server).write_all(&buf[..n]).is_err() {
+                            break;
+                        }
+                        forwarded += n;
+                        if forwarded > reset_after && !reset.swap(true, Ordering::SeqCst) {
+                            for stream in [&client, &server].iter() {
+                                SockRef::from(*stream)
+                                    .set_linger(Some(Duration::from_secs(0)))
+                                    .unwrap();
+                            }
+                            // Ends the copy above, the last close sends the resets.
+                            server.shutdown(net::Shutdown::Read).unwrap();
+                            break;
+                        }
+                    }
+                });
             }
-        }
+        });
 
-        ret
+        (socket_handle, reset)
     }
 
-    fn close_send(&mut self, send_comm_id: SocketSendCommID) -> Result<(), BaguaNetError> {
-        self.send_comm_map.remove(&send_comm_id);
+    #[test]
+    fn test_reconnect_after_reset() {
+        let mut net = loopback_net("127.0.0.1:0");
+        let (socket_handle, listen_id) = net.listen(0).unwrap();
+        let upstream = match socket_handle.addr {
+            SockAddr::Inet(inet_addr) => inet_addr.to_std(),
+            others => panic!("unexpected address {:?}", others),
+        };
+        let (proxied, reset) = resetting_proxy(upstream, 256 * 1024);
 
-        Ok(())
+        let send_id = net.connect(0, proxied).unwrap();
+        let recv_id = wait_accepted(&mut net, listen_id);
+        wait_connected(&mut net, send_id).unwrap();
+        check_send_recv(&mut net, send_id, recv_id);
+        assert!(reset.load(Ordering::SeqCst));
+
+        // The replacement carries the later messages too.
+        check_send_recv(&mut net, send_id, recv_id);
     }
 
-    fn close_recv(&mut self, recv_comm_id: SocketRecvCommID) -> Result<(), BaguaNetError> {
-        self.recv_comm_map.remove(&recv_comm_id);
+    /// Forwards what the connection it accepts `throttled`-th sends at
+    /// `bytes_per_second`, the others as fast as they come.
+    fn throttling_proxy(
+        upstream: net::SocketAddr,
+        throttled: usize,
+        bytes_per_second: f64,
+    ) -> SocketHandle {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&listener.local_addr().unwrap())),
+            alt_addr: None,
+            uds_addr: None,
+            hostname: None,
+        };
+        std::thread::spawn(move || {
+            for (accepted, client) in listener.incoming().enumerate() {
+                let mut client = client.unwrap();
+                let server = net::TcpStream::connect(upstream).unwrap();
+                let (mut from, mut to) = (server.try_clone().unwrap(), client.try_clone().unwrap());
+                std::thread::spawn(move || std::io::copy(&mut from, &mut to));
+                if accepted != throttled {
+                    let mut server = server;
+                    std::thread::spawn(move || std::io::copy(&mut client, &mut server));
+                    continue;
+                }
 
-        Ok(())
+                // Holds little more than it forwards.
+                SockRef::from(&client)
+                    .set_recv_buffer_size(64 * 1024)
+                    .unwrap();
+                std::thread::spawn(move || {
+                    let mut buf = vec![0u8; 16 * 1024];
+                    loop {
+                        let n = match client.read(&mut buf[..]) {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => n,
+                        };
+                        if (&server).write_all(&buf[..n]).is_err() {
+                            break;
+                        }
+                        std::thread::sleep(Duration::from_secs_f64(n as f64 / bytes_per_second));
+                    }
+                });
+            }
+        });
+
+        socket_handle
     }
 
-    fn close_listen(&mut self, listen_comm_id: SocketListenCommID) -> Result<(), BaguaNetError> {
-        self.listen_comm_map.remove(&listen_comm_id);
+    /// Run with `cargo test --release -- --ignored --nocapture
+    /// bench_work_stealing`.
+    #[test]
+    #[ignore]
+    fn bench_work_stealing() {
+        let nbytes = 16 << 20;
+        let iterations = 16;
+        let send_buf: &'static [u8] = Box::leak(vec![7u8; nbytes].into_boxed_slice());
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; nbytes].into_boxed_slice());
+        let recv_ptr = recv_buf.as_mut_ptr();
+        for &work_stealing in [false, true].iter() {
+            let mut net = stealing_net(work_stealing, work_stealing);
+            net.connect_config.buffers.sndbuf = Some(256 * 1024);
+            let (socket_handle, listen_id) = net.listen(0).unwrap();
+            let upstream = match socket_handle.addr {
+                SockAddr::Inet(inet_addr) => inet_addr.to_std(),
+                others => panic!("unexpected address {:?}", others),
+            };
+            // The master stream is accepted first, then the data streams.
+            let proxied = throttling_proxy(upstream, 1, 25e6);
+            let send_id = net.connect(0, proxied).unwrap();
+            let recv_id = wait_accepted(&mut net, listen_id);
+            wait_connected(&mut net, send_id).unwrap();
 
-        Ok(())
+            let timer = std::time::Instant::now();
+            for _ in 0..iterations {
+                let recv_buf = unsafe { std::slice::from_raw_parts_mut(recv_ptr, nbytes) };
+                let recv_req = net.irecv(recv_id, recv_buf.into(), None).unwrap();
+                let send_req = net.isend(send_id, send_buf.into(), None).unwrap();
+                wait_done(&mut net, send_req);
+                wait_done(&mut net, recv_req);
+            }
+            println!(
+                "work_stealing={}: {:.1} MB/s over {} streams, one of them at 25 MB/s",
+                work_stealing,
+                (nbytes * iterations) as f64 / timer.elapsed().as_secs_f64() / 1e6,
+                net.nstreams
+            );
+        }
     }
-}
 
-impl Drop for BaguaNet {
-    fn drop(&mut self) {
-        // TODO: make shutdown global
-        self.trace_span_context.span().end();
-        opentelemetry::global::shutdown_tracer_provider();
+    /// Run with `cargo test --release -- --ignored --nocapture
+    /// bench_test_outstanding`.
+    #[test]
+    #[ignore]
+    fn bench_test_outstanding() {
+        let mut net = BaguaNet::new().unwrap();
+        let tracer = opentelemetry::global::tracer("bagua-net");
+        let nrequests = 100000;
+        let ids: Vec<_> = (0..nrequests)
+            .map(|_| {
+                net.socket_request_map
+                    .insert(SocketRequest::SendRequest(SocketSendRequest {
+                        state: Arc::new(RequestState::new(1)),
+                        trace_span: RequestSpan(Some(tracer.start("bench"))),
+                        send_comm_id: 0,
+                        posted_at: Instant::now(),
+                        health: Default::default(),
+                        inflight: None,
+                    }))
+                    .unwrap()
+            })
+            .collect();
+        let rounds = 20;
+        let timer = Instant::now();
+        for _ in 0..rounds {
+            for &id in ids.iter() {
+                assert!(!net.test(id).unwrap().0);
+            }
+        }
+        println!(
+            "{} outstanding requests: {:?} per test()",
+            nrequests,
+            timer.elapsed() / (rounds * nrequests) as u32
+        );
     }
 }
@@ -0,0 +1,660 @@
+use crate::interface::{BaguaNetError, CompletionHook, RequestTiming};
+use crate::utils;
+use std::fmt;
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Of `Net::set_completion_hook`, which is not `Debug`.
+struct Hook(CompletionHook);
+
+impl fmt::Debug for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+/// Of `RequestState::started` and `RequestState::finished`, until they are.
+const NOT_YET: u64 = u64::MAX;
+
+/// Of a comm, of the comms on a device, or of every comm of a net, what their
+/// requests added up to since it was opened. Those of a comm add up in those
+/// of its device, and those of a device in those of its net.
+#[derive(Debug, Default)]
+pub struct Tally {
+    pub nbytes: AtomicU64,
+    pub completed: AtomicU64,
+    pub failed: AtomicU64,
+    /// Of the counters, as of the last `reset`.
+    baseline: [AtomicU64; 3],
+    net: Option<Arc<Tally>>,
+}
+
+impl Tally {
+    /// Of the counters, in `since_reset`.
+    pub const NBYTES: usize = 0;
+    pub const COMPLETED: usize = 1;
+    pub const FAILED: usize = 2;
+
+    /// Of a comm, or a device, of what `net` is of.
+    pub fn of_comm(net: &Arc<Tally>) -> Tally {
+        Tally {
+            net: Some(net.clone()),
+            ..Default::default()
+        }
+    }
+
+    fn counters(&self) -> [&AtomicU64; 3] {
+        [&self.nbytes, &self.completed, &self.failed]
+    }
+
+    fn add(&self, counter: usize, n: u64) {
+        self.counters()[counter].fetch_add(n, Ordering::Relaxed);
+        if let Some(net) = &self.net {
+            net.add(counter, n);
+        }
+    }
+
+    /// Of the bytes, the requests completed and those failed since the last
+    /// `reset`.
+    pub fn since_reset(&self) -> [u64; 3] {
+        let mut since = [0; 3];
+        for ((since, counter), baseline) in since
+            .iter_mut()
+            .zip(self.counters().iter())
+            .zip(self.baseline.iter())
+        {
+            *since = counter
+                .load(Ordering::Relaxed)
+                .saturating_sub(baseline.load(Ordering::Relaxed));
+        }
+        since
+    }
+
+    /// Not of its net, the counters themselves go on.
+    pub fn reset(&self) {
+        for (counter, baseline) in self.counters().iter().zip(self.baseline.iter()) {
+            baseline.store(counter.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Progress of an isend or irecv, shared by `test()` on the NCCL proxy
+/// thread and the threads completing its subtasks. Subtasks can be added
+/// while others complete, as long as one of them stays pending until the
+/// last is added. A thread that panics while it locks the state does not
+/// keep the others from completing it.
+#[derive(Debug)]
+pub struct RequestState {
+    pub nsubtasks: AtomicUsize,
+    pub completed_subtasks: AtomicUsize,
+    pub nbytes_transferred: AtomicUsize,
+    failed: AtomicBool,
+    err: Mutex<Option<BaguaNetError>>,
+    /// By `Net::cancel`, its buffer is not to be touched anymore.
+    cancelled: AtomicBool,
+    /// Of the reads and writes on its buffer, see `lend`.
+    loans: AtomicUsize,
+    /// Notified once the last subtask completed or the request failed, under
+    /// `waiting`, see `wait`.
+    signal: Condvar,
+    waiting: Mutex<()>,
+    /// Called by whoever completes the last subtask or fails the request
+    /// first, once it is taken out of here.
+    hook: Mutex<Option<Hook>>,
+    posted_at: Instant,
+    /// In nanoseconds since `posted_at`, see `RequestTiming`.
+    started: AtomicU64,
+    finished: AtomicU64,
+    /// Of the spans of its chunks, with `BAGUA_NET_TRACE_CHUNKS=1`.
+    trace_parent: Option<opentelemetry::Context>,
+    /// Of its comm, what the bytes of its subtasks add up in.
+    comm_nbytes: Option<Arc<Tally>>,
+    /// Of its comm, what it counts in once done, see `tally_done`.
+    comm_requests: Option<Arc<Tally>>,
+    tallied: AtomicBool,
+}
+
+impl RequestState {
+    pub fn new(nsubtasks: usize) -> RequestState {
+        RequestState {
+            nsubtasks: AtomicUsize::new(nsubtasks),
+            completed_subtasks: AtomicUsize::new(0),
+            nbytes_transferred: AtomicUsize::new(0),
+            failed: AtomicBool::new(false),
+            err: Mutex::new(None),
+            cancelled: AtomicBool::new(false),
+            loans: AtomicUsize::new(0),
+            signal: Condvar::new(),
+            waiting: Mutex::new(()),
+            hook: Mutex::new(None),
+            posted_at: Instant::now(),
+            started: AtomicU64::new(NOT_YET),
+            finished: AtomicU64::new(NOT_YET),
+            trace_parent: None,
+            comm_nbytes: None,
+            comm_requests: None,
+            tallied: AtomicBool::new(false),
+        }
+    }
+
+    pub fn traced(mut self, trace_parent: Option<opentelemetry::Context>) -> RequestState {
+        self.trace_parent = trace_parent;
+        self
+    }
+
+    pub fn trace_parent(&self) -> Option<&opentelemetry::Context> {
+        self.trace_parent.as_ref()
+    }
+
+    pub fn counted(mut self, comm_nbytes: Arc<Tally>) -> RequestState {
+        self.comm_nbytes = Some(comm_nbytes);
+        self
+    }
+
+    pub fn tallied(mut self, comm_requests: Arc<Tally>) -> RequestState {
+        self.comm_requests = Some(comm_requests);
+        self
+    }
+
+    /// As completed or failed, whichever it was first.
+    fn tally_done(&self) {
+        if let Some(comm_requests) = &self.comm_requests {
+            if !self.tallied.swap(true, Ordering::Relaxed) {
+                let counter = match self.failed.load(Ordering::Acquire) {
+                    true => Tally::FAILED,
+                    false => Tally::COMPLETED,
+                };
+                comm_requests.add(counter, 1);
+            }
+        }
+    }
+
+    /// Once a thread of its comm takes it, only the first call counts.
+    pub fn start(&self) {
+        if self.started.load(Ordering::Relaxed) == NOT_YET {
+            let _ = self.started.compare_exchange(
+                NOT_YET,
+                self.since_posted(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    fn since_posted(&self) -> u64 {
+        self.posted_at.elapsed().as_nanos() as u64
+    }
+
+    /// Once its last subtask completed. A request that was never started,
+    /// e.g. a send copied by `isend`, was queued all along.
+    pub fn timing(&self) -> Option<RequestTiming> {
+        let finished = match self.finished.load(Ordering::Relaxed) {
+            // Being stored by the thread that completed the last subtask.
+            NOT_YET if self.progress().0 => self.since_posted(),
+            NOT_YET => return None,
+            finished => finished,
+        };
+        let started = self.started.load(Ordering::Relaxed).min(finished);
+        Some(RequestTiming {
+            queued: Duration::from_nanos(started),
+            wire: Duration::from_nanos(finished - started),
+        })
+    }
+
+    /// Before any of them can complete.
+    pub fn add_subtasks(&self, n: usize) {
+        self.nsubtasks.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn complete_subtask(&self, nbytes: usize) {
+        self.nbytes_transferred.fetch_add(nbytes, Ordering::Relaxed);
+        if let Some(comm_nbytes) = &self.comm_nbytes {
+            comm_nbytes.add(Tally::NBYTES, nbytes as u64);
+        }
+        // Publishes the subtask, and the subtasks added before it. Syncs with
+        // the completions before it, so that the last sees every subtask.
+        let completed = self.completed_subtasks.fetch_add(1, Ordering::AcqRel) + 1;
+        if completed == self.nsubtasks.load(Ordering::Relaxed) {
+            self.finished.store(self.since_posted(), Ordering::Relaxed);
+            self.tally_done();
+            self.notify();
+            self.call_hook(|| self.result());
+        }
+    }
+
+    /// The first error wins, for the hook and `err()` alike. Reported once
+    /// its buffer is lent to nothing.
+    pub fn fail(&self, err: BaguaNetError) {
+        let err = utils::lock(&self.err).get_or_insert(err).clone();
+        self.failed.store(true, Ordering::SeqCst);
+        self.tally_done();
+        if self.failure_settled() {
+            self.notify();
+            self.call_hook(|| Err(err));
+        }
+    }
+
+    /// Whether it failed, and nothing reads or writes its buffer anymore.
+    fn failure_settled(&self) -> bool {
+        self.failed.load(Ordering::SeqCst) && self.loans.load(Ordering::SeqCst) == 0
+    }
+
+    /// For a read or write on its buffer, none once it was cancelled or
+    /// failed. Until the loan is dropped, `cancel` waits, and the request is
+    /// not reported failed.
+    pub fn lend(self: &Arc<Self>) -> Option<BufferLoan> {
+        self.loans.fetch_add(1, Ordering::SeqCst);
+        let loan = BufferLoan(self.clone());
+        if self.gave_up() {
+            return None;
+        }
+        Some(loan)
+    }
+
+    /// Whether it was cancelled or failed, its buffer is lent no more.
+    pub fn gave_up(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst) || self.failed.load(Ordering::SeqCst)
+    }
+
+    fn give_back(&self) {
+        if self.loans.fetch_sub(1, Ordering::SeqCst) == 1 && self.failed.load(Ordering::SeqCst) {
+            self.notify();
+            self.call_hook(|| self.result());
+        }
+    }
+
+    /// Called once the request is done or failed, right away if it already
+    /// is, with the bytes transferred or the error.
+    pub fn set_hook(&self, hook: CompletionHook) {
+        let mut slot = utils::lock(&self.hook);
+        if !self.done() {
+            *slot = Some(Hook(hook));
+            return;
+        }
+        drop(slot);
+        hook(self.result());
+    }
+
+    /// Outside the lock of its slot.
+    fn call_hook(&self, result: impl FnOnce() -> Result<usize, BaguaNetError>) {
+        let hook = utils::lock(&self.hook).take();
+        if let Some(Hook(hook)) = hook {
+            hook(result());
+        }
+    }
+
+    /// Whether every subtask completed or the request failed.
+    pub fn done(&self) -> bool {
+        self.progress().0 || self.failure_settled()
+    }
+
+    fn result(&self) -> Result<usize, BaguaNetError> {
+        match self.err() {
+            Some(err) => Err(err),
+            None => Ok(self.progress().1),
+        }
+    }
+
+    /// Lends its buffer no more, see `wait_returned`.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Once cancelled, blocks until its buffer is lent to nothing.
+    pub fn wait_returned(&self) {
+        let mut waiting = utils::lock(&self.waiting);
+        while self.loans.load(Ordering::SeqCst) > 0 {
+            // Given back by the driver, which the caller woke.
+            waiting = self
+                .signal
+                .wait_timeout(waiting, Duration::from_millis(10))
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Once a waiter that did not see the change is waiting.
+    fn notify(&self) {
+        let _waiting = utils::lock(&self.waiting);
+        self.signal.notify_all();
+    }
+
+    /// Blocks until every subtask completed or the request failed, for up to
+    /// `timeout` if any. Whether it did.
+    pub fn wait(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut waiting = utils::lock(&self.waiting);
+        loop {
+            if self.done() {
+                return true;
+            }
+            waiting = match deadline {
+                None => self
+                    .signal
+                    .wait(waiting)
+                    .unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.signal
+                        .wait_timeout(waiting, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        }
+    }
+
+    /// To be read after `progress()`, errors are set before the subtasks
+    /// they fail complete.
+    pub fn err(&self) -> Option<BaguaNetError> {
+        if !self.failure_settled() {
+            return None;
+        }
+        utils::lock(&self.err).clone()
+    }
+
+    /// Whether every subtask completed, and the bytes transferred so far.
+    pub fn progress(&self) -> (bool, usize) {
+        let completed = self.completed_subtasks.load(Ordering::Relaxed);
+        // Syncs with the completions read above, so that the subtasks added
+        // before them are counted below.
+        atomic::fence(Ordering::Acquire);
+        let nsubtasks = self.nsubtasks.load(Ordering::Relaxed);
+        (
+            completed == nsubtasks,
+            self.nbytes_transferred.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Of `RequestState::lend`, given back once dropped.
+#[derive(Debug)]
+pub struct BufferLoan(Arc<RequestState>);
+
+impl Drop for BufferLoan {
+    fn drop(&mut self) {
+        self.0.give_back();
+    }
+}
+
+impl Drop for RequestState {
+    /// Dropped by the comm before it was done, e.g. once the net is.
+    fn drop(&mut self) {
+        let hook = self.hook.get_mut().unwrap_or_else(PoisonError::into_inner);
+        if let Some(Hook(hook)) = hook.take() {
+            hook(Err(BaguaNetError::InnerError(
+                "request was dropped before it was done".to_owned(),
+            )));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_concurrent_completions() {
+        let nthreads = 8;
+        let nsubtasks_per_thread = 100000;
+        let state = Arc::new(RequestState::new(1));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|_| {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..nsubtasks_per_thread {
+                        state.add_subtasks(1);
+                        state.complete_subtask(3);
+                        assert!(!state.progress().0);
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..nsubtasks_per_thread {
+            assert!(!state.progress().0);
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(!state.progress().0);
+
+        state.complete_subtask(0);
+        let nsubtasks = nthreads * nsubtasks_per_thread + 1;
+        assert_eq!(state.nsubtasks.load(Ordering::Relaxed), nsubtasks);
+        assert_eq!(state.completed_subtasks.load(Ordering::Relaxed), nsubtasks);
+        assert_eq!(state.progress(), (true, 3 * (nsubtasks - 1)));
+        assert!(state.err().is_none());
+    }
+
+    #[test]
+    fn test_fail() {
+        let state = RequestState::new(1);
+        state.fail(BaguaNetError::InnerError("first".to_owned()));
+        state.fail(BaguaNetError::InnerError("second".to_owned()));
+        assert!(matches!(state.err(), Some(BaguaNetError::InnerError(err)) if err == "first"));
+        assert_eq!(state.progress(), (false, 0));
+        assert!(state.wait(Some(Duration::ZERO)));
+    }
+
+    #[test]
+    fn test_wait() {
+        let state = Arc::new(RequestState::new(1));
+        let timer = Instant::now();
+        assert!(!state.wait(Some(Duration::from_millis(20))));
+        assert!(timer.elapsed() >= Duration::from_millis(20));
+
+        // The last subtask completes while the waiter waits, or before.
+        for delay in [Duration::ZERO, Duration::from_millis(5)] {
+            let state = Arc::new(RequestState::new(1));
+            state.add_subtasks(100);
+            let completer = {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(delay);
+                    for _ in 0..101 {
+                        state.complete_subtask(1);
+                    }
+                })
+            };
+            assert!(state.wait(None));
+            assert_eq!(state.progress(), (true, 101));
+            completer.join().unwrap();
+        }
+
+        let waiter = {
+            let state = state.clone();
+            std::thread::spawn(move || state.wait(Some(Duration::from_secs(10))))
+        };
+        state.fail(BaguaNetError::InnerError("failed".to_owned()));
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn test_hook() {
+        let (sender, called) = flume::unbounded();
+        let hook = |sender: &flume::Sender<Result<usize, BaguaNetError>>| -> CompletionHook {
+            let sender = sender.clone();
+            Box::new(move |ret| sender.send(ret).unwrap())
+        };
+
+        // Once, by the last subtask.
+        let state = RequestState::new(2);
+        state.set_hook(hook(&sender));
+        state.complete_subtask(3);
+        assert!(called.is_empty());
+        state.complete_subtask(4);
+        assert_eq!(called.try_recv().unwrap().unwrap(), 7);
+        drop(state);
+        assert!(called.is_empty());
+
+        // With the first error.
+        let state = RequestState::new(2);
+        state.set_hook(hook(&sender));
+        state.fail(BaguaNetError::InnerError("first".to_owned()));
+        state.fail(BaguaNetError::InnerError("second".to_owned()));
+        state.complete_subtask(0);
+        state.complete_subtask(0);
+        assert!(
+            matches!(called.try_recv().unwrap(), Err(BaguaNetError::InnerError(err)) if err == "first")
+        );
+        assert!(called.is_empty());
+
+        // Right away once done.
+        let state = RequestState::new(1);
+        state.complete_subtask(5);
+        state.set_hook(hook(&sender));
+        assert_eq!(called.try_recv().unwrap().unwrap(), 5);
+
+        // Never done.
+        let state = RequestState::new(1);
+        state.set_hook(hook(&sender));
+        drop(state);
+        assert!(called.try_recv().unwrap().is_err());
+
+        // Completions racing the hook being set.
+        for _ in 0..1000 {
+            let state = Arc::new(RequestState::new(1));
+            let completer = {
+                let state = state.clone();
+                std::thread::spawn(move || state.complete_subtask(1))
+            };
+            state.set_hook(hook(&sender));
+            completer.join().unwrap();
+            drop(state);
+            assert_eq!(called.try_recv().unwrap().unwrap(), 1);
+            assert!(called.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_poisoned() {
+        let (sender, called) = flume::unbounded();
+        let state = Arc::new(RequestState::new(2));
+        state.set_hook(Box::new(move |ret| sender.send(ret).unwrap()));
+        let waiter = {
+            let state = state.clone();
+            std::thread::spawn(move || state.wait(Some(Duration::from_secs(10))))
+        };
+        state.complete_subtask(3);
+        {
+            let state = state.clone();
+            let panicked = std::thread::spawn(move || {
+                let _locked = (
+                    state.err.lock().unwrap(),
+                    state.waiting.lock().unwrap(),
+                    state.hook.lock().unwrap(),
+                );
+                panic!("while the state is locked");
+            });
+            assert!(panicked.join().is_err());
+        }
+        assert!(state.hook.is_poisoned() && state.waiting.is_poisoned());
+
+        // Completed all the same.
+        state.complete_subtask(4);
+        assert!(waiter.join().unwrap());
+        assert_eq!(state.progress(), (true, 7));
+        assert_eq!(called.try_recv().unwrap().unwrap(), 7);
+        state.fail(BaguaNetError::Timeout);
+        assert!(matches!(state.err(), Some(BaguaNetError::Timeout)));
+    }
+
+    #[test]
+    fn test_lend() {
+        let (sender, called) = flume::unbounded();
+        let state = Arc::new(RequestState::new(1));
+        state.set_hook(Box::new(move |ret| sender.send(ret).unwrap()));
+        let loan = state.lend().unwrap();
+        let second = state.lend().unwrap();
+
+        // Reported once nothing is lent the buffer.
+        state.fail(BaguaNetError::InnerError("failed".to_owned()));
+        assert!(state.gave_up());
+        assert!(state.lend().is_none());
+        assert!(state.err().is_none() && !state.done());
+        drop(loan);
+        assert!(!state.wait(Some(Duration::ZERO)) && called.is_empty());
+        drop(second);
+        assert!(state.wait(Some(Duration::ZERO)));
+        assert!(matches!(state.err(), Some(BaguaNetError::InnerError(_))));
+        assert!(called.try_recv().unwrap().is_err());
+
+        // Cancelled, until the driver gives it back.
+        let state = Arc::new(RequestState::new(1));
+        let loan = state.lend().unwrap();
+        let giver = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(loan);
+        });
+        let timer = Instant::now();
+        state.cancel();
+        assert!(state.lend().is_none());
+        state.wait_returned();
+        assert!(timer.elapsed() >= Duration::from_millis(20));
+        giver.join().unwrap();
+        assert!(!state.done());
+    }
+
+    #[test]
+    fn test_timing() {
+        let state = RequestState::new(2);
+        let timer = Instant::now();
+        std::thread::sleep(Duration::from_millis(10));
+        state.start();
+        state.complete_subtask(1);
+        assert_eq!(state.timing(), None);
+        std::thread::sleep(Duration::from_millis(10));
+        // Only the first counts.
+        state.start();
+        state.complete_subtask(1);
+        let timing = state.timing().unwrap();
+        assert!(timing.queued >= Duration::from_millis(10), "{:?}", timing);
+        assert!(timing.wire >= Duration::from_millis(10), "{:?}", timing);
+        assert!(timing.queued + timing.wire <= timer.elapsed());
+
+        // Never started, queued until done.
+        let state = RequestState::new(1);
+        state.complete_subtask(0);
+        assert_eq!(state.timing().unwrap().wire, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_tally() {
+        let net = Arc::new(Tally::default());
+        let comm = Arc::new(Tally::of_comm(&net));
+        let request = || {
+            RequestState::new(2)
+                .counted(comm.clone())
+                .tallied(comm.clone())
+        };
+        let state = request();
+        state.complete_subtask(100);
+        state.complete_subtask(28);
+        // Failed, then its subtasks complete, or fail once more.
+        let state = request();
+        state.fail(BaguaNetError::InnerError("first".to_owned()));
+        state.complete_subtask(0);
+        state.fail(BaguaNetError::InnerError("second".to_owned()));
+        state.complete_subtask(0);
+        assert_eq!(comm.since_reset(), [128, 1, 1]);
+        assert_eq!(net.since_reset(), [128, 1, 1]);
+
+        comm.reset();
+        let state = request();
+        state.complete_subtask(5);
+        state.complete_subtask(5);
+        assert_eq!(comm.since_reset(), [10, 1, 0]);
+        assert_eq!(net.since_reset(), [138, 2, 1]);
+        // The counters go on.
+        assert_eq!(comm.nbytes.load(Ordering::Relaxed), 138);
+    }
+}
@@ -1,62 +1,85 @@
+use crate::connection;
+use crate::connection::{
+    AcceptConfig, AdaptiveStreamsConfig, ConnectConfig, ListenCloser, ListenConfig, Listener,
+    PendingStreams, Stream,
+};
+pub use crate::implement::RequestState;
 use crate::interface;
 use crate::interface::{
-    BaguaNetError, NCCLNetProperties, Net, SocketHandle, SocketListenCommID, SocketRecvCommID,
-    SocketRequestID, SocketSendCommID,
+    BaguaNetError, MrHandle, NCCLNetProperties, RecvBuffer, SendBuffer, SocketHandle,
+    SocketListenCommID, SocketRecvCommID, SocketRequestID, SocketSendCommID,
 };
+use crate::mr::{MrRegistry, PtrType};
+use crate::slab;
+use crate::slab::{RecentlyDone, Slab};
+use crate::split_tuning;
+use crate::telemetry::{self, Gauge, RequestSpan, SpanSampler, TelemetryConfig};
+use crate::tls::TlsConfig;
 use crate::utils;
 use crate::utils::NCCLSocketDev;
-use nix::sys::socket::{InetAddr, SockAddr};
+use nix::sys::socket::SockAddr;
 use opentelemetry::{
-    metrics::{BoundValueRecorder, ObserverResult},
+    metrics::{Meter, ObserverResult},
     trace::{Span, TraceContextExt, Tracer},
     KeyValue,
 };
-use socket2::{Domain, Socket, Type};
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::mpsc;
+use uuid::Uuid;
 
 const NCCL_PTR_HOST: i32 = 1;
+#[allow(dead_code)]
 const NCCL_PTR_CUDA: i32 = 2;
+/// Of the metrics uploader and server, once the net is dropped.
+const UPLOADER_STOP_TIMEOUT: Duration = Duration::from_secs(10);
 
 lazy_static! {
     static ref HANDLER_ALL: [KeyValue; 1] = [KeyValue::new("handler", "all")];
 }
 
 pub struct SocketListenComm {
-    pub tcp_listener: Arc<Mutex<net::TcpListener>>,
+    pub listener: Arc<Mutex<Listener>>,
+    pub pending_streams: Arc<Mutex<PendingStreams>>,
+    /// Of `listener`, which an accept keeps locked.
+    pub closer: ListenCloser,
+}
+
+/// Of a comm, the error of the first of its streams that broke. The requests
+/// after the one it failed fail with it, and so do posts to the comm.
+pub type Broken = Arc<Mutex<Option<BaguaNetError>>>;
+
+/// Of a request, whose stream broke with `err`.
+fn fail_broken(broken: &Broken, state: &RequestState, err: std::io::Error) {
+    let err = BaguaNetError::remote_io("stream broke", err);
+    tracing::warn!("stream broke, err={:?}", err);
+    broken.lock().unwrap().get_or_insert_with(|| err.clone());
+    state.fail(err);
 }
 
 // TODO: make Rotating communicator
 #[derive(Clone)]
 pub struct SocketSendComm {
-    pub msg_sender: mpsc::UnboundedSender<(&'static [u8], Arc<Mutex<RequestState>>)>,
+    pub msg_sender: mpsc::Sender<(SendBuffer, Arc<RequestState>)>,
+    pub broken: Broken,
 }
 
 #[derive(Clone)]
 pub struct SocketRecvComm {
-    pub msg_sender: mpsc::UnboundedSender<(&'static mut [u8], Arc<Mutex<RequestState>>)>,
+    pub msg_sender: mpsc::Sender<(RecvBuffer, Arc<RequestState>)>,
+    pub broken: Broken,
 }
 
 pub struct SocketSendRequest {
-    pub state: Arc<Mutex<RequestState>>,
-    pub trace_span: opentelemetry::global::BoxedSpan,
+    pub state: Arc<RequestState>,
+    pub trace_span: RequestSpan,
 }
 
 pub struct SocketRecvRequest {
-    pub state: Arc<Mutex<RequestState>>,
-    pub trace_span: opentelemetry::global::BoxedSpan,
-}
-
-#[derive(Debug)]
-pub struct RequestState {
-    pub nsubtasks: usize,
-    pub completed_subtasks: usize,
-    pub nbytes_transferred: usize,
-    pub err: Option<BaguaNetError>,
+    pub state: Arc<RequestState>,
+    pub trace_span: RequestSpan,
 }
 
 pub enum SocketRequest {
@@ -64,174 +87,139 @@ pub enum SocketRequest {
     RecvRequest(SocketRecvRequest),
 }
 
-static TELEMETRY_INIT_ONCE: std::sync::Once = std::sync::Once::new();
-// static TELEMETRY_GUARD: Option<TelemetryGuard> = None;
-
+#[allow(dead_code)]
 struct AppState {
-    exporter: opentelemetry_prometheus::PrometheusExporter,
-    isend_nbytes_gauge: BoundValueRecorder<'static, u64>,
-    irecv_nbytes_gauge: BoundValueRecorder<'static, u64>,
+    isend_nbytes_gauge: Gauge,
+    irecv_nbytes_gauge: Gauge,
     isend_per_second: Arc<Mutex<f64>>,
     request_count: Arc<Mutex<usize>>,
     isend_nbytes_per_second: Arc<Mutex<f64>>,
     isend_percentage_of_effective_time: Arc<Mutex<f64>>,
-    // isend_nbytes_gauge: BoundValueRecorder<'static, u64>,
-    // irecv_nbytes_gauge: BoundValueRecorder<'static, u64>,
-    uploader: std::thread::JoinHandle<()>,
+}
+
+/// Of the metrics that are pushed.
+fn observe_metrics(meter: &Meter, state: &AppState) {
+    for (name, value) in [
+        ("isend_nbytes_per_second", &state.isend_nbytes_per_second),
+        ("isend_per_second", &state.isend_per_second),
+        (
+            "isend_percentage_of_effective_time",
+            &state.isend_percentage_of_effective_time,
+        ),
+    ] {
+        let value = value.clone();
+        meter
+            .f64_value_observer(name, move |res: ObserverResult<f64>| {
+                res.observe(*value.lock().unwrap(), HANDLER_ALL.as_ref());
+            })
+            .init();
+    }
+    let request_count = state.request_count.clone();
+    meter.i64_value_observer("hold_on_request", move |res: ObserverResult<i64>| {
+        res.observe(*request_count.lock().unwrap() as i64, HANDLER_ALL.as_ref());
+    });
 }
 
 pub struct BaguaNet {
     pub socket_devs: Vec<NCCLSocketDev>,
-    pub listen_comm_next_id: usize,
-    pub listen_comm_map: HashMap<SocketListenCommID, SocketListenComm>,
-    pub send_comm_next_id: usize,
-    pub send_comm_map: HashMap<SocketSendCommID, SocketSendComm>,
-    pub recv_comm_next_id: usize,
-    pub recv_comm_map: HashMap<SocketRecvCommID, SocketRecvComm>,
-    pub socket_request_next_id: usize,
-    pub socket_request_map: HashMap<SocketRequestID, SocketRequest>,
+    /// See `utils::socket_dev_latencies_us`, devices added since are
+    /// estimated.
+    latencies_us: HashMap<String, f32>,
+    pub listen_comm_map: Slab<SocketListenComm>,
+    pub send_comm_map: Slab<SocketSendComm>,
+    pub recv_comm_map: Slab<SocketRecvComm>,
+    pub socket_request_map: Slab<SocketRequest>,
+    pub recently_done: RecentlyDone,
     pub trace_span_context: opentelemetry::Context,
+    #[allow(dead_code)]
     pub rank: i32,
     state: Arc<AppState>,
     nstreams: usize,
     min_chunksize: usize,
+    /// Of the messages posted to a comm, and of those its master stream
+    /// announced ahead of the data streams.
+    queue_capacity: usize,
+    connect_config: ConnectConfig,
+    accept_config: AcceptConfig,
+    listen_config: ListenConfig,
     tokio_rt: tokio::runtime::Runtime,
+    mr_registry: MrRegistry,
+    /// Pushes the metrics until it is told to stop, by dropping the sender.
+    uploader: Option<(flume::Sender<()>, std::thread::JoinHandle<()>)>,
+    metrics_server: Option<(flume::Sender<()>, std::thread::JoinHandle<()>)>,
+    /// Of the sends and receives that get a span, `None` if none do.
+    span_sampler: Option<SpanSampler>,
 }
 
 impl BaguaNet {
     const DEFAULT_SOCKET_MAX_COMMS: i32 = 65536;
-    const DEFAULT_LISTEN_BACKLOG: i32 = 16384;
 
     pub fn new() -> Result<BaguaNet, BaguaNetError> {
-        let rank: i32 = std::env::var("RANK")
-            .unwrap_or("-1".to_string())
-            .parse()
-            .unwrap();
-        TELEMETRY_INIT_ONCE.call_once(|| {
-            if rank == -1 || rank > 7 {
-                return;
-            }
-
-            let jaeger_addr = match std::env::var("BAGUA_NET_JAEGER_ADDRESS") {
-                Ok(jaeger_addr) => {
-                    tracing::info!("detected auto tuning server, connecting");
-                    jaeger_addr
-                }
-                Err(_) => {
-                    tracing::warn!("Jaeger server not detected.");
-                    return;
-                }
-            };
+        if TlsConfig::from_env()?.is_some() {
+            return Err(BaguaNetError::InvalidArgument(
+                "TLS is only supported by the BASIC implementation".to_owned(),
+            ));
+        }
+        let rank = utils::detect_rank();
+        let telemetry = TelemetryConfig::from_env();
+        telemetry.init_tracing(rank);
 
-            opentelemetry::global::set_text_map_propagator(opentelemetry_jaeger::Propagator::new());
-            opentelemetry_jaeger::new_pipeline()
-                .with_collector_endpoint(format!("http://{}/api/traces", jaeger_addr))
-                .with_service_name("bagua-net")
-                .install_batch(opentelemetry::runtime::AsyncStd)
-                .unwrap();
-        });
+        let socket_devs = utils::filter_socket_devs(
+            utils::find_interfaces(),
+            &std::env::var("BAGUA_NET_SOCKET_IFNAME").unwrap_or("".to_owned()),
+        );
 
         let tracer = opentelemetry::global::tracer("bagua-net");
         let mut span = tracer.start(format!("BaguaNet-{}", rank));
         span.set_attribute(KeyValue::new(
             "socket_devs",
-            format!("{:?}", utils::find_interfaces()),
+            telemetry::capped_attribute(format!("{:?}", socket_devs)),
         ));
 
-        let prom_exporter = opentelemetry_prometheus::exporter()
-            .with_default_histogram_boundaries(vec![16., 1024., 4096., 1048576.])
-            .init();
-
-        let isend_nbytes_per_second = Arc::new(Mutex::new(0.));
-        let isend_percentage_of_effective_time = Arc::new(Mutex::new(0.));
-        let isend_per_second = Arc::new(Mutex::new(0.));
-        let request_count = Arc::new(Mutex::new(0));
-
-        let meter = opentelemetry::global::meter("bagua-net");
-        let isend_nbytes_per_second_clone = isend_nbytes_per_second.clone();
-        meter
-            .f64_value_observer(
-                "isend_nbytes_per_second",
-                move |res: ObserverResult<f64>| {
-                    res.observe(
-                        *isend_nbytes_per_second_clone.lock().unwrap(),
-                        HANDLER_ALL.as_ref(),
-                    );
-                },
-            )
-            .init();
-        let isend_per_second_clone = isend_per_second.clone();
-        meter
-            .f64_value_observer("isend_per_second", move |res: ObserverResult<f64>| {
-                res.observe(
-                    *isend_per_second_clone.lock().unwrap(),
-                    HANDLER_ALL.as_ref(),
-                );
-            })
-            .init();
-        let isend_percentage_of_effective_time_clone = isend_percentage_of_effective_time.clone();
-        meter
-            .f64_value_observer(
-                "isend_percentage_of_effective_time",
-                move |res: ObserverResult<f64>| {
-                    res.observe(
-                        *isend_percentage_of_effective_time_clone.lock().unwrap(),
-                        HANDLER_ALL.as_ref(),
-                    );
-                },
-            )
-            .init();
-        let request_count_clone = request_count.clone();
-        meter.i64_value_observer("hold_on_request", move |res: ObserverResult<i64>| {
-            res.observe(
-                *request_count_clone.lock().unwrap() as i64,
-                HANDLER_ALL.as_ref(),
-            );
-        });
+        let metrics = telemetry.metrics();
+        let meter = metrics.as_ref().map(|(_, meters)| &meters.meter);
         let state = Arc::new(AppState {
-            exporter: prom_exporter.clone(),
-            isend_nbytes_gauge: meter
-                .u64_value_recorder("isend_nbytes")
-                .init()
-                .bind(HANDLER_ALL.as_ref()),
-            irecv_nbytes_gauge: meter
-                .u64_value_recorder("irecv_nbytes")
-                .init()
-                .bind(HANDLER_ALL.as_ref()),
-            request_count: request_count,
-            isend_per_second: isend_per_second,
-            isend_nbytes_per_second: isend_nbytes_per_second,
-            isend_percentage_of_effective_time: isend_percentage_of_effective_time,
-            uploader: std::thread::spawn(move || {
-                let prometheus_addr =
-                    std::env::var("BAGUA_NET_PROMETHEUS_ADDRESS").unwrap_or_default();
-                let (user, pass, address) = match utils::parse_user_pass_and_addr(&prometheus_addr)
-                {
-                    Some(ret) => ret,
-                    None => return,
-                };
-
-                loop {
-                    std::thread::sleep(std::time::Duration::from_micros(200));
-                    let metric_families = prom_exporter.registry().gather();
-                    match prometheus::push_metrics(
-                        "BaguaNet",
-                        prometheus::labels! { "rank".to_owned() => rank.to_string(), },
-                        &address,
-                        metric_families,
-                        Some(prometheus::BasicAuthentication {
-                            username: user.clone(),
-                            password: pass.clone(),
-                        }),
-                    ) {
-                        Ok(_) => {}
-                        Err(err) => {
-                            tracing::warn!("{:?}", err);
-                        }
+            isend_nbytes_gauge: Gauge::new(meter, "isend_nbytes", HANDLER_ALL.as_ref()),
+            irecv_nbytes_gauge: Gauge::new(meter, "irecv_nbytes", HANDLER_ALL.as_ref()),
+            request_count: Arc::new(Mutex::new(0)),
+            isend_per_second: Arc::new(Mutex::new(0.)),
+            isend_nbytes_per_second: Arc::new(Mutex::new(0.)),
+            isend_percentage_of_effective_time: Arc::new(Mutex::new(0.)),
+        });
+        if let Some(meter) = meter {
+            observe_metrics(meter, &state);
+        }
+        let metrics_server = match (&metrics, telemetry.prometheus_listen) {
+            (Some((exporter, meters)), Some(addr)) => {
+                match telemetry::listen_metrics(addr, &meters.meter) {
+                    Ok(listener) => {
+                        let exporter = exporter.clone();
+                        let (stop_server, stopped) = flume::bounded::<()>(0);
+                        let server = std::thread::spawn(move || {
+                            telemetry::serve_metrics(listener, exporter, stopped)
+                        });
+                        Some((stop_server, server))
+                    }
+                    Err(err) => {
+                        tracing::warn!("failed to serve metrics at {}, err={:?}", addr, err);
+                        None
                     }
                 }
-            }),
-        });
+            }
+            _ => None,
+        };
+        let span_sampler = telemetry.span_sampler();
+        // Nothing to push to otherwise.
+        let uploader = match (metrics, telemetry.prometheus) {
+            (Some((exporter, _)), Some(prometheus)) => {
+                let (stop_uploader, stopped) = flume::bounded::<()>(0);
+                let uploader = std::thread::spawn(move || {
+                    telemetry::push_metrics(prometheus, exporter, rank, stopped)
+                });
+                Some((stop_uploader, uploader))
+            }
+            _ => None,
+        };
 
         let tokio_rt = match std::env::var("BAGUA_NET_TOKIO_WORKER_THREADS") {
             Ok(nworker_thread) => tokio::runtime::Builder::new_multi_thread()
@@ -241,31 +229,78 @@ impl BaguaNet {
                 .unwrap(),
             Err(_) => tokio::runtime::Runtime::new().unwrap(),
         };
+        // Never tuned, with `BAGUA_NET_MIN_CHUNKSIZE=auto` either.
+        let min_chunksize = split_tuning::min_chunksize_from_env(65535).unwrap_or(65535);
+        let queue_capacity = std::env::var("BAGUA_NET_QUEUE_CAPACITY")
+            .unwrap_or("256".to_owned())
+            .parse()
+            .unwrap();
 
         Ok(Self {
-            socket_devs: utils::find_interfaces(),
-            listen_comm_next_id: 0,
+            latencies_us: utils::socket_dev_latencies_us(&socket_devs),
+            socket_devs,
             listen_comm_map: Default::default(),
-            send_comm_next_id: 0,
             send_comm_map: Default::default(),
-            recv_comm_next_id: 0,
             recv_comm_map: Default::default(),
-            socket_request_next_id: 0,
             socket_request_map: Default::default(),
+            recently_done: Default::default(),
             trace_span_context: opentelemetry::Context::current_with_span(span),
-            rank: rank,
-            state: state,
-            nstreams: std::env::var("BAGUA_NET_NSTREAMS")
-                .unwrap_or("2".to_owned())
-                .parse()
-                .unwrap(),
-            min_chunksize: std::env::var("BAGUA_NET_MIN_CHUNKSIZE")
-                .unwrap_or("65535".to_owned())
-                .parse()
-                .unwrap(),
-            tokio_rt: tokio_rt,
+            rank,
+            span_sampler,
+            state,
+            // Never grows with `BAGUA_NET_NSTREAMS=auto`.
+            nstreams: AdaptiveStreamsConfig::nstreams_from_env(),
+            min_chunksize,
+            queue_capacity,
+            // The async pipelines only speak TCP, never inline messages, cut
+            // them in as many chunks as streams, and send no chunk headers,
+            // CRCs or frames, and never steal chunks.
+            connect_config: ConnectConfig {
+                uds: false,
+                inline_threshold: 0,
+                min_chunksize,
+                chunk_bytes: 0,
+                seq_check: false,
+                crc: false,
+                compression: false,
+                work_stealing: false,
+                ..ConnectConfig::from_env()
+            },
+            accept_config: AcceptConfig {
+                inline_threshold: 0,
+                min_chunksize,
+                chunk_bytes: 0,
+                seq_check: false,
+                crc: false,
+                compression: false,
+                work_stealing: false,
+                ..AcceptConfig::from_env()
+            },
+            listen_config: ListenConfig {
+                uds: false,
+                ..ListenConfig::from_env()
+            },
+            tokio_rt,
+            mr_registry: Default::default(),
+            uploader,
+            metrics_server,
         })
     }
+
+    fn socket_dev(&self, dev_id: usize) -> Result<&NCCLSocketDev, BaguaNetError> {
+        self.socket_devs.get(dev_id).ok_or_else(|| {
+            BaguaNetError::InvalidArgument(format!(
+                "invalid dev_id {}, there are {} devices",
+                dev_id,
+                self.socket_devs.len()
+            ))
+        })
+    }
+
+    /// Connect options for the streams of a send comm on device `dev_id`.
+    fn connect_config_of(&self, dev_id: usize) -> Result<ConnectConfig, BaguaNetError> {
+        Ok(self.connect_config.bind_to(&self.socket_dev(dev_id)?.addr))
+    }
 }
 
 impl interface::Net for BaguaNet {
@@ -274,16 +309,24 @@ impl interface::Net for BaguaNet {
     }
 
     fn get_properties(&self, dev_id: usize) -> Result<NCCLNetProperties, BaguaNetError> {
-        let socket_dev = &self.socket_devs[dev_id];
+        let socket_dev = self.socket_dev(dev_id)?;
+        let speed = utils::get_socket_dev_speed(socket_dev);
 
         Ok(NCCLNetProperties {
             name: socket_dev.interface_name.clone(),
             pci_path: socket_dev.pci_path.clone(),
             guid: dev_id as u64,
             ptr_support: NCCL_PTR_HOST,
-            speed: utils::get_net_if_speed(&socket_dev.interface_name),
+            speed,
             port: 0,
             max_comms: BaguaNet::DEFAULT_SOCKET_MAX_COMMS,
+            latency_us: self
+                .latencies_us
+                .get(&socket_dev.interface_name)
+                .copied()
+                .unwrap_or_else(|| utils::estimate_latency_us(speed)),
+            mtu: utils::get_net_if_mtu(&socket_dev.interface_name),
+            numa_node: utils::get_socket_dev_numa_node(socket_dev).map_or(-1, |node| node as i32),
         })
     }
 
@@ -291,8 +334,8 @@ impl interface::Net for BaguaNet {
         &mut self,
         dev_id: usize,
     ) -> Result<(SocketHandle, SocketListenCommID), BaguaNetError> {
-        let socket_dev = &self.socket_devs[dev_id];
-        let addr = match socket_dev.addr.clone() {
+        let socket_dev = self.socket_dev(dev_id)?;
+        let addr = match socket_dev.addr {
             SockAddr::Inet(inet_addr) => inet_addr,
             others => {
                 return Err(BaguaNetError::InnerError(format!(
@@ -302,73 +345,59 @@ impl interface::Net for BaguaNet {
             }
         };
 
-        let socket = match Socket::new(
-            match addr {
-                InetAddr::V4(_) => Domain::IPV4,
-                InetAddr::V6(_) => Domain::IPV6,
-            },
-            Type::STREAM,
-            None,
-        ) {
-            Ok(sock) => sock,
-            Err(err) => return Err(BaguaNetError::IOError(format!("{:?}", err))),
-        };
-        socket.bind(&addr.to_std().into()).unwrap();
-        socket.listen(BaguaNet::DEFAULT_LISTEN_BACKLOG).unwrap();
-
-        let listener: net::TcpListener = socket.into();
-        let socket_addr = listener.local_addr().unwrap();
-        let socket_handle = SocketHandle {
-            addr: SockAddr::new_inet(InetAddr::from_std(&socket_addr)),
-        };
-        let id = self.listen_comm_next_id;
-        self.listen_comm_next_id += 1;
-        self.listen_comm_map.insert(
-            id,
-            SocketListenComm {
-                tcp_listener: Arc::new(Mutex::new(listener)),
-            },
-        );
+        let mut listener = connection::bind_listener(addr, &self.listen_config)?;
+        if let Some(SockAddr::Inet(alt_addr)) = socket_dev.alt_addr {
+            listener.bind_alt(alt_addr, &self.listen_config);
+        }
+        let socket_handle = listener.socket_handle()?;
+        let id = self.listen_comm_map.next_id()?;
+        self.listen_comm_map.insert(SocketListenComm {
+            closer: listener.closer.clone(),
+            listener: Arc::new(Mutex::new(listener)),
+            pending_streams: Default::default(),
+        })?;
 
         Ok((socket_handle, id))
     }
 
     fn connect(
         &mut self,
-        _dev_id: usize,
+        dev_id: usize,
         socket_handle: SocketHandle,
     ) -> Result<SocketSendCommID, BaguaNetError> {
+        let connect_config = self.connect_config_of(dev_id)?;
         // Init datapass tcp stream
+        let comm_uuid = Uuid::new_v4();
+        let ctrl_stream = connection::connect_ctrl_stream(
+            &socket_handle,
+            comm_uuid,
+            self.nstreams,
+            &connect_config,
+        )?
+        .0
+        .into_tcp()?;
         let mut stream_vec = Vec::new();
-        for stream_id in 0..self.nstreams {
-            let mut stream = match net::TcpStream::connect(socket_handle.addr.clone().to_str()) {
-                Ok(stream) => stream,
-                Err(err) => {
-                    tracing::warn!(
-                        "net::TcpStream::connect failed, err={:?}, socket_handle={:?}",
-                        err,
-                        socket_handle
-                    );
-                    return Err(BaguaNetError::TCPError(format!(
-                        "socket_handle={:?}, err={:?}",
-                        socket_handle, err
-                    )));
-                }
-            };
+        for stream in connection::connect_data_streams(
+            &socket_handle,
+            comm_uuid,
+            self.nstreams,
+            &connect_config,
+        )? {
+            let stream = stream.into_tcp()?;
             tracing::debug!(
                 "{:?} connect to {:?}",
                 stream.local_addr(),
                 socket_handle.addr.clone().to_str()
             );
-            stream.write_all(&stream_id.to_be_bytes()[..]).unwrap();
-
             stream_vec.push(stream);
         }
 
         // Launch async datapass pipeline
         let min_chunksize = self.min_chunksize;
         let (datapass_sender, mut datapass_receiver) =
-            mpsc::unbounded_channel::<(&'static [u8], Arc<Mutex<RequestState>>)>();
+            mpsc::channel::<(&'static [u8], Arc<RequestState>)>(self.queue_capacity);
+        let broken: Broken = Default::default();
+        let datapass_broken = broken.clone();
         self.tokio_rt.spawn(async move {
             let mut stream_vec: Vec<tokio::net::TcpStream> = stream_vec
                 .into_iter()
@@ -384,13 +413,17 @@ impl interface::Net for BaguaNet {
                     Some(it) => it,
                     None => break,
                 };
-                if data.len() == 0 {
-                    state.lock().unwrap().completed_subtasks += 1;
+                if let Some(err) = datapass_broken.lock().unwrap().clone() {
+                    state.fail(err);
+                    continue;
+                }
+                if data.is_empty() {
+                    state.complete_subtask(0);
                     continue;
                 }
 
                 let mut chunks =
-                    data.chunks(utils::chunk_size(data.len(), min_chunksize, nstreams));
+                    data.chunks(utils::chunk_size(data.len(), min_chunksize, 0, nstreams));
 
                 let mut datapass_fut = Vec::with_capacity(stream_vec.len());
                 for stream in stream_vec.iter_mut() {
@@ -399,78 +432,68 @@ impl interface::Net for BaguaNet {
                         None => break,
                     };
 
-                    datapass_fut.push(stream.write_all(&chunk[..]));
+                    datapass_fut.push(stream.write_all(chunk));
+                }
+                let written: Result<Vec<_>, _> = futures::future::join_all(datapass_fut)
+                    .await
+                    .into_iter()
+                    .collect();
+                if let Err(err) = written {
+                    fail_broken(&datapass_broken, &state, err);
+                    continue;
                 }
-                futures::future::join_all(datapass_fut).await;
 
-                match state.lock() {
-                    Ok(mut state) => {
-                        state.completed_subtasks += 1;
-                        state.nbytes_transferred += data.len();
-                    }
-                    Err(poisoned) => {
-                        tracing::warn!("{:?}", poisoned);
-                    }
-                };
+                state.complete_subtask(data.len());
             }
         });
 
-        let mut ctrl_stream = match net::TcpStream::connect(socket_handle.addr.clone().to_str()) {
-            Ok(ctrl_stream) => ctrl_stream,
-            Err(err) => {
-                tracing::warn!(
-                    "net::TcpStream::connect failed, err={:?}, socket_handle={:?}",
-                    err,
-                    socket_handle
-                );
-                return Err(BaguaNetError::TCPError(format!(
-                    "socket_handle={:?}, err={:?}",
-                    socket_handle, err
-                )));
-            }
-        };
-        ctrl_stream
-            .write_all(&self.nstreams.to_be_bytes()[..])
-            .unwrap();
         tracing::debug!(
             "ctrl_stream {:?} connect to {:?}",
             ctrl_stream.local_addr(),
             ctrl_stream.peer_addr()
         );
 
-        let (msg_sender, mut msg_receiver) = tokio::sync::mpsc::unbounded_channel();
-        let id = self.send_comm_next_id;
-        self.send_comm_next_id += 1;
+        let (msg_sender, mut msg_receiver) = mpsc::channel(self.queue_capacity);
+        let id = self.send_comm_map.next_id()?;
         let send_comm = SocketSendComm {
-            msg_sender: msg_sender,
+            msg_sender,
+            broken: broken.clone(),
         };
         self.tokio_rt.spawn(async move {
             let mut ctrl_stream = tokio::net::TcpStream::from_std(ctrl_stream).unwrap();
             ctrl_stream.set_nodelay(true).unwrap();
             loop {
                 let (data, state) = match msg_receiver.recv().await {
-                    Some(it) => it,
+                    Some((data, state)) => (data.slice(), state),
                     None => break,
                 };
+                if let Some(err) = broken.lock().unwrap().clone() {
+                    state.fail(err);
+                    continue;
+                }
 
-                match ctrl_stream.write_u32(data.len() as u32).await {
-                    Ok(_) => {}
-                    Err(err) => {
-                        state.lock().unwrap().err =
-                            Some(BaguaNetError::IOError(format!("{:?}", err)));
-                        break;
-                    }
-                };
+                if let Err(err) = ctrl_stream.write_u32(data.len() as u32).await {
+                    fail_broken(&broken, &state, err);
+                    continue;
+                }
                 tracing::debug!(
                     "send to {:?} target_nbytes={}",
                     ctrl_stream.peer_addr(),
                     data.len()
                 );
 
-                datapass_sender.send((data, state)).unwrap();
+                // Waits for the data streams to catch up, which take every
+                // message until the comm is closed.
+                if let Err(mpsc::error::SendError((_, state))) =
+                    datapass_sender.send((data, state)).await
+                {
+                    state.fail(BaguaNetError::remote(
+                        "the data streams are gone".to_owned(),
+                    ));
+                }
             }
         });
-        self.send_comm_map.insert(id, send_comm);
+        self.send_comm_map.insert(send_comm)?;
 
         Ok(id)
     }
@@ -478,38 +501,43 @@ impl interface::Net for BaguaNet {
     fn accept(
         &mut self,
         listen_comm_id: SocketListenCommID,
-    ) -> Result<SocketRecvCommID, BaguaNetError> {
-        let listen_comm = self.listen_comm_map.get(&listen_comm_id).unwrap();
-
-        let mut ctrl_stream = None;
-        let mut stream_vec = std::collections::BTreeMap::new();
-        for _ in 0..=self.nstreams {
-            let (mut stream, _addr) = match listen_comm.tcp_listener.lock().unwrap().accept() {
-                Ok(listen) => listen,
-                Err(err) => {
-                    return Err(BaguaNetError::TCPError(format!("{:?}", err)));
-                }
-            };
-
-            let mut stream_id = (0 as usize).to_be_bytes();
-            stream.read_exact(&mut stream_id[..]).unwrap();
-            let stream_id = usize::from_be_bytes(stream_id);
-
-            if stream_id == self.nstreams {
-                ctrl_stream = Some(stream);
-            } else {
-                stream_vec.insert(stream_id, stream);
-            }
-        }
-        let ctrl_stream = ctrl_stream.unwrap();
+    ) -> Result<Option<SocketRecvCommID>, BaguaNetError> {
+        let listen_comm = self
+            .listen_comm_map
+            .get(listen_comm_id)
+            .ok_or_else(|| slab::unknown("listen comm", listen_comm_id))?;
+
+        let group = connection::accept_stream_group(
+            &listen_comm.listener.lock().unwrap(),
+            &mut listen_comm.pending_streams.lock().unwrap(),
+            self.nstreams,
+            &self.accept_config,
+        )?;
+        let group = match group {
+            Some(group) => group,
+            None => return Ok(None),
+        };
+        tracing::debug!(
+            "accepted send comm {}, peer={}",
+            group.comm_uuid,
+            group.ctrl_stream.peer()
+        );
+        let stream_vec = group
+            .data_streams
+            .into_iter()
+            .map(Stream::into_tcp)
+            .collect::<Result<Vec<_>, _>>()?;
+        let ctrl_stream = group.ctrl_stream.into_tcp()?;
 
         let min_chunksize = self.min_chunksize;
         let (datapass_sender, mut datapass_receiver) =
-            mpsc::unbounded_channel::<(&'static mut [u8], Arc<Mutex<RequestState>>)>();
+            mpsc::channel::<(&'static mut [u8], Arc<RequestState>)>(self.queue_capacity);
+        let broken: Broken = Default::default();
+        let datapass_broken = broken.clone();
         self.tokio_rt.spawn(async move {
             let mut stream_vec: Vec<tokio::net::TcpStream> = stream_vec
                 .into_iter()
-                .map(|(_, stream)| tokio::net::TcpStream::from_std(stream).unwrap())
+                .map(|stream| tokio::net::TcpStream::from_std(stream).unwrap())
                 .collect();
             for stream in stream_vec.iter_mut() {
                 stream.set_nodelay(true).unwrap();
@@ -521,13 +549,17 @@ impl interface::Net for BaguaNet {
                     Some(it) => it,
                     None => break,
                 };
-                if data.len() == 0 {
-                    state.lock().unwrap().completed_subtasks += 1;
+                if let Some(err) = datapass_broken.lock().unwrap().clone() {
+                    state.fail(err);
+                    continue;
+                }
+                if data.is_empty() {
+                    state.complete_subtask(0);
                     continue;
                 }
 
                 let mut chunks =
-                    data.chunks_mut(utils::chunk_size(data.len(), min_chunksize, nstreams));
+                    data.chunks_mut(utils::chunk_size(data.len(), min_chunksize, 0, nstreams));
                 let mut datapass_fut = Vec::with_capacity(stream_vec.len());
                 for stream in stream_vec.iter_mut() {
                     let chunk = match chunks.next() {
@@ -537,41 +569,43 @@ impl interface::Net for BaguaNet {
 
                     datapass_fut.push(stream.read_exact(&mut chunk[..]));
                 }
-                futures::future::join_all(datapass_fut).await;
+                let read: Result<Vec<_>, _> = futures::future::join_all(datapass_fut)
+                    .await
+                    .into_iter()
+                    .collect();
+                if let Err(err) = read {
+                    fail_broken(&datapass_broken, &state, err);
+                    continue;
+                }
 
-                match state.lock() {
-                    Ok(mut state) => {
-                        state.completed_subtasks += 1;
-                        state.nbytes_transferred += data.len();
-                    }
-                    Err(poisoned) => {
-                        tracing::warn!("{:?}", poisoned);
-                    }
-                };
+                state.complete_subtask(data.len());
             }
         });
 
-        let (msg_sender, mut msg_receiver) = mpsc::unbounded_channel();
-        let id = self.recv_comm_next_id;
-        self.recv_comm_next_id += 1;
+        let (msg_sender, mut msg_receiver) = mpsc::channel(self.queue_capacity);
+        let id = self.recv_comm_map.next_id()?;
         let recv_comm = SocketRecvComm {
-            msg_sender: msg_sender,
+            msg_sender,
+            broken: broken.clone(),
         };
         self.tokio_rt.spawn(async move {
             let mut ctrl_stream = tokio::net::TcpStream::from_std(ctrl_stream).unwrap();
             ctrl_stream.set_nodelay(true).unwrap();
             loop {
                 let (data, state) = match msg_receiver.recv().await {
-                    Some(it) => it,
+                    Some((data, state)) => (data.into_slice(), state),
                     None => break,
                 };
+                if let Some(err) = broken.lock().unwrap().clone() {
+                    state.fail(err);
+                    continue;
+                }
 
                 let target_nbytes = match ctrl_stream.read_u32().await {
                     Ok(n) => n as usize,
                     Err(err) => {
-                        state.lock().unwrap().err =
-                            Some(BaguaNetError::IOError(format!("{:?}", err)));
-                        break;
+                        fail_broken(&broken, &state, err);
+                        continue;
                     }
                 };
 
@@ -580,49 +614,93 @@ impl interface::Net for BaguaNet {
                     ctrl_stream.local_addr(),
                     target_nbytes
                 );
+                if target_nbytes > data.len() {
+                    // The streams are out of step from then on.
+                    let err = BaguaNetError::remote(format!(
+                        "message of {} bytes does not fit in the {} bytes posted",
+                        target_nbytes,
+                        data.len()
+                    ));
+                    broken.lock().unwrap().get_or_insert_with(|| err.clone());
+                    state.fail(err);
+                    continue;
+                }
 
-                datapass_sender
+                if let Err(mpsc::error::SendError((_, state))) = datapass_sender
                     .send((&mut data[..target_nbytes], state))
-                    .unwrap();
+                    .await
+                {
+                    state.fail(BaguaNetError::remote(
+                        "the data streams are gone".to_owned(),
+                    ));
+                }
             }
         });
-        self.recv_comm_map.insert(id, recv_comm);
+        self.recv_comm_map.insert(recv_comm)?;
 
-        Ok(id)
+        Ok(Some(id))
+    }
+
+    fn reg_mr(
+        &mut self,
+        data: &'static [u8],
+        ptr_type: PtrType,
+    ) -> Result<MrHandle, BaguaNetError> {
+        if ptr_type != PtrType::Host {
+            return Err(BaguaNetError::InvalidArgument(format!(
+                "{:?} memory is not supported",
+                ptr_type
+            )));
+        }
+        self.mr_registry.register(data, ptr_type)
+    }
+
+    fn dereg_mr(&mut self, handle: MrHandle) -> Result<(), BaguaNetError> {
+        self.mr_registry.deregister(handle)
     }
 
     fn isend(
         &mut self,
         send_comm_id: SocketSendCommID,
-        data: &'static [u8],
+        data: SendBuffer,
+        mr: Option<MrHandle>,
     ) -> Result<SocketRequestID, BaguaNetError> {
-        let tracer = opentelemetry::global::tracer("bagua-net");
-        let mut span = tracer
-            .span_builder(format!("isend-{}", send_comm_id))
-            .with_parent_context(self.trace_span_context.clone())
-            .start(&tracer);
-        let send_comm = self.send_comm_map.get(&send_comm_id).unwrap();
-        let id = self.socket_request_next_id;
+        self.mr_registry.check(mr, data.as_raw())?;
+        let mut span = RequestSpan::start(self.span_sampler, &self.trace_span_context, || {
+            format!("isend-{}", send_comm_id)
+        });
+        let send_comm = self
+            .send_comm_map
+            .get(send_comm_id)
+            .ok_or_else(|| slab::unknown("send comm", send_comm_id))?;
+        if let Some(err) = &*send_comm.broken.lock().unwrap() {
+            return Err(err.clone());
+        }
+        // Before the task is queued, which it cannot be taken back from.
+        let id = self.socket_request_map.next_id()?;
+        let task_state = Arc::new(RequestState::new(1));
+        match send_comm.msg_sender.try_send((data, task_state.clone())) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => return Err(BaguaNetError::Busy),
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                return Err(BaguaNetError::remote(format!(
+                    "send comm {} is gone",
+                    send_comm_id
+                )))
+            }
+        }
 
         span.set_attribute(KeyValue::new("id", id as i64));
         span.set_attribute(KeyValue::new("nbytes", data.len() as i64));
+        if data.is_empty() {
+            span.set_attribute(KeyValue::new("zero_byte", true));
+        }
 
-        self.socket_request_next_id += 1;
-        let task_state = Arc::new(Mutex::new(RequestState {
-            nsubtasks: 1,
-            completed_subtasks: 0,
-            nbytes_transferred: 0,
-            err: None,
-        }));
-        self.socket_request_map.insert(
-            id,
-            SocketRequest::SendRequest(SocketSendRequest {
+        self.socket_request_map
+            .insert(SocketRequest::SendRequest(SocketSendRequest {
                 state: task_state.clone(),
                 trace_span: span,
-            }),
-        );
-
-        send_comm.msg_sender.send((data, task_state)).unwrap();
+            }))?;
 
         Ok(id)
     }
@@ -630,100 +708,200 @@ impl interface::Net for BaguaNet {
     fn irecv(
         &mut self,
         recv_comm_id: SocketRecvCommID,
-        data: &'static mut [u8],
+        data: RecvBuffer,
+        mr: Option<MrHandle>,
     ) -> Result<SocketRequestID, BaguaNetError> {
-        let tracer = opentelemetry::global::tracer("bagua-net");
-        let mut span = tracer
-            .span_builder(format!("irecv-{}", recv_comm_id))
-            .with_parent_context(self.trace_span_context.clone())
-            .start(&tracer);
-        let recv_comm = self.recv_comm_map.get(&recv_comm_id).unwrap();
-        let id = self.socket_request_next_id;
+        self.mr_registry.check(mr, data.as_raw())?;
+        let mut span = RequestSpan::start(self.span_sampler, &self.trace_span_context, || {
+            format!("irecv-{}", recv_comm_id)
+        });
+        let recv_comm = self
+            .recv_comm_map
+            .get(recv_comm_id)
+            .ok_or_else(|| slab::unknown("recv comm", recv_comm_id))?;
+        if let Some(err) = &*recv_comm.broken.lock().unwrap() {
+            return Err(err.clone());
+        }
+        // Before the task is queued, which it cannot be taken back from.
+        let id = self.socket_request_map.next_id()?;
+        let task_state = Arc::new(RequestState::new(1));
+        match recv_comm.msg_sender.try_send((data, task_state.clone())) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => return Err(BaguaNetError::Busy),
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                return Err(BaguaNetError::remote(format!(
+                    "recv comm {} is gone",
+                    recv_comm_id
+                )))
+            }
+        }
 
         span.set_attribute(KeyValue::new("id", id as i64));
 
-        self.socket_request_next_id += 1;
-        let task_state = Arc::new(Mutex::new(RequestState {
-            nsubtasks: 1,
-            completed_subtasks: 0,
-            nbytes_transferred: 0,
-            err: None,
-        }));
-        self.socket_request_map.insert(
-            id,
-            SocketRequest::RecvRequest(SocketRecvRequest {
+        self.socket_request_map
+            .insert(SocketRequest::RecvRequest(SocketRecvRequest {
                 state: task_state.clone(),
                 trace_span: span,
-            }),
-        );
-
-        recv_comm.msg_sender.send((data, task_state)).unwrap();
+            }))?;
 
         Ok(id)
     }
 
     fn test(&mut self, request_id: SocketRequestID) -> Result<(bool, usize), BaguaNetError> {
         *self.state.request_count.lock().unwrap() = self.socket_request_map.len();
-        let request = self.socket_request_map.get_mut(&request_id).unwrap();
+        let request = match self.socket_request_map.get_mut(request_id) {
+            Some(request) => request,
+            None => {
+                return match self.recently_done.take(request_id) {
+                    Some(nbytes) => Ok((true, nbytes)),
+                    None => Err(slab::unknown("request", request_id)),
+                }
+            }
+        };
         let ret = match request {
             SocketRequest::SendRequest(send_req) => {
-                let state = send_req.state.lock().unwrap();
-                if let Some(err) = state.err.clone() {
+                let (task_completed, nbytes_transferred) = send_req.state.progress();
+                if let Some(err) = send_req.state.err() {
+                    send_req.trace_span.set_attribute(KeyValue::new(
+                        "error",
+                        telemetry::capped_attribute(format!("{:?}", err)),
+                    ));
+                    send_req.trace_span.end();
                     return Err(err);
                 }
 
-                let task_completed = state.nsubtasks == state.completed_subtasks;
                 if task_completed {
                     send_req.trace_span.end();
                 }
-                Ok((task_completed, state.nbytes_transferred))
+                Ok((task_completed, nbytes_transferred))
             }
             SocketRequest::RecvRequest(recv_req) => {
-                let state = recv_req.state.lock().unwrap();
-                if let Some(err) = state.err.clone() {
+                let (task_completed, nbytes_transferred) = recv_req.state.progress();
+                if let Some(err) = recv_req.state.err() {
+                    recv_req.trace_span.set_attribute(KeyValue::new(
+                        "error",
+                        telemetry::capped_attribute(format!("{:?}", err)),
+                    ));
+                    recv_req.trace_span.end();
                     return Err(err);
                 }
 
-                let task_completed = state.nsubtasks == state.completed_subtasks;
                 if task_completed {
+                    if nbytes_transferred == 0 {
+                        recv_req
+                            .trace_span
+                            .set_attribute(KeyValue::new("zero_byte", true));
+                    }
                     recv_req.trace_span.end();
                 }
-                Ok((task_completed, state.nbytes_transferred))
+                Ok((task_completed, nbytes_transferred))
             }
         };
 
         if let Ok(ret) = ret {
             if ret.0 {
-                self.socket_request_map.remove(&request_id).unwrap();
+                self.socket_request_map.remove(request_id).unwrap();
+                self.recently_done.push(request_id, ret.1);
             }
         }
 
         ret
     }
 
+    fn wait(
+        &mut self,
+        request_id: SocketRequestID,
+        timeout: Option<Duration>,
+    ) -> Result<usize, BaguaNetError> {
+        let state = match self
+            .socket_request_map
+            .get(request_id)
+            .ok_or_else(|| slab::unknown("request", request_id))?
+        {
+            SocketRequest::SendRequest(send_req) => send_req.state.clone(),
+            SocketRequest::RecvRequest(recv_req) => recv_req.state.clone(),
+        };
+        if !state.wait(timeout) {
+            return Err(BaguaNetError::Timeout);
+        }
+        let (_, nbytes) = self.test(request_id)?;
+        Ok(nbytes)
+    }
+
+    fn received_size(
+        &mut self,
+        request_id: SocketRequestID,
+    ) -> Result<Option<usize>, BaguaNetError> {
+        match self
+            .socket_request_map
+            .get(request_id)
+            .ok_or_else(|| slab::unknown("request", request_id))?
+        {
+            // Only known once the message is in.
+            SocketRequest::RecvRequest(recv_req) => match recv_req.state.progress() {
+                (true, nbytes) => Ok(Some(nbytes)),
+                (false, _) => Ok(None),
+            },
+            SocketRequest::SendRequest(_) => Err(BaguaNetError::InvalidArgument(format!(
+                "request {} is a send",
+                request_id
+            ))),
+        }
+    }
+
     fn close_send(&mut self, send_comm_id: SocketSendCommID) -> Result<(), BaguaNetError> {
-        self.send_comm_map.remove(&send_comm_id);
+        self.send_comm_map
+            .remove(send_comm_id)
+            .ok_or_else(|| slab::unknown("send comm", send_comm_id))?;
         tracing::debug!("close_send send_comm_id={}", send_comm_id);
 
         Ok(())
     }
 
     fn close_recv(&mut self, recv_comm_id: SocketRecvCommID) -> Result<(), BaguaNetError> {
-        self.recv_comm_map.remove(&recv_comm_id);
+        self.recv_comm_map
+            .remove(recv_comm_id)
+            .ok_or_else(|| slab::unknown("recv comm", recv_comm_id))?;
         tracing::debug!("close_recv recv_comm_id={}", recv_comm_id);
 
         Ok(())
     }
 
     fn close_listen(&mut self, listen_comm_id: SocketListenCommID) -> Result<(), BaguaNetError> {
-        self.listen_comm_map.remove(&listen_comm_id);
+        let listen_comm = self
+            .listen_comm_map
+            .remove(listen_comm_id)
+            .ok_or_else(|| slab::unknown("listen comm", listen_comm_id))?;
+        listen_comm.closer.close();
 
         Ok(())
     }
+
+    fn listen_closer(
+        &self,
+        listen_comm_id: SocketListenCommID,
+    ) -> Result<Option<ListenCloser>, BaguaNetError> {
+        let listen_comm = self
+            .listen_comm_map
+            .get(listen_comm_id)
+            .ok_or_else(|| slab::unknown("listen comm", listen_comm_id))?;
+        Ok(Some(listen_comm.closer.clone()))
+    }
 }
 
 impl Drop for BaguaNet {
     fn drop(&mut self) {
+        for (name, exporter) in [
+            ("uploader", self.uploader.take()),
+            ("server", self.metrics_server.take()),
+        ] {
+            if let Some((stop, exporter)) = exporter {
+                drop(stop);
+                if !utils::join_until(exporter, Instant::now() + UPLOADER_STOP_TIMEOUT) {
+                    tracing::warn!("metrics {} still running after the net was dropped", name);
+                }
+            }
+        }
         // TODO: make shutdown global
         self.trace_span_context.span().end();
         opentelemetry::global::shutdown_tracer_provider();
@@ -733,9 +911,279 @@ impl Drop for BaguaNet {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use interface::Net;
+    use nix::sys::socket::InetAddr;
+
+    /// A `BaguaNet` whose only device is the loopback interface.
+    fn loopback_net() -> BaguaNet {
+        let mut net = BaguaNet::new().unwrap();
+        net.socket_devs = vec![NCCLSocketDev {
+            interface_name: "lo".to_owned(),
+            addr: SockAddr::new_inet(InetAddr::from_std(&"127.0.0.1:0".parse().unwrap())),
+            alt_addr: None,
+            pci_path: "".to_owned(),
+        }];
+        net
+    }
+
+    fn wait_done(net: &mut BaguaNet, id: SocketRequestID) -> usize {
+        let timer = std::time::Instant::now();
+        loop {
+            let (done, nbytes) = net.test(id).unwrap();
+            if done {
+                return nbytes;
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_send_recv_loopback() {
+        let mut recv_net = loopback_net();
+        let props = recv_net.get_properties(0).unwrap();
+        assert_eq!(props.name, "lo");
+        assert_eq!(props.speed, utils::LOOPBACK_SPEED);
+
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        let send_buf: &'static [u8] = Box::leak(data.clone().into_boxed_slice());
+        let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; data.len()].into_boxed_slice());
+        let recv_ptr = recv_buf.as_ptr();
+
+        let (socket_handle, listen_id) = recv_net.listen(0).unwrap();
+        // connect() returns once accept() answered its handshake.
+        let sender = std::thread::spawn(move || {
+            let mut send_net = loopback_net();
+            let send_id = send_net.connect(0, socket_handle).unwrap();
+            let send_req = send_net.isend(send_id, send_buf.into(), None).unwrap();
+            wait_done(&mut send_net, send_req)
+        });
+        let timer = std::time::Instant::now();
+        let recv_id = loop {
+            if let Some(id) = recv_net.accept(listen_id).unwrap() {
+                break id;
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        };
+        let recv_req = recv_net.irecv(recv_id, recv_buf.into(), None).unwrap();
+        assert_eq!(wait_done(&mut recv_net, recv_req), data.len());
+        assert_eq!(sender.join().unwrap(), data.len());
+
+        let received = unsafe { std::slice::from_raw_parts(recv_ptr, data.len()) };
+        assert_eq!(received, &data[..]);
+    }
+
+    #[test]
+    fn test_received_size() {
+        let mut recv_net = loopback_net();
+        let (socket_handle, listen_id) = recv_net.listen(0).unwrap();
+        let sizes = [3, 0, 1 << 20];
+        let sender = std::thread::spawn(move || {
+            let mut send_net = loopback_net();
+            let send_id = send_net.connect(0, socket_handle).unwrap();
+            for &nbytes in sizes.iter() {
+                let send_buf: &'static [u8] = Box::leak(vec![7u8; nbytes].into_boxed_slice());
+                let send_req = send_net.isend(send_id, send_buf.into(), None).unwrap();
+                wait_done(&mut send_net, send_req);
+            }
+        });
+        let timer = std::time::Instant::now();
+        let recv_id = loop {
+            if let Some(id) = recv_net.accept(listen_id).unwrap() {
+                break id;
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        };
+
+        // Into larger buffers.
+        for &nbytes in sizes.iter() {
+            let recv_buf: &'static mut [u8] = Box::leak(vec![0u8; 1 << 20].into_boxed_slice());
+            let recv_req = recv_net.irecv(recv_id, recv_buf.into(), None).unwrap();
+            let timer = std::time::Instant::now();
+            while recv_net.received_size(recv_req).unwrap().is_none() {
+                assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+                std::thread::yield_now();
+            }
+            assert_eq!(recv_net.received_size(recv_req).unwrap(), Some(nbytes));
+            assert_eq!(wait_done(&mut recv_net, recv_req), nbytes);
+        }
+        sender.join().unwrap();
+    }
 
     #[test]
-    fn it_works() {
-        BaguaNet::new().unwrap();
+    fn test_send_peer_gone() {
+        let mut recv_net = loopback_net();
+        let (socket_handle, listen_id) = recv_net.listen(0).unwrap();
+        let (posted, wait_posted) = flume::bounded::<()>(0);
+        let sender = std::thread::spawn(move || {
+            // More than the socket buffers hold, and one queued behind it.
+            let send_buf: &'static [u8] = Box::leak(vec![1u8; 256 << 20].into_boxed_slice());
+            let mut send_net = loopback_net();
+            let send_id = send_net.connect(0, socket_handle).unwrap();
+            let send_req = send_net.isend(send_id, send_buf.into(), None).unwrap();
+            let queued_req = send_net
+                .isend(send_id, (&send_buf[..1024]).into(), None)
+                .unwrap();
+            posted.send(()).unwrap();
+            let timer = std::time::Instant::now();
+            let err = loop {
+                match send_net.test(send_req) {
+                    Ok((done, _)) => assert!(!done),
+                    Err(err) => break err,
+                }
+                assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+                std::thread::yield_now();
+            };
+            assert!(matches!(err, BaguaNetError::TCPError { .. }));
+            loop {
+                match send_net.test(queued_req) {
+                    Ok((done, _)) => assert!(!done),
+                    Err(err) => break assert!(matches!(err, BaguaNetError::TCPError { .. })),
+                }
+                assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+                std::thread::yield_now();
+            }
+            assert!(matches!(
+                send_net.isend(send_id, (&send_buf[..1024]).into(), None),
+                Err(BaguaNetError::TCPError { .. })
+            ));
+        });
+        let timer = std::time::Instant::now();
+        let recv_id = loop {
+            if let Some(id) = recv_net.accept(listen_id).unwrap() {
+                break id;
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        };
+
+        // Mid-transfer, its streams close without anything received.
+        wait_posted.recv().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        recv_net.close_recv(recv_id).unwrap();
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_close_unknown_ids() {
+        let mut recv_net = loopback_net();
+        assert!(matches!(
+            recv_net.close_recv(0),
+            Err(BaguaNetError::InvalidId {
+                kind: "recv comm",
+                ..
+            })
+        ));
+        assert!(matches!(
+            recv_net.close_listen(0),
+            Err(BaguaNetError::InvalidId {
+                kind: "listen comm",
+                ..
+            })
+        ));
+        let (socket_handle, listen_id) = recv_net.listen(0).unwrap();
+        let sender = std::thread::spawn(move || {
+            let mut send_net = loopback_net();
+            assert!(matches!(
+                send_net.close_send(0),
+                Err(BaguaNetError::InvalidId {
+                    kind: "send comm",
+                    ..
+                })
+            ));
+            let send_id = send_net.connect(0, socket_handle).unwrap();
+            send_net.close_send(send_id).unwrap();
+            assert!(matches!(
+                send_net.close_send(send_id),
+                Err(BaguaNetError::InvalidId {
+                    kind: "send comm",
+                    ..
+                })
+            ));
+        });
+        let timer = std::time::Instant::now();
+        let recv_id = loop {
+            if let Some(id) = recv_net.accept(listen_id).unwrap() {
+                break id;
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        };
+        sender.join().unwrap();
+
+        recv_net.close_recv(recv_id).unwrap();
+        recv_net.close_listen(listen_id).unwrap();
+        assert!(matches!(
+            recv_net.close_recv(recv_id),
+            Err(BaguaNetError::InvalidId {
+                kind: "recv comm",
+                ..
+            })
+        ));
+        assert!(matches!(
+            recv_net.close_listen(listen_id),
+            Err(BaguaNetError::InvalidId {
+                kind: "listen comm",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_recv_busy() {
+        let mut recv_net = loopback_net();
+        recv_net.queue_capacity = 1;
+        let (socket_handle, listen_id) = recv_net.listen(0).unwrap();
+        let (go, wait_go) = flume::bounded::<()>(0);
+        let sender = std::thread::spawn(move || {
+            let mut send_net = loopback_net();
+            let send_id = send_net.connect(0, socket_handle).unwrap();
+            wait_go.recv().unwrap();
+            for i in 0..2u8 {
+                let send_buf: &'static [u8] = Box::leak(vec![i; 64].into_boxed_slice());
+                let send_req = send_net.isend(send_id, send_buf.into(), None).unwrap();
+                wait_done(&mut send_net, send_req);
+            }
+        });
+        let timer = std::time::Instant::now();
+        let recv_id = loop {
+            if let Some(id) = recv_net.accept(listen_id).unwrap() {
+                break id;
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        };
+
+        // One waits for its size on the master stream, the other in the
+        // channel.
+        let recv_ptrs: Vec<*mut u8> = (0..3)
+            .map(|_| Box::leak(vec![0u8; 64].into_boxed_slice()).as_mut_ptr())
+            .collect();
+        let recv_buf = |i: usize| unsafe { std::slice::from_raw_parts_mut(recv_ptrs[i], 64) };
+        let first = recv_net.irecv(recv_id, recv_buf(0).into(), None).unwrap();
+        let timer = std::time::Instant::now();
+        let second = loop {
+            match recv_net.irecv(recv_id, recv_buf(1).into(), None) {
+                Ok(id) => break id,
+                Err(BaguaNetError::Busy) => {}
+                Err(err) => panic!("{:?}", err),
+            }
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::yield_now();
+        };
+        assert!(matches!(
+            recv_net.irecv(recv_id, recv_buf(2).into(), None),
+            Err(BaguaNetError::Busy)
+        ));
+
+        go.send(()).unwrap();
+        for (i, id) in [first, second].iter().enumerate() {
+            assert_eq!(wait_done(&mut recv_net, *id), 64);
+            let received = unsafe { std::slice::from_raw_parts(recv_ptrs[i], 64) };
+            assert_eq!(received, &[i as u8; 64][..]);
+        }
+        sender.join().unwrap();
     }
 }
@@ -1,13 +1,150 @@
+use crate::connection::ListenCloser;
+use crate::mr::PtrType;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 
+/// What an error is about, for callers that handle errors by cause rather
+/// than by message, see `BaguaNetError::nccl_result`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A call into the system, or the device, failed on this host.
+    SystemError,
+    /// The peer is unreachable, went away, or does not speak the protocol
+    /// the way this side does.
+    RemoteError,
+    /// Of the caller, e.g. an unknown device, comm or request.
+    InvalidArgument,
+    /// A bug.
+    InternalError,
+    Timeout,
+    /// Post again later.
+    Busy,
+}
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug, Clone)]
 pub enum BaguaNetError {
-    #[error("io error")]
-    IOError(String),
-    #[error("tcp error")]
-    TCPError(String),
-    #[error("inner error")]
+    /// A `ErrorKind::SystemError`, with the error of the call if there is
+    /// one.
+    #[error("io error: {msg}")]
+    IOError {
+        msg: String,
+        #[source]
+        source: Option<Arc<std::io::Error>>,
+    },
+    /// A `ErrorKind::RemoteError`, with the error of the socket if there is
+    /// one, e.g. a refused connect.
+    #[error("tcp error: {msg}")]
+    TCPError {
+        msg: String,
+        #[source]
+        source: Option<Arc<std::io::Error>>,
+    },
+    #[error("inner error: {0}")]
     InnerError(String),
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    /// The comm has as many messages queued as it takes, post it again later.
+    #[error("busy")]
+    Busy,
+    /// The CRC32C of a received message is not the one it was sent with, see
+    /// `BAGUA_NET_CRC`.
+    #[error("corruption")]
+    Corruption(String),
+    /// `Net::wait` gave up, the request is still pending. From `Net::test`,
+    /// or from `Net::wait` once the request is past
+    /// `BAGUA_NET_REQUEST_TIMEOUT_SECS`, it is terminal instead: the request
+    /// failed and stays until it is cancelled, and posts to its comm fail
+    /// from then on.
+    #[error("timeout")]
+    Timeout,
+    /// The listen comm was closed while `Net::accept` waited on it.
+    #[error("closed")]
+    Closed,
+    /// Not the ID of a live comm or request of `kind`, e.g. it was closed
+    /// already.
+    #[error("unknown {kind} {id}")]
+    InvalidId { kind: &'static str, id: usize },
+}
+
+impl BaguaNetError {
+    pub fn system(msg: impl Into<String>) -> BaguaNetError {
+        BaguaNetError::IOError {
+            msg: msg.into(),
+            source: None,
+        }
+    }
+
+    /// Of a call that failed with `err`, described by `msg`.
+    pub fn system_io(msg: impl Into<String>, err: impl Into<std::io::Error>) -> BaguaNetError {
+        BaguaNetError::IOError {
+            msg: msg.into(),
+            source: Some(Arc::new(err.into())),
+        }
+    }
+
+    pub fn remote(msg: impl Into<String>) -> BaguaNetError {
+        BaguaNetError::TCPError {
+            msg: msg.into(),
+            source: None,
+        }
+    }
+
+    pub fn remote_io(msg: impl Into<String>, err: impl Into<std::io::Error>) -> BaguaNetError {
+        BaguaNetError::TCPError {
+            msg: msg.into(),
+            source: Some(Arc::new(err.into())),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            BaguaNetError::IOError { .. } => ErrorKind::SystemError,
+            BaguaNetError::TCPError { .. } | BaguaNetError::Corruption(_) => ErrorKind::RemoteError,
+            BaguaNetError::InnerError(_) => ErrorKind::InternalError,
+            BaguaNetError::InvalidArgument(_)
+            | BaguaNetError::Closed
+            | BaguaNetError::InvalidId { .. } => ErrorKind::InvalidArgument,
+            BaguaNetError::Busy => ErrorKind::Busy,
+            BaguaNetError::Timeout => ErrorKind::Timeout,
+        }
+    }
+
+    /// The `ncclResult_t` NCCL is handed for it:
+    ///
+    /// | kind              | result                        |
+    /// |-------------------|-------------------------------|
+    /// | `SystemError`     | `ncclSystemError` (2)         |
+    /// | `InternalError`   | `ncclInternalError` (3)       |
+    /// | `InvalidArgument` | `ncclInvalidArgument` (4)     |
+    /// | `RemoteError`     | `ncclRemoteError` (6)         |
+    /// | `Timeout`         | `ncclRemoteError` (6)         |
+    /// | `Busy`            | `ncclSuccess` (0)             |
+    ///
+    /// A request times out when its peer stops answering. NCCL posts again
+    /// after a success without a request, which is how busy is told.
+    pub fn nccl_result(&self) -> i32 {
+        match self.kind() {
+            ErrorKind::SystemError => 2,
+            ErrorKind::InternalError => 3,
+            ErrorKind::InvalidArgument => 4,
+            ErrorKind::RemoteError | ErrorKind::Timeout => 6,
+            ErrorKind::Busy => 0,
+        }
+    }
+}
+
+impl From<std::io::Error> for BaguaNetError {
+    fn from(err: std::io::Error) -> BaguaNetError {
+        BaguaNetError::system_io(err.to_string(), err)
+    }
+}
+
+impl From<nix::Error> for BaguaNetError {
+    fn from(err: nix::Error) -> BaguaNetError {
+        BaguaNetError::system_io(err.to_string(), err)
+    }
 }
 
 #[derive(Debug)]
@@ -19,17 +156,191 @@ pub struct NCCLNetProperties {
     pub speed: i32,       // Port speed in Mbps.
     pub port: i32,
     pub max_comms: i32,
+    pub latency_us: f32, // One-way latency in microseconds, -1 if unknown.
+    pub mtu: i32,        // Interface MTU in bytes, -1 if unknown.
+    pub numa_node: i32,  // NUMA node of the NIC, -1 if unknown.
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SocketHandle {
     pub addr: nix::sys::socket::SockAddr,
+    /// Address of the other family on dual-stack hosts, tried if `addr` is
+    /// not reachable.
+    pub alt_addr: Option<nix::sys::socket::SockAddr>,
+    /// Abstract unix socket of the listener, for connectors on the same host.
+    pub uds_addr: Option<nix::sys::socket::SockAddr>,
+    /// Resolved by the connector, with the port of `addr`, instead of
+    /// dialing `addr` and `alt_addr`.
+    pub hostname: Option<String>,
 }
 
 pub type SocketListenCommID = usize;
 pub type SocketSendCommID = usize;
 pub type SocketRecvCommID = usize;
 pub type SocketRequestID = usize;
+/// Of a memory region registered with `Net::reg_mr`.
+pub type MrHandle = usize;
+
+/// Of `Net::isend`, read by the comm's threads until the request is done.
+#[derive(Debug, Clone, Copy)]
+pub struct SendBuffer {
+    ptr: *const u8,
+    len: usize,
+}
+
+// Only read, by the contract of `from_raw_parts`.
+unsafe impl Send for SendBuffer {}
+unsafe impl Sync for SendBuffer {}
+
+impl SendBuffer {
+    /// # Safety
+    ///
+    /// `ptr` must be valid for reads of `len` bytes, which must not be
+    /// written, until `test()` reports the request it is posted with done or
+    /// failed, or the request is cancelled and its comm closed. It may be
+    /// null if `len` is 0.
+    pub unsafe fn from_raw_parts(ptr: *const u8, len: usize) -> SendBuffer {
+        SendBuffer { ptr, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_raw(&self) -> *const [u8] {
+        std::ptr::slice_from_raw_parts(self.ptr, self.len)
+    }
+
+    /// Valid as long as the request it is posted with.
+    pub(crate) fn slice(&self) -> &'static [u8] {
+        match self.len {
+            0 => &[],
+            len => unsafe { std::slice::from_raw_parts(self.ptr, len) },
+        }
+    }
+}
+
+impl From<&'static [u8]> for SendBuffer {
+    fn from(data: &'static [u8]) -> SendBuffer {
+        SendBuffer {
+            ptr: data.as_ptr(),
+            len: data.len(),
+        }
+    }
+}
+
+impl From<&'static mut [u8]> for SendBuffer {
+    fn from(data: &'static mut [u8]) -> SendBuffer {
+        SendBuffer::from(&*data)
+    }
+}
+
+/// Of `Net::irecv`, written by the comm's threads until the request is done.
+#[derive(Debug)]
+pub struct RecvBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// Only accessed by one thread at a time, by the contract of `from_raw_parts`.
+unsafe impl Send for RecvBuffer {}
+
+impl RecvBuffer {
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writes of `len` bytes, which must not be
+    /// accessed otherwise, until `test()` reports the request it is posted
+    /// with done or failed, or the request is cancelled and its comm closed.
+    /// It may be null if `len` is 0.
+    pub unsafe fn from_raw_parts(ptr: *mut u8, len: usize) -> RecvBuffer {
+        RecvBuffer { ptr, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_raw(&self) -> *const [u8] {
+        std::ptr::slice_from_raw_parts(self.ptr, self.len)
+    }
+
+    /// Valid as long as the request it is posted with.
+    pub(crate) fn into_slice(self) -> &'static mut [u8] {
+        match self.len {
+            0 => &mut [],
+            len => unsafe { std::slice::from_raw_parts_mut(self.ptr, len) },
+        }
+    }
+}
+
+impl From<&'static mut [u8]> for RecvBuffer {
+    fn from(data: &'static mut [u8]) -> RecvBuffer {
+        RecvBuffer {
+            ptr: data.as_mut_ptr(),
+            len: data.len(),
+        }
+    }
+}
+
+/// Of `Net::set_completion_hook`, called with what `test()` would have
+/// returned once the request is done: the bytes it carried, or its error.
+pub type CompletionHook = Box<dyn FnOnce(Result<usize, BaguaNetError>) + Send>;
+
+/// Of a request that is done, see `Net::request_timing`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestTiming {
+    /// From when it was posted until a thread of its comm took it: a send
+    /// off its queue, a receive once the header of its message came in.
+    pub queued: Duration,
+    /// From then until its last chunk was written out, or read in.
+    pub wire: Duration,
+}
+
+/// Of `Net::get_statistics`. The counters are of the requests done since
+/// the net was created or last reset, the rest is as of the call.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetStatistics {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Of the sends and receives, flushes are not counted.
+    pub requests_completed: u64,
+    pub requests_failed: u64,
+    /// Of the send and recv comms not closed yet.
+    pub active_comms: usize,
+    /// Of the sends and receives posted, not yet tested done, cancelled or
+    /// handed to their completion hooks.
+    pub active_requests: usize,
+    /// Of the comms not closed yet.
+    pub comms: Vec<CommStatistics>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommKind {
+    Send,
+    Recv,
+}
+
+/// Of a comm, see `NetStatistics`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommStatistics {
+    pub kind: CommKind,
+    /// A `SocketSendCommID` or a `SocketRecvCommID`, by `kind`.
+    pub id: usize,
+    pub peer: String,
+    /// Sent or received, by `kind`.
+    pub nbytes: u64,
+    pub requests_completed: u64,
+    pub requests_failed: u64,
+    pub active_requests: usize,
+}
 
 pub trait Net {
     fn devices(&self) -> Result<usize, BaguaNetError>;
@@ -41,31 +352,257 @@ pub trait Net {
         dev_id: usize,
     ) -> Result<(SocketHandle, SocketListenCommID), BaguaNetError>;
 
+    /// May return before the streams of the send comm are established,
+    /// messages posted on it meanwhile are queued.
     fn connect(
         &mut self,
-        _dev_id: usize,
+        dev_id: usize,
         socket_handle: SocketHandle,
     ) -> Result<SocketSendCommID, BaguaNetError>;
 
+    /// Whether the send comm returned by `connect()` is ready, or the error
+    /// that prevented it from connecting.
+    fn connect_test(&mut self, _send_comm_id: SocketSendCommID) -> Result<bool, BaguaNetError> {
+        Ok(true)
+    }
+
+    /// Does not block waiting for a connector, returns `Ok(None)` when no
+    /// send comm is connecting to the listener yet.
     fn accept(
         &mut self,
         listen_comm_id: SocketListenCommID,
-    ) -> Result<SocketRecvCommID, BaguaNetError>;
+    ) -> Result<Option<SocketRecvCommID>, BaguaNetError>;
+
+    /// For another thread to wake an `accept()` waiting on the listen comm,
+    /// which errs with `BaguaNetError::Closed`, before it calls
+    /// `close_listen()`.
+    fn listen_closer(
+        &self,
+        _listen_comm_id: SocketListenCommID,
+    ) -> Result<Option<ListenCloser>, BaguaNetError> {
+        Ok(None)
+    }
 
+    /// Host memory is locked until it is deregistered.
+    fn reg_mr(&mut self, data: &'static [u8], ptr_type: PtrType)
+        -> Result<MrHandle, BaguaNetError>;
+
+    fn dereg_mr(&mut self, handle: MrHandle) -> Result<(), BaguaNetError>;
+
+    /// `mr` is the region `data` was registered in, if any.
     fn isend(
         &mut self,
         send_comm_id: SocketSendCommID,
-        data: &'static [u8],
+        data: SendBuffer,
+        mr: Option<MrHandle>,
     ) -> Result<SocketRequestID, BaguaNetError>;
 
+    /// One message of the slices `iovs` one after the other, received into
+    /// one buffer like any other. `test()` reports their summed bytes.
+    fn isend_v(
+        &mut self,
+        _send_comm_id: SocketSendCommID,
+        _iovs: &[SendBuffer],
+    ) -> Result<SocketRequestID, BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "gather sends are not supported".to_owned(),
+        ))
+    }
+
     fn irecv(
         &mut self,
         recv_comm_id: SocketRecvCommID,
-        data: &'static mut [u8],
+        data: RecvBuffer,
+        mr: Option<MrHandle>,
     ) -> Result<SocketRequestID, BaguaNetError>;
 
+    /// One request over `bufs`, each of which takes a message like after as
+    /// many `irecv`. Done once they all are, see `test_sizes`.
+    fn irecv_multi(
+        &mut self,
+        _recv_comm_id: SocketRecvCommID,
+        _bufs: Vec<RecvBuffer>,
+    ) -> Result<SocketRequestID, BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "grouped receives are not supported".to_owned(),
+        ))
+    }
+
+    /// Makes what the receives posted before it put in `data` visible there,
+    /// for a device buffer received into through host memory. A request done
+    /// once they all landed, or `None` if there is nothing to wait for, as
+    /// for a host buffer. `data` is not accessed.
+    fn iflush(
+        &mut self,
+        _recv_comm_id: SocketRecvCommID,
+        _data: *const [u8],
+    ) -> Result<Option<SocketRequestID>, BaguaNetError> {
+        Ok(None)
+    }
+
+    /// Whether the request is done, with the bytes it carried. The first poll
+    /// that finds it done frees it, but an ID polled again soon after is
+    /// answered done once more, and errs with `BaguaNetError::InvalidId`
+    /// from then on, like an ID that was never posted. A failed request is
+    /// not freed, every poll errs with its error.
     fn test(&mut self, request_id: SocketRequestID) -> Result<(bool, usize), BaguaNetError>;
 
+    /// Like `test()`, with the size of the message received into each buffer
+    /// of the request, or the bytes transferred if it has one buffer.
+    fn test_sizes(
+        &mut self,
+        request_id: SocketRequestID,
+    ) -> Result<(bool, Vec<usize>), BaguaNetError> {
+        self.test(request_id)
+            .map(|(done, nbytes)| (done, vec![nbytes]))
+    }
+
+    /// Of a recv request, the size of the message its sender declared, which a
+    /// posted buffer may be larger than. `None` until it is known, at the
+    /// latest once `test()` reports the request done, which it then returns.
+    fn received_size(
+        &mut self,
+        _request_id: SocketRequestID,
+    ) -> Result<Option<usize>, BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "received sizes are not supported".to_owned(),
+        ))
+    }
+
+    /// Retracts the request, which `test()` no longer knows then. Returns
+    /// once nothing reads into or writes from its buffer anymore: the rest of
+    /// a message being received is read and dropped, the rest of one being
+    /// sent is written as zeros, so that the streams stay in step, and the
+    /// receiver is told to fail the receive it matched. A send the comm did
+    /// not take yet is not sent at all.
+    fn cancel(&mut self, _request_id: SocketRequestID) -> Result<(), BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "cancelling requests is not supported".to_owned(),
+        ))
+    }
+
+    /// Like `test()` on each of `ids`, into the same index of `out`, which is
+    /// as long. An unknown or failed request only fails its entry.
+    fn test_many(
+        &mut self,
+        ids: &[SocketRequestID],
+        out: &mut [Result<(bool, usize), BaguaNetError>],
+    ) -> Result<(), BaguaNetError> {
+        if ids.len() != out.len() {
+            return Err(BaguaNetError::InvalidArgument(format!(
+                "{} requests tested into {} results",
+                ids.len(),
+                out.len()
+            )));
+        }
+        for (&id, out) in ids.iter().zip(out.iter_mut()) {
+            *out = self.test(id);
+        }
+        Ok(())
+    }
+
+    /// Blocks until the request is done, like `test()` then, for up to
+    /// `timeout` if any. Returns the bytes transferred.
+    fn wait(
+        &mut self,
+        _request_id: SocketRequestID,
+        _timeout: Option<Duration>,
+    ) -> Result<usize, BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "blocking waits are not supported".to_owned(),
+        ))
+    }
+
+    /// Tears the net down in order rather than once it is dropped: nothing
+    /// more is posted, the requests posted get up to `timeout` to be done,
+    /// then the comms are closed and the threads of the net stopped. Does
+    /// nothing once it was called.
+    fn shutdown(&mut self, _timeout: Duration) -> Result<(), BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "shutdown is not supported".to_owned(),
+        ))
+    }
+
+    /// Of a request that is done but not yet found so by `test()`, which frees
+    /// it. `None` while it is pending.
+    fn request_timing(
+        &mut self,
+        _request_id: SocketRequestID,
+    ) -> Result<Option<RequestTiming>, BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "request timings are not supported".to_owned(),
+        ))
+    }
+
+    /// Hands the request over to `hook`, which `test()` no longer knows then.
+    /// Called once, on the thread that finishes the request, or right away
+    /// if it is done already. It runs without any lock of the net held, and
+    /// should not block. A request handed over never times out, `cancel()`
+    /// still retracts it.
+    fn set_completion_hook(
+        &mut self,
+        _request_id: SocketRequestID,
+        _hook: CompletionHook,
+    ) -> Result<(), BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "completion hooks are not supported".to_owned(),
+        ))
+    }
+
+    /// Every message on the send comm is `nbytes` from then on, and goes
+    /// without its size on the master stream once the receiver acknowledged
+    /// it, which sets the same size on its recv comm. Before the first
+    /// message of the comm.
+    fn set_fixed_message_size(
+        &mut self,
+        _send_comm_id: SocketSendCommID,
+        _nbytes: usize,
+    ) -> Result<(), BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "fixed message sizes are not supported".to_owned(),
+        ))
+    }
+
+    /// Like `set_fixed_message_size`, before the first message posted on
+    /// the recv comm.
+    fn set_recv_fixed_message_size(
+        &mut self,
+        _recv_comm_id: SocketRecvCommID,
+        _nbytes: usize,
+    ) -> Result<(), BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "fixed message sizes are not supported".to_owned(),
+        ))
+    }
+
+    /// Caps the bandwidth of the send comm, summed over its streams, at
+    /// `mbps` megabits per second in place of `BAGUA_NET_MAX_BW_MBPS`, `None`
+    /// lifts the cap. Applies to what is not written yet.
+    fn set_max_bandwidth(
+        &mut self,
+        _send_comm_id: SocketSendCommID,
+        _mbps: Option<u64>,
+    ) -> Result<(), BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "bandwidth caps are not supported".to_owned(),
+        ))
+    }
+
+    /// Of counters the comms keep up to date as they go, see `NetStatistics`.
+    fn get_statistics(&self) -> Result<NetStatistics, BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "statistics are not supported".to_owned(),
+        ))
+    }
+
+    /// Starts the counters of `get_statistics` over, e.g. for the rates of an
+    /// interval.
+    fn reset_statistics(&mut self) -> Result<(), BaguaNetError> {
+        Err(BaguaNetError::InvalidArgument(
+            "statistics are not supported".to_owned(),
+        ))
+    }
+
     fn close_send(&mut self, send_comm_id: SocketSendCommID) -> Result<(), BaguaNetError>;
 
     fn close_recv(&mut self, recv_comm_id: SocketRecvCommID) -> Result<(), BaguaNetError>;
@@ -1,18 +1,66 @@
+// The C entry points below validate their pointers by hand and are only ever
+// called from the NCCL plugin shim.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 #[macro_use]
 extern crate lazy_static;
 
+pub mod async_api;
+mod buffer_pool;
+mod compression;
+#[macro_use]
+mod connection;
+mod event_loop;
 mod implement;
 mod interface;
+mod mr;
+mod rate_limit;
+mod slab;
+mod split_tuning;
+mod staging;
+mod telemetry;
+mod tls;
 mod utils;
+mod zerocopy;
 
+use connection::ListenCloser;
 use ffi_convert::{AsRust, CDrop, CReprOf};
 use implement::{nthread_per_socket_backend, tokio_backend};
-use interface::{NCCLNetProperties, Net, SocketHandle};
-use libc;
+use interface::{
+    BaguaNetError, CommKind, CommStatistics, NCCLNetProperties, Net, NetStatistics, RecvBuffer,
+    SendBuffer, SocketHandle,
+};
+use nix::sys::socket::{InetAddr, SockAddr, UnixAddr};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::c_void;
 use std::sync::{Arc, Mutex};
 
+thread_local! {
+    /// Of the last call on the thread that failed with -3, see
+    /// `bagua_net_c_last_nccl_result`.
+    static LAST_NCCL_RESULT: Cell<i32> = const { Cell::new(0) };
+}
+
+/// -3, after it is logged and kept for `bagua_net_c_last_nccl_result`.
+fn failed(err: BaguaNetError) -> i32 {
+    tracing::warn!("{:?}", err);
+    LAST_NCCL_RESULT.with(|result| result.set(err.nccl_result()));
+    -3
+}
+
+/// The `ncclResult_t` of the error of the last call on this thread that
+/// returned -3, see `BaguaNetError::nccl_result`. 0 if there was none.
+#[no_mangle]
+pub extern "C" fn bagua_net_c_last_nccl_result() -> i32 {
+    LAST_NCCL_RESULT.with(Cell::get)
+}
+
 pub struct BaguaNetC {
-    inner: Arc<Mutex<Box<dyn Net>>>,
+    inner: Arc<Mutex<Box<dyn Net + Send>>>,
+    /// By listen comm, reached without `inner`, which an accept keeps
+    /// locked while it waits.
+    listen_closers: Mutex<HashMap<usize, ListenCloser>>,
 }
 
 #[no_mangle]
@@ -20,7 +68,7 @@ pub extern "C" fn bagua_net_c_create() -> *mut BaguaNetC {
     let config = std::env::var("BAGUA_NET_IMPLEMENT")
         .unwrap_or("BASIC".to_owned())
         .to_uppercase();
-    let bagua_net: Box<dyn Net> = match &config[..] {
+    let bagua_net: Box<dyn Net + Send> = match &config[..] {
         "TOKIO" => Box::new(tokio_backend::BaguaNet::new().unwrap()),
         "BASIC" => Box::new(nthread_per_socket_backend::BaguaNet::new().unwrap()),
         _ => {
@@ -29,6 +77,7 @@ pub extern "C" fn bagua_net_c_create() -> *mut BaguaNetC {
     };
     let obj = BaguaNetC {
         inner: Arc::new(Mutex::new(bagua_net)),
+        listen_closers: Default::default(),
     };
 
     // into_raw turns the Box into a *mut, which the borrow checker
@@ -58,9 +107,33 @@ pub extern "C" fn bagua_net_c_destroy(ptr: &mut *mut BaguaNetC) {
     *ptr = ::std::ptr::null_mut();
 }
 
+/// Waits up to `timeout_ms` for the requests posted to be done, then closes
+/// every comm, see `Net::shutdown`. Only `bagua_net_c_destroy` is left to
+/// call.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -3: bagua-net inner error, e.g. requests not done in time
+#[no_mangle]
+pub extern "C" fn bagua_net_c_shutdown(ptr: *mut BaguaNetC, timeout_ms: u64) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+
+    let timeout = std::time::Duration::from_millis(timeout_ms);
+    match unsafe { (*ptr).inner.lock().unwrap().shutdown(timeout) } {
+        Ok(()) => 0,
+        Err(err) => failed(err),
+    }
+}
+
 /// Error code
 /// 0: success
 /// -1: null pointer
+/// -3: bagua-net inner error
 #[no_mangle]
 pub extern "C" fn bagua_net_c_devices(ptr: *mut BaguaNetC, ndev: *mut i32) -> i32 {
     // First, we **must** check to see if the pointer is null.
@@ -69,10 +142,15 @@ pub extern "C" fn bagua_net_c_devices(ptr: *mut BaguaNetC, ndev: *mut i32) -> i3
         return -1;
     }
 
-    unsafe {
-        *ndev = (*ptr).inner.lock().unwrap().devices().unwrap() as i32;
+    match unsafe { (*ptr).inner.lock().unwrap().devices() } {
+        Ok(devices) => {
+            unsafe {
+                *ndev = devices as i32;
+            }
+            0
+        }
+        Err(err) => failed(err),
     }
-    return 0;
 }
 
 #[repr(C)]
@@ -86,11 +164,16 @@ pub struct NCCLNetPropertiesC {
     speed: i32,
     port: i32,
     max_comms: i32,
+    latency_us: f32,
+    mtu: i32,
+    numa_node: i32,
 }
 
 /// Error code
 /// 0: success
 /// -1: null pointer
+/// -2: invalid parameter
+/// -3: bagua-net inner error, e.g. no such device
 #[no_mangle]
 pub extern "C" fn bagua_net_c_get_properties(
     ptr: *mut BaguaNetC,
@@ -106,21 +189,280 @@ pub extern "C" fn bagua_net_c_get_properties(
         return -2;
     }
 
-    unsafe {
-        let props_raw = (*ptr)
-            .inner
-            .lock()
-            .unwrap()
-            .get_properties(dev_id as usize)
-            .unwrap();
-        *props = NCCLNetPropertiesC::c_repr_of(props_raw).unwrap();
+    let props_raw = match unsafe { (*ptr).inner.lock().unwrap().get_properties(dev_id as usize) } {
+        Ok(props_raw) => props_raw,
+        Err(err) => return failed(err),
+    };
+    match NCCLNetPropertiesC::c_repr_of(props_raw) {
+        Ok(props_c) => {
+            unsafe {
+                *props = props_c;
+            }
+            0
+        }
+        Err(err) => failed(BaguaNetError::InvalidArgument(format!(
+            "properties of device {}: {}",
+            dev_id, err
+        ))),
     }
-    return 0;
 }
 
+/// Large enough for both AF_INET and AF_INET6 (address plus scope id).
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union SocketAddrC {
+    pub sa: libc::sockaddr,
+    pub sin: libc::sockaddr_in,
+    pub sin6: libc::sockaddr_in6,
+}
+
+/// Layout version of `SocketHandleC` whose body has the addresses of the
+/// listener. Handles of any other version are rejected.
+const SOCKET_HANDLE_VERSION: u8 = 2;
+/// Layout version of `SocketHandleC` with a hostname in place of the IP
+/// addresses, see `SocketHandleNamedC`.
+const SOCKET_HANDLE_HOSTNAME_VERSION: u8 = 4;
+/// Offset of the hostname in `sockaddr`, right after the family and the port
+/// of both `sockaddr_in` and `sockaddr_in6`.
+const SOCKADDR_HOSTNAME_OFFSET: usize = 4;
+/// The longest hostname a `SocketHandleC` has room for.
+const SOCKET_HANDLE_HOSTNAME_MAX: usize =
+    std::mem::size_of::<SocketAddrC>() - SOCKADDR_HOSTNAME_OFFSET + 20;
+
 #[repr(C)]
 pub struct SocketHandleC {
-    pub sockaddr: libc::sockaddr,
+    pub sockaddr: SocketAddrC,
+    pub version: u8,
+    pub _reserved: [u8; 3],
+    pub body: SocketHandleBodyC,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union SocketHandleBodyC {
+    pub addrs: SocketHandleAddrsC,
+    pub named: SocketHandleNamedC,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SocketHandleAddrsC {
+    /// `AF_INET` or `AF_INET6` if the listener also has an address of the
+    /// other family, 0 if it has none.
+    pub alt_family: u8,
+    pub _reserved: u8,
+    /// In network byte order.
+    pub alt_port: u16,
+    /// IPv4 addresses take the first 4 bytes.
+    pub alt_ip: [u8; 16],
+    pub alt_scope_id: u32,
+    /// Id of the listener's unix socket in big endian, 0 if it has none.
+    pub uds_id: [u8; 8],
+}
+
+/// The body of a handle with a hostname, whose first bytes take the place of
+/// the IP address in `sockaddr`. The addresses are those it resolves to, with
+/// the port of `sockaddr` or `alt_port` by family.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SocketHandleNamedC {
+    /// See `SocketHandleAddrsC::alt_family`.
+    pub alt_family: u8,
+    pub _reserved: u8,
+    /// In network byte order.
+    pub alt_port: u16,
+    /// See `SocketHandleAddrsC::uds_id`.
+    pub uds_id: [u8; 8],
+    /// The rest of the hostname, NUL padded.
+    pub hostname: [u8; 20],
+}
+
+impl SocketHandleC {
+    fn from_handle(handle: &SocketHandle) -> SocketHandleC {
+        // Zeroed as a whole, the reserved bytes included.
+        let mut c_handle: SocketHandleC = unsafe { std::mem::zeroed() };
+        let (sockaddr, len) = handle.addr.as_ffi_pair();
+        let len = std::cmp::min(len as usize, std::mem::size_of::<SocketAddrC>());
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                sockaddr as *const libc::sockaddr as *const u8,
+                &mut c_handle.sockaddr as *mut SocketAddrC as *mut u8,
+                len,
+            );
+        }
+        let alt_addr = match &handle.alt_addr {
+            Some(SockAddr::Inet(inet_addr)) => Some(inet_addr.to_std()),
+            Some(others) => {
+                tracing::warn!("cannot pass address {} in a handle", others);
+                None
+            }
+            None => None,
+        };
+        let mut uds_id = [0u8; 8];
+        if let Some(SockAddr::Unix(unix_addr)) = &handle.uds_addr {
+            match unix_addr.as_abstract().and_then(connection::unix_name_id) {
+                Some(id) => uds_id = id.to_be_bytes(),
+                None => {
+                    tracing::warn!("cannot pass unix address {} in a handle", unix_addr);
+                }
+            }
+        }
+
+        if let Some(hostname) = &handle.hostname {
+            if hostname.len() <= SOCKET_HANDLE_HOSTNAME_MAX {
+                let sockaddr = unsafe {
+                    std::slice::from_raw_parts_mut(
+                        &mut c_handle.sockaddr as *mut SocketAddrC as *mut u8,
+                        std::mem::size_of::<SocketAddrC>(),
+                    )
+                };
+                let sockaddr = &mut sockaddr[SOCKADDR_HOSTNAME_OFFSET..];
+                let (head, tail) = hostname
+                    .as_bytes()
+                    .split_at(std::cmp::min(hostname.len(), sockaddr.len()));
+                sockaddr.fill(0);
+                sockaddr[..head.len()].copy_from_slice(head);
+
+                let mut named: SocketHandleNamedC = unsafe { std::mem::zeroed() };
+                if let Some(alt_addr) = alt_addr {
+                    named.alt_family = match alt_addr {
+                        std::net::SocketAddr::V4(_) => libc::AF_INET as u8,
+                        std::net::SocketAddr::V6(_) => libc::AF_INET6 as u8,
+                    };
+                    named.alt_port = alt_addr.port().to_be();
+                }
+                named.uds_id = uds_id;
+                named.hostname[..tail.len()].copy_from_slice(tail);
+                c_handle.version = SOCKET_HANDLE_HOSTNAME_VERSION;
+                c_handle.body.named = named;
+                return c_handle;
+            }
+            tracing::warn!(
+                "hostname {} is longer than the {} bytes a handle has room for, advertise the address",
+                hostname,
+                SOCKET_HANDLE_HOSTNAME_MAX
+            );
+        }
+
+        let mut addrs: SocketHandleAddrsC = unsafe { std::mem::zeroed() };
+        match alt_addr {
+            Some(std::net::SocketAddr::V4(addr)) => {
+                addrs.alt_family = libc::AF_INET as u8;
+                addrs.alt_port = addr.port().to_be();
+                addrs.alt_ip[..4].copy_from_slice(&addr.ip().octets());
+            }
+            Some(std::net::SocketAddr::V6(addr)) => {
+                addrs.alt_family = libc::AF_INET6 as u8;
+                addrs.alt_port = addr.port().to_be();
+                addrs.alt_ip = addr.ip().octets();
+                addrs.alt_scope_id = addr.scope_id();
+            }
+            None => {}
+        }
+        addrs.uds_id = uds_id;
+        c_handle.version = SOCKET_HANDLE_VERSION;
+        c_handle.body.addrs = addrs;
+        c_handle
+    }
+
+    fn to_handle(&self) -> Option<SocketHandle> {
+        match self.version {
+            SOCKET_HANDLE_VERSION => {}
+            SOCKET_HANDLE_HOSTNAME_VERSION => return self.named_handle(),
+            _ => return None,
+        }
+
+        let addr = unsafe { utils::from_libc_sockaddr(&self.sockaddr.sa)? };
+        let mut handle = SocketHandle {
+            addr,
+            alt_addr: None,
+            uds_addr: None,
+            hostname: None,
+        };
+        let addrs = unsafe { &self.body.addrs };
+        let alt_port = u16::from_be(addrs.alt_port);
+        let alt_addr = match addrs.alt_family as i32 {
+            0 => None,
+            libc::AF_INET => {
+                let ip = &addrs.alt_ip;
+                let ip = std::net::Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]);
+                Some(std::net::SocketAddr::from((ip, alt_port)))
+            }
+            libc::AF_INET6 => Some(std::net::SocketAddr::V6(std::net::SocketAddrV6::new(
+                addrs.alt_ip.into(),
+                alt_port,
+                0,
+                addrs.alt_scope_id,
+            ))),
+            _ => return None,
+        };
+        handle.alt_addr = alt_addr.map(|addr| SockAddr::new_inet(InetAddr::from_std(&addr)));
+        handle.uds_addr = uds_addr(addrs.uds_id)?;
+        Some(handle)
+    }
+
+    /// The addresses of a handle with a hostname have unspecified IPs.
+    fn named_handle(&self) -> Option<SocketHandle> {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &self.sockaddr as *const SocketAddrC as *const u8,
+                std::mem::size_of::<SocketAddrC>(),
+            )
+        };
+        let mut sockaddr: SocketAddrC = unsafe { std::mem::zeroed() };
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                &mut sockaddr as *mut SocketAddrC as *mut u8,
+                SOCKADDR_HOSTNAME_OFFSET,
+            );
+        }
+        let addr = unsafe { utils::from_libc_sockaddr(&sockaddr.sa)? };
+
+        let named = unsafe { &self.body.named };
+        let head = nul_padded(&bytes[SOCKADDR_HOSTNAME_OFFSET..]);
+        let mut hostname = head.to_vec();
+        if head.len() == bytes.len() - SOCKADDR_HOSTNAME_OFFSET {
+            hostname.extend_from_slice(nul_padded(&named.hostname));
+        }
+        let hostname = String::from_utf8(hostname).ok()?;
+        let alt_port = u16::from_be(named.alt_port);
+        let alt_addr = match named.alt_family as i32 {
+            0 => None,
+            libc::AF_INET => Some(std::net::SocketAddr::from((
+                std::net::Ipv4Addr::UNSPECIFIED,
+                alt_port,
+            ))),
+            libc::AF_INET6 => Some(std::net::SocketAddr::from((
+                std::net::Ipv6Addr::UNSPECIFIED,
+                alt_port,
+            ))),
+            _ => return None,
+        };
+        Some(SocketHandle {
+            addr,
+            alt_addr: alt_addr.map(|addr| SockAddr::new_inet(InetAddr::from_std(&addr))),
+            uds_addr: uds_addr(named.uds_id)?,
+            hostname: Some(hostname),
+        })
+    }
+}
+
+/// The bytes of `bytes` up to the first NUL.
+fn nul_padded(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..len]
+}
+
+/// The abstract address of the unix socket with the big endian `uds_id`,
+/// `Some(None)` if it is 0.
+fn uds_addr(uds_id: [u8; 8]) -> Option<Option<SockAddr>> {
+    match u64::from_be_bytes(uds_id) {
+        0 => Some(None),
+        id => Some(Some(SockAddr::Unix(
+            UnixAddr::new_abstract(&connection::unix_name(id)).ok()?,
+        ))),
+    }
 }
 
 #[repr(C)]
@@ -150,15 +492,18 @@ pub extern "C" fn bagua_net_c_listen(
     }
 
     unsafe {
-        let (handle, id) = match (*ptr).inner.lock().unwrap().listen(dev_id as usize) {
+        let mut inner = (*ptr).inner.lock().unwrap();
+        let (handle, id) = match inner.listen(dev_id as usize) {
             Ok(result) => result,
-            Err(_err) => return -3,
+            Err(err) => return failed(err),
         };
-        let (sockaddr, _) = handle.addr.as_ffi_pair();
-        (*socket_handle).sockaddr = *sockaddr;
-        *socket_listen_comm_id = id as usize;
+        if let Ok(Some(closer)) = inner.listen_closer(id) {
+            (*ptr).listen_closers.lock().unwrap().insert(id, closer);
+        }
+        *socket_handle = SocketHandleC::from_handle(&handle);
+        *socket_listen_comm_id = id;
     }
-    return 0;
+    0
 }
 
 /// Error code
@@ -184,61 +529,105 @@ pub extern "C" fn bagua_net_c_connect(
     }
 
     unsafe {
-        let sockaddr = (*socket_handle).sockaddr;
-
-        let sockaddr = libc::sockaddr {
-            sa_family: sockaddr.sa_family,
-            sa_data: sockaddr.sa_data,
+        let handle = match (*socket_handle).to_handle() {
+            Some(handle) => handle,
+            None => return -2,
         };
 
-        *socket_send_comm_id = match (*ptr).inner.lock().unwrap().connect(
-            dev_id as usize,
-            SocketHandle {
-                addr: utils::from_libc_sockaddr(&sockaddr).unwrap(),
-            },
-        ) {
+        *socket_send_comm_id = match (*ptr)
+            .inner
+            .lock()
+            .unwrap()
+            .connect(dev_id as usize, handle)
+        {
             Ok(id) => id,
-            Err(_err) => return -3,
+            Err(err) => return failed(err),
         }
     }
-    return 0;
+    0
 }
 
 /// Error code
 /// 0: success
 /// -1: null pointer
+/// -3: connect failed
+#[no_mangle]
+pub extern "C" fn bagua_net_c_connect_test(
+    ptr: *mut BaguaNetC,
+    send_comm_id: usize,
+    ready: *mut bool,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() || ready.is_null() {
+        // Do nothing.
+        return -1;
+    }
+
+    unsafe {
+        *ready = match (*ptr).inner.lock().unwrap().connect_test(send_comm_id) {
+            Ok(ready) => ready,
+            Err(err) => {
+                return failed(err);
+            }
+        }
+    }
+    0
+}
+
+/// Error code
+/// 0: success, `*accepted` is false if no send comm is connecting yet
+/// -1: null pointer
+/// -3: accept failed
+/// -4: the listen comm was closed meanwhile
 #[no_mangle]
 pub extern "C" fn bagua_net_c_accept(
     ptr: *mut BaguaNetC,
     listen_comm_id: usize,
     recv_comm_id: *mut usize,
+    accepted: *mut bool,
 ) -> i32 {
     // First, we **must** check to see if the pointer is null.
-    if ptr.is_null() {
+    if ptr.is_null() || accepted.is_null() {
         // Do nothing.
         return -1;
     }
 
     unsafe {
-        *recv_comm_id = (*ptr).inner.lock().unwrap().accept(listen_comm_id).unwrap();
+        match (*ptr).inner.lock().unwrap().accept(listen_comm_id) {
+            Ok(Some(id)) => {
+                *recv_comm_id = id;
+                *accepted = true;
+            }
+            Ok(None) => *accepted = false,
+            Err(BaguaNetError::Closed) => return -4,
+            Err(err) => {
+                return failed(err);
+            }
+        }
     }
-    return 0;
+    0
 }
 
 #[repr(C)]
 pub struct Buffer {
-    data: *mut u8,
-    len: usize,
+    pub data: *mut u8,
+    pub len: usize,
 }
 
+/// `mhandle` is null, or what `bagua_net_c_reg_mr` returned for the region
+/// of the buffer.
+///
 /// Error code
 /// 0: success
 /// -1: null pointer
+/// -3: bagua-net inner error
+/// -4: the comm is busy, post it again later
 #[no_mangle]
 pub extern "C" fn bagua_net_c_isend(
     ptr: *mut BaguaNetC,
     send_comm_id: usize,
     buf: Buffer,
+    mhandle: *const usize,
     request_id: *mut usize,
 ) -> i32 {
     // First, we **must** check to see if the pointer is null.
@@ -248,25 +637,74 @@ pub extern "C" fn bagua_net_c_isend(
     }
 
     unsafe {
-        let data: &'static mut [u8] = std::slice::from_raw_parts_mut(buf.data, buf.len);
-        *request_id = (*ptr)
-            .inner
-            .lock()
-            .unwrap()
-            .isend(send_comm_id, data)
-            .unwrap();
+        // Valid until the request is done, as NCCL posts it.
+        let data = SendBuffer::from_raw_parts(buf.data, buf.len);
+        let mr = mhandle.as_ref().copied();
+        match (*ptr).inner.lock().unwrap().isend(send_comm_id, data, mr) {
+            Ok(id) => *request_id = id,
+            Err(BaguaNetError::Busy) => return -4,
+            Err(err) => {
+                return failed(err);
+            }
+        }
+    }
+    0
+}
+
+/// One message of the `nbufs` buffers at `bufs` one after the other.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -3: bagua-net inner error
+/// -4: the comm is busy, post it again later
+#[no_mangle]
+pub extern "C" fn bagua_net_c_isend_v(
+    ptr: *mut BaguaNetC,
+    send_comm_id: usize,
+    bufs: *const Buffer,
+    nbufs: usize,
+    request_id: *mut usize,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() || (bufs.is_null() && nbufs > 0) {
+        // Do nothing.
+        return -1;
     }
-    return 0;
+
+    unsafe {
+        let iovs: Vec<SendBuffer> = match nbufs {
+            0 => Vec::new(),
+            _ => std::slice::from_raw_parts(bufs, nbufs)
+                .iter()
+                .map(|buf| SendBuffer::from_raw_parts(buf.data, buf.len))
+                .collect(),
+        };
+        match (*ptr).inner.lock().unwrap().isend_v(send_comm_id, &iovs) {
+            Ok(id) => *request_id = id,
+            Err(BaguaNetError::Busy) => return -4,
+            Err(err) => {
+                return failed(err);
+            }
+        }
+    }
+    0
 }
 
+/// `mhandle` is null, or what `bagua_net_c_reg_mr` returned for the region
+/// of the buffer.
+///
 /// Error code
 /// 0: success
 /// -1: null pointer
+/// -3: bagua-net inner error
+/// -4: the comm is busy, post it again later
 #[no_mangle]
 pub extern "C" fn bagua_net_c_irecv(
     ptr: *mut BaguaNetC,
     recv_comm_id: usize,
     buf: Buffer,
+    mhandle: *const usize,
     request_id: *mut usize,
 ) -> i32 {
     // First, we **must** check to see if the pointer is null.
@@ -276,82 +714,142 @@ pub extern "C" fn bagua_net_c_irecv(
     }
 
     unsafe {
-        let data: &'static mut [u8] = std::slice::from_raw_parts_mut(buf.data, buf.len);
-        *request_id = (*ptr)
-            .inner
-            .lock()
-            .unwrap()
-            .irecv(recv_comm_id, data)
-            .unwrap();
+        // Valid until the request is done, as NCCL posts it.
+        let data = RecvBuffer::from_raw_parts(buf.data, buf.len);
+        let mr = mhandle.as_ref().copied();
+        match (*ptr).inner.lock().unwrap().irecv(recv_comm_id, data, mr) {
+            Ok(id) => *request_id = id,
+            Err(BaguaNetError::Busy) => return -4,
+            Err(err) => {
+                return failed(err);
+            }
+        }
+    }
+    0
+}
+
+/// One request over the `nbufs` buffers at `bufs`, each of which takes a
+/// message like after as many `bagua_net_c_irecv`.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -3: bagua-net inner error
+/// -4: the comm is busy, post it again later
+#[no_mangle]
+pub extern "C" fn bagua_net_c_irecv_multi(
+    ptr: *mut BaguaNetC,
+    recv_comm_id: usize,
+    bufs: *const Buffer,
+    nbufs: usize,
+    request_id: *mut usize,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() || bufs.is_null() {
+        // Do nothing.
+        return -1;
+    }
+
+    unsafe {
+        let bufs: Vec<RecvBuffer> = std::slice::from_raw_parts(bufs, nbufs)
+            .iter()
+            .map(|buf| RecvBuffer::from_raw_parts(buf.data, buf.len))
+            .collect();
+        match (*ptr).inner.lock().unwrap().irecv_multi(recv_comm_id, bufs) {
+            Ok(id) => *request_id = id,
+            Err(BaguaNetError::Busy) => return -4,
+            Err(err) => {
+                return failed(err);
+            }
+        }
     }
-    return 0;
+    0
 }
 
+/// Makes what the receives posted before it put in `buf` visible there. A
+/// request to test in `request_id` if `posted`, else nothing to wait for.
+///
 /// Error code
 /// 0: success
 /// -1: null pointer
 /// -2: invalid parameter
 /// -3: bagua-net inner error
 #[no_mangle]
-pub extern "C" fn bagua_net_c_test(
+pub extern "C" fn bagua_net_c_iflush(
     ptr: *mut BaguaNetC,
-    request_id: usize,
-    done: *mut bool,
-    bytes: *mut usize,
+    recv_comm_id: usize,
+    buf: Buffer,
+    posted: *mut bool,
+    request_id: *mut usize,
 ) -> i32 {
     // First, we **must** check to see if the pointer is null.
     if ptr.is_null() {
         // Do nothing.
         return -1;
     }
-    if done.is_null() {
+    if posted.is_null() || request_id.is_null() {
         return -2;
     }
 
     unsafe {
-        match (*ptr).inner.lock().unwrap().test(request_id) {
-            Ok((let_done, let_bytes)) => {
-                *done = let_done;
-                if let_done && !bytes.is_null() {
-                    *bytes = let_bytes;
+        let data = std::ptr::slice_from_raw_parts(buf.data as *const u8, buf.len);
+        match (*ptr).inner.lock().unwrap().iflush(recv_comm_id, data) {
+            Ok(id) => {
+                *posted = id.is_some();
+                if let Some(id) = id {
+                    *request_id = id;
                 }
             }
             Err(err) => {
-                tracing::warn!("{:?}", err);
-                return -3;
+                return failed(err);
             }
         }
     }
-    return 0;
+    0
 }
 
+/// Locks host memory until `bagua_net_c_dereg_mr`.
+///
 /// Error code
 /// 0: success
 /// -1: null pointer
+/// -2: invalid parameter
+/// -3: bagua-net inner error
 #[no_mangle]
-pub extern "C" fn bagua_net_c_close_send(ptr: *mut BaguaNetC, send_comm_id: usize) -> i32 {
+pub extern "C" fn bagua_net_c_reg_mr(
+    ptr: *mut BaguaNetC,
+    buf: Buffer,
+    ptr_type: i32,
+    mhandle: *mut usize,
+) -> i32 {
     // First, we **must** check to see if the pointer is null.
     if ptr.is_null() {
         // Do nothing.
         return -1;
     }
+    let ptr_type = match mr::PtrType::from_nccl(ptr_type) {
+        Some(ptr_type) => ptr_type,
+        None => return -2,
+    };
 
     unsafe {
-        (*ptr)
-            .inner
-            .lock()
-            .unwrap()
-            .close_send(send_comm_id)
-            .unwrap();
+        let data: &'static [u8] = std::slice::from_raw_parts(buf.data, buf.len);
+        match (*ptr).inner.lock().unwrap().reg_mr(data, ptr_type) {
+            Ok(handle) => *mhandle = handle,
+            Err(err) => {
+                return failed(err);
+            }
+        }
     }
-    return 0;
+    0
 }
 
 /// Error code
 /// 0: success
 /// -1: null pointer
+/// -3: bagua-net inner error, e.g. a handle deregistered already
 #[no_mangle]
-pub extern "C" fn bagua_net_c_close_recv(ptr: *mut BaguaNetC, recv_comm_id: usize) -> i32 {
+pub extern "C" fn bagua_net_c_dereg_mr(ptr: *mut BaguaNetC, mhandle: usize) -> i32 {
     // First, we **must** check to see if the pointer is null.
     if ptr.is_null() {
         // Do nothing.
@@ -359,34 +857,777 @@ pub extern "C" fn bagua_net_c_close_recv(ptr: *mut BaguaNetC, recv_comm_id: usiz
     }
 
     unsafe {
-        (*ptr)
-            .inner
-            .lock()
-            .unwrap()
-            .close_recv(recv_comm_id)
-            .unwrap();
+        if let Err(err) = (*ptr).inner.lock().unwrap().dereg_mr(mhandle) {
+            return failed(err);
+        }
     }
-    return 0;
+    0
 }
 
 /// Error code
 /// 0: success
 /// -1: null pointer
+/// -2: invalid parameter
+/// -3: bagua-net inner error
 #[no_mangle]
-pub extern "C" fn bagua_net_c_close_listen(ptr: *mut BaguaNetC, listen_comm_id: usize) -> i32 {
+pub extern "C" fn bagua_net_c_test(
+    ptr: *mut BaguaNetC,
+    request_id: usize,
+    done: *mut bool,
+    bytes: *mut usize,
+) -> i32 {
     // First, we **must** check to see if the pointer is null.
     if ptr.is_null() {
         // Do nothing.
         return -1;
     }
+    if done.is_null() {
+        return -2;
+    }
 
     unsafe {
-        (*ptr)
-            .inner
-            .lock()
-            .unwrap()
-            .close_listen(listen_comm_id)
-            .unwrap();
+        match (*ptr).inner.lock().unwrap().test(request_id) {
+            Ok((let_done, let_bytes)) => {
+                *done = let_done;
+                if let_done && !bytes.is_null() {
+                    *bytes = let_bytes;
+                }
+            }
+            Err(err) => {
+                return failed(err);
+            }
+        }
+    }
+    0
+}
+
+/// Like `bagua_net_c_test`, with the size of the message received into each
+/// of the `nsizes` buffers of the request in `sizes` once it is done.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -2: invalid parameter
+/// -3: bagua-net inner error
+#[no_mangle]
+pub extern "C" fn bagua_net_c_test_sizes(
+    ptr: *mut BaguaNetC,
+    request_id: usize,
+    done: *mut bool,
+    sizes: *mut usize,
+    nsizes: usize,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+    if done.is_null() || (sizes.is_null() && nsizes > 0) {
+        return -2;
+    }
+
+    unsafe {
+        match (*ptr).inner.lock().unwrap().test_sizes(request_id) {
+            Ok((let_done, let_sizes)) => {
+                *done = let_done;
+                if let_done {
+                    for (i, size) in let_sizes.into_iter().take(nsizes).enumerate() {
+                        *sizes.add(i) = size;
+                    }
+                }
+            }
+            Err(err) => {
+                return failed(err);
+            }
+        }
+    }
+    0
+}
+
+/// The size of the message the sender declared for a recv request in
+/// `bytes`, once `known`, which it is at the latest once the request is done.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -2: invalid parameter
+/// -3: bagua-net inner error, e.g. a send request
+#[no_mangle]
+pub extern "C" fn bagua_net_c_received_size(
+    ptr: *mut BaguaNetC,
+    request_id: usize,
+    known: *mut bool,
+    bytes: *mut usize,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+    if known.is_null() || bytes.is_null() {
+        return -2;
+    }
+
+    unsafe {
+        match (*ptr).inner.lock().unwrap().received_size(request_id) {
+            Ok(let_size) => {
+                *known = let_size.is_some();
+                if let Some(let_size) = let_size {
+                    *bytes = let_size;
+                }
+            }
+            Err(err) => {
+                return failed(err);
+            }
+        }
+    }
+    0
+}
+
+/// Of a request that is done but not yet tested done, how long it was
+/// queued and on the wire in `queued_ns` and `wire_ns`, once `done`.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -2: invalid parameter
+/// -3: bagua-net inner error, e.g. a request tested done already
+#[no_mangle]
+pub extern "C" fn bagua_net_c_request_timing(
+    ptr: *mut BaguaNetC,
+    request_id: usize,
+    done: *mut bool,
+    queued_ns: *mut u64,
+    wire_ns: *mut u64,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+    if done.is_null() || queued_ns.is_null() || wire_ns.is_null() {
+        return -2;
+    }
+
+    unsafe {
+        match (*ptr).inner.lock().unwrap().request_timing(request_id) {
+            Ok(timing) => {
+                *done = timing.is_some();
+                if let Some(timing) = timing {
+                    *queued_ns = timing.queued.as_nanos() as u64;
+                    *wire_ns = timing.wire.as_nanos() as u64;
+                }
+            }
+            Err(err) => {
+                return failed(err);
+            }
+        }
+    }
+    0
+}
+
+/// Retracts the request, its buffer is not touched once the transfers under
+/// way are done.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -3: bagua-net inner error, e.g. a request done and tested already
+#[no_mangle]
+pub extern "C" fn bagua_net_c_cancel(ptr: *mut BaguaNetC, request_id: usize) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+
+    unsafe {
+        if let Err(err) = (*ptr).inner.lock().unwrap().cancel(request_id) {
+            return failed(err);
+        }
+    }
+    0
+}
+
+/// Tests the `n` requests of `request_ids` at once, into the same index of
+/// `done`, `bytes` and `errs`. The error code of each request is in `errs`,
+/// 0 or -3 like with `bagua_net_c_test`.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -2: invalid parameter
+#[no_mangle]
+pub extern "C" fn bagua_net_c_test_many(
+    ptr: *mut BaguaNetC,
+    request_ids: *const usize,
+    n: usize,
+    done: *mut bool,
+    bytes: *mut usize,
+    errs: *mut i32,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+    if n == 0 {
+        return 0;
+    }
+    if request_ids.is_null() || done.is_null() || bytes.is_null() || errs.is_null() {
+        return -2;
+    }
+
+    unsafe {
+        let request_ids = std::slice::from_raw_parts(request_ids, n);
+        let mut out = vec![Ok((false, 0)); n];
+        if let Err(err) = (*ptr)
+            .inner
+            .lock()
+            .unwrap()
+            .test_many(request_ids, &mut out)
+        {
+            tracing::warn!("{:?}", err);
+            return -2;
+        }
+        let done = std::slice::from_raw_parts_mut(done, n);
+        let bytes = std::slice::from_raw_parts_mut(bytes, n);
+        let errs = std::slice::from_raw_parts_mut(errs, n);
+        for (i, ret) in out.into_iter().enumerate() {
+            match ret {
+                Ok((let_done, let_bytes)) => {
+                    done[i] = let_done;
+                    bytes[i] = let_bytes;
+                    errs[i] = 0;
+                }
+                Err(err) => {
+                    done[i] = false;
+                    errs[i] = failed(err);
+                }
+            }
+        }
+    }
+    0
+}
+
+/// Blocks until the request is done, for up to `timeout_ms` milliseconds
+/// unless it is negative. Other calls on the net wait meanwhile.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -3: bagua-net inner error
+/// -5: timed out, the request is still pending
+#[no_mangle]
+pub extern "C" fn bagua_net_c_wait(
+    ptr: *mut BaguaNetC,
+    request_id: usize,
+    timeout_ms: i64,
+    bytes: *mut usize,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+
+    let timeout = (timeout_ms >= 0).then(|| std::time::Duration::from_millis(timeout_ms as u64));
+    unsafe {
+        match (*ptr).inner.lock().unwrap().wait(request_id, timeout) {
+            Ok(nbytes) => {
+                if !bytes.is_null() {
+                    *bytes = nbytes;
+                }
+            }
+            Err(BaguaNetError::Timeout) => return -5,
+            Err(err) => {
+                return failed(err);
+            }
+        }
+    }
+    0
+}
+
+/// Of `bagua_net_c_set_completion_hook`, called with its `user_data`, 0 or -3
+/// like `bagua_net_c_test`, and the bytes the request carried.
+pub type CompletionCallback = extern "C" fn(user_data: *mut c_void, status: i32, bytes: usize);
+
+/// Hands the request over to `callback`, which `bagua_net_c_test` no longer
+/// knows then. Called once, on a thread of the net once the request is done,
+/// or on this one after the net is unlocked if it is done already.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -2: unknown request, e.g. tested to completion already
+/// -3: bagua-net inner error, e.g. a flush
+#[no_mangle]
+pub extern "C" fn bagua_net_c_set_completion_hook(
+    ptr: *mut BaguaNetC,
+    request_id: usize,
+    callback: Option<CompletionCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    let callback = match callback {
+        Some(callback) if !ptr.is_null() => callback,
+        _ => return -1,
+    };
+
+    let user_data = user_data as usize;
+    let call = move |ret: Result<usize, BaguaNetError>| match ret {
+        Ok(nbytes) => callback(user_data as *mut c_void, 0, nbytes),
+        Err(err) => {
+            tracing::warn!("{:?}", err);
+            callback(user_data as *mut c_void, -3, 0)
+        }
+    };
+    // While the net is locked, what the hook is called with waits here.
+    let deferred = Arc::new(Mutex::new(Some(None)));
+    let hook_deferred = deferred.clone();
+    let hook = Box::new(move |ret| {
+        let mut deferred = hook_deferred.lock().unwrap();
+        match &mut *deferred {
+            Some(pending) => *pending = Some(ret),
+            None => {
+                drop(deferred);
+                call(ret)
+            }
+        }
+    });
+    let ret = unsafe {
+        (*ptr)
+            .inner
+            .lock()
+            .unwrap()
+            .set_completion_hook(request_id, hook)
+    };
+    if let Some(Some(ret)) = deferred.lock().unwrap().take() {
+        call(ret);
+    }
+    match ret {
+        Ok(()) => 0,
+        Err(BaguaNetError::InvalidId { .. }) => -2,
+        Err(err) => failed(err),
+    }
+}
+
+/// Before the first message on the send comm.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -3: bagua-net inner error
+#[no_mangle]
+pub extern "C" fn bagua_net_c_set_fixed_message_size(
+    ptr: *mut BaguaNetC,
+    send_comm_id: usize,
+    nbytes: usize,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+
+    unsafe {
+        let mut inner = (*ptr).inner.lock().unwrap();
+        if let Err(err) = inner.set_fixed_message_size(send_comm_id, nbytes) {
+            return failed(err);
+        }
+    }
+    0
+}
+
+/// Before the first message on the recv comm.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -3: bagua-net inner error
+#[no_mangle]
+pub extern "C" fn bagua_net_c_set_recv_fixed_message_size(
+    ptr: *mut BaguaNetC,
+    recv_comm_id: usize,
+    nbytes: usize,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+
+    unsafe {
+        let mut inner = (*ptr).inner.lock().unwrap();
+        if let Err(err) = inner.set_recv_fixed_message_size(recv_comm_id, nbytes) {
+            return failed(err);
+        }
+    }
+    0
+}
+
+/// Caps the bandwidth of the send comm at `mbps` megabits per second, 0
+/// lifts the cap.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -3: bagua-net inner error
+#[no_mangle]
+pub extern "C" fn bagua_net_c_set_max_bandwidth(
+    ptr: *mut BaguaNetC,
+    send_comm_id: usize,
+    mbps: u64,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+
+    unsafe {
+        let mut inner = (*ptr).inner.lock().unwrap();
+        if let Err(err) = inner.set_max_bandwidth(send_comm_id, Some(mbps).filter(|&mbps| mbps > 0))
+        {
+            return failed(err);
+        }
+    }
+    0
+}
+
+/// Of `bagua_net_c_get_statistics`, see `NetStatistics`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct NetStatisticsC {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub requests_completed: u64,
+    pub requests_failed: u64,
+    pub active_comms: usize,
+    pub active_requests: usize,
+}
+
+impl NetStatisticsC {
+    fn of(stats: &NetStatistics) -> NetStatisticsC {
+        NetStatisticsC {
+            bytes_sent: stats.bytes_sent,
+            bytes_received: stats.bytes_received,
+            requests_completed: stats.requests_completed,
+            requests_failed: stats.requests_failed,
+            active_comms: stats.active_comms,
+            active_requests: stats.active_requests,
+        }
+    }
+}
+
+/// Of a comm, see `CommStatistics`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct CommStatisticsC {
+    /// 0 for a send comm, 1 for a recv comm.
+    pub kind: i32,
+    pub id: usize,
+    pub nbytes: u64,
+    pub requests_completed: u64,
+    pub requests_failed: u64,
+    pub active_requests: usize,
+}
+
+impl CommStatisticsC {
+    fn of(stats: &CommStatistics) -> CommStatisticsC {
+        CommStatisticsC {
+            kind: match stats.kind {
+                CommKind::Send => 0,
+                CommKind::Recv => 1,
+            },
+            id: stats.id,
+            nbytes: stats.nbytes,
+            requests_completed: stats.requests_completed,
+            requests_failed: stats.requests_failed,
+            active_requests: stats.active_requests,
+        }
+    }
+}
+
+/// The totals into `stats`, the first `ncomms` comms of its `active_comms`
+/// into `comms`, which can be null if `ncomms` is 0.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -2: invalid parameter
+/// -3: bagua-net inner error, e.g. the tokio backend
+#[no_mangle]
+pub extern "C" fn bagua_net_c_get_statistics(
+    ptr: *mut BaguaNetC,
+    stats: *mut NetStatisticsC,
+    comms: *mut CommStatisticsC,
+    ncomms: usize,
+) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+    if stats.is_null() || (comms.is_null() && ncomms > 0) {
+        return -2;
+    }
+
+    unsafe {
+        let net_stats = match (*ptr).inner.lock().unwrap().get_statistics() {
+            Ok(net_stats) => net_stats,
+            Err(err) => return failed(err),
+        };
+        *stats = NetStatisticsC::of(&net_stats);
+        for (i, comm) in net_stats.comms.iter().take(ncomms).enumerate() {
+            comms.add(i).write(CommStatisticsC::of(comm));
+        }
+    }
+    0
+}
+
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -3: bagua-net inner error
+#[no_mangle]
+pub extern "C" fn bagua_net_c_reset_statistics(ptr: *mut BaguaNetC) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+
+    match unsafe { (*ptr).inner.lock().unwrap().reset_statistics() } {
+        Ok(()) => 0,
+        Err(err) => failed(err),
+    }
+}
+
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -2: unknown send comm, e.g. closed already
+/// -3: close failed
+#[no_mangle]
+pub extern "C" fn bagua_net_c_close_send(ptr: *mut BaguaNetC, send_comm_id: usize) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+
+    unsafe { close_ret((*ptr).inner.lock().unwrap().close_send(send_comm_id)) }
+}
+
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -2: unknown recv comm, e.g. closed already
+/// -3: close failed
+#[no_mangle]
+pub extern "C" fn bagua_net_c_close_recv(ptr: *mut BaguaNetC, recv_comm_id: usize) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+
+    unsafe { close_ret((*ptr).inner.lock().unwrap().close_recv(recv_comm_id)) }
+}
+
+/// Wakes an accept waiting on the listen comm on another thread first.
+///
+/// Error code
+/// 0: success
+/// -1: null pointer
+/// -2: unknown listen comm, e.g. closed already
+/// -3: close failed
+#[no_mangle]
+pub extern "C" fn bagua_net_c_close_listen(ptr: *mut BaguaNetC, listen_comm_id: usize) -> i32 {
+    // First, we **must** check to see if the pointer is null.
+    if ptr.is_null() {
+        // Do nothing.
+        return -1;
+    }
+
+    unsafe {
+        let closer = (*ptr)
+            .listen_closers
+            .lock()
+            .unwrap()
+            .remove(&listen_comm_id);
+        if let Some(closer) = closer {
+            closer.close();
+        }
+        close_ret((*ptr).inner.lock().unwrap().close_listen(listen_comm_id))
+    }
+}
+
+/// Of the close functions.
+fn close_ret(ret: Result<(), BaguaNetError>) -> i32 {
+    match ret {
+        Ok(()) => 0,
+        Err(BaguaNetError::InvalidId { .. }) => -2,
+        Err(err) => failed(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socket_handle_c_v6() {
+        let addr: std::net::SocketAddr = "[fe80::1%2]:8123".parse().unwrap();
+        let handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&addr)),
+            alt_addr: None,
+            uds_addr: None,
+            hostname: None,
+        };
+        let c_handle = SocketHandleC::from_handle(&handle);
+        assert_eq!(std::mem::size_of::<SocketHandleC>(), 64);
+
+        let handle = c_handle.to_handle().unwrap();
+        match handle.addr {
+            SockAddr::Inet(inet) => assert_eq!(inet.to_std(), addr),
+            _ => panic!("unexpected address {:?}", handle.addr),
+        }
+    }
+
+    #[test]
+    fn test_socket_handle_c_uds() {
+        let addr: std::net::SocketAddr = "127.0.0.1:8123".parse().unwrap();
+        let handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&addr)),
+            alt_addr: None,
+            uds_addr: Some(SockAddr::Unix(
+                UnixAddr::new_abstract(b"bagua-net-0123456789abcdef").unwrap(),
+            )),
+            hostname: None,
+        };
+        let handle = SocketHandleC::from_handle(&handle).to_handle().unwrap();
+        match handle.uds_addr {
+            Some(SockAddr::Unix(unix_addr)) => {
+                assert_eq!(
+                    unix_addr.as_abstract(),
+                    Some(&b"bagua-net-0123456789abcdef"[..])
+                )
+            }
+            _ => panic!("unexpected unix address {:?}", handle.uds_addr),
+        }
+
+        let handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&addr)),
+            alt_addr: None,
+            uds_addr: None,
+            hostname: None,
+        };
+        let handle = SocketHandleC::from_handle(&handle).to_handle().unwrap();
+        assert!(handle.uds_addr.is_none());
+    }
+
+    #[test]
+    fn test_socket_handle_c_dual_stack() {
+        for (addr, alt_addr) in [
+            ("192.0.2.2:8123", "[fd00::2%3]:8124"),
+            ("[fd00::2]:8123", "192.0.2.2:8123"),
+        ]
+        .iter()
+        {
+            let addr: std::net::SocketAddr = addr.parse().unwrap();
+            let alt_addr: std::net::SocketAddr = alt_addr.parse().unwrap();
+            let handle = SocketHandle {
+                addr: SockAddr::new_inet(InetAddr::from_std(&addr)),
+                alt_addr: Some(SockAddr::new_inet(InetAddr::from_std(&alt_addr))),
+                uds_addr: None,
+                hostname: None,
+            };
+            let handle = SocketHandleC::from_handle(&handle).to_handle().unwrap();
+            match handle.alt_addr {
+                Some(SockAddr::Inet(inet)) => assert_eq!(inet.to_std(), alt_addr),
+                _ => panic!("unexpected alt address {:?}", handle.alt_addr),
+            }
+        }
+    }
+
+    #[test]
+    fn test_socket_handle_c_unknown_version() {
+        let addr: std::net::SocketAddr = "127.0.0.1:8123".parse().unwrap();
+        let handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&addr)),
+            alt_addr: None,
+            uds_addr: None,
+            hostname: None,
+        };
+        let mut c_handle = SocketHandleC::from_handle(&handle);
+        for version in [0, b'b', 3, 5, u8::MAX] {
+            c_handle.version = version;
+            assert!(c_handle.to_handle().is_none(), "version {}", version);
+        }
+    }
+
+    #[test]
+    fn test_socket_handle_c_hostname() {
+        let addr: std::net::SocketAddr = "192.0.2.2:8123".parse().unwrap();
+        let alt_addr: std::net::SocketAddr = "[fd00::2]:8124".parse().unwrap();
+        let mut handle = SocketHandle {
+            addr: SockAddr::new_inet(InetAddr::from_std(&addr)),
+            alt_addr: Some(SockAddr::new_inet(InetAddr::from_std(&alt_addr))),
+            uds_addr: Some(SockAddr::Unix(
+                UnixAddr::new_abstract(b"bagua-net-0123456789abcdef").unwrap(),
+            )),
+            hostname: None,
+        };
+        for hostname in [
+            "worker-0",
+            "worker-0.trainer.default.svc.cluster.local",
+            "worker-0.trainer.default.svc.cluster.local.",
+            &"w".repeat(SOCKET_HANDLE_HOSTNAME_MAX),
+        ]
+        .iter()
+        {
+            handle.hostname = Some(hostname.to_string());
+            let c_handle = SocketHandleC::from_handle(&handle);
+            assert_eq!(c_handle.version, SOCKET_HANDLE_HOSTNAME_VERSION);
+            let decoded = c_handle.to_handle().unwrap();
+            assert_eq!(decoded.hostname.as_deref(), Some(*hostname));
+            assert_eq!(decoded.addr.to_str(), "0.0.0.0:8123");
+            assert_eq!(decoded.alt_addr.unwrap().to_str(), "[::]:8124");
+            match decoded.uds_addr {
+                Some(SockAddr::Unix(unix_addr)) => assert_eq!(
+                    unix_addr.as_abstract(),
+                    Some(&b"bagua-net-0123456789abcdef"[..])
+                ),
+                _ => panic!("unexpected unix address {:?}", decoded.uds_addr),
+            }
+        }
+
+        // Too long for the handle, the addresses are advertised instead.
+        handle.hostname = Some("w".repeat(SOCKET_HANDLE_HOSTNAME_MAX + 1));
+        let c_handle = SocketHandleC::from_handle(&handle);
+        assert_eq!(c_handle.version, SOCKET_HANDLE_VERSION);
+        let decoded = c_handle.to_handle().unwrap();
+        assert!(decoded.hostname.is_none());
+        assert_eq!(decoded.addr.to_str(), "192.0.2.2:8123");
+        assert_eq!(decoded.alt_addr.unwrap().to_str(), "[fd00::2]:8124");
+    }
+
+    #[test]
+    fn test_c_get_properties_bad_dev_id() {
+        let mut ptr = bagua_net_c_create();
+        assert!(!ptr.is_null());
+        let mut ndev = 0;
+        assert_eq!(bagua_net_c_devices(ptr, &mut ndev), 0);
+        let mut props = std::mem::MaybeUninit::<NCCLNetPropertiesC>::uninit();
+        // Failed with the mapped code rather than a panic.
+        assert_eq!(
+            bagua_net_c_get_properties(ptr, ndev, props.as_mut_ptr()),
+            -3
+        );
+        assert_eq!(bagua_net_c_last_nccl_result(), 4);
+        assert_eq!(bagua_net_c_get_properties(ptr, -1, props.as_mut_ptr()), -2);
+        bagua_net_c_destroy(&mut ptr);
     }
-    return 0;
 }
@@ -0,0 +1,274 @@
+//! Memory regions NCCL registers with `regMr` before posting transfers on
+//! them. Host memory is locked in RAM, so that sends do not fault on pages
+//! swapped out since the last one.
+
+use crate::interface::{BaguaNetError, MrHandle};
+use std::collections::HashMap;
+
+/// `NCCL_PTR_HOST` and `NCCL_PTR_CUDA`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtrType {
+    Host,
+    Cuda,
+}
+
+impl PtrType {
+    pub fn from_nccl(ptr_type: i32) -> Option<PtrType> {
+        match ptr_type {
+            1 => Some(PtrType::Host),
+            2 => Some(PtrType::Cuda),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Region {
+    addr: usize,
+    len: usize,
+    ptr_type: PtrType,
+    /// Unless locking failed, e.g. over `RLIMIT_MEMLOCK`.
+    pinned: bool,
+}
+
+impl Region {
+    fn contains(&self, data: *const [u8]) -> bool {
+        let addr = data as *const u8 as usize;
+        addr >= self.addr && addr + data.len() <= self.addr + self.len
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// The range of the pages `len` bytes at `addr` are on.
+fn pages(addr: usize, len: usize) -> (usize, usize) {
+    let page = page_size();
+    (addr / page * page, (addr + len).div_ceil(page) * page)
+}
+
+/// Regions may overlap, the pages they share stay locked until the last
+/// of them is deregistered.
+#[derive(Debug, Default)]
+pub struct MrRegistry {
+    next_id: MrHandle,
+    regions: HashMap<MrHandle, Region>,
+    pinned_bytes: usize,
+}
+
+impl MrRegistry {
+    pub fn register(
+        &mut self,
+        data: &'static [u8],
+        ptr_type: PtrType,
+    ) -> Result<MrHandle, BaguaNetError> {
+        let mut region = Region {
+            addr: data.as_ptr() as usize,
+            len: data.len(),
+            ptr_type,
+            pinned: false,
+        };
+        if ptr_type == PtrType::Host && !data.is_empty() {
+            let ret = unsafe { libc::mlock(data.as_ptr() as *const libc::c_void, data.len()) };
+            if ret == 0 {
+                region.pinned = true;
+                self.pinned_bytes += region.len;
+            } else {
+                tracing::warn!(
+                    "failed to lock {} bytes of registered memory, err={:?}",
+                    data.len(),
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+
+        let handle = self.next_id;
+        self.next_id += 1;
+        self.regions.insert(handle, region);
+        Ok(handle)
+    }
+
+    pub fn deregister(&mut self, handle: MrHandle) -> Result<(), BaguaNetError> {
+        let region = self.regions.remove(&handle).ok_or_else(|| {
+            BaguaNetError::InvalidArgument(format!("memory region {} is not registered", handle))
+        })?;
+        if !region.pinned {
+            return Ok(());
+        }
+        self.pinned_bytes -= region.len;
+
+        // Only the pages no other pinned region is on.
+        let (start, end) = pages(region.addr, region.len);
+        let mut others: Vec<(usize, usize)> = self
+            .regions
+            .values()
+            .filter(|other| other.pinned)
+            .map(|other| pages(other.addr, other.len))
+            .filter(|&(other_start, other_end)| other_start < end && other_end > start)
+            .collect();
+        others.sort_unstable();
+        let mut pos = start;
+        for (other_start, other_end) in others.into_iter().chain(std::iter::once((end, end))) {
+            if other_start > pos {
+                unsafe { libc::munlock(pos as *const libc::c_void, other_start - pos) };
+            }
+            pos = pos.max(other_end);
+        }
+        Ok(())
+    }
+
+    /// Whether `data` is mapped, with `BAGUA_NET_CHECK_BUFFERS=1`. Buffers
+    /// within the registered region of `handle` are trusted, the others cost
+    /// a `mincore`. Of the buffer at `data`, which is not accessed.
+    pub fn check(&self, handle: Option<MrHandle>, data: *const [u8]) -> Result<(), BaguaNetError> {
+        match handle.and_then(|handle| self.regions.get(&handle)) {
+            Some(region) if region.contains(data) => Ok(()),
+            Some(region) => {
+                tracing::debug!(
+                    "buffer of {} bytes at {:?} is not in its {:?} region",
+                    data.len(),
+                    data as *const u8,
+                    region.ptr_type
+                );
+                check_mapped(data)
+            }
+            None => check_mapped(data),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// Overlapping regions count twice.
+    pub fn pinned_bytes(&self) -> usize {
+        self.pinned_bytes
+    }
+}
+
+fn check_mapped(data: *const [u8]) -> Result<(), BaguaNetError> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let (start, end) = pages(data as *const u8 as usize, data.len());
+    let mut residency = vec![0u8; (end - start) / page_size()];
+    let ret = unsafe {
+        libc::mincore(
+            start as *mut libc::c_void,
+            end - start,
+            residency.as_mut_ptr() as *mut libc::c_uchar,
+        )
+    };
+    if ret < 0 {
+        return Err(BaguaNetError::InvalidArgument(format!(
+            "buffer of {} bytes at {:?} is not mapped, err={:?}",
+            data.len(),
+            data as *const u8,
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Of the mappings within `data`, from `/proc/self/smaps`.
+    fn locked_bytes(data: &[u8]) -> usize {
+        let (start, end) = (data.as_ptr() as usize, data.as_ptr() as usize + data.len());
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut within = false;
+        let mut locked = 0;
+        for line in smaps.lines() {
+            let mut fields = line.split_whitespace();
+            let first = fields.next().unwrap_or("");
+            if let Some((lo, hi)) = first.split_once('-') {
+                if let (Ok(lo), Ok(hi)) =
+                    (usize::from_str_radix(lo, 16), usize::from_str_radix(hi, 16))
+                {
+                    within = lo >= start && hi <= end;
+                    continue;
+                }
+            }
+            if within && first == "Locked:" {
+                locked += fields.next().unwrap().parse::<usize>().unwrap() * 1024;
+            }
+        }
+        locked
+    }
+
+    /// A mapping of its own, so that smaps shows what is locked of it.
+    fn mapping(npages: usize) -> &'static mut [u8] {
+        let len = npages * page_size();
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        unsafe { std::slice::from_raw_parts_mut(addr as *mut u8, len) }
+    }
+
+    #[test]
+    fn test_overlapping_regions() {
+        let page = page_size();
+        let data = mapping(4);
+        let mut registry = MrRegistry::default();
+        let a = registry.register(&data[..2 * page], PtrType::Host).unwrap();
+        if registry.pinned_bytes() == 0 {
+            println!("cannot lock memory, skipping");
+            return;
+        }
+        // Shares the second page with `a`.
+        let b = registry
+            .register(&data[page + 8..3 * page], PtrType::Host)
+            .unwrap();
+        assert_eq!(registry.count(), 2);
+        assert_eq!(registry.pinned_bytes(), 4 * page - 8);
+        assert_eq!(locked_bytes(data), 3 * page);
+
+        assert!(registry.check(Some(b), &data[2 * page..3 * page]).is_ok());
+        assert!(registry.check(Some(b), &data[..page]).is_ok());
+        assert!(registry.check(None, &data[..]).is_ok());
+
+        registry.deregister(a).unwrap();
+        assert_eq!(locked_bytes(data), 2 * page);
+        registry.deregister(b).unwrap();
+        assert_eq!(locked_bytes(data), 0);
+        assert_eq!(registry.count(), 0);
+        assert_eq!(registry.pinned_bytes(), 0);
+    }
+
+    #[test]
+    fn test_check_unmapped() {
+        let page = page_size();
+        let data = mapping(2);
+        let registry = MrRegistry::default();
+        assert!(registry.check(None, data).is_ok());
+        unsafe { libc::munmap(data[page..].as_mut_ptr() as *mut libc::c_void, page) };
+        assert!(registry.check(None, &data[..page]).is_ok());
+        assert!(registry.check(None, &data[page - 8..]).is_err());
+    }
+
+    #[test]
+    fn test_double_deregister() {
+        let data = mapping(1);
+        let mut registry = MrRegistry::default();
+        let handle = registry.register(data, PtrType::Host).unwrap();
+        let other = registry.register(&data[..64], PtrType::Cuda).unwrap();
+        assert_ne!(handle, other);
+
+        registry.deregister(handle).unwrap();
+        assert!(registry.deregister(handle).is_err());
+        assert_eq!(registry.count(), 1);
+        registry.deregister(other).unwrap();
+        assert_eq!(registry.count(), 0);
+    }
+}
@@ -0,0 +1,151 @@
+//! Caps the bandwidth of a send comm, summed over its data streams, at
+//! `BAGUA_NET_MAX_BW_MBPS` or at what `Net::set_max_bandwidth` set for the
+//! comm. Writes to the data streams wait for the tokens of a bucket that
+//! fills at the cap. Messages inlined on the master stream are not counted.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// In megabits per second, `None` or 0 for uncapped. Shared by a send comm
+/// and its driver.
+pub type MaxBandwidth = Arc<Mutex<Option<u64>>>;
+
+/// Of the tokens a bucket holds at most, in time at the cap.
+const BURST: Duration = Duration::from_millis(10);
+/// Of the tokens a bucket holds at most, however low the cap.
+const MIN_BURST: usize = 16 * 1024;
+
+/// `BAGUA_NET_MAX_BW_MBPS`.
+pub fn max_bandwidth_from_env() -> Option<u64> {
+    std::env::var("BAGUA_NET_MAX_BW_MBPS")
+        .ok()
+        .map(|mbps| mbps.parse().unwrap())
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    max_bandwidth: MaxBandwidth,
+    /// Of `max_bandwidth` when last read, in bytes per second.
+    rate: Option<f64>,
+    burst: f64,
+    tokens: f64,
+    filled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(max_bandwidth: MaxBandwidth) -> TokenBucket {
+        let mut bucket = TokenBucket {
+            max_bandwidth,
+            rate: None,
+            burst: 0.,
+            tokens: 0.,
+            filled_at: Instant::now(),
+        };
+        bucket.update();
+        bucket
+    }
+
+    /// Picks up a new cap, with a full bucket.
+    pub fn update(&mut self) {
+        let rate = self
+            .max_bandwidth
+            .lock()
+            .unwrap()
+            .filter(|&mbps| mbps > 0)
+            .map(|mbps| mbps as f64 * 1e6 / 8.);
+        if rate == self.rate {
+            return;
+        }
+        self.rate = rate;
+        self.burst = rate.map_or(0., |rate| {
+            (rate * BURST.as_secs_f64()).max(MIN_BURST as f64)
+        });
+        self.tokens = self.burst;
+        self.filled_at = Instant::now();
+    }
+
+    pub fn is_capped(&self) -> bool {
+        self.rate.is_some()
+    }
+
+    /// How many of the next `len` bytes may be written at `now`, or when to
+    /// ask again.
+    pub fn allowance(&mut self, len: usize, now: Instant) -> Result<usize, Instant> {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return Ok(len),
+        };
+        let elapsed = now.saturating_duration_since(self.filled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(self.burst);
+        self.filled_at = now;
+        // Waits for a burst rather than trickling out small writes.
+        let wanted = (len as f64).min(self.burst);
+        if self.tokens >= wanted {
+            return Ok((self.tokens as usize).min(len));
+        }
+        Err(now + Duration::from_secs_f64((wanted - self.tokens) / rate))
+    }
+
+    /// Of `n` bytes written, or submitted to be.
+    pub fn consume(&mut self, n: usize) {
+        if self.rate.is_some() {
+            self.tokens -= n as f64;
+        }
+    }
+
+    /// Of `n` bytes submitted but not written.
+    pub fn refund(&mut self, n: usize) {
+        if self.rate.is_some() {
+            self.tokens = (self.tokens + n as f64).min(self.burst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn near(instant: Instant, expected: Instant) -> bool {
+        let diff = match instant > expected {
+            true => instant - expected,
+            false => expected - instant,
+        };
+        diff < Duration::from_micros(1)
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let max_bandwidth: MaxBandwidth = Arc::new(Mutex::new(Some(100)));
+        let mut bucket = TokenBucket::new(max_bandwidth.clone());
+        let start = bucket.filled_at;
+        let ms = Duration::from_millis(1);
+        // 100 Mbps, 125000 bytes in 10ms.
+        assert_eq!(bucket.allowance(1 << 20, start), Ok(125000));
+        bucket.consume(125000);
+        let next = bucket.allowance(1 << 20, start).unwrap_err();
+        assert!(near(next, start + 10 * ms));
+        // Half full, still waiting.
+        let next = bucket.allowance(1 << 20, start + 5 * ms).unwrap_err();
+        assert!(near(next, start + 10 * ms));
+        let allowed = bucket.allowance(1 << 20, start + 11 * ms).unwrap();
+        assert_eq!(allowed, 125000);
+        bucket.consume(allowed);
+
+        // A small write only waits for its own bytes.
+        let next = bucket.allowance(1000, start + 11 * ms).unwrap_err();
+        assert!(near(next, start + 11 * ms + Duration::from_micros(80)));
+        assert_eq!(bucket.allowance(1000, start + 12 * ms), Ok(1000));
+
+        bucket.refund(1 << 20);
+        assert_eq!(bucket.allowance(1 << 20, start + 12 * ms), Ok(125000));
+
+        // Lifted.
+        *max_bandwidth.lock().unwrap() = Some(0);
+        bucket.update();
+        bucket.consume(1 << 30);
+        assert_eq!(bucket.allowance(1 << 30, start), Ok(1 << 30));
+        *max_bandwidth.lock().unwrap() = None;
+        bucket.update();
+        assert_eq!(bucket.allowance(1 << 30, start), Ok(1 << 30));
+    }
+}
@@ -0,0 +1,256 @@
+//! The comm and request tables of the backends. An ID is the index of its
+//! slot in the low bits and the generation of the slot in the high bits, so
+//! that lookups are array accesses, slots are reused once freed, and an ID
+//! that outlived its entry finds nothing instead of the entry that took its
+//! slot. A slot whose generations ran out is retired rather than wrapped,
+//! so no ID is ever handed out twice; once every index is live or retired,
+//! inserting fails.
+
+use crate::interface::BaguaNetError;
+use std::collections::VecDeque;
+
+struct Slot<T> {
+    generation: usize,
+    value: Option<T>,
+}
+
+pub struct Slab<T> {
+    slots: Vec<Slot<T>>,
+    /// Indices of the empty slots, the last freed on top.
+    free: Vec<usize>,
+    len: usize,
+    index_bits: u32,
+    generation_bits: u32,
+}
+
+impl<T> Default for Slab<T> {
+    /// Half of a `usize` for the index, half for the generation.
+    fn default() -> Slab<T> {
+        Slab::with_id_bits(usize::BITS / 2, usize::BITS / 2)
+    }
+}
+
+impl<T> Slab<T> {
+    pub fn with_id_bits(index_bits: u32, generation_bits: u32) -> Slab<T> {
+        assert!(index_bits > 0 && generation_bits > 0);
+        assert!(index_bits + generation_bits <= usize::BITS);
+        Slab {
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+            index_bits,
+            generation_bits,
+        }
+    }
+
+    fn id(&self, index: usize, generation: usize) -> usize {
+        generation << self.index_bits | index
+    }
+
+    fn index(&self, id: usize) -> usize {
+        id & ((1 << self.index_bits) - 1)
+    }
+
+    fn slot(&self, id: usize) -> Option<&Slot<T>> {
+        let index = self.index(id);
+        self.slots
+            .get(index)
+            .filter(|slot| self.id(index, slot.generation) == id)
+    }
+
+    /// The ID the next `insert` returns.
+    pub fn next_id(&self) -> Result<usize, BaguaNetError> {
+        match self.free.last() {
+            Some(&index) => Ok(self.id(index, self.slots[index].generation)),
+            None if self.slots.len() < 1 << self.index_bits => Ok(self.id(self.slots.len(), 0)),
+            None => Err(BaguaNetError::InnerError(format!(
+                "out of IDs, {} of {} live",
+                self.len,
+                self.slots.len()
+            ))),
+        }
+    }
+
+    /// The ID of `value`.
+    pub fn insert(&mut self, value: T) -> Result<usize, BaguaNetError> {
+        let id = self.next_id()?;
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: None,
+                });
+                self.slots.len() - 1
+            }
+        };
+        self.slots[index].value = Some(value);
+        self.len += 1;
+        Ok(id)
+    }
+
+    pub fn get(&self, id: usize) -> Option<&T> {
+        self.slot(id).and_then(|slot| slot.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut T> {
+        self.slot(id)?;
+        let index = self.index(id);
+        self.slots[index].value.as_mut()
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<T> {
+        self.slot(id)?;
+        let index = self.index(id);
+        let last_generation = (1 << self.generation_bits) - 1;
+        let slot = &mut self.slots[index];
+        let value = slot.value.take()?;
+        self.len -= 1;
+        if slot.generation < last_generation {
+            slot.generation += 1;
+            self.free.push(index);
+        }
+        Some(value)
+    }
+
+    /// Of the live entries.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The live entries with their IDs, by slot.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(move |(index, slot)| {
+                let value = slot.value.as_ref()?;
+                Some((self.id(index, slot.generation), value))
+            })
+    }
+}
+
+/// Of the requests `Net::test` found done and removed from their table.
+const RECENTLY_DONE: usize = 64;
+
+/// The last `RECENTLY_DONE` requests removed once done, with their sizes,
+/// each answered done once more by `Net::test`.
+#[derive(Default)]
+pub struct RecentlyDone {
+    requests: VecDeque<(usize, usize)>,
+}
+
+impl RecentlyDone {
+    pub fn push(&mut self, id: usize, nbytes: usize) {
+        if self.requests.len() == RECENTLY_DONE {
+            self.requests.pop_front();
+        }
+        self.requests.push_back((id, nbytes));
+    }
+
+    /// The size of the request, if it is one of them, which it is not
+    /// anymore.
+    pub fn take(&mut self, id: usize) -> Option<usize> {
+        let index = self.requests.iter().position(|&(done, _)| done == id)?;
+        self.requests.remove(index).map(|(_, nbytes)| nbytes)
+    }
+}
+
+/// For an ID of a `kind` that is not in its table, e.g. it was closed.
+pub fn unknown(kind: &'static str, id: usize) -> BaguaNetError {
+    BaguaNetError::InvalidId { kind, id }
+}
+
+impl<T> std::ops::Index<usize> for Slab<T> {
+    type Output = T;
+
+    fn index(&self, id: usize) -> &T {
+        self.get(id).expect("no entry for the ID")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slab() {
+        let mut slab = Slab::default();
+        let a = slab.insert("a").unwrap();
+        let b = slab.insert("b").unwrap();
+        assert_eq!((slab[a], slab[b], slab.len()), ("a", "b", 2));
+
+        assert_eq!(slab.remove(a), Some("a"));
+        assert_eq!(slab.remove(a), None);
+        assert!(slab.get(a).is_none());
+
+        // Reuses the slot of a, which a no longer finds.
+        let c = slab.next_id().unwrap();
+        assert_eq!(slab.insert("c").unwrap(), c);
+        assert_eq!(slab.index(c), slab.index(a));
+        assert_ne!(c, a);
+        assert!(slab.get(a).is_none());
+        assert!(slab.get_mut(a).is_none());
+        assert!(slab.remove(a).is_none());
+        assert_eq!(slab[c], "c");
+        *slab.get_mut(c).unwrap() = "d";
+        assert_eq!(slab.get(c), Some(&"d"));
+        assert_eq!(slab.len(), 2);
+        let entries: Vec<_> = slab.iter().collect();
+        assert_eq!(entries, vec![(c, &"d"), (b, &"b")]);
+
+        assert!(slab.get(5).is_none());
+        slab.remove(b);
+        slab.remove(c);
+        assert!(matches!(
+            unknown("request", c),
+            BaguaNetError::InvalidId { kind: "request", id } if id == c
+        ));
+        assert_eq!(slab.len(), 0);
+    }
+
+    #[test]
+    fn test_recently_done() {
+        let mut done = RecentlyDone::default();
+        for id in 0..RECENTLY_DONE + 1 {
+            done.push(id, id * 10);
+        }
+        // Pushed out.
+        assert_eq!(done.take(0), None);
+        assert_eq!(done.take(1), Some(10));
+        assert_eq!(done.take(1), None);
+        assert_eq!(done.take(RECENTLY_DONE), Some(RECENTLY_DONE * 10));
+    }
+
+    #[test]
+    fn test_id_wraparound() {
+        // 4 slots of 4 generations.
+        let mut slab = Slab::with_id_bits(2, 2);
+        let live = slab.insert(usize::MAX).unwrap();
+        let mut seen = vec![live];
+        // Cycles through the other 3 slots until their generations run out.
+        for i in 0..3 * 4 {
+            let id = slab.insert(i).unwrap();
+            assert!(!seen.contains(&id));
+            seen.push(id);
+            assert_eq!(slab.remove(id), Some(i));
+            assert_eq!(slab.len(), 1);
+        }
+        assert_eq!(seen.len(), 1 + 3 * 4);
+
+        // The live entry is never taken over.
+        assert!(slab.next_id().is_err());
+        assert!(slab.insert(0).is_err());
+        assert_eq!(slab[live], usize::MAX);
+        for &id in seen[1..].iter() {
+            assert!(slab.get(id).is_none());
+        }
+
+        // Its slot has generations left once it is freed.
+        slab.remove(live);
+        let id = slab.insert(1).unwrap();
+        assert_eq!(slab.index(id), slab.index(live));
+        assert!(!seen.contains(&id));
+        assert!(slab.get(live).is_none());
+    }
+}
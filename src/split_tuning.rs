@@ -0,0 +1,306 @@
+//! With `BAGUA_NET_MIN_CHUNKSIZE=auto`, the threshold over which messages are
+//! split in chunks, see `utils::chunk_size`, follows the sizes of the
+//! messages sent and how fast they went whole or split. Every send comm
+//! announces a new threshold to its receiver on the master stream before the
+//! first message split by it, see `connection::SPLIT_NBYTES`.
+
+use std::time::{Duration, Instant};
+
+/// What both ends of a comm split by until the sender announces otherwise.
+pub const INITIAL_MIN_CHUNKSIZE: usize = 1 << 20;
+
+/// Class `c` holds the sizes in `(2^(c-1), 2^c]`.
+const NCLASSES: usize = usize::BITS as usize + 1;
+/// Of the threshold, which is a power of two that fits
+/// `CommHandshake::min_chunksize`.
+const MAX_LOG2: u32 = 30;
+/// Classes with less of the bytes sent are not probed.
+const MIN_SHARE: f64 = 0.1;
+/// How fast a class goes whole or split is not known under as many messages.
+const MIN_SAMPLES: f64 = 4.;
+/// Of the estimated time to send the classes, that a new threshold must save.
+const HYSTERESIS: f64 = 0.05;
+
+/// `BAGUA_NET_MIN_CHUNKSIZE`, `None` for auto.
+pub fn min_chunksize_from_env(default: usize) -> Option<usize> {
+    match std::env::var("BAGUA_NET_MIN_CHUNKSIZE") {
+        Ok(min_chunksize) if min_chunksize == "auto" => None,
+        min_chunksize => Some(
+            min_chunksize
+                .unwrap_or(default.to_string())
+                .parse()
+                .unwrap(),
+        ),
+    }
+}
+
+/// Of the messages of a class that went one way, decayed once per period.
+#[derive(Debug, Default, Clone, Copy)]
+struct Samples {
+    messages: f64,
+    bytes: f64,
+    secs: f64,
+}
+
+impl Samples {
+    fn add(&mut self, nbytes: usize, elapsed: Duration) {
+        self.messages += 1.;
+        self.bytes += nbytes as f64;
+        self.secs += elapsed.as_secs_f64();
+    }
+
+    fn known(&self) -> bool {
+        self.messages >= MIN_SAMPLES
+    }
+
+    fn secs_per_byte(&self) -> f64 {
+        self.secs / self.bytes
+    }
+
+    fn decay(&mut self) {
+        self.messages /= 2.;
+        self.bytes /= 2.;
+        self.secs /= 2.;
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct SizeClass {
+    whole: Samples,
+    split: Samples,
+}
+
+impl SizeClass {
+    fn bytes(&self) -> f64 {
+        self.whole.bytes + self.split.bytes
+    }
+
+    /// Of the way it goes with its messages split or not, or the other way
+    /// if that is not known.
+    fn secs_per_byte(&self, split: bool) -> Option<f64> {
+        let (chosen, other) = match split {
+            true => (&self.split, &self.whole),
+            false => (&self.whole, &self.split),
+        };
+        match (chosen.known(), other.known()) {
+            (true, _) => Some(chosen.secs_per_byte()),
+            (false, true) => Some(other.secs_per_byte()),
+            (false, false) => None,
+        }
+    }
+}
+
+/// A histogram of the sizes of the messages sent by every comm, and how long
+/// each took from when it was taken until it was done, whole and split. Once
+/// per `period`, the threshold moves across the busiest class whose messages
+/// were only seen one way, or else to where the classes would take the
+/// least time.
+#[derive(Debug)]
+pub struct SplitTuner {
+    period: Duration,
+    classes: [SizeClass; NCLASSES],
+    log2: u32,
+    tuned_at: Instant,
+}
+
+impl SplitTuner {
+    /// `None` unless `BAGUA_NET_MIN_CHUNKSIZE=auto`.
+    pub fn from_env() -> Option<SplitTuner> {
+        min_chunksize_from_env(INITIAL_MIN_CHUNKSIZE)
+            .is_none()
+            .then(|| {
+                SplitTuner::new(Duration::from_millis(
+                    std::env::var("BAGUA_NET_MIN_CHUNKSIZE_PERIOD_MS")
+                        .unwrap_or("1000".to_owned())
+                        .parse()
+                        .unwrap(),
+                ))
+            })
+    }
+
+    pub fn new(period: Duration) -> SplitTuner {
+        SplitTuner {
+            period,
+            classes: [SizeClass::default(); NCLASSES],
+            log2: INITIAL_MIN_CHUNKSIZE.trailing_zeros(),
+            tuned_at: Instant::now(),
+        }
+    }
+
+    /// Messages larger are split.
+    pub fn threshold(&self) -> usize {
+        1 << self.log2
+    }
+
+    /// Of a message of `nbytes`, split by `threshold`, done `elapsed` after
+    /// it was taken.
+    pub fn record(&mut self, nbytes: usize, threshold: usize, elapsed: Duration) {
+        // No time per byte.
+        if nbytes == 0 {
+            return;
+        }
+        let class = &mut self.classes[class_of(nbytes)];
+        match nbytes > threshold {
+            true => class.split.add(nbytes, elapsed),
+            false => class.whole.add(nbytes, elapsed),
+        }
+    }
+
+    /// Retunes the threshold at most once per period.
+    pub fn tune(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.tuned_at) < self.period {
+            return;
+        }
+        self.tuned_at = now;
+        let log2 = self.probe().unwrap_or_else(|| self.fastest());
+        if log2 != self.log2 {
+            tracing::info!(
+                "min chunksize tuned from {} to {}",
+                self.threshold(),
+                1usize << log2
+            );
+            self.log2 = log2;
+        }
+        for class in self.classes.iter_mut() {
+            class.whole.decay();
+            class.split.decay();
+        }
+    }
+
+    /// Of the busiest class only seen one way, the threshold that sends it
+    /// the other way.
+    fn probe(&self) -> Option<u32> {
+        let total: f64 = self.classes.iter().map(SizeClass::bytes).sum();
+        let mut busy: Vec<(usize, &SizeClass)> = self
+            .classes
+            .iter()
+            .enumerate()
+            .filter(|(_, class)| total > 0. && class.bytes() >= MIN_SHARE * total)
+            .collect();
+        busy.sort_by(|(_, a), (_, b)| b.bytes().total_cmp(&a.bytes()));
+        busy.into_iter().find_map(|(c, class)| {
+            let split = c as u32 > self.log2;
+            match (split, class.split.known(), class.whole.known()) {
+                (true, true, false) if c as u32 <= MAX_LOG2 => Some(c as u32),
+                (false, false, true) if c > 0 => Some(c as u32 - 1),
+                _ => None,
+            }
+        })
+    }
+
+    /// The threshold under which the classes would take the least time, or
+    /// the current one unless another saves `HYSTERESIS` of it.
+    fn fastest(&self) -> u32 {
+        let cost = |log2: u32| -> f64 {
+            self.classes
+                .iter()
+                .enumerate()
+                .filter_map(|(c, class)| {
+                    class
+                        .secs_per_byte(c as u32 > log2)
+                        .map(|secs_per_byte| class.bytes() * secs_per_byte)
+                })
+                .sum()
+        };
+        let current = cost(self.log2);
+        let (log2, fastest) = (0..=MAX_LOG2)
+            .map(|log2| (log2, cost(log2)))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        match fastest < (1. - HYSTERESIS) * current {
+            true => log2,
+            false => self.log2,
+        }
+    }
+}
+
+fn class_of(nbytes: usize) -> usize {
+    match nbytes {
+        0 => 0,
+        nbytes => (usize::BITS - (nbytes - 1).leading_zeros()) as usize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_class_of() {
+        assert_eq!(class_of(0), 0);
+        assert_eq!(class_of(1), 0);
+        assert_eq!(class_of(2), 1);
+        assert_eq!(class_of(1 << 20), 20);
+        assert_eq!(class_of((1 << 20) + 1), 21);
+        assert_eq!(class_of(usize::MAX), usize::BITS as usize);
+    }
+
+    /// `n` messages of `nbytes` split by the tuner's threshold, at `gbps`.
+    fn send(tuner: &mut SplitTuner, n: usize, nbytes: usize, gbps: f64) {
+        let elapsed = Duration::from_secs_f64(nbytes as f64 * 8. / (gbps * 1e9));
+        for _ in 0..n {
+            tuner.record(nbytes, tuner.threshold(), elapsed);
+        }
+    }
+
+    #[test]
+    fn test_split_tuner() {
+        let period = Duration::from_millis(100);
+        let mut tuner = SplitTuner::new(period);
+        let mut now = tuner.tuned_at;
+        let mut next_period = |tuner: &mut SplitTuner| {
+            now += period;
+            tuner.tune(now);
+        };
+        assert_eq!(tuner.threshold(), 1 << 20);
+
+        // 256 KiB messages go whole, so splitting is probed.
+        send(&mut tuner, 100, 256 << 10, 5.);
+        tuner.tune(tuner.tuned_at + period / 2);
+        assert_eq!(tuner.threshold(), 1 << 20);
+        next_period(&mut tuner);
+        assert_eq!(tuner.threshold(), 1 << 17);
+        // Faster split, which stays until the whole samples decayed.
+        send(&mut tuner, 100, 256 << 10, 10.);
+        next_period(&mut tuner);
+        assert_eq!(tuner.threshold(), 1 << 17);
+        for _ in 0..3 {
+            send(&mut tuner, 100, 256 << 10, 10.);
+            next_period(&mut tuner);
+            assert_eq!(tuner.threshold(), 1 << 17);
+        }
+        send(&mut tuner, 100, 256 << 10, 10.);
+        next_period(&mut tuner);
+        assert_eq!(tuner.threshold(), 1 << 18);
+
+        // Slower split, back to whole.
+        let mut tuner = SplitTuner::new(period);
+        let mut now = tuner.tuned_at;
+        send(&mut tuner, 100, 256 << 10, 10.);
+        now += period;
+        tuner.tune(now);
+        assert_eq!(tuner.threshold(), 1 << 17);
+        send(&mut tuner, 100, 256 << 10, 5.);
+        now += period;
+        tuner.tune(now);
+        assert_eq!(tuner.threshold(), 1 << 18);
+
+        // The busier 64 MiB messages were only split, they are probed whole
+        // and faster that way. The 256 KiB ones stay whole.
+        send(&mut tuner, 10, 256 << 10, 10.);
+        send(&mut tuner, 10, 64 << 20, 5.);
+        now += period;
+        tuner.tune(now);
+        assert_eq!(tuner.threshold(), 64 << 20);
+        send(&mut tuner, 10, 256 << 10, 10.);
+        send(&mut tuner, 10, 64 << 20, 8.);
+        now += period;
+        tuner.tune(now);
+        assert_eq!(tuner.threshold(), 64 << 20);
+
+        // Nothing sent, nothing learned.
+        let mut tuner = SplitTuner::new(period);
+        tuner.tune(tuner.tuned_at + period);
+        assert_eq!(tuner.threshold(), 1 << 20);
+    }
+}
@@ -0,0 +1,484 @@
+//! Device buffers, posted once `NCCL_PTR_CUDA` is advertised, go through
+//! page-locked host buffers: a send chunk is copied to the host before it is
+//! written, a receive chunk is copied to the device once it was read. The
+//! copies run on a thread of their own, `BAGUA_NET_STAGING_DEPTH` of them
+//! at a time, while the event loops write and read the chunks before them.
+
+use crate::buffer_pool::{self, PooledBuffer};
+use crate::interface::BaguaNetError;
+use std::sync::{Arc, Mutex};
+
+/// A copy of `len` bytes between host and device, either way.
+#[cfg_attr(not(any(test, feature = "cuda")), allow(dead_code))]
+#[derive(Debug, Clone, Copy)]
+pub struct CopyRange {
+    pub dst: usize,
+    pub src: usize,
+    pub len: usize,
+}
+
+pub trait Device: Send + Sync {
+    fn is_device(&self, ptr: *const u8) -> bool;
+    /// Page-locked host memory, `None` if it cannot be had.
+    fn alloc_host(&self, len: usize) -> Option<*mut u8>;
+    fn free_host(&self, ptr: *mut u8);
+    /// Returns once every copy is done, they are issued together.
+    fn copy(&self, copies: &[CopyRange]) -> Result<(), BaguaNetError>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StagingConfig {
+    /// Host buffers kept for reuse.
+    pub pool_size: usize,
+    /// Copies in flight at a time.
+    pub depth: usize,
+}
+
+impl StagingConfig {
+    pub fn from_env() -> StagingConfig {
+        StagingConfig {
+            pool_size: std::env::var("BAGUA_NET_STAGING_POOL_SIZE")
+                .unwrap_or("32".to_owned())
+                .parse()
+                .unwrap(),
+            depth: std::env::var("BAGUA_NET_STAGING_DEPTH")
+                .unwrap_or("4".to_owned())
+                .parse()
+                .unwrap(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HostBuffer {
+    ptr: usize,
+    cap: usize,
+    /// Unless it is from `Device::alloc_host`.
+    pooled: Option<PooledBuffer>,
+}
+
+type Done = Box<dyn FnOnce(Result<(), BaguaNetError>) + Send>;
+
+pub struct Staging {
+    device: Arc<dyn Device>,
+    config: StagingConfig,
+    /// Smallest first.
+    free: Mutex<Vec<HostBuffer>>,
+    copies: flume::Sender<(CopyRange, Done)>,
+}
+
+impl Staging {
+    pub fn new(device: Arc<dyn Device>, config: StagingConfig) -> Arc<Staging> {
+        let (copies, queued) = flume::unbounded::<(CopyRange, Done)>();
+        let copier = device.clone();
+        let depth = config.depth.max(1);
+        std::thread::spawn(move || {
+            while let Ok(first) = queued.recv() {
+                let mut batch = vec![first];
+                while batch.len() < depth {
+                    match queued.try_recv() {
+                        Ok(copy) => batch.push(copy),
+                        Err(_) => break,
+                    }
+                }
+                let ranges: Vec<CopyRange> = batch.iter().map(|(range, _)| *range).collect();
+                let ret = copier.copy(&ranges[..]);
+                for (_, done) in batch {
+                    done(ret.clone());
+                }
+            }
+        });
+
+        Arc::new(Staging {
+            device,
+            config,
+            free: Default::default(),
+            copies,
+        })
+    }
+
+    /// With the `cuda` feature, unless `BAGUA_NET_CUDA=0`.
+    pub fn from_env() -> Option<Arc<Staging>> {
+        if std::env::var("BAGUA_NET_CUDA").unwrap_or("1".to_owned()) == "0" {
+            return None;
+        }
+        default_device().map(|device| Staging::new(device, StagingConfig::from_env()))
+    }
+
+    /// Of the buffer at `data`, which is not accessed.
+    pub fn is_device(&self, data: *const [u8]) -> bool {
+        !data.is_empty() && self.device.is_device(data as *const u8)
+    }
+
+    /// A host buffer of at least `len` bytes, from the pool if it has one.
+    pub fn lease(self: &Arc<Self>, len: usize) -> Bounce {
+        let reused = {
+            let mut free = self.free.lock().unwrap();
+            free.iter()
+                .position(|buffer| buffer.cap >= len)
+                .map(|i| free.remove(i))
+        };
+        let buffer = reused.unwrap_or_else(|| {
+            let cap = len.next_power_of_two();
+            match self.device.alloc_host(cap) {
+                Some(ptr) => HostBuffer {
+                    ptr: ptr as usize,
+                    cap,
+                    pooled: None,
+                },
+                None => {
+                    tracing::debug!("failed to allocate {} bytes of page-locked memory", cap);
+                    let pooled = buffer_pool::POOL.lease(cap);
+                    HostBuffer {
+                        ptr: pooled.ptr(),
+                        cap,
+                        pooled: Some(pooled),
+                    }
+                }
+            }
+        });
+        Bounce {
+            buffer: Some(buffer),
+            staging: self.clone(),
+        }
+    }
+
+    /// Queues the copy, `done` is called on the copying thread.
+    pub fn copy(
+        &self,
+        range: CopyRange,
+        done: impl FnOnce(Result<(), BaguaNetError>) + Send + 'static,
+    ) {
+        // The thread only stops once this is dropped.
+        let _ = self.copies.send((range, Box::new(done)));
+    }
+
+    /// On the calling thread, for messages too small to be worth queueing.
+    pub fn copy_now(&self, range: CopyRange) -> Result<(), BaguaNetError> {
+        self.device.copy(&[range])
+    }
+
+    fn release(&self, buffer: HostBuffer) {
+        let mut free = self.free.lock().unwrap();
+        let i = free.partition_point(|other| other.cap < buffer.cap);
+        free.insert(i, buffer);
+        // The smallest are the least likely to fit.
+        if free.len() > self.config.pool_size {
+            let evicted = free.remove(0);
+            drop(free);
+            self.free_buffer(evicted);
+        }
+    }
+
+    fn free_buffer(&self, buffer: HostBuffer) {
+        // Pooled ones go back to `buffer_pool::POOL`.
+        if buffer.pooled.is_none() {
+            self.device.free_host(buffer.ptr as *mut u8);
+        }
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        for buffer in std::mem::take(&mut *self.free.lock().unwrap()) {
+            self.free_buffer(buffer);
+        }
+    }
+}
+
+#[cfg(feature = "cuda")]
+fn default_device() -> Option<Arc<dyn Device>> {
+    Some(Arc::new(cuda::CudaDevice))
+}
+
+#[cfg(not(feature = "cuda"))]
+fn default_device() -> Option<Arc<dyn Device>> {
+    None
+}
+
+/// A host buffer of the pool, back in it once dropped.
+pub struct Bounce {
+    buffer: Option<HostBuffer>,
+    staging: Arc<Staging>,
+}
+
+impl Bounce {
+    pub fn ptr(&self) -> usize {
+        self.buffer.as_ref().unwrap().ptr
+    }
+
+    /// Its first `len` bytes, valid until it is dropped.
+    pub fn slice(&self, len: usize) -> &'static mut [u8] {
+        let buffer = self.buffer.as_ref().unwrap();
+        assert!(len <= buffer.cap);
+        unsafe { std::slice::from_raw_parts_mut(buffer.ptr as *mut u8, len) }
+    }
+
+    pub fn staging(&self) -> &Arc<Staging> {
+        &self.staging
+    }
+}
+
+impl Drop for Bounce {
+    fn drop(&mut self) {
+        self.staging.release(self.buffer.take().unwrap());
+    }
+}
+
+#[cfg(feature = "cuda")]
+mod cuda {
+    use super::{CopyRange, Device};
+    use crate::interface::BaguaNetError;
+    use std::os::raw::{c_int, c_uint, c_void};
+
+    type CudaStream = *mut c_void;
+
+    const CUDA_SUCCESS: c_int = 0;
+    const CUDA_MEMORY_TYPE_DEVICE: c_int = 2;
+    const CUDA_MEMCPY_DEFAULT: c_int = 4;
+    const CUDA_STREAM_NON_BLOCKING: c_uint = 1;
+    const CUDA_HOST_ALLOC_PORTABLE: c_uint = 1;
+
+    /// `cudaPointerAttributes` of CUDA 11 and later.
+    #[repr(C)]
+    struct PointerAttributes {
+        memory_type: c_int,
+        device: c_int,
+        device_pointer: *mut c_void,
+        host_pointer: *mut c_void,
+    }
+
+    #[link(name = "cudart")]
+    extern "C" {
+        fn cudaPointerGetAttributes(
+            attributes: *mut PointerAttributes,
+            ptr: *const c_void,
+        ) -> c_int;
+        fn cudaGetLastError() -> c_int;
+        fn cudaHostAlloc(ptr: *mut *mut c_void, size: usize, flags: c_uint) -> c_int;
+        fn cudaFreeHost(ptr: *mut c_void) -> c_int;
+        fn cudaStreamCreateWithFlags(stream: *mut CudaStream, flags: c_uint) -> c_int;
+        fn cudaMemcpyAsync(
+            dst: *mut c_void,
+            src: *const c_void,
+            count: usize,
+            kind: c_int,
+            stream: CudaStream,
+        ) -> c_int;
+        fn cudaStreamSynchronize(stream: CudaStream) -> c_int;
+    }
+
+    thread_local! {
+        /// Not the default stream, that would wait for the kernels of NCCL.
+        static STREAM: CudaStream = {
+            let mut stream = std::ptr::null_mut();
+            let ret = unsafe { cudaStreamCreateWithFlags(&mut stream, CUDA_STREAM_NON_BLOCKING) };
+            if ret != CUDA_SUCCESS {
+                tracing::warn!("cudaStreamCreateWithFlags failed, err={}", ret);
+            }
+            stream
+        };
+    }
+
+    fn check(ret: c_int, what: &str) -> Result<(), BaguaNetError> {
+        if ret != CUDA_SUCCESS {
+            return Err(BaguaNetError::system(format!(
+                "{} failed, err={}",
+                what, ret
+            )));
+        }
+        Ok(())
+    }
+
+    pub struct CudaDevice;
+
+    impl Device for CudaDevice {
+        fn is_device(&self, ptr: *const u8) -> bool {
+            let mut attributes: PointerAttributes = unsafe { std::mem::zeroed() };
+            let ret = unsafe { cudaPointerGetAttributes(&mut attributes, ptr as *const c_void) };
+            if ret != CUDA_SUCCESS {
+                // Before CUDA 11, for memory CUDA does not know of.
+                unsafe { cudaGetLastError() };
+                return false;
+            }
+            // Managed memory is accessible from the host.
+            attributes.memory_type == CUDA_MEMORY_TYPE_DEVICE
+        }
+
+        fn alloc_host(&self, len: usize) -> Option<*mut u8> {
+            let mut ptr = std::ptr::null_mut();
+            let ret = unsafe { cudaHostAlloc(&mut ptr, len, CUDA_HOST_ALLOC_PORTABLE) };
+            (ret == CUDA_SUCCESS).then_some(ptr as *mut u8)
+        }
+
+        fn free_host(&self, ptr: *mut u8) {
+            unsafe { cudaFreeHost(ptr as *mut c_void) };
+        }
+
+        fn copy(&self, copies: &[CopyRange]) -> Result<(), BaguaNetError> {
+            STREAM.with(|&stream| {
+                for range in copies {
+                    check(
+                        unsafe {
+                            cudaMemcpyAsync(
+                                range.dst as *mut c_void,
+                                range.src as *const c_void,
+                                range.len,
+                                CUDA_MEMCPY_DEFAULT,
+                                stream,
+                            )
+                        },
+                        "cudaMemcpyAsync",
+                    )?;
+                }
+                check(
+                    unsafe { cudaStreamSynchronize(stream) },
+                    "cudaStreamSynchronize",
+                )
+            })
+        }
+    }
+}
+
+/// Host memory it hands out counts as device memory, only reachable
+/// through its copies.
+#[cfg(test)]
+pub mod fake {
+    use super::{CopyRange, Device};
+    use crate::interface::BaguaNetError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    pub struct FakeDevice {
+        allocations: Mutex<Vec<(usize, usize)>>,
+        pub copied: AtomicUsize,
+    }
+
+    impl FakeDevice {
+        pub fn alloc(&self, data: Vec<u8>) -> &'static mut [u8] {
+            let data: &'static mut [u8] = Box::leak(data.into_boxed_slice());
+            self.allocations
+                .lock()
+                .unwrap()
+                .push((data.as_ptr() as usize, data.len()));
+            data
+        }
+    }
+
+    impl Device for FakeDevice {
+        fn is_device(&self, ptr: *const u8) -> bool {
+            let ptr = ptr as usize;
+            self.allocations
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|&(start, len)| ptr >= start && ptr < start + len)
+        }
+
+        /// The pool falls back to `buffer_pool::POOL`.
+        fn alloc_host(&self, _len: usize) -> Option<*mut u8> {
+            None
+        }
+
+        fn free_host(&self, _ptr: *mut u8) {
+            unreachable!()
+        }
+
+        fn copy(&self, copies: &[CopyRange]) -> Result<(), BaguaNetError> {
+            for range in copies {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        range.src as *const u8,
+                        range.dst as *mut u8,
+                        range.len,
+                    )
+                };
+                self.copied.fetch_add(range.len, Ordering::Relaxed);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fake::FakeDevice;
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    fn staging(pool_size: usize) -> (Arc<FakeDevice>, Arc<Staging>) {
+        let device = Arc::new(FakeDevice::default());
+        let staging = Staging::new(
+            device.clone(),
+            StagingConfig {
+                pool_size,
+                depth: 4,
+            },
+        );
+        (device, staging)
+    }
+
+    #[test]
+    fn test_pool_reuse() {
+        let (_, staging) = staging(2);
+        let first = staging.lease(1000);
+        let ptr = first.ptr();
+        drop(first);
+        // Reused if it fits.
+        assert_eq!(staging.lease(1024).ptr(), ptr);
+        assert_ne!(staging.lease(1025).ptr(), ptr);
+
+        let leased: Vec<Bounce> = (0..4).map(|i| staging.lease(64 << i)).collect();
+        drop(leased);
+        // The 64 and 128 byte leases reuse the pooled buffers, the largest
+        // two of the four are kept.
+        let caps: Vec<usize> = staging
+            .free
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|buffer| buffer.cap)
+            .collect();
+        assert_eq!(caps, vec![1024, 2048]);
+    }
+
+    #[test]
+    fn test_copy_round_trip() {
+        let (device, staging) = staging(4);
+        let data: Vec<u8> = (0..1 << 20).map(|i| (i * 7) as u8).collect();
+        let src = device.alloc(data.clone());
+        let dst = device.alloc(vec![0u8; data.len()]);
+        assert!(staging.is_device(src));
+        assert!(!staging.is_device(&data[..]));
+
+        // Device to host to device, in chunks.
+        let (done, copied) = flume::unbounded();
+        let bounces: Vec<(usize, Bounce)> = (0..src.len())
+            .step_by(64 * 1024)
+            .map(|offset| (offset, staging.lease(64 * 1024)))
+            .collect();
+        for (offset, bounce) in bounces.iter() {
+            let done = done.clone();
+            let range = CopyRange {
+                dst: bounce.ptr(),
+                src: src.as_ptr() as usize + offset,
+                len: 64 * 1024,
+            };
+            staging.copy(range, move |ret| done.send(ret).unwrap());
+        }
+        for _ in bounces.iter() {
+            copied.recv().unwrap().unwrap();
+        }
+        for (offset, bounce) in bounces.iter() {
+            let range = CopyRange {
+                dst: dst.as_ptr() as usize + offset,
+                src: bounce.ptr(),
+                len: 64 * 1024,
+            };
+            staging.copy_now(range).unwrap();
+        }
+        assert_eq!(&dst[..], &data[..]);
+        assert_eq!(device.copied.load(Ordering::Relaxed), 2 * data.len());
+    }
+}
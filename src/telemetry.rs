@@ -0,0 +1,927 @@
+//! Spans sent to the Jaeger collector at `BAGUA_NET_JAEGER_ADDRESS`, or over
+//! UDP to the Jaeger agent at `BAGUA_NET_JAEGER_AGENT`, like `localhost:6831`,
+//! rather if both are set. Metrics pushed to Prometheus at
+//! `BAGUA_NET_PROMETHEUS_ADDRESS` and served to scrapers at
+//! `BAGUA_NET_PROMETHEUS_LISTEN`, each only once its address is set, none
+//! with `BAGUA_NET_TELEMETRY=off`. Without them, spans are no-ops and
+//! metrics are neither observed nor exported. Built with the
+//! `otlp` feature, spans go to an OpenTelemetry collector at
+//! `BAGUA_NET_OTLP_ENDPOINT` instead, once it is set.
+//! `BAGUA_NET_METRICS_PER_PEER=0` leaves out the byte counters by peer and
+//! comm, only their aggregate is.
+//!
+//! `BAGUA_NET_TCP_INFO=1` adds the `tcp_rtt_us`, `tcp_retrans_total` and
+//! `tcp_snd_cwnd` of each data stream, by comm and stream, read from its
+//! `TCP_INFO` each time the metrics are pushed or scraped.
+//!
+//! The request latency histograms have the buckets in microseconds of
+//! `BAGUA_NET_LATENCY_BUCKETS_US`, comma separated, `LATENCY_BUCKETS_US` by
+//! default, the other histograms those of the byte counters.
+//!
+//! The rank is the first there is of `utils::RANK_VARS`, -1 if none.
+//!
+//! Spans are only exported by the ranks of `BAGUA_NET_TRACE_RANKS`, like
+//! `0,8,64-71` or `all`, the first 8 by default. Of their sends and
+//! receives, `BAGUA_NET_TRACE_SAMPLE_RATIO`, like `0.01` or `1%`, get a
+//! span, all by default. Each one costs an allocation of its attributes and
+//! events while the request is posted, and a slot in the exporter's batch
+//! until it is sent, whose queue drops spans once full: with small messages
+//! on many comms, a ratio well below 1 keeps the posting threads from
+//! spending their time on spans, and their memory bounded.
+//!
+//! `BAGUA_NET_TRACE=on` exports spans whatever the rank is, e.g. under
+//! launchers that set none of those variables, `off` leaves sends and receives without a
+//! span at all, not even a no-op one, and `auto`, the default, picks the
+//! ranks as above.
+
+use crate::utils;
+use nix::poll::{PollFd, PollFlags};
+use opentelemetry::global::BoxedSpan;
+use opentelemetry::metrics::{BoundValueRecorder, Meter, ObserverResult, ValueRecorder};
+use opentelemetry::sdk::trace::{SamplingDecision, SamplingResult};
+use opentelemetry::trace::{Span, SpanBuilder, SpanContext, StatusCode, Tracer};
+use opentelemetry::KeyValue;
+use opentelemetry_prometheus::PrometheusExporter;
+use prometheus::Encoder;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+static TRACING_INIT_ONCE: std::sync::Once = std::sync::Once::new();
+
+#[cfg(feature = "otlp")]
+lazy_static! {
+    /// Of the OTLP exporter, whose gRPC client only runs on tokio.
+    static ref OTLP_RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("bagua-net-otlp")
+        .enable_all()
+        .build()
+        .unwrap();
+}
+
+/// Of the pushes after failed ones, however many failed.
+const MAX_PUSH_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Of the ranks on a host, which serve their metrics on successive ports.
+const LISTEN_PORT_ATTEMPTS: u16 = 64;
+/// Of reading a scrape's request and writing the response.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the server checks whether it is to stop.
+const SERVE_POLL_MS: i32 = 100;
+
+const NBYTES_BUCKETS: [f64; 4] = [16., 1024., 4096., 1048576.];
+const LATENCY_BUCKETS_US: &str = "50,100,250,500,1000,2500,5000,10000,25000,50000,100000,1000000";
+
+#[derive(Debug, Clone)]
+pub struct PrometheusConfig {
+    pub user: String,
+    pub pass: String,
+    pub address: String,
+    /// `BAGUA_NET_METRICS_PUSH_INTERVAL_MS`, between pushes that succeed.
+    pub interval: Duration,
+}
+
+impl PrometheusConfig {
+    /// After `failures` pushes in a row failed, doubled with each of them up
+    /// to `MAX_PUSH_BACKOFF`.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let backoff = 2u32.checked_pow(failures).unwrap_or(u32::MAX);
+        self.interval
+            .checked_mul(backoff)
+            .map_or(MAX_PUSH_BACKOFF, |delay| delay.min(MAX_PUSH_BACKOFF))
+            .max(self.interval)
+    }
+}
+
+/// Of the ranks that export spans.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceRanks {
+    All,
+    Listed(Vec<usize>),
+}
+
+impl TraceRanks {
+    /// `all`, or ranks like `0,8,64-71`.
+    pub fn parse(ranks: &str) -> Option<TraceRanks> {
+        match ranks.trim() {
+            "all" => Some(TraceRanks::All),
+            ranks => utils::parse_cpu_list(ranks).map(TraceRanks::Listed),
+        }
+    }
+
+    /// Not of a process without a rank, -1.
+    pub fn contains(&self, rank: i32) -> bool {
+        match self {
+            TraceRanks::All => rank >= 0,
+            TraceRanks::Listed(ranks) => rank >= 0 && ranks.contains(&(rank as usize)),
+        }
+    }
+}
+
+impl Default for TraceRanks {
+    fn default() -> TraceRanks {
+        TraceRanks::Listed((0..8).collect())
+    }
+}
+
+/// Like `0.01` or `1%`, within 0 and 1.
+pub fn parse_sample_ratio(ratio: &str) -> Option<f64> {
+    let ratio = ratio.trim();
+    let ratio = match ratio.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().ok()? / 100.,
+        None => ratio.parse().ok()?,
+    };
+    (0. ..=1.).contains(&ratio).then_some(ratio)
+}
+
+/// Of which sends and receives get a span, the others get one that is
+/// neither recorded nor exported, nor are the spans of their chunks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpanSampler {
+    pub ratio: f64,
+}
+
+impl SpanSampler {
+    pub fn sample(&self, builder: SpanBuilder) -> SpanBuilder {
+        if self.ratio >= 1. || rand::random::<f64>() < self.ratio {
+            return builder;
+        }
+        builder.with_sampling_result(SamplingResult {
+            decision: SamplingDecision::Drop,
+            attributes: Vec::new(),
+            trace_state: Default::default(),
+        })
+    }
+}
+
+impl Default for SpanSampler {
+    fn default() -> SpanSampler {
+        SpanSampler { ratio: 1. }
+    }
+}
+
+/// `BAGUA_NET_TRACE`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TraceMode {
+    On,
+    Off,
+    #[default]
+    Auto,
+}
+
+/// Of a send, receive or flush, `None` if it was given no span, see
+/// `TelemetryConfig::span_sampler`.
+#[derive(Debug)]
+pub struct RequestSpan(pub Option<BoxedSpan>);
+
+impl RequestSpan {
+    /// Named once it is.
+    pub fn start(
+        sampler: Option<SpanSampler>,
+        parent: &opentelemetry::Context,
+        name: impl FnOnce() -> String,
+    ) -> RequestSpan {
+        RequestSpan(sampler.map(|sampler| {
+            let tracer = opentelemetry::global::tracer("bagua-net");
+            sampler
+                .sample(tracer.span_builder(name()))
+                .with_parent_context(parent.clone())
+                .start(&tracer)
+        }))
+    }
+}
+
+lazy_static! {
+    static ref NO_SPAN_CONTEXT: SpanContext = SpanContext::empty_context();
+}
+
+impl Span for RequestSpan {
+    fn add_event_with_timestamp(
+        &mut self,
+        name: String,
+        timestamp: SystemTime,
+        attributes: Vec<KeyValue>,
+    ) {
+        if let Some(span) = &mut self.0 {
+            span.add_event_with_timestamp(name, timestamp, attributes)
+        }
+    }
+
+    fn span_context(&self) -> &SpanContext {
+        self.0
+            .as_ref()
+            .map_or(&NO_SPAN_CONTEXT, |span| span.span_context())
+    }
+
+    fn is_recording(&self) -> bool {
+        self.0.as_ref().is_some_and(|span| span.is_recording())
+    }
+
+    fn set_attribute(&mut self, attribute: KeyValue) {
+        if let Some(span) = &mut self.0 {
+            span.set_attribute(attribute)
+        }
+    }
+
+    fn set_status(&mut self, code: StatusCode, message: String) {
+        if let Some(span) = &mut self.0 {
+            span.set_status(code, message)
+        }
+    }
+
+    fn update_name(&mut self, new_name: String) {
+        if let Some(span) = &mut self.0 {
+            span.update_name(new_name)
+        }
+    }
+
+    fn end_with_timestamp(&mut self, timestamp: SystemTime) {
+        if let Some(span) = &mut self.0 {
+            span.end_with_timestamp(timestamp)
+        }
+    }
+}
+
+/// Where spans are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanExporter<'a> {
+    Otlp(&'a str),
+    Jaeger(&'a str),
+    JaegerAgent(&'a str),
+}
+
+/// Of the spans the Jaeger agent is sent in one datagram. It drops those
+/// larger than 65000 bytes, with every span in them.
+const AGENT_BATCH_SPANS: usize = 64;
+
+/// Of the string attributes that grow with the host or the error, like the
+/// socket devices, the bytes a span keeps, so that a batch of them still
+/// fits a datagram to the Jaeger agent.
+pub const MAX_ATTRIBUTE_LEN: usize = 512;
+
+/// `value`, cut on a char boundary to at most `MAX_ATTRIBUTE_LEN` bytes.
+pub fn capped_attribute(mut value: String) -> String {
+    if value.len() > MAX_ATTRIBUTE_LEN {
+        let mut end = MAX_ATTRIBUTE_LEN - "...".len();
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        value.truncate(end);
+        value.push_str("...");
+    }
+    value
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TelemetryConfig {
+    /// Only with the `otlp` feature.
+    pub otlp_endpoint: Option<String>,
+    pub jaeger_addr: Option<String>,
+    pub jaeger_agent: Option<String>,
+    pub prometheus: Option<PrometheusConfig>,
+    pub prometheus_listen: Option<SocketAddr>,
+    /// Unless `BAGUA_NET_METRICS_PER_PEER=0`.
+    pub per_peer: bool,
+    pub latency_buckets_us: Vec<f64>,
+    /// `BAGUA_NET_TRACE_CHUNKS=1`, a span for each chunk of a request.
+    pub trace_chunks: bool,
+    pub trace: TraceMode,
+    pub trace_ranks: TraceRanks,
+    pub span_sampler: SpanSampler,
+    /// `BAGUA_NET_TCP_INFO=1`, only on Linux.
+    pub tcp_info: bool,
+}
+
+impl TelemetryConfig {
+    pub fn from_env() -> TelemetryConfig {
+        match std::env::var("BAGUA_NET_TELEMETRY")
+            .unwrap_or("on".to_owned())
+            .as_str()
+        {
+            "on" => {}
+            "off" => return TelemetryConfig::default(),
+            others => panic!("BAGUA_NET_TELEMETRY={:?}, expected on or off", others),
+        }
+        TelemetryConfig {
+            otlp_endpoint: std::env::var("BAGUA_NET_OTLP_ENDPOINT").ok().filter(|_| {
+                if !cfg!(feature = "otlp") {
+                    tracing::warn!(
+                        "BAGUA_NET_OTLP_ENDPOINT is ignored, built without the otlp feature"
+                    );
+                }
+                cfg!(feature = "otlp")
+            }),
+            jaeger_addr: std::env::var("BAGUA_NET_JAEGER_ADDRESS").ok(),
+            jaeger_agent: std::env::var("BAGUA_NET_JAEGER_AGENT").ok(),
+            prometheus: std::env::var("BAGUA_NET_PROMETHEUS_ADDRESS")
+                .ok()
+                .and_then(|addr| utils::parse_user_pass_and_addr(&addr))
+                .map(|(user, pass, address)| PrometheusConfig {
+                    user,
+                    pass,
+                    address,
+                    interval: Duration::from_millis(
+                        std::env::var("BAGUA_NET_METRICS_PUSH_INTERVAL_MS")
+                            .unwrap_or("1000".to_owned())
+                            .parse()
+                            .unwrap(),
+                    ),
+                }),
+            prometheus_listen: std::env::var("BAGUA_NET_PROMETHEUS_LISTEN")
+                .ok()
+                .map(|addr| addr.parse().unwrap()),
+            per_peer: std::env::var("BAGUA_NET_METRICS_PER_PEER").unwrap_or("1".to_owned()) != "0",
+            latency_buckets_us: std::env::var("BAGUA_NET_LATENCY_BUCKETS_US")
+                .unwrap_or(LATENCY_BUCKETS_US.to_owned())
+                .split(',')
+                .map(|bound| bound.trim().parse().unwrap())
+                .collect(),
+            trace_chunks: std::env::var("BAGUA_NET_TRACE_CHUNKS").unwrap_or("0".to_owned()) == "1",
+            trace: match std::env::var("BAGUA_NET_TRACE")
+                .unwrap_or("auto".to_owned())
+                .as_str()
+            {
+                "on" => TraceMode::On,
+                "off" => TraceMode::Off,
+                "auto" => TraceMode::Auto,
+                others => panic!("BAGUA_NET_TRACE={:?}, expected on, off or auto", others),
+            },
+            trace_ranks: TraceRanks::parse(
+                &std::env::var("BAGUA_NET_TRACE_RANKS").unwrap_or("0-7".to_owned()),
+            )
+            .unwrap(),
+            span_sampler: SpanSampler {
+                ratio: parse_sample_ratio(
+                    &std::env::var("BAGUA_NET_TRACE_SAMPLE_RATIO").unwrap_or("1".to_owned()),
+                )
+                .unwrap(),
+            },
+            tcp_info: std::env::var("BAGUA_NET_TCP_INFO").unwrap_or("0".to_owned()) == "1" && {
+                if !cfg!(target_os = "linux") {
+                    tracing::warn!("BAGUA_NET_TCP_INFO is ignored, only Linux has TCP_INFO");
+                }
+                cfg!(target_os = "linux")
+            },
+        }
+    }
+
+    /// Once spans are sent somewhere.
+    pub fn traces_chunks(&self) -> bool {
+        self.trace_chunks
+            && self.trace != TraceMode::Off
+            && (self.otlp_endpoint.is_some()
+                || self.jaeger_addr.is_some()
+                || self.jaeger_agent.is_some())
+    }
+
+    /// Of the spans of sends and receives, `None` with `BAGUA_NET_TRACE=off`.
+    pub fn span_sampler(&self) -> Option<SpanSampler> {
+        match self.trace {
+            TraceMode::Off => None,
+            TraceMode::On | TraceMode::Auto => Some(self.span_sampler),
+        }
+    }
+
+    /// Ascending, of the latency histograms.
+    fn latency_buckets(&self) -> Vec<f64> {
+        let mut buckets = self.latency_buckets_us.clone();
+        buckets.sort_by(f64::total_cmp);
+        buckets.dedup();
+        buckets
+    }
+
+    /// OTLP if it is set, then the Jaeger agent.
+    pub fn span_exporter(&self) -> Option<SpanExporter<'_>> {
+        let jaeger = match (&self.jaeger_agent, &self.jaeger_addr) {
+            (Some(agent), jaeger_addr) => {
+                if jaeger_addr.is_some() && self.otlp_endpoint.is_none() {
+                    tracing::info!(
+                        "both BAGUA_NET_JAEGER_AGENT and BAGUA_NET_JAEGER_ADDRESS are set, spans go to the agent at {}",
+                        agent
+                    );
+                }
+                Some(("BAGUA_NET_JAEGER_AGENT", SpanExporter::JaegerAgent(agent)))
+            }
+            (None, Some(jaeger_addr)) => Some((
+                "BAGUA_NET_JAEGER_ADDRESS",
+                SpanExporter::Jaeger(jaeger_addr),
+            )),
+            (None, None) => None,
+        };
+        match (&self.otlp_endpoint, jaeger) {
+            (Some(endpoint), jaeger) => {
+                if let Some((var, _)) = jaeger {
+                    tracing::warn!(
+                        "both BAGUA_NET_OTLP_ENDPOINT and {} are set, spans go to {}",
+                        var,
+                        endpoint
+                    );
+                }
+                Some(SpanExporter::Otlp(endpoint))
+            }
+            (None, jaeger) => jaeger.map(|(_, exporter)| exporter),
+        }
+    }
+
+    /// Of any rank with `BAGUA_NET_TRACE=on`, of those of `trace_ranks` with
+    /// `auto`, once per process.
+    pub fn init_tracing(&self, rank: i32) {
+        let exporter = match (self.span_exporter(), self.trace) {
+            (Some(exporter), TraceMode::On) => exporter,
+            (Some(exporter), TraceMode::Auto) if self.trace_ranks.contains(rank) => exporter,
+            (None, TraceMode::On) => {
+                tracing::warn!("BAGUA_NET_TRACE=on, but spans are sent nowhere");
+                return;
+            }
+            _ => return,
+        };
+        TRACING_INIT_ONCE.call_once(|| match exporter {
+            #[cfg(feature = "otlp")]
+            SpanExporter::Otlp(endpoint) => {
+                use opentelemetry_otlp::WithExportConfig;
+                tracing::info!("exporting spans to {}", endpoint);
+                let _runtime = OTLP_RUNTIME.enter();
+                opentelemetry::global::set_text_map_propagator(
+                    opentelemetry::sdk::propagation::TraceContextPropagator::new(),
+                );
+                opentelemetry_otlp::new_pipeline()
+                    .tracing()
+                    .with_exporter(
+                        opentelemetry_otlp::new_exporter()
+                            .tonic()
+                            .with_endpoint(endpoint),
+                    )
+                    .with_trace_config(opentelemetry::sdk::trace::config().with_resource(
+                        opentelemetry::sdk::Resource::new(vec![KeyValue::new(
+                            "service.name",
+                            "bagua-net",
+                        )]),
+                    ))
+                    .install_batch(opentelemetry::runtime::Tokio)
+                    .unwrap();
+            }
+            #[cfg(not(feature = "otlp"))]
+            SpanExporter::Otlp(_) => unreachable!(),
+            SpanExporter::Jaeger(jaeger_addr) => {
+                tracing::info!("detected auto tuning server, connecting");
+                opentelemetry::global::set_text_map_propagator(
+                    opentelemetry_jaeger::Propagator::new(),
+                );
+                opentelemetry_jaeger::new_pipeline()
+                    .with_collector_endpoint(format!("http://{}/api/traces", jaeger_addr))
+                    .with_service_name("bagua-net")
+                    .install_batch(opentelemetry::runtime::AsyncStd)
+                    .unwrap();
+            }
+            SpanExporter::JaegerAgent(agent) => {
+                tracing::info!("exporting spans to the Jaeger agent at {}", agent);
+                opentelemetry::global::set_text_map_propagator(
+                    opentelemetry_jaeger::Propagator::new(),
+                );
+                let exporter = opentelemetry_jaeger::new_pipeline()
+                    .with_agent_endpoint(agent)
+                    .with_service_name("bagua-net")
+                    .init_async_exporter(opentelemetry::runtime::AsyncStd)
+                    .unwrap();
+                let processor = opentelemetry::sdk::trace::BatchSpanProcessor::builder(
+                    exporter,
+                    opentelemetry::runtime::AsyncStd,
+                )
+                .with_max_export_batch_size(AGENT_BATCH_SPANS)
+                .build();
+                let _ = opentelemetry::global::set_tracer_provider(
+                    opentelemetry::sdk::trace::TracerProvider::builder()
+                        .with_span_processor(processor)
+                        .build(),
+                );
+            }
+        });
+    }
+
+    /// The exporter and the meters to observe the metrics with, if they are
+    /// pushed or served.
+    pub fn metrics(&self) -> Option<(MetricsExporter, Meters)> {
+        if self.prometheus.is_none() && self.prometheus_listen.is_none() {
+            return None;
+        }
+        Some(self.exporter())
+    }
+
+    /// Sets the global meter provider to the one of `Meters::meter`.
+    ///
+    /// The buckets of the histograms are those of the exporter, with otel
+    /// 0.16 there is no view to give an instrument its own. The latency
+    /// histograms go through an exporter of their own instead.
+    pub fn exporter(&self) -> (MetricsExporter, Meters) {
+        use opentelemetry::metrics::MeterProvider;
+        let latency = opentelemetry_prometheus::exporter()
+            .with_default_histogram_boundaries(self.latency_buckets())
+            .init();
+        let exporter = opentelemetry_prometheus::exporter()
+            .with_default_histogram_boundaries(NBYTES_BUCKETS.to_vec())
+            .init();
+        let meters = Meters {
+            meter: exporter.provider().unwrap().meter("bagua-net", None),
+            latency: latency.provider().unwrap().meter("bagua-net", None),
+        };
+        (MetricsExporter { exporter, latency }, meters)
+    }
+}
+
+/// Of the metrics, the latency histograms in a registry of their own, see
+/// `TelemetryConfig::exporter`.
+#[derive(Debug, Clone)]
+pub struct MetricsExporter {
+    exporter: PrometheusExporter,
+    latency: PrometheusExporter,
+}
+
+impl MetricsExporter {
+    /// Of both registries, sorted by name.
+    pub fn gather(&self) -> Vec<prometheus::proto::MetricFamily> {
+        let mut families = self.exporter.registry().gather();
+        families.extend(self.latency.registry().gather());
+        families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        families
+    }
+}
+
+/// To observe the metrics with.
+#[derive(Debug)]
+pub struct Meters {
+    /// Its histograms have `NBYTES_BUCKETS`.
+    pub meter: Meter,
+    /// Of the `*_latency_us` histograms, see `BAGUA_NET_LATENCY_BUCKETS_US`.
+    pub latency: Meter,
+}
+
+/// To `prometheus` until `stopped` is disconnected, unless they did not
+/// change since they were last pushed.
+pub fn push_metrics(
+    prometheus: PrometheusConfig,
+    exporter: MetricsExporter,
+    rank: i32,
+    stopped: flume::Receiver<()>,
+) {
+    let mut pushed = None;
+    let mut failures = 0;
+    loop {
+        if let Err(flume::RecvTimeoutError::Disconnected) =
+            stopped.recv_timeout(prometheus.delay(failures))
+        {
+            return;
+        }
+        let metric_families = exporter.gather();
+        if pushed.as_ref() == Some(&metric_families) {
+            continue;
+        }
+        match prometheus::push_metrics(
+            "BaguaNet",
+            prometheus::labels! { "rank".to_owned() => rank.to_string(), },
+            &prometheus.address,
+            metric_families.clone(),
+            Some(prometheus::BasicAuthentication {
+                username: prometheus.user.clone(),
+                password: prometheus.pass.clone(),
+            }),
+        ) {
+            Ok(_) => {
+                pushed = Some(metric_families);
+                failures = 0;
+            }
+            Err(err) => {
+                failures += 1;
+                tracing::warn!(
+                    "push to {} failed {} times in a row, retry in {:?}, err={:?}",
+                    prometheus.address,
+                    failures,
+                    prometheus.delay(failures),
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// On `addr`, or the first port after it that is free, as for the other
+/// ranks on the host. Observed as `metrics_listen_info`, labelled by the
+/// address bound.
+pub fn listen_metrics(addr: SocketAddr, meter: &Meter) -> io::Result<TcpListener> {
+    let mut tried = addr;
+    let listener = loop {
+        match TcpListener::bind(tried) {
+            Ok(listener) => break listener,
+            Err(err)
+                if err.kind() == io::ErrorKind::AddrInUse
+                    && tried.port() != 0
+                    && tried.port() - addr.port() + 1 < LISTEN_PORT_ATTEMPTS =>
+            {
+                match tried.port().checked_add(1) {
+                    Some(port) => tried.set_port(port),
+                    None => return Err(err),
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    let bound = listener.local_addr()?.to_string();
+    tracing::info!("serving metrics at http://{}/metrics", bound);
+    let labels = [KeyValue::new("address", bound)];
+    meter
+        .u64_value_observer("metrics_listen_info", move |res: ObserverResult<u64>| {
+            res.observe(1, &labels);
+        })
+        .init();
+    Ok(listener)
+}
+
+/// `/metrics` from `exporter`, in the text format, to whoever connects to
+/// `listener`, one at a time until `stopped` is disconnected.
+pub fn serve_metrics(
+    listener: TcpListener,
+    exporter: MetricsExporter,
+    stopped: flume::Receiver<()>,
+) {
+    loop {
+        if let Err(flume::TryRecvError::Disconnected) = stopped.try_recv() {
+            return;
+        }
+        let mut fds = [PollFd::new(listener.as_raw_fd(), PollFlags::POLLIN)];
+        match nix::poll::poll(&mut fds, SERVE_POLL_MS) {
+            Ok(0) | Err(nix::Error::EINTR) => continue,
+            Ok(_) => {}
+            Err(err) => {
+                tracing::warn!("metrics server stopped, err={:?}", err);
+                return;
+            }
+        }
+        let ret = listener
+            .accept()
+            .and_then(|(stream, _)| respond_scrape(stream, &exporter));
+        if let Err(err) = ret {
+            tracing::debug!("failed to serve a scrape, err={:?}", err);
+        }
+    }
+}
+
+fn respond_scrape(mut stream: TcpStream, exporter: &MetricsExporter) -> io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    // Only the request line matters, the headers are read to be polite.
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") && request.len() < 8192 {
+        match stream.read(&mut buf)? {
+            0 => break,
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, content_type, body) = match path.split('?').next() {
+        Some("/metrics") => {
+            let encoder = prometheus::TextEncoder::new();
+            let mut body = Vec::new();
+            encoder
+                .encode(&exporter.gather(), &mut body)
+                .map_err(io::Error::other)?;
+            ("200 OK", encoder.format_type().to_owned(), body)
+        }
+        _ => (
+            "404 Not Found",
+            "text/plain".to_owned(),
+            b"not found\n".to_vec(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(&body)
+}
+
+/// A value recorder, which records nothing unless the metrics are pushed.
+pub struct Gauge(Option<BoundValueRecorder<'static, u64>>);
+
+impl Gauge {
+    pub fn new(meter: Option<&Meter>, name: &str, labels: &'static [KeyValue]) -> Gauge {
+        Gauge(meter.map(|meter| meter.u64_value_recorder(name).init().bind(labels)))
+    }
+
+    pub fn record(&self, value: u64) {
+        if let Some(recorder) = &self.0 {
+            recorder.record(value);
+        }
+    }
+}
+
+/// Of a value recorder by peer and comm, bound once for each pair. Their
+/// labels are leaked, the exporter keeps a record of each label set anyway.
+pub struct PeerGauges {
+    /// Unless the metrics are not pushed, or not by peer.
+    recorder: Option<ValueRecorder<u64>>,
+    bound: Mutex<HashMap<(String, usize), Arc<Gauge>>>,
+}
+
+impl PeerGauges {
+    pub fn new(meter: Option<&Meter>, name: &str, per_peer: bool) -> PeerGauges {
+        PeerGauges {
+            recorder: meter
+                .filter(|_| per_peer)
+                .map(|meter| meter.u64_value_recorder(name).init()),
+            bound: Default::default(),
+        }
+    }
+
+    /// Labelled `{peer, comm_id}`.
+    pub fn of(&self, peer: String, comm_id: usize) -> Arc<Gauge> {
+        let recorder = match &self.recorder {
+            Some(recorder) => recorder,
+            None => return Arc::new(Gauge(None)),
+        };
+        utils::lock(&self.bound)
+            .entry((peer, comm_id))
+            .or_insert_with_key(|(peer, comm_id)| {
+                let labels = vec![
+                    KeyValue::new("peer", peer.clone()),
+                    KeyValue::new("comm_id", *comm_id as i64),
+                ];
+                Arc::new(Gauge(Some(
+                    recorder.bind(Box::leak(labels.into_boxed_slice())),
+                )))
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_backoff() {
+        let prometheus = PrometheusConfig {
+            user: "".to_owned(),
+            pass: "".to_owned(),
+            address: "127.0.0.1:9091".to_owned(),
+            interval: Duration::from_secs(1),
+        };
+        let delays: Vec<u64> = (0..9).map(|n| prometheus.delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60, 60]);
+        assert_eq!(prometheus.delay(u32::MAX), MAX_PUSH_BACKOFF);
+
+        // Never shorter than the interval.
+        let prometheus = PrometheusConfig {
+            interval: Duration::from_secs(120),
+            ..prometheus
+        };
+        assert_eq!(prometheus.delay(3), Duration::from_secs(120));
+        let prometheus = PrometheusConfig {
+            interval: Duration::ZERO,
+            ..prometheus
+        };
+        assert_eq!(prometheus.delay(5), Duration::ZERO);
+    }
+
+    /// The response to a GET of `path`, headers and all.
+    pub fn scrape(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_listen_metrics() {
+        let (exporter, meters) = TelemetryConfig::default().exporter();
+        let meter = meters.meter;
+        // By another rank on the host.
+        let first = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken = first.local_addr().unwrap();
+        let second = listen_metrics(taken, &meter).unwrap();
+        let addr = second.local_addr().unwrap();
+        assert!(addr.port() > taken.port());
+
+        let (stop, stopped) = flume::bounded::<()>(0);
+        let server = std::thread::spawn(move || serve_metrics(second, exporter, stopped));
+        let response = scrape(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains(&format!("metrics_listen_info{{address=\"{}\"", addr)),
+            "{}",
+            response
+        );
+        assert!(scrape(addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        drop(stop);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_span_exporter() {
+        let mut config = TelemetryConfig::default();
+        assert_eq!(config.span_exporter(), None);
+        config.jaeger_addr = Some("127.0.0.1:14268".to_owned());
+        assert_eq!(
+            config.span_exporter(),
+            Some(SpanExporter::Jaeger("127.0.0.1:14268"))
+        );
+        // Wins over the collector.
+        config.jaeger_agent = Some("127.0.0.1:6831".to_owned());
+        assert_eq!(
+            config.span_exporter(),
+            Some(SpanExporter::JaegerAgent("127.0.0.1:6831"))
+        );
+        // Wins over Jaeger.
+        config.otlp_endpoint = Some("http://127.0.0.1:4317".to_owned());
+        assert_eq!(
+            config.span_exporter(),
+            Some(SpanExporter::Otlp("http://127.0.0.1:4317"))
+        );
+    }
+
+    #[test]
+    fn test_capped_attribute() {
+        assert_eq!(capped_attribute("lo".to_owned()), "lo");
+        let capped = capped_attribute("é".repeat(MAX_ATTRIBUTE_LEN));
+        assert!(capped.len() <= MAX_ATTRIBUTE_LEN && capped.ends_with("é..."));
+    }
+
+    #[test]
+    fn test_parse_sample_ratio() {
+        assert_eq!(parse_sample_ratio("1"), Some(1.));
+        assert_eq!(parse_sample_ratio(" 0.01 "), Some(0.01));
+        assert_eq!(parse_sample_ratio("0"), Some(0.));
+        assert_eq!(parse_sample_ratio("25%"), Some(0.25));
+        assert_eq!(parse_sample_ratio("100 %"), Some(1.));
+        assert_eq!(parse_sample_ratio("1.5"), None);
+        assert_eq!(parse_sample_ratio("-0.1"), None);
+        assert_eq!(parse_sample_ratio("200%"), None);
+        assert_eq!(parse_sample_ratio("NaN"), None);
+        assert_eq!(parse_sample_ratio("half"), None);
+        assert_eq!(parse_sample_ratio(""), None);
+    }
+
+    #[test]
+    fn test_trace_ranks() {
+        let ranks = TraceRanks::parse("0,8,64-71").unwrap();
+        assert!([0, 8, 64, 67, 71].iter().all(|&rank| ranks.contains(rank)));
+        assert!(![-1, 1, 7, 63, 72].iter().any(|&rank| ranks.contains(rank)));
+        let all = TraceRanks::parse("all").unwrap();
+        assert!(all.contains(0) && all.contains(4096) && !all.contains(-1));
+        assert_eq!(TraceRanks::parse("7-0"), None);
+        assert_eq!(TraceRanks::parse("0,x"), None);
+        // As before they were configurable.
+        assert_eq!(TraceRanks::parse("0-7").unwrap(), TraceRanks::default());
+    }
+
+    #[test]
+    fn test_trace_mode() {
+        let mut config = TelemetryConfig {
+            jaeger_addr: Some("127.0.0.1:14268".to_owned()),
+            trace_chunks: true,
+            ..Default::default()
+        };
+        assert_eq!(config.trace, TraceMode::Auto);
+        assert_eq!(config.span_sampler(), Some(SpanSampler::default()));
+        assert!(config.traces_chunks());
+        config.trace = TraceMode::Off;
+        assert_eq!(config.span_sampler(), None);
+        assert!(!config.traces_chunks());
+        // Named lazily, and not at all without a sampler.
+        let span = RequestSpan::start(None, &opentelemetry::Context::new(), || unreachable!());
+        assert!(span.0.is_none() && !span.span_context().is_valid());
+    }
+
+    #[test]
+    fn test_span_sampler() {
+        let sampled = |ratio| {
+            SpanSampler { ratio }
+                .sample(SpanBuilder::from_name("isend-0"))
+                .sampling_result
+                .is_none()
+        };
+        assert!(sampled(1.));
+        assert!(!sampled(0.));
+        let nsampled = (0..10000).filter(|_| sampled(0.1)).count();
+        assert!((500..1500).contains(&nsampled), "{}", nsampled);
+    }
+
+    #[test]
+    fn test_latency_buckets() {
+        let config = TelemetryConfig {
+            latency_buckets_us: vec![1000., 16., 100.],
+            ..Default::default()
+        };
+        assert_eq!(config.latency_buckets(), [16., 100., 1000.]);
+        // The default ones parse.
+        assert!(!TelemetryConfig::from_env().latency_buckets().is_empty());
+    }
+}
@@ -0,0 +1,467 @@
+use crate::connection::Stream;
+use crate::interface::BaguaNetError;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{CertificateError, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Mutual TLS on every stream, both ends present a certificate signed by the
+/// same CA. Needed when gradients cross networks that are not trusted.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub client: Arc<rustls::ClientConfig>,
+    pub server: Arc<rustls::ServerConfig>,
+}
+
+impl TlsConfig {
+    /// `None` unless `BAGUA_NET_TLS_CERT`, `BAGUA_NET_TLS_KEY` and
+    /// `BAGUA_NET_TLS_CA` name PEM files, setting only some of them is an
+    /// error.
+    pub fn from_env() -> Result<Option<TlsConfig>, BaguaNetError> {
+        let paths: Vec<Option<String>> = [
+            "BAGUA_NET_TLS_CERT",
+            "BAGUA_NET_TLS_KEY",
+            "BAGUA_NET_TLS_CA",
+        ]
+        .iter()
+        .map(|name| std::env::var(name).ok().filter(|path| !path.is_empty()))
+        .collect();
+        match &paths[..] {
+            [None, None, None] => Ok(None),
+            [Some(cert), Some(key), Some(ca)] => {
+                let read = |path: &str| {
+                    std::fs::read(path)
+                        .map_err(|err| BaguaNetError::system_io(format!("read {}", path), err))
+                };
+                TlsConfig::from_pem(&read(cert)?, &read(key)?, &read(ca)?).map(Some)
+            }
+            _ => Err(BaguaNetError::InvalidArgument(
+                "BAGUA_NET_TLS_CERT, BAGUA_NET_TLS_KEY and BAGUA_NET_TLS_CA must be set together"
+                    .to_owned(),
+            )),
+        }
+    }
+
+    pub fn from_pem(cert: &[u8], key: &[u8], ca: &[u8]) -> Result<TlsConfig, BaguaNetError> {
+        let invalid = |what: &str, err: String| {
+            BaguaNetError::InvalidArgument(format!("invalid TLS {}, err={}", what, err))
+        };
+        let cert_chain = CertificateDer::pem_slice_iter(cert)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| invalid("certificate", format!("{:?}", err)))?;
+        let key = PrivateKeyDer::from_pem_slice(key)
+            .map_err(|err| invalid("key", format!("{:?}", err)))?;
+        let mut roots = RootCertStore::empty();
+        for ca_cert in CertificateDer::pem_slice_iter(ca) {
+            let ca_cert = ca_cert.map_err(|err| invalid("CA", format!("{:?}", err)))?;
+            roots
+                .add(ca_cert)
+                .map_err(|err| invalid("CA", format!("{:?}", err)))?;
+        }
+        let roots = Arc::new(roots);
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let server_verifier =
+            WebPkiServerVerifier::builder_with_provider(roots.clone(), provider.clone())
+                .build()
+                .map_err(|err| invalid("CA", err.to_string()))?;
+        let client = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| invalid("config", err.to_string()))?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(CaOnlyVerifier {
+                inner: server_verifier,
+            }))
+            .with_client_auth_cert(cert_chain.clone(), key.clone_key())
+            .map_err(|err| invalid("certificate", err.to_string()))?;
+
+        let client_verifier = WebPkiClientVerifier::builder_with_provider(roots, provider.clone())
+            .build()
+            .map_err(|err| invalid("CA", err.to_string()))?;
+        let server = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|err| invalid("config", err.to_string()))?
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(cert_chain, key)
+            .map_err(|err| invalid("certificate", err.to_string()))?;
+
+        Ok(TlsConfig {
+            client: Arc::new(client),
+            server: Arc::new(server),
+        })
+    }
+}
+
+/// Listeners are dialed by address, which their certificates rarely name,
+/// so a server is trusted for any certificate the CA signed.
+#[derive(Debug)]
+struct CaOnlyVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for CaOnlyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        ) {
+            // The chain was checked before the name.
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName))
+            | Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForNameContext {
+                ..
+            })) => Ok(ServerCertVerified::assertion()),
+            verified => verified,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// A stream inside a TLS session. Does not block if the stream does not:
+/// records that did not fit into the socket go out on the next write or
+/// flush.
+#[derive(Debug)]
+pub struct TlsStream {
+    conn: rustls::Connection,
+    pub stream: Stream,
+}
+
+impl TlsStream {
+    /// Runs the handshake as the client, within `timeout`.
+    pub fn connect(
+        stream: Stream,
+        config: &TlsConfig,
+        server_name: ServerName<'static>,
+        timeout: Duration,
+    ) -> io::Result<TlsStream> {
+        let conn = rustls::ClientConnection::new(config.client.clone(), server_name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        TlsStream::handshake(conn.into(), stream, timeout)
+    }
+
+    /// Runs the handshake as the server, within `timeout`.
+    pub fn accept(stream: Stream, config: &TlsConfig, timeout: Duration) -> io::Result<TlsStream> {
+        let conn = rustls::ServerConnection::new(config.server.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        TlsStream::handshake(conn.into(), stream, timeout)
+    }
+
+    /// Starts the handshake as the server on a non-blocking `stream`, see
+    /// `advance_handshake`.
+    pub fn accepting(stream: Stream, config: &TlsConfig) -> io::Result<TlsStream> {
+        let conn = rustls::ServerConnection::new(config.server.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(TlsStream {
+            conn: conn.into(),
+            stream,
+        })
+    }
+
+    /// Continues the handshake until the stream would block, whether it is
+    /// done.
+    pub fn advance_handshake(&mut self) -> io::Result<bool> {
+        while self.conn.is_handshaking() {
+            match self.conn.complete_io(&mut self.stream) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+
+    fn handshake(
+        mut conn: rustls::Connection,
+        mut stream: Stream,
+        timeout: Duration,
+    ) -> io::Result<TlsStream> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(timeout))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        stream.set_read_timeout(None)?;
+
+        Ok(TlsStream { conn, stream })
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.stream)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            // Gives up with the stream's WouldBlock when no record came in.
+            self.conn.read_tls(&mut self.stream)?;
+            if let Err(err) = self.conn.process_new_packets() {
+                // The alert telling the peer why.
+                let _ = self.write_pending();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+            match self.write_pending() {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => result?,
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[io::IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        // Encrypting more before the socket took the last records only grows
+        // the buffer.
+        self.write_pending()?;
+        let n = self.conn.writer().write_vectored(bufs)?;
+        if n == 0 && bufs.iter().any(|buf| !buf.is_empty()) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        match self.write_pending() {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            result => result?,
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.writer().flush()?;
+        self.write_pending()?;
+        self.stream.flush()
+    }
+}
+
+impl AsRawFd for TlsStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.write_pending();
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::utils;
+    use openssl::asn1::{Asn1Integer, Asn1Time};
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::{
+        BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+    };
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::net;
+
+    fn ec_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    /// Signed by `issuer`, self-signed CA if it is `None`.
+    fn certificate(cn: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", cn).unwrap();
+        let name = name.build();
+        let serial = BigNum::from_u32(rand::random::<u32>() >> 1 | 1).unwrap();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&Asn1Integer::from_bn(&serial).unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder
+            .set_issuer_name(issuer.map_or(&name, |(cert, _)| cert.subject_name()))
+            .unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            None => {
+                builder
+                    .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                builder
+                    .append_extension(
+                        KeyUsage::new()
+                            .critical()
+                            .key_cert_sign()
+                            .crl_sign()
+                            .build()
+                            .unwrap(),
+                    )
+                    .unwrap();
+            }
+            Some((issuer_cert, _)) => {
+                builder
+                    .append_extension(BasicConstraints::new().build().unwrap())
+                    .unwrap();
+                builder
+                    .append_extension(KeyUsage::new().digital_signature().build().unwrap())
+                    .unwrap();
+                builder
+                    .append_extension(
+                        ExtendedKeyUsage::new()
+                            .server_auth()
+                            .client_auth()
+                            .build()
+                            .unwrap(),
+                    )
+                    .unwrap();
+                let san = SubjectAlternativeName::new()
+                    .ip("127.0.0.1")
+                    .build(&builder.x509v3_context(Some(issuer_cert), None))
+                    .unwrap();
+                builder.append_extension(san).unwrap();
+            }
+        }
+        builder
+            .sign(issuer.map_or(key, |(_, key)| key), MessageDigest::sha256())
+            .unwrap();
+        builder.build()
+    }
+
+    /// Certificate, key and CA as PEM, of a fresh CA.
+    pub fn pems() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let ca_key = ec_key();
+        let ca = certificate("bagua-net test CA", &ca_key, None);
+        let key = ec_key();
+        let cert = certificate("bagua-net", &key, Some((&ca, &ca_key)));
+
+        (
+            cert.to_pem().unwrap(),
+            key.private_key_to_pem_pkcs8().unwrap(),
+            ca.to_pem().unwrap(),
+        )
+    }
+
+    /// Both ends of a comm can share it.
+    pub fn tls_config() -> TlsConfig {
+        let (cert, key, ca) = pems();
+        TlsConfig::from_pem(&cert, &key, &ca).unwrap()
+    }
+
+    /// The client and server ends of a TLS session over loopback TCP.
+    fn tls_pair(
+        client_config: TlsConfig,
+        server_config: TlsConfig,
+    ) -> (io::Result<TlsStream>, io::Result<TlsStream>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            TlsStream::accept(Stream::Tcp(stream), &server_config, Duration::from_secs(10))
+        });
+        let stream = Stream::Tcp(net::TcpStream::connect(addr).unwrap());
+        let client = TlsStream::connect(
+            stream,
+            &client_config,
+            ServerName::from(addr.ip()),
+            Duration::from_secs(10),
+        );
+
+        (client, server.join().unwrap())
+    }
+
+    #[test]
+    fn test_tls_stream_nonblocking() {
+        let config = tls_config();
+        let (client, server) = tls_pair(config.clone(), config);
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        client.stream.set_nonblocking(true).unwrap();
+        server.stream.set_nonblocking(true).unwrap();
+
+        // Far more than the socket buffers hold, records have to wait.
+        let data: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+        let receiver = std::thread::spawn(move || {
+            let mut buf = vec![0u8; expected.len()];
+            let mut pos = 0;
+            while !utils::try_read_into(&mut server, &mut buf[..], &mut pos).unwrap() {
+                std::thread::yield_now();
+            }
+            assert_eq!(buf, expected);
+        });
+        assert!(matches!(
+            utils::nonblocking_write_all(&mut client, &data[..], utils::WaitMode::SpinThenYield),
+            utils::IoOutcome::Done
+        ));
+        receiver.join().unwrap();
+    }
+
+    #[test]
+    fn test_tls_unknown_ca() {
+        let (client, server) = tls_pair(tls_config(), tls_config());
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn test_from_pem_invalid() {
+        let (cert, key, ca) = pems();
+        assert!(TlsConfig::from_pem(&cert, &key, &ca).is_ok());
+        assert!(TlsConfig::from_pem(&cert, b"not a key", &ca).is_err());
+        assert!(TlsConfig::from_pem(&cert, &key, b"").is_err());
+    }
+}
@@ -1,17 +1,25 @@
 use nix::net::if_::InterfaceFlags;
+use nix::poll::{PollFd, PollFlags};
+use nix::sched::{sched_setaffinity, CpuSet};
 use nix::sys::socket::{AddressFamily, InetAddr, SockAddr};
+use nix::unistd::Pid;
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::io::{Read, Write};
+use std::net;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 pub fn get_net_if_speed(device: &str) -> i32 {
     const DEFAULT_SPEED: i32 = 10000;
 
     let speed_path = format!("/sys/class/net/{}/speed", device);
     match fs::read_to_string(speed_path.clone()) {
-        Ok(speed_str) => {
-            return speed_str.trim().parse().unwrap_or(DEFAULT_SPEED);
-        }
+        Ok(speed_str) => speed_str.trim().parse().unwrap_or(DEFAULT_SPEED),
         Err(_) => {
             tracing::debug!(
                 "Could not get speed from {}. Defaulting to 10 Gbps.",
@@ -22,31 +30,411 @@ pub fn get_net_if_speed(device: &str) -> i32 {
     }
 }
 
+/// Reported for loopback devices, which have no link speed, in Mbps.
+pub const LOOPBACK_SPEED: i32 = 100000;
+
+/// Link speed of `socket_dev` in Mbps.
+pub fn get_socket_dev_speed(socket_dev: &NCCLSocketDev) -> i32 {
+    if is_loopback(&socket_dev.addr) {
+        LOOPBACK_SPEED
+    } else {
+        get_net_if_speed(&socket_dev.interface_name)
+    }
+}
+
+/// CPUs like `0-3,8`, as in `/sys/devices/system/node/node0/cpulist`.
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (first, last): (usize, usize) = match range.split_once('-') {
+            Some((first, last)) => (first.trim().parse().ok()?, last.trim().parse().ok()?),
+            None => {
+                let cpu = range.trim().parse().ok()?;
+                (cpu, cpu)
+            }
+        };
+        if first > last {
+            return None;
+        }
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
+
+/// NUMA node of the PCI device at `pci_path`, as in
+/// `/sys/bus/pci/devices/0000:3b:00.0`, `None` if it is not on one.
+pub fn numa_node_of_pci(pci_path: &str) -> Option<usize> {
+    let numa_node_path = format!("{}/numa_node", pci_path);
+    let node: i64 = fs::read_to_string(&numa_node_path)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    // -1 without NUMA.
+    if node < 0 {
+        tracing::debug!("{} is on no NUMA node", pci_path);
+        return None;
+    }
+    Some(node as usize)
+}
+
+/// NUMA node of the NIC of `socket_dev`, `None` if it is not on one, e.g.
+/// loopback or single-node machines.
+pub fn get_socket_dev_numa_node(socket_dev: &NCCLSocketDev) -> Option<usize> {
+    if socket_dev.pci_path.is_empty() {
+        return None;
+    }
+    numa_node_of_pci(&socket_dev.pci_path)
+}
+
+pub fn get_numa_node_cpus(node: usize) -> Option<Vec<usize>> {
+    let cpulist_path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    parse_cpu_list(&fs::read_to_string(cpulist_path).ok()?)
+}
+
+/// The NUMA nodes of the NICs of `socket_devs`, ascending.
+fn socket_devs_numa_nodes(socket_devs: &[NCCLSocketDev]) -> Vec<usize> {
+    let mut nodes: Vec<usize> = socket_devs
+        .iter()
+        .filter_map(get_socket_dev_numa_node)
+        .collect();
+    nodes.sort_unstable();
+    nodes.dedup();
+    nodes
+}
+
+/// The CPUs each I/O thread may run on, from `BAGUA_NET_THREAD_AFFINITY`,
+/// the threads wrapping around them:
+/// - unset, those of a NUMA node of the NICs of `socket_devs`, one node
+///   after the other.
+/// - `numa`, pinned to one CPU each of those nodes.
+/// - a list like `0-3,8`, pinned to one CPU each of it.
+/// - `none`, any CPU.
+///
+/// Empty, so not restricted, if no node is known.
+pub fn thread_affinity(spec: &str, socket_devs: &[NCCLSocketDev]) -> Vec<Vec<usize>> {
+    let mut cpus = match spec {
+        "none" => return Vec::new(),
+        "" => {
+            let affinity: Vec<Vec<usize>> = socket_devs_numa_nodes(socket_devs)
+                .into_iter()
+                .filter_map(get_numa_node_cpus)
+                .filter(|cpus| !cpus.is_empty())
+                .collect();
+            if !affinity.is_empty() {
+                tracing::info!(
+                    "I/O threads run on the NUMA nodes of the NICs, CPUs {:?}",
+                    affinity
+                );
+            }
+            return affinity;
+        }
+        "numa" => {
+            let cpus: Vec<usize> = socket_devs_numa_nodes(socket_devs)
+                .into_iter()
+                .filter_map(get_numa_node_cpus)
+                .flatten()
+                .collect();
+            if cpus.is_empty() {
+                tracing::warn!("BAGUA_NET_THREAD_AFFINITY=numa, but no NUMA node is known");
+            }
+            cpus
+        }
+        list => parse_cpu_list(list).unwrap_or_else(|| {
+            panic!(
+                "BAGUA_NET_THREAD_AFFINITY={:?}, expected numa or a CPU list like 0-3,8",
+                list
+            )
+        }),
+    };
+    cpus.sort_unstable();
+    cpus.dedup();
+    tracing::info!(
+        "BAGUA_NET_THREAD_AFFINITY={:?} pins to CPUs {:?}",
+        spec,
+        cpus
+    );
+
+    cpus.into_iter().map(|cpu| vec![cpu]).collect()
+}
+
+/// Restricts the calling thread to `cpus`.
+pub fn set_thread_affinity(cpus: &[usize]) -> nix::Result<()> {
+    let mut cpu_set = CpuSet::new();
+    for &cpu in cpus {
+        cpu_set.set(cpu)?;
+    }
+    sched_setaffinity(Pid::from_raw(0), &cpu_set)
+}
+
+/// The threads spawned through it that are still running, e.g. those of a
+/// `BaguaNet` once it is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct LiveThreads(Arc<AtomicUsize>);
+
+impl LiveThreads {
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        struct Running(Arc<AtomicUsize>);
+        impl Drop for Running {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+
+        self.0.fetch_add(1, Ordering::AcqRel);
+        let running = Running(self.0.clone());
+        std::thread::spawn(move || {
+            let _running = running;
+            f()
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// Even if a thread panicked holding it. Of the state whose every update is
+/// whole once stored, e.g. counters or a slot, which is still to be updated
+/// rather than given up on.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Joins `thread` unless it still runs at `deadline`, whether it did.
+pub fn join_until<T>(thread: JoinHandle<T>, deadline: Instant) -> bool {
+    while !thread.is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    let _ = thread.join();
+    true
+}
+
+/// MTU of `device` in bytes, -1 if unknown.
+pub fn get_net_if_mtu(device: &str) -> i32 {
+    let mtu_path = format!("/sys/class/net/{}/mtu", device);
+    if let Some(mtu) = fs::read_to_string(&mtu_path)
+        .ok()
+        .and_then(|mtu_str| mtu_str.trim().parse().ok())
+    {
+        return mtu;
+    }
+    // E.g. in containers without /sys.
+    match ioctl_mtu(device) {
+        Ok(mtu) => mtu,
+        Err(err) => {
+            tracing::debug!("Could not get MTU of {}, err={:?}", device, err);
+            -1
+        }
+    }
+}
+
+fn ioctl_mtu(device: &str) -> io::Result<i32> {
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    if device.len() >= ifr.ifr_name.len() {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(device.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFMTU, &mut ifr) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { ifr.ifr_ifru.ifru_mtu })
+}
+
+/// Typical one-way latency of TCP over a link of `speed` Mbps in
+/// microseconds, socket stacks included, -1 if the speed is unknown.
+pub fn estimate_latency_us(speed: i32) -> f32 {
+    match speed {
+        s if s >= 100000 => 10.0,
+        s if s >= 25000 => 15.0,
+        s if s >= 10000 => 25.0,
+        s if s >= 1000 => 50.0,
+        s if s > 0 => 100.0,
+        _ => -1.0,
+    }
+}
+
+/// Half the median round trip of pings over TCP to a listener on `addr`.
+/// The pings never leave the host, so this is the cost of the socket stack,
+/// a lower bound of the latency to a peer.
+pub fn measure_latency_us(addr: &SockAddr) -> io::Result<f32> {
+    const PINGS: usize = 32;
+    let mut addr = match addr {
+        SockAddr::Inet(inet_addr) => inet_addr.to_std(),
+        others => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot ping {:?}", others),
+            ))
+        }
+    };
+    addr.set_port(0);
+    let listener = net::TcpListener::bind(addr)?;
+    let mut stream =
+        net::TcpStream::connect_timeout(&listener.local_addr()?, Duration::from_secs(1))?;
+    let (mut echo, _) = listener.accept()?;
+    for stream in [&stream, &echo].iter() {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    }
+    let echo = std::thread::spawn(move || {
+        let mut buf = [0u8; 1];
+        while echo.read_exact(&mut buf[..]).is_ok() && echo.write_all(&buf[..]).is_ok() {}
+    });
+
+    let mut rtts = Vec::with_capacity(PINGS);
+    let mut buf = [0u8; 1];
+    for _ in 0..PINGS {
+        let timer = Instant::now();
+        stream.write_all(&buf[..])?;
+        stream.read_exact(&mut buf[..])?;
+        rtts.push(timer.elapsed());
+    }
+    drop(stream);
+    let _ = echo.join();
+    rtts.sort();
+
+    Ok(rtts[PINGS / 2].as_secs_f32() * 1e6 / 2.0)
+}
+
+/// One-way latency to the peers of `socket_dev` in microseconds, measured
+/// with `BAGUA_NET_MEASURE_LATENCY=1`, estimated from the link speed
+/// otherwise, -1 if unknown.
+pub fn get_socket_dev_latency_us(socket_dev: &NCCLSocketDev) -> f32 {
+    let measure: u32 = std::env::var("BAGUA_NET_MEASURE_LATENCY")
+        .unwrap_or("0".to_owned())
+        .parse()
+        .unwrap();
+    if measure != 0 {
+        match measure_latency_us(&socket_dev.addr) {
+            Ok(latency_us) => return latency_us,
+            Err(err) => tracing::warn!(
+                "measure latency of {} failed, err={:?}, estimate it",
+                socket_dev.interface_name,
+                err
+            ),
+        }
+    }
+
+    estimate_latency_us(get_socket_dev_speed(socket_dev))
+}
+
+/// `get_socket_dev_latency_us` of each of `socket_devs` by interface name,
+/// taken once when the net is created: measuring costs a few dozen round
+/// trips and NCCL asks for the properties of a device several times.
+pub fn socket_dev_latencies_us(socket_devs: &[NCCLSocketDev]) -> HashMap<String, f32> {
+    socket_devs
+        .iter()
+        .map(|socket_dev| {
+            (
+                socket_dev.interface_name.clone(),
+                get_socket_dev_latency_us(socket_dev),
+            )
+        })
+        .collect()
+}
+
+fn is_loopback(addr: &SockAddr) -> bool {
+    match addr {
+        SockAddr::Inet(inet_addr) => inet_addr.ip().to_std().is_loopback(),
+        _ => false,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NCCLSocketDev {
     pub interface_name: String,
     pub addr: SockAddr,
+    /// The preferred address of the other family on dual-stack interfaces.
+    pub alt_addr: Option<SockAddr>,
     pub pci_path: String,
 }
 
+impl NCCLSocketDev {
+    /// Keeps the preferred address of the interface in `addr` and the
+    /// preferred one of the other family in `alt_addr`.
+    fn add_addr(&mut self, addr: SockAddr) {
+        let better = addr_preference(&addr) < addr_preference(&self.addr);
+        if addr.family() == self.addr.family() {
+            if better {
+                self.addr = addr;
+            }
+            return;
+        }
+
+        let addr = if better {
+            std::mem::replace(&mut self.addr, addr)
+        } else {
+            addr
+        };
+        // Link-local addresses are only reachable with the connector's own
+        // scope id, they are no use as an alternative.
+        if addr_preference(&addr) >= 2 {
+            return;
+        }
+        match &self.alt_addr {
+            Some(alt_addr) if addr_preference(alt_addr) <= addr_preference(&addr) => {}
+            _ => self.alt_addr = Some(addr),
+        }
+    }
+}
+
+/// Lower is better: IPv4 first, then global IPv6, then link-local IPv6.
+fn addr_preference(addr: &SockAddr) -> u8 {
+    match addr {
+        SockAddr::Inet(InetAddr::V4(_)) => 0,
+        SockAddr::Inet(InetAddr::V6(sin6)) => {
+            let ip = std::net::Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            if ip.segments()[0] & 0xffc0 == 0xfe80 {
+                2
+            } else {
+                1
+            }
+        }
+        _ => 3,
+    }
+}
+
 pub fn find_interfaces() -> Vec<NCCLSocketDev> {
     let nccl_socket_family = std::env::var("NCCL_SOCKET_FAMILY")
         .unwrap_or("-1".to_string())
         .parse::<i32>()
         .unwrap_or(-1);
+    // Single node runs, for development and CI.
+    let allow_loopback = std::env::var("BAGUA_NET_ALLOW_LOOPBACK").unwrap_or("0".to_owned()) == "1";
+    let default_ifname = if allow_loopback {
+        "^docker"
+    } else {
+        "^docker,lo"
+    };
     let nccl_socket_ifname =
-        std::env::var("NCCL_SOCKET_IFNAME").unwrap_or("^docker,lo".to_string());
+        std::env::var("NCCL_SOCKET_IFNAME").unwrap_or(default_ifname.to_string());
     // TODO @shjwudp: support parse sockaddr from NCCL_COMM_ID
 
-    let mut search_not = Vec::<&str>::new();
-    let mut search_exact = Vec::<&str>::new();
-    if nccl_socket_ifname.starts_with("^") {
-        search_not = nccl_socket_ifname[1..].split(",").collect();
-    } else if nccl_socket_ifname.starts_with("=") {
-        search_exact = nccl_socket_ifname[1..].split(",").collect();
-    } else {
-        search_exact = nccl_socket_ifname.split(",").collect();
-    }
+    find_interfaces_with(nccl_socket_family, &nccl_socket_ifname, allow_loopback)
+}
+
+fn find_interfaces_with(
+    nccl_socket_family: i32,
+    nccl_socket_ifname: &str,
+    allow_loopback: bool,
+) -> Vec<NCCLSocketDev> {
+    let ifname_filter = IfnameFilter::parse(nccl_socket_ifname);
 
     let mut socket_devs = Vec::<NCCLSocketDev>::new();
     const MAX_IF_NAME_SIZE: usize = 16;
@@ -57,16 +445,19 @@ pub fn find_interfaces() -> Vec<NCCLSocketDev> {
                 if addr.family() != AddressFamily::Inet && addr.family() != AddressFamily::Inet6 {
                     continue;
                 }
-                if ifaddr.flags.contains(InterfaceFlags::IFF_LOOPBACK) {
+                if ifaddr.flags.contains(InterfaceFlags::IFF_LOOPBACK) && !allow_loopback {
+                    continue;
+                }
+                if nccl_socket_family != -1 && addr.family() as i32 != nccl_socket_family {
                     continue;
                 }
 
-                assert_eq!(ifaddr.interface_name.len() < MAX_IF_NAME_SIZE, true);
-                let found_ifs: Vec<&NCCLSocketDev> = socket_devs
-                    .iter()
-                    .filter(|scoket_dev| scoket_dev.interface_name == ifaddr.interface_name)
-                    .collect();
-                if found_ifs.len() > 0 {
+                assert!(ifaddr.interface_name.len() < MAX_IF_NAME_SIZE);
+                if let Some(socket_dev) = socket_devs
+                    .iter_mut()
+                    .find(|socket_dev| socket_dev.interface_name == ifaddr.interface_name)
+                {
+                    socket_dev.add_addr(addr);
                     continue;
                 }
 
@@ -77,9 +468,10 @@ pub fn find_interfaces() -> Vec<NCCLSocketDev> {
                 };
 
                 socket_devs.push(NCCLSocketDev {
-                    addr: addr,
+                    addr,
+                    alt_addr: None,
                     interface_name: ifaddr.interface_name.clone(),
-                    pci_path: pci_path,
+                    pci_path,
                 })
             }
             None => {
@@ -91,90 +483,422 @@ pub fn find_interfaces() -> Vec<NCCLSocketDev> {
         }
     }
 
-    let search_not = &mut search_not;
-    let search_exact = &mut search_exact;
-    let socket_devs = socket_devs
-        .iter()
-        .filter({
-            |socket_dev| -> bool {
-                let (sockaddr, _) = socket_dev.addr.as_ffi_pair();
-                if nccl_socket_family != -1 && sockaddr.sa_family as i32 != nccl_socket_family {
-                    return false;
-                }
+    // Real NICs first, so that they keep their device ids.
+    socket_devs.sort_by_key(|socket_dev| is_loopback(&socket_dev.addr));
+    socket_devs
+        .into_iter()
+        .filter(|socket_dev| ifname_filter.matches(&socket_dev.interface_name))
+        .collect()
+}
 
-                for not_interface in &*search_not {
-                    if socket_dev.interface_name.starts_with(not_interface) {
-                        return false;
-                    }
-                }
-                if !(&*search_exact).is_empty() {
-                    let mut ok = false;
-                    for exact_interface in &*search_exact {
-                        if socket_dev.interface_name.starts_with(exact_interface) {
-                            ok = true;
-                            break;
-                        }
-                    }
-                    if !ok {
-                        return false;
-                    }
-                }
+/// Interface selection with the syntax of `NCCL_SOCKET_IFNAME`: a comma
+/// separated list of name prefixes, a leading `^` excludes the listed
+/// interfaces and a leading `=` (after `^`, if any) matches exact names.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IfnameFilter {
+    exclude: bool,
+    exact: bool,
+    names: Vec<String>,
+}
 
-                return true;
+impl IfnameFilter {
+    pub fn parse(spec: &str) -> IfnameFilter {
+        let mut spec = spec.trim();
+        let exclude = match spec.strip_prefix('^') {
+            Some(rest) => {
+                spec = rest;
+                true
             }
-        })
-        .cloned()
-        .collect();
+            None => false,
+        };
+        let exact = match spec.strip_prefix('=') {
+            Some(rest) => {
+                spec = rest;
+                true
+            }
+            None => false,
+        };
+        let names = spec
+            .split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_owned())
+            .collect();
 
-    socket_devs
+        IfnameFilter {
+            exclude,
+            exact,
+            names,
+        }
+    }
+
+    pub fn matches(&self, interface_name: &str) -> bool {
+        if self.names.is_empty() {
+            return true;
+        }
+
+        let listed = self.names.iter().any(|name| {
+            if self.exact {
+                interface_name == name
+            } else {
+                interface_name.starts_with(name.as_str())
+            }
+        });
+
+        listed != self.exclude
+    }
+}
+
+/// Applies `BAGUA_NET_SOCKET_IFNAME` on top of `find_interfaces()`.
+pub fn filter_socket_devs(socket_devs: Vec<NCCLSocketDev>, spec: &str) -> Vec<NCCLSocketDev> {
+    let ifname_filter = IfnameFilter::parse(spec);
+    let (kept, dropped): (Vec<NCCLSocketDev>, Vec<NCCLSocketDev>) = socket_devs
+        .into_iter()
+        .partition(|socket_dev| ifname_filter.matches(&socket_dev.interface_name));
+
+    let names = |socket_devs: &[NCCLSocketDev]| -> Vec<String> {
+        socket_devs
+            .iter()
+            .map(|socket_dev| socket_dev.interface_name.clone())
+            .collect()
+    };
+    tracing::info!(
+        "BAGUA_NET_SOCKET_IFNAME={:?} kept interfaces {:?}, dropped {:?}",
+        spec,
+        names(&kept),
+        names(&dropped)
+    );
+
+    kept
+}
+
+/// How the `nonblocking_*` helpers wait for a stream that would block, from
+/// `BAGUA_NET_WAIT_MODE`: `spin`, `yield` or `poll`.
+///
+/// The send and receive drivers wait on epoll or io_uring event loops, and
+/// an idle comm burns no CPU whatever the mode. It only applies to the writes
+/// that run on a connecting thread: the replay onto a replaced data stream
+/// and the handshake of a revived comm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitMode {
+    /// Retries right away, at the cost of a core per waiting thread.
+    Spin,
+    /// Spins for a while, then yields the core to other threads.
+    SpinThenYield,
+    /// Sleeps in poll(2) until the stream is ready, at most the timeout.
+    Poll(Duration),
 }
 
-pub fn nonblocking_write_all(stream: &mut std::net::TcpStream, mut buf: &[u8]) -> io::Result<()> {
-    while !buf.is_empty() {
-        match stream.write(buf) {
+impl WaitMode {
+    const SPINS: usize = 64;
+
+    pub fn from_env() -> WaitMode {
+        match std::env::var("BAGUA_NET_WAIT_MODE")
+            .unwrap_or("yield".to_owned())
+            .as_str()
+        {
+            "spin" => WaitMode::Spin,
+            "yield" => WaitMode::SpinThenYield,
+            "poll" => WaitMode::Poll(Duration::from_millis(
+                std::env::var("BAGUA_NET_WAIT_POLL_TIMEOUT_MS")
+                    .unwrap_or("10".to_owned())
+                    .parse()
+                    .unwrap(),
+            )),
+            others => panic!(
+                "BAGUA_NET_WAIT_MODE={:?}, expected spin, yield or poll",
+                others
+            ),
+        }
+    }
+
+    /// Waits for `fd` to be ready for `events`, the `nwaits`-th time in a
+    /// row.
+    fn wait(self, fd: RawFd, events: PollFlags, nwaits: &mut usize) {
+        *nwaits += 1;
+        match self {
+            WaitMode::Spin => std::hint::spin_loop(),
+            WaitMode::SpinThenYield if *nwaits < WaitMode::SPINS => std::hint::spin_loop(),
+            WaitMode::SpinThenYield => std::thread::yield_now(),
+            WaitMode::Poll(timeout) => {
+                // Errors and hangups show up on the next call, a signal only
+                // cuts the wait short.
+                let mut fds = [PollFd::new(fd, events)];
+                let _ = nix::poll::poll(&mut fds[..], timeout.as_millis() as libc::c_int);
+            }
+        }
+    }
+}
+
+/// Of the `nonblocking_*` helpers, which retry the calls that were
+/// interrupted or would block until the whole buffer is moved.
+#[derive(Debug)]
+pub enum IoOutcome {
+    Done,
+    /// The peer closed its end, or reset it, before the whole buffer was
+    /// moved. Another stream to it may still do.
+    PeerClosed,
+    /// Of the stream itself, no use retrying on it.
+    Fatal(io::Error),
+}
+
+impl From<io::Result<()>> for IoOutcome {
+    fn from(result: io::Result<()>) -> IoOutcome {
+        match result {
+            Ok(()) => IoOutcome::Done,
+            Err(err) => match err.kind() {
+                io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::WriteZero
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted => IoOutcome::PeerClosed,
+                _ => IoOutcome::Fatal(err),
+            },
+        }
+    }
+}
+
+pub fn nonblocking_write_all<W: Write + AsRawFd>(
+    stream: &mut W,
+    buf: &[u8],
+    wait_mode: WaitMode,
+) -> IoOutcome {
+    nonblocking_write_from(stream, buf, &mut 0, wait_mode).into()
+}
+
+/// Writes `buf[*pos..]` and flushes it, e.g. the last TLS records, `pos` is
+/// past what was written even if it fails.
+pub fn nonblocking_write_from<W: Write + AsRawFd>(
+    stream: &mut W,
+    buf: &[u8],
+    pos: &mut usize,
+    wait_mode: WaitMode,
+) -> io::Result<()> {
+    let mut nwaits = 0;
+    while *pos < buf.len() {
+        match stream.write(&buf[*pos..]) {
             Ok(0) => {
                 return Err(io::Error::new(
                     io::ErrorKind::WriteZero,
                     "failed to write whole buffer",
                 ));
             }
-            Ok(n) => buf = &buf[n..],
-            Err(ref e)
-                if e.kind() == io::ErrorKind::Interrupted
-                    || e.kind() == io::ErrorKind::WouldBlock => {}
+            Ok(n) => {
+                *pos += n;
+                nwaits = 0;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                wait_mode.wait(stream.as_raw_fd(), PollFlags::POLLOUT, &mut nwaits)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    loop {
+        match stream.flush() {
+            Ok(()) => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                wait_mode.wait(stream.as_raw_fd(), PollFlags::POLLOUT, &mut nwaits)
+            }
             Err(e) => return Err(e),
         }
-        std::thread::yield_now();
     }
-    Ok(())
 }
 
-pub fn nonblocking_read_exact(
-    stream: &mut std::net::TcpStream,
-    mut buf: &mut [u8],
-) -> io::Result<()> {
-    while !buf.is_empty() {
-        match stream.read(buf) {
-            Ok(0) => break,
-            Ok(n) => {
-                let tmp = buf;
-                buf = &mut tmp[n..];
+/// Like `nonblocking_write_from`, but gives up with `Ok(false)` once the
+/// stream would block, e.g. to wait for it on an event loop.
+pub fn try_write_from<W: Write>(stream: &mut W, buf: &[u8], pos: &mut usize) -> io::Result<bool> {
+    while *pos < buf.len() {
+        match stream.write(&buf[*pos..]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ));
             }
-            Err(ref e)
-                if e.kind() == io::ErrorKind::Interrupted
-                    || e.kind() == io::ErrorKind::WouldBlock => {}
+            Ok(n) => *pos += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
             Err(e) => return Err(e),
         }
-        std::thread::yield_now();
     }
-    if !buf.is_empty() {
-        Err(io::Error::new(
-            io::ErrorKind::UnexpectedEof,
-            "failed to fill whole buffer",
-        ))
-    } else {
-        Ok(())
+    loop {
+        match stream.flush() {
+            Ok(()) => return Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Writes `bufs` as one buffer, e.g. both parts of a replay window, in as
+/// few calls as the stream takes.
+pub fn nonblocking_write_all_vectored<W: Write + AsRawFd>(
+    stream: &mut W,
+    bufs: &[&[u8]],
+    wait_mode: WaitMode,
+) -> IoOutcome {
+    let (mut pos, mut nwaits) = (0, 0);
+    loop {
+        match try_write_vectored_from(stream, bufs, &mut pos) {
+            Ok(true) => return IoOutcome::Done,
+            Ok(false) => wait_mode.wait(stream.as_raw_fd(), PollFlags::POLLOUT, &mut nwaits),
+            Err(err) => return Err(err).into(),
+        }
+    }
+}
+
+/// Like `try_write_from` over `bufs` as one buffer, `pos` counts what was
+/// written of all of them.
+pub fn try_write_vectored_from<W: Write>(
+    stream: &mut W,
+    bufs: &[&[u8]],
+    pos: &mut usize,
+) -> io::Result<bool> {
+    let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+    while *pos < total {
+        let mut skip = *pos;
+        let slices: Vec<io::IoSlice> = bufs
+            .iter()
+            .filter_map(|buf| {
+                if skip >= buf.len() {
+                    skip -= buf.len();
+                    return None;
+                }
+                let slice = io::IoSlice::new(&buf[skip..]);
+                skip = 0;
+                Some(slice)
+            })
+            .collect();
+        match stream.write_vectored(&slices[..]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ));
+            }
+            Ok(n) => *pos += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+    try_write_from(stream, &[], &mut 0)
+}
+
+/// Fills `buf[*pos..]`, gives up with `Ok(false)` once the stream would
+/// block. `pos` is past what was read even if it fails.
+pub fn try_read_into<R: Read>(stream: &mut R, buf: &mut [u8], pos: &mut usize) -> io::Result<bool> {
+    while *pos < buf.len() {
+        match stream.read(&mut buf[*pos..]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ));
+            }
+            Ok(n) => *pos += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Fills `buf`, e.g. with a reply that is due on a stream that is served
+/// by an event loop otherwise.
+#[allow(dead_code)]
+pub fn nonblocking_read_exact<R: Read + AsRawFd>(
+    stream: &mut R,
+    buf: &mut [u8],
+    wait_mode: WaitMode,
+) -> IoOutcome {
+    let (mut pos, mut nwaits) = (0, 0);
+    loop {
+        let read = pos;
+        match try_read_into(stream, buf, &mut pos) {
+            Ok(true) => return IoOutcome::Done,
+            Ok(false) => {
+                if pos > read {
+                    nwaits = 0;
+                }
+                wait_mode.wait(stream.as_raw_fd(), PollFlags::POLLIN, &mut nwaits)
+            }
+            Err(err) => return Err(err).into(),
+        }
+    }
+}
+
+/// Like `try_read_into` over `bufs` as one buffer, `pos` counts what was
+/// read into all of them.
+pub fn try_read_vectored_into<R: Read>(
+    stream: &mut R,
+    bufs: &mut [&mut [u8]],
+    pos: &mut usize,
+) -> io::Result<bool> {
+    let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+    while *pos < total {
+        let mut skip = *pos;
+        let mut slices: Vec<io::IoSliceMut> = bufs
+            .iter_mut()
+            .filter_map(|buf| {
+                if skip >= buf.len() {
+                    skip -= buf.len();
+                    return None;
+                }
+                let slice = io::IoSliceMut::new(&mut buf[skip..]);
+                skip = 0;
+                Some(slice)
+            })
+            .collect();
+        match stream.read_vectored(&mut slices[..]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ));
+            }
+            Ok(n) => *pos += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
     }
+    Ok(true)
+}
+
+/// Of the rank of the process, in the order `detect_rank` looks them up:
+/// torchrun and the Bagua launcher, OpenMPI, PMIx, MPICH and the other PMI
+/// launchers, then Slurm.
+pub const RANK_VARS: [&str; 5] = [
+    "RANK",
+    "OMPI_COMM_WORLD_RANK",
+    "PMIX_RANK",
+    "PMI_RANK",
+    "SLURM_PROCID",
+];
+
+/// From the first of `RANK_VARS` set to a rank, -1 if none is. A value that
+/// is not a rank is skipped.
+pub fn detect_rank() -> i32 {
+    for &var in RANK_VARS.iter() {
+        let value = match std::env::var(var) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        match value.trim().parse::<i32>() {
+            Ok(rank) if rank >= 0 => {
+                tracing::info!("rank {} from {}", rank, var);
+                return rank;
+            }
+            _ => tracing::warn!("{}={:?} is not a rank, skipped", var, value),
+        }
+    }
+    tracing::info!("no rank in any of {:?}, -1", RANK_VARS);
+    -1
 }
 
 pub fn parse_user_pass_and_addr(raw_url: &str) -> Option<(String, String, String)> {
@@ -197,11 +921,20 @@ pub fn parse_user_pass_and_addr(raw_url: &str) -> Option<(String, String, String
     }
 }
 
-pub fn chunk_size(total: usize, min_chunksize: usize, expected_nchunks: usize) -> usize {
-    let chunk_size = (total + expected_nchunks - 1) / expected_nchunks;
-    let chunk_size = std::cmp::max(chunk_size, min_chunksize);
+/// Messages over `min_chunksize` are cut in chunks of `chunk_bytes`, so that
+/// the streams pipeline them, or in `expected_nchunks` if it is 0.
+pub fn chunk_size(
+    total: usize,
+    min_chunksize: usize,
+    chunk_bytes: usize,
+    expected_nchunks: usize,
+) -> usize {
+    if chunk_bytes > 0 && total > min_chunksize {
+        return chunk_bytes;
+    }
+    let chunk_size = total.div_ceil(expected_nchunks);
 
-    chunk_size
+    std::cmp::max(chunk_size, min_chunksize)
 }
 
 /// Creates a `SockAddr` struct from libc's sockaddr.
@@ -265,6 +998,75 @@ mod tests {
     use super::*;
     use nix::sys::socket::{InetAddr, IpAddr, SockAddr};
 
+    lazy_static! {
+        /// Of the tests that set variables, which the process shares.
+        static ref ENV: Mutex<()> = Mutex::new(());
+    }
+
+    /// Sets the variables to their values, or unsets them, until it is
+    /// dropped, which puts back what they were.
+    struct ScopedEnv {
+        saved: Vec<(&'static str, Option<String>)>,
+        _locked: MutexGuard<'static, ()>,
+    }
+
+    impl ScopedEnv {
+        fn new(vars: &[(&'static str, Option<&str>)]) -> ScopedEnv {
+            let locked = lock(&ENV);
+            let saved = vars
+                .iter()
+                .map(|&(var, value)| {
+                    let saved = std::env::var(var).ok();
+                    match value {
+                        Some(value) => std::env::set_var(var, value),
+                        None => std::env::remove_var(var),
+                    }
+                    (var, saved)
+                })
+                .collect();
+            ScopedEnv {
+                saved,
+                _locked: locked,
+            }
+        }
+    }
+
+    impl Drop for ScopedEnv {
+        fn drop(&mut self) {
+            for (var, saved) in self.saved.drain(..) {
+                match saved {
+                    Some(value) => std::env::set_var(var, value),
+                    None => std::env::remove_var(var),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_detect_rank() {
+        let ranks = |values: [Option<&str>; 5]| {
+            let vars: Vec<_> = RANK_VARS
+                .iter()
+                .copied()
+                .zip(values.iter().copied())
+                .collect();
+            let _env = ScopedEnv::new(&vars);
+            detect_rank()
+        };
+        assert_eq!(ranks([None; 5]), -1);
+        assert_eq!(ranks([Some("3"), Some("4"), None, Some("5"), Some("6")]), 3);
+        assert_eq!(ranks([None, Some("4"), Some("7"), Some("5"), Some("6")]), 4);
+        assert_eq!(ranks([None, None, Some("7"), Some("5"), Some("6")]), 7);
+        assert_eq!(ranks([None, None, None, Some("5"), Some("6")]), 5);
+        assert_eq!(ranks([None, None, None, None, Some("6")]), 6);
+        // Falls through the malformed ones.
+        assert_eq!(
+            ranks([Some("x"), Some(""), Some("-2"), None, Some(" 6\n")]),
+            6
+        );
+        assert_eq!(ranks([Some("1.5"), None, None, None, None]), -1);
+    }
+
     #[test]
     fn test_parse() {
         let username = "nagle";
@@ -295,13 +1097,187 @@ mod tests {
         assert_eq!(addr.to_str(), "127.0.0.1:8123");
     }
 
+    #[test]
+    fn test_socket_handle_v6() {
+        let std_addr: std::net::SocketAddr = "[fe80::1%3]:8123".parse().unwrap();
+        let addr = SockAddr::new_inet(InetAddr::from_std(&std_addr));
+        let addr = unsafe {
+            let (c_sockaddr, _) = addr.as_ffi_pair();
+            from_libc_sockaddr(c_sockaddr).unwrap()
+        };
+
+        match addr {
+            SockAddr::Inet(inet) => assert_eq!(inet.to_std(), std_addr),
+            _ => panic!("unexpected address {:?}", addr),
+        }
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpu_list("5"), Some(vec![5]));
+        assert_eq!(parse_cpu_list(""), Some(vec![]));
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("a-b"), None);
+
+        let no_numa = NCCLSocketDev {
+            interface_name: "lo".to_owned(),
+            addr: SockAddr::new_inet(InetAddr::from_std(&"127.0.0.1:0".parse().unwrap())),
+            alt_addr: None,
+            pci_path: "".to_owned(),
+        };
+        let socket_devs = [no_numa];
+        assert!(thread_affinity("", &socket_devs).is_empty());
+        assert!(thread_affinity("none", &socket_devs).is_empty());
+        assert!(thread_affinity("numa", &socket_devs).is_empty());
+        assert_eq!(
+            thread_affinity("3,1-2,2", &socket_devs),
+            vec![vec![1], vec![2], vec![3]]
+        );
+    }
+
+    #[test]
+    fn test_numa_node_of_pci() {
+        let pci_path = std::env::temp_dir().join(format!("bagua-net-pci-{}", std::process::id()));
+        fs::create_dir_all(&pci_path).unwrap();
+        let pci_path_str = pci_path.to_str().unwrap();
+        assert_eq!(numa_node_of_pci(pci_path_str), None);
+        for (numa_node, expected) in [("1\n", Some(1)), ("-1\n", None), ("?", None)] {
+            fs::write(pci_path.join("numa_node"), numa_node).unwrap();
+            assert_eq!(numa_node_of_pci(pci_path_str), expected);
+        }
+        fs::remove_dir_all(&pci_path).unwrap();
+    }
+
+    #[test]
+    fn test_addr_preference() {
+        let pref = |s: &str| {
+            let addr: std::net::SocketAddr = s.parse().unwrap();
+            addr_preference(&SockAddr::new_inet(InetAddr::from_std(&addr)))
+        };
+
+        assert!(pref("192.0.2.2:0") < pref("[fd00::2]:0"));
+        assert!(pref("[fd00::2]:0") < pref("[fe80::1%2]:0"));
+    }
+
+    #[test]
+    fn test_socket_dev_alt_addr() {
+        let inet = |s: &str| {
+            let addr: std::net::SocketAddr = s.parse().unwrap();
+            SockAddr::new_inet(InetAddr::from_std(&addr))
+        };
+        let mut socket_dev = NCCLSocketDev {
+            interface_name: "eth0".to_owned(),
+            addr: inet("[fe80::1%2]:0"),
+            alt_addr: None,
+            pci_path: "".to_owned(),
+        };
+
+        socket_dev.add_addr(inet("[fd00::2]:0"));
+        assert_eq!(socket_dev.addr.to_str(), "[fd00::2]:0");
+        assert!(socket_dev.alt_addr.is_none());
+
+        socket_dev.add_addr(inet("192.0.2.2:0"));
+        assert_eq!(socket_dev.addr.to_str(), "192.0.2.2:0");
+        assert_eq!(socket_dev.alt_addr.unwrap().to_str(), "[fd00::2]:0");
+
+        let mut socket_dev = NCCLSocketDev {
+            alt_addr: None,
+            ..socket_dev
+        };
+        socket_dev.add_addr(inet("[fe80::1%2]:0"));
+        assert!(socket_dev.alt_addr.is_none());
+    }
+
+    #[test]
+    fn test_find_interfaces_loopback() {
+        let has_lo = |socket_devs: &[NCCLSocketDev]| {
+            socket_devs
+                .iter()
+                .any(|socket_dev| is_loopback(&socket_dev.addr))
+        };
+        assert!(!has_lo(&find_interfaces_with(-1, "", false)));
+
+        let socket_devs = find_interfaces_with(-1, "", true);
+        assert!(has_lo(&socket_devs));
+        assert!(is_loopback(&socket_devs.last().unwrap().addr));
+
+        let lo = find_interfaces_with(libc::AF_INET, "=lo", true);
+        assert_eq!(lo.len(), 1);
+        assert_eq!(lo[0].interface_name, "lo");
+        assert_eq!(get_socket_dev_speed(&lo[0]), LOOPBACK_SPEED);
+    }
+
+    #[test]
+    fn test_net_if_mtu() {
+        let mtu = get_net_if_mtu("lo");
+        assert!(mtu > 0);
+        assert_eq!(ioctl_mtu("lo").unwrap(), mtu);
+        assert_eq!(get_net_if_mtu("bagua-none"), -1);
+    }
+
+    #[test]
+    fn test_latency() {
+        assert_eq!(estimate_latency_us(0), -1.0);
+        assert!(estimate_latency_us(100000) < estimate_latency_us(1000));
+
+        let addr = SockAddr::new_inet(InetAddr::from_std(&"127.0.0.1:0".parse().unwrap()));
+        let latency_us = measure_latency_us(&addr).unwrap();
+        assert!(latency_us > 0.0 && latency_us < 1e6, "{}", latency_us);
+    }
+
+    #[test]
+    fn test_ifname_filter() {
+        let prefix = IfnameFilter::parse("eth,ib");
+        assert!(prefix.matches("eth0"));
+        assert!(prefix.matches("ib1"));
+        assert!(!prefix.matches("eno1"));
+
+        let exclude = IfnameFilter::parse("^docker,lo");
+        assert!(exclude.matches("eth0"));
+        assert!(!exclude.matches("docker0"));
+        assert!(!exclude.matches("lo"));
+
+        let exact = IfnameFilter::parse("=eth1");
+        assert!(exact.matches("eth1"));
+        assert!(!exact.matches("eth10"));
+
+        let exclude_exact = IfnameFilter::parse("^=eth1");
+        assert!(!exclude_exact.matches("eth1"));
+        assert!(exclude_exact.matches("eth10"));
+
+        assert!(IfnameFilter::parse("").matches("eno1"));
+    }
+
+    #[test]
+    fn test_filter_socket_devs() {
+        let addr: std::net::SocketAddr = "192.0.2.2:0".parse().unwrap();
+        let socket_dev = |name: &str| NCCLSocketDev {
+            interface_name: name.to_owned(),
+            addr: SockAddr::new_inet(InetAddr::from_std(&addr)),
+            alt_addr: None,
+            pci_path: "".to_owned(),
+        };
+        let socket_devs = vec![socket_dev("eno1"), socket_dev("ib0"), socket_dev("ib1")];
+
+        let kept: Vec<String> = filter_socket_devs(socket_devs.clone(), "^eno")
+            .into_iter()
+            .map(|socket_dev| socket_dev.interface_name)
+            .collect();
+        assert_eq!(kept, vec!["ib0", "ib1"]);
+        assert_eq!(filter_socket_devs(socket_devs, "").len(), 3);
+    }
+
     #[test]
     fn test_chunks() {
         let chunks = |total: usize, min_chunksize: usize, expected_nchunks: usize| -> usize {
-            let size = chunk_size(total, min_chunksize, expected_nchunks);
+            let size = chunk_size(total, min_chunksize, 0, expected_nchunks);
 
             let mut chunk_count = total / size;
-            if total % size != 0 {
+            if !total.is_multiple_of(size) {
                 chunk_count += 1;
             }
 
@@ -310,5 +1286,325 @@ mod tests {
 
         assert_eq!(chunks(1024, 1, 20), 20);
         assert_eq!(chunks(1024, 1000, 20), 2);
+        assert_eq!(chunk_size(4 << 20, 1 << 20, 0, 4), 1 << 20);
+        assert_eq!(chunk_size(4 << 20, 1, 0, 4), 1 << 20);
+        assert_eq!(chunks((4 << 20) + 1, 1, 4), 4);
+
+        // Pipelined, in more chunks than streams unless under the threshold.
+        assert_eq!(chunk_size(64 << 20, 1 << 20, 256 << 10, 4), 256 << 10);
+        assert_eq!(chunk_size(1 << 20, 1 << 20, 256 << 10, 4), 1 << 20);
+        assert_eq!(chunk_size((1 << 20) + 1, 1 << 20, 256 << 10, 4), 256 << 10);
+    }
+
+    /// Takes at most `limit` bytes a call, and would block every other call.
+    struct Throttled {
+        stream: std::os::unix::net::UnixStream,
+        limit: usize,
+        blocked: bool,
+        ncalls: usize,
+    }
+
+    impl Write for Throttled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[io::IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+            self.blocked = !self.blocked;
+            if self.blocked {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.ncalls += 1;
+            let mut left = self.limit;
+            let mut slices = Vec::new();
+            for buf in bufs {
+                let n = std::cmp::min(left, buf.len());
+                slices.push(io::IoSlice::new(&buf[..n]));
+                left -= n;
+            }
+            self.stream.write_vectored(&slices[..])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.stream.flush()
+        }
+    }
+
+    impl AsRawFd for Throttled {
+        fn as_raw_fd(&self) -> RawFd {
+            self.stream.as_raw_fd()
+        }
+    }
+
+    #[test]
+    fn test_write_all_vectored() {
+        let header = 5usize.to_be_bytes();
+        let payload = b"hello";
+        let expected: Vec<u8> = header.iter().chain(payload.iter()).cloned().collect();
+        for &limit in [1, 7, 8, 9, 13].iter() {
+            let (stream, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+            let mut throttled = Throttled {
+                stream,
+                limit,
+                blocked: false,
+                ncalls: 0,
+            };
+            assert!(matches!(
+                nonblocking_write_all_vectored(
+                    &mut throttled,
+                    &[&header[..], &payload[..]],
+                    WaitMode::SpinThenYield,
+                ),
+                IoOutcome::Done
+            ));
+            assert_eq!(throttled.ncalls, expected.len().div_ceil(limit));
+
+            let mut received = vec![0u8; expected.len()];
+            peer.read_exact(&mut received[..]).unwrap();
+            assert_eq!(received, expected, "limit={}", limit);
+        }
+    }
+
+    /// CPU time of this thread while it writes to a socket that is full
+    /// until the peer drains it after `blocked`.
+    fn blocked_write_cpu_time(wait_mode: WaitMode, blocked: Duration) -> Duration {
+        let (mut stream, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut pos = 0;
+        let filler = vec![0u8; 1 << 20];
+        while try_write_from(&mut stream, &filler[..], &mut pos).unwrap() {
+            pos = 0;
+        }
+        let drained = std::thread::spawn(move || {
+            std::thread::sleep(blocked);
+            io::copy(&mut peer, &mut io::sink()).unwrap()
+        });
+
+        let cpu_time = || {
+            let now = nix::time::clock_gettime(nix::time::ClockId::CLOCK_THREAD_CPUTIME_ID);
+            Duration::from(now.unwrap())
+        };
+        let start = cpu_time();
+        assert!(matches!(
+            nonblocking_write_all(&mut stream, &[1u8; 64][..], wait_mode),
+            IoOutcome::Done
+        ));
+        let spent = cpu_time() - start;
+        drop(stream);
+        drained.join().unwrap();
+        spent
+    }
+
+    #[test]
+    fn test_wait_mode_cpu_time() {
+        // A write blocked on a full socket rather than an idle recv comm: the
+        // event loops of the comms do not spin, so the mode only costs CPU on
+        // the writes of a connecting thread like this one.
+        let blocked = Duration::from_millis(200);
+        let spin = blocked_write_cpu_time(WaitMode::Spin, blocked);
+        let spin_then_yield = blocked_write_cpu_time(WaitMode::SpinThenYield, blocked);
+        let poll = blocked_write_cpu_time(WaitMode::Poll(Duration::from_millis(10)), blocked);
+        println!(
+            "cpu time while blocked for {:?}: spin={:?} yield={:?} poll={:?}",
+            blocked, spin, spin_then_yield, poll
+        );
+        // Only threads waiting for the core make yielding cheaper.
+        assert!(poll * 4 < spin, "spin={:?} poll={:?}", spin, poll);
+        assert!(
+            poll * 4 < spin_then_yield,
+            "yield={:?} poll={:?}",
+            spin_then_yield,
+            poll
+        );
+    }
+
+    #[test]
+    fn test_read_exact_dribbled() {
+        let wait_modes = [
+            WaitMode::Spin,
+            WaitMode::SpinThenYield,
+            WaitMode::Poll(Duration::from_millis(10)),
+        ];
+        for &wait_mode in wait_modes.iter() {
+            let (mut stream, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+            stream.set_nonblocking(true).unwrap();
+            let writer = std::thread::spawn(move || {
+                for i in 0..64u8 {
+                    peer.write_all(&[i][..]).unwrap();
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            });
+            let mut buf = [0u8; 64];
+            let outcome = nonblocking_read_exact(&mut stream, &mut buf[..], wait_mode);
+            assert!(matches!(outcome, IoOutcome::Done), "{:?}", outcome);
+            assert!(buf.iter().enumerate().all(|(i, &b)| b == i as u8));
+            writer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_exact_interrupted() {
+        static SIGNALED: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn on_signal(_: libc::c_int) {
+            SIGNALED.fetch_add(1, Ordering::Relaxed);
+        }
+        // Without SA_RESTART, the poll of the reader fails with EINTR.
+        let action = nix::sys::signal::SigAction::new(
+            nix::sys::signal::SigHandler::Handler(on_signal),
+            nix::sys::signal::SaFlags::empty(),
+            nix::sys::signal::SigSet::empty(),
+        );
+        unsafe { nix::sys::signal::sigaction(nix::sys::signal::SIGUSR1, &action) }.unwrap();
+
+        let (mut stream, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let (sender, outcome) = flume::unbounded();
+        let reader = std::thread::spawn(move || {
+            let mut buf = [0u8; 8];
+            let wait_mode = WaitMode::Poll(Duration::from_secs(10));
+            sender
+                .send(nonblocking_read_exact(&mut stream, &mut buf[..], wait_mode))
+                .unwrap();
+            buf
+        });
+        let thread = std::os::unix::thread::JoinHandleExt::as_pthread_t(&reader);
+        for _ in 0..10 {
+            std::thread::sleep(Duration::from_millis(5));
+            assert_eq!(unsafe { libc::pthread_kill(thread, libc::SIGUSR1) }, 0);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(outcome.is_empty());
+        assert!(SIGNALED.load(Ordering::Relaxed) > 0);
+
+        peer.write_all(&[3u8; 8][..]).unwrap();
+        let outcome = outcome.recv().unwrap();
+        assert!(matches!(outcome, IoOutcome::Done), "{:?}", outcome);
+        assert_eq!(reader.join().unwrap(), [3u8; 8]);
+    }
+
+    #[test]
+    fn test_peer_closed() {
+        // Half of what is due, then the write side is closed.
+        let (mut stream, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        peer.write_all(&[1u8; 32][..]).unwrap();
+        peer.shutdown(net::Shutdown::Write).unwrap();
+        let mut buf = [0u8; 64];
+        let outcome = nonblocking_read_exact(&mut stream, &mut buf[..], WaitMode::SpinThenYield);
+        assert!(matches!(outcome, IoOutcome::PeerClosed), "{:?}", outcome);
+        // It still reads.
+        assert!(matches!(
+            nonblocking_write_all(&mut stream, &[2u8; 16][..], WaitMode::SpinThenYield),
+            IoOutcome::Done
+        ));
+        let mut received = [0u8; 16];
+        peer.read_exact(&mut received[..]).unwrap();
+
+        // Gone while a write is blocked on it.
+        let (mut stream, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let closer = std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            peer.read_exact(&mut buf[..]).unwrap();
+        });
+        let data = vec![0u8; 8 << 20];
+        let outcome = nonblocking_write_all(&mut stream, &data[..], WaitMode::SpinThenYield);
+        assert!(matches!(outcome, IoOutcome::PeerClosed), "{:?}", outcome);
+        closer.join().unwrap();
+
+        // Not a stream that can be read or written.
+        let path = std::env::temp_dir().join(format!("bagua-net-io-{}", std::process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+        let outcome = nonblocking_read_exact(&mut file, &mut buf[..], WaitMode::SpinThenYield);
+        assert!(matches!(outcome, IoOutcome::Fatal(_)), "{:?}", outcome);
+        let mut file = fs::File::open(&path).unwrap();
+        let outcome = nonblocking_write_all(&mut file, &buf[..], WaitMode::SpinThenYield);
+        assert!(matches!(outcome, IoOutcome::Fatal(_)), "{:?}", outcome);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_try_write_vectored_from() {
+        let (mut stream, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let header = 0usize.to_be_bytes();
+        let payload = vec![7u8; 1 << 20];
+        let bufs = [&header[..], &[][..], &payload[..]];
+
+        // Stuck once the socket buffer is full, goes on from there.
+        let mut pos = 0;
+        assert!(!try_write_vectored_from(&mut stream, &bufs[..], &mut pos).unwrap());
+        assert!(pos > 0 && pos < header.len() + payload.len());
+        let total = header.len() + payload.len();
+        let reader = std::thread::spawn(move || {
+            let mut received = vec![0u8; total];
+            peer.read_exact(&mut received[..]).unwrap();
+            received
+        });
+        while !try_write_vectored_from(&mut stream, &bufs[..], &mut pos).unwrap() {
+            std::thread::yield_now();
+        }
+        assert_eq!(pos, header.len() + payload.len());
+
+        let received = reader.join().unwrap();
+        assert_eq!(&received[..8], &header[..]);
+        assert!(received[8..] == payload[..]);
+    }
+
+    #[test]
+    fn test_try_read_vectored_into() {
+        let sizes = [3, 0, 5, 1, 4];
+        let total: usize = sizes.iter().sum();
+        let data: Vec<u8> = (1..=total as u8).collect();
+        // Every split of what is in the socket when it would block, at and
+        // between the ends of the buffers.
+        for split in 0..=total {
+            let (mut stream, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+            stream.set_nonblocking(true).unwrap();
+            let mut storage: Vec<Vec<u8>> = sizes.iter().map(|&n| vec![0u8; n]).collect();
+            let mut bufs: Vec<&mut [u8]> = storage.iter_mut().map(|buf| &mut buf[..]).collect();
+
+            let mut pos = 0;
+            peer.write_all(&data[..split]).unwrap();
+            let done = try_read_vectored_into(&mut stream, &mut bufs[..], &mut pos).unwrap();
+            assert_eq!((done, pos), (split == total, split), "split={}", split);
+            peer.write_all(&data[split..]).unwrap();
+            assert!(try_read_vectored_into(&mut stream, &mut bufs[..], &mut pos).unwrap());
+            assert_eq!(pos, total);
+            assert_eq!(storage.concat(), data, "split={}", split);
+        }
+
+        let (mut stream, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        peer.write_all(&[1, 2]).unwrap();
+        drop(peer);
+        let (mut a, mut b) = ([0u8; 1], [0u8; 4]);
+        let mut pos = 0;
+        let err = try_read_vectored_into(&mut stream, &mut [&mut a[..], &mut b[..]], &mut pos)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!((pos, a, b[0]), (2, [1], 2));
+    }
+
+    #[test]
+    fn test_live_threads() {
+        let threads = LiveThreads::default();
+        let (stop, stopped) = flume::bounded::<()>(0);
+        let waiting = threads.spawn(move || {
+            let _ = stopped.recv();
+        });
+        let panicking = threads.spawn(|| panic!("expected"));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        assert!(join_until(panicking, deadline));
+        assert_eq!(threads.count(), 1);
+        assert!(!join_until(
+            threads.spawn(|| std::thread::sleep(Duration::from_secs(1))),
+            Instant::now()
+        ));
+        drop(stop);
+        assert!(join_until(waiting, deadline));
+        while threads.count() > 0 {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
     }
 }
@@ -0,0 +1,196 @@
+//! `MSG_ZEROCOPY` sends: the kernel sends the pages of the buffer in place,
+//! so they must stay put until it reports the send complete on the error
+//! queue of the socket. Only worth it for large sends, and over loopback the
+//! kernel copies anyway.
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+/// Not in libc yet, from `asm-generic/socket.h` and `linux/errqueue.h`.
+const SO_ZEROCOPY: libc::c_int = 60;
+const SO_EE_ORIGIN_ZEROCOPY: u8 = 5;
+const SO_EE_CODE_ZEROCOPY_COPIED: u8 = 1;
+
+/// Fails if the kernel does not support it.
+pub fn enable(fd: RawFd) -> io::Result<()> {
+    let one: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            SO_ZEROCOPY,
+            &one as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sends what it can of `buf`, which must not change until the send is
+/// reported complete. Once it returns, that is the next send of `fd` in
+/// `Completions`.
+pub fn send(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::send(
+            fd,
+            buf.as_ptr() as *const libc::c_void,
+            buf.len(),
+            libc::MSG_ZEROCOPY | libc::MSG_NOSIGNAL | libc::MSG_DONTWAIT,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// Reads the completions on the error queue of `fd`, each a range of sends
+/// and whether the kernel copied them after all.
+pub fn reap(fd: RawFd, mut completed: impl FnMut(u32, u32, bool)) -> io::Result<()> {
+    loop {
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+        let ret = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::WouldBlock => return Ok(()),
+                io::ErrorKind::Interrupted => continue,
+                _ => return Err(err),
+            }
+        }
+
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let (level, ty) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            if (level == libc::SOL_IP && ty == libc::IP_RECVERR)
+                || (level == libc::SOL_IPV6 && ty == libc::IPV6_RECVERR)
+            {
+                let err = unsafe {
+                    std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err)
+                };
+                if err.ee_origin == SO_EE_ORIGIN_ZEROCOPY {
+                    completed(
+                        err.ee_info,
+                        err.ee_data,
+                        err.ee_code & SO_EE_CODE_ZEROCOPY_COPIED != 0,
+                    );
+                }
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+    }
+}
+
+/// Which zerocopy sends of a socket completed. The kernel numbers them from
+/// 0, and may report them out of order.
+#[derive(Debug, Default)]
+pub struct Completions {
+    next: u32,
+    /// Every send before this one completed.
+    done: u32,
+    /// Ranges completed ahead of an earlier send, inclusive.
+    ahead: Vec<(u32, u32)>,
+}
+
+impl Completions {
+    /// The number of the next send.
+    pub fn next(&self) -> u32 {
+        self.next
+    }
+
+    pub fn sent(&mut self) {
+        self.next += 1;
+    }
+
+    pub fn completed(&mut self, first: u32, last: u32) {
+        self.ahead.push((first, last));
+        while let Some(i) = self.ahead.iter().position(|&(first, _)| first <= self.done) {
+            let (_, last) = self.ahead.swap_remove(i);
+            self.done = self.done.max(last + 1);
+        }
+    }
+
+    /// Whether every send before `seq` completed.
+    pub fn done_before(&self, seq: u32) -> bool {
+        self.done >= seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_send_reap() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let sender = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut receiver, _) = listener.accept().unwrap();
+        if let Err(err) = enable(sender.as_raw_fd()) {
+            println!("no zerocopy sends, err={:?}", err);
+            return;
+        }
+        sender.set_nonblocking(true).unwrap();
+
+        let data: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+        let received = std::thread::spawn(move || {
+            let mut buf = vec![0u8; 1 << 20];
+            receiver.read_exact(&mut buf[..]).unwrap();
+            buf
+        });
+        let mut completions = Completions::default();
+        let mut pos = 0;
+        let mut copied = false;
+        let timer = std::time::Instant::now();
+        while pos < data.len() || !completions.done_before(completions.next()) {
+            assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+            if pos < data.len() {
+                match send(sender.as_raw_fd(), &data[pos..]) {
+                    Ok(n) => {
+                        pos += n;
+                        completions.sent();
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => panic!("{:?}", err),
+                }
+            }
+            reap(sender.as_raw_fd(), |first, last, was_copied| {
+                completions.completed(first, last);
+                copied |= was_copied;
+            })
+            .unwrap();
+        }
+        assert!(completions.next() > 0);
+        assert_eq!(received.join().unwrap(), data);
+        // Loopback copies to the receiving socket.
+        assert!(copied);
+    }
+
+    #[test]
+    fn test_completions() {
+        let mut completions = Completions::default();
+        for _ in 0..5 {
+            completions.sent();
+        }
+        assert_eq!(completions.next(), 5);
+        assert!(completions.done_before(0));
+        assert!(!completions.done_before(1));
+
+        // Out of order, the later ones wait for the first.
+        completions.completed(2, 3);
+        assert!(!completions.done_before(1));
+        completions.completed(0, 1);
+        assert!(completions.done_before(4));
+        assert!(!completions.done_before(5));
+        completions.completed(4, 4);
+        assert!(completions.done_before(5));
+    }
+}